@@ -0,0 +1,496 @@
+// gguf.rs: reader for the header of GGUF model files, std only
+//
+// A GGUF file starts with:
+//   magic "GGUF", version (u32), tensor count, metadata key/value count,
+//   the metadata key/value pairs, then one info record per tensor
+//   (name, dimensions, type, offset), followed by the tensor data.
+// Only the header is read; the tensor data (gigabytes) is never touched.
+// Version 1 files use u32 for counts and string lengths, later versions u64.
+// Format: https://github.com/ggml-org/ggml/blob/master/docs/gguf.md
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// First four bytes of every GGUF file
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+/// Longest string read from a header; longer lengths mean a corrupt file
+/// (chat templates are a few KB, the longest expected strings)
+const MAX_GGUF_STRING_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Most metadata entries or tensors read from a header (real models have hundreds to a few thousand)
+const MAX_GGUF_ENTRIES: u64 = 1_000_000;
+
+/// Most dimensions of one tensor (ggml supports 4)
+const MAX_GGUF_TENSOR_DIMS: u32 = 8;
+
+/// A metadata value; arrays are only summarized (tokenizer vocabularies hold 100k+ strings)
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    Unsigned(u64),
+    Signed(i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Array { item_type: &'static str, len: u64 },
+}
+
+impl GgufValue {
+    /// The value as an unsigned number, for integer values that are not negative
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            GgufValue::Unsigned(value) => Some(*value),
+            GgufValue::Signed(value) => u64::try_from(*value).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for GgufValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GgufValue::Unsigned(value) => write!(f, "{}", value),
+            GgufValue::Signed(value) => write!(f, "{}", value),
+            GgufValue::Float(value) => write!(f, "{}", value),
+            GgufValue::Bool(value) => write!(f, "{}", value),
+            GgufValue::String(value) => write!(f, "{:?}", value),
+            GgufValue::Array { item_type, len } => write!(f, "[{} x {}]", len, item_type),
+        }
+    }
+}
+
+/// One tensor's info record
+#[derive(Debug, Clone, PartialEq)]
+pub struct GgufTensor {
+    pub name: String,
+    pub dims: Vec<u64>,
+    pub ggml_type: u32,
+}
+
+impl GgufTensor {
+    /// Number of elements; dims come from the file, so a corrupt one
+    /// saturates at u64::MAX instead of overflowing
+    pub fn element_count(&self) -> u64 {
+        self.dims.iter().fold(1u64, |count, dim| count.saturating_mul(*dim))
+    }
+}
+
+/// The header of a GGUF file: metadata in file order, and the tensor list
+#[derive(Debug, Clone, PartialEq)]
+pub struct GgufHeader {
+    pub version: u32,
+    pub metadata: Vec<(String, GgufValue)>,
+    pub tensors: Vec<GgufTensor>,
+}
+
+impl GgufHeader {
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata.iter().find(|(name, _)| name == key).map(|(_, value)| value)
+    }
+
+    /// Model architecture, e.g. "llama", "qwen2", "gemma3"
+    pub fn architecture(&self) -> Option<&str> {
+        self.get("general.architecture").and_then(GgufValue::as_str)
+    }
+
+    /// An architecture-specific number, e.g. "context_length" reads `llama.context_length`
+    pub fn architecture_u64(&self, field: &str) -> Option<u64> {
+        let architecture = self.architecture()?;
+        self.get(&format!("{}.{}", architecture, field)).and_then(GgufValue::as_u64)
+    }
+
+    /// Context length the model was trained for
+    pub fn context_length(&self) -> Option<u64> {
+        self.architecture_u64("context_length")
+    }
+
+    pub fn chat_template(&self) -> Option<&str> {
+        self.get("tokenizer.chat_template").and_then(GgufValue::as_str)
+    }
+
+    /// Number of weights: the sum of every tensor's element count
+    ///
+    /// For split models only the tensors in this file are counted.
+    pub fn parameter_count(&self) -> u64 {
+        self.tensors.iter()
+            .fold(0u64, |total, tensor| total.saturating_add(tensor.element_count()))
+    }
+
+    /// Quantization type, e.g. "Q4_K_M": from `general.file_type`, or else
+    /// the type holding the most weights
+    pub fn quantization(&self) -> Option<String> {
+        if let Some(name) = self.get("general.file_type").and_then(GgufValue::as_u64).and_then(file_type_name) {
+            return Some(name.to_string());
+        }
+        let mut weights_by_type: Vec<(u32, u64)> = Vec::new();
+        for tensor in &self.tensors {
+            let count = tensor.element_count();
+            match weights_by_type.iter_mut().find(|(ggml_type, _)| *ggml_type == tensor.ggml_type) {
+                Some(entry) => entry.1 = entry.1.saturating_add(count),
+                None => weights_by_type.push((tensor.ggml_type, count)),
+            }
+        }
+        weights_by_type.iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(ggml_type, _)| ggml_type_name(*ggml_type).map(str::to_string).unwrap_or_else(|| format!("type {}", ggml_type)))
+    }
+}
+
+/// Name of a `general.file_type` value (llama.cpp's llama_ftype)
+pub fn file_type_name(file_type: u64) -> Option<&'static str> {
+    Some(match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        36 => "TQ1_0",
+        37 => "TQ2_0",
+        _ => return None,
+    })
+}
+
+/// Name of a tensor's ggml type
+pub fn ggml_type_name(ggml_type: u32) -> Option<&'static str> {
+    Some(match ggml_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        6 => "Q5_0",
+        7 => "Q5_1",
+        8 => "Q8_0",
+        9 => "Q8_1",
+        10 => "Q2_K",
+        11 => "Q3_K",
+        12 => "Q4_K",
+        13 => "Q5_K",
+        14 => "Q6_K",
+        15 => "Q8_K",
+        16 => "IQ2_XXS",
+        17 => "IQ2_XS",
+        18 => "IQ3_XXS",
+        19 => "IQ1_S",
+        20 => "IQ4_NL",
+        21 => "IQ3_S",
+        22 => "IQ2_S",
+        23 => "IQ4_XS",
+        24 => "I8",
+        25 => "I16",
+        26 => "I32",
+        27 => "I64",
+        28 => "F64",
+        29 => "IQ1_M",
+        30 => "BF16",
+        34 => "TQ1_0",
+        35 => "TQ2_0",
+        _ => return None,
+    })
+}
+
+/// Reads the header of a GGUF file
+///
+/// # Returns
+/// - Ok(GgufHeader): Metadata and tensor list
+/// - Err(String): Not a GGUF file, an unsupported version, or a truncated/corrupt header
+pub fn read_gguf_header(path: &Path) -> Result<GgufHeader, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    parse_gguf_header(&mut BufReader::new(file)).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Parses a GGUF header from the start of a file
+pub fn parse_gguf_header(reader: &mut impl Read) -> Result<GgufHeader, String> {
+    let mut magic = [0u8; 4];
+    read_exact(reader, &mut magic)?;
+    if &magic != GGUF_MAGIC {
+        return Err("not a GGUF file (no GGUF magic)".to_string());
+    }
+    let version = read_u32(reader)?;
+    if !(1..=3).contains(&version) {
+        return Err(format!("unsupported GGUF version {}", version));
+    }
+    let mut header_reader = HeaderReader { reader, version };
+    let tensor_count = header_reader.read_count("tensor count")?;
+    let metadata_count = header_reader.read_count("metadata count")?;
+
+    let mut metadata = Vec::new();
+    for _ in 0..metadata_count {
+        let key = header_reader.read_string()?;
+        let value_type = read_u32(header_reader.reader)?;
+        let value = header_reader.read_value(value_type)
+            .map_err(|e| format!("metadata {}: {}", key, e))?;
+        metadata.push((key, value));
+    }
+
+    let mut tensors = Vec::new();
+    for _ in 0..tensor_count {
+        let name = header_reader.read_string()?;
+        let dim_count = read_u32(header_reader.reader)?;
+        if dim_count > MAX_GGUF_TENSOR_DIMS {
+            return Err(format!("tensor {} has {} dimensions", name, dim_count));
+        }
+        let dims = (0..dim_count)
+            .map(|_| header_reader.read_count_value())
+            .collect::<Result<Vec<u64>, String>>()?;
+        let ggml_type = read_u32(header_reader.reader)?;
+        let _offset = read_u64(header_reader.reader)?;
+        tensors.push(GgufTensor { name, dims, ggml_type });
+    }
+
+    Ok(GgufHeader { version, metadata, tensors })
+}
+
+/// Reads the version-dependent parts of a header (u32 sizes in version 1, u64 after)
+struct HeaderReader<'a, R: Read> {
+    reader: &'a mut R,
+    version: u32,
+}
+
+impl<R: Read> HeaderReader<'_, R> {
+    fn read_count_value(&mut self) -> Result<u64, String> {
+        if self.version == 1 {
+            read_u32(self.reader).map(u64::from)
+        } else {
+            read_u64(self.reader)
+        }
+    }
+
+    fn read_count(&mut self, what: &str) -> Result<u64, String> {
+        let count = self.read_count_value()?;
+        if count > MAX_GGUF_ENTRIES {
+            return Err(format!("{} of {} is not plausible", what, count));
+        }
+        Ok(count)
+    }
+
+    fn read_string(&mut self) -> Result<String, String> {
+        let len = self.read_count_value()?;
+        if len > MAX_GGUF_STRING_BYTES {
+            return Err(format!("string of {} bytes is not plausible", len));
+        }
+        let mut bytes = vec![0u8; len as usize];
+        read_exact(self.reader, &mut bytes)?;
+        Ok(String::from_utf8_lossy(&bytes).to_string())
+    }
+
+    fn read_value(&mut self, value_type: u32) -> Result<GgufValue, String> {
+        let reader = &mut *self.reader;
+        Ok(match value_type {
+            0 => GgufValue::Unsigned(read_array::<1>(reader)?[0] as u64),
+            1 => GgufValue::Signed(read_array::<1>(reader)?[0] as i8 as i64),
+            2 => GgufValue::Unsigned(u16::from_le_bytes(read_array(reader)?) as u64),
+            3 => GgufValue::Signed(i16::from_le_bytes(read_array(reader)?) as i64),
+            4 => GgufValue::Unsigned(read_u32(reader)? as u64),
+            5 => GgufValue::Signed(i32::from_le_bytes(read_array(reader)?) as i64),
+            6 => GgufValue::Float(f32::from_le_bytes(read_array(reader)?) as f64),
+            7 => GgufValue::Bool(read_array::<1>(reader)?[0] != 0),
+            8 => GgufValue::String(self.read_string()?),
+            9 => {
+                let item_type = read_u32(self.reader)?;
+                let len = self.read_count_value()?;
+                for _ in 0..len {
+                    if item_type == 9 {
+                        return Err("nested arrays are not supported".to_string());
+                    }
+                    self.read_value(item_type)?;
+                }
+                GgufValue::Array { item_type: value_type_name(item_type), len }
+            },
+            10 => GgufValue::Unsigned(read_u64(reader)?),
+            11 => GgufValue::Signed(i64::from_le_bytes(read_array(reader)?)),
+            12 => GgufValue::Float(f64::from_le_bytes(read_array(reader)?)),
+            other => return Err(format!("unknown value type {}", other)),
+        })
+    }
+}
+
+/// Name of a metadata value type, for array summaries
+fn value_type_name(value_type: u32) -> &'static str {
+    match value_type {
+        0 => "u8",
+        1 => "i8",
+        2 => "u16",
+        3 => "i16",
+        4 => "u32",
+        5 => "i32",
+        6 => "f32",
+        7 => "bool",
+        8 => "string",
+        10 => "u64",
+        11 => "i64",
+        12 => "f64",
+        _ => "unknown",
+    }
+}
+
+fn read_exact(reader: &mut impl Read, buffer: &mut [u8]) -> Result<(), String> {
+    reader.read_exact(buffer).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => "header is truncated".to_string(),
+        _ => format!("failed to read header: {}", e),
+    })
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], String> {
+    let mut bytes = [0u8; N];
+    read_exact(reader, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, String> {
+    read_array(reader).map(u32::from_le_bytes)
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, String> {
+    read_array(reader).map(u64::from_le_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a GGUF (version 3) header in memory
+    struct HeaderBuilder {
+        metadata: Vec<u8>,
+        metadata_count: u64,
+        tensors: Vec<u8>,
+        tensor_count: u64,
+    }
+
+    impl HeaderBuilder {
+        fn new() -> Self {
+            HeaderBuilder { metadata: Vec::new(), metadata_count: 0, tensors: Vec::new(), tensor_count: 0 }
+        }
+
+        fn string(bytes: &mut Vec<u8>, text: &str) {
+            bytes.extend((text.len() as u64).to_le_bytes());
+            bytes.extend(text.as_bytes());
+        }
+
+        fn key(&mut self, key: &str, value_type: u32) -> &mut Vec<u8> {
+            self.metadata_count += 1;
+            Self::string(&mut self.metadata, key);
+            self.metadata.extend(value_type.to_le_bytes());
+            &mut self.metadata
+        }
+
+        fn string_value(mut self, key: &str, value: &str) -> Self {
+            Self::string(self.key(key, 8), value);
+            self
+        }
+
+        fn u32_value(mut self, key: &str, value: u32) -> Self {
+            self.key(key, 4).extend(value.to_le_bytes());
+            self
+        }
+
+        fn string_array(mut self, key: &str, items: &[&str]) -> Self {
+            let bytes = self.key(key, 9);
+            bytes.extend(8u32.to_le_bytes());
+            bytes.extend((items.len() as u64).to_le_bytes());
+            for item in items {
+                Self::string(bytes, item);
+            }
+            self
+        }
+
+        fn tensor(mut self, name: &str, dims: &[u64], ggml_type: u32) -> Self {
+            self.tensor_count += 1;
+            Self::string(&mut self.tensors, name);
+            self.tensors.extend((dims.len() as u32).to_le_bytes());
+            for dim in dims {
+                self.tensors.extend(dim.to_le_bytes());
+            }
+            self.tensors.extend(ggml_type.to_le_bytes());
+            self.tensors.extend(0u64.to_le_bytes());
+            self
+        }
+
+        fn build(self) -> Vec<u8> {
+            let mut bytes = b"GGUF".to_vec();
+            bytes.extend(3u32.to_le_bytes());
+            bytes.extend(self.tensor_count.to_le_bytes());
+            bytes.extend(self.metadata_count.to_le_bytes());
+            bytes.extend(self.metadata);
+            bytes.extend(self.tensors);
+            bytes
+        }
+    }
+
+    #[test]
+    fn test_parse_gguf_header() {
+        let bytes = HeaderBuilder::new()
+            .string_value("general.architecture", "llama")
+            .string_value("general.name", "Tiny Llama")
+            .u32_value("general.file_type", 15)
+            .u32_value("llama.context_length", 4096)
+            .string_array("tokenizer.ggml.tokens", &["<s>", "</s>", "hi"])
+            .string_value("tokenizer.chat_template", "{% for m in messages %}{{ m.content }}{% endfor %}")
+            .tensor("token_embd.weight", &[64, 1000], 12)
+            .tensor("output_norm.weight", &[64], 0)
+            .build();
+        let header = parse_gguf_header(&mut bytes.as_slice()).unwrap();
+        assert_eq!(header.version, 3);
+        assert_eq!(header.architecture(), Some("llama"));
+        assert_eq!(header.context_length(), Some(4096));
+        assert_eq!(header.quantization().as_deref(), Some("Q4_K_M"));
+        assert_eq!(header.parameter_count(), 64_064);
+        assert_eq!(header.get("tokenizer.ggml.tokens"), Some(&GgufValue::Array { item_type: "string", len: 3 }));
+        assert!(header.chat_template().unwrap().starts_with("{% for m"));
+        assert_eq!(header.tensors[0].dims, vec![64, 1000]);
+
+        // Without general.file_type, the type holding the most weights
+        let bytes = HeaderBuilder::new()
+            .tensor("a", &[10, 10], 12)
+            .tensor("b", &[10, 20], 8)
+            .build();
+        assert_eq!(parse_gguf_header(&mut bytes.as_slice()).unwrap().quantization().as_deref(), Some("Q8_0"));
+
+        // Huge dims from a corrupt file saturate instead of overflowing
+        let bytes = HeaderBuilder::new()
+            .tensor("a", &[u64::MAX, 2], 12)
+            .tensor("b", &[1 << 40, 1 << 20], 8)
+            .tensor("c", &[1 << 40, 1 << 20], 8)
+            .build();
+        let header = parse_gguf_header(&mut bytes.as_slice()).unwrap();
+        assert_eq!(header.parameter_count(), u64::MAX);
+        assert_eq!(header.quantization().as_deref(), Some("Q4_K"));
+
+        let bytes = HeaderBuilder::new().string_value("general.name", "x").build();
+        assert!(parse_gguf_header(&mut &bytes[..bytes.len() - 1]).unwrap_err().contains("truncated"));
+        assert!(parse_gguf_header(&mut &b"GGML\x03\x00\x00\x00"[..]).unwrap_err().contains("not a GGUF"));
+        assert!(parse_gguf_header(&mut &b"GGUF\x09\x00\x00\x00"[..]).unwrap_err().contains("version 9"));
+    }
+}
@@ -0,0 +1,748 @@
+//! A small HTTP/1.1 client
+//!
+//! Used for llama-server and endpoint requests, model downloads, and the
+//! Hugging Face API, so they share one way of sending requests, following
+//! redirects, and reading chunked or sized bodies.
+//!
+//! `http://` URLs are handled here over a TcpStream. The program uses only
+//! std, so `https://` URLs are delegated to a TLS backend (see TlsBackend):
+//! curl, which does the whole request, or `openssl s_client`, which only
+//! carries the bytes while this module speaks HTTP over it. Redirects are
+//! followed here in every case, so all backends behave the same.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Child, Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// Most redirects followed for one request
+const MAX_REDIRECTS: usize = 10;
+
+/// Timeout of a request that does not set one
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest response head (status line and headers) read
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+/// curl exit codes for network trouble worth retrying: could not resolve host (6),
+/// could not connect (7), timeout (28), TLS handshake (35), empty reply (52),
+/// send/receive errors (55, 56)
+const RETRYABLE_CURL_EXIT_CODES: &[i32] = &[6, 7, 28, 35, 52, 55, 56];
+
+/// A request to send
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub timeout: Duration, // for connecting, and for each read or write after that
+}
+
+impl Request {
+    pub fn new(method: &str, url: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            url: url.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn get(url: &str) -> Request {
+        Request::new("GET", url)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Request {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Request {
+        self.body = body.into();
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Request {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// A response; `body` is empty when it was written to a sink instead (see Client::send_to)
+#[derive(Debug, Clone, Default)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// The value of a header (names are case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body as text (invalid UTF-8 replaced)
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+}
+
+/// A request that got no response
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub message: String,
+    pub retryable: bool, // network trouble (no connection, timeout...), not a bad URL or missing tool
+}
+
+impl Error {
+    fn fatal(message: String) -> Error {
+        Error { message, retryable: false }
+    }
+
+    fn network(message: String) -> Error {
+        Error { message, retryable: true }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Where a response body goes
+///
+/// `begin` is called with the final response (after redirects) before its
+/// body is written, e.g. to choose between appending to a partial download
+/// (206) and starting over (200).
+pub trait ResponseSink: Write {
+    fn begin(&mut self, _response: &Response) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ResponseSink for Vec<u8> {}
+
+impl From<Error> for String {
+    fn from(error: Error) -> String {
+        error.message
+    }
+}
+
+/// How `https://` requests are made
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TlsBackend {
+    /// curl makes the request (its HTTP/2, proxies, and certificate store)
+    #[default]
+    Curl,
+    /// `openssl s_client` makes the TLS connection; HTTP/1.1 is spoken here
+    OpenSsl,
+}
+
+impl TlsBackend {
+    /// Reads a backend name as written in the config ("curl" or "openssl")
+    pub fn from_name(name: &str) -> Option<TlsBackend> {
+        match name.trim().to_lowercase().as_str() {
+            "curl" => Some(TlsBackend::Curl),
+            "openssl" => Some(TlsBackend::OpenSsl),
+            _ => None,
+        }
+    }
+}
+
+/// Sends requests; holds the settings that apply to every request
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    pub proxy: Option<String>,     // e.g. "http://proxy:3128"; None to connect directly
+    pub ca_bundle: Option<String>, // CA certificates (PEM file) to trust for https, instead of the system ones
+    pub tls: TlsBackend,
+}
+
+/// The parts of a URL that a request needs
+#[derive(Debug, Clone, PartialEq)]
+struct Url {
+    scheme: String,
+    host: String,
+    port: u16,
+    target: String, // path and query, e.g. "/api/models?blobs=true"
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Url, Error> {
+        let (scheme, rest) = url.split_once("://")
+            .ok_or_else(|| Error::fatal(format!("Not a URL: {}", url)))?;
+        let scheme = scheme.to_lowercase();
+        let default_port = match scheme.as_str() {
+            "http" => 80,
+            "https" => 443,
+            _ => return Err(Error::fatal(format!("Unsupported URL scheme '{}': {}", scheme, url))),
+        };
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(index) if rest[index..].starts_with('?') => (&rest[..index], format!("/{}", &rest[index..])),
+            Some(index) => (&rest[..index], rest[index..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        let authority = authority.rsplit_once('@').map(|(_, host)| host).unwrap_or(authority);
+        // [::1]:8080 style IPv6 hosts keep their brackets
+        let (host, port) = match authority.rsplit_once(':').filter(|(host, _)| !host.ends_with(':')) {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port.parse::<u16>().map_err(|_| Error::fatal(format!("Invalid port in URL: {}", url)))?;
+                (host, port)
+            },
+            _ => (authority, default_port),
+        };
+        if host.is_empty() {
+            return Err(Error::fatal(format!("No host in URL: {}", url)));
+        }
+        Ok(Url { scheme, host: host.to_string(), port, target })
+    }
+
+    /// True when both URLs have the same scheme, host, and port
+    fn is_same_origin(&self, other: &Url) -> bool {
+        self.scheme == other.scheme && self.host.eq_ignore_ascii_case(&other.host) && self.port == other.port
+    }
+
+    fn is_default_port(&self) -> bool {
+        (self.scheme == "http" && self.port == 80) || (self.scheme == "https" && self.port == 443)
+    }
+
+    /// The Host header value: the host, with the port if it is not the scheme's default
+    fn host_header(&self) -> String {
+        if self.is_default_port() {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    /// The URL as text
+    fn to_text(&self) -> String {
+        format!("{}://{}{}", self.scheme, self.host_header(), self.target)
+    }
+
+    /// The URL a `Location` header points to, from this URL
+    fn join(&self, location: &str) -> String {
+        if location.contains("://") {
+            location.to_string()
+        } else if let Some(rest) = location.strip_prefix("//") {
+            format!("{}://{}", self.scheme, rest)
+        } else if location.starts_with('/') {
+            format!("{}://{}{}", self.scheme, self.host_header(), location)
+        } else {
+            let path = self.target.split('?').next().unwrap_or("/");
+            let directory = &path[..path.rfind('/').map(|index| index + 1).unwrap_or(0)];
+            format!("{}://{}{}{}", self.scheme, self.host_header(), directory, location)
+        }
+    }
+}
+
+/// Returns true for statuses that redirect to their Location
+fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+impl Client {
+    /// Sends a request, following redirects, and returns the whole response
+    pub fn send(&self, request: &Request) -> Result<Response, Error> {
+        let mut body = Vec::new();
+        let mut response = self.send_to(request, &mut body)?;
+        response.body = body;
+        Ok(response)
+    }
+
+    /// Sends a request, following redirects, and writes the final response's body to `sink`
+    ///
+    /// For downloads too large to keep in memory. The returned response has an empty body.
+    pub fn send_to(&self, request: &Request, sink: &mut dyn ResponseSink) -> Result<Response, Error> {
+        let mut request = request.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let url = Url::parse(&request.url)?;
+            let response = self.send_once(&request, &url, sink)?;
+            let Some(location) = response.header("location").filter(|_| is_redirect(response.status)) else {
+                return Ok(response);
+            };
+            let next = url.join(location);
+            // Credentials are only sent where they were meant to go, and never downgraded to http
+            if !url.is_same_origin(&Url::parse(&next)?) {
+                request.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("authorization"));
+            }
+            if response.status == 303 || (response.status != 307 && response.status != 308 && request.method == "POST") {
+                request.method = "GET".to_string();
+                request.body.clear();
+            }
+            request.url = next;
+        }
+        Err(Error::fatal(format!("Too many redirects from {}", request.url)))
+    }
+
+    /// Sends one request without following redirects
+    fn send_once(&self, request: &Request, url: &Url, sink: &mut dyn ResponseSink) -> Result<Response, Error> {
+        let proxy = self.proxy.as_deref().map(Url::parse).transpose()?;
+        let proxy_is_http = proxy.as_ref().is_none_or(|proxy| proxy.scheme == "http");
+        if url.scheme == "https" || !proxy_is_http {
+            // Only curl speaks to SOCKS proxies
+            let backend = if proxy_is_http { self.tls } else { TlsBackend::Curl };
+            return match backend {
+                TlsBackend::Curl => self.send_with_curl(request, url, sink),
+                TlsBackend::OpenSsl => self.send_with_openssl(request, url, proxy.as_ref(), sink),
+            };
+        }
+
+        // Through an HTTP proxy, the request names the whole URL
+        let (connect_to, target) = match &proxy {
+            Some(proxy) => (proxy, url.to_text()),
+            None => (url, url.target.clone()),
+        };
+        let stream = connect(&connect_to.host, connect_to.port, request.timeout)?;
+        let network_error = |e: io::Error| Error::network(format!("Connection to {} failed: {}", connect_to.host_header(), e));
+        stream.set_read_timeout(Some(request.timeout)).map_err(network_error)?;
+        stream.set_write_timeout(Some(request.timeout)).map_err(network_error)?;
+        exchange(&mut BufReader::new(&stream), &mut &stream, request, url, &target, sink)
+    }
+
+    /// Makes the request with curl, which handles TLS and proxies
+    ///
+    /// Headers and body go to curl on stdin (`-K -`), so secrets such as
+    /// Authorization headers never show up in process listings.
+    fn send_with_curl(&self, request: &Request, url: &Url, sink: &mut dyn ResponseSink) -> Result<Response, Error> {
+        let body = std::str::from_utf8(&request.body)
+            .map_err(|_| Error::fatal("The curl backend only sends text request bodies".to_string()))?;
+        let mut config = format!("url = {}\n", curl_config_quote(&url.to_text()));
+        for (name, value) in &request.headers {
+            config.push_str(&format!("header = {}\n", curl_config_quote(&format!("{}: {}", name, value))));
+        }
+        if !body.is_empty() {
+            config.push_str(&format!("data-binary = {}\n", curl_config_quote(body)));
+        }
+
+        let mut command = Command::new("curl");
+        command.args(["-sS", "--connect-timeout", &request.timeout.as_secs().max(1).to_string()]);
+        // A HEAD request must not wait for a body
+        if request.method == "HEAD" {
+            command.arg("-I");
+        } else {
+            command.args(["-D", "-", "-X", &request.method]);
+        }
+        // The proxy's own CONNECT response would look like the server's
+        match &self.proxy {
+            Some(proxy) => command.arg("--proxy").arg(proxy).arg("--suppress-connect-headers"),
+            None => command.arg("--noproxy").arg("*"),
+        };
+        if let Some(ca_bundle) = &self.ca_bundle {
+            command.arg("--cacert").arg(ca_bundle);
+        }
+        let mut child = command.args(["-K", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::fatal(format!("Failed to run curl (is it installed?): {}", e)))?;
+        child.stdin.take()
+            .ok_or_else(|| Error::fatal("Failed to open curl stdin".to_string()))?
+            .write_all(config.as_bytes())
+            .map_err(|e| Error::fatal(format!("Failed to send request to curl: {}", e)))?;
+
+        let run = with_watchdog(child, request.timeout, |stdout| {
+            let mut reader = BufReader::new(stdout);
+            let (status, headers) = read_head(&mut reader)?;
+            let response = Response { status, headers, body: Vec::new() };
+            // curl has already decoded the body
+            let target: &mut dyn Write = if is_redirect(status) {
+                &mut io::sink()
+            } else {
+                sink.begin(&response)?;
+                sink
+            };
+            io::copy(&mut reader, target)?;
+            Ok(response)
+        })?;
+        let failure = match (&run.result, run.exit_code) {
+            (Ok(_), Some(0)) => None,
+            (Ok(_), code) => Some(io::Error::other(format!("curl exited with status {}", code.unwrap_or(-1)))),
+            (Err(e), _) => Some(io::Error::new(e.kind(), e.to_string())),
+        };
+        match failure {
+            None => run.result.map_err(|e| Error::fatal(e.to_string())),
+            Some(e) => Err(Error {
+                message: format!("Request to {} failed: {}", url.to_text(), run.message(&e)),
+                retryable: run.stalled || run.exit_code.is_none_or(|code| RETRYABLE_CURL_EXIT_CODES.contains(&code)),
+            }),
+        }
+    }
+
+    /// Makes the request over a TLS connection opened by `openssl s_client`
+    fn send_with_openssl(&self, request: &Request, url: &Url, proxy: Option<&Url>, sink: &mut dyn ResponseSink) -> Result<Response, Error> {
+        let mut command = Command::new("openssl");
+        command.args(["s_client", "-quiet", "-verify_return_error"])
+            .arg("-connect").arg(format!("{}:{}", url.host, url.port))
+            .arg("-servername").arg(&url.host)
+            .arg("-verify_hostname").arg(&url.host);
+        if let Some(proxy) = proxy {
+            command.arg("-proxy").arg(format!("{}:{}", proxy.host, proxy.port));
+        }
+        if let Some(ca_bundle) = &self.ca_bundle {
+            command.arg("-CAfile").arg(ca_bundle);
+        }
+        let mut child = command.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::fatal(format!("Failed to run openssl (is it installed?): {}", e)))?;
+        let mut stdin = child.stdin.take()
+            .ok_or_else(|| Error::fatal("Failed to open openssl stdin".to_string()))?;
+
+        let run = with_watchdog(child, request.timeout, move |stdout| {
+            let result = exchange(&mut BufReader::new(stdout), &mut stdin, request, url, &url.target, sink);
+            drop(stdin);
+            Ok(result)
+        })?;
+        // openssl's exit status says little once a whole response has arrived
+        match run.result {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) if !run.stalled && run.stderr.trim().is_empty() => Err(e),
+            // openssl reports TLS failures among its progress output
+            Ok(Err(_)) => {
+                let errors: Vec<&str> = run.stderr.lines().filter(|line| line.contains("error")).collect();
+                let message = if run.stalled || errors.is_empty() { run.message(&io::Error::other("no response")) } else { errors.join("; ") };
+                Err(Error {
+                    message: format!("Request to {} failed: {}", url.to_text(), message),
+                    retryable: !run.stderr.contains("verify error"),
+                })
+            },
+            Err(ref e) => Err(Error::network(format!("Request to {} failed: {}", url.to_text(), run.message(e)))),
+        }
+    }
+}
+
+/// Connects to host:port, trying each address the host resolves to
+fn connect(host: &str, port: u16, timeout: Duration) -> Result<TcpStream, Error> {
+    let addresses = (host.trim_matches(['[', ']']), port).to_socket_addrs()
+        .map_err(|e| Error::network(format!("Could not resolve {}: {}", host, e)))?;
+    let mut last_error = None;
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(Error::network(match last_error {
+        Some(e) => format!("Failed to connect to {}:{}: {}", host, port, e),
+        None => format!("Could not resolve {}", host),
+    }))
+}
+
+/// How a backend process run went
+struct BackendRun<T> {
+    result: io::Result<T>,  // what was read from its output
+    exit_code: Option<i32>, // None if it was killed
+    stalled: bool,          // killed for producing no output within the timeout
+    stderr: String,
+}
+
+impl<T> BackendRun<T> {
+    /// The error message: the first line the process printed, if any
+    fn message(&self, e: &io::Error) -> String {
+        match self.stderr.lines().map(str::trim).find(|line| !line.is_empty()) {
+            _ if self.stalled => "timed out".to_string(),
+            Some(line) => line.to_string(),
+            None => e.to_string(),
+        }
+    }
+}
+
+/// Runs a backend process, killing it if it produces nothing for `timeout`
+///
+/// `read` gets the process's stdout.
+fn with_watchdog<T>(
+    mut child: Child,
+    timeout: Duration,
+    read: impl FnOnce(&mut dyn Read) -> io::Result<T>,
+) -> Result<BackendRun<T>, Error> {
+    let mut stdout = child.stdout.take().ok_or_else(|| Error::fatal("Failed to read backend output".to_string()))?;
+    let mut stderr = child.stderr.take().ok_or_else(|| Error::fatal("Failed to read backend errors".to_string()))?;
+    let stderr_reader = std::thread::spawn(move || {
+        let mut text = String::new();
+        let _ = stderr.read_to_string(&mut text);
+        text
+    });
+
+    // Every read restarts the clock; no output for `timeout` ends the request
+    let child = Arc::new(Mutex::new(child));
+    let (progress, progress_seen) = mpsc::channel::<()>();
+    let watched = Arc::clone(&child);
+    let watchdog = std::thread::spawn(move || {
+        let stalled = loop {
+            match progress_seen.recv_timeout(timeout) {
+                Ok(()) => continue,
+                Err(mpsc::RecvTimeoutError::Timeout) => break true,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break false, // output was read
+            }
+        };
+        if stalled {
+            if let Ok(mut child) = watched.lock() {
+                let _ = child.kill();
+            }
+        }
+        stalled
+    });
+    let mut watched_stdout = ProgressReader { inner: &mut stdout, progress };
+    let result = read(&mut watched_stdout);
+    drop(watched_stdout);
+    let stalled = watchdog.join().unwrap_or(false);
+
+    // Give the process a moment to exit on its own
+    let mut status = None;
+    for _ in 0..50 {
+        status = child.lock().ok().and_then(|mut child| child.try_wait().ok().flatten());
+        if status.is_some() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    if status.is_none() {
+        if let Ok(mut child) = child.lock() {
+            let _ = child.kill();
+            status = child.wait().ok();
+        }
+    }
+    let stderr = stderr_reader.join().unwrap_or_default();
+    Ok(BackendRun { result, exit_code: status.and_then(|status| status.code()), stalled, stderr })
+}
+
+/// Reads from `inner`, telling a watchdog about each read
+struct ProgressReader<'a> {
+    inner: &'a mut dyn Read,
+    progress: mpsc::Sender<()>,
+}
+
+impl Read for ProgressReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buffer)?;
+        let _ = self.progress.send(());
+        Ok(count)
+    }
+}
+
+/// Sends a request over a connection and reads the response
+///
+/// The body goes to `sink`, except for redirects, whose body is dropped.
+fn exchange(
+    reader: &mut dyn BufRead,
+    writer: &mut dyn Write,
+    request: &Request,
+    url: &Url,
+    target: &str,
+    sink: &mut dyn ResponseSink,
+) -> Result<Response, Error> {
+    let network_error = |e: io::Error| Error::network(format!("Request to {} failed: {}", url.to_text(), e));
+
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, target, url.host_header());
+    for (name, value) in &request.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !request.body.is_empty() || matches!(request.method.as_str(), "POST" | "PUT" | "PATCH") {
+        head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    // One write, so the request arrives in one piece
+    let mut message = head.into_bytes();
+    message.extend_from_slice(&request.body);
+    writer.write_all(&message).and_then(|_| writer.flush()).map_err(network_error)?;
+
+    let (status, headers) = read_head(reader).map_err(network_error)?;
+    let response = Response { status, headers, body: Vec::new() };
+    let target: &mut dyn Write = if is_redirect(status) {
+        &mut io::sink()
+    } else {
+        sink.begin(&response).map_err(network_error)?;
+        sink
+    };
+    if request.method == "HEAD" || status == 204 || status == 304 {
+        return Ok(response);
+    }
+    let is_chunked = response.header("transfer-encoding")
+        .is_some_and(|encoding| encoding.to_lowercase().contains("chunked"));
+    if is_chunked {
+        copy_chunked(reader, target).map_err(network_error)?;
+    } else if let Some(length) = response.header("content-length").and_then(|length| length.trim().parse::<u64>().ok()) {
+        let copied = io::copy(&mut reader.take(length), target).map_err(network_error)?;
+        if copied < length {
+            return Err(Error::network(format!(
+                "Request to {} failed: connection closed after {} of {} bytes", url.to_text(), copied, length
+            )));
+        }
+    } else {
+        io::copy(reader, target).map_err(network_error)?;
+    }
+    Ok(response)
+}
+
+/// Reads a response's status line and headers, skipping `100 Continue` and other 1xx responses
+fn read_head(reader: &mut dyn BufRead) -> io::Result<(u16, Vec<(String, String)>)> {
+    loop {
+        let mut lines = Vec::new();
+        let mut read = 0;
+        loop {
+            let mut line = String::new();
+            let count = reader.read_line(&mut line)?;
+            read += count;
+            if count == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the server closed the connection without a response"));
+            }
+            if read > MAX_HEAD_BYTES {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "response headers are too long"));
+            }
+            let line = line.trim_end_matches(['\r', '\n']).to_string();
+            if line.is_empty() {
+                if lines.is_empty() {
+                    continue; // blank lines before the status line
+                }
+                break;
+            }
+            lines.push(line);
+        }
+
+        let status = lines[0].split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .filter(|_| lines[0].starts_with("HTTP/"))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed status line: {}", lines[0])))?;
+        if (100..200).contains(&status) {
+            continue;
+        }
+        let headers = lines[1..].iter()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        return Ok((status, headers));
+    }
+}
+
+/// Copies a chunked transfer-encoded body to `sink`, without the chunk framing
+fn copy_chunked(reader: &mut dyn BufRead, sink: &mut dyn Write) -> io::Result<()> {
+    loop {
+        let mut size_line = String::new();
+        if reader.read_line(&mut size_line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunked body ended early"));
+        }
+        let size = u64::from_str_radix(size_line.split(';').next().unwrap_or("").trim(), 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("bad chunk size: {}", size_line.trim())))?;
+        if size == 0 {
+            // Trailer headers, up to a blank line (or the end)
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                    return Ok(());
+                }
+            }
+        }
+        if io::copy(&mut reader.take(size), sink)? < size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunked body ended early"));
+        }
+        let mut line_end = String::new();
+        reader.read_line(&mut line_end)?;
+    }
+}
+
+/// Quotes a value for a curl config file (`curl -K`)
+fn curl_config_quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_exchange() {
+        let url = Url::parse("http://127.0.0.1:8080/completion?x=1").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.target.as_str()), ("127.0.0.1", 8080, "/completion?x=1"));
+        assert_eq!(Url::parse("https://user@hf.co").unwrap().to_text(), "https://hf.co/");
+        assert_eq!(Url::parse("http://[::1]:9000/a").unwrap().host, "[::1]");
+        assert!(Url::parse("ftp://x").is_err());
+        assert_eq!(url.join("/v1/models"), "http://127.0.0.1:8080/v1/models");
+        assert_eq!(url.join("other"), "http://127.0.0.1:8080/other");
+        assert_eq!(url.join("https://cdn.example/f.gguf"), "https://cdn.example/f.gguf");
+
+        // Redirects keep the Authorization header only to the same scheme, host, and port
+        let api = Url::parse("https://api.example.com/v1/models").unwrap();
+        assert!(api.is_same_origin(&Url::parse("https://API.example.com:443/v2").unwrap()));
+        assert!(!api.is_same_origin(&Url::parse("http://api.example.com/v1/models").unwrap()));
+        assert!(!api.is_same_origin(&Url::parse("https://api.example.com:8443/v1").unwrap()));
+        assert!(!api.is_same_origin(&Url::parse("https://cdn.example.com/v1").unwrap()));
+
+        // Followed for real: a redirect to another port drops the key
+        let target = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let redirector = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let start_url = format!("http://127.0.0.1:{}/start", redirector.local_addr().unwrap().port());
+        let location = format!("http://127.0.0.1:{}/next", target.local_addr().unwrap().port());
+        let redirect_thread = std::thread::spawn(move || {
+            let (mut stream, _) = redirector.accept().unwrap();
+            read_request_head(&mut stream);
+            write!(stream, "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n", location).unwrap();
+        });
+        let target_thread = std::thread::spawn(move || {
+            let (mut stream, _) = target.accept().unwrap();
+            let head = read_request_head(&mut stream);
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+            head
+        });
+        let response = Client::default()
+            .send(&Request::new("GET", &start_url).header("Authorization", "Bearer sk-secret"))
+            .unwrap();
+        redirect_thread.join().unwrap();
+        let head = target_thread.join().unwrap();
+        assert_eq!(response.status, 200);
+        assert!(head.starts_with("GET /next ") && !head.to_lowercase().contains("authorization"), "{}", head);
+
+        let request = Request::new("POST", "http://127.0.0.1:8080/completion").header("Content-Type", "application/json").body("{}");
+        let reply = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let mut sent = Vec::new();
+        let mut body = Vec::new();
+        let response = exchange(&mut &reply[..], &mut sent, &request, &url, "/completion", &mut body).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("transfer-encoding"), Some("chunked"));
+        assert_eq!(body, b"hello world");
+        let sent = String::from_utf8(sent).unwrap();
+        assert!(sent.starts_with("POST /completion HTTP/1.1\r\nHost: 127.0.0.1:8080\r\nContent-Type: application/json\r\n"));
+        assert!(sent.ends_with("Content-Length: 2\r\nConnection: close\r\n\r\n{}"));
+
+        // A body shorter than its Content-Length is an error
+        let reply = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort";
+        assert!(exchange(&mut &reply[..], &mut Vec::new(), &request, &url, "/", &mut Vec::new()).unwrap_err().retryable);
+
+        assert_eq!(curl_config_quote("a\"b\\c\nd"), "\"a\\\"b\\\\c\\nd\"");
+    }
+
+    /// Reads a request's head (up to the blank line) from a test server's connection
+    fn read_request_head(stream: &mut TcpStream) -> String {
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+                return head;
+            }
+            head.push_str(&line);
+        }
+    }
+}
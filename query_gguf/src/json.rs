@@ -0,0 +1,429 @@
+//! A small JSON reader and writer
+//!
+//! Used for llama-server and endpoint requests and responses, batch
+//! checkpoints and results, agent tool calls, and Hugging Face model
+//! metadata, so that every feature escapes and reads JSON the same way.
+//!
+//! Numbers are f64, as in JavaScript. Objects keep their keys in order, so
+//! written JSON is stable. Output is compact (no spaces), one value per line
+//! fits JSONL files.
+
+use std::fmt;
+
+/// Deepest nesting read, so a hostile document cannot overflow the stack
+const MAX_DEPTH: usize = 128;
+
+/// A JSON value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Builds an object from (key, value) pairs, e.g. `Value::object([("role", "user".into())])`
+    pub fn object<K: Into<String>>(entries: impl IntoIterator<Item = (K, Value)>) -> Value {
+        Value::Object(entries.into_iter().map(|(key, value)| (key.into(), value)).collect())
+    }
+
+    /// Sets a member of an object, replacing one with the same key (other values are left as is)
+    pub fn insert(&mut self, key: &str, value: impl Into<Value>) {
+        if let Value::Object(members) = self {
+            let value = value.into();
+            match members.iter_mut().find(|(member, _)| member == key) {
+                Some((_, existing)) => *existing = value,
+                None => members.push((key.to_string(), value)),
+            }
+        }
+    }
+
+    /// A member of an object
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(member, _)| member == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// Follows a dotted path of object keys and array indices, e.g. `choices.0.message.content`
+    pub fn path(&self, path: &str) -> Option<&Value> {
+        path.split('.').try_fold(self, |value, step| match value {
+            Value::Array(items) => items.get(step.parse::<usize>().ok()?),
+            _ => value.get(step),
+        })
+    }
+
+    /// The first member named `key` at any depth, in document order
+    pub fn find(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find_map(|(member, value)| {
+                if member == key { Some(value) } else { value.find(key) }
+            }),
+            Value::Array(items) => items.iter().find_map(|item| item.find(key)),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    /// A whole, non-negative number
+    pub fn as_u64(&self) -> Option<u64> {
+        self.as_f64().filter(|number| *number >= 0.0 && number.fract() == 0.0).map(|number| number as u64)
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Value)]> {
+        match self {
+            Value::Object(members) => Some(members),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::String(text.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::String(text)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(flag: bool) -> Self {
+        Value::Bool(flag)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(number: f64) -> Self {
+        Value::Number(number)
+    }
+}
+
+impl From<f32> for Value {
+    /// Goes through the f32's shortest decimal form, so 0.8f32 is written as 0.8
+    fn from(number: f32) -> Self {
+        Value::Number(number.to_string().parse().unwrap_or(number as f64))
+    }
+}
+
+impl From<i32> for Value {
+    fn from(number: i32) -> Self {
+        Value::Number(number as f64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(number: i64) -> Self {
+        Value::Number(number as f64)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(number: u64) -> Self {
+        Value::Number(number as f64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(number: usize) -> Self {
+        Value::Number(number as f64)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(items: Vec<Value>) -> Self {
+        Value::Array(items)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(flag) => write!(f, "{}", flag),
+            // JSON has no NaN or infinity
+            Value::Number(number) if !number.is_finite() => write!(f, "null"),
+            Value::Number(number) if number.fract() == 0.0 && number.abs() < 1e15 => write!(f, "{}", *number as i64),
+            Value::Number(number) => write!(f, "{}", number),
+            Value::String(text) => write_quoted(f, text),
+            Value::Array(items) => {
+                write!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Value::Object(members) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in members.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_quoted(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+/// Writes text as a JSON string, with quotes
+fn write_quoted(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Parses a JSON document (surrounding whitespace is allowed, other trailing text is not)
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser { chars: text.chars().collect(), pos: 0 };
+    let value = parser.parse_value(0)?;
+    parser.skip_whitespace();
+    if parser.pos < parser.chars.len() {
+        return Err(parser.error("unexpected text after the value"));
+    }
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> String {
+        format!("Invalid JSON: {} at character {}", message, self.pos + 1)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect_word(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        for expected in word.chars() {
+            if self.next() != Some(expected) {
+                return Err(self.error(&format!("expected {}", word)));
+            }
+        }
+        Ok(value)
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("too deeply nested"));
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.parse_object(depth),
+            Some('[') => self.parse_array(depth),
+            Some('"') => self.parse_string().map(Value::String),
+            Some('t') => self.expect_word("true", Value::Bool(true)),
+            Some('f') => self.expect_word("false", Value::Bool(false)),
+            Some('n') => self.expect_word("null", Value::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+
+    fn parse_object(&mut self, depth: usize) -> Result<Value, String> {
+        self.pos += 1;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("expected a quoted key"));
+            }
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            if self.next() != Some(':') {
+                return Err(self.error("expected ':'"));
+            }
+            let value = self.parse_value(depth + 1)?;
+            members.push((key, value));
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Object(members)),
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn parse_array(&mut self, depth: usize) -> Result<Value, String> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.parse_value(depth + 1)?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    /// Reads a string at its opening quote
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut text = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(text),
+                Some('\\') => {
+                    let escaped = match self.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.parse_unicode_escape()?,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    text.push(escaped);
+                },
+                Some(c) => text.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    /// Reads the XXXX of \uXXXX, and the low half of a surrogate pair (e.g. \ud83d\ude00)
+    fn parse_unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.parse_hex4()?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return Ok(char::from_u32(high).unwrap_or('\u{FFFD}'));
+        }
+        if self.next() != Some('\\') || self.next() != Some('u') {
+            return Ok('\u{FFFD}');
+        }
+        let low = self.parse_hex4()?;
+        if !(0xDC00..0xE000).contains(&low) {
+            return Ok('\u{FFFD}');
+        }
+        Ok(char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)).unwrap_or('\u{FFFD}'))
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, String> {
+        let hex: String = (0..4).filter_map(|_| self.next()).collect();
+        u32::from_str_radix(&hex, 16).ok()
+            .filter(|_| hex.len() == 4)
+            .ok_or_else(|| self.error("invalid \\u escape"))
+    }
+
+    fn parse_number(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+            self.pos += 1;
+        }
+        let number: String = self.chars[start..self.pos].iter().collect();
+        number.parse::<f64>()
+            .map(Value::Number)
+            .map_err(|_| self.error(&format!("invalid number {}", number)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_round_trip() {
+        let text = r#" {"content":"Hello \"you\"\nA\u00e9 \ud83d\ude00","tokens":12,"ratio":-0.5e1,
+            "ok":true,"none":null,"list":[1,"two",[]],"timings":{"prompt_ms":35.5}} "#;
+        let value = parse(text).unwrap();
+        assert_eq!(value.get("content").and_then(Value::as_str), Some("Hello \"you\"\nAé 😀"));
+        assert_eq!(value.get("tokens").and_then(Value::as_u64), Some(12));
+        assert_eq!(value.get("ratio").and_then(Value::as_f64), Some(-5.0));
+        assert_eq!(value.get("ok"), Some(&Value::Bool(true)));
+        assert_eq!(value.get("none"), Some(&Value::Null));
+        assert_eq!(value.path("list.1").and_then(Value::as_str), Some("two"));
+        assert_eq!(value.path("timings.prompt_ms").and_then(Value::as_f64), Some(35.5));
+        assert_eq!(value.find("prompt_ms"), value.path("timings.prompt_ms"));
+        assert_eq!(value.path("list.9"), None);
+
+        // Written compactly, in key order, and read back the same
+        let written = value.to_string();
+        assert!(written.starts_with(r#"{"content":"Hello \"you\"\nAé 😀","tokens":12,"ratio":-5,"#));
+        assert_eq!(parse(&written).unwrap(), value);
+
+        let mut request = Value::object([("role", "user".into()), ("temperature", 0.8f32.into())]);
+        request.insert("seed", 42);
+        request.insert("role", "system");
+        assert_eq!(request.to_string(), r#"{"role":"system","temperature":0.8,"seed":42}"#);
+        assert_eq!(Value::from("tab\there\u{1}").to_string(), "\"tab\\there\\u0001\"");
+        assert_eq!(Value::Number(f64::NAN).to_string(), "null");
+
+        for bad in ["", "{", "{\"a\" 1}", "[1,]", "\"open", "tru", "{\"a\":1} x", "\"\\x\""] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+        assert!(parse(&"[".repeat(MAX_DEPTH + 2)).unwrap_err().contains("too deeply nested"));
+    }
+}
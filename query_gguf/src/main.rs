@@ -13,9 +13,218 @@ query_gguf
 # Launch with specific mode
 query_gguf 1
 
+# Chat in this terminal instead of a new window (automatic in tmux, screen, and ssh sessions)
+query_gguf 1 --inline
+
 # Launch with manual mode
 query_gguf manual
 
+# Open the config file in an editor
+query_gguf config
+
+# Check the config for fixable problems (add --fix to apply them)
+query_gguf config lint
+query_gguf config lint --fix
+
+# Who changed what in the config, and when (setup, saved modes, retune, lint --fix, edits)
+query_gguf config log
+query_gguf config log 100
+
+# Launch mode 4 with piped stdin as context and a first question
+kubectl logs mypod | query_gguf 4 --stdin-as-context -q "why is this crashing?"
+
+# Directory mode with only signatures/outlines of Rust and Python files (much smaller prompt)
+query_gguf dir --outline
+# Directory mode with only the tree (no file contents), two levels deep
+query_gguf dir --tree-only --depth 2
+
+# Fill a prompt's {{ask:Project name}} placeholders (missing ones are asked for at launch)
+query_gguf review --var "Project name=query_gguf"
+
+# Answer one question and print only the answer on stdout (status lines go to stderr), for scripts
+query_gguf ask 2 "summarize rust ownership" > answer.txt
+git diff | query_gguf ask 2 "write a commit message for this diff" --stdin-as-context
+echo "what is a monad?" | query_gguf ask 2
+
+# Run once without a terminal and print the response (cached if response_cache = true)
+query_gguf 2 -q "summarize rust ownership" --print
+query_gguf 2 -q "summarize rust ownership" --print --no-cache
+# Stop runaway generations: the output so far ends with an "[output truncated: ...]" line
+query_gguf 2 -q "summarize rust ownership" --print --max-seconds 60 --max-bytes 20000
+# Save the response's fenced code blocks to files (paths from "// file: ..." hints)
+query_gguf 2 -q "write a hello world in rust and python" --print --save-code out/
+# Scroll and search a long response in $PAGER (less -R by default); implies --print
+query_gguf 2 -q "explain the borrow checker in depth" --pager
+# Speak the question instead of typing it (runs stt_command from the config, e.g. whisper.cpp)
+query_gguf 2 --dictate --print
+# Image modes (kind=image) draw the description with stable-diffusion.cpp
+query_gguf sd15 -q "a lighthouse at dusk"
+# Launch a mode even if its only_if/hours/min_free_ram_gb conditions are not met
+query_gguf 5 --force
+query_gguf cache stats
+query_gguf cache clear
+
+# Chat with mode 2 in this terminal, with a context window usage meter (uses llama-server)
+query_gguf chat 2
+# Replies are rendered as markdown (code highlighting, bold, lists); --raw shows them as plain text
+query_gguf chat 2 --raw
+# At the end of a chat, code blocks from the replies are offered for saving; --save-code saves them all
+query_gguf chat 2 --save-code out/
+
+# Ask mode 2 for a code change in ./src as a unified diff; preview it, then apply it (--dry-run only previews)
+query_gguf patch 2 ./src -q "add a --verbose flag"
+query_gguf patch 2 ./src -q "add a --verbose flag" --dry-run
+
+# Experimental agent: the model may run allowlisted commands and read files (each step confirmed)
+query_gguf agent 2 --max-steps 5
+
+# Keep mode 2's model loaded in RAM (llama-server --mlock), unloaded after 30 idle minutes;
+# --print runs, chat, agent, and tune with mode 2 then use it instead of loading the model
+query_gguf preload 2 --idle-minutes 30
+query_gguf preload list
+query_gguf preload stop 2
+# Keep mode 2's model loaded only while answering quick one-off queries here; other
+# terminals (--print, chat, batch) and OpenAI clients use the same server meanwhile
+query_gguf server 2
+
+# With launch_backend = "docker", llama-cli runs in the llama.cpp container; manage the containers:
+query_gguf docker list
+query_gguf docker logs 2
+query_gguf docker stop 2
+
+# Modes with remote=user@host in their entry run llama-cli on that machine over ssh;
+# the rendered prompt (with any directory dump or context) is copied there first
+query_gguf 4 -q "review this" --print   # mode 4 has remote=me@gpubox
+
+# Modes with endpoint=<url> use an OpenAI-compatible server (API key from api_key_env,
+# the OS keychain, or asked once per run; never stored in the config file)
+query_gguf chat 5
+
+# Experiment with sampling parameters for mode 2 with the model kept loaded (uses llama-server)
+query_gguf tune 2
+# Run a prompt over every combination of settings and write a CSV of timings and output samples
+query_gguf bench 2 --sweep "temp=0.2:1.0:0.2" --sweep "top_p=0.8,0.9,0.95" -q "name three colors"
+# Check that a seeded prompt gives byte-identical output (e.g. before building test fixtures)
+query_gguf verify-determinism 2 --runs 5 --seed 7 -q "list three primes"
+# Run a mode over each line of a file (JSON lines out); an interrupted run continues with --resume
+query_gguf batch 2 questions.txt --out answers.jsonl
+query_gguf batch 2 questions.txt --out answers.jsonl --resume
+# Spread a batch over modes: two items at once on mode small, one on the endpoint mode gpubox
+query_gguf batch small questions.txt --workers small:2,gpubox:1
+# Score modes on a suite of questions (expect patterns, or a judge mode with a rubric) side by side
+query_gguf eval suite.toml
+query_gguf eval suite.toml --modes small,coder --judge big --out eval.csv
+
+# Watch a running chat, agent, batch, or preloaded server from another terminal (colored by role)
+query_gguf logs
+query_gguf logs -f batch_coder
+
+# Search all saved chat transcripts (ranked, with snippets); reindex rebuilds the search index
+query_gguf history search "borrow checker"
+query_gguf history reindex
+# Compare two sessions' responses (e.g. the same prompt on two modes), words highlighted
+query_gguf history diff chat_coder_2025_01_02_10_00_00 chat_small_2025_01_02_10_05_00
+query_gguf history diff chat_coder_2025_01_02_10_00_00 chat_small_2025_01_02_10_05_00 --side-by-side
+
+# Model load time, first token latency, and speed per model (from --print and tune sessions)
+query_gguf stats
+
+# Check that mode 2's model loads and its flags are accepted (generates one token, no session)
+query_gguf mode verify 2
+# Run mode 2's canary prompts (tests=tests/coder.toml in its entry); reports tests that
+# regressed since the last run, e.g. after editing the system prompt or swapping the model
+query_gguf mode test 2
+
+# List, delete, rename, or copy saved modes (by number or id; deleting renumbers the mode_N entries)
+query_gguf modes list
+query_gguf modes delete 3
+query_gguf modes rename coder "Careful Coder"
+query_gguf modes copy coder "Coder (long context)"
+# Change a mode's model, prompt file, or parameters (asks with the current values as defaults)
+query_gguf edit coder
+# Search mode names, descriptions, model file names, and prompt file contents
+query_gguf find qwen
+
+# Print the exact llama-cli command of mode 2 (absolute paths) instead of running it,
+# e.g. to debug a failing launch or to copy into a script; --print shows the one-shot command
+query_gguf 2 --dry-run
+query_gguf 2 --print --dry-run
+# The same with the mode's resolved model and prompt files (missing ones are marked)
+query_gguf show coder
+
+# Explain a failed llama-cli run from its saved stderr (run automatically in the terminal window)
+query_gguf diagnose ~/query_gguf/temp/llama_cli_stderr.log
+
+# Write a local Markdown usage report (modes, models, tokens, disk usage); never sent anywhere
+query_gguf report --days 30
+
+# Re-tune all modes' threads and GPU layers for this machine
+query_gguf retune
+
+# Download a model from Hugging Face into the first model directory (checks free disk space first)
+query_gguf pull TheBloke/Mistral-7B-Instruct-v0.2-GGUF mistral-7b-instruct-v0.2.Q4_K_M.gguf
+
+# Split models: naming any part downloads all parts (add --merge to join them into one file)
+query_gguf pull unsloth/Some-Model-GGUF some-model-Q8_0-00001-of-00002.gguf --merge
+
+# Download a model and create a mode for it in one step: quantization picked from the repo's
+# GGUF files (or --quant), ctx_size and chat mode from its GGUF metadata, sampling from its model card
+query_gguf add https://huggingface.co/bartowski/Llama-3.2-1B-Instruct-GGUF
+query_gguf add bartowski/Llama-3.2-1B-Instruct-GGUF --quant Q6_K_L --dir ~/models
+
+# Move models not used for 90 days (no sessions, not downloaded since) to another disk; their
+# modes are kept, pointed at the archive, and disabled with a note (--dry-run only lists them)
+query_gguf models archive --older-than 90d --to /mnt/archive
+query_gguf models archive --dry-run   # --to defaults to model_archive_directory in the config
+
+# Architecture, parameter count, quantization, context length, and chat template from a model's
+# GGUF header (--all lists every metadata key, --template prints the whole chat template)
+query_gguf inspect ~/models/mistral-7b-instruct-v0.2.Q4_K_M.gguf
+query_gguf inspect 2 --all
+
+# Quantize a model with llama-quantize (checks free disk space first)
+query_gguf quantize ~/models/mistral-7b-f16.gguf Q4_K_M
+
+# Clone/update the shared prompt library set as prompt_repo in the config
+query_gguf prompts sync
+
+# Create a prompt file from a script (a taken name gets -2, -3...); typed in when not piped
+cat <<EOF | query_gguf prompts new review
+You are a careful code reviewer. Point out bugs first, then style.
+EOF
+query_gguf prompts new summarize --from-stdin < summarize.txt
+
+# Find prompt files with the same text (also with only whitespace differences) across all
+# prompt directories; merging keeps one copy and points the modes at it
+query_gguf prompts dedup
+
+# Add --offline to any command to refuse all network use (or set offline = true)
+query_gguf 2 --offline
+
+# Keep config, prompts, and logs somewhere other than ~/query_gguf (for any command)
+query_gguf --data-dir ~/.local/share/query_gguf 2
+# Show the data directory in use, or move it (paths in the config and state are rewritten)
+query_gguf data-dir
+query_gguf data-dir migrate ~/.local/share/query_gguf
+# Packagers: build with another default (an existing ~/query_gguf is used until migrated)
+QUERY_GGUF_DEFAULT_DATA_DIR='~/.local/share/query_gguf' cargo build --release
+
+# Run a command line saved under a word in the config's [aliases] table (extra options are appended)
+query_gguf review --print
+
+# Plugins: any other word runs query_gguf-<word> from PATH, git-style (with QUERY_GGUF_CONFIG,
+# QUERY_GGUF_HOME, and QUERY_GGUF_MODE set for it)
+query_gguf hello 2   # runs query_gguf-hello 2 (built-in commands like bench always win)
+
+# Exit codes (stable, for scripts):
+#   0 ok
+#   1 other error
+#   2 config or usage error (bad config, unknown mode or option)
+#   3 model file missing
+#   4 launch failure (llama-cli/llama-server/docker/ssh failed to start or run)
+#   5 generation timeout (--max-seconds reached; the partial output is still printed)
+#   6 user abort (Ctrl-C, a declined confirmation)
+
 # query_gguf.rs, a minimal rust cli program, to:
 
 - ideally operate on linux, macOS, or other prominant non-posix OS
@@ -84,9 +293,31 @@ debug = false
 use std::fs::{self, File};
 use std::io::{self, BufRead, Write};
 use std::process::Command;
+use std::ffi::OsString;
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::path::{PathBuf, Path};
 
+mod gguf;
+mod http;
+mod json;
+mod tokens;
+mod toml_lite;
+
+/// Set by `ask`: stdout carries only the model's answer, for pipelines
+static ANSWER_ONLY_STDOUT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// True when stdout is reserved for the model's answer (`ask`)
+fn answer_only_stdout() -> bool {
+    ANSWER_ONLY_STDOUT.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// println! for status lines: printed to stderr while stdout carries only the answer
+macro_rules! status_println {
+    ($($arg:tt)*) => {
+        if answer_only_stdout() { eprintln!($($arg)*) } else { println!($($arg)*) }
+    };
+}
+
 /// Gets the user's home directory path across different operating systems
 /// 
 /// This function attempts to find the user's home directory by checking environment
@@ -118,7 +349,9 @@ fn get_home_dir() -> Result<String, String> {
 
 /// Gets the absolute path to the application's base directory
 /// 
-/// Creates a 'query_gguf' directory in the user's home directory if it doesn't exist.
+/// The directory is `--data-dir` if given, else the build's default, else
+/// 'query_gguf' in the user's home directory (see `choose_data_dir`), and is
+/// created if it doesn't exist.
 /// This directory serves as the base location for all application files including:
 /// - Configuration file
 /// - Prompt files
@@ -146,7 +379,11 @@ fn get_app_base_dir() -> Result<PathBuf, String> {
         .or_else(|_| std::env::var("USERPROFILE")) // Fallback for Windows
         .map_err(|_| "Could not determine home directory".to_string())?;
     
-    let base_dir = PathBuf::from(home).join("query_gguf");
+    let flag = DATA_DIR_FLAG.get();
+    if flag.is_some_and(|dir| dir.as_os_str().is_empty()) {
+        return Err("--data-dir needs a directory, e.g. --data-dir ~/.local/share/query_gguf".to_string());
+    }
+    let base_dir = choose_data_dir(flag.map(PathBuf::as_path), BUILD_DEFAULT_DATA_DIR, &home, |path| path.is_dir());
     
     // Create the directory if it doesn't exist
     fs::create_dir_all(&base_dir)
@@ -155,6 +392,37 @@ fn get_app_base_dir() -> Result<PathBuf, String> {
     Ok(base_dir)
 }
 
+/// Default data directory set at build time, e.g. `~/.local/share/query_gguf` for distro packages
+const BUILD_DEFAULT_DATA_DIR: Option<&str> = option_env!("QUERY_GGUF_DEFAULT_DATA_DIR");
+
+/// Set by the `--data-dir DIR` command line flag (absolute, empty when DIR is missing)
+static DATA_DIR_FLAG: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+
+/// Picks the app directory: `--data-dir`, else the build's default, else ~/query_gguf
+///
+/// A build default (QUERY_GGUF_DEFAULT_DATA_DIR) that does not exist yet
+/// falls back to an existing ~/query_gguf, so upgrading a package does not
+/// lose the user's config; `query_gguf data-dir migrate` moves it over.
+///
+/// # Arguments
+/// * `flag` - The `--data-dir` directory, if given
+/// * `build_default` - The build's default, `~` is expanded
+/// * `home` - The user's home directory
+/// * `is_dir` - Checks whether a directory exists
+fn choose_data_dir(flag: Option<&Path>, build_default: Option<&str>, home: &str, is_dir: impl Fn(&Path) -> bool) -> PathBuf {
+    if let Some(dir) = flag {
+        return dir.to_path_buf();
+    }
+    let legacy_dir = PathBuf::from(home).join("query_gguf");
+    match build_default.filter(|dir| !dir.trim().is_empty()) {
+        Some(dir) => {
+            let default_dir = PathBuf::from(normalize_config_path_value(dir, home));
+            if !is_dir(&default_dir) && is_dir(&legacy_dir) { legacy_dir } else { default_dir }
+        },
+        None => legacy_dir,
+    }
+}
+
 /// Gets the absolute path to the configuration file
 /// 
 /// Returns the path to query_gguf_config.toml in the application's base directory:
@@ -238,6 +506,149 @@ fn query_gguf_config_exists() -> bool {
     }
 }
 
+/// Returns the numeric user id of the current process on unix-like systems
+///
+/// Uses `id -u` so that no third party crates (libc) are needed.
+/// Returns None if the id cannot be determined (e.g. on Windows).
+#[cfg(unix)]
+fn get_current_user_id() -> Option<u32> {
+    let output = Command::new("id").arg("-u").output().ok()?;
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+#[cfg(not(unix))]
+fn get_current_user_id() -> Option<u32> {
+    None
+}
+
+/// Returns the numeric owner id of a file or directory on unix-like systems
+#[cfg(unix)]
+fn get_path_owner_id(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|metadata| metadata.uid())
+}
+
+#[cfg(not(unix))]
+fn get_path_owner_id(_path: &Path) -> Option<u32> {
+    None
+}
+
+/// Checks that a single application path is owned by the current user and writable
+///
+/// For directories, writability is tested by creating and removing a small
+/// temporary file. For files, the file is opened for appending (without
+/// modifying its contents).
+///
+/// # Arguments
+/// * `path` - File or directory to check
+/// * `label` - Human readable name used in problem messages (e.g. "config file")
+/// * `current_uid` - Id of the current user, if known
+///
+/// # Returns
+/// * `Vec<String>` - Description of each problem found (empty if none)
+fn check_path_ownership_and_access(path: &Path, label: &str, current_uid: Option<u32>) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if !path.exists() {
+        return problems;
+    }
+
+    // Ownership check (unix only)
+    if let (Some(uid), Some(owner)) = (current_uid, get_path_owner_id(path)) {
+        if uid != owner {
+            let owner_note = if owner == 0 { " (owned by root)" } else { "" };
+            problems.push(format!(
+                "{} is owned by user id {}, not by you (user id {}){}: {}",
+                label, owner, uid, owner_note, path.display()
+            ));
+        }
+    }
+
+    // Write access check
+    let writable = if path.is_dir() {
+        let test_file_path = path.join("query_gguf_write_test.tmp");
+        let result = fs::write(&test_file_path, "").is_ok();
+        let _ = fs::remove_file(test_file_path);
+        result
+    } else {
+        fs::OpenOptions::new().append(true).open(path).is_ok()
+    };
+
+    if !writable {
+        problems.push(format!("{} is not writable: {}", label, path.display()));
+    }
+
+    problems
+}
+
+/// Verifies that the application's files and directories are owned by the user and writable
+///
+/// Checks:
+/// - App base directory: ~/query_gguf/
+/// - Config file: ~/query_gguf/query_gguf_config.toml
+/// - Prompts directory: ~/query_gguf/prompts/
+/// - Log directory (if logging is enabled in the config)
+///
+/// A common cause of problems is running the setup under `sudo`, which leaves
+/// these files owned by root. This is detected and explained with a suggested
+/// fix, instead of failing later with scattered IO errors.
+///
+/// # Returns
+/// - Ok(()): All paths are accessible
+/// - Err(String): Explanation of the problems found and how to fix them
+fn check_app_directory_permissions() -> Result<(), String> {
+    let base_dir = get_app_base_dir()?;
+    let current_uid = get_current_user_id();
+
+    let mut paths_to_check = vec![
+        (base_dir.clone(), "App directory"),
+        (get_config_path()?, "Config file"),
+        (base_dir.join("prompts"), "Prompts directory"),
+    ];
+
+    // Only check the log directory when a config exists and logging is on
+    if query_gguf_config_exists() && read_config_bool("logging_enabled") == Some(true) {
+        let log_dir = read_field_from_toml("log_directory_path");
+        if !log_dir.is_empty() {
+            let log_path = if Path::new(&log_dir).is_absolute() {
+                PathBuf::from(&log_dir)
+            } else {
+                PathBuf::from(get_home_dir()?).join(&log_dir)
+            };
+            paths_to_check.push((log_path, "Log directory"));
+        }
+    }
+
+    let mut problems = Vec::new();
+    for (path, label) in &paths_to_check {
+        problems.extend(check_path_ownership_and_access(path, label, current_uid));
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = String::from("Permission problems found in the query_gguf directory:\n");
+    for problem in &problems {
+        message.push_str(&format!("  - {}\n", problem));
+    }
+
+    let running_as_sudo = std::env::var("SUDO_USER").is_ok();
+    let root_owned = problems.iter().any(|p| p.contains("(owned by root)"));
+    if running_as_sudo {
+        message.push_str("\nquery_gguf appears to be running under sudo. ");
+        message.push_str("Run it as your normal user instead.\n");
+    } else if root_owned {
+        message.push_str("\nThis usually happens when setup was run with sudo.\n");
+    }
+    message.push_str(&format!(
+        "To fix, give the directory back to your user, e.g.:\n  sudo chown -R $USER: \"{}\"",
+        base_dir.display()
+    ));
+
+    Err(message)
+}
+
 /// Represents the result of the setup wizard process
 #[derive(Debug)]
 struct SetupWizardResult {
@@ -246,6 +657,129 @@ struct SetupWizardResult {
     log_directory_path: String,
     logging_enabled: bool,
     llama_cpp_directory: String,
+    terminal_command: String,
+    editor: String,
+}
+
+/// Terminal emulators the setup wizard looks for, with how each runs a command
+///
+/// `{cmd}` stands for the shell command of the session, passed as one argument.
+const KNOWN_TERMINAL_COMMANDS: &[(&str, &str)] = &[
+    ("gnome-terminal", "gnome-terminal -- bash -c {cmd}"),
+    ("konsole", "konsole -e bash -c {cmd}"),
+    ("xfce4-terminal", "xfce4-terminal -x bash -c {cmd}"),
+    ("kitty", "kitty bash -c {cmd}"),
+    ("alacritty", "alacritty -e bash -c {cmd}"),
+    ("wezterm", "wezterm start -- bash -c {cmd}"),
+    ("foot", "foot bash -c {cmd}"),
+    ("x-terminal-emulator", "x-terminal-emulator -e bash -c {cmd}"),
+    ("xterm", "xterm -e bash -c {cmd}"),
+];
+
+/// Editors the setup wizard looks for (graphical ones must wait for the file to close)
+const KNOWN_EDITOR_COMMANDS: &[(&str, &str)] = &[
+    ("nano", "nano"),
+    ("micro", "micro"),
+    ("vim", "vim"),
+    ("nvim", "nvim"),
+    ("vi", "vi"),
+    ("emacs", "emacs"),
+    ("hx", "hx"),
+    ("code", "code --wait"),
+    ("gedit", "gedit --wait"),
+    ("notepad", "notepad"),
+];
+
+/// Finds an executable by name on PATH
+fn find_on_path(program: &str) -> Option<PathBuf> {
+    let file_name = format!("{}{}", program, std::env::consts::EXE_SUFFIX);
+    std::env::var_os("PATH")
+        .and_then(|paths| std::env::split_paths(&paths)
+            .map(|dir| dir.join(&file_name))
+            .find(|path| path.is_file()))
+}
+
+/// Builds the arguments that open a terminal running a shell command
+///
+/// # Arguments
+/// * `terminal_command` - Configured `terminal_command`, e.g. `kitty bash -c {cmd}`
+/// * `shell_command` - Command to run, replacing `{cmd}` (or appended when there is none)
+///
+/// # Returns
+/// - Program followed by its arguments (empty if `terminal_command` is blank)
+fn terminal_command_args(terminal_command: &str, shell_command: &str) -> Vec<String> {
+    let mut words = split_command_line(terminal_command);
+    if words.is_empty() {
+        return words;
+    }
+    match words.iter().position(|word| word == "{cmd}") {
+        Some(index) => words[index] = shell_command.to_string(),
+        None => words.push(shell_command.to_string()),
+    }
+    words
+}
+
+/// Asks the user to pick one of the detected programs, the first being the default
+///
+/// # Returns
+/// - Ok(String): Command of the chosen program, or an empty string if none was found
+fn choose_detected_program(kind: &str, detected: &[(String, String)]) -> Result<String, String> {
+    if detected.is_empty() {
+        println!("No {} found on PATH; a default will be used.", kind);
+        return Ok(String::new());
+    }
+
+    println!("\nDetected {}s:", kind);
+    for (i, (name, command)) in detected.iter().enumerate() {
+        println!("  {}. {} ({})", i + 1, name, command);
+    }
+    loop {
+        print!("Preferred {} [1]: ", kind);
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut input = String::new();
+        io::stdin().read_line(&mut input).map_err(|e| e.to_string())?;
+        let input = input.trim();
+        if input.is_empty() {
+            return Ok(detected[0].1.clone());
+        }
+        match input.parse::<usize>() {
+            Ok(choice) if choice >= 1 && choice <= detected.len() => return Ok(detected[choice - 1].1.clone()),
+            _ => println!("Please enter a number from 1 to {}.", detected.len()),
+        }
+    }
+}
+
+/// Detects installed terminal emulators and editors and lets the user pick their preferred ones
+///
+/// Terminals are only asked about on Linux and the BSDs; the other platforms
+/// open sessions with their own terminal. $EDITOR, when set, is offered first.
+///
+/// # Returns
+/// - Ok((terminal_command, editor)): Chosen commands (empty when none was found)
+fn setup_terminal_and_editor() -> Result<(String, String), String> {
+    let mut terminal_command = String::new();
+    if cfg!(target_os = "linux") || is_bsd() {
+        let terminals: Vec<(String, String)> = KNOWN_TERMINAL_COMMANDS.iter()
+            .filter(|(name, _)| find_on_path(name).is_some())
+            .map(|(name, command)| (name.to_string(), command.to_string()))
+            .collect();
+        terminal_command = choose_detected_program("terminal emulator", &terminals)?;
+    }
+
+    let mut editors: Vec<(String, String)> = Vec::new();
+    if let Ok(env_editor) = std::env::var("EDITOR") {
+        if !env_editor.trim().is_empty() {
+            editors.push(("$EDITOR".to_string(), env_editor.trim().to_string()));
+        }
+    }
+    for (name, command) in KNOWN_EDITOR_COMMANDS {
+        if find_on_path(name).is_some() && !editors.iter().any(|(_, existing)| existing == command) {
+            editors.push((name.to_string(), command.to_string()));
+        }
+    }
+    let editor = choose_detected_program("editor", &editors)?;
+
+    Ok((terminal_command, editor))
 }
 
 /// Prompts for llama.cpp executable path during setup
@@ -255,13 +789,7 @@ fn setup_llama_cpp_directory() -> Result<String, String> {
     println!("(e.g., /path/to/llama.cpp/build/bin/llama-cli");
     println!(" or    /path/to/llama.cpp/build/bin)");
     
-    print!("Path to llama.cpp's llama-cli: ");
-    io::stdout().flush().map_err(|e| e.to_string())?;
-    
-    let mut input = String::new();
-    io::stdin()
-        .read_line(&mut input)
-        .map_err(|e| format!("Failed to read input: {}", e))?;
+    let input = read_path_input("Path to llama.cpp's llama-cli: ")?;
     
     let path = input.trim();
 
@@ -270,15 +798,14 @@ fn setup_llama_cpp_directory() -> Result<String, String> {
     let normalized_path_buf = PathBuf::from(&normalized_path);
 
     // Check if the path points directly to llama-cli
-    if normalized_path_buf.is_file() {
-        if normalized_path_buf.file_name()
+    if normalized_path_buf.is_file()
+        && normalized_path_buf.file_name()
             .and_then(|f| f.to_str())
             .map(|s| s.contains("llama-cli"))
             .unwrap_or(false) 
         {
             return Ok(normalized_path);
         }
-    }
 
     // If it's a directory, look for llama-cli inside it
     if normalized_path_buf.is_dir() {
@@ -304,6 +831,8 @@ fn run_query_gguf_setup_wizard() -> Result<SetupWizardResult, String> {
         log_directory_path: String::new(),
         logging_enabled: true,
         llama_cpp_directory: String::new(),
+        terminal_command: String::new(),
+        editor: String::new(),
     };
 
     // Get llama.cpp directory first
@@ -345,6 +874,11 @@ fn run_query_gguf_setup_wizard() -> Result<SetupWizardResult, String> {
             }
         }
     }
+    // Pick the terminal emulator and editor to use
+    let (terminal_command, editor) = setup_terminal_and_editor()?;
+    wizard_result.terminal_command = terminal_command;
+    wizard_result.editor = editor;
+
     // // Configure logging
     // match prompt_yes_no("Enable Save and Print history.") {
     //     Ok(enable_logging) => {
@@ -369,9 +903,9 @@ fn normalize_path(path: &str) -> Result<String, String> {
     let path = path.trim();
     
     // Handle home directory expansion if path starts with ~
-    let expanded_path = if path.starts_with('~') {
+    let expanded_path = if let Some(rest) = path.strip_prefix('~') {
         match std::env::var("HOME") {
-            Ok(home) => format!("{}{}", home, &path[1..]),
+            Ok(home) => format!("{}{}", home, rest),
             Err(_) => return Err("Could not expand home directory (~)".to_string()),
         }
     } else {
@@ -400,13 +934,7 @@ fn normalize_path(path: &str) -> Result<String, String> {
 
 /// Modified prompt_for_directory to use path normalization
 fn prompt_for_directory(prompt: &str) -> Result<String, String> {
-    print!("{}: ", prompt);
-    io::stdout().flush().map_err(|e| e.to_string())?;
-
-    let mut input = String::new();
-    io::stdin()
-        .read_line(&mut input)
-        .map_err(|e| e.to_string())?;
+    let input = read_path_input(&format!("{}: ", prompt))?;
     
     let input = input.trim();
     
@@ -469,33 +997,188 @@ fn generate_toml_config(wizard_result: &SetupWizardResult) -> String {
     for (i, path) in wizard_result.gguf_model_directories.iter().enumerate() {
         toml_content.push_str(&format!("gguf_model_directory_{} = \"{}\"\n", i + 1, path));
     }
-    toml_content.push_str("\n");
+    toml_content.push('\n');
 
     // Add prompt directories
     for (i, path) in wizard_result.prompt_file_directories.iter().enumerate() {
-        toml_content.push_str(&format!("prompt_file_directory_{} = \"{}\"\n", i + 1, path));
+        toml_content.push_str(&format!("prompt_directory_{} = \"{}\"\n", i + 1, path));
     }
     
     // Add prompt directory
-    toml_content.push_str(&format!("prompt_directory = \"prompts\"\n\n"));
+    toml_content.push_str("prompt_directory = \"prompts\"\n\n");
+
+    // Add the terminal emulator and editor picked during setup
+    if !wizard_result.terminal_command.is_empty() {
+        toml_content.push_str(&format!("terminal_command = \"{}\"\n", wizard_result.terminal_command));
+    }
+    if !wizard_result.editor.is_empty() {
+        toml_content.push_str(&format!("editor = \"{}\"\n", wizard_result.editor));
+    }
+    if !wizard_result.terminal_command.is_empty() || !wizard_result.editor.is_empty() {
+        toml_content.push('\n');
+    }
 
     // Add commented examples for future reference
     toml_content.push_str("# Configuration Examples:\n");
     toml_content.push_str("# Additional model directories can be added as:\n");
     toml_content.push_str("# gguf_model_directory_2 = \"/path/to/more/models\"\n");
-    toml_content.push_str("# gguf_model_directory_3 = \"/another/path/to/models\"\n\n");
+    toml_content.push_str("# gguf_model_directory_3 = \"/another/path/to/models\"\n");
+    toml_content.push_str("# Where `query_gguf models archive` moves models not used for a while (e.g. a big slow disk):\n");
+    toml_content.push_str("# model_archive_directory = \"/mnt/archive/models\"\n\n");
     
     toml_content.push_str("# Additional prompt directories can be added as:\n");
     toml_content.push_str("# prompt_directory_2 = \"/path/to/more/prompts\"\n");
     toml_content.push_str("# prompt_directory_3 = \"/another/path/to/prompts\"\n\n");
+
+    toml_content.push_str("# On spinning disks or network mounts, limit how fast model, prompt, and directory scans read\n");
+    toml_content.push_str("# (KiB per second), and stop scans that take too long (the last complete scan fills in the rest):\n");
+    toml_content.push_str("# scan_io_limit_kb = 2048\n");
+    toml_content.push_str("# scan_time_budget_secs = 10\n\n");
+
+    toml_content.push_str("# Directory mode skips what .gitignore and .query_gguf_ignore files list, and these names\n");
+    toml_content.push_str("# (besides .git, target, node_modules, __pycache__, and .venv), and limits file sizes (0 for no limit):\n");
+    toml_content.push_str("# scan_exclude = [\"dist\", \"*.min.js\"]\n");
+    toml_content.push_str("# scan_max_file_kb = 256\n");
+    toml_content.push_str("# scan_max_total_kb = 1024\n\n");
     
+    toml_content.push_str("# Shared prompt library, synced with 'query_gguf prompts sync':\n");
+    toml_content.push_str("# prompt_repo = \"https://github.com/me/prompts\"\n\n");
+
+    toml_content.push_str("# Downloads and prompt sync use the HTTPS_PROXY/HTTP_PROXY/NO_PROXY environment variables.\n");
+    toml_content.push_str("# For TLS-intercepting proxies, set the CA bundle to trust:\n");
+    toml_content.push_str("# ca_bundle_path = \"/etc/ssl/certs/corporate-ca.pem\"\n");
+    toml_content.push_str("# Program used for https connections, curl (default) or openssl:\n");
+    toml_content.push_str("# https_backend = \"curl\"\n\n");
+
+    toml_content.push_str("# Never use the network (downloads and prompt sync are refused); same as --offline:\n");
+    toml_content.push_str("# offline = true\n\n");
+
+    toml_content.push_str("# Programs that {{cmd:...}} placeholders in prompts may run (e.g. {{cmd:git log -5 --oneline}}).\n");
+    toml_content.push_str("# Commands are shown for confirmation first unless prompt_command_confirm = false:\n");
+    toml_content.push_str("# prompt_command_allowlist = \"git,ps,journalctl\"\n\n");
+
+    toml_content.push_str("# Experimental agent mode ('query_gguf agent <mode>'): programs the model may run,\n");
+    toml_content.push_str("# and the directory it may read files under (default: the current directory):\n");
+    toml_content.push_str("# agent_command_allowlist = \"git,ls,grep\"\n");
+    toml_content.push_str("# agent_file_root = \"/home/me/projects\"\n");
+    toml_content.push_str("# Agent commands run with no network and read-only files in a sandbox:\n");
+    toml_content.push_str("# auto (default), bwrap, firejail, sandbox-exec, or off. Tools can opt out with sandbox = false.\n");
+    toml_content.push_str("# On Windows, psexec (Sysinternals, accept its license once) only lowers privileges; the network stays reachable.\n");
+    toml_content.push_str("# agent_sandbox = \"auto\"\n");
+    toml_content.push_str("# In WSL, Windows paths (C:\\models) are read as /mnt/c/models. Launch in Windows Terminal:\n");
+    toml_content.push_str("# wsl_terminal = \"wt\"\n");
+    toml_content.push_str("# Terminal emulator for new sessions on Linux/BSD ({cmd} is the session's command),\n");
+    toml_content.push_str("# and the editor for 'config edit' and similar (default: $EDITOR, then nano/notepad):\n");
+    toml_content.push_str("# terminal_command = \"kitty bash -c {cmd}\"\n");
+    toml_content.push_str("# editor = \"code --wait\"\n");
+    toml_content.push_str("# Read chat and --print replies aloud: the reply text is written to this command's stdin:\n");
+    toml_content.push_str("# tts_command = \"/home/me/bin/speak.sh\"\n");
+    toml_content.push_str("# Speak instead of typing (--dictate, /dictate in chat): this command's stdout is the message:\n");
+    toml_content.push_str("# stt_command = \"/home/me/bin/record_and_whisper.sh\"\n");
+    toml_content.push_str("# On Windows, run llama-cli inside WSL (llama_cli_path is then a Linux path):\n");
+    toml_content.push_str("# windows_run_in_wsl = true\n");
+    toml_content.push_str("# More agent tools go in tables at the end of the file; {name} in the command\n");
+    toml_content.push_str("# is replaced by the model's argument of that name. Path arguments (path, file, dir,\n");
+    toml_content.push_str("# *_path, *_file, *_dir, or those in path_parameters = \"a,b\") must be under agent_file_root:\n");
+    toml_content.push_str("# [tools.find_todos]\n");
+    toml_content.push_str("# description = \"Search the project for TODO comments\"\n");
+    toml_content.push_str("# parameters = '{\"path\": \"directory to search\"}'\n");
+    toml_content.push_str("# command = \"grep -rn TODO {path}\"\n\n");
+
+    toml_content.push_str("# Run llama-cli in the official llama.cpp container instead of a local build\n");
+    toml_content.push_str("# (model and prompt directories are mounted read-only; see 'query_gguf docker'):\n");
+    toml_content.push_str("# launch_backend = \"docker\"\n");
+    toml_content.push_str("# docker_image = \"ghcr.io/ggml-org/llama.cpp:light\"\n");
+    toml_content.push_str("# Pass GPUs to the container (the default image is then the -cuda one):\n");
+    toml_content.push_str("# docker_gpus = \"all\"\n\n");
+
+    toml_content.push_str("# Aliases: one word for a whole query_gguf command line (in a table at the end of the file);\n");
+    toml_content.push_str("# 'query_gguf review --print' then runs mode 2 with these options plus --print:\n");
+    toml_content.push_str("# [aliases]\n");
+    toml_content.push_str("# review = \"2 --var 'Project name=query_gguf' -q 'review the latest changes'\"\n\n");
+
+    toml_content.push_str("# Skip the 'initial question' step when launching saved modes:\n");
+    toml_content.push_str("# quick_question = false\n\n");
+
+    toml_content.push_str("# Before launching, show the fully rendered prompt ($PAGER) with a token estimate,\n");
+    toml_content.push_str("# to approve, edit, or cancel it:\n");
+    toml_content.push_str("# confirm_prompt = true\n\n");
+
+    toml_content.push_str("# Cache responses of non-interactive (--print) runs:\n");
+    toml_content.push_str("# response_cache = true\n\n");
+
+    toml_content.push_str("# Extra llama.cpp builds (e.g. GPU builds) go in tables at the end of the file,\n");
+    toml_content.push_str("# and are picked per mode with backend=<name> in the mode entry:\n");
+    toml_content.push_str("# [binaries.cuda]\n");
+    toml_content.push_str("# path = \"/path/to/llama.cpp-cuda/build/bin/llama-cli\"\n\n");
+
+    toml_content.push_str("# Modes can also be tables at the end of the file (new modes are saved this way);\n");
+    toml_content.push_str("# the table name is the mode id, and other keys are the mode's parameters:\n");
+    toml_content.push_str("# [mode.fast]\n");
+    toml_content.push_str("# name = \"Fast\"\n");
+    toml_content.push_str("# description = \"quick answers | no detail\"\n");
+    toml_content.push_str("# model = \"/path/to/model.gguf\"\n");
+    toml_content.push_str("# prompt = \"prompts/short.txt\"\n");
+    toml_content.push_str("# temp = 0.3\n\n");
+
+    toml_content.push_str("# Modes with remote=user@host run llama-cli on that machine over ssh (the model path\n");
+    toml_content.push_str("# is a path there); the prompt is copied over with scp and the log fetched back:\n");
+    toml_content.push_str("# mode_4 = \"/srv/models/big.gguf|prompts/code.txt|remote=me@gpubox|Big Coder|on the server\"\n");
+    toml_content.push_str("# remote_llama_cli_path = \"/opt/llama.cpp/build/bin/llama-cli\"\n\n");
+
+    toml_content.push_str("# Modes with endpoint=<url> talk to an OpenAI-compatible server (e.g. llama-server on\n");
+    toml_content.push_str("# another machine) with 'chat' and --print; the model field is the model name there:\n");
+    toml_content.push_str("# mode_5 = \"gpt-4o-mini|prompts/code.txt|endpoint=https://api.openai.com|Cloud|hosted\"\n");
+    toml_content.push_str("# API keys are never read from this file. They come from the environment variable\n");
+    toml_content.push_str("# named by api_key_env, else the OS keychain (service query_gguf, account = host):\n");
+    toml_content.push_str("#   secret-tool store --label=query_gguf service query_gguf host api.openai.com\n");
+    toml_content.push_str("#   security add-generic-password -s query_gguf -a api.openai.com -w\n");
+    toml_content.push_str("# else they are asked for once per run when the server wants one:\n");
+    toml_content.push_str("# api_key_env = \"OPENAI_API_KEY\"\n");
+    toml_content.push_str("# Endpoint requests: timeout per attempt, retries (with backoff) on connection\n");
+    toml_content.push_str("# errors/429/5xx, and at most this many requests per minute per host (0 = no limit):\n");
+    toml_content.push_str("# endpoint_timeout_secs = 300\n");
+    toml_content.push_str("# endpoint_retries = 3\n");
+    toml_content.push_str("# endpoint_rate_limit = 20\n\n");
+
+    toml_content.push_str("# Set strict = true to treat unknown keys and malformed modes as errors:\n");
+    toml_content.push_str("# strict = true\n\n");
+
     toml_content.push_str("# example llama.cpp llama-cli path:\n");
     toml_content.push_str("# llama_cli_path = \"/home/oopsy/llama.cpp/build/bin/llama-cli\"\n");
    
     
     
     toml_content.push_str("# Saved modes will appear as:\n");
-    toml_content.push_str("# mode_1 = \"model_path|prompt_path|temp=0.8|top_k=40|description\"\n\n");
+    toml_content.push_str("# mode_1 = \"model_path|prompt_path|temp=0.8|top_k=40|id=mode-name|mode name|description\"\n");
+    toml_content.push_str("# A mode can inherit model, prompt, and parameters from another mode by id,\n");
+    toml_content.push_str("# leaving model/prompt empty and overriding only what differs:\n");
+    toml_content.push_str("# mode_2 = \"||extends=mode-name|temp=0.3|careful mode|lower temperature\"\n");
+    toml_content.push_str("# default_mode can be a mode id (e.g. default_mode = \"mode-name\") or a number\n");
+    toml_content.push_str("# llama-cli output: display_prompt=true shows the prompt in interactive sessions\n");
+    toml_content.push_str("# (it is never echoed into --print output), verbose_prompt=true adds --verbose-prompt\n");
+    toml_content.push_str("# (prompt tokens), log_disable=true adds --log-disable (no llama.cpp log output):\n");
+    toml_content.push_str("# mode_6 = \"||extends=mode-name|display_prompt=true|verbose_prompt=true|debug|see the prompt\"\n");
+    toml_content.push_str("# Interactive sessions: multiline_input=true adds --multiline-input (end a line with \\\n");
+    toml_content.push_str("# to continue it), in_prefix/in_suffix are put before/after each of your inputs, and\n");
+    toml_content.push_str("# conversation=true/false turns llama-cli's chat template mode on (-cnv) or off (-no-cnv):\n");
+    toml_content.push_str("# mode_7 = \"||extends=mode-name|multiline_input=true|in_prefix=User: |in_suffix=Assistant:|chat|paste friendly\"\n");
+    toml_content.push_str("# Limit what directory mode and attachments (piped context, {{cmd:...}}) may add to a\n");
+    toml_content.push_str("# mode's prompt; bigger prompts stop with an error instead of overflowing ctx_size:\n");
+    toml_content.push_str("# mode_3 = \"||extends=mode-name|max_prompt_bytes=6000|max_files=20|small model|guarded\"\n");
+    toml_content.push_str("# Launch conditions: a mode whose conditions are not met is grayed out and refuses to\n");
+    toml_content.push_str("# launch (unless --force): only_if=on_ac_power, hours=09:00-18:00 (local time,\n");
+    toml_content.push_str("# comma separated ranges), min_free_ram_gb=24:\n");
+    toml_content.push_str("# mode_9 = \"||extends=mode-name|only_if=on_ac_power|hours=19:00-08:00|min_free_ram_gb=24|big model|evenings\"\n");
+    toml_content.push_str("# Canary prompts for `query_gguf mode test <mode>` (files relative to ~/query_gguf, with\n");
+    toml_content.push_str("# [tests.<name>] tables of prompt = \"...\" and contains/not_contains = [\"...\"]):\n");
+    toml_content.push_str("# mode_10 = \"||extends=mode-name|tests=tests/coder.toml|coder|checked\"\n");
+    toml_content.push_str("# A mode with disabled=<why> is grayed out and refuses to launch (unless --force);\n");
+    toml_content.push_str("# `models archive` disables the modes of the models it moves this way.\n");
+    toml_content.push_str("# Image modes (kind=image) generate pictures with stable-diffusion.cpp; the prompt file,\n");
+    toml_content.push_str("# if not blank, is added to each description as a style:\n");
+    toml_content.push_str("# sd_cli_path = \"/home/me/stable-diffusion.cpp/build/bin/sd\"\n");
+    toml_content.push_str("# mode_8 = \"/models/sd-v1-5.q8_0.gguf||kind=image|steps=20|cfg_scale=7|output_dir=/home/me/images|sd15|local images\"\n\n");
 
 
     toml_content
@@ -511,7 +1194,7 @@ fn generate_toml_config(wizard_result: &SetupWizardResult) -> String {
 /// 
 fn save_query_gguf_config(config_content: &str) -> Result<(), String> {
     let config_path = get_config_path()?;
-    fs::write(&config_path, config_content)
+    write_config_file(&config_path, config_content, "setup wizard")
         .map_err(|e| format!("Failed to save configuration: {}", e))?;
     println!("Configuration saved to: {}", config_path.display());
     Ok(())
@@ -568,6 +1251,163 @@ fn validate_query_gguf_directories(wizard_result: &SetupWizardResult) -> Result<
     Ok(())
 }
 
+/// Append-only journal of config changes, in the application directory
+const CONFIG_JOURNAL_FILE: &str = "config_journal.log";
+
+/// Entries shown by `config log` without a count
+const CONFIG_LOG_DEFAULT_ENTRIES: usize = 30;
+
+/// Formats seconds since the Unix epoch as "YYYY-MM-DD HH:MM:SS UTC"
+fn format_unix_time(seconds: u64) -> String {
+    // Days to civil date (Howard Hinnant's algorithm)
+    let days = (seconds / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let time = seconds % 86_400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// The current user and machine, e.g. "alice@laptop"
+fn current_user_and_host() -> String {
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+    let host = std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    format!("{}@{}", user, host)
+}
+
+/// Reads the values of config content, keys of [tables] as "table.key"
+fn config_key_values(content: &str) -> Vec<(String, String)> {
+    toml_lite::parse(content).0
+        .flatten()
+        .into_iter()
+        .map(|(key, value)| (key, value.to_text()))
+        .collect()
+}
+
+/// Describes which keys differ between two versions of the config
+///
+/// Changed mode entries list only the parts that differ, e.g.
+/// `mode_2: temp=0.8 -> temp=0.3`.
+fn describe_config_changes(old: &str, new: &str) -> Vec<String> {
+    let old_values = config_key_values(old);
+    let new_values = config_key_values(new);
+    let find = |values: &[(String, String)], key: &str| values.iter()
+        .find(|(other, _)| other == key)
+        .map(|(_, value)| value.clone());
+    let mut changes = Vec::new();
+
+    for (key, new_value) in &new_values {
+        match find(&old_values, key) {
+            None => changes.push(format!("added {} = \"{}\"", key, one_line_preview(new_value, 80))),
+            Some(old_value) if old_value == *new_value => (),
+            Some(old_value) if old_value.contains('|') && new_value.contains('|') => {
+                let old_parts: Vec<&str> = old_value.split('|').collect();
+                let new_parts: Vec<&str> = new_value.split('|').collect();
+                let removed: Vec<&str> = old_parts.iter().filter(|part| !new_parts.contains(part)).copied().collect();
+                let added: Vec<&str> = new_parts.iter().filter(|part| !old_parts.contains(part)).copied().collect();
+                changes.push(format!("{}: {} -> {}", key, removed.join("|"), added.join("|")));
+            },
+            Some(old_value) => changes.push(format!(
+                "{}: \"{}\" -> \"{}\"", key, one_line_preview(&old_value, 60), one_line_preview(new_value, 60)
+            )),
+        }
+    }
+    for (key, _) in &old_values {
+        if find(&new_values, key).is_none() {
+            changes.push(format!("removed {}", key));
+        }
+    }
+    changes
+}
+
+/// Appends the key changes between two versions of the config to the journal
+///
+/// Nothing is written when no key changed. A journal that cannot be
+/// written only gives a warning; the config change itself stands.
+///
+/// # Arguments
+/// * `action` - What changed the config, e.g. "mode saved (coder)"
+fn record_config_changes(action: &str, old: &str, new: &str) {
+    let changes = describe_config_changes(old, new);
+    if changes.is_empty() {
+        return;
+    }
+    let when = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let prefix = format!("{}\t{}\t{}", format_unix_time(when), current_user_and_host(), action);
+    let entry: String = changes.iter().map(|change| format!("{}\t{}\n", prefix, change)).collect();
+
+    let result = get_app_base_dir().and_then(|dir| {
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(CONFIG_JOURNAL_FILE))
+            .and_then(|mut file| file.write_all(entry.as_bytes()))
+            .map_err(|e| e.to_string())
+    });
+    if let Err(e) = result {
+        println!("Warning: Could not write the config journal: {}", e);
+    }
+}
+
+/// Writes the config file and records what changed in the config journal
+///
+/// # Arguments
+/// * `config_path` - The config file
+/// * `content` - New content of the config
+/// * `action` - What is changing the config, for the journal
+fn write_config_file(config_path: &Path, content: &str, action: &str) -> Result<(), String> {
+    let old_content = fs::read_to_string(config_path).unwrap_or_default();
+    fs::write(config_path, content)
+        .map_err(|e| format!("Failed to write config to {}: {}", config_path.display(), e))?;
+    record_config_changes(action, &old_content, content);
+    Ok(())
+}
+
+/// Shows the latest config journal entries: `query_gguf config log [N]`
+fn show_config_log(args: &[String]) -> Result<(), String> {
+    let count = match args.first() {
+        Some(count) => count.parse::<usize>()
+            .map_err(|_| format!("Invalid count: {}. Use: config log [N]", count))?,
+        None => CONFIG_LOG_DEFAULT_ENTRIES,
+    };
+    let journal_path = get_app_base_dir()?.join(CONFIG_JOURNAL_FILE);
+    let journal = match fs::read_to_string(&journal_path) {
+        Ok(journal) => journal,
+        Err(_) => {
+            println!("No config changes recorded yet ({}).", journal_path.display());
+            return Ok(());
+        },
+    };
+
+    let lines: Vec<&str> = journal.lines().collect();
+    let mut last_heading = "";
+    for line in &lines[lines.len().saturating_sub(count)..] {
+        let fields: Vec<&str> = line.splitn(4, '\t').collect();
+        let [when, who, action, change] = fields.as_slice() else { continue };
+        // One heading per change (all its key changes share time, user, and action)
+        let heading = &line[..line.len() - change.len()];
+        if heading != last_heading {
+            println!("\n{}  {}  {}", when, who, action);
+            last_heading = heading;
+        }
+        println!("    {}", change);
+    }
+    Ok(())
+}
+
 /// Creates a backup of an existing configuration file
 /// 
 /// Copies the config file to a timestamped backup in the same directory:
@@ -575,7 +1415,8 @@ fn validate_query_gguf_directories(wizard_result: &SetupWizardResult) -> Result<
 /// To:   ~/query_gguf/query_gguf_config_TIMESTAMP.toml.bak
 /// 
 /// # Returns
-/// - Ok(()): Backup created successfully
+/// - Ok(Some(PathBuf)): Backup created successfully, at this path
+/// - Ok(None): There is no config to back up
 /// - Err(String): Error message if backup fails
 /// 
 /// # Error Cases
@@ -583,13 +1424,13 @@ fn validate_query_gguf_directories(wizard_result: &SetupWizardResult) -> Result<
 /// - Unable to create backup (permissions/disk space)
 /// - Path resolution fails
 /// 
-fn backup_existing_config() -> Result<(), String> {
+fn backup_existing_config() -> Result<Option<PathBuf>, String> {
     // CHANGE 1: Get absolute path to current config
     let config_path = get_config_path()?;
 
     // CHANGE 2: Only proceed if config exists
     if !config_path.exists() {
-        return Ok(());  // No config to backup
+        return Ok(None);  // No config to backup
     }
 
     // CHANGE 3: Create backup path in same directory
@@ -603,7 +1444,7 @@ fn backup_existing_config() -> Result<(), String> {
         .map_err(|e| format!("Failed to create backup: {}", e))?;
 
     println!("Created backup of existing config: {}", backup_path.display());
-    Ok(())
+    Ok(Some(backup_path))
 }
 
 /// Main function to handle the setup process
@@ -644,6 +1485,23 @@ fn handle_query_gguf_setup() -> Result<(), String> {
 mod tests {
     use super::*;
 
+    /// Builds a mode with default parameters for tests
+    fn make_test_mode(id: &str) -> ChatModeConfig {
+        ChatModeConfig {
+            id: id.to_string(),
+            backend: String::new(),
+            remote: String::new(),
+            endpoint: String::new(),
+            extends: String::new(),
+            kind: String::new(),
+            name: id.to_string(),
+            description: String::new(),
+            model_path: String::new(),
+            prompt_path: String::new(),
+            parameters: LlamaCppParameters::default(),
+        }
+    }
+
     #[test]
     fn test_generate_toml_config() {
         let test_result = SetupWizardResult {
@@ -652,6 +1510,8 @@ mod tests {
             log_directory_path: "/path/to/logs".to_string(),
             logging_enabled: true,
             llama_cpp_directory: "/path/to/llama-cli".to_string(), // Added this line
+            terminal_command: "kitty bash -c {cmd}".to_string(),
+            editor: "code --wait".to_string(),
         };
 
         let config = generate_toml_config(&test_result);
@@ -661,6 +1521,348 @@ mod tests {
         assert!(config.contains("/path/to/prompts"));
         assert!(config.contains("/path/to/logs"));
         assert!(config.contains("/path/to/llama-cli")); // Added this check
+        assert!(config.contains("terminal_command = \"kitty bash -c {cmd}\""));
+        assert!(config.contains("editor = \"code --wait\""));
+    }
+
+    #[test]
+    fn test_parse_ask_args() {
+        let to_args = |words: &[&str]| words.iter().map(|word| word.to_string()).collect::<Vec<String>>();
+        let (selector, options) = parse_ask_args(&to_args(&["2", "what is rust?", "--no-cache", "--max-seconds", "30"])).unwrap();
+        assert_eq!(selector, "2");
+        assert_eq!(options.question.as_deref(), Some("what is rust?"));
+        assert!(options.no_cache);
+        assert_eq!(options.max_seconds, Some(30));
+
+        // No question: read from stdin later
+        let (_, options) = parse_ask_args(&to_args(&["coder", "--stdin-as-context"])).unwrap();
+        assert!(options.question.is_none() && options.stdin_as_context);
+        let (_, options) = parse_ask_args(&to_args(&["coder", "-"])).unwrap();
+        assert_eq!(options.question.as_deref(), Some("-"));
+        let (_, options) = parse_ask_args(&to_args(&["coder", "-q", "hi"])).unwrap();
+        assert_eq!(options.question.as_deref(), Some("hi"));
+
+        assert!(parse_ask_args(&[]).is_err());
+        assert!(parse_ask_args(&to_args(&["2", "hi", "--pager"])).is_err());
+        assert!(parse_ask_args(&to_args(&["2", "hi", "extra words"])).is_err());
+    }
+
+    #[test]
+    fn test_find_duplicate_prompts() {
+        let file = |path: &str, content: &str| (path.to_string(), content.to_string());
+        let files = vec![
+            file("/p/review.txt", "You review code.\nBe brief."),
+            file("/p/blank.txt", ""),
+            file("/p/review-copy.txt", "You review code.\nBe brief."),
+            file("/p/other.txt", "You write docs."),
+            file("/p/blankprompt.txt", "\n"),
+            file("/p/review-2.txt", "You review  code.\n\nBe brief.\n"),
+            file("/p/docs.txt", "You write docs.\n"),
+        ];
+        assert_eq!(find_duplicate_prompts(&files), vec![
+            PromptDuplicates {
+                paths: vec!["/p/review.txt".to_string(), "/p/review-copy.txt".to_string(), "/p/review-2.txt".to_string()],
+                exact: false,
+            },
+            PromptDuplicates { paths: vec!["/p/other.txt".to_string(), "/p/docs.txt".to_string()], exact: false },
+        ]);
+        assert!(find_duplicate_prompts(&files[..3])[0].exact);
+
+        let content = "mode_1 = \"/m.gguf|/p/review-copy.txt|id=a|A|x\"\nmode_2 = \"||extends=a|id=b|B|y\"\n";
+        let updated = update_mode_entry_prompt_path(content, 0, "/p/review.txt").unwrap();
+        assert_eq!(update_mode_entry_prompt_path(&updated, 1, "/p/review.txt").unwrap(), content.replace("review-copy", "review"));
+    }
+
+    #[test]
+    fn test_models_archive() {
+        assert_eq!(parse_age_days("90d"), Some(90));
+        assert_eq!(parse_age_days("12w"), Some(84));
+        assert_eq!(parse_age_days("30"), Some(30));
+        assert_eq!(parse_age_days("3 months"), None);
+
+        let stats = "100\tchat\tcoder\t/m/a.gguf\t1\t0\t0\n300\tprint\tcoder\t/m/a.gguf\t1\t0\t0\t5\n200\tprint\tsmall\t/m/b.gguf\t1\t0\t0\nbad line\n";
+        assert_eq!(
+            latest_use_by_model(stats),
+            vec![("/m/a.gguf".to_string(), 300), ("/m/b.gguf".to_string(), 200)]
+        );
+
+        // Entries inheriting the model (extends=) keep their empty model path
+        let content = "mode_1 = \"/m/a.gguf|/p.txt|temp=0.8|id=coder|Coder|x\"\nmode_2 = \"||extends=coder|id=careful|Careful|y\"\n";
+        let updated = update_mode_entry_model_path(content, 0, "/mnt/archive/a.gguf").unwrap();
+        let updated = update_mode_entry_model_path(&updated, 1, "/mnt/archive/a.gguf").unwrap();
+        let updated = update_mode_entry_parameters(&updated, 0, &[("disabled", "model archived".to_string())]).unwrap();
+        assert_eq!(
+            updated,
+            "mode_1 = \"/mnt/archive/a.gguf|/p.txt|disabled=model archived|temp=0.8|id=coder|Coder|x\"\nmode_2 = \"||extends=coder|id=careful|Careful|y\"\n"
+        );
+        assert!(find_mode_entry_problems("/mnt/archive/a.gguf|/p.txt|disabled=model archived|x|y").is_empty());
+    }
+
+    #[test]
+    fn test_session_logs() {
+        let pid = std::process::id();
+        let content = format!("[[session kind=chat mode=coder pid={}]]\n[[user 10:00:01]]\nhi\n[[assistant 10:00:02]]\nhello\nthere\n", pid);
+        let status = parse_session_log_status(&content);
+        assert_eq!(status, SessionLogStatus { kind: "chat".to_string(), mode_id: "coder".to_string(), pid: Some(pid), ended: false });
+        assert!(is_session_running(&status));
+        let ended = parse_session_log_status(&format!("{}{}\n", content, SESSION_LOG_END_MARKER));
+        assert!(ended.ended && !is_session_running(&ended));
+
+        // Server logs: the pid comes after llama-server's first lines
+        let server = parse_session_log_status("[[session kind=server mode=big port=8080]]\n[[stderr 09:00:00]]\nloading\n\n[[pid 77]]\n");
+        assert_eq!((server.kind.as_str(), server.pid), ("server", Some(77)));
+
+        let mut role = String::new();
+        let rendered: Vec<String> = content.lines()
+            .filter_map(|line| render_session_log_line(line, &mut role, false))
+            .collect();
+        assert_eq!(rendered[1], "--- user 10:00:01 ---");
+        assert_eq!(rendered[4], "hello");
+        assert_eq!(role, "assistant");
+        assert_eq!(render_session_log_line("there", &mut role, true).unwrap(), format!("{}there{}", STYLE_STRING, STYLE_RESET));
+        assert_eq!(render_session_log_line("[[pid 77]]", &mut role, false), None);
+    }
+
+    #[test]
+    fn test_mode_tests() {
+        assert_eq!(toml_string_list("[\"a.toml\", 'b.toml']"), vec!["a.toml", "b.toml"]);
+        assert_eq!(toml_string_list("tests/coder.toml"), vec!["tests/coder.toml"]);
+        assert!(toml_string_list("[]").is_empty());
+
+        let mut params = LlamaCppParameters::default();
+        apply_parameters_from_parts(&mut params, &["tests=tests/coder.toml,tests/extra.toml"]);
+        assert_eq!(params.tests, "tests/coder.toml,tests/extra.toml");
+        assert!(find_mode_entry_problems("||tests=tests/coder.toml|x|y").is_empty());
+
+        let tests = parse_mode_tests("[tests.capital]\nprompt = \"Capital of France?\"\ncontains = [\"Paris\"]\nnot_contains = \"Lyon\"\nn = 32\n").unwrap();
+        assert_eq!(tests[0].contains, vec!["Paris"]);
+        assert_eq!(tests[0].max_tokens, 32);
+        assert_eq!(check_mode_test_reply(&tests[0], "It is Paris."), None);
+        assert_eq!(check_mode_test_reply(&tests[0], "Paris, not Lyon").unwrap(), "contains \"Lyon\"");
+        assert_eq!(check_mode_test_reply(&tests[0], "Rome").unwrap(), "missing \"Paris\"");
+        assert!(parse_mode_tests("[tests.empty]\nprompt = \"hi\"\n").is_err());
+
+        let previous = ModeTestRecord {
+            model: "/m/a.gguf".to_string(),
+            prompt_hash: "01".to_string(),
+            results: vec![("capital".to_string(), true), ("haiku".to_string(), false)],
+        };
+        assert_eq!(ModeTestRecord::parse(&previous.format()), previous);
+        let current = ModeTestRecord {
+            results: vec![("capital".to_string(), false), ("haiku".to_string(), true), ("new".to_string(), false)],
+            ..previous.clone()
+        };
+        assert_eq!(compare_mode_test_runs(&previous, &current), (vec!["capital".to_string()], vec!["haiku".to_string()]));
+    }
+
+    #[test]
+    fn test_eval_suite() {
+        let pattern = AnswerPattern::parse(r"(?i)^\s*paris|capital is [A-Z][a-z]+\.?$").unwrap();
+        assert!(pattern.is_match("  Paris, of course"));
+        assert!(pattern.is_match("The capital is Rome."));
+        assert!(!pattern.is_match("I think it is Paris"));
+        let digits = AnswerPattern::parse(r"\d+ apples?").unwrap();
+        assert!(digits.is_match("you have 12 apples left") && digits.is_match("1 apple"));
+        assert!(!digits.is_match("some apples"));
+        assert!(AnswerPattern::parse("[^x]y").unwrap().is_match("ay"));
+        assert!(!AnswerPattern::parse("[^x]y").unwrap().is_match("xy"));
+        assert!(AnswerPattern::parse("(a|b)").is_err());
+        assert!(AnswerPattern::parse("*a").is_err());
+        assert!(AnswerPattern::parse("[a-").is_err());
+
+        let suite = parse_eval_suite("[suite]\nmodes = \"small, coder\"\njudge = \"big\"\nrubric = \"Correct\"\n\n\
+            [questions.capital]\nquestion = \"Capital of France?\"\nexpect = '(?i)paris'\n\n\
+            [questions.haiku]\nquestion = \"A haiku\"\n").unwrap();
+        assert_eq!(suite.modes, vec!["small", "coder"]);
+        assert_eq!((suite.max_tokens, suite.seed), (EVAL_DEFAULT_MAX_TOKENS, 42));
+        assert!(suite.questions[0].expect.is_some() && suite.questions[0].rubric.is_empty());
+        assert_eq!(suite.questions[1].rubric, "Correct");
+        assert!(parse_eval_suite("[questions.a]\nquestion = \"q\"\n").is_err());
+        assert!(parse_eval_suite("[suite]\nmodes = \"a\"\n").is_err());
+
+        assert_eq!(parse_judge_verdict("**PASS**\nIt is right."), Some(true));
+        assert_eq!(parse_judge_verdict("fail: too long"), Some(false));
+        assert_eq!(parse_judge_verdict("The answer is fine"), None);
+
+        let pass = EvalOutcome { passed: true, ..EvalOutcome::default() };
+        let fail = EvalOutcome::default();
+        let matrix = format_eval_matrix(
+            &["capital".to_string(), "haiku".to_string()],
+            &["small".to_string(), "coder".to_string()],
+            &[vec![pass.clone(), fail.clone()], vec![pass.clone(), pass]],
+        );
+        assert_eq!(matrix, "question  small   coder \ncapital   PASS    PASS  \nhaiku     FAIL    PASS  \nscore     1/2     2/2   \n");
+    }
+
+    #[test]
+    fn test_batch_checkpoint() {
+        let inputs = read_batch_inputs("first\r\n\nsecond\nthird\n");
+        assert_eq!(inputs, vec!["first", "second", "third"]);
+
+        let header = batch_checkpoint_header("coder", &inputs);
+        let checkpoint = format!("{}\n{{\"index\":2,\"mode\":\"small\",\"output\":\"three \\\"3\\\"\"}}\n{{\"index\":0,\"output\":\"one\"}}\n{{\"index\":1,\"outp", header);
+        let outputs = parse_batch_checkpoint(&checkpoint, &header, inputs.len()).unwrap();
+        assert_eq!(outputs, vec![
+            Some((String::new(), "one".to_string())),
+            None,
+            Some(("small".to_string(), "three \"3\"".to_string())),
+        ]);
+
+        // A checkpoint of other inputs is not resumed
+        let other_header = batch_checkpoint_header("coder", &inputs[..2]);
+        assert!(parse_batch_checkpoint(&checkpoint, &other_header, 2).is_err());
+
+        assert_eq!(format_eta(42), "42s");
+        assert_eq!(format_eta(250), "4m 10s");
+        assert_eq!(format_eta(7500), "2h 05m");
+
+        assert_eq!(parse_batch_workers("small:2, gpubox").unwrap(), vec![("small".to_string(), 2), ("gpubox".to_string(), 1)]);
+        assert!(parse_batch_workers("small:0").is_err());
+        assert!(parse_batch_workers("small:2,small:1").is_err());
+        assert!(parse_batch_workers(",").is_err());
+    }
+
+    #[test]
+    fn test_determinism_checks() {
+        assert_eq!(first_difference("hello", "hello"), None);
+        assert_eq!(first_difference("hello", "help!"), Some(3));
+        assert_eq!(first_difference("hello", "hello world"), Some(5));
+
+        let outputs: Vec<String> = ["a", "b", "a", "c"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(group_identical_outputs(&outputs), vec![vec![1, 3], vec![2], vec![4]]);
+
+        let mut mode = make_test_mode("det");
+        mode.parameters.thread_count = 1;
+        mode.parameters.gpu_layers = 0;
+        assert!(nondeterminism_hints(&mode).is_empty());
+        mode.parameters.thread_count = 8;
+        mode.parameters.gpu_layers = 99;
+        let hints = nondeterminism_hints(&mode);
+        assert!(hints[0].starts_with("threads=8") && hints[1].starts_with("gpu_layers=99"));
+    }
+
+    #[test]
+    fn test_bench_sweeps() {
+        let to_strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+        assert_eq!(parse_sweep("temp=0.2:1.0:0.2").unwrap(), ("temp".to_string(), to_strings(&["0.2", "0.4", "0.6", "0.8", "1"])));
+        assert_eq!(parse_sweep("top_p=0.8,0.9,0.95").unwrap().1, to_strings(&["0.8", "0.9", "0.95"]));
+        assert_eq!(parse_sweep("n=64").unwrap().1, to_strings(&["64"]));
+        assert!(parse_sweep("ctx_size=1000,2000").is_err());
+        assert!(parse_sweep("temp=1.0:0.2:0.2").is_err());
+        assert!(parse_sweep("top_k=a,b").is_err());
+
+        let sweeps = vec![
+            ("temp".to_string(), to_strings(&["0.2", "0.8"])),
+            ("top_p".to_string(), to_strings(&["0.9", "0.95", "1"])),
+        ];
+        let combinations = sweep_combinations(&sweeps);
+        assert_eq!(combinations.len(), 6);
+        assert_eq!(combinations[1], vec![("temp".to_string(), "0.2".to_string()), ("top_p".to_string(), "0.95".to_string())]);
+        assert_eq!(combinations[5][0].1, "0.8");
+
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a, \"b\""), "\"a, \"\"b\"\"\"");
+    }
+
+    #[test]
+    fn test_mode_conditions() {
+        assert_eq!(parse_hours_ranges("09:00-18:00"), Some(vec![(540, 1080)]));
+        assert_eq!(parse_hours_ranges("22:00-06:00, 12:00-13:00"), Some(vec![(1320, 360), (720, 780)]));
+        assert!(parse_hours_ranges("9-18").is_none());
+        assert!(parse_hours_ranges("25:00-26:00").is_none());
+
+        let mut params = LlamaCppParameters::default();
+        let state = MachineState { on_ac_power: Some(false), minutes_of_day: Some(23 * 60), free_ram_gb: Some(8.0) };
+        assert!(unmet_mode_condition(&params, &state).is_none());
+
+        params.hours = "22:00-06:00".to_string();
+        assert!(unmet_mode_condition(&params, &state).is_none());
+        params.hours = "09:00-18:00".to_string();
+        assert_eq!(unmet_mode_condition(&params, &state).unwrap(), "hours=09:00-18:00, and it is 23:00");
+        params.hours.clear();
+
+        params.min_free_ram_gb = Some(24.0);
+        assert!(unmet_mode_condition(&params, &state).unwrap().contains("8.0 GB is free"));
+        params.only_if = "on_ac_power".to_string();
+        assert!(unmet_mode_condition(&params, &state).unwrap().contains("battery"));
+
+        // Unknown machine state does not block
+        assert!(unmet_mode_condition(&params, &MachineState::default()).is_none());
+
+        assert!(find_mode_entry_problems("||only_if=on_ac_power|hours=09:00-18:00|min_free_ram_gb=24|x|y").is_empty());
+        assert_eq!(find_mode_entry_problems("||only_if=quiet|hours=9am|x|y").len(), 2);
+
+        params.disabled = "model archived to /mnt/archive".to_string();
+        assert_eq!(unmet_mode_condition(&params, &MachineState::default()).unwrap(), "disabled: model archived to /mnt/archive");
+    }
+
+    #[test]
+    fn test_image_mode() {
+        let mut mode = make_test_mode("sd15");
+        mode.kind = "image".to_string();
+        mode.model_path = "/models/sd.gguf".to_string();
+        mode.parameters.thread_count = 4;
+        mode.parameters.image_steps = Some(20);
+        let args = image_generation_args(&mode, "a lighthouse, watercolor", Path::new("/tmp/out.png"));
+        assert_eq!(args, vec!["-m", "/models/sd.gguf", "-p", "a lighthouse, watercolor", "-o", "/tmp/out.png", "-t", "4", "--steps", "20"]);
+        assert_eq!(image_prompt("a lighthouse", "watercolor\n"), "a lighthouse, watercolor");
+        assert_eq!(image_prompt("a lighthouse", ""), "a lighthouse");
+
+        // Image modes have no llama.cpp session
+        assert_eq!(check_model_file(&mode).map_err(|e| exit_code_for_error(&e)), Err(EXIT_CONFIG_ERROR));
+
+        let entry = "/models/sd.gguf||kind=image|steps=20|cfg_scale=7.5|output_dir=/tmp/images|SD|images";
+        assert!(find_mode_entry_problems(entry).is_empty());
+        assert_eq!(find_mode_entry_problems("/m.gguf||kind=video|steps=0|x|y").len(), 2);
+        mode.parameters.cfg_scale = Some(7.5);
+        let saved = format_mode_entry(&mode);
+        assert!(saved.contains("\nsteps = 20\ncfg_scale = 7.5\n") && saved.contains("\nkind = \"image\"\n"));
+        let (config, errors) = toml_lite::parse(&saved);
+        assert!(errors.is_empty());
+        let entry = &config_mode_entries(&config)[0];
+        assert_eq!((entry.model.as_str(), entry.setting("id"), entry.setting("steps")), ("/models/sd.gguf", Some(mode.id.as_str()), Some("20")));
+    }
+
+    #[test]
+    fn test_text_for_speech() {
+        let reply = "# Steps\n\n1. Boil **water**.\n```sh\necho hi\n```\n> Use `salt`.";
+        assert_eq!(text_for_speech(reply), "Steps\n1. Boil water.\nCode block omitted.\nUse salt.");
+    }
+
+    #[test]
+    fn test_tee_stream() {
+        let mut output = Vec::new();
+        let mut log = b"### transcript\n".to_vec();
+        tee_stream(io::Cursor::new(b"> hi\nHello!".to_vec()), &mut output, &mut log).unwrap();
+        assert_eq!(output, b"> hi\nHello!");
+        assert_eq!(log, b"### transcript\n> hi\nHello!");
+
+        // A log that cannot be written does not stop the session
+        let mut output = Vec::new();
+        tee_stream(io::Cursor::new(b"text".to_vec()), &mut output, &mut FailingWriter).unwrap();
+        assert_eq!(output, b"text");
+    }
+
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_terminal_command_args() {
+        assert_eq!(
+            terminal_command_args("gnome-terminal -- bash -c {cmd}", "llama-cli -m 'a b.gguf'"),
+            vec!["gnome-terminal", "--", "bash", "-c", "llama-cli -m 'a b.gguf'"]
+        );
+        assert_eq!(terminal_command_args("my-term -e", "top"), vec!["my-term", "-e", "top"]);
+        assert!(terminal_command_args("  ", "top").is_empty());
     }
 
     #[test]
@@ -674,1241 +1876,14086 @@ mod tests {
             llama_cpp_directory: temp_dir.join("llama-cli")  // Added this line
                 .to_string_lossy()
                 .to_string(),
+            terminal_command: String::new(),
+            editor: String::new(),
         };
 
         assert!(validate_query_gguf_directories(&result).is_ok());
     }
-}
 
-/// old
-/// The function reads a single line from a TOML file that starts with a specified field name
-/// and ends with a value. The function returns an empty string if the field is not found, and
-/// does not panic or unwrap in case of errors. The function uses only standard Rust libraries
-/// and does not introduce unnecessary dependencies.
-///
-/// design:
-/// 0. start with an empty string to return by default
-/// 1. get file at path
-/// 2. open as text
-/// 3. iterate through rows
-/// 4. look for filed name as start of string the " = "
-/// 5. grab that whole row of text
-/// 6. remove "fieldname = " from the beginning
-/// 7. remove '" ' and trailing spaces from the end
-/// 8. return that string, if any
-/// by default, return an empty string, if anything goes wrong, 
-/// handle the error, and return an empty string
-///
-/// requires:
-/// use std::fs::File;
-/// use std::io::{self, BufRead};
-///
-/// example use:
-///     let value = read_field_from_toml("test.toml", "fieldname");
-///
-/// new
-/// The function reads a single line from a TOML file that starts with a specified field name.
-/// The file path is obtained using get_config_path() to ensure the correct absolute path.
-/// The function returns an empty string if the field is not found, and
-/// does not panic or unwrap in case of errors.
-///
-/// # Arguments
-/// * `field_name` - The name of the field to search for in the TOML file
-///
-/// # Returns
-/// * `String` - The value of the field if found, empty string otherwise
-///
-/// # Examples
-/// ```
-/// let llama_path = read_field_from_toml("llama_cli_path");
-/// if llama_path.is_empty() {
-///     println!("llama_cli_path not found in config");
-/// }
-/// ```
-fn read_field_from_toml(field_name: &str) -> String {
-    // Get absolute path to config file
-    let path = match get_config_path() {
-        Ok(path) => path,
-        Err(e) => {
-            println!("Error read_field_from_toml getting config path: {}", e);
-            return String::new();
-        }
-    };
-    
-    // Validate input parameters
-    // A PathBuf is invalid if it has no file name component
-    if path.file_name().is_none() || field_name.is_empty() {
-        println!("Error: read_field_from_toml Invalid path or empty field name provided");
-        return String::new();
-    }
+    #[test]
+    fn test_path_ownership_and_access() {
+        let temp_dir = std::env::temp_dir().join("query_gguf_permission_test");
+        fs::create_dir_all(&temp_dir).unwrap();
 
-    // New check:
-    if !path.extension()
-        .and_then(|ext| ext.to_str())
-        .map(|s| s.to_lowercase())
-        .map_or(false, |ext| ext == "toml") 
-    {
-        println!("Warning: read_field_from_toml File does not have .toml extension: {}", path.display());
+        let problems = check_path_ownership_and_access(&temp_dir, "Test dir", get_current_user_id());
+        assert!(problems.is_empty(), "unexpected problems: {:?}", problems);
 
+        // Missing paths are not reported as problems
+        let missing = temp_dir.join("does_not_exist");
+        assert!(check_path_ownership_and_access(&missing, "Missing", None).is_empty());
+
+        let _ = fs::remove_dir_all(&temp_dir);
     }
 
-    // Debug print statement
-    println!("Attempting read_field_from_toml to open file at path: {}", path.display());
+    #[test]
+    fn test_mode_ids_and_selection() {
+        assert_eq!(slugify_mode_name("Base Coder v2"), "base-coder-v2");
+        assert_eq!(slugify_mode_name("  llama3.2 (small)! "), "llama3-2-small");
 
+        let modes = vec![make_test_mode("coder"), make_test_mode("writer")];
 
-    // Open the file at the specified path
-    let file = match File::open(&path) {
-        Ok(file) => file,
-        Err(e) => {
-            // More detailed error reporting
-            println!("Failed read_field_from_toml to open file at path: {}. Error: {}", path.display(), e);
-            return String::new();
-        },
-    };
+        assert_eq!(make_unique_mode_id("coder", &modes), "coder-2");
+        assert_eq!(make_unique_mode_id("chat", &modes), "chat");
 
-    // Debug print statement
-    println!("read_field_from_toml Successfully opened file at path: {}", path.display());
+        assert_eq!(find_mode_by_selector(&modes, "2").map(|m| m.id.as_str()), Some("writer"));
+        assert_eq!(find_mode_by_selector(&modes, "coder").map(|m| m.id.as_str()), Some("coder"));
+        assert!(find_mode_by_selector(&modes, "0").is_none());
+        assert!(find_mode_by_selector(&modes, "missing").is_none());
+    }
 
+    #[test]
+    fn test_config_journal() {
+        assert_eq!(format_unix_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_unix_time(1_709_210_096), "2024-02-29 12:34:56 UTC");
+
+        let old = "default_mode = \"a\"\nmode_1 = \"m|p|temp=0.8|id=a|A|x\"\nstrict = true\n[aliases]\nr = \"1\"\n";
+        let new = "default_mode = \"b\"\nmode_1 = \"m|p|temp=0.3|id=a|A|x\"\nmode_2 = \"m|p|id=b|B|y\"\n[aliases]\nr = \"2\"\n";
+        assert_eq!(describe_config_changes(old, new), vec![
+            "default_mode: \"a\" -> \"b\"",
+            "mode_1: temp=0.8 -> temp=0.3",
+            "added mode_2 = \"m|p|id=b|B|y\"",
+            "aliases.r: \"1\" -> \"2\"",
+            "removed strict",
+        ]);
+        assert!(describe_config_changes(old, &format!("# comment\n{}", old)).is_empty());
+    }
 
-    // Create a buffered reader to read the file line by line
-    let reader = io::BufReader::new(file);
+    #[test]
+    fn test_new_config_problems() {
+        let before = "bogus = 1\nmode_1 = \"m|p|A|x\"\n";
+        // The old problem moved to another line and is not reported again
+        let after = "strict = true\nbogus = 1\nmode_1 = \"m|p|temp=hot|A|x\"\nstrict = false\n";
+        assert_eq!(new_config_problems(before, after), vec![
+            "line 3: mode_1: invalid value for 'temp': hot",
+            "line 4: duplicate key 'strict' (first defined on line 1)",
+        ]);
+        assert!(new_config_problems(before, before).is_empty());
+    }
 
-    // Keep track of line numbers for better error reporting
-    let mut line_number = 0;
+    #[test]
+    fn test_validate_config_strict() {
+        let good = "llama_cli_path = \"/bin/llama-cli\"\nstrict = true\n# comment\n\
+            mode_1 = \"/m.gguf|p.txt|temp=0.8|id=a|A|first\"\n";
+        assert!(validate_config_strict(good).is_ok());
+
+        let bad = "llama_cli_pth = \"/bin/llama-cli\"\n\
+            mode_1 = \"/m.gguf|p.txt|temp=hot|A|first\"\n\
+            mode_1 = \"/m.gguf\"\n";
+        let error = validate_config_strict(bad).unwrap_err();
+        assert!(error.contains("line 1: unknown key 'llama_cli_pth'"));
+        assert!(error.contains("line 2: mode_1: invalid value for 'temp'"));
+        assert!(error.contains("line 3: duplicate key 'mode_1' (first defined on line 2)"));
+        assert!(error.contains("line 3: mode_1: insufficient parts"));
+
+        let tables = "[mode.fast]\nmodel = \"/m.gguf\"\ndescription = \"a | b\"\ntemp = \"hot\"\ntop_k = 40\ncolor = \"red\"\n";
+        assert_eq!(find_config_problems(tables), vec![
+            "line 4: invalid value for 'temp' in [mode.fast]: \"hot\"",
+            "line 6: unknown key 'color' in [mode.fast]",
+        ]);
+
+        let error = validate_config_strict("strict = \"yes\"\neditor = nano\n").unwrap_err();
+        assert!(error.contains("line 1: 'strict' must be true or false, not \"yes\""));
+        assert!(error.contains("line 2: invalid value 'nano' (text needs quotes)"));
+    }
 
-    // Iterate through each line in the file
-    for line_result in reader.lines() {
-        line_number += 1;
+    #[test]
+    fn test_lint_config_content() {
+        let content = "\
+llama_cpp_directory = \"~/llama.cpp/build/bin/llama-cli\"
+gguf_model_directory_1 = \"/models/\"
+gguf_model_directory_1 = \"/other\"
+default_mode = 3
+
+# Mode 3 - b - second
+mode_3 = \"/m.gguf|p.txt|temp=0.8|b|second\"
+
+# Mode 1 - a - first
+mode_1 = \"/m.gguf|p.txt|temp=0.8|a|first\"
+";
+        let (fixed, changes) = lint_config_content(content, "/home/me");
+
+        assert!(fixed.contains("llama_cli_path = \"/home/me/llama.cpp/build/bin/llama-cli\""));
+        assert!(fixed.contains("gguf_model_directory_1 = \"/models\""));
+        assert!(!fixed.contains("/other"));
+        assert!(fixed.contains("default_mode = 2"));
+        assert!(fixed.contains("# Mode 1 - a - first\nmode_1 = \"/m.gguf|p.txt|temp=0.8|a|first\""));
+        assert!(fixed.contains("# Mode 2 - b - second\nmode_2 = \"/m.gguf|p.txt|temp=0.8|b|second\""));
+        assert!(changes.iter().any(|c| c.contains("renamed deprecated key")));
+        assert!(changes.iter().any(|c| c.contains("removed duplicate key")));
+
+        // Linting the fixed output again changes nothing
+        let (refixed, rechanges) = lint_config_content(&fixed, "/home/me");
+        assert_eq!(refixed, fixed);
+        assert!(rechanges.is_empty());
+    }
 
-        // Handle line reading errors
-        let line = match line_result {
-            Ok(line) => line,
-            Err(e) => {
-                println!("Error read_field_from_toml reading line {}: {}", line_number, e);
-                continue;
-            }
-        };
+    #[test]
+    fn test_retune_mode_entries() {
+        let content = "threads_note = 1\nmode_1 = \"/m.gguf|p.txt|threads=11|gpu_layers=20|a|first\"\n";
 
-        // Skip empty lines and comments
-        if line.trim().is_empty() || line.trim_start().starts_with('#') {
-            continue;
-        }
+        let (cpu_only, changes) = retune_mode_entries(content, 3, Some(0));
+        assert!(cpu_only.contains("|threads=3|gpu_layers=0|"));
+        assert!(cpu_only.starts_with("threads_note = 1\n"));
+        assert_eq!(changes.len(), 1);
 
-        // Debug print statement
-        println!("Processing line {}: {}", line_number, line);
+        let (keep_gpu, _) = retune_mode_entries(content, 3, None);
+        assert!(keep_gpu.contains("|threads=3|gpu_layers=20|"));
 
-        // Check if line starts with field name
-        if line.trim_start().starts_with(field_name) {
-            // Debug print statement
-            println!("Found field '{}' on line {}", field_name, line_number);
+        let (unchanged, no_changes) = retune_mode_entries(&keep_gpu, 3, None);
+        assert_eq!(unchanged, keep_gpu);
+        assert!(no_changes.is_empty());
 
-            // Split the line by '=' and handle malformed lines
-            let parts: Vec<&str> = line.splitn(2, '=').collect();
-            if parts.len() != 2 {
-                println!("Malformed TOML line {} - missing '=': {}", line_number, line);
-                continue;
-            }
+        let content = "[mode.fast]\nthreads = 11\ngpu_layers = 20\n[binaries.cuda]\nthreads = 1\n";
+        let (retuned, changes) = retune_mode_entries(content, 3, Some(0));
+        assert_eq!(retuned, "[mode.fast]\nthreads = 3\ngpu_layers = 0\n[binaries.cuda]\nthreads = 1\n");
+        assert_eq!(changes, vec!["mode.fast: threads=3, gpu_layers=0"]);
+    }
 
-            let key = parts[0].trim();
-            let value = parts[1].trim();
+    #[test]
+    fn test_resolve_mode_inheritance() {
+        let mut base = make_test_mode("base");
+        base.model_path = "/models/coder.gguf".to_string();
+        base.prompt_path = "/prompts/code.txt".to_string();
+        base.parameters.context_size = 8000;
+        base.parameters.temperature_value = 0.8;
+
+        let mut child = make_test_mode("careful");
+        child.extends = "base".to_string();
+        child.model_path = "/home/me/".to_string(); // what an empty model field resolves to
+
+        let mut grandchild = make_test_mode("careful-doc");
+        grandchild.extends = "careful".to_string();
+        grandchild.prompt_path = "/prompts/doc.txt".to_string();
+
+        let mut looping = make_test_mode("loop");
+        looping.extends = "loop".to_string();
+
+        let modes = vec![base, child, grandchild, looping];
+        let (config, _) = toml_lite::parse("\
+mode_1 = \"/models/coder.gguf|/prompts/code.txt|temp=0.8|ctx_size=8000|base|d\"
+mode_2 = \"||extends=base|temp=0.3|careful|d\"
+[mode.careful-doc]
+prompt = \"/prompts/doc.txt\"
+extends = \"careful\"
+description = \"d | with a pipe\"
+[mode.loop]
+extends = \"loop\"
+");
+        let entries = config_mode_entries(&config);
+        assert_eq!(entries[2].name, "careful-doc");
+        assert_eq!(entries[2].description, "d | with a pipe");
+        assert_eq!(entries[2].setting("id"), Some("careful-doc"));
+        assert_eq!(entries[3].model, "");
+
+        let resolved = resolve_mode_inheritance(&modes, &entries);
+
+        assert_eq!(resolved[1].model_path, "/models/coder.gguf");
+        assert_eq!(resolved[1].prompt_path, "/prompts/code.txt");
+        assert_eq!(resolved[1].parameters.context_size, 8000);
+        assert_eq!(resolved[1].parameters.temperature_value, 0.3);
+
+        assert_eq!(resolved[2].model_path, "/models/coder.gguf");
+        assert_eq!(resolved[2].prompt_path, "/prompts/doc.txt");
+        assert_eq!(resolved[2].parameters.temperature_value, 0.3);
+
+        // Cycles leave the mode as written
+        assert_eq!(resolved[3].id, "loop");
+    }
 
-            // Verify exact field name match (avoiding partial matches)
-            if key != field_name {
-                continue;
-            }
+    #[test]
+    fn test_server_responses() {
+        let response = r#"{"content":"Hello","tokens_evaluated":4,"tokens_predicted":12,"timings":{"prompt_ms":35.5,"predicted_ms":120}}"#;
+        let result = parse_completion_response(response).unwrap();
+        assert_eq!(result.content, "Hello");
+        assert_eq!((result.tokens_evaluated, result.tokens_predicted), (4, 12));
+        assert_eq!((result.prompt_ms, result.predicted_ms), (35.5, 120.0));
+        assert!(parse_completion_response("{\"error\":\"x\"}").is_err());
+
+        let response = r#"{"choices":[{"message":{"role":"assistant","content":"Hi \"you\""}}],"usage":{"prompt_tokens":3,"completion_tokens":2}}"#;
+        let result = parse_chat_response(response).unwrap();
+        assert_eq!(result.content, "Hi \"you\"");
+        assert_eq!((result.prompt_tokens, result.completion_tokens), (3, 2));
+        assert_eq!(result.tokens_per_second, 0.0);
+    }
 
-            // Handle empty values
-            if value.is_empty() {
-                println!("Warning: Empty value found for field '{}'", field_name);
-                return String::new();
-            }
+    #[test]
+    fn test_local_http_request() {
+        use std::io::Read;
+
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0u8; 1024];
+            let read = stream.read(&mut buffer).unwrap();
+            let request = String::from_utf8_lossy(&buffer[..read]).to_string();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 15\r\n\r\n{\"content\":\"x\"}").unwrap();
+            request
+        });
+
+        let (status, body) = local_http_request(
+            port, "POST", "/completion", "{}", std::time::Duration::from_secs(5)
+        ).unwrap();
+        let request = server.join().unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(json::parse(&body).unwrap().get("content").and_then(json::Value::as_str), Some("x"));
+        assert!(request.starts_with("POST /completion HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n{}"));
+    }
 
-            // Debug print statement
-            println!("Extracted value: {}", value);
+    #[test]
+    fn test_parse_llama_perf() {
+        let stderr = "\
+llama_perf_sampler_print:    sampling time =       5.12 ms /    60 runs   (    0.09 ms per token, 11718.75 tokens per second)
+llama_perf_context_print:        load time =     612.34 ms
+llama_perf_context_print: prompt eval time =     100.00 ms /    11 tokens (    9.09 ms per token,   110.00 tokens per second)
+llama_perf_context_print:        eval time =    1000.00 ms /    49 runs   (   20.41 ms per token,    49.00 tokens per second)
+";
+        assert_eq!(parse_llama_perf_ms(stderr, "load time"), Some(612.34));
+        assert_eq!(parse_llama_tokens_per_second(stderr), Some(49.0));
+        assert_eq!(parse_llama_eval_tokens(stderr), Some(49));
+        assert_eq!(parse_llama_perf_ms("no report", "load time"), None);
+    }
 
-            // Clean up the value: remove quotes and trim spaces
-            let cleaned_value = value.trim().trim_matches('"').trim();
-            
-            // Verify the cleaned value isn't empty
-            if cleaned_value.is_empty() {
-                println!("Warning: Value became empty after cleaning for field '{}'", field_name);
-                return String::new();
-            }
+    #[test]
+    fn test_build_usage_report() {
+        let stats = "\
+100\tprint\tcoder\t/m/old.gguf\t500\t100\t20.00
+2000\tchat\tcoder\t/m/mistral.Q4_K_M.gguf\t800\t0\t30.00\t120
+2100\ttune\twriter\t/m/mistral.Q4_K_M.gguf\t700\t90\t10.00\t30
+";
+        let sizes = vec![("/m/small.gguf".to_string(), 1024), ("/m/mistral.Q4_K_M.gguf".to_string(), 4096)];
+        let report = build_usage_report(stats, &sizes, 1000);
+
+        assert!(report.contains("- Sessions: 2\n- Tokens generated: 150"));
+        assert!(report.contains("| coder (chat) | 1 | 120 | 30.0 |"));
+        assert!(report.contains("| mistral.Q4_K_M.gguf | 2 | 150 | 20.0 |"));
+        assert!(!report.contains("old.gguf"));
+        assert!(report.contains("## Disk usage by model (5.0 KB total)"));
+        assert!(report.find("mistral.Q4_K_M.gguf | 4.0 KB").unwrap() < report.find("small.gguf").unwrap());
+    }
 
-            return cleaned_value.to_string();
-        }
+    #[test]
+    fn test_context_meter() {
+        assert_eq!(
+            format_context_meter(1024, 4096),
+            "[context █████░░░░░░░░░░░░░░░ 1024/4096 tokens (25%)]"
+        );
+        assert!(format_context_meter(5000, 4096).contains("(100%)"));
+        assert!(context_usage_warning(1000, 4096).is_none());
+        assert!(context_usage_warning(3500, 4096).unwrap().contains("85%"));
+        assert!(context_usage_warning(4096, 4096).unwrap().contains("full"));
+        assert!(context_usage_warning(10, 0).is_none());
     }
 
-    // If we get here, the field wasn't found
-    println!("Field '{}' not found in file", field_name);
-    String::new()
-}
+    #[test]
+    fn test_classify_llama_failure() {
+        let stderr = "llama_model_load: loading model\nggml_backend_cuda_buffer_type_alloc_buffer: allocating 8000 MiB on device 0: cudaMalloc failed: out of memory\nmain: error: unable to load model\n";
+        let (problem, _) = classify_llama_failure(stderr).unwrap();
+        assert!(problem.contains("GPU ran out of memory"));
+        assert!(describe_llama_failure(stderr).contains("cudaMalloc failed"));
 
-/// Reads all fields from a TOML file that share a common base name (prefix before underscore)
-/// and returns a vector of their values.
-/// 
-/// Uses the standard config file location:
-/// - Linux/MacOS: ~/query_gguf/query_gguf_config.toml
-/// - Windows: \Users\username\query_gguf\query_gguf_config.toml
-///
-/// # Arguments
-/// * `base_name` - Base name to search for (e.g., "prompt" will match "prompt_1", "prompt_2", etc.)
-///
-/// # Returns
-/// * `Vec<String>` - Vector containing all values for fields matching the base name
-///
-fn read_basename_fields_from_toml(base_name: &str) -> Vec<String> {
-    let mut values = Vec::new();
-    let mut numbered_values = Vec::new();  // Store (number, value) pairs
+        let (problem, _) = classify_llama_failure("llama-cli: error while loading shared libraries: libcudart.so.12").unwrap();
+        assert!(problem.contains("shared library"));
 
-    // Get config path
-    let path = match get_config_path() {
-        Ok(path) => path,
-        Err(e) => {
-            println!("Failed to get config path: {}", e);
-            return values;
-        }
-    };
+        let (problem, _) = classify_llama_failure("error loading model architecture: unknown model architecture: 'foo'").unwrap();
+        assert!(problem.contains("architecture"));
 
-    // Validate input parameters
-    if base_name.is_empty() {
-        println!("Error: Empty base name provided");
-        return values;
+        assert!(classify_llama_failure("segfault somewhere").is_none());
+        assert!(describe_llama_failure("line one\nsegfault somewhere").contains("segfault somewhere"));
     }
 
-    // // Open and read the file
-    // let file = match File::open(&path) {
-    //     Ok(file) => file,
-    //     Err(e) => {
-    //         println!("Failed to open file at path: {}. Error: {}", path.display(), e);
-    //         return values;
-    //     },
-    // };
-
-    // let reader = io::BufReader::new(file);
-    // let base_name_with_underscore = format!("{}_", base_name);
+    #[test]
+    fn test_order_favorites_first() {
+        let items: Vec<String> = ["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect();
+        let favorites: Vec<String> = ["c", "gone", "a"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(order_favorites_first(&items, &favorites), vec![2, 0, 1, 3]);
+        assert_eq!(order_favorites_first(&items, &[]), vec![0, 1, 2, 3]);
+    }
 
-    // Open and read the file
-    let file = match File::open(&path) {
-        Ok(file) => file,
-        Err(e) => {
-            println!("Failed to open file at path: {}. Error: {}", path.display(), e);
-            return values;
-        },
-    };
+    #[test]
+    fn test_update_state_file() {
+        let dir = std::env::temp_dir().join(format!("query_gguf_state_lock_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.toml");
+
+        // Parallel updates each see the others' changes, so none is lost
+        let writers: Vec<_> = (0..8).map(|writer| {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                for item in 0..5 {
+                    update_state_file(&path, |content| Some(format!("{}{}-{}\n", content, writer, item))).unwrap();
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 40);
+        assert!(!dir.join("state.toml.lock").exists());
 
-    let reader = io::BufReader::new(file);
-    let base_name_with_underscore = format!("{}_", base_name);
+        // A lock left by a process that has exited is taken over
+        fs::write(dir.join("state.toml.lock"), "999999999").unwrap();
+        update_state_file(&path, |_| None).unwrap();
 
-    for (line_number, line_result) in reader.lines().enumerate() {
-        let line = match line_result {
-            Ok(line) => line,
-            Err(e) => {
-                println!("Error reading line {}: {}", line_number + 1, e);
-                continue;
-            }
-        };
+        assert_eq!(set_state_field("a = \"1\"\nb = \"2\"\n", "a", "3"), "b = \"2\"\na = \"3\"\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
 
-        // Skip empty lines and comments
-        let trimmed_line = line.trim();
-        if trimmed_line.is_empty() || trimmed_line.starts_with('#') {
-            continue;
-        }
+    #[test]
+    fn test_push_recent_item() {
+        let recent: Vec<String> = ["/a", "/b", "/c"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(push_recent_item(&recent, "/b", 3), vec!["/b", "/a", "/c"]);
+        assert_eq!(push_recent_item(&recent, "/d", 3), vec!["/d", "/a", "/b"]);
+        assert_eq!(push_recent_item(&[], "/a", 3), vec!["/a"]);
+    }
 
-        // Check if line starts with base_name_
-        if trimmed_line.starts_with(&base_name_with_underscore) {
-            // Extract the number after the underscore
-            if let Some(num_str) = trimmed_line
-                .split('=')
-                .next()
-                .and_then(|s| s.trim().strip_prefix(&base_name_with_underscore))
-            {
-                if let Ok(num) = num_str.parse::<usize>() {
-                    // Split the line by '=' and handle malformed lines
-                    let parts: Vec<&str> = trimmed_line.splitn(2, '=').collect();
-                    if parts.len() == 2 {
-                        let value = parts[1].trim().trim_matches('"').trim();
-                        if !value.is_empty() {
-                            numbered_values.push((num, value.to_string()));
-                        }
-                    }
-                }
-            }
+    #[test]
+    fn test_line_editor() {
+        let mut line = LineBuffer::default();
+        for c in "héllo".chars() {
+            line.insert(c);
         }
+        line.move_left();
+        line.move_left();
+        line.move_left();
+        line.backspace();
+        assert_eq!(line.text(), "hllo");
+        line.insert('é');
+        line.delete();
+        assert_eq!(line.text(), "hélo");
+        assert_eq!(line.cursor, 2);
+
+        let mut history = HistoryBrowser::new(vec!["one".to_string(), "two".to_string()]);
+        assert_eq!(history.previous("draft").as_deref(), Some("two"));
+        assert_eq!(history.previous("two").as_deref(), Some("one"));
+        assert_eq!(history.previous("one"), None);
+        assert_eq!(history.next().as_deref(), Some("two"));
+        assert_eq!(history.next().as_deref(), Some("draft"));
+        assert_eq!(history.next(), None);
+
+        let mut keys: &[u8] = b"a\x1b[D\x1b[3~\xc3\xa9\x7f\r";
+        let decoded: Vec<EditorKey> = std::iter::from_fn(|| read_editor_key(&mut keys)).collect();
+        assert_eq!(decoded, vec![
+            EditorKey::Char('a'), EditorKey::Left, EditorKey::Delete,
+            EditorKey::Char('é'), EditorKey::Backspace, EditorKey::Enter,
+        ]);
     }
 
-    // Sort by the actual mode numbers
-    numbered_values.sort_by_key(|(num, _)| *num);
-    
-    // Extract just the values in correct order
-    values = numbered_values.into_iter().map(|(_, value)| value).collect();
+    #[test]
+    fn test_complete_path() {
+        let base = std::env::temp_dir().join(format!("query_gguf_complete_{}", std::process::id()));
+        fs::create_dir_all(base.join("models_big")).unwrap();
+        fs::create_dir_all(base.join("models_small")).unwrap();
+        fs::write(base.join("prompt.txt"), "").unwrap();
+        fs::write(base.join(".hidden"), "").unwrap();
+        let base_text = format!("{}/", base.display());
 
-    values
-}
+        assert_eq!(complete_path(&format!("{}pr", base_text)), (format!("{}prompt.txt", base_text), vec![]));
 
-/// Defines all adjustable parameters for the llama.cpp command execution
-/// Each field corresponds to a specific llama.cpp command line argument
-#[derive(Debug, Clone)]
-struct LlamaCppParameters {
-    temperature_value: f32,      // --temp parameter
-    top_k_sampling: i32,         // --top-k parameter
-    top_p_sampling: f32,         // --top-p parameter
-    context_size: i32,           // --ctx-size parameter
-    thread_count: i32,           // --threads parameter
-    gpu_layers: i32,             // --n-gpu-layers parameter
-    interactive_first: bool,     // --interactive-first flag
-}
-    
-    // temperature_value: f32,      // --temp parameter
-    // top_k_sampling: i32,         // --top-k parameter
-    // top_p_sampling: f32,         // --top-p parameter
-    // min_p_sampling: f32,         // --min-p parameter
-    // random_seed: i32,            // --seed parameter
-    // tail_free_sampling: f32,     // --tfs parameter
-    // thread_count: i32,           // --threads parameter
-    // typical_sampling: f32,       // --typical parameter
-    // mirostat_version: i32,       // --mirostat parameter
-    // mirostat_learning_rate: f32, // --mirostat-lr parameter
-    // mirostat_entropy: f32,       // --mirostat-ent parameter
-    // context_window_size: i32,    // --ctx-size parameter
-// }
+        let (completed, candidates) = complete_path(&format!("{}mo", base_text));
+        assert_eq!(completed, format!("{}models_", base_text));
+        assert_eq!(candidates, vec!["models_big/", "models_small/"]);
 
-impl Default for LlamaCppParameters {
-    fn default() -> Self {
-        Self {
-            temperature_value: 0.8,
-            top_k_sampling: 40,
-            top_p_sampling: 0.9,
-            context_size: 2000,
-            thread_count: get_system_cpu_count(),
-            gpu_layers: 0,       // default to CPU-only
-            interactive_first: true,
-        }
-        // Self {
-        //     temperature_value: 0.8,
-        //     top_k_sampling: 40,
-        //     top_p_sampling: 0.9,
-        //     min_p_sampling: 0.05,
-        //     random_seed: -1,
-        //     tail_free_sampling: 1.0,
-        //     thread_count: get_system_cpu_count() - 1,
-        //     typical_sampling: 1.0,
-        //     mirostat_version: 2,
-        //     mirostat_learning_rate: 0.05,
-        //     mirostat_entropy: 3.0,
-        //     context_window_size: 500,
-        // }
+        assert_eq!(complete_path(&format!("{}models_b", base_text)).0, format!("{}models_big/", base_text));
+        assert_eq!(complete_path(&format!("{}zz", base_text)).0, format!("{}zz", base_text));
+        assert!(complete_path(&base_text).1.iter().all(|name| !name.starts_with('.')));
+
+        let _ = fs::remove_dir_all(&base);
     }
-}
 
-/// Retrieves the number of CPU cores available on the current system minus 1
-/// Returns the number of available CPU cores minus 1 or a safe default if detection fails
-fn get_system_cpu_count() -> i32 {
-    match std::thread::available_parallelism() {
-        Ok(count) => {
-            let cpu_count = count.get() as i32;
-            // Ensure we don't return less than 1 thread
-            if cpu_count > 1 {
-                cpu_count - 1
-            } else {
-                1
-            }
-        },
-        Err(_) => {
-            println!("Warning: Could not detect CPU count, using default value of 3");
-            3 // conservative default (assuming at least 4 cores)
-        }
+    #[test]
+    fn test_model_family_grouping() {
+        assert_eq!(
+            split_model_quantization("Mistral-7B-Instruct-v0.2.Q4_K_M.gguf"),
+            ("Mistral-7B-Instruct-v0.2".to_string(), "Q4_K_M".to_string())
+        );
+        assert_eq!(
+            split_model_quantization("qwen2.5-7b-instruct-iq4_xs.gguf"),
+            ("qwen2.5-7b-instruct".to_string(), "iq4_xs".to_string())
+        );
+        assert_eq!(split_model_quantization("llama_q8_0.gguf"), ("llama".to_string(), "q8_0".to_string()));
+        assert_eq!(split_model_quantization("phi-2-F16.gguf"), ("phi-2".to_string(), "F16".to_string()));
+        assert_eq!(
+            split_model_quantization("big-Q8_0-00001-of-00002.gguf"),
+            ("big".to_string(), "Q8_0".to_string())
+        );
+        assert_eq!(split_model_quantization("Qwen2-7B.gguf"), ("Qwen2-7B".to_string(), String::new()));
+
+        let model = |name: &str| ModelFile { full_path: format!("/m/{}", name), display_name: name.to_string() };
+        let models = vec![
+            model("Mistral-7B.Q4_K_M.gguf"),
+            model("phi-2-Q8_0.gguf"),
+            model("mistral-7b.Q5_K_S.gguf"),
+        ];
+        assert_eq!(group_models_by_family(&models), vec![
+            ("Mistral-7B".to_string(), vec![0, 2]),
+            ("phi-2".to_string(), vec![1]),
+        ]);
     }
-}
 
-/// Generates a unique timestamp string for log file names and entries
-/// Returns a string representation of the current Unix timestamp
-fn generate_timestamp_string() -> String {
-    match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(duration) => duration.as_secs().to_string(),
-        Err(_) => {
-            println!("Warning: System time error, using 'unknown_time' as timestamp");
-            "unknown_time".to_string()
-        }
+    #[test]
+    fn test_select_proxy_for_url() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| vars.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string())
+        };
+        let vars: &[(&str, &str)] = &[("HTTPS_PROXY", "http://proxy:3128"), ("NO_PROXY", "localhost,.corp.example")];
+        assert_eq!(select_proxy_for_url("https://huggingface.co/x", env(vars)).as_deref(), Some("http://proxy:3128"));
+        assert_eq!(select_proxy_for_url("https://git.corp.example/prompts", env(vars)), None);
+        assert_eq!(select_proxy_for_url("http://huggingface.co/x", env(vars)), None);
+
+        let vars: &[(&str, &str)] = &[("http_proxy", "http://lower:1"), ("HTTP_PROXY", "http://upper:1"), ("ALL_PROXY", "socks5://all:1")];
+        assert_eq!(select_proxy_for_url("http://example.com", env(vars)).as_deref(), Some("http://lower:1"));
+        assert_eq!(select_proxy_for_url("https://example.com", env(vars)).as_deref(), Some("socks5://all:1"));
+
+        assert!(no_proxy_matches("anything", "*"));
+        assert!(!no_proxy_matches("notexample.com", "example.com"));
     }
-}
 
-/// Creates a blank prompt file in the prompts directory
-/// 
-/// Creates the file 'blankprompt.txt' in the standard prompts directory:
-/// - Linux/MacOS: ~/query_gguf/prompts/blankprompt.txt
-/// - Windows: \Users\username\query_gguf\prompts\blankprompt.txt
-/// 
-/// This blank prompt serves as a default when no specific prompt is selected.
-/// The function ensures both the prompts directory and the blank prompt file exist.
-/// 
-/// # Returns
-/// - Ok(String): Absolute path to the created blank prompt file
-/// - Err(String): Error message if creation fails
-/// 
-/// # Error Cases
-/// - Cannot create prompts directory (permissions/disk space)
-/// - Cannot create blank prompt file
-/// - Path resolution fails
-/// 
-fn create_blank_prompt() -> Result<String, String> {
-    // CHANGE 1: Get absolute path to prompts directory
-    let prompts_dir = get_prompts_dir()?;
-    let blank_prompt_path = prompts_dir.join("blankprompt.txt");
+    #[test]
+    fn test_parse_split_part() {
+        assert_eq!(
+            parse_split_part("model-Q8_0-00002-of-00003.gguf"),
+            Some(("model-Q8_0".to_string(), 2, 3))
+        );
+        assert_eq!(split_part_file_name("model-Q8_0", 1, 3), "model-Q8_0-00001-of-00003.gguf");
+        assert_eq!(parse_split_part("model-Q8_0.gguf"), None);
+        assert_eq!(parse_split_part("model-2-of-3.gguf"), None);
+        assert_eq!(parse_split_part("model-00004-of-00003.gguf"), None);
+    }
 
-    println!("DEBUG: Creating prompt directory: {}", prompts_dir.display());
-    
-    // CHANGE 2: Create prompts directory with all parent directories
-    fs::create_dir_all(&prompts_dir)
-        .map_err(|e| format!("Failed to create prompts directory: {}", e))?;
+    #[test]
+    fn test_add_from_huggingface() {
+        assert_eq!(parse_hf_model_url("https://huggingface.co/owner/Model-GGUF"), Some(("owner/Model-GGUF".to_string(), None)));
+        assert_eq!(
+            parse_hf_model_url("hf.co/owner/Model-GGUF/blob/main/model.Q4_K_M.gguf?download=true"),
+            Some(("owner/Model-GGUF".to_string(), Some("model.Q4_K_M.gguf".to_string())))
+        );
+        assert_eq!(parse_hf_model_url("owner/Model-GGUF"), Some(("owner/Model-GGUF".to_string(), None)));
+        assert_eq!(parse_hf_model_url("https://example.com"), None);
+
+        let json = r#"{"gguf":{"total":1,"architecture":"llama","context_length":131072,"chat_template":"{{ x }}"},
+            "siblings":[{"rfilename":"README.md","size":10},{"rfilename":"m-Q4_K_M.gguf","size":800},
+            {"rfilename":"m-Q8_0-00001-of-00002.gguf","size":900},{"rfilename":"m-Q8_0-00002-of-00002.gguf","size":900}]}"#;
+        let info = parse_hf_model_info(json);
+        assert_eq!(info.architecture, "llama");
+        assert_eq!(info.context_length, Some(131072));
+        assert!(info.has_chat_template);
+        assert_eq!(info.gguf_files, vec![
+            ("m-Q4_K_M.gguf".to_string(), Some(800)),
+            ("m-Q8_0-00001-of-00002.gguf".to_string(), Some(900)),
+        ]);
+        assert_eq!(choose_hf_gguf_file(&info, Some("q8_0")).unwrap(), "m-Q8_0-00001-of-00002.gguf");
+        assert!(choose_hf_gguf_file(&info, Some("Q2_K")).is_err());
+
+        let card = "We recommend **Temperature**: 0.6 and `top_p=0.95`. Top-k of 20. Use a temperature of 7 at your own risk.";
+        assert_eq!(infer_card_parameters(card), vec!["temp=0.6", "top_k=20", "top_p=0.95"]);
+        assert!(infer_card_parameters("No settings here.").is_empty());
+    }
 
-    println!("DEBUG: Creating blank prompt file: {}", blank_prompt_path.display());
-    
-    // CHANGE 3: Create the blank prompt file with minimal content
-    fs::write(&blank_prompt_path, "# Blank prompt file\n")
-        .map_err(|e| format!("Failed to create blank prompt file: {}", e))?;
+    #[test]
+    fn test_prompts_new_names() {
+        assert_eq!(prompt_file_name_from("review").unwrap(), "review.txt");
+        assert_eq!(prompt_file_name_from("notes.md").unwrap(), "notes.md");
+        assert!(prompt_file_name_from("../etc/passwd").is_err());
+        assert!(prompt_file_name_from(".hidden").is_err());
+
+        let dir = std::env::temp_dir().join(format!("query_gguf_prompts_new_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(unique_file_path(&dir, "review.txt"), dir.join("review.txt"));
+        fs::write(dir.join("review.txt"), "a").unwrap();
+        fs::write(dir.join("review-2.txt"), "b").unwrap();
+        assert_eq!(unique_file_path(&dir, "review.txt"), dir.join("review-3.txt"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-    // CHANGE 4: Verify the file was created
-    if !blank_prompt_path.exists() {
-        return Err("Failed to verify blank prompt file creation".to_string());
+    #[test]
+    fn test_size_estimates() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024 / 2), "1.5 GB");
+
+        assert_eq!(estimate_quantized_size(16_000, "F16", "Q8_0"), 8_500);
+        assert_eq!(estimate_quantized_size(16_000, "", "Q4_0"), 4_500);
+        assert_eq!(estimate_quantized_size(16_000, "F16", "MADE_UP"), 16_000);
+
+        let response = |headers: &[(&str, &str)]| http::Response {
+            status: 200,
+            headers: headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            body: Vec::new(),
+        };
+        let linked = response(&[("content-length", "1183"), ("x-linked-size", "4368439584")]);
+        assert_eq!(parse_remote_file_size(&linked), Some(4368439584));
+        assert_eq!(parse_remote_file_size(&response(&[("Content-Length", "42")])), Some(42));
+        assert_eq!(parse_remote_file_size(&response(&[])), None);
     }
 
-    println!("Successfully created blank prompt file at: {}", blank_prompt_path.display());
-    Ok(blank_prompt_path.to_string_lossy().to_string())
-}
+    #[test]
+    fn test_chat_compaction() {
+        let message = |role: &str, content: &str| ChatMessage { role: role.to_string(), content: content.to_string() };
+        let messages = vec![
+            message("system", "Be helpful."),
+            message("user", "q1"), message("assistant", "a1"),
+            message("user", "q2"), message("assistant", "a2"),
+            message("user", "q3"), message("assistant", "a3"),
+        ];
+
+        let (to_summarize, kept) = split_for_compaction(&messages, 4).unwrap();
+        assert_eq!(to_summarize.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), vec!["q1", "a1"]);
+        assert_eq!(kept.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), vec!["Be helpful.", "q2", "a2", "q3", "a3"]);
+        assert!(build_summary_request(&to_summarize)[0].content.contains("user: q1"));
+
+        let compacted = insert_compaction_summary(kept, "they asked q1");
+        assert_eq!(compacted[0].content, "Be helpful.");
+        assert_eq!(compacted[1].role, "system");
+        assert!(compacted[1].content.ends_with("they asked q1"));
+        assert_eq!(compacted[2].content, "q2");
+
+        assert!(split_for_compaction(&messages[..3], 4).is_none());
+
+        // /context lists the prompt, the turns left, and what was compacted
+        let report = format_context_report(&ContextReport {
+            messages: &compacted,
+            prompt_file: Some(("/prompts/helpful.txt", 12)),
+            summarized_messages: to_summarize.len(),
+            used_tokens: 400,
+            context_size: 4096,
+        });
+        assert!(report.contains("System prompt: ~3 tokens: Be helpful."));
+        assert!(report.contains("Files: /prompts/helpful.txt (12 B)"));
+        assert!(report.contains("Turns (4):"));
+        assert!(report.contains("Compacted: 2 earlier message(s) replaced by 1 summary message(s)"));
+        let report = format_context_report(&ContextReport {
+            messages: &messages[..1], prompt_file: None, summarized_messages: 0, used_tokens: 0, context_size: 4096,
+        });
+        assert!(report.contains("Turns: none yet") && report.contains("Compacted: nothing"));
+    }
 
-/// Handles prompt directory setup, creating a default if needed
-fn setup_prompt_directory() -> Result<String, String> {
-    println!("\nPrompt Directory Setup:");
-    println!("Prompts are text files that will be used to start conversations with LLaMA.");
-    
-    let prompts_dir = match prompt_yes_no("Do you already have a directory containing prompt files?") {
-        Ok(true) => {
-            prompt_for_directory("Enter the path to your existing prompts directory")?
-        },
-        Ok(false) => {
-            // Create default prompts directory in current working directory
-            let default_prompts_dir = "prompts";
-            println!("DEBUG: Creating default prompts directory: {}", default_prompts_dir);
-            fs::create_dir_all(default_prompts_dir)
-                .map_err(|e| format!("Failed to create prompts directory: {}", e))?;
-            
-            println!("\nCreated new prompts directory: {}/", default_prompts_dir);
-            println!("You can add your prompt text files here.");
-            default_prompts_dir.to_string()
-        },
-        Err(e) => return Err(format!("Error during prompt: {}", e))
-    };
+    #[test]
+    fn test_update_mode_entry_parameters() {
+        let content = "mode_2 = \"/b.gguf|p.txt|temp=0.8|b|second\"\nmode_1 = \"/a.gguf|p.txt|a|first\"\n";
+        let updated = update_mode_entry_parameters(content, 1, &[("temp", "0.5".to_string())]).unwrap();
+        assert!(updated.contains("mode_2 = \"/b.gguf|p.txt|temp=0.5|b|second\""));
+
+        let updated = update_mode_entry_parameters(content, 0, &[("top_k", "20".to_string())]).unwrap();
+        assert!(updated.contains("mode_1 = \"/a.gguf|p.txt|top_k=20|a|first\""));
+
+        assert!(update_mode_entry_parameters(content, 5, &[]).is_err());
+
+        // Table modes come after the mode_N strings
+        let content = "mode_1 = \"/a.gguf|p.txt|a|first\"\n\n[mode.fast]\n# tuned\ntemp = 0.8\nmodel = \"/f.gguf\"\n\n[aliases]\nf = \"fast\"\n";
+        let updated = update_mode_entry_parameters(content, 1, &[("temp", "0.5".to_string()), ("disabled", "archived".to_string())]).unwrap();
+        assert_eq!(updated, content.replace("temp = 0.8\nmodel = \"/f.gguf\"\n", "temp = 0.5\nmodel = \"/f.gguf\"\ndisabled = \"archived\"\n"));
+        let updated = update_mode_entry_prompt_path(content, 1, "/p/new.txt").unwrap();
+        assert_eq!(updated, content);
+        let updated = update_mode_entry_model_path(content, 1, "/g.gguf").unwrap();
+        assert!(updated.contains("model = \"/g.gguf\"\n\n[aliases]"));
+        assert!(update_mode_entry_parameters(content, 2, &[]).is_err());
+    }
 
-    // Always create blankprompt.txt
-    println!("\nCreating blank prompt file...");
-    let blank_prompt_path = create_blank_prompt()?;
-    
-    // Verify the file exists
-    if !Path::new(&blank_prompt_path).exists() {
-        return Err(format!("Failed to verify blank prompt file exists at: {}", blank_prompt_path));
+    #[test]
+    fn test_edit_mode_entry() {
+        let content = "mode_1 = \"/a.gguf|p.txt|temp=0.8|a|first\"\nmode_2 = \"||extends=a|b|second\"\n\n[mode.fast]\nmodel = \"/f.gguf\"\n";
+        let updated = set_mode_entry_files(content, 0, Some("/new.gguf"), Some("/q.txt")).unwrap();
+        assert!(updated.starts_with("mode_1 = \"/new.gguf|/q.txt|temp=0.8|a|first\"\n"));
+        // Inherited paths become the mode's own
+        let updated = set_mode_entry_files(content, 1, None, Some("/q.txt")).unwrap();
+        assert!(updated.contains("mode_2 = \"|/q.txt|extends=a|b|second\"\n"));
+        let updated = set_mode_entry_files(content, 2, None, Some("/q.txt")).unwrap();
+        assert!(updated.ends_with("[mode.fast]\nmodel = \"/f.gguf\"\nprompt = \"/q.txt\"\n"));
+
+        let before = LlamaCppParameters::default();
+        let mut after = before.clone();
+        assert!(changed_parameter_settings(&before, &after).is_empty());
+        after.temperature_value = 0.2;
+        after.interactive_first = !before.interactive_first;
+        assert_eq!(changed_parameter_settings(&before, &after), vec![
+            ("temp", "0.2".to_string()),
+            ("interactive_first", after.interactive_first.to_string()),
+        ]);
     }
 
-    // Print current directory and file listing for debugging
-    println!("DEBUG: Current directory: {:?}", std::env::current_dir().unwrap_or_default());
-    println!("DEBUG: Contents of prompts directory:");
-    if let Ok(entries) = fs::read_dir("prompts") {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                println!("  {:?}", entry.path());
-            }
+    #[test]
+    fn test_resolve_launch_paths() {
+        let mut mode = make_test_mode("dry");
+        mode.model_path = "models/a.gguf".to_string();
+        mode.prompt_path = "/p/a.txt".to_string();
+        resolve_launch_paths(&mut mode);
+        assert_eq!(PathBuf::from(&mode.model_path), std::env::current_dir().unwrap().join("models/a.gguf"));
+        assert_eq!(mode.prompt_path, "/p/a.txt");
+        assert_eq!(resolve_program_path("/opt/llama/llama-cli"), "/opt/llama/llama-cli");
+        assert_eq!(resolve_program_path("no-such-program-here"), "no-such-program-here");
+        assert!(parse_launch_options(&["--dry-run".to_string()]).unwrap().dry_run);
+    }
+
+    #[test]
+    fn test_data_dir() {
+        let exists = |dirs: &'static [&'static str]| move |path: &Path| dirs.iter().any(|dir| Path::new(dir) == path);
+        assert_eq!(choose_data_dir(Some(Path::new("/data/q")), Some("~/.local/share/q"), "/home/me", exists(&[])), PathBuf::from("/data/q"));
+        assert_eq!(choose_data_dir(None, None, "/home/me", exists(&[])), PathBuf::from("/home/me/query_gguf"));
+        assert_eq!(choose_data_dir(None, Some("~/.local/share/q"), "/home/me", exists(&[])), PathBuf::from("/home/me/.local/share/q"));
+        // An old ~/query_gguf is kept until it is migrated
+        assert_eq!(choose_data_dir(None, Some("~/.local/share/q"), "/home/me", exists(&["/home/me/query_gguf"])), PathBuf::from("/home/me/query_gguf"));
+        assert_eq!(
+            choose_data_dir(None, Some("~/.local/share/q"), "/home/me", exists(&["/home/me/query_gguf", "/home/me/.local/share/q"])),
+            PathBuf::from("/home/me/.local/share/q")
+        );
+
+        let mut args: Vec<String> = ["query_gguf", "--data-dir", "/d", "2"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(take_data_dir_flag(&mut args).as_deref(), Some("/d"));
+        assert_eq!(args, vec!["query_gguf", "2"]);
+        let mut args: Vec<String> = ["query_gguf", "--data-dir=/d"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(take_data_dir_flag(&mut args).as_deref(), Some("/d"));
+        let mut args: Vec<String> = ["query_gguf", "--data-dir"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(take_data_dir_flag(&mut args).as_deref(), Some(""));
+
+        let config = "\
+log_directory_path = \"query_gguf/chatlogs\"
+prompt_directory = \"/home/me/query_gguf/prompts\"
+prompt_directory_2 = \"~/query_gguf2/prompts\"
+mode_1 = \"/models/a.gguf|~/query_gguf/prompts/a.txt|a|first\"
+tts_command = \"say -f /home/me/query_gguf/tts.txt\"
+";
+        let (updated, count) = rewrite_data_dir_paths(config, Path::new("/home/me/query_gguf"), Path::new("/home/me/.local/share/query_gguf"), "/home/me");
+        assert_eq!(count, 4);
+        assert_eq!(updated, "\
+log_directory_path = \"/home/me/.local/share/query_gguf/chatlogs\"
+prompt_directory = \"/home/me/.local/share/query_gguf/prompts\"
+prompt_directory_2 = \"~/query_gguf2/prompts\"
+mode_1 = \"/models/a.gguf|/home/me/.local/share/query_gguf/prompts/a.txt|a|first\"
+tts_command = \"say -f /home/me/.local/share/query_gguf/tts.txt\"
+");
+
+        // Links are copied as links, so a loop does not recurse
+        #[cfg(unix)]
+        {
+            let dir = std::env::temp_dir().join(format!("query_gguf_copy_dir_{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("from/prompts")).unwrap();
+            fs::write(dir.join("from/prompts/a.txt"), "a").unwrap();
+            std::os::unix::fs::symlink("..", dir.join("from/prompts/loop")).unwrap();
+            copy_dir_recursive(&dir.join("from"), &dir.join("to")).unwrap();
+            assert_eq!(fs::read_to_string(dir.join("to/prompts/a.txt")).unwrap(), "a");
+            assert_eq!(fs::read_link(dir.join("to/prompts/loop")).unwrap(), PathBuf::from(".."));
+            fs::remove_dir_all(&dir).unwrap();
         }
     }
 
-    Ok(prompts_dir)
-}
+    #[test]
+    fn test_mode_search_matches() {
+        let mut mode = make_test_mode("coder");
+        mode.name = "Careful Coder".to_string();
+        mode.model_path = "/models/Qwen2.5-Coder-7B.Q4_K_M.gguf".to_string();
+        mode.prompt_path = "/prompts/coder.txt".to_string();
+        let prompt = "You write Rust.\nPrefer small QWEN-style answers.\n";
+        assert_eq!(mode_search_matches(&mode, prompt, "qwen"), vec![
+            ("model", "Qwen2.5-Coder-7B.Q4_K_M.gguf".to_string()),
+            ("prompt", "Prefer small QWEN-style answers.".to_string()),
+        ]);
+        assert_eq!(mode_search_matches(&mode, prompt, "CODER").len(), 4);
+        assert!(mode_search_matches(&mode, prompt, "llama").is_empty());
+    }
 
-// /// not used now, maybe in future
-// /// Sets up the logging directory, using default or custom path
-// fn setup_log_directory() -> Result<String, String> {
-//     let default_log_dir = "query_gguf/chatlogs";
-    
-//     println!("\nLog Directory Setup:");
-//     println!("Chat logs will be saved in: {}/", default_log_dir);
+    #[test]
+    fn test_mode_management() {
+        let content = "\
+default_mode = \"3\"
+# Mode 1 - a - first
+mode_1 = \"/a.gguf|p.txt|a|first\"
+# Mode 2 - b - second
+mode_2 = \"/b.gguf|p.txt|temp=0.5|b|second\"
+mode_4 = \"/c.gguf|p.txt|id=cee|c|third\"
+
+[mode.fast]
+model = \"/f.gguf\"
+temp = 0.8
+
+# Shortcuts
+[aliases]
+f = \"fast\"
+";
+        // Later mode_N entries and a numeric default_mode follow the deletion
+        let updated = remove_mode_entry(content, 1, "b").unwrap();
+        assert!(updated.starts_with("default_mode = \"2\"\n# Mode 1 - a - first\nmode_1 = \"/a.gguf|p.txt|a|first\"\nmode_2 = \"/c.gguf|p.txt|id=cee|c|third\"\n\n[mode.fast]"));
+        let updated = remove_mode_entry(content, 2, "cee").unwrap();
+        assert!(!updated.contains("default_mode") && updated.contains("mode_2 = \"/b.gguf"));
+        let updated = remove_mode_entry(content, 3, "fast").unwrap();
+        assert!(updated.contains("mode_4 = \"/c.gguf|p.txt|id=cee|c|third\"\n\n# Shortcuts\n[aliases]"));
+        assert!(config_mode_entries(&toml_lite::parse(&updated).0).len() == 3);
+        assert!(remove_mode_entry(content, 4, "x").is_err());
+
+        // Renaming keeps the id
+        let updated = rename_mode_entry(content, 1, "b", "Bee").unwrap();
+        assert!(updated.contains("mode_2 = \"/b.gguf|p.txt|temp=0.5|id=b|Bee|second\""));
+        let updated = rename_mode_entry(content, 2, "cee", "Sea").unwrap();
+        assert!(updated.contains("mode_4 = \"/c.gguf|p.txt|id=cee|Sea|third\""));
+        let updated = rename_mode_entry(content, 3, "fast", "Fast | short").unwrap();
+        assert!(updated.contains("temp = 0.8\nname = \"Fast | short\"\n"));
+        assert!(rename_mode_entry(content, 0, "a", "a|b").is_err());
+
+        // A copy keeps the settings as written, with the new table name as id
+        let entry = config_mode_entries(&toml_lite::parse(content).0)[2].clone();
+        let copy = format_mode_entry_table("c-copy", &ModeEntry { name: "c copy".to_string(), ..entry.clone() });
+        assert_eq!(copy, "\n[mode.c-copy]\nname = \"c copy\"\ndescription = \"third\"\nmodel = \"/c.gguf\"\nprompt = \"p.txt\"\n");
+        let parsed = config_mode_entries(&toml_lite::parse(&copy).0);
+        assert_eq!((parsed[0].model.as_str(), parsed[0].setting("id")), ("/c.gguf", Some("c-copy")));
+    }
+
+    #[test]
+    fn test_config_tables() {
+        let content = "\
+llama_cli_path = \"/cpu/llama-cli\"
+mode_1 = \"/m.gguf|p.txt|backend=cuda|a|first\"
+
+[binaries.cuda]
+path = \"/cuda/llama-cli\"
+
+[binaries.vulkan]
+path = \"/vulkan/llama-cli\"
+";
+        let binaries = llama_binaries(&toml_lite::parse(content).0);
+        assert_eq!(binaries, vec![
+            ("cuda".to_string(), "/cuda/llama-cli".to_string()),
+            ("vulkan".to_string(), "/vulkan/llama-cli".to_string()),
+        ]);
+
+        // Same key in two tables is not a duplicate; unknown tables are errors
+        assert!(validate_config_strict(content).is_ok());
+        let error = validate_config_strict("[binaries.cuda]\npath = \"a\"\nflags = 1\n[gpu]\n").unwrap_err();
+        assert!(error.contains("line 3: unknown key 'flags' in [binaries.cuda]"));
+        assert!(error.contains("line 4: unknown table [gpu]"));
+
+        // Lint keeps modes above the tables
+        let (fixed, _) = lint_config_content(content, "/home/me");
+        let mode_position = fixed.find("mode_1").unwrap();
+        let table_position = fixed.find("[binaries.cuda]").unwrap();
+        assert!(mode_position < table_position);
+
+        let (top, tables) = split_config_tables(content);
+        assert_eq!(format!("{}{}", top, tables), content);
+        assert!(tables.starts_with("[binaries.cuda]"));
+    }
+
+    #[test]
+    fn test_render_prompt_text() {
+        assert_eq!(render_prompt_text("You are helpful.\n\n", None, Some("Why?")), "You are helpful.\n\nWhy?\n");
+        assert_eq!(render_prompt_text("", None, Some("Why?")), "Why?\n");
+        assert_eq!(
+            render_prompt_text("Be brief.", Some("log line 1\nlog line 2\n"), Some("why is this crashing?")),
+            "Be brief.\n\n### Context ###\nlog line 1\nlog line 2\n\nwhy is this crashing?\n"
+        );
+    }
+
+    #[test]
+    fn test_ask_placeholders() {
+        let prompt = "Review {{ask:Project name}} in {{ask: Language }}. {{ask:Project name}} is old. {{not:this}}";
+        assert_eq!(find_placeholders(prompt, "ask"), vec!["Project name", "Language"]);
+
+        let given = vec![("project-name".to_string(), "crate".to_string()), ("Language".to_string(), "Rust".to_string())];
+        let values = collect_prompt_variables(prompt, &given, false).unwrap();
+        assert_eq!(
+            substitute_placeholders(prompt, &[("ask", &values)]),
+            "Review crate in Rust. crate is old. {{not:this}}"
+        );
+
+        // An answer containing a placeholder is inserted as it is, never run
+        let prompt = "Project: {{ask:Name}}\nLog: {{cmd:git log -1}}";
+        let values = vec![("Name".to_string(), "{{cmd:git log -1}}".to_string())];
+        let outputs = vec![("git log -1".to_string(), "abc123 Fix".to_string())];
+        assert_eq!(
+            substitute_placeholders(prompt, &[("ask", &values), ("cmd", &outputs)]),
+            "Project: {{cmd:git log -1}}\nLog: abc123 Fix"
+        );
+
+        assert!(collect_prompt_variables(prompt, &given[1..], false).is_err());
+    }
+
+    #[test]
+    fn test_prompt_commands() {
+        let prompt = "Recent work:\n{{cmd:git log -5 --oneline}}\nStatus: {{cmd: git status --short }}";
+        assert_eq!(find_placeholders(prompt, "cmd"), vec!["git log -5 --oneline", "git status --short"]);
+
+        let outputs = vec![("git log -5 --oneline".to_string(), "abc123 Fix".to_string())];
+        assert_eq!(
+            substitute_placeholders(prompt, &[("cmd", &outputs)]),
+            "Recent work:\nabc123 Fix\nStatus: {{cmd: git status --short }}"
+        );
+
+        assert_eq!(split_command_line("grep -n \"two words\" 'a b' plain"), vec!["grep", "-n", "two words", "a b", "plain"]);
+        assert_eq!(split_command_line("echo ''"), vec!["echo", ""]);
+
+        assert!(is_command_allowed("git", "git, ps,journalctl"));
+        assert!(!is_command_allowed("rm", "git, ps"));
+        assert!(!is_command_allowed("", ""));
+    }
+
+    #[test]
+    fn test_agent_tool_calls() {
+        let reply = "Let me check.\nTOOL: {\"tool\": \"run_command\", \"command\": \"git log -5\"}";
+        let call = parse_tool_call(reply).unwrap();
+        assert_eq!(call.name, "run_command");
+        assert_eq!(call.arguments.get("command").and_then(json::Value::as_str), Some("git log -5"));
+        let call = parse_tool_call("TOOL: {\"tool\": \"read_file\", \"path\": \"a}b\"} (checking the file)").unwrap();
+        assert_eq!(call.arguments.get("path").and_then(json::Value::as_str), Some("a}b"));
+        assert_eq!(parse_tool_call("The answer is 42."), None);
+
+        let root = std::env::temp_dir().join(format!("query_gguf_agent_{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+        assert!(resolve_path_under_root(&root, "src/main.rs").is_ok());
+        assert!(resolve_path_under_root(&root, "src/../src/main.rs").is_ok());
+        assert!(resolve_path_under_root(&root, "..").unwrap_err().contains("outside"));
+        assert!(resolve_path_under_root(&root, "/etc/hostname").is_err());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_platform_defaults() {
+        assert_eq!(classify_platform("macos", "aarch64"), PlatformKind::AppleSilicon);
+        assert_eq!(classify_platform("linux", "aarch64"), PlatformKind::ArmBoard);
+        assert_eq!(classify_platform("linux", "arm"), PlatformKind::ArmBoard);
+        assert_eq!(classify_platform("macos", "x86_64"), PlatformKind::Other);
+
+        // 4 big cores at 2.4 GHz, 4 little at 1.8 GHz (e.g. RK3588)
+        assert_eq!(count_performance_cores(&[1800000, 1800000, 1800000, 1800000, 2400000, 2400000, 2400000, 2400000]), 4);
+        assert_eq!(count_performance_cores(&[]), 0);
+
+        assert!(slow_quantization_warning(PlatformKind::ArmBoard, "IQ3_XS").is_some());
+        assert!(slow_quantization_warning(PlatformKind::ArmBoard, "Q4_0").is_none());
+        assert!(slow_quantization_warning(PlatformKind::AppleSilicon, "bf16").is_some());
+        assert!(slow_quantization_warning(PlatformKind::Other, "IQ3_XS").is_none());
+
+        assert!(default_thread_count() >= 1);
+        assert_eq!(default_gpu_layers() > 0, detect_platform() == PlatformKind::AppleSilicon);
+    }
+
+    #[test]
+    fn test_endpoint_secrets() {
+        assert_eq!(url_host("https://api.example.com/v1"), "api.example.com");
+        assert_eq!(url_host("http://me:pw@gpubox:8080"), "gpubox");
+        assert_eq!(url_host("gpubox:8080"), "gpubox");
+        assert_eq!(url_host("http://[::1]:8080/v1"), "[::1]");
+        assert_eq!(url_host("http://[fe80::2]"), "[fe80::2]");
+
+        assert_eq!(mask_secret("bad key sk-123 for sk-123", "sk-123"), "bad key **** for ****");
+        assert_eq!(mask_secret("no secret", ""), "no secret");
+    }
+
+    #[test]
+    fn test_endpoint_retry_and_rate_limit() {
+        assert!(is_retryable_status(503) && is_retryable_status(429));
+        assert!(!is_retryable_status(400) && !is_retryable_status(200));
+
+        assert_eq!(endpoint_retry_delay(0).as_secs(), 1);
+        assert_eq!(endpoint_retry_delay(2).as_secs(), 4);
+        assert_eq!(endpoint_retry_delay(10).as_secs(), MAX_ENDPOINT_RETRY_DELAY_SECS);
+
+        assert_eq!(rate_limit_wait_secs(&[100, 110], 120, 0), None);
+        assert_eq!(rate_limit_wait_secs(&[100, 110], 120, 3), None);
+        // Two requests in the last minute with a limit of 2: wait until the first is 60s old
+        assert_eq!(rate_limit_wait_secs(&[100, 110], 120, 2), Some(40));
+        // Requests older than a minute do not count
+        assert_eq!(rate_limit_wait_secs(&[10, 110], 120, 2), None);
+
+        // Through the state file, with a dotted host
+        let dir = std::env::temp_dir().join(format!("query_gguf_rate_limit_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let state_path = dir.join("state.toml");
+        assert_eq!(endpoint_requests_field("api.example.com:8443"), "endpoint_requests_api_example_com_8443");
+        for _ in 0..3 {
+            assert!(record_endpoint_request(&state_path, "api.example.com", 3, 1000).is_ok());
+        }
+        let error = record_endpoint_request(&state_path, "api.example.com", 3, 1001).unwrap_err();
+        assert!(error.contains("Rate limit reached for api.example.com"), "{}", error);
+        assert!(record_endpoint_request(&state_path, "other.example.com", 3, 1001).is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_remote_llama_command() {
+        let mut mode = make_test_mode("big");
+        mode.model_path = "/srv/models/big model.gguf".to_string();
+        mode.remote = "me@gpubox".to_string();
+        let command = remote_llama_command("llama-cli", &mode, ".query_gguf_remote/big_1", "prompt.txt", false);
+        assert!(command.starts_with("cd '.query_gguf_remote/big_1' && 'llama-cli' '-m' '/srv/models/big model.gguf' '--file' 'prompt.txt'"));
+        assert!(command.contains("'-no-cnv'"));
+        assert!(command.ends_with("2> llama_cli.log"));
+
+        assert_eq!(sh_quote("it's"), "'it'\\''s'");
+
+        // Local sessions: paths with spaces, quotes, or $ stay one argument
+        let args: Vec<OsString> = ["/opt/llama cpp/llama-cli", "-m", "/models/it's $HOME.gguf", "--in-prefix", " "]
+            .iter().map(OsString::from).collect();
+        assert_eq!(shell_command_line(&args, false), "'/opt/llama cpp/llama-cli' -m '/models/it'\\''s $HOME.gguf' --in-prefix ' '");
+        assert_eq!(shell_command_line(&args[..3], true), "\"/opt/llama cpp/llama-cli\" -m \"/models/it's $HOME.gguf\"");
+        let params = LlamaCppParameters { gpu_layers: 0, in_prefix: "User: ".to_string(), ..LlamaCppParameters::default() };
+        let flags = llama_session_flags(&params);
+        assert!(!flags.contains(&"--n-gpu-layers".to_string()));
+        assert!(flags.windows(2).any(|pair| pair[0] == "--in-prefix" && pair[1] == "User: "));
+    }
+
+    #[test]
+    fn test_docker_run_args() {
+        let mut mode = make_test_mode("coder");
+        mode.model_path = "/nonexistent/models/tiny-Q4_0.gguf".to_string();
+        mode.prompt_path = "/nonexistent/prompts/code.txt".to_string();
+
+        let args = docker_run_args(&mode, DEFAULT_DOCKER_CUDA_IMAGE, "all", true, false).unwrap();
+        assert_eq!(args, vec![
+            "run", "-it", "--name", "query_gguf-coder",
+            "-v", "/nonexistent/models:/models:ro",
+            "-v", "/nonexistent/prompts:/prompts:ro",
+            "--gpus", "all",
+            DEFAULT_DOCKER_CUDA_IMAGE,
+            "-m", "/models/tiny-Q4_0.gguf",
+            "--file", "/prompts/code.txt",
+        ]);
+        let args = docker_run_args(&mode, DEFAULT_DOCKER_IMAGE, "", false, false).unwrap();
+        assert!(!args.contains(&"-it".to_string()) && !args.contains(&"--gpus".to_string()));
+        assert!(!args.contains(&"--pull=never".to_string()));
+        // Offline, docker must not pull a missing image
+        let args = docker_run_args(&mode, DEFAULT_DOCKER_IMAGE, "", false, true).unwrap();
+        assert_eq!(&args[..2], &["run", "--pull=never"]);
+
+        assert_eq!(shell_command_line(&[OsString::from("/models:/models:ro")], false), "/models:/models:ro");
+        assert_eq!(shell_command_line(&[OsString::from("/my models:/models:ro")], false), "'/my models:/models:ro'");
+    }
+
+    #[test]
+    fn test_headless_launch() {
+        assert!(is_container_cgroup("0::/system.slice/docker-3f2a.scope\n"));
+        assert!(is_container_cgroup("12:memory:/kubepods/burstable/pod1234\n"));
+        assert!(!is_container_cgroup("0::/init.scope\n"));
+
+        assert_eq!(choose_terminal_launch(false, true, "xterm-256color", true, false), TerminalLaunch::NewWindow);
+        assert_eq!(choose_terminal_launch(true, true, "xterm", true, false), TerminalLaunch::CurrentTerminal);
+        assert_eq!(choose_terminal_launch(false, false, "xterm", true, false), TerminalLaunch::CurrentTerminal);
+        assert_eq!(choose_terminal_launch(false, true, "dumb", true, false), TerminalLaunch::CurrentTerminal);
+        assert_eq!(choose_terminal_launch(false, true, "xterm", false, false), TerminalLaunch::Piped);
+        // --inline (or tmux/ssh) stays in this terminal even with a desktop
+        assert_eq!(choose_terminal_launch(false, true, "xterm-256color", true, true), TerminalLaunch::CurrentTerminal);
+        assert_eq!(choose_terminal_launch(false, true, "xterm", false, true), TerminalLaunch::Piped);
+    }
+
+    #[test]
+    fn test_hardware_detection() {
+        // Only where detection is supported, so the test passes on any CI runner
+        let supported = cfg!(any(target_os = "linux", target_os = "macos")) || is_bsd();
+        if supported {
+            assert!(detect_cpu_count().unwrap_or(0) >= 1);
+            assert!(detect_total_ram_mb() > 0);
+        }
+        assert!(get_system_cpu_count() >= 1);
+        assert!(!(is_bsd() && cfg!(target_os = "linux")));
+    }
+
+    #[test]
+    fn test_wsl_paths() {
+        assert!(is_wsl_kernel("Linux version 5.15.153.1-microsoft-standard-WSL2 (gcc ...)"));
+        assert!(!is_wsl_kernel("Linux version 6.8.0-45-generic (buildd@lcy02)"));
+
+        assert_eq!(windows_path_to_wsl("C:\\models\\llama\\a.gguf"), Some("/mnt/c/models/llama/a.gguf".to_string()));
+        assert_eq!(windows_path_to_wsl("d:/models"), Some("/mnt/d/models".to_string()));
+        assert_eq!(windows_path_to_wsl("/home/me/models"), None);
+        assert_eq!(windows_path_to_wsl("models:old"), None);
+    }
+
+    #[test]
+    fn test_preloaded_server_entry() {
+        let server = PreloadedServer {
+            mode_id: "coder".to_string(),
+            model_path: "/models/my=model.gguf".to_string(),
+            port: 8081,
+            pid: 4242,
+            idle_minutes: 30,
+        };
+        assert_eq!(PreloadedServer::parse(&server.to_text()), Some(server));
+        assert_eq!(PreloadedServer::parse("mode_id=coder\nport=80\n"), None);
+    }
+
+    #[test]
+    fn test_parse_running_models() {
+        let ps_output = "  101 /usr/bin/bash\n\
+            \x20 202 /opt/llama.cpp/llama-server -m /models/big.gguf --ctx-size 4096 --port 8081\n\
+            \x20 303 llama-cli -m /models/big.gguf --file p.txt\n\
+            \x20 404 vim /models/big.gguf\n\
+            \x20 505 llama-cli -m /models/small.gguf\n";
+        assert_eq!(parse_running_models(ps_output, "/models/big.gguf"), vec![
+            RunningModel { pid: 202, program: "llama-server".to_string(), port: Some(8081) },
+            RunningModel { pid: 303, program: "llama-cli".to_string(), port: None },
+        ]);
+        assert!(parse_running_models(ps_output, "/models/other.gguf").is_empty());
+    }
+
+    #[test]
+    fn test_mode_snapshot() {
+        let mut mode = make_test_mode("coder");
+        mode.name = "Coder".to_string();
+        mode.model_path = "/models/coder.gguf".to_string();
+        mode.prompt_path = "/prompts/code.txt".to_string();
+        let snapshot = format_mode_snapshot(&mode, "fnv1a:0000000000000001", "You write code.\n");
+        assert!(snapshot.starts_with("### session\n[mode.coder]\nname = \"Coder\"\n"));
+        assert!(snapshot.contains("\nmodel = \"/models/coder.gguf\"\nprompt = \"/prompts/code.txt\"\ntemp = "));
+        assert!(snapshot.contains(&format!("prompt_hash = \"fnv1a:{:016x}\"", fnv1a_hash(b"You write code.\n"))));
+        assert!(snapshot.ends_with("### prompt file\nYou write code.\n"));
+
+        let model = std::env::temp_dir().join(format!("query_gguf_fingerprint_{}.gguf", std::process::id()));
+        fs::write(&model, b"GGUF model bytes").unwrap();
+        let first = model_fingerprint(&model).unwrap();
+        fs::write(&model, b"GGUF model bytez").unwrap();
+        assert_ne!(model_fingerprint(&model).unwrap(), first);
+        fs::remove_file(&model).unwrap();
+    }
+
+    #[test]
+    fn test_history_index() {
+        let mut index = HistoryIndex::default();
+        index.add_document("a.txt", "### user\nWhy does the borrow checker complain?\n### assistant\nBorrow rules.");
+        index.add_document("b.txt", "### user\nWrite a python script");
+        index.add_document("c.txt", "### user\nborrow a python book");
+
+        let parsed = HistoryIndex::parse(&index.to_text());
+        assert_eq!(parsed, index);
+
+        let results = parsed.search("Borrow checker");
+        assert_eq!(results.iter().map(|(document, _)| *document).collect::<Vec<_>>(), vec![0, 2]);
+        assert!(parsed.search("rust").is_empty());
+
+        assert_eq!(
+            make_search_snippet("### user\nWhy does the borrow checker complain?", "checker"),
+            "Why does the borrow checker complain?"
+        );
+    }
+
+    #[test]
+    fn test_history_diff() {
+        let transcript = "### session\nmode_1 = \"m|p|x|y\"\n\n### prompt file\nBe brief.\n\n\
+            ### user\nWhat is Rust?\n\n### assistant\n### Answer\nA language.\n\n### user\nAnd Go?\n\n### assistant\nAlso one.\n";
+        assert_eq!(transcript_exchanges(transcript), vec![
+            ("What is Rust?".to_string(), "### Answer\nA language.".to_string()),
+            ("And Go?".to_string(), "Also one.".to_string()),
+        ]);
+        assert_eq!(transcript_exchanges("plain log"), vec![(String::new(), "plain log".to_string())]);
+
+        let ops = diff_sequences(&['a', 'b', 'c', 'd'], &['a', 'x', 'c', 'd', 'e']);
+        assert_eq!(ops, vec![
+            DiffOp::Same('a'), DiffOp::Removed('b'), DiffOp::Added('x'),
+            DiffOp::Same('c'), DiffOp::Same('d'), DiffOp::Added('e'),
+        ]);
+        assert_eq!(split_words_for_diff("a  bc d").concat(), "a  bc d");
+        let merged = merge_word_diff(diff_sequences(&split_words_for_diff("Hello there, you"), &split_words_for_diff("Hello, you")));
+        assert_eq!(merged, vec![
+            DiffOp::Removed("Hello there,".to_string()), DiffOp::Added("Hello,".to_string()), DiffOp::Same(" you".to_string()),
+        ]);
+
+        let unified = render_unified_word_diff("same line\nthe quick fox", "same line\nthe slow fox", false);
+        assert_eq!(unified, "  same line\n~ the [-quick-]{+slow+} fox\n");
+
+        let side = render_side_by_side_diff("one\nred", "one\nblue", 8, false);
+        assert_eq!(side, "one      | one\n[-red-]  ~ {+blue+}\n");
+        // Long lines wrap within their column
+        assert_eq!(layout_diff_column(&[("abcdef".to_string(), None)], 4), vec!["abcd", "ef  "]);
+    }
+
+    #[test]
+    fn test_chat_regen_and_edit() {
+        assert_eq!(parse_regen_options(""), Ok((None, None)));
+        assert_eq!(parse_regen_options("temp=1.2 seed=7"), Ok((Some(7), Some(1.2))));
+        assert!(parse_regen_options("seed=x").is_err());
+        assert!(parse_regen_options("top_k=3").is_err());
+
+        let message = |role: &str, content: &str| ChatMessage { role: role.to_string(), content: content.to_string() };
+        let mut messages = vec![message("system", "s"), message("user", "hi"), message("assistant", "hello")];
+        assert_eq!(take_last_exchange(&mut messages), Some("hi".to_string()));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(take_last_exchange(&mut messages), None);
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn test_extract_code_blocks() {
+        let reply = "Here:\n```rust\n// file: src/main.rs\nfn main() {}\n```\nAnd:\n```\necho hi\n```\n```py\nprint(1)";
+        let blocks = extract_code_blocks(reply);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].suggested_path.as_deref(), Some("src/main.rs"));
+        assert_eq!(blocks[0].code, "fn main() {}\n");
+        assert_eq!(blocks[1].language, "");
+        assert_eq!(blocks[1].suggested_path, None);
+        assert_eq!(blocks[2].code, "print(1)\n");
+        assert_eq!(default_code_file_name(3, "py"), "snippet_3.py");
+
+        assert!(is_safe_relative_path("src/main.rs"));
+        assert!(!is_safe_relative_path("../etc/passwd"));
+        assert!(!is_safe_relative_path("/etc/passwd"));
+    }
+
+    #[test]
+    fn test_extract_unified_diff() {
+        let reply = "Sure:\n```diff\n--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1 +1 @@\n-old\n+new\n```\nDone.";
+        let diff = extract_unified_diff(reply).unwrap();
+        assert!(diff.starts_with("--- a/src/main.rs\n"));
+        assert!(diff.ends_with("+new\n"));
+        assert_eq!(diff_file_paths(&diff), (vec!["src/main.rs".to_string()], 1));
+
+        let bare = "--- lib.rs\n+++ lib.rs\n@@ -1 +1 @@\n-a\n+b";
+        assert_eq!(diff_file_paths(&extract_unified_diff(bare).unwrap()), (vec!["lib.rs".to_string()], 0));
+        let new_file = "--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1 @@\n+hi\n";
+        assert_eq!(diff_file_paths(new_file), (vec!["new.txt".to_string()], 1));
+
+        assert_eq!(extract_unified_diff("no diff here"), None);
+    }
+
+    #[test]
+    fn test_extract_outline() {
+        let rust = "use std::fs;\n\npub struct Scan {\n    tree: String,\n}\n\nimpl Scan {\n    pub(crate) async fn run(&self) -> u8 {\n        let fn_count = 1;\n        0\n    }\n}\nconst MAX: usize = 3;\n";
+        assert_eq!(
+            extract_outline(Path::new("src/lib.rs"), rust).unwrap(),
+            "pub struct Scan\nimpl Scan\n    pub(crate) async fn run(&self) -> u8\nconst MAX: usize = 3;"
+        );
+        let python = "import os\n\nclass Scan:\n    def run(self):\n        return 1\n\nasync def main():\n    pass\n";
+        assert_eq!(
+            extract_outline(Path::new("tool.py"), python).unwrap(),
+            "class Scan:\n    def run(self):\nasync def main():"
+        );
+        assert_eq!(extract_outline(Path::new("notes.md"), "# Notes"), None);
+
+        let options = parse_launch_options(&["--outline".to_string()]).unwrap();
+        assert!(options.outline);
+    }
+
+    #[test]
+    fn test_scan_throttle() {
+        let mut throttle = ScanThrottle::new(1, None);
+        let start = throttle.last_refill;
+        let at = |millis: u64| start + std::time::Duration::from_millis(millis);
+        assert!(throttle.take(512, at(0)).is_zero());
+        // 512 bytes left of 1024; 1024 more is half a second of debt
+        assert_eq!(throttle.take(1024, at(0)).as_millis(), 500);
+        assert!(throttle.take(0, at(500)).is_zero());
+        assert_eq!(throttle.take(256, at(500)).as_millis(), 250);
+        // The bucket holds at most one second's worth
+        assert_eq!(throttle.take(2048, at(10_000)).as_millis(), 1000);
+        assert!(ScanThrottle::new(0, None).take(u64::MAX, at(0)).is_zero());
+
+        let index = vec!["/models/a.gguf".to_string(), "/models/sub/b.gguf".to_string(), "/old/c.gguf".to_string()];
+        let found = vec!["/models/a.gguf".to_string()];
+        assert_eq!(merge_scan_index(&found, &index, &[PathBuf::from("/models")]), vec!["/models/a.gguf", "/models/sub/b.gguf"]);
+    }
+
+    #[test]
+    fn test_scan_directory_depth_and_tree_only() {
+        let root = std::env::temp_dir().join(format!("query_gguf_scan_{}", std::process::id()));
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::write(root.join("README.md"), "readme text").unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(root.join("src/nested/deep.rs"), "fn deep() {}").unwrap();
+
+        let full = scan_directory(&root, "", &ScanOptions::default(), &mut ScanFilter::new(Vec::new(), 0, 0), &mut ScanThrottle::new(0, None)).unwrap();
+        assert!(full.tree_structure.contains("deep.rs"));
+        assert!(full.file_contents.contains("fn deep() {}"));
+        assert_eq!(full.file_count, 3);
+
+        let shallow = scan_directory(&root, "", &ScanOptions { max_depth: Some(2), ..ScanOptions::default() }, &mut ScanFilter::new(Vec::new(), 0, 0), &mut ScanThrottle::new(0, None)).unwrap();
+        assert!(shallow.tree_structure.contains("main.rs"));
+        assert!(shallow.tree_structure.contains("nested"));
+        assert!(!shallow.tree_structure.contains("deep.rs"));
+
+        let tree_only = scan_directory(&root, "", &ScanOptions { tree_only: true, ..ScanOptions::default() }, &mut ScanFilter::new(Vec::new(), 0, 0), &mut ScanThrottle::new(0, None)).unwrap();
+        assert!(tree_only.tree_structure.contains("deep.rs"));
+        assert!(tree_only.file_contents.is_empty());
+
+        // Out of time: the tree says where the scan stopped
+        let mut out_of_time = ScanThrottle::new(0, Some(std::time::Duration::ZERO));
+        let stopped = scan_directory(&root, "", &ScanOptions::default(), &mut ScanFilter::new(Vec::new(), 0, 0), &mut out_of_time).unwrap();
+        assert!(out_of_time.ran_out && stopped.file_count == 0);
+        assert!(stopped.tree_structure.contains("scan stopped"));
+
+        let options = parse_launch_options(&["--tree-only".to_string(), "--depth".to_string(), "2".to_string()]).unwrap();
+        assert!(options.tree_only);
+        assert_eq!(options.depth, Some(2));
+        assert!(parse_launch_options(&["--depth".to_string(), "0".to_string()]).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_scan_directory_ignores_and_limits() {
+        let root = std::env::temp_dir().join(format!("query_gguf_scan_filter_{}", std::process::id()));
+        fs::create_dir_all(root.join("src/gen")).unwrap();
+        fs::create_dir_all(root.join("target/debug")).unwrap();
+        fs::write(root.join(".gitignore"), "*.log\n/src/gen/\n!keep.log\n").unwrap();
+        fs::write(root.join("app.log"), "log line").unwrap();
+        fs::write(root.join("keep.log"), "kept").unwrap();
+        fs::write(root.join("big.txt"), "x".repeat(2000)).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(root.join("src/gen/out.rs"), "generated").unwrap();
+        fs::write(root.join("target/debug/x.rs"), "built").unwrap();
+
+        let mut filter = ScanFilter::new(vec!["target".to_string()], 1024, 0);
+        let scan = scan_directory(&root, "", &ScanOptions::default(), &mut filter, &mut ScanThrottle::new(0, None)).unwrap();
+        assert!(!scan.tree_structure.contains("app.log") && !scan.tree_structure.contains("gen"));
+        assert!(scan.file_contents.contains("kept") && scan.file_contents.contains("fn main() {}"));
+        assert!(scan.tree_structure.contains("target [excluded]") && !scan.tree_structure.contains("debug"));
+        assert!(scan.tree_structure.contains("big.txt [2.0 KB, over scan_max_file_kb]"));
+        assert_eq!(scan.file_count, 2);
+        assert_eq!(
+            filter.summary().unwrap(),
+            "Skipped: 2 entries listed in ignore files; excluded: target; over scan_max_file_kb (1.0 KB): big.txt"
+        );
+
+        // The total limit leaves out files once it is reached
+        let mut filter = ScanFilter::new(Vec::new(), 0, 10);
+        let scan = scan_directory(&root.join("src"), "", &ScanOptions::default(), &mut filter, &mut ScanThrottle::new(0, None)).unwrap();
+        assert_eq!(scan.file_count, 1);
+        assert_eq!(filter.skipped.over_total.len(), 1);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(glob_match("**/*.min.js", "a/b/c.min.js") && glob_match("**/*.min.js", "c.min.js"));
+        assert!(glob_match("docs/**", "docs/a/b.md") && !glob_match("*.md", "docs/a.md"));
+    }
+
+    #[test]
+    fn test_describe_file_header() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&640u32.to_be_bytes());
+        png.extend_from_slice(&480u32.to_be_bytes());
+        assert_eq!(describe_file_header(&png, 2048), "PNG image, 640x480, 2.0 KB");
+
+        // SOI, an APP0 segment, then SOF0 with height 200 and width 300
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00,
+            0xFF, 0xC0, 0x00, 0x11, 0x08, 0x00, 0xC8, 0x01, 0x2C, 0x03];
+        assert_eq!(describe_file_header(&jpeg, 100), "JPEG image, 300x200, 100 B");
+
+        assert_eq!(describe_file_header(b"GGUF\x03\0\0\0", 10), "GGUF model, 10 B");
+        assert_eq!(describe_file_header(b"all: build\n", 11), "text file, not included, 11 B");
+        assert_eq!(describe_file_header(&[0, 1, 2, 3], 4), "binary file, 4 B");
+    }
+
+    #[test]
+    fn test_render_markdown() {
+        let rendered = render_markdown("# Title\n- **one** and *two*\n```rust\nlet x = \"a\"; // note\n```\nplain `code`");
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines[0], format!("{}Title{}", STYLE_BOLD, STYLE_RESET));
+        assert_eq!(lines[1], format!("• {}one{} and {}two{}", STYLE_BOLD, STYLE_RESET, STYLE_ITALIC, STYLE_RESET));
+        assert_eq!(lines[3], format!(
+            "{}let{} x = {}\"a\"{}; {}// note{}",
+            STYLE_KEYWORD, STYLE_RESET, STYLE_STRING, STYLE_RESET, STYLE_COMMENT, STYLE_RESET
+        ));
+        assert_eq!(lines[5], format!("plain {}code{}", STYLE_CODE, STYLE_RESET));
+
+        // Unclosed markers and unknown languages are left alone
+        assert_eq!(render_inline_markdown("2 * 3 and a*"), "2 * 3 and a*");
+        assert_eq!(render_markdown("```\nlet x = 1;\n```").lines().nth(1), Some("let x = 1;"));
+        assert_eq!(highlight_code_line("x = 3.5", &code_syntax("py").unwrap()),
+            format!("x = {}3.5{}", STYLE_NUMBER, STYLE_RESET));
+    }
+
+    #[test]
+    fn test_agent_sandbox() {
+        let none_installed = |_: &str| false;
+        let all_installed = |_: &str| true;
+        assert_eq!(choose_sandbox("off", none_installed), Ok(None));
+        assert_eq!(choose_sandbox("firejail", all_installed), Ok(Some(SandboxKind::Firejail)));
+        assert!(choose_sandbox("bwrap", none_installed).is_err());
+        assert!(choose_sandbox("docker", all_installed).is_err());
+        if cfg!(target_os = "linux") {
+            assert_eq!(choose_sandbox("", |program: &str| program == "firejail"), Ok(Some(SandboxKind::Firejail)));
+            assert!(choose_sandbox("auto", none_installed).is_err());
+        }
+
+        let argv = vec!["git".to_string(), "status".to_string()];
+        let wrapped = wrap_in_sandbox(SandboxKind::Bubblewrap, &argv, Path::new("/work/project"));
+        assert_eq!(wrapped[0], "bwrap");
+        assert!(wrapped.contains(&"--unshare-all".to_string()));
+        assert_eq!(&wrapped[wrapped.len() - 3..], &["--", "git", "status"]);
+        assert!(wrap_in_sandbox(SandboxKind::Firejail, &argv, Path::new("/w")).contains(&"--net=none".to_string()));
+        assert_eq!(choose_sandbox("psexec", all_installed), Ok(Some(SandboxKind::PsExec)));
+        let wrapped = wrap_in_sandbox(SandboxKind::PsExec, &argv, Path::new("C:\\work"));
+        assert_eq!(&wrapped[..5], &["psexec", "-nobanner", "-l", "-w", "C:\\work"]);
+        assert_eq!(&wrapped[5..], &["git", "status"]);
+        if cfg!(windows) {
+            assert!(choose_sandbox("auto", all_installed).is_err());
+        }
+    }
+
+    #[test]
+    fn test_tool_registry() {
+        let config = "\
+llama_cli_path = \"/bin/llama-cli\"
+
+[tools.find_text]
+description = \"Search files\"
+parameters = '{\"pattern\": \"text to find\", \"path\": \"where\", \"max\": 5}'
+command = \"grep -rn -m {max} {pattern} {path}\"
+
+[tools.broken]
+description = \"no command\"
+";
+        let tools = parse_tool_definitions(config);
+        assert_eq!(tools.len(), 1);
+        assert_eq!(tool_parameter_names(&tools[0].parameters), vec!["pattern", "path", "max"]);
+        assert!(describe_tools_for_prompt(&tools).contains("- find_text: Search files. Arguments: {\"pattern\""));
+
+        assert_eq!(tools[0].path_parameters, vec!["path"]);
+
+        let root = std::env::temp_dir().join(format!("query_gguf_tool_root_{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        let src = root.join("src").canonicalize().unwrap().to_string_lossy().to_string();
+        let build = |arguments: &str| build_tool_command(&tools[0], &json::parse(arguments).unwrap(), &root);
+        let argv = build(r#"{"tool": "find_text", "pattern": "two words; rm -rf /", "path": "src", "max": 3}"#).unwrap();
+        assert_eq!(argv, vec!["grep", "-rn", "-m", "3", "two words; rm -rf /", &src]);
+        assert!(build(r#"{"pattern": "x"}"#).is_err());
+        // A value is inserted once, never expanded again
+        let argv = build(r#"{"pattern": "{path}", "path": "src", "max": 1}"#).unwrap();
+        assert_eq!(argv, vec!["grep", "-rn", "-m", "1", "{path}", &src]);
+        // Paths stay under the root, and values cannot become options
+        assert!(build(r#"{"pattern": "x", "path": "../../secret", "max": 1}"#).is_err());
+        assert!(build(r#"{"pattern": "x", "path": "/etc", "max": 1}"#).unwrap_err().contains("outside"));
+        assert!(build(r#"{"pattern": "--output=/etc/x", "path": "src", "max": 1}"#).unwrap_err().contains("may not start with '-'"));
+        let _ = fs::remove_dir_all(&root);
+
+        assert!(is_known_table_key("tools.find_text", "command"));
+        assert!(tools[0].sandbox);
+        assert!(validate_config_strict(config).is_ok());
+    }
+
+    #[test]
+    fn test_llama_output_flags() {
+        let params = LlamaCppParameters::default();
+        assert_eq!(llama_output_flags(&params, true), vec!["--no-display-prompt"]);
+
+        let params = parse_parameters_from_parts(&["display_prompt=true", "verbose_prompt=true", "log_disable=true"]);
+        assert_eq!(llama_output_flags(&params, true), vec!["--verbose-prompt", "--log-disable"]);
+        // Never echo the prompt into --print output
+        assert_eq!(llama_output_flags(&params, false), vec!["--no-display-prompt", "--verbose-prompt", "--log-disable"]);
+    }
+
+    #[test]
+    fn test_llama_interaction_flags() {
+        assert!(llama_interaction_flags(&LlamaCppParameters::default()).is_empty());
+
+        let params = parse_parameters_from_parts(&["conversation=false", "multiline_input=true", "in_prefix=User: ", "in_suffix=Assistant:"]);
+        assert_eq!(
+            llama_interaction_flags(&params),
+            vec!["-no-cnv", "--multiline-input", "--in-prefix", "User: ", "--in-suffix", "Assistant:"]
+        );
+        let problems = find_mode_entry_problems("m|p|in_prefix= |conversation=yes|n|d");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("'conversation'"));
+
+        let mut mode = make_test_mode("chat");
+        mode.parameters = params;
+        let remote = remote_llama_command("llama-cli", &mode, "dir", "prompt.txt", true);
+        assert!(remote.contains("'--in-prefix' 'User: '"));
+        assert!(!remote_llama_command("llama-cli", &mode, "dir", "prompt.txt", false).contains("--in-prefix"));
+    }
+
+    #[test]
+    fn test_check_prompt_size() {
+        let mut params = parse_parameters_from_parts(&["max_prompt_bytes=100", "max_files=2"]);
+        assert_eq!(params.max_prompt_bytes, Some(100));
+        assert!(check_prompt_size(100, Some(2), &params).is_ok());
+        let error = check_prompt_size(101, None, &params).unwrap_err();
+        assert!(error.contains("max_prompt_bytes=100") && error.contains("--outline"));
+        assert!(check_prompt_size(10, Some(3), &params).unwrap_err().contains("max_files=2"));
+
+        // Without limits an oversized prompt is only a warning
+        params.max_prompt_bytes = None;
+        params.max_files = None;
+        assert!(check_prompt_size(5_000_000, Some(500), &params).is_ok());
+        assert!(find_mode_entry_problems("m|p|max_files=lots|n|d")[0].contains("max_files"));
+    }
+
+    #[test]
+    fn test_estimate_token_count() {
+        assert_eq!(estimate_token_count(""), 0);
+        assert_eq!(estimate_token_count("abcd"), 1);
+        assert_eq!(estimate_token_count("abcde"), 2);
+        // Characters, not bytes
+        assert_eq!(estimate_token_count("éééé"), 1);
+    }
+
+    #[test]
+    fn test_find_plugin() {
+        assert!(plugin_file_name("hello").starts_with("query_gguf-hello"));
+        assert_eq!(find_plugin("../hello"), None);
+        assert_eq!(find_plugin(""), None);
+        assert_eq!(find_plugin("surely-not-an-installed-plugin"), None);
+
+        // Built-in subcommands win over plugins with the same name
+        let dir = std::env::temp_dir().join(format!("query_gguf_plugins_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for word in ["bench", "hello"] {
+            fs::write(dir.join(plugin_file_name(word)), "#!/bin/sh\n").unwrap();
+        }
+        assert_eq!(find_plugin_in("hello", dir.as_os_str()), Some(dir.join(plugin_file_name("hello"))));
+        assert_eq!(find_plugin_in("bench", dir.as_os_str()), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_expand_alias() {
+        let config = "mode_1 = \"/m.gguf|p.txt|a|b\"\n[binaries.cuda]\npath = \"/x\"\n[aliases]\nreview = \"2 --var 'Project name=q' -q 'review this'\"\n";
+        let aliases = config_aliases(&toml_lite::parse(config).0);
+        assert_eq!(aliases.len(), 1);
+        let args: Vec<String> = ["query_gguf", "review", "--print"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(expand_alias(args, &aliases), vec![
+            "query_gguf", "2", "--var", "Project name=q", "-q", "review this", "--print",
+        ]);
+        let args: Vec<String> = ["query_gguf", "2"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(expand_alias(args.clone(), &aliases), args);
+
+        assert!(validate_config_strict(config).is_ok());
+    }
+
+    #[test]
+    fn test_collect_llama_warnings() {
+        let stderr = "llama_model_loader: loaded meta data\nW: warning: not compiled with GPU offload support\nW: warning: not compiled with GPU offload support\nllm_load_print_meta: n_ctx_train = 4096\n";
+        assert_eq!(collect_llama_warnings(stderr), vec!["W: warning: not compiled with GPU offload support"]);
+        assert!(collect_llama_warnings("all good\n").is_empty());
+    }
+
+    #[test]
+    fn test_exit_code_for_error() {
+        assert_eq!(exit_code_for_error("Launch cancelled: model already running"), EXIT_USER_ABORT);
+        assert_eq!(exit_code_for_error("Generation timed out (--max-seconds 5)"), EXIT_GENERATION_TIMEOUT);
+        assert_eq!(exit_code_for_error("Model file not found: /m.gguf (mode 'coder')"), EXIT_MODEL_MISSING);
+        assert_eq!(exit_code_for_error("Unknown mode: 9"), EXIT_CONFIG_ERROR);
+        assert_eq!(exit_code_for_error("LLaMA CLI path not found in configuration"), EXIT_CONFIG_ERROR);
+        assert_eq!(exit_code_for_error("llama-cli exited with status 1."), EXIT_LAUNCH_FAILURE);
+        assert_eq!(exit_code_for_error("Use: preload <mode> [--idle-minutes N]"), EXIT_CONFIG_ERROR);
+        assert_eq!(exit_code_for_error("Something else"), 1);
+        assert_eq!(exit_code_for_error("Backend 'cuda' is not registered. Add it"), EXIT_CONFIG_ERROR);
+        assert_eq!(exit_code_for_error("Failed to run ssh2 (is OpenSSH installed?): gone"), EXIT_LAUNCH_FAILURE);
+        // Kind words inside user paths do not count
+        assert_eq!(exit_code_for_error("Failed to read prompt /home/me/docker/p.txt: denied"), 1);
+        assert_eq!(exit_code_for_error("Cannot open /tmp/cancelled/llama-cli.log"), 1);
+
+        let mut mode = make_test_mode("coder");
+        mode.model_path = "/nonexistent/model.gguf".to_string();
+        assert_eq!(check_model_file(&mode).map_err(|e| exit_code_for_error(&e)), Err(EXIT_MODEL_MISSING));
+        mode.endpoint = "https://api.example.com".to_string();
+        assert!(check_model_file(&mode).is_ok());
+    }
+
+    #[test]
+    fn test_generation_limits() {
+        let args: Vec<String> = ["--print", "--max-seconds", "30", "--max-bytes", "100"].iter().map(|s| s.to_string()).collect();
+        let options = parse_launch_options(&args).unwrap();
+        assert_eq!((options.max_seconds, options.max_bytes), (Some(30), Some(100)));
+        assert!(parse_launch_options(&["--max-bytes".to_string(), "0".to_string()]).is_err());
+
+        // Never cuts inside a multi-byte character
+        assert_eq!(truncate_to_bytes("héllo", 2), "h");
+        assert_eq!(truncate_to_bytes("hello", 10), "hello");
+
+        let limits = GenerationLimits { max_seconds: None, max_bytes: Some(5) };
+        let output = apply_byte_limit("hello world".to_string(), &limits);
+        assert!(output.starts_with("hello\n[output truncated: --max-bytes 5 reached]"));
+        assert_eq!(apply_byte_limit("hi".to_string(), &limits), "hi");
+    }
+
+    #[test]
+    fn test_parse_launch_options() {
+        let args: Vec<String> = ["--stdin-as-context", "-q", "why?"].iter().map(|s| s.to_string()).collect();
+        let options = parse_launch_options(&args).unwrap();
+        assert!(options.stdin_as_context);
+        assert_eq!(options.question.as_deref(), Some("why?"));
+
+        let args: Vec<String> = ["--var", "Project name=query_gguf"].iter().map(|s| s.to_string()).collect();
+        let options = parse_launch_options(&args).unwrap();
+        assert_eq!(options.variables, vec![("Project name".to_string(), "query_gguf".to_string())]);
+        assert!(parse_launch_options(&["--var".to_string(), "novalue".to_string()]).is_err());
+
+        let options = parse_launch_options(&["--pager".to_string()]).unwrap();
+        assert!(options.pager && options.print);
+
+        assert!(parse_launch_options(&["--dictate".to_string()]).unwrap().dictate);
+        let args: Vec<String> = ["--dictate", "-q", "why?"].iter().map(|s| s.to_string()).collect();
+        assert!(parse_launch_options(&args).is_err());
+
+        let whisper_output = "\n[00:00:00.000 --> 00:00:02.500]   How do I boil an egg?\n[00:00:02.500 --> 00:00:04.000]  Soft, please.\n";
+        assert_eq!(clean_transcript(whisper_output), "How do I boil an egg? Soft, please.");
+        assert_eq!(clean_transcript("[plain] text\n"), "[plain] text");
+
+        assert!(parse_launch_options(&["-q".to_string()]).is_err());
+        assert!(parse_launch_options(&["--bogus".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_response_cache_key() {
+        // Known FNV-1a test vector keeps keys stable across builds
+        assert_eq!(fnv1a_hash(b"a"), 0xaf63dc4c8601ec8c);
+
+        let mut mode = make_test_mode("m");
+        mode.model_path = "/models/a.gguf".to_string();
+        let mut hotter = mode.clone();
+        hotter.parameters.temperature_value = 1.5;
+
+        let mut remote = mode.clone();
+        remote.remote = "gpu-box".to_string();
+        let mut served = mode.clone();
+        served.endpoint = "http://localhost:8080/v1".to_string();
+
+        assert_eq!(make_response_cache_key(&mode, "", "hi"), make_response_cache_key(&mode, "", "hi"));
+        assert_ne!(make_response_cache_key(&mode, "", "hi"), make_response_cache_key(&mode, "", "hello"));
+        assert_ne!(make_response_cache_key(&mode, "", "hi"), make_response_cache_key(&hotter, "", "hi"));
+        assert_ne!(make_response_cache_key(&mode, "", "hi"), make_response_cache_key(&mode, "docker", "hi"));
+        assert_ne!(make_response_cache_key(&mode, "", "hi"), make_response_cache_key(&remote, "", "hi"));
+        assert_ne!(make_response_cache_key(&mode, "", "hi"), make_response_cache_key(&served, "", "hi"));
+    }
+}
+
+/// Reads and parses the config file
+///
+/// Uses the standard config file location:
+/// - Linux/MacOS: ~/query_gguf/query_gguf_config.toml
+/// - Windows: \Users\username\query_gguf\query_gguf_config.toml
+///
+/// Lines that do not parse are skipped (`query_gguf config lint` lists them), so
+/// this never fails: a missing or unreadable config gives an empty table.
+fn read_config() -> toml_lite::Table {
+    match get_config_path().and_then(|path| fs::read_to_string(path).map_err(|e| e.to_string())) {
+        Ok(content) => toml_lite::parse(&content).0,
+        Err(_) => toml_lite::Table::default(),
+    }
+}
+
+/// Reads a top level field of the config as text
+///
+/// Strings give their content; numbers and booleans give their TOML text
+/// (e.g. `default_mode = 2` gives "2").
+///
+/// # Arguments
+/// * `field_name` - The name of the field, e.g. "llama_cli_path"
+///
+/// # Returns
+/// * `String` - The value of the field if found, empty string otherwise
+///
+/// # Examples
+/// ```
+/// let llama_path = read_field_from_toml("llama_cli_path");
+/// if llama_path.is_empty() {
+///     println!("llama_cli_path not found in config");
+/// }
+/// ```
+fn read_field_from_toml(field_name: &str) -> String {
+    read_config().get_text(field_name).unwrap_or_default()
+}
+
+/// Reads a true/false setting of the config (None if unset or not a boolean)
+fn read_config_bool(field_name: &str) -> Option<bool> {
+    read_config().get_bool(field_name)
+}
+
+/// Reads an integer setting of the config (None if unset or not an integer)
+fn read_config_int(field_name: &str) -> Option<i64> {
+    read_config().get_int(field_name)
+}
+
+/// Values of the numbered top level fields sharing a base name, in number order
+///
+/// e.g. "prompt_directory" matches prompt_directory_1, prompt_directory_2, ...
+/// Empty values are left out.
+fn numbered_config_values(config: &toml_lite::Table, base_name: &str) -> Vec<String> {
+    let prefix = format!("{}_", base_name);
+    let mut numbered_values: Vec<(usize, String)> = config.entries()
+        .iter()
+        .filter_map(|entry| {
+            let num = entry.key.strip_prefix(&prefix)?.parse::<usize>().ok()?;
+            Some((num, entry.value.to_text()))
+        })
+        .filter(|(_, value)| !value.trim().is_empty())
+        .collect();
+    numbered_values.sort_by_key(|(num, _)| *num);
+    numbered_values.into_iter().map(|(_, value)| value).collect()
+}
+
+/// Reads all fields from the config that share a common base name (prefix before underscore)
+/// and returns a vector of their values.
+///
+/// # Arguments
+/// * `base_name` - Base name to search for (e.g., "prompt" will match "prompt_1", "prompt_2", etc.)
+///
+/// # Returns
+/// * `Vec<String>` - Vector containing all values for fields matching the base name
+///
+fn read_basename_fields_from_toml(base_name: &str) -> Vec<String> {
+    numbered_config_values(&read_config(), base_name)
+}
+
+/// Defines all adjustable parameters for the llama.cpp command execution
+/// Each field corresponds to a specific llama.cpp command line argument
+#[derive(Debug, Clone)]
+struct LlamaCppParameters {
+    temperature_value: f32,      // --temp parameter
+    top_k_sampling: i32,         // --top-k parameter
+    top_p_sampling: f32,         // --top-p parameter
+    context_size: i32,           // --ctx-size parameter
+    thread_count: i32,           // --threads parameter
+    gpu_layers: i32,             // --n-gpu-layers parameter
+    interactive_first: bool,     // --interactive-first flag
+    display_prompt: bool,        // show the prompt in interactive sessions (otherwise --no-display-prompt)
+    verbose_prompt: bool,        // --verbose-prompt flag
+    log_disable: bool,           // --log-disable flag
+    multiline_input: bool,       // --multiline-input flag (interactive sessions)
+    in_prefix: String,           // --in-prefix parameter, empty for none (interactive sessions)
+    in_suffix: String,           // --in-suffix parameter, empty for none (interactive sessions)
+    conversation: Option<bool>,  // -cnv / -no-cnv, None for llama-cli's default (interactive sessions)
+    max_prompt_bytes: Option<u64>, // largest prompt built from directories/attachments (not passed to llama.cpp)
+    max_files: Option<usize>,    // most files included from a directory (not passed to llama.cpp)
+    image_steps: Option<u32>,    // --steps for image modes (stable-diffusion.cpp)
+    cfg_scale: Option<f32>,      // --cfg-scale for image modes (stable-diffusion.cpp)
+    output_dir: String,          // where image modes save pictures, empty for ~/query_gguf/images
+    only_if: String,             // launch condition, e.g. "on_ac_power", empty for none (not passed to llama.cpp)
+    hours: String,               // local times the mode may launch, e.g. "09:00-18:00", empty for any
+    min_free_ram_gb: Option<f64>, // free memory needed to launch (not passed to llama.cpp)
+    tests: String,               // canary prompt files for `mode test`, e.g. "tests/coder.toml" (not passed to llama.cpp)
+    disabled: String,            // why the mode may not launch, e.g. "model archived to /mnt/archive", empty if enabled
+}
     
-//     match prompt_yes_no("Would you like to use a different directory for logs?") {
-//         Ok(true) => {
-//             prompt_for_directory("Enter custom path for log files")
-//         },
-//         Ok(false) => {
-//             // Create default log directory
-//             match fs::create_dir_all(default_log_dir) {
-//                 Ok(()) => {
-//                     println!("Using default log directory: {}/", default_log_dir);
-//                     Ok(default_log_dir.to_string())
-//                 },
-//                 Err(e) => Err(format!("Failed to create log directory: {}", e))
-//             }
-//         },
-//         Err(e) => Err(format!("Error during prompt: {}", e))
-//     }
+    // temperature_value: f32,      // --temp parameter
+    // top_k_sampling: i32,         // --top-k parameter
+    // top_p_sampling: f32,         // --top-p parameter
+    // min_p_sampling: f32,         // --min-p parameter
+    // random_seed: i32,            // --seed parameter
+    // tail_free_sampling: f32,     // --tfs parameter
+    // thread_count: i32,           // --threads parameter
+    // typical_sampling: f32,       // --typical parameter
+    // mirostat_version: i32,       // --mirostat parameter
+    // mirostat_learning_rate: f32, // --mirostat-lr parameter
+    // mirostat_entropy: f32,       // --mirostat-ent parameter
+    // context_window_size: i32,    // --ctx-size parameter
 // }
 
-// /// Launches and manages a LLaMA.cpp process in the current terminal
-// /// 
-// /// This function:
-// /// 1. Validates the llama-cli path from configuration
-// /// 2. Constructs the command with all parameters and arguments
-// /// 3. Launches llama-cli process in current terminal
-// /// 4. Monitors process execution and handles termination
-// /// 
-// /// # Arguments
-// /// * `mode` - ChatModeConfig containing model, prompt, and parameter settings
-// /// 
-// /// # Returns
-// /// - Ok(()): Process completed successfully
-// /// - Err(String): Detailed error message if any step fails
-// /// 
-// /// # Process Handling
-// /// - Runs in current terminal (no new window)
-// /// - Waits for process completion
-// /// - Handles SIGINT (Ctrl+C) gracefully
-// /// 
-// /// # Error Cases
-// /// - LLaMA CLI path not found in config
-// /// - Invalid paths or parameters
-// /// - Process spawn failure
-// /// - Runtime errors from llama-cli
-// /// 
-// /// # Example Command Format
-// /// ```bash
-// /// /path/to/llama-cli -m "/path/to/model.gguf" --file "/path/to/prompt.txt" \
-// ///     --temp 0.8 --top-k 40 --top-p 0.9 --ctx-size 2000 --threads 4
-// /// ```
-// fn launch_llama(mode: &ChatModeConfig) -> Result<(), String> {
-//     // Validate llama-cli path
-//     let llama_cli_path = read_field_from_toml("llama_cli_path");
-//     if llama_cli_path.is_empty() {
-//         return Err("LLaMA CLI path not found in configuration".to_string());
-//     }
+impl Default for LlamaCppParameters {
+    fn default() -> Self {
+        Self {
+            temperature_value: 0.8,
+            top_k_sampling: 40,
+            top_p_sampling: 0.9,
+            context_size: 2000,
+            thread_count: default_thread_count(),
+            gpu_layers: default_gpu_layers(),  // CPU-only, except Metal offload on Apple Silicon
+            interactive_first: true,
+            display_prompt: false,
+            verbose_prompt: false,
+            log_disable: false,
+            multiline_input: false,
+            in_prefix: String::new(),
+            in_suffix: String::new(),
+            conversation: None,
+            max_prompt_bytes: None,
+            max_files: None,
+            image_steps: None,
+            cfg_scale: None,
+            output_dir: String::new(),
+            only_if: String::new(),
+            hours: String::new(),
+            min_free_ram_gb: None,
+            tests: String::new(),
+            disabled: String::new(),
+        }
+        // Self {
+        //     temperature_value: 0.8,
+        //     top_k_sampling: 40,
+        //     top_p_sampling: 0.9,
+        //     min_p_sampling: 0.05,
+        //     random_seed: -1,
+        //     tail_free_sampling: 1.0,
+        //     thread_count: get_system_cpu_count() - 1,
+        //     typical_sampling: 1.0,
+        //     mirostat_version: 2,
+        //     mirostat_learning_rate: 0.05,
+        //     mirostat_entropy: 3.0,
+        //     context_window_size: 500,
+        // }
+    }
+}
+
+/// Retrieves the number of CPU cores available on the current system minus 1
+/// Returns the number of available CPU cores minus 1 or a safe default if detection fails
+fn get_system_cpu_count() -> i32 {
+    match detect_cpu_count() {
+        Some(count) => {
+            let cpu_count = count as i32;
+            // Ensure we don't return less than 1 thread
+            if cpu_count > 1 {
+                cpu_count - 1
+            } else {
+                1
+            }
+        },
+        None => {
+            println!("Warning: Could not detect CPU count, using default value of 3");
+            3 // conservative default (assuming at least 4 cores)
+        }
+    }
+}
+
+/// Returns true on FreeBSD, OpenBSD, NetBSD, or DragonFly BSD
+fn is_bsd() -> bool {
+    cfg!(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))
+}
+
+/// Reads a numeric kernel value with `sysctl -n` (macOS and the BSDs)
+fn read_sysctl_number(name: &str) -> Option<u64> {
+    let output = Command::new("sysctl").args(["-n", name]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).trim().parse::<u64>().ok()
+}
+
+/// Counts the CPUs, asking sysctl on the BSDs if the standard library cannot tell
+fn detect_cpu_count() -> Option<usize> {
+    std::thread::available_parallelism()
+        .map(|count| count.get())
+        .ok()
+        .or_else(|| {
+            // OpenBSD reports the CPUs that are online (SMT may be disabled) separately
+            ["hw.ncpuonline", "hw.ncpu"].iter()
+                .find_map(|name| read_sysctl_number(name))
+                .map(|count| count as usize)
+                .filter(|count| *count > 0)
+        })
+}
+
+/// Hardware families whose llama.cpp defaults differ from an x86 desktop
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlatformKind {
+    AppleSilicon,  // macOS on arm64: unified memory, Metal GPU
+    ArmBoard,      // other ARM (Raspberry Pi, Rockchip, ...): CPU only, often big.LITTLE
+    Other,
+}
+
+/// Classifies a platform from its OS and CPU architecture names
+fn classify_platform(os: &str, arch: &str) -> PlatformKind {
+    match (os, arch) {
+        ("macos", "aarch64") => PlatformKind::AppleSilicon,
+        (_, "aarch64") | (_, "arm") => PlatformKind::ArmBoard,
+        _ => PlatformKind::Other,
+    }
+}
+
+/// The platform this program runs on
+fn detect_platform() -> PlatformKind {
+    classify_platform(std::env::consts::OS, std::env::consts::ARCH)
+}
+
+/// Counts the fastest cores of a big.LITTLE CPU from each core's max frequency
+///
+/// # Returns
+/// Number of cores at the highest max frequency, 0 if the list is empty
+fn count_performance_cores(max_frequencies: &[u64]) -> usize {
+    let fastest = max_frequencies.iter().copied().max().unwrap_or(0);
+    max_frequencies.iter().filter(|frequency| **frequency == fastest && fastest > 0).count()
+}
+
+/// Counts performance cores where the CPU mixes fast and slow cores
+///
+/// Apple Silicon reports P-cores through sysctl; ARM boards through each
+/// core's cpuinfo_max_freq. Generation runs only as fast as the slowest
+/// thread, so using the efficiency cores as well slows it down.
+///
+/// # Returns
+/// - Some(count): Performance cores, when there are also slower cores
+/// - None: Not such a CPU, or unknown
+fn detect_performance_cores() -> Option<usize> {
+    match detect_platform() {
+        PlatformKind::AppleSilicon => read_sysctl_number("hw.perflevel0.physicalcpu")
+            .map(|count| count as usize)
+            .filter(|count| *count > 0),
+        PlatformKind::ArmBoard => {
+            let frequencies: Vec<u64> = fs::read_dir("/sys/devices/system/cpu").ok()?
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    name.strip_prefix("cpu").is_some_and(|number| number.parse::<u32>().is_ok())
+                })
+                .filter_map(|entry| fs::read_to_string(entry.path().join("cpufreq/cpuinfo_max_freq")).ok())
+                .filter_map(|frequency| frequency.trim().parse::<u64>().ok())
+                .collect();
+            let performance = count_performance_cores(&frequencies);
+            (performance > 0 && performance < frequencies.len()).then_some(performance)
+        },
+        PlatformKind::Other => None,
+    }
+}
+
+/// Default thread count: the performance cores on big.LITTLE/Apple CPUs, else all cores but one
+fn default_thread_count() -> i32 {
+    detect_performance_cores()
+        .map(|count| count as i32)
+        .unwrap_or_else(get_system_cpu_count)
+}
+
+/// Default GPU layers: all layers on Apple Silicon (Metal, unified memory), else CPU-only
+fn default_gpu_layers() -> i32 {
+    if detect_platform() == PlatformKind::AppleSilicon {
+        99  // more than any model has, so llama.cpp offloads every layer
+    } else {
+        0
+    }
+}
+
+/// Warns about quantizations that run much slower than alternatives on a platform
+///
+/// # Arguments
+/// * `platform` - Platform the model will run on
+/// * `quantization` - Quantization tag, e.g. IQ3_XS (see `split_model_quantization`)
+fn slow_quantization_warning(platform: PlatformKind, quantization: &str) -> Option<String> {
+    let tag = quantization.to_uppercase();
+    match platform {
+        PlatformKind::ArmBoard if tag.starts_with("IQ") => Some(format!(
+            "{} (i-quant) is slow on ARM CPUs; Q4_0 uses llama.cpp's ARM-optimized kernels and is much faster", tag
+        )),
+        PlatformKind::ArmBoard if tag == "F16" || tag == "BF16" || tag == "F32" => Some(format!(
+            "{} is slow and memory-heavy on ARM boards; use Q4_0 or Q8_0", tag
+        )),
+        PlatformKind::AppleSilicon if tag.starts_with("IQ1") || tag.starts_with("IQ2") => Some(format!(
+            "{} is slower on Metal than K-quants of similar size (e.g. Q2_K/Q3_K_S)", tag
+        )),
+        PlatformKind::AppleSilicon if tag == "BF16" => Some(
+            "BF16 is slow on Metal on M1/M2; F16 or Q8_0 run faster".to_string()
+        ),
+        _ => None,
+    }
+}
+
+/// Returns a warning if the mode's model quantization is slow on this platform
+fn check_model_quantization_speed(mode: &ChatModeConfig) -> Option<String> {
+    let file_name = Path::new(&mode.model_path).file_name()?.to_string_lossy().to_string();
+    let (_, quantization) = split_model_quantization(&file_name);
+    slow_quantization_warning(detect_platform(), &quantization)
+}
+
+/// Seconds after which a state file lock whose owner cannot be checked is taken over
+const STATE_LOCK_STALE_SECS: u64 = 60;
+
+/// Milliseconds to wait for another query_gguf to release a state file lock
+const STATE_LOCK_TIMEOUT_MS: u64 = 5000;
+
+/// An exclusive lock on a state file, released when dropped
+///
+/// The lock is a `<file>.lock` file next to the state file, created with
+/// create_new so only one process can hold it. It names the holding
+/// process, so a lock left behind by a crashed run is taken over.
+struct StateFileLock {
+    lock_path: PathBuf,
+}
+
+impl StateFileLock {
+    /// Waits for and takes the lock of a state file
+    fn acquire(file_path: &Path) -> Result<StateFileLock, String> {
+        let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
+        let lock_path = file_path.with_file_name(format!("{}.lock", file_name));
+        let started = std::time::Instant::now();
+        loop {
+            match fs::OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+                Ok(mut file) => {
+                    let _ = write!(file, "{}", std::process::id());
+                    return Ok(StateFileLock { lock_path });
+                },
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if Self::is_stale(&lock_path) {
+                        let _ = fs::remove_file(&lock_path);
+                        continue;
+                    }
+                    if started.elapsed() >= std::time::Duration::from_millis(STATE_LOCK_TIMEOUT_MS) {
+                        return Err(format!(
+                            "{} is locked by another query_gguf (remove {} if none is running)",
+                            file_path.display(), lock_path.display()
+                        ));
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                },
+                Err(e) => return Err(format!("Failed to lock {}: {}", file_path.display(), e)),
+            }
+        }
+    }
+
+    /// A lock is stale when its process has exited, or (if that cannot be told) when it is old
+    fn is_stale(lock_path: &Path) -> bool {
+        let age_secs = fs::metadata(lock_path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .map(|age| age.as_secs());
+        match fs::read_to_string(lock_path).ok().and_then(|pid| pid.trim().parse::<u32>().ok()) {
+            // Checking the pid runs a program, so give a fresh lock a moment first
+            Some(pid) => age_secs.unwrap_or(0) >= 1 && !is_process_running(pid),
+            None => age_secs.map(|age| age >= STATE_LOCK_STALE_SECS).unwrap_or(false),
+        }
+    }
+}
+
+impl Drop for StateFileLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.lock_path);
+    }
+}
+
+/// Writes a file through a temporary file renamed over it, so readers never see half of it
+fn write_file_atomically(path: &Path, content: &str) -> Result<(), String> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+    fs::write(&temp_path, content)
+        .and_then(|_| fs::rename(&temp_path, path))
+        .map_err(|e| {
+            let _ = fs::remove_file(&temp_path);
+            format!("Failed to write {}: {}", path.display(), e)
+        })
+}
+
+/// Changes a state file under its lock
+///
+/// `update` gets the current content (empty if there is no file yet) and
+/// returns the new content, or None to leave the file as it is. Since the
+/// content is read under the lock, concurrent runs each apply their change
+/// to what the others wrote, instead of the last writer undoing the rest.
+fn update_state_file(path: &Path, update: impl FnOnce(&str) -> Option<String>) -> Result<(), String> {
+    let _lock = StateFileLock::acquire(path)?;
+    let content = fs::read_to_string(path).unwrap_or_default();
+    match update(&content) {
+        Some(new_content) => write_file_atomically(path, &new_content),
+        None => Ok(()),
+    }
+}
+
+/// Gets the absolute path to the state file
+///
+/// Location: ~/query_gguf/query_gguf_state.toml
+///
+/// The state file holds values query_gguf manages itself (not user settings),
+/// so it is kept separate from the config file.
+fn get_state_path() -> Result<PathBuf, String> {
+    Ok(get_app_base_dir()?.join("query_gguf_state.toml"))
+}
+
+/// Reads a field from the state file, empty string if missing
+fn read_state_field(field_name: &str) -> String {
+    let content = match get_state_path().and_then(|path| {
+        fs::read_to_string(path).map_err(|e| e.to_string())
+    }) {
+        Ok(content) => content,
+        Err(_) => return String::new(),
+    };
+
+    toml_lite::parse(&content).0.get_text(field_name).unwrap_or_default()
+}
+
+/// Sets a field in the state file, creating the file if needed
+fn write_state_field(field_name: &str, value: &str) -> Result<(), String> {
+    update_state_file(&get_state_path()?, |content| Some(set_state_field(content, field_name, value)))
+}
+
+/// State file content with one field set to a new value
+fn set_state_field(content: &str, field_name: &str, value: &str) -> String {
+    let mut lines: Vec<String> = content.lines()
+        .filter(|line| line.split_once('=').map(|(key, _)| key.trim() != field_name).unwrap_or(true))
+        .map(|line| line.to_string())
+        .collect();
+    lines.push(format!("{} = \"{}\"", field_name, value.replace('"', "'")));
+    format!("{}\n", lines.join("\n"))
+}
+
+/// State field holding favorite mode ids
+const FAVORITE_MODES_FIELD: &str = "favorite_modes";
+
+/// State field holding favorite prompt file paths
+const FAVORITE_PROMPTS_FIELD: &str = "favorite_prompts";
+
+/// Reads a '|' separated list (favorites, recent directories) from the state file
+fn read_state_list(field_name: &str) -> Vec<String> {
+    split_state_list(&read_state_field(field_name))
+}
+
+fn split_state_list(value: &str) -> Vec<String> {
+    value.split('|')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Changes a list in the state file, under the state file lock
+///
+/// `update` gets the list as it is now, including what other runs added
+/// since this one read it, and returns the new list.
+fn update_state_list(field_name: &str, update: impl FnOnce(Vec<String>) -> Vec<String>) -> Result<(), String> {
+    update_state_list_at(&get_state_path()?, field_name, update)
+}
+
+/// Changes a list in the given state file, under its lock
+fn update_state_list_at(path: &Path, field_name: &str, update: impl FnOnce(Vec<String>) -> Vec<String>) -> Result<(), String> {
+    update_state_file(path, |content| {
+        let current = toml_lite::parse(content).0.get_text(field_name).unwrap_or_default();
+        let updated = update(split_state_list(&current));
+        Some(set_state_field(content, field_name, &updated.join("|")))
+    })
+}
+
+/// Adds or removes an item from a favorites list in the state file
+///
+/// # Returns
+/// - Ok(true): The item is now a favorite
+/// - Ok(false): The item was a favorite and has been removed
+/// - Err(String): The state file could not be written
+fn toggle_favorite(field_name: &str, item: &str) -> Result<bool, String> {
+    let mut is_favorite = false;
+    update_state_list(field_name, |mut favorites| {
+        if let Some(position) = favorites.iter().position(|favorite| favorite == item) {
+            favorites.remove(position);
+        } else {
+            favorites.push(item.to_string());
+            is_favorite = true;
+        }
+        favorites
+    })?;
+    Ok(is_favorite)
+}
+
+/// State field holding the directories most recently used in directory mode
+const RECENT_DIRECTORIES_FIELD: &str = "recent_directories";
+
+/// How many recent directories are remembered
+const MAX_RECENT_DIRECTORIES: usize = 5;
+
+/// Puts `item` first in a most-recent-first list, without repeats, keeping at most `max` items
+fn push_recent_item(list: &[String], item: &str, max: usize) -> Vec<String> {
+    let mut updated = vec![item.to_string()];
+    updated.extend(list.iter().filter(|existing| *existing != item).cloned());
+    updated.truncate(max);
+    updated
+}
+
+/// Asks for the directory to scan in directory mode, offering recent ones as a quick pick
+///
+/// # Returns
+/// - Ok(String): The chosen recent directory, or the newly typed path
+/// - Err(String): Input could not be read
+fn prompt_for_scan_directory() -> Result<String, String> {
+    let recent: Vec<String> = read_state_list(RECENT_DIRECTORIES_FIELD)
+        .into_iter()
+        .filter(|dir| Path::new(dir).is_dir())
+        .collect();
+
+    if recent.is_empty() {
+        return Ok(read_path_input("Enter directory path to scan: ")?.trim().to_string());
+    }
+
+    println!("Recent directories:");
+    for (index, dir) in recent.iter().enumerate() {
+        println!("  {}. {}", index + 1, dir);
+    }
+    let input = read_path_input("Enter a number, or a directory path to scan: ")?.trim().to_string();
+    match input.parse::<usize>() {
+        Ok(number) if number >= 1 && number <= recent.len() => Ok(recent[number - 1].clone()),
+        _ => Ok(input),
+    }
+}
+
+/// Records a directory as the most recently used one in directory mode
+fn remember_recent_directory(dir_path: &str) -> Result<(), String> {
+    let absolute = Path::new(dir_path).canonicalize()
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_else(|_| dir_path.to_string());
+    update_state_list(RECENT_DIRECTORIES_FIELD, |recent| push_recent_item(&recent, &absolute, MAX_RECENT_DIRECTORIES))
+}
+
+/// Returns the indices of `items` with favorites first (in favorite order), then the rest in order
+fn order_favorites_first(items: &[String], favorites: &[String]) -> Vec<usize> {
+    let mut order: Vec<usize> = favorites.iter()
+        .filter_map(|favorite| items.iter().position(|item| item == favorite))
+        .collect();
+    let rest: Vec<usize> = (0..items.len()).filter(|index| !order.contains(index)).collect();
+    order.extend(rest);
+    order
+}
+
+/// Describes the hardware that thread and GPU layer settings depend on
+#[derive(Debug, Clone, PartialEq)]
+struct HardwareFingerprint {
+    cpu_count: usize,
+    ram_mb: u64,
+    gpus: Vec<String>,
+}
+
+impl HardwareFingerprint {
+    /// Single line form stored in the state file, e.g. "cpus=12;ram_mb=31822;gpus=NVIDIA RTX 3060"
+    fn to_state_string(&self) -> String {
+        format!("cpus={};ram_mb={};gpus={}", self.cpu_count, self.ram_mb, self.gpus.join(","))
+    }
+}
+
+/// Reads total system RAM in megabytes, 0 if it cannot be determined
+fn detect_total_ram_mb() -> u64 {
+    // Linux
+    if let Ok(meminfo) = fs::read_to_string("/proc/meminfo") {
+        if let Some(kb) = meminfo.lines()
+            .find_map(|line| line.strip_prefix("MemTotal:"))
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|kb| kb.parse::<u64>().ok())
+        {
+            return kb / 1024;
+        }
+    }
+
+    // macOS (hw.memsize), OpenBSD/NetBSD (hw.physmem64), FreeBSD/DragonFly (hw.physmem)
+    ["hw.memsize", "hw.physmem64", "hw.physmem"].iter()
+        .find_map(|name| read_sysctl_number(name))
+        .map(|bytes| bytes / (1024 * 1024))
+        .unwrap_or(0)
+}
+
+/// Lists GPUs visible to llama.cpp, using `nvidia-smi -L` when available (or Metal on Apple Silicon)
+///
+/// Returns an empty list if no GPU tool is available.
+fn detect_gpus() -> Vec<String> {
+    // Apple Silicon has no nvidia-smi; its GPU is always there, used through Metal
+    if detect_platform() == PlatformKind::AppleSilicon {
+        return vec!["Apple Silicon GPU (Metal)".to_string()];
+    }
+    Command::new("nvidia-smi")
+        .arg("-L")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                // "GPU 0: NVIDIA GeForce RTX 3060 (UUID: ...)" -> "NVIDIA GeForce RTX 3060"
+                .filter_map(|line| line.split_once(": ").map(|(_, rest)| rest))
+                .map(|rest| rest.split(" (UUID").next().unwrap_or(rest).trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Detects the current hardware fingerprint
+fn detect_hardware_fingerprint() -> HardwareFingerprint {
+    HardwareFingerprint {
+        cpu_count: detect_cpu_count().unwrap_or(0),
+        ram_mb: detect_total_ram_mb(),
+        gpus: detect_gpus(),
+    }
+}
+
+/// Sets threads (and optionally gpu_layers) in every mode entry of the config content
+///
+/// # Arguments
+/// * `content` - Full text of the config file
+/// * `threads` - New thread count for all modes
+/// * `gpu_layers` - New GPU layer count, or None to leave GPU layers unchanged
+///
+/// # Returns
+/// * `(String, Vec<String>)` - Updated content, and one description per changed mode
+fn retune_mode_entries(content: &str, threads: i32, gpu_layers: Option<i32>) -> (String, Vec<String>) {
+    let mut changes = Vec::new();
+    let mut lines = Vec::new();
+    let describe = |mode: &str| format!("{}: threads={}{}", mode, threads,
+        gpu_layers.map(|l| format!(", gpu_layers={}", l)).unwrap_or_default());
+    // The [mode.<id>] table the lines are in, if any
+    let mut mode_table: Option<String> = None;
+
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            mode_table = trimmed.trim_matches(|c| c == '[' || c == ']').trim()
+                .strip_prefix("mode.")
+                .map(|name| format!("mode.{}", name));
+            lines.push(line.to_string());
+            continue;
+        }
+        let key = trimmed.split('=').next().unwrap_or("").trim();
+        if let Some(table) = mode_table.as_ref().filter(|_| !trimmed.starts_with('#')) {
+            let new_value = match (key, gpu_layers) {
+                ("threads", _) => Some(threads),
+                ("gpu_layers", Some(layers)) => Some(layers),
+                _ => None,
+            };
+            let old_value = trimmed.split_once('=').map(|(_, value)| value.trim()).unwrap_or("");
+            match new_value.filter(|value| value.to_string() != old_value) {
+                Some(value) => {
+                    let change = describe(table);
+                    if !changes.contains(&change) {
+                        changes.push(change);
+                    }
+                    lines.push(format!("{} = {}", key, value));
+                },
+                None => lines.push(line.to_string()),
+            }
+            continue;
+        }
+        let is_mode_entry = key.strip_prefix("mode_").map(|n| n.parse::<usize>().is_ok()).unwrap_or(false);
+        if !is_mode_entry || trimmed.starts_with('#') {
+            lines.push(line.to_string());
+            continue;
+        }
+
+        let value = trimmed.split_once('=').map(|(_, v)| v.trim().trim_matches('"')).unwrap_or("");
+        let new_parts: Vec<String> = value.split('|')
+            .map(|part| match part.split_once('=') {
+                Some(("threads", _)) => format!("threads={}", threads),
+                Some(("gpu_layers", old)) => match gpu_layers {
+                    Some(layers) => format!("gpu_layers={}", layers),
+                    None => format!("gpu_layers={}", old),
+                },
+                _ => part.to_string(),
+            })
+            .collect();
+        let new_value = new_parts.join("|");
+
+        if new_value != value {
+            changes.push(describe(key));
+            lines.push(format!("{} = \"{}\"", key, new_value));
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+
+    let mut output = lines.join("\n");
+    if content.ends_with('\n') {
+        output.push('\n');
+    }
+    (output, changes)
+}
+
+/// Re-tunes thread and GPU layer values of all saved modes for the current hardware
+///
+/// - threads: CPU count - 1 (see get_system_cpu_count)
+/// - gpu_layers: 0 if no GPU is detected, otherwise the user is asked
+///   (Enter keeps each mode's current value)
+///
+/// The config is backed up before it is changed.
+fn run_hardware_retune(fingerprint: &HardwareFingerprint) -> Result<(), String> {
+    let threads = default_thread_count();
+    let gpu_layers = if fingerprint.gpus.is_empty() {
+        println!("No GPU detected: gpu_layers will be set to 0 (CPU-only).");
+        Some(0)
+    } else {
+        println!("GPU(s) detected: {}", fingerprint.gpus.join(", "));
+        print!("GPU layers for all modes (Enter to keep each mode's value): ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let input = read_user_input()?;
+        if input.trim().is_empty() {
+            None
+        } else {
+            Some(input.trim().parse::<i32>().map_err(|_| "Invalid GPU layers value".to_string())?)
+        }
+    };
+
+    let config_path = get_config_path()?;
+    let content = fs::read_to_string(&config_path)
+        .map_err(|e| format!("Failed to read config at {}: {}", config_path.display(), e))?;
+    let (new_content, changes) = retune_mode_entries(&content, threads, gpu_layers);
+
+    if changes.is_empty() {
+        println!("All modes already match this machine.");
+        return Ok(());
+    }
+
+    backup_existing_config()?;
+    write_config_file(&config_path, &new_content, "retune")?;
+    for change in &changes {
+        println!("  updated {}", change);
+    }
+    Ok(())
+}
+
+/// Compares the current hardware to the fingerprint in the state file
+///
+/// On the first run the fingerprint is only stored. When it differs (new
+/// machine, docked eGPU, config copied from elsewhere), the user is asked
+/// whether to re-tune the saved modes' threads and gpu_layers.
+fn check_hardware_change() -> Result<(), String> {
+    let current = detect_hardware_fingerprint();
+    let current_string = current.to_state_string();
+    let stored = read_state_field("hardware_fingerprint");
+
+    if stored == current_string {
+        return Ok(());
+    }
+
+    if !stored.is_empty() {
+        println!("\nHardware change detected:");
+        println!("  before: {}", stored);
+        println!("  now:    {}", current_string);
+        if prompt_yes_no("Re-tune saved modes' threads and GPU layers for this machine?")? {
+            run_hardware_retune(&current)?;
+        }
+    }
+
+    write_state_field("hardware_fingerprint", &current_string)
+}
+
+/// Generates a unique timestamp string for log file names and entries
+/// Returns a string representation of the current Unix timestamp
+fn generate_timestamp_string() -> String {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs().to_string(),
+        Err(_) => {
+            println!("Warning: System time error, using 'unknown_time' as timestamp");
+            "unknown_time".to_string()
+        }
+    }
+}
+
+/// Creates a blank prompt file in the prompts directory
+/// 
+/// Creates the file 'blankprompt.txt' in the standard prompts directory:
+/// - Linux/MacOS: ~/query_gguf/prompts/blankprompt.txt
+/// - Windows: \Users\username\query_gguf\prompts\blankprompt.txt
+/// 
+/// This blank prompt serves as a default when no specific prompt is selected.
+/// The function ensures both the prompts directory and the blank prompt file exist.
+/// 
+/// # Returns
+/// - Ok(String): Absolute path to the created blank prompt file
+/// - Err(String): Error message if creation fails
+/// 
+/// # Error Cases
+/// - Cannot create prompts directory (permissions/disk space)
+/// - Cannot create blank prompt file
+/// - Path resolution fails
+/// 
+fn create_blank_prompt() -> Result<String, String> {
+    // CHANGE 1: Get absolute path to prompts directory
+    let prompts_dir = get_prompts_dir()?;
+    let blank_prompt_path = prompts_dir.join("blankprompt.txt");
+
+    println!("DEBUG: Creating prompt directory: {}", prompts_dir.display());
+    
+    // CHANGE 2: Create prompts directory with all parent directories
+    fs::create_dir_all(&prompts_dir)
+        .map_err(|e| format!("Failed to create prompts directory: {}", e))?;
+
+    println!("DEBUG: Creating blank prompt file: {}", blank_prompt_path.display());
+    
+    // CHANGE 3: Create the blank prompt file with minimal content
+    fs::write(&blank_prompt_path, "# Blank prompt file\n")
+        .map_err(|e| format!("Failed to create blank prompt file: {}", e))?;
+
+    // CHANGE 4: Verify the file was created
+    if !blank_prompt_path.exists() {
+        return Err("Failed to verify blank prompt file creation".to_string());
+    }
+
+    println!("Successfully created blank prompt file at: {}", blank_prompt_path.display());
+    Ok(blank_prompt_path.to_string_lossy().to_string())
+}
+
+/// Handles prompt directory setup, creating a default if needed
+fn setup_prompt_directory() -> Result<String, String> {
+    println!("\nPrompt Directory Setup:");
+    println!("Prompts are text files that will be used to start conversations with LLaMA.");
+    
+    let prompts_dir = match prompt_yes_no("Do you already have a directory containing prompt files?") {
+        Ok(true) => {
+            prompt_for_directory("Enter the path to your existing prompts directory")?
+        },
+        Ok(false) => {
+            // Create default prompts directory in current working directory
+            let default_prompts_dir = "prompts";
+            println!("DEBUG: Creating default prompts directory: {}", default_prompts_dir);
+            fs::create_dir_all(default_prompts_dir)
+                .map_err(|e| format!("Failed to create prompts directory: {}", e))?;
+            
+            println!("\nCreated new prompts directory: {}/", default_prompts_dir);
+            println!("You can add your prompt text files here.");
+            default_prompts_dir.to_string()
+        },
+        Err(e) => return Err(format!("Error during prompt: {}", e))
+    };
+
+    // Always create blankprompt.txt
+    println!("\nCreating blank prompt file...");
+    let blank_prompt_path = create_blank_prompt()?;
+    
+    // Verify the file exists
+    if !Path::new(&blank_prompt_path).exists() {
+        return Err(format!("Failed to verify blank prompt file exists at: {}", blank_prompt_path));
+    }
+
+    // Print current directory and file listing for debugging
+    println!("DEBUG: Current directory: {:?}", std::env::current_dir().unwrap_or_default());
+    println!("DEBUG: Contents of prompts directory:");
+    if let Ok(entries) = fs::read_dir("prompts") {
+        for entry in entries.flatten() {
+            println!("  {:?}", entry.path());
+        }
+    }
+
+    Ok(prompts_dir)
+}
+
+// /// not used now, maybe in future
+// /// Sets up the logging directory, using default or custom path
+// fn setup_log_directory() -> Result<String, String> {
+//     let default_log_dir = "query_gguf/chatlogs";
+    
+//     println!("\nLog Directory Setup:");
+//     println!("Chat logs will be saved in: {}/", default_log_dir);
+    
+//     match prompt_yes_no("Would you like to use a different directory for logs?") {
+//         Ok(true) => {
+//             prompt_for_directory("Enter custom path for log files")
+//         },
+//         Ok(false) => {
+//             // Create default log directory
+//             match fs::create_dir_all(default_log_dir) {
+//                 Ok(()) => {
+//                     println!("Using default log directory: {}/", default_log_dir);
+//                     Ok(default_log_dir.to_string())
+//                 },
+//                 Err(e) => Err(format!("Failed to create log directory: {}", e))
+//             }
+//         },
+//         Err(e) => Err(format!("Error during prompt: {}", e))
+//     }
+// }
+
+// /// Launches and manages a LLaMA.cpp process in the current terminal
+// /// 
+// /// This function:
+// /// 1. Validates the llama-cli path from configuration
+// /// 2. Constructs the command with all parameters and arguments
+// /// 3. Launches llama-cli process in current terminal
+// /// 4. Monitors process execution and handles termination
+// /// 
+// /// # Arguments
+// /// * `mode` - ChatModeConfig containing model, prompt, and parameter settings
+// /// 
+// /// # Returns
+// /// - Ok(()): Process completed successfully
+// /// - Err(String): Detailed error message if any step fails
+// /// 
+// /// # Process Handling
+// /// - Runs in current terminal (no new window)
+// /// - Waits for process completion
+// /// - Handles SIGINT (Ctrl+C) gracefully
+// /// 
+// /// # Error Cases
+// /// - LLaMA CLI path not found in config
+// /// - Invalid paths or parameters
+// /// - Process spawn failure
+// /// - Runtime errors from llama-cli
+// /// 
+// /// # Example Command Format
+// /// ```bash
+// /// /path/to/llama-cli -m "/path/to/model.gguf" --file "/path/to/prompt.txt" \
+// ///     --temp 0.8 --top-k 40 --top-p 0.9 --ctx-size 2000 --threads 4
+// /// ```
+// fn launch_llama(mode: &ChatModeConfig) -> Result<(), String> {
+//     // Validate llama-cli path
+//     let llama_cli_path = read_field_from_toml("llama_cli_path");
+//     if llama_cli_path.is_empty() {
+//         return Err("LLaMA CLI path not found in configuration".to_string());
+//     }
+
+//     // Validate that paths exist
+//     for path in [&llama_cli_path, &mode.model_path, &mode.prompt_path] {
+//         if !std::path::Path::new(path).exists() {
+//             return Err(format!("Path does not exist: {}", path));
+//         }
+//     }
+
+//     // Build command arguments as a Vec for cleaner handling
+//     let mut command_args: Vec<String> = Vec::new();
+    
+//     // Add model path
+//     command_args.push("-m".to_string());
+//     command_args.push(mode.model_path.clone());
+
+//     // Add clean terminal print (no load-data)    
+//     // command_args.push("2>/dev/null".to_string());
+    
+//     // Add prompt file
+//     command_args.push("--file".to_string());
+//     command_args.push(mode.prompt_path.clone());
+    
+//     // Add all parameters
+//     command_args.extend(vec![
+//         "--temp".to_string(), mode.parameters.temperature_value.to_string(),
+//         "--top-k".to_string(), mode.parameters.top_k_sampling.to_string(),
+//         "--top-p".to_string(), mode.parameters.top_p_sampling.to_string(),
+//         "--ctx-size".to_string(), mode.parameters.context_size.to_string(),
+//         "--threads".to_string(), mode.parameters.thread_count.to_string(),
+//     ]);
+
+//     // Add GPU layers if specified
+//     if mode.parameters.gpu_layers > 0 {
+//         command_args.extend(vec![
+//             "--n-gpu-layers".to_string(),
+//             mode.parameters.gpu_layers.to_string(),
+//         ]);
+//     }
+
+//     // Add interactive-first if enabled
+//     if mode.parameters.interactive_first {
+//         command_args.push("--interactive-first".to_string());
+//     }
+
+//     // Add no-display-prompt flag
+//     command_args.push("--no-display-prompt".to_string());
+
+//     // Log the complete command for debugging
+//     println!("\nLaunching LLaMA.cpp with command:");
+//     println!("{} {}", llama_cli_path, command_args.join(" "));
+
+//     // Create and configure command
+//     let process_result = Command::new(&llama_cli_path)
+//         .args(&command_args)
+//         .stdin(std::process::Stdio::inherit())
+//         .stdout(std::process::Stdio::inherit())
+//         .stderr(std::process::Stdio::inherit())
+//         .spawn();
+
+//     // Handle process creation result
+//     let mut process = match process_result {
+//         Ok(process) => process,
+//         Err(e) => return Err(format!(
+//             "Failed to launch LLaMA process: {}. Check if llama-cli path is correct: {}", 
+//             e, llama_cli_path
+//         )),
+//     };
+
+//     // Wait for process completion
+//     match process.wait() {
+//         Ok(status) => {
+//             if status.success() {
+//                 Ok(())
+//             } else {
+//                 Err(format!(
+//                     "LLaMA process exited with status: {}",
+//                     status.code().unwrap_or(-1)
+//                 ))
+//             }
+//         },
+//         Err(e) => Err(format!("Error waiting for LLaMA process: {}", e)),
+//     }
+// }
+
+/// Reads the llama-cli builds registered as `[binaries.<name>]` tables in the config
+///
+/// # Returns
+/// * `Vec<(String, String)>` - (backend name, llama-cli path) pairs, in config order
+fn read_llama_binaries() -> Vec<(String, String)> {
+    llama_binaries(&read_config())
+}
+
+/// The `[binaries.<name>]` tables of a parsed config that have a path
+fn llama_binaries(config: &toml_lite::Table) -> Vec<(String, String)> {
+    config.get_table("binaries")
+        .map(|binaries| binaries.tables()
+            .filter_map(|(name, table)| table.get_str("path").map(|path| (name.to_string(), path.to_string())))
+            .collect())
+        .unwrap_or_default()
+}
+
+/// Checks that a llama-cli binary can start, by running it with --version
+///
+/// A GPU build with broken drivers or missing libraries typically fails here
+/// (e.g. CUDA builds after a driver update).
+fn llama_binary_starts(llama_cli_path: &str) -> bool {
+    Command::new(llama_cli_path)
+        .arg("--version")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Gets the path of the CPU llama-cli build
+///
+/// Uses `[binaries.cpu]` if registered, otherwise the top level llama_cli_path.
+fn get_cpu_llama_cli_path() -> Result<String, String> {
+    if let Some((_, path)) = read_llama_binaries().into_iter().find(|(name, _)| name == "cpu") {
+        return Ok(path);
+    }
+    let llama_cli_path = read_field_from_toml("llama_cli_path");
+    if llama_cli_path.is_empty() {
+        return Err("LLaMA CLI path not found in configuration".to_string());
+    }
+    Ok(to_native_path(&llama_cli_path))
+}
+
+/// Chooses the llama-cli binary for a mode, falling back to the CPU build
+///
+/// Modes with `backend=<name>` use the `[binaries.<name>]` build. If that build
+/// fails to start, the CPU build is used instead, with gpu_layers set to 0.
+///
+/// # Arguments
+/// * `mode` - Mode to launch
+///
+/// # Returns
+/// - Ok((String, ChatModeConfig)): llama-cli path, and the mode adjusted for it
+/// - Err(String): Unknown backend, or no llama-cli configured
+fn select_llama_binary(mode: &ChatModeConfig) -> Result<(String, ChatModeConfig), String> {
+    if mode.backend.is_empty() {
+        return Ok((get_cpu_llama_cli_path()?, mode.clone()));
+    }
+
+    let binaries = read_llama_binaries();
+    let backend_path = binaries.iter()
+        .find(|(name, _)| *name == mode.backend)
+        .map(|(_, path)| path.clone())
+        .ok_or_else(|| format!(
+            "Backend '{}' is not registered. Add it to the config as:\n[binaries.{}]\npath = \"/path/to/llama-cli\"",
+            mode.backend, mode.backend
+        ))?;
+
+    if mode.backend == "cpu" || llama_binary_starts(&backend_path) {
+        return Ok((backend_path, mode.clone()));
+    }
+
+    let cpu_path = get_cpu_llama_cli_path()?;
+    println!(
+        "Warning: '{}' build failed to start ({}), falling back to CPU build: {}",
+        mode.backend, backend_path, cpu_path
+    );
+    let mut cpu_mode = mode.clone();
+    cpu_mode.backend = "cpu".to_string();
+    cpu_mode.parameters.gpu_layers = 0;
+    Ok((cpu_path, cpu_mode))
+}
+
+/// Known llama.cpp failures: (stderr patterns, what went wrong, what to do)
+///
+/// Patterns are matched case-insensitively; any one pattern matching is enough.
+const LLAMA_FAILURE_PATTERNS: &[(&[&str], &str, &str)] = &[
+    (
+        &["cuda error: out of memory", "cudamalloc failed", "out of memory", "failed to allocate"],
+        "The GPU ran out of memory while loading or running the model.",
+        "Lower gpu_layers for this mode (or set it to 0), use a smaller ctx_size, or use a smaller quantization of the model.",
+    ),
+    (
+        &["unsupported gguf version", "invalid gguf version", "gguf version"],
+        "The model file uses a GGUF version this llama.cpp build does not support.",
+        "Update llama.cpp (git pull and rebuild), or download a GGUF made for your llama.cpp version.",
+    ),
+    (
+        &["unknown model architecture", "unknown architecture"],
+        "This llama.cpp build does not know the model's architecture.",
+        "Update llama.cpp to a version that supports this model family, or pick a different model.",
+    ),
+    (
+        &["wrong shape", "tensor shape", "has wrong shape", "unexpected tensor dimensions"],
+        "A tensor in the model file does not have the shape llama.cpp expects.",
+        "The download may be corrupt or made by a different converter version. Re-download the model, or update llama.cpp.",
+    ),
+    (
+        &["error while loading shared libraries", "cannot open shared object file", "library not loaded", "dll not found"],
+        "llama.cpp could not find a shared library it was built against.",
+        "Rebuild llama.cpp on this machine, or install the missing library (e.g. the CUDA runtime for a CUDA build).",
+    ),
+    (
+        &["failed to open", "no such file or directory"],
+        "llama.cpp could not open a file it was given.",
+        "Check that the mode's model and prompt paths exist (query_gguf config lint).",
+    ),
+];
+
+/// Matches captured llama.cpp stderr against known failures
+///
+/// # Returns
+/// * `Option<(&str, &str)>` - (what went wrong, what to do) for the first known failure found
+fn classify_llama_failure(stderr: &str) -> Option<(&'static str, &'static str)> {
+    let lowered = stderr.to_lowercase();
+    LLAMA_FAILURE_PATTERNS.iter()
+        .find(|(patterns, _, _)| patterns.iter().any(|pattern| lowered.contains(pattern)))
+        .map(|(_, problem, remedy)| (*problem, *remedy))
+}
+
+/// Turns captured llama.cpp stderr into a readable failure message
+///
+/// Known failures get a short explanation and a suggested fix, followed by
+/// the few stderr lines that matter; anything else shows the end of stderr.
+fn describe_llama_failure(stderr: &str) -> String {
+    let lines: Vec<&str> = stderr.lines().filter(|line| !line.trim().is_empty()).collect();
+    match classify_llama_failure(stderr) {
+        Some((problem, remedy)) => {
+            let known_patterns: Vec<&str> = LLAMA_FAILURE_PATTERNS.iter()
+                .flat_map(|(patterns, _, _)| patterns.iter().copied())
+                .collect();
+            let relevant: Vec<&str> = lines.iter()
+                .filter(|line| {
+                    let lowered = line.to_lowercase();
+                    known_patterns.iter().any(|pattern| lowered.contains(pattern))
+                })
+                .take(3)
+                .copied()
+                .collect();
+            format!("{}\nFix: {}\n\nFrom llama.cpp:\n  {}", problem, remedy, relevant.join("\n  "))
+        },
+        None => {
+            let tail_start = lines.len().saturating_sub(15);
+            format!("Last lines from llama.cpp:\n  {}", lines[tail_start..].join("\n  "))
+        },
+    }
+}
+
+/// Picks llama.cpp's warning lines out of its stderr, without repeats (at most 10)
+fn collect_llama_warnings(stderr: &str) -> Vec<String> {
+    let mut warnings: Vec<String> = Vec::new();
+    for line in stderr.lines() {
+        let line = line.trim();
+        if line.to_lowercase().contains("warn") && !warnings.iter().any(|warning| warning == line) {
+            warnings.push(line.to_string());
+        }
+    }
+    warnings.truncate(10);
+    warnings
+}
+
+/// Reads a TOML string or string list value, e.g. `"a"` or `["a", "b"]`, into its strings
+///
+/// Text that is not a TOML value (e.g. an unquoted path in a mode entry) is one item.
+fn toml_string_list(value: &str) -> Vec<String> {
+    match toml_lite::parse_value(value) {
+        Ok(value) => value.string_list(),
+        Err(_) if value.trim().is_empty() => Vec::new(),
+        Err(_) => vec![value.trim().to_string()],
+    }
+}
+
+/// One `[tests.<name>]` canary of a mode's test file
+#[derive(Debug, Clone, PartialEq)]
+struct ModeTest {
+    name: String,
+    prompt: String,
+    contains: Vec<String>,     // substrings the reply must have
+    not_contains: Vec<String>, // substrings the reply must not have
+    max_tokens: i32,
+}
+
+/// Parses a mode test file, e.g.
+/// ```toml
+/// [tests.capital]
+/// prompt = "What is the capital of France?"
+/// contains = ["Paris"]
+/// not_contains = "Lyon"
+/// n = 64
+/// ```
+fn parse_mode_tests(content: &str) -> Result<Vec<ModeTest>, String> {
+    let (root, errors) = toml_lite::parse(content);
+    if let Some(error) = errors.first() {
+        return Err(error.clone());
+    }
+    let mut tests = Vec::new();
+    for (name, table) in root.get_table("tests").map(|tests| tests.tables().collect()).unwrap_or_else(Vec::new) {
+        let prompt = table.get_text("prompt").unwrap_or_default();
+        if prompt.is_empty() {
+            return Err(format!("[tests.{}] has no prompt", name));
+        }
+        let contains = table.get_string_list("contains");
+        let not_contains = table.get_string_list("not_contains");
+        if contains.is_empty() && not_contains.is_empty() {
+            return Err(format!("[tests.{}] needs contains or not_contains", name));
+        }
+        let max_tokens = match table.get("n") {
+            None => 256,
+            Some(value) => value.as_int().and_then(|tokens| i32::try_from(tokens).ok()).filter(|tokens| *tokens > 0)
+                .ok_or_else(|| format!("[tests.{}] n must be a number of tokens: {}", name, value))?,
+        };
+        tests.push(ModeTest { name: name.to_string(), prompt, contains, not_contains, max_tokens });
+    }
+    Ok(tests)
+}
+
+/// Checks a reply against a test's assertions (None if it passes, else the first failed assertion)
+fn check_mode_test_reply(test: &ModeTest, reply: &str) -> Option<String> {
+    if let Some(missing) = test.contains.iter().find(|expected| !reply.contains(expected.as_str())) {
+        return Some(format!("missing \"{}\"", missing));
+    }
+    test.not_contains.iter()
+        .find(|unwanted| reply.contains(unwanted.as_str()))
+        .map(|unwanted| format!("contains \"{}\"", unwanted))
+}
+
+/// Results of a mode's last `mode test` run: what it ran with, and pass/fail per test
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ModeTestRecord {
+    model: String,
+    prompt_hash: String,
+    results: Vec<(String, bool)>,
+}
+
+impl ModeTestRecord {
+    fn format(&self) -> String {
+        let mut text = format!("model\t{}\nprompt_hash\t{}\n", self.model, self.prompt_hash);
+        for (name, passed) in &self.results {
+            text.push_str(&format!("test\t{}\t{}\n", name, if *passed { "pass" } else { "fail" }));
+        }
+        text
+    }
+
+    fn parse(text: &str) -> ModeTestRecord {
+        let mut record = ModeTestRecord::default();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.as_slice() {
+                ["model", model] => record.model = model.to_string(),
+                ["prompt_hash", hash] => record.prompt_hash = hash.to_string(),
+                ["test", name, result] => record.results.push((name.to_string(), *result == "pass")),
+                _ => (),
+            }
+        }
+        record
+    }
+}
+
+/// Compares a test run with the previous one
+///
+/// # Returns
+/// * `(Vec<String>, Vec<String>)` - Tests that passed before and fail now (regressions),
+///   and tests that failed before and pass now (fixed)
+fn compare_mode_test_runs(previous: &ModeTestRecord, current: &ModeTestRecord) -> (Vec<String>, Vec<String>) {
+    let mut regressions = Vec::new();
+    let mut fixed = Vec::new();
+    for (name, passed) in &current.results {
+        match previous.results.iter().find(|(previous_name, _)| previous_name == name) {
+            Some((_, true)) if !passed => regressions.push(name.clone()),
+            Some((_, false)) if *passed => fixed.push(name.clone()),
+            _ => (),
+        }
+    }
+    (regressions, fixed)
+}
+
+/// Runs a mode's canary prompts: `query_gguf mode test <mode>`
+///
+/// The mode's `tests=` files (paths relative to ~/query_gguf, see
+/// `parse_mode_tests`) are run with the mode's prompt file as system message
+/// and a fixed seed. The results are compared with the mode's previous run,
+/// so a changed system prompt or swapped model shows which tests regressed.
+///
+/// # Returns
+/// - Ok(()): All tests passed
+/// - Err(String): Tests failed, or could not be run
+fn handle_mode_test_command(selector: &str) -> Result<(), String> {
+    let modes = read_saved_modes()?;
+    let mode = find_mode_by_selector(&modes, selector)
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?
+        .clone();
+    let test_files = toml_string_list(&mode.parameters.tests);
+    if test_files.is_empty() {
+        return Err(format!("Mode '{}' has no tests; add tests=tests/{}.toml to its entry", mode.id, mode.id));
+    }
+
+    let base_dir = get_app_base_dir()?;
+    let mut tests: Vec<ModeTest> = Vec::new();
+    for file in &test_files {
+        let path = base_dir.join(file);
+        let content = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read tests {}: {}", path.display(), e))?;
+        for test in parse_mode_tests(&content).map_err(|e| format!("{}: {}", path.display(), e))? {
+            if tests.iter().any(|existing| existing.name == test.name) {
+                return Err(format!("Test '{}' is defined twice in {}", test.name, test_files.join(", ")));
+            }
+            tests.push(test);
+        }
+    }
+    if tests.is_empty() {
+        return Err(format!("No [tests.<name>] tables in {}", test_files.join(", ")));
+    }
+
+    let prompt_text = fs::read_to_string(&mode.prompt_path).unwrap_or_default();
+    let server = start_or_attach_server(&mode)?;
+    println!("\n=== Testing {} [{}]: {} test(s) from {} ===", mode.name, mode.id, tests.len(), test_files.join(", "));
+    let mut current = ModeTestRecord {
+        model: mode.model_path.clone(),
+        prompt_hash: format!("{:016x}", fnv1a_hash(prompt_text.as_bytes())),
+        results: Vec::new(),
+    };
+    for test in &tests {
+        let mut messages = Vec::new();
+        if !prompt_text.trim().is_empty() {
+            messages.push(ChatMessage { role: "system".to_string(), content: prompt_text.trim().to_string() });
+        }
+        messages.push(ChatMessage { role: "user".to_string(), content: test.prompt.clone() });
+        let failure = match server.chat(&messages, &mode.parameters, test.max_tokens, Some(42)) {
+            Ok(result) => check_mode_test_reply(test, &result.content)
+                .map(|reason| format!("{}: {}", reason, one_line_preview(&result.content, 60))),
+            Err(e) => Some(format!("error: {}", e)),
+        };
+        match &failure {
+            None => println!("  PASS {}", test.name),
+            Some(reason) => println!("  FAIL {}: {}", test.name, reason),
+        }
+        current.results.push((test.name.clone(), failure.is_none()));
+    }
+
+    let record_dir = base_dir.join("mode_tests");
+    let record_path = record_dir.join(format!("{}.tsv", mode.id));
+    if let Ok(text) = fs::read_to_string(&record_path) {
+        let previous = ModeTestRecord::parse(&text);
+        let mut changes = Vec::new();
+        if previous.model != current.model {
+            changes.push(format!("model ({} -> {})", previous.model, current.model));
+        }
+        if previous.prompt_hash != current.prompt_hash {
+            changes.push("system prompt".to_string());
+        }
+        if !changes.is_empty() {
+            println!("\nChanged since the last run: {}", changes.join(", "));
+        }
+        let (regressions, fixed) = compare_mode_test_runs(&previous, &current);
+        for name in &regressions {
+            println!("REGRESSION: {} (passed last run)", name);
+        }
+        for name in &fixed {
+            println!("Fixed: {} (failed last run)", name);
+        }
+    }
+    fs::create_dir_all(&record_dir)
+        .map_err(|e| format!("Failed to create {}: {}", record_dir.display(), e))?;
+    fs::write(&record_path, current.format())
+        .map_err(|e| format!("Failed to write {}: {}", record_path.display(), e))?;
+
+    let failed = current.results.iter().filter(|(_, passed)| !passed).count();
+    println!("\n{} of {} test(s) passed", tests.len() - failed, tests.len());
+    if failed > 0 {
+        return Err(format!("{} test(s) failed for mode '{}'", failed, mode.id));
+    }
+    Ok(())
+}
+
+/// Proves a mode works without an interactive session: `query_gguf mode verify <mode>`
+///
+/// Runs llama-cli with the mode's model and flags, a trivial prompt, and
+/// `--n-predict 1`, so the model has to load (fit in memory) and every flag
+/// has to be accepted. Reports the load time and any warnings llama.cpp printed.
+/// `mode test <mode>` runs the mode's canary prompts instead (`handle_mode_test_command`).
+fn handle_mode_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: mode verify|test <mode number or id>";
+    let (Some(action), Some(selector)) = (args.first().map(|arg| arg.as_str()), args.get(1)) else {
+        return Err(usage.to_string());
+    };
+    match action {
+        "verify" => (),
+        "test" => return handle_mode_test_command(selector),
+        _ => return Err(usage.to_string()),
+    }
+    let modes = read_saved_modes()?;
+    let mode = find_mode_by_selector(&modes, selector)
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?
+        .clone();
+    if !mode.remote.is_empty() {
+        return Err(format!("Mode '{}' runs on {}; mode verify checks local models only", mode.id, mode.remote));
+    }
+    println!("Verifying {} [{}]: {}", mode.name, mode.id, mode.model_path);
+
+    if !mode.endpoint.is_empty() {
+        let started = std::time::Instant::now();
+        LlamaServer::connect(&mode).complete("Hi", &mode.parameters, 1)?;
+        println!("OK: {} answered in {:.1}s", mode.endpoint, started.elapsed().as_secs_f64());
+        return Ok(());
+    }
+    check_model_file(&mode)?;
+    if !Path::new(&mode.prompt_path).is_file() {
+        println!("Warning: Prompt file not found: {}", mode.prompt_path);
+    }
+
+    let (llama_cli_path, mode) = select_llama_binary(&mode)?;
+    let mut command_args: Vec<String> = vec![
+        "-m".to_string(), mode.model_path.clone(),
+        "-p".to_string(), "Hi".to_string(),
+        "--n-predict".to_string(), "1".to_string(),
+        "--temp".to_string(), mode.parameters.temperature_value.to_string(),
+        "--top-k".to_string(), mode.parameters.top_k_sampling.to_string(),
+        "--top-p".to_string(), mode.parameters.top_p_sampling.to_string(),
+        "--ctx-size".to_string(), mode.parameters.context_size.to_string(),
+        "--threads".to_string(), mode.parameters.thread_count.to_string(),
+    ];
+    if mode.parameters.gpu_layers > 0 {
+        command_args.push("--n-gpu-layers".to_string());
+        command_args.push(mode.parameters.gpu_layers.to_string());
+    }
+    command_args.push("-no-cnv".to_string());
+
+    let started = std::time::Instant::now();
+    let output = Command::new(&llama_cli_path)
+        .args(&command_args)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|e| format!("Failed to run llama-cli at {}: {}", llama_cli_path, e))?;
+    let elapsed = started.elapsed();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+    if !output.status.success() {
+        return Err(format!(
+            "llama-cli exited with status {}; mode '{}' does not work as configured.\n{}",
+            output.status.code().unwrap_or(-1),
+            mode.id,
+            describe_llama_failure(&stderr)
+        ));
+    }
+
+    match parse_llama_perf_ms(&stderr, "load time") {
+        Some(load_ms) => println!("OK: model loaded in {:.1}s ({:.1}s in total)", load_ms / 1000.0, elapsed.as_secs_f64()),
+        None => println!("OK: llama-cli finished in {:.1}s", elapsed.as_secs_f64()),
+    }
+    let warnings = collect_llama_warnings(&stderr);
+    if warnings.is_empty() {
+        println!("No warnings from llama.cpp.");
+    } else {
+        println!("Warnings from llama.cpp:");
+        for warning in warnings {
+            println!("  {}", warning);
+        }
+    }
+    Ok(())
+}
+
+/// Shows or moves the data directory: `query_gguf data-dir [migrate [DIR]]`
+///
+/// - `data-dir`               -> print the directory in use and where it comes from
+/// - `data-dir migrate [DIR]` -> move it to DIR (default: the build's default directory)
+///
+/// Moving rewrites the paths into the old directory in the config and the
+/// state file. Unless DIR is the build's default, later runs need
+/// `--data-dir DIR` (e.g. in the shell alias).
+fn handle_data_dir_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: data-dir | data-dir migrate [new directory]";
+    let home_dir = get_home_dir()?;
+    let build_default = BUILD_DEFAULT_DATA_DIR
+        .filter(|dir| !dir.trim().is_empty())
+        .map(|dir| PathBuf::from(normalize_config_path_value(dir, &home_dir)));
+    let data_dir = get_app_base_dir()?;
+
+    match args.first().map(|arg| arg.as_str()) {
+        None => {
+            let source = if DATA_DIR_FLAG.get().is_some() {
+                "--data-dir"
+            } else if build_default.as_ref() == Some(&data_dir) {
+                "build default"
+            } else {
+                "~/query_gguf"
+            };
+            println!("{} ({})", data_dir.display(), source);
+            if let Some(default_dir) = build_default.filter(|dir| *dir != data_dir && DATA_DIR_FLAG.get().is_none()) {
+                println!(
+                    "This build keeps its files in {}; run 'query_gguf data-dir migrate' to move them there.",
+                    default_dir.display()
+                );
+            }
+            Ok(())
+        },
+        Some("migrate") => {
+            let new_dir = match args.get(1) {
+                Some(dir) => {
+                    let dir = PathBuf::from(normalize_config_path_value(dir, &home_dir));
+                    if dir.is_relative() {
+                        std::env::current_dir().map_err(|e| e.to_string())?.join(dir)
+                    } else {
+                        dir
+                    }
+                },
+                None => build_default.clone().ok_or("This build has no default data directory. Use: data-dir migrate <new directory>")?,
+            };
+            migrate_data_dir(&data_dir, &new_dir)?;
+            println!("Moved {} to {}", data_dir.display(), new_dir.display());
+            if build_default.as_ref() != Some(&new_dir) {
+                println!("Run query_gguf with --data-dir {} (e.g. in your shell alias) to use it.", new_dir.display());
+            }
+            Ok(())
+        },
+        _ => Err(usage.to_string()),
+    }
+}
+
+/// Moves the data directory and rewrites the paths into it in the config and state file
+///
+/// Tries a rename first; across file systems the tree is copied and the old
+/// one removed after the copy succeeded.
+///
+/// # Arguments
+/// * `old_dir` - The data directory in use
+/// * `new_dir` - Where to move it; must not exist, or be an empty directory
+fn migrate_data_dir(old_dir: &Path, new_dir: &Path) -> Result<(), String> {
+    if new_dir.starts_with(old_dir) || old_dir.starts_with(new_dir) {
+        return Err(format!("{} and {} are inside each other", old_dir.display(), new_dir.display()));
+    }
+    if new_dir.exists() {
+        let is_empty = fs::read_dir(new_dir).map(|mut entries| entries.next().is_none()).unwrap_or(false);
+        if !is_empty {
+            return Err(format!("{} already exists and is not an empty directory", new_dir.display()));
+        }
+        fs::remove_dir(new_dir).map_err(|e| format!("Failed to replace {}: {}", new_dir.display(), e))?;
+    }
+    if let Some(parent) = new_dir.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    if fs::rename(old_dir, new_dir).is_err() {
+        copy_dir_recursive(old_dir, new_dir)?;
+        fs::remove_dir_all(old_dir)
+            .map_err(|e| format!("Copied to {}, but failed to remove {}: {}", new_dir.display(), old_dir.display(), e))?;
+    }
+
+    let home_dir = get_home_dir()?;
+    for file_name in ["query_gguf_config.toml", "query_gguf_state.toml"] {
+        let path = new_dir.join(file_name);
+        if !path.is_file() {
+            continue;
+        }
+        let _lock = StateFileLock::acquire(&path)?;
+        let content = fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let (updated, count) = rewrite_data_dir_paths(&content, old_dir, new_dir, &home_dir);
+        if count > 0 {
+            write_file_atomically(&path, &updated)?;
+            println!("Updated {} path(s) in {}", count, path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Copies a directory tree, for moves across file systems
+///
+/// Symlinks are recreated as symlinks, not followed, so a link to a big
+/// model folder is not copied and a link loop cannot recurse forever.
+fn copy_dir_recursive(from: &Path, to: &Path) -> Result<(), String> {
+    fs::create_dir_all(to).map_err(|e| format!("Failed to create {}: {}", to.display(), e))?;
+    for entry in fs::read_dir(from).map_err(|e| format!("Failed to read {}: {}", from.display(), e))? {
+        let entry = entry.map_err(|e| format!("Failed to read {}: {}", from.display(), e))?;
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type().map_err(|e| format!("Failed to read {}: {}", entry.path().display(), e))?;
+        if file_type.is_symlink() {
+            copy_symlink(&entry.path(), &target)?;
+        } else if file_type.is_dir() {
+            copy_dir_recursive(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)
+                .map_err(|e| format!("Failed to copy {}: {}", entry.path().display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates a symlink at `to` pointing where the symlink `from` points
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> Result<(), String> {
+    let link_target = fs::read_link(from).map_err(|e| format!("Failed to read link {}: {}", from.display(), e))?;
+    std::os::unix::fs::symlink(&link_target, to)
+        .map_err(|e| format!("Failed to create link {}: {}", to.display(), e))
+}
+
+#[cfg(windows)]
+fn copy_symlink(from: &Path, to: &Path) -> Result<(), String> {
+    let link_target = fs::read_link(from).map_err(|e| format!("Failed to read link {}: {}", from.display(), e))?;
+    let created = if from.is_dir() {
+        std::os::windows::fs::symlink_dir(&link_target, to)
+    } else {
+        std::os::windows::fs::symlink_file(&link_target, to)
+    };
+    created.map_err(|e| format!("Failed to create link {}: {}", to.display(), e))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn copy_symlink(from: &Path, _to: &Path) -> Result<(), String> {
+    Err(format!("Cannot copy the link {} on this system", from.display()))
+}
+
+/// Rewrites paths into the old data directory to the new one
+///
+/// Matches the absolute path and, under the home directory, its `~/` form
+/// and its home-relative form (e.g. `query_gguf/chatlogs` for
+/// log_directory_path). A match must start a value or `|`-separated part
+/// (absolute and `~/` paths may also follow `=`, a space, or a quote) and
+/// end at a path separator or the end of the part, so e.g. `~/query_gguf2`
+/// is left alone.
+///
+/// # Returns
+/// * `(String, usize)` - The rewritten content and the number of paths changed
+fn rewrite_data_dir_paths(content: &str, old_dir: &Path, new_dir: &Path, home_dir: &str) -> (String, usize) {
+    let new_text = new_dir.to_string_lossy().to_string();
+    // (form, characters it may follow, characters it may be followed by)
+    let mut forms = vec![(old_dir.to_string_lossy().to_string(), "\"|= '", "/\"|' ")];
+    if let Ok(relative) = old_dir.strip_prefix(home_dir) {
+        let relative = relative.to_string_lossy().to_string();
+        forms.push((format!("~/{}", relative), "\"|= '", "/\"|' "));
+        forms.push((relative, "\"|", "/"));
+    }
+
+    let mut rewritten = String::with_capacity(content.len());
+    let mut count = 0;
+    let mut previous = None;
+    let mut rest = content;
+    while let Some(c) = rest.chars().next() {
+        let found = forms.iter().find(|(form, before, after)| {
+            !form.is_empty()
+                && rest.starts_with(form.as_str())
+                && previous.is_some_and(|previous| before.contains(previous))
+                && rest[form.len()..].chars().next().is_none_or(|next| after.contains(next))
+        });
+        match found {
+            Some((form, _, _)) => {
+                rewritten.push_str(&new_text);
+                rest = &rest[form.len()..];
+                previous = form.chars().last();
+                count += 1;
+            },
+            None => {
+                rewritten.push(c);
+                rest = &rest[c.len_utf8()..];
+                previous = Some(c);
+            },
+        }
+    }
+    (rewritten, count)
+}
+
+/// Manages saved modes: `query_gguf modes list|delete|rename|copy`
+///
+/// - `modes` or `modes list`                -> list modes with their number, id, and model
+/// - `modes delete <mode> [--yes]`          -> remove a mode (asks first unless --yes)
+/// - `modes rename <mode> <name>`           -> change a mode's display name (its id stays)
+/// - `modes copy <mode> [name]`             -> add a copy as a `[mode.<id>]` table, e.g. to try other settings
+///
+/// `<mode>` is a mode number or id, as in the menu. Deleting a `mode_N`
+/// entry renumbers the later ones, so the file and the menu agree.
+fn handle_modes_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: modes list | modes delete <mode> [--yes] | modes rename <mode> <name> | modes copy <mode> [name]";
+    let action = args.first().map(|arg| arg.as_str()).unwrap_or("list");
+    let modes = read_saved_modes()?;
+    if action == "list" {
+        if modes.is_empty() {
+            println!("No modes saved. Create one from the menu with 'make'.");
+            return Ok(());
+        }
+        let default_mode = read_field_from_toml("default_mode");
+        for (index, mode) in modes.iter().enumerate() {
+            let is_default = find_mode_by_selector(&modes, &default_mode).is_some_and(|default| default.id == mode.id);
+            println!("{}. {} [{}]{}", index + 1, mode.name, mode.id, if is_default { " (default)" } else { "" });
+            if !mode.description.is_empty() {
+                println!("   {}", mode.description);
+            }
+            let target = match (mode.endpoint.as_str(), mode.remote.as_str()) {
+                ("", "") => mode.model_path.clone(),
+                ("", remote) => format!("{} on {}", mode.model_path, remote),
+                (endpoint, _) => endpoint.to_string(),
+            };
+            println!("   {}", target);
+        }
+        return Ok(());
+    }
+
+    let selector = args.get(1).ok_or(usage)?;
+    let index = find_mode_by_selector(&modes, selector)
+        .and_then(|found| modes.iter().position(|mode| mode.id == found.id))
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?;
+    let mode = &modes[index];
+    let config_path = get_config_path()?;
+    let content = fs::read_to_string(&config_path)
+        .map_err(|e| format!("Failed to read config at {}: {}", config_path.display(), e))?;
+
+    match action {
+        "delete" | "remove" => {
+            let assume_yes = args.iter().any(|arg| arg == "--yes");
+            if !assume_yes && !prompt_yes_no(&format!("Delete mode {}. {} [{}]?", index + 1, mode.name, mode.id))? {
+                println!("Nothing deleted.");
+                return Ok(());
+            }
+            let updated = remove_mode_entry(&content, index, &mode.id)?;
+            write_config_file(&config_path, &updated, &format!("mode deleted ({})", mode.id))?;
+            println!("Deleted mode {} [{}]", mode.name, mode.id);
+            for child in modes.iter().filter(|other| other.extends == mode.id) {
+                println!("Warning: Mode {} [{}] extends the deleted mode; edit its extends= entry", child.name, child.id);
+            }
+        },
+        "rename" => {
+            let name = args[2..].join(" ");
+            if name.trim().is_empty() {
+                return Err(usage.to_string());
+            }
+            let updated = rename_mode_entry(&content, index, &mode.id, name.trim())?;
+            write_config_file(&config_path, &updated, &format!("mode renamed ({})", mode.id))?;
+            println!("Renamed {} to {} [{}]", mode.name, name.trim(), mode.id);
+        },
+        "copy" | "duplicate" => {
+            let entry = config_mode_entries(&read_config())
+                .get(index)
+                .cloned()
+                .ok_or_else(|| format!("Mode {} not found in the config", index + 1))?;
+            let name = Some(args[2..].join(" ")).filter(|name| !name.trim().is_empty())
+                .unwrap_or_else(|| format!("{} (copy)", mode.name));
+            let id = make_unique_mode_id(&slugify_mode_name(&name), &modes);
+            let mut updated = content.trim_end().to_string();
+            updated.push('\n');
+            updated.push_str(&format_mode_entry_table(&id, &ModeEntry { name: name.trim().to_string(), ..entry }));
+            write_config_file(&config_path, &updated, &format!("mode copied ({} -> {})", mode.id, id))?;
+            println!("Copied {} to mode {}. {} [{}]", mode.name, modes.len() + 1, name.trim(), id);
+        },
+        _ => return Err(usage.to_string()),
+    }
+    Ok(())
+}
+
+/// Searches saved modes and prompt files: `query_gguf find <text>`
+///
+/// Matches (ignoring case) mode names, ids, descriptions, model file names,
+/// prompt file names, and prompt file contents. Each matching mode is shown
+/// with the fields that matched; prompt files from the prompt directories
+/// that no mode uses are listed after the modes.
+fn handle_find_command(args: &[String]) -> Result<(), String> {
+    let query = args.join(" ");
+    if query.trim().is_empty() {
+        return Err("Use: find <text>".to_string());
+    }
+    let query = query.trim();
+    let modes = read_saved_modes()?;
+    // Scanned first, so the scan's messages come before the results
+    let used_prompts: Vec<PathBuf> = modes.iter()
+        .filter_map(|mode| Path::new(&mode.prompt_path).canonicalize().ok())
+        .collect();
+    let mut paths = Vec::new();
+    let mut throttle = ScanThrottle::from_config();
+    for dir in &configured_prompt_dirs()? {
+        search_directory_for_prompts(&mut paths, dir, &mut throttle)?;
+    }
+    if throttle.ran_out {
+        println!("Warning: The prompt scan ran out of time (scan_time_budget_secs); only {} files are searched", paths.len());
+    }
+    paths.sort();
+    paths.dedup();
+
+    let mut mode_count = 0;
+    for (index, mode) in modes.iter().enumerate() {
+        let prompt_text = fs::read_to_string(&mode.prompt_path).unwrap_or_default();
+        let matches = mode_search_matches(mode, &prompt_text, query);
+        if matches.is_empty() {
+            continue;
+        }
+        if mode_count == 0 {
+            println!("Modes matching \"{}\":\n", query);
+        }
+        mode_count += 1;
+        println!("{}. {} [{}]", index + 1, mode.name, mode.id);
+        for (field, line) in matches {
+            println!("   {}: {}", field, line);
+        }
+    }
+
+    let mut prompt_count = 0;
+    for path in paths.iter().filter(|path| !used_prompts.contains(&PathBuf::from(path))) {
+        let file_name = Path::new(path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let text = fs::read_to_string(path).unwrap_or_default();
+        let Some(line) = find_matching_line(&file_name, query).or_else(|| find_matching_line(&text, query)) else {
+            continue;
+        };
+        if prompt_count == 0 {
+            println!("{}Other prompt files matching \"{}\":\n", if mode_count > 0 { "\n" } else { "" }, query);
+        }
+        prompt_count += 1;
+        println!("{}\n   {}", path, line);
+    }
+
+    if mode_count == 0 && prompt_count == 0 {
+        println!("No modes or prompt files match: {}", query);
+    }
+    Ok(())
+}
+
+/// Lists the fields of a mode that contain `query`, with the matching line of each
+///
+/// # Arguments
+/// * `mode` - The saved mode
+/// * `prompt_text` - Contents of the mode's prompt file (empty if unreadable)
+/// * `query` - Text to look for, ignoring case
+///
+/// # Returns
+/// * `Vec<(&str, String)>` - (field, matching line) pairs, in display order
+fn mode_search_matches(mode: &ChatModeConfig, prompt_text: &str, query: &str) -> Vec<(&'static str, String)> {
+    let file_name = |path: &str| Path::new(path).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let fields = [
+        ("name", mode.name.clone()),
+        ("id", mode.id.clone()),
+        ("description", mode.description.clone()),
+        ("model", file_name(&mode.model_path)),
+        ("endpoint", mode.endpoint.clone()),
+        ("prompt file", file_name(&mode.prompt_path)),
+        ("prompt", prompt_text.to_string()),
+    ];
+    fields.into_iter()
+        .filter_map(|(field, text)| find_matching_line(&text, query).map(|line| (field, line)))
+        .collect()
+}
+
+/// Finds the first line of `text` containing `query` (ignoring case), shortened for display
+fn find_matching_line(text: &str, query: &str) -> Option<String> {
+    let query = query.to_lowercase();
+    text.lines()
+        .find(|line| line.to_lowercase().contains(&query))
+        .map(|line| one_line_preview(line, 120))
+}
+
+/// Edits a saved mode interactively: `query_gguf edit <mode>`
+///
+/// Offers to swap the model and prompt file (with the same pickers as
+/// 'make'), then asks for each parameter with the mode's current value as
+/// the default. Only what changed is written, in place in the mode's entry,
+/// so settings inherited with extends= stay inherited and comments and the
+/// entry's position are kept.
+fn handle_edit_command(args: &[String]) -> Result<(), String> {
+    let selector = args.first().ok_or("Usage: query_gguf edit <mode number or id>")?;
+    let modes = read_saved_modes()?;
+    let index = find_mode_by_selector(&modes, selector)
+        .and_then(|found| modes.iter().position(|mode| mode.id == found.id))
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?;
+    let mode = modes[index].clone();
+
+    println!("\n=== Edit {} [{}] ===", mode.name, mode.id);
+    println!("  Model: {}", mode.model_path);
+    println!("  Prompt: {}", mode.prompt_path);
+    display_parameters(&mode.parameters);
+
+    let model_path = if prompt_yes_no("\nChange the model?")? {
+        let models = find_gguf_models()?;
+        if models.is_empty() {
+            return Err("No GGUF models found in configured directories".to_string());
+        }
+        Some(select_model_grouped(&models)?.full_path.clone())
+    } else {
+        None
+    };
+    let prompt_path = if prompt_yes_no("Change the prompt file?")? {
+        Some(select_prompt_file()?)
+    } else {
+        None
+    };
+
+    println!("\nEnter new values (or press Enter to keep the current one):");
+    let mut parameters = mode.parameters.clone();
+    configure_parameters_interactive(&mut parameters)?;
+    let changes = changed_parameter_settings(&mode.parameters, &parameters);
+
+    let model_path = model_path.filter(|path| *path != mode.model_path);
+    let prompt_path = prompt_path.filter(|path| *path != mode.prompt_path);
+    if changes.is_empty() && model_path.is_none() && prompt_path.is_none() {
+        println!("\nNo changes; mode {} left as it was.", mode.id);
+        return Ok(());
+    }
+
+    let config_path = get_config_path()?;
+    let content = fs::read_to_string(&config_path)
+        .map_err(|e| format!("Failed to read config at {}: {}", config_path.display(), e))?;
+    let updated = set_mode_entry_files(&content, index, model_path.as_deref(), prompt_path.as_deref())?;
+    let updated = update_mode_entry_parameters(&updated, index, &changes)?;
+    write_config_file(&config_path, &updated, &format!("mode edited ({})", mode.id))?;
+
+    println!("\nUpdated mode {} [{}]:", mode.name, mode.id);
+    if let Some(model_path) = &model_path {
+        println!("  Model: {}", model_path);
+    }
+    if let Some(prompt_path) = &prompt_path {
+        println!("  Prompt: {}", prompt_path);
+    }
+    for (key, value) in &changes {
+        println!("  {} = {}", key, value);
+    }
+    Ok(())
+}
+
+/// Prints the failure description for a saved stderr file: `query_gguf diagnose <file>`
+///
+/// Called from the terminal window after llama-cli exits with an error,
+/// so the window explains the failure instead of only showing raw output.
+fn handle_diagnose_command(args: &[String]) -> Result<(), String> {
+    let stderr_path = args.first()
+        .ok_or_else(|| "Usage: query_gguf diagnose <stderr file>".to_string())?;
+    let stderr = fs::read_to_string(stderr_path)
+        .map_err(|e| format!("Failed to read {}: {}", stderr_path, e))?;
+    println!("\nllama-cli failed.\n{}", describe_llama_failure(&stderr));
+    Ok(())
+}
+
+/// Checks the text of /proc/version for a WSL kernel
+fn is_wsl_kernel(proc_version: &str) -> bool {
+    proc_version.to_lowercase().contains("microsoft")
+}
+
+/// Returns true when running inside WSL (Windows Subsystem for Linux)
+fn is_wsl() -> bool {
+    cfg!(target_os = "linux")
+        && fs::read_to_string("/proc/version").map(|version| is_wsl_kernel(&version)).unwrap_or(false)
+}
+
+/// Translates a Windows path to its WSL mount, e.g. `C:\models\a.gguf` -> `/mnt/c/models/a.gguf`
+///
+/// # Returns
+/// - Some(String): The WSL path
+/// - None: Not a drive-letter Windows path
+fn windows_path_to_wsl(path: &str) -> Option<String> {
+    let mut chars = path.chars();
+    let drive = chars.next().filter(|c| c.is_ascii_alphabetic())?;
+    if chars.next() != Some(':') {
+        return None;
+    }
+    let rest = chars.as_str();
+    if !(rest.is_empty() || rest.starts_with('\\') || rest.starts_with('/')) {
+        return None;
+    }
+    Some(format!("/mnt/{}{}", drive.to_ascii_lowercase(), rest.replace('\\', "/")))
+}
+
+/// Makes a configured path usable on this system
+///
+/// In WSL, Windows paths (models kept on the Windows side) are translated
+/// to /mnt/<drive>/...; everywhere else the path is returned unchanged.
+fn to_native_path(path: &str) -> String {
+    if is_wsl() {
+        if let Some(translated) = windows_path_to_wsl(path) {
+            return translated;
+        }
+    }
+    path.to_string()
+}
+
+/// How an interactive llama-cli session is started
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TerminalLaunch {
+    NewWindow,        // spawn a terminal emulator window (the desktop default)
+    CurrentTerminal,  // run llama-cli right here, attached to this terminal
+    Piped,            // no terminal at all: run once and print the output
+}
+
+/// Checks the text of /proc/1/cgroup for container runtimes
+fn is_container_cgroup(cgroup: &str) -> bool {
+    ["docker", "kubepods", "containerd", "libpod", "lxc"]
+        .iter()
+        .any(|runtime| cgroup.contains(runtime))
+}
+
+/// Returns true when running inside a Docker/Podman/Kubernetes/LXC container
+fn is_in_container() -> bool {
+    if !cfg!(target_os = "linux") {
+        return false;
+    }
+    Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists()  // podman
+        || fs::read_to_string("/proc/1/cgroup").map(|cgroup| is_container_cgroup(&cgroup)).unwrap_or(false)
+}
+
+/// Returns true when a new terminal window could be shown
+///
+/// Linux and the BSDs need an X11 or Wayland display; WSL opens Windows
+/// Terminal or cmd instead, and macOS/Windows always have a desktop.
+fn has_graphical_display() -> bool {
+    if !(cfg!(target_os = "linux") || is_bsd()) || is_wsl() {
+        return true;
+    }
+    ["DISPLAY", "WAYLAND_DISPLAY"].iter()
+        .any(|name| std::env::var(name).map(|value| !value.is_empty()).unwrap_or(false))
+}
+
+/// Returns true inside tmux/screen or an ssh session, where a new window would open out of sight
+///
+/// (With X forwarding, ssh sessions have a $DISPLAY, but the window would
+/// open on the client machine, away from the session.)
+fn is_multiplexer_or_ssh_session() -> bool {
+    ["TMUX", "STY", "SSH_CONNECTION", "SSH_TTY"].iter()
+        .any(|name| std::env::var(name).map(|value| !value.is_empty()).unwrap_or(false))
+}
+
+/// Starts the session log of a launch in this terminal, telling the user where it goes
+fn open_session_log_for_terminal(mode: &ChatModeConfig) -> Option<File> {
+    let (path, file) = start_inline_session_log(mode)?;
+    println!("Session log: {}", path.display());
+    Some(file)
+}
+
+/// Chooses how to start an interactive session
+///
+/// # Arguments
+/// * `in_container` - Running inside a container (no terminal emulators to spawn)
+/// * `has_display` - A desktop is available for new windows
+/// * `term` - Value of $TERM (empty when unset)
+/// * `is_interactive` - stdin and stdout are both a terminal
+/// * `inline` - Stay in this terminal (--inline, or inside tmux/screen/ssh)
+fn choose_terminal_launch(in_container: bool, has_display: bool, term: &str, is_interactive: bool, inline: bool) -> TerminalLaunch {
+    if !is_interactive {
+        TerminalLaunch::Piped
+    } else if inline || in_container || !has_display || term == "dumb" {
+        TerminalLaunch::CurrentTerminal
+    } else {
+        TerminalLaunch::NewWindow
+    }
+}
+
+/// Runs a llama-cli command line in the current terminal, waiting for it to exit
+///
+/// With a session log (see start_inline_session_log), llama-cli's stdin and
+/// stdout go through pipes, and everything typed and printed is also
+/// written to the log. stderr (loading messages) is not logged.
+fn run_in_current_terminal(llama_args: &[OsString], log: Option<File>) -> Result<(), String> {
+    let status = match log {
+        None => Command::new(&llama_args[0])
+            .args(&llama_args[1..])
+            .status(),
+        Some(mut log) => Command::new(&llama_args[0])
+            .args(&llama_args[1..])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                if let Some(child_stdin) = child.stdin.take() {
+                    let mut input_log: Box<dyn Write + Send> = match log.try_clone() {
+                        Ok(file) => Box::new(file),
+                        Err(_) => Box::new(io::sink()),
+                    };
+                    // Not joined: it waits for a line on stdin until the program exits
+                    std::thread::spawn(move || tee_stream(io::stdin(), child_stdin, &mut input_log));
+                }
+                if let Some(child_stdout) = child.stdout.take() {
+                    tee_stream(child_stdout, io::stdout(), &mut log)?;
+                }
+                child.wait()
+            }),
+    }
+    .map_err(|e| format!("Failed to run {}: {}", llama_args[0].to_string_lossy(), e))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("llama-cli exited with status {}", status.code().unwrap_or(-1)))
+    }
+}
+
+/// Copies `input` to `output` until it ends, writing a copy of everything to `log`
+///
+/// Output is flushed after every read, so streamed tokens and prompts
+/// without a newline show up at once. Log write errors are ignored.
+fn tee_stream(mut input: impl io::Read, mut output: impl Write, log: &mut impl Write) -> io::Result<()> {
+    let mut buffer = [0u8; 4096];
+    loop {
+        let count = match input.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        output.write_all(&buffer[..count])?;
+        output.flush()?;
+        let _ = log.write_all(&buffer[..count]).and_then(|_| log.flush());
+    }
+}
+
+/// Section of a session log that holds what was typed and printed
+const SESSION_TRANSCRIPT_SECTION: &str = "### transcript";
+
+/// Opens the log of a llama-cli session run in this terminal, if `logging_enabled = true`
+///
+/// The log is `<log directory>/inline_<mode id>_<timestamp>.txt` and starts
+/// with the mode snapshot (name, model path, parameters, prompt; see
+/// format_mode_snapshot), then SESSION_TRANSCRIPT_SECTION. Logging never stops
+/// a session: if the log cannot be created, a warning is printed and None returned.
+fn start_inline_session_log(mode: &ChatModeConfig) -> Option<(PathBuf, File)> {
+    if read_config_bool("logging_enabled") != Some(true) {
+        return None;
+    }
+    let opened = get_chat_log_dir().and_then(|log_dir| {
+        let path = log_dir.join(format!("inline_{}_{}.txt", mode.id, generate_timestamp_string()));
+        let fingerprint = model_fingerprint(Path::new(&mode.model_path))
+            .unwrap_or_else(|e| format!("unavailable ({})", e));
+        let prompt_text = fs::read_to_string(&mode.prompt_path).unwrap_or_default();
+        let mut file = File::create(&path)
+            .map_err(|e| format!("Failed to create session log {}: {}", path.display(), e))?;
+        write!(file, "{}\n{}\n", format_mode_snapshot(mode, &fingerprint, &prompt_text), SESSION_TRANSCRIPT_SECTION)
+            .map_err(|e| format!("Failed to write session log {}: {}", path.display(), e))?;
+        Ok((path, file))
+    });
+    match opened {
+        Ok(log) => Some(log),
+        Err(e) => {
+            println!("Warning: {} (the session is not logged)", e);
+            None
+        },
+    }
+}
+
+/// Directory under the remote home that holds the files of remote sessions
+const REMOTE_SESSION_BASE_DIR: &str = ".query_gguf_remote";
+
+/// Name of llama-cli's stderr log in a remote session directory
+const REMOTE_LOG_FILE_NAME: &str = "llama_cli.log";
+
+/// Quotes a string for a POSIX shell (the remote side of ssh)
+fn sh_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Builds the shell command that runs llama-cli in a remote session directory
+///
+/// stderr goes to REMOTE_LOG_FILE_NAME in the session directory, so it can
+/// be fetched back afterwards.
+///
+/// # Arguments
+/// * `llama_cli_path` - llama-cli on the remote machine
+/// * `mode` - Mode whose model path (on the remote machine) and parameters are used
+/// * `session_dir` - Remote session directory, relative to the remote home
+/// * `prompt_file_name` - Prompt file already copied into `session_dir`
+/// * `interactive` - Interactive session (otherwise runs once, like --print)
+fn remote_llama_command(
+    llama_cli_path: &str,
+    mode: &ChatModeConfig,
+    session_dir: &str,
+    prompt_file_name: &str,
+    interactive: bool,
+) -> String {
+    let mut args: Vec<String> = vec![
+        llama_cli_path.to_string(),
+        "-m".to_string(), mode.model_path.clone(),
+        "--file".to_string(), prompt_file_name.to_string(),
+        "--temp".to_string(), mode.parameters.temperature_value.to_string(),
+        "--top-k".to_string(), mode.parameters.top_k_sampling.to_string(),
+        "--top-p".to_string(), mode.parameters.top_p_sampling.to_string(),
+        "--ctx-size".to_string(), mode.parameters.context_size.to_string(),
+        "--threads".to_string(), mode.parameters.thread_count.to_string(),
+    ];
+    if mode.parameters.gpu_layers > 0 {
+        args.push("--n-gpu-layers".to_string());
+        args.push(mode.parameters.gpu_layers.to_string());
+    }
+    if !interactive {
+        args.push("-no-cnv".to_string());
+    } else {
+        if mode.parameters.interactive_first {
+            args.push("--interactive-first".to_string());
+        }
+        args.extend(llama_interaction_flags(&mode.parameters));
+    }
+    args.extend(llama_output_flags(&mode.parameters, interactive));
+
+    format!(
+        "cd {} && {} 2> {}",
+        sh_quote(session_dir),
+        args.iter().map(|arg| sh_quote(arg)).collect::<Vec<_>>().join(" "),
+        REMOTE_LOG_FILE_NAME
+    )
+}
+
+/// llama-cli flags for how much it prints: the prompt, prompt tokens, and logs
+///
+/// The prompt is only displayed in interactive sessions with display_prompt
+/// set; non-interactive output is the response alone.
+///
+/// # Arguments
+/// * `params` - The mode's parameters (display_prompt, verbose_prompt, log_disable)
+/// * `interactive` - Interactive session (otherwise runs once, like --print)
+fn llama_output_flags(params: &LlamaCppParameters, interactive: bool) -> Vec<String> {
+    let mut flags = Vec::new();
+    if !(interactive && params.display_prompt) {
+        flags.push("--no-display-prompt".to_string());
+    }
+    if params.verbose_prompt {
+        flags.push("--verbose-prompt".to_string());
+    }
+    if params.log_disable {
+        flags.push("--log-disable".to_string());
+    }
+    flags
+}
+
+/// llama-cli flags for typing into an interactive session
+///
+/// Only used for interactive sessions; non-interactive runs always pass -no-cnv.
+///
+/// # Arguments
+/// * `params` - The mode's parameters (multiline_input, in_prefix, in_suffix, conversation)
+fn llama_interaction_flags(params: &LlamaCppParameters) -> Vec<String> {
+    let mut flags = Vec::new();
+    match params.conversation {
+        Some(true) => flags.push("-cnv".to_string()),
+        Some(false) => flags.push("-no-cnv".to_string()),
+        None => (),
+    }
+    if params.multiline_input {
+        flags.push("--multiline-input".to_string());
+    }
+    if !params.in_prefix.is_empty() {
+        flags.push("--in-prefix".to_string());
+        flags.push(params.in_prefix.clone());
+    }
+    if !params.in_suffix.is_empty() {
+        flags.push("--in-suffix".to_string());
+        flags.push(params.in_suffix.clone());
+    }
+    flags
+}
+
+/// llama-cli flags of an interactive session after the model and prompt file: sampling,
+/// context, threads, GPU offload, interaction, and output flags
+fn llama_session_flags(params: &LlamaCppParameters) -> Vec<String> {
+    let mut flags: Vec<String> = vec![
+        "--temp".to_string(), params.temperature_value.to_string(),
+        "--top-k".to_string(), params.top_k_sampling.to_string(),
+        "--top-p".to_string(), params.top_p_sampling.to_string(),
+        "--ctx-size".to_string(), params.context_size.to_string(),
+        "--threads".to_string(), params.thread_count.to_string(),
+    ];
+    if params.gpu_layers > 0 {
+        flags.push("--n-gpu-layers".to_string());
+        flags.push(params.gpu_layers.to_string());
+    }
+    if params.interactive_first {
+        flags.push("--interactive-first".to_string());
+    }
+    flags.extend(llama_interaction_flags(params));
+    flags.extend(llama_output_flags(params, true));
+    flags
+}
+
+/// Quotes a program and its arguments into one command line, for terminal windows
+/// that run a command string
+///
+/// For POSIX shells, arguments with spaces, quotes, `$`, or other special
+/// characters are single-quoted, so the shell passes them on unchanged. For
+/// cmd.exe they are double-quoted, with inner quotes doubled.
+///
+/// # Arguments
+/// * `args` - Program followed by its arguments
+/// * `windows_cmd` - Quote for cmd.exe instead of sh/bash
+fn shell_command_line(args: &[OsString], windows_cmd: bool) -> String {
+    args.iter()
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@".contains(c)) {
+                arg.to_string()
+            } else if windows_cmd {
+                format!("\"{}\"", arg.replace('"', "\"\""))
+            } else {
+                sh_quote(&arg)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Runs ssh/scp, turning a failure into an error naming the step
+fn run_remote_step(program: &str, args: &[&str], step: &str) -> Result<(), String> {
+    let status = Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .status()
+        .map_err(|e| format!("Failed to run {} (is OpenSSH installed?): {}", program, e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} failed ({} exited with status {})", step, program, status.code().unwrap_or(-1)))
+    }
+}
+
+/// A remote mode's session: its files copied to a temp directory on the host
+struct RemoteSession {
+    host: String,
+    dir: String,               // relative to the remote home
+    prompt_file_name: String,
+}
+
+impl RemoteSession {
+    /// Creates the remote session directory and copies the mode's prompt into it
+    ///
+    /// The prompt is the rendered one, so directory dumps, piped context,
+    /// questions, and attachments that were put into it all come along.
+    fn stage(mode: &ChatModeConfig) -> Result<RemoteSession, String> {
+        ensure_online("Remote modes")?;
+        let dir = format!("{}/{}_{}", REMOTE_SESSION_BASE_DIR, mode.id, generate_timestamp_string());
+        let prompt_file_name = Path::new(&mode.prompt_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "prompt.txt".to_string());
+
+        println!("Copying prompt to {}:{}", mode.remote, dir);
+        run_remote_step("ssh", &["--", &mode.remote, &format!("mkdir -p {}", sh_quote(&dir))],
+            "Creating the remote session directory")?;
+        let remote_prompt = format!("{}:{}", mode.remote, sh_quote(&format!("{}/{}", dir, prompt_file_name)));
+        run_remote_step("scp", &["-q", "--", &mode.prompt_path, &remote_prompt], "Copying the prompt")?;
+
+        Ok(RemoteSession { host: mode.remote.clone(), dir, prompt_file_name })
+    }
+
+    /// Fetches llama-cli's log into the local log directory, then removes the remote session directory
+    ///
+    /// # Returns
+    /// - Ok(PathBuf): Where the log was saved
+    /// - Err(String): The log could not be fetched (the remote directory is kept then)
+    fn finish(&self, mode_id: &str) -> Result<PathBuf, String> {
+        let local_log = get_chat_log_dir()?
+            .join(format!("remote_{}_{}.log", mode_id, generate_timestamp_string()));
+        run_remote_step("scp", &["-q", "--",
+            &format!("{}:{}", self.host, sh_quote(&format!("{}/{}", self.dir, REMOTE_LOG_FILE_NAME))),
+            &local_log.to_string_lossy()], "Fetching the remote log")?;
+        run_remote_step("ssh", &["--", &self.host, &format!("rm -rf {}", sh_quote(&self.dir))],
+            "Removing the remote session directory")?;
+        Ok(local_log)
+    }
+}
+
+/// Path of llama-cli on remote hosts (`remote_llama_cli_path`, default: llama-cli from PATH)
+fn get_remote_llama_cli_path() -> String {
+    let configured = read_field_from_toml("remote_llama_cli_path");
+    if configured.is_empty() { "llama-cli".to_string() } else { configured }
+}
+
+/// Runs an interactive session of a remote mode in this terminal (ssh -t)
+///
+/// ssh already provides the terminal, so no new window is opened; this
+/// also lets the log be fetched back once the session ends.
+fn launch_remote_llama(mode: &ChatModeConfig) -> Result<(), String> {
+    let session = RemoteSession::stage(mode)?;
+    let command = remote_llama_command(&get_remote_llama_cli_path(), mode, &session.dir, &session.prompt_file_name, true);
+    println!("\nRunning llama-cli on {}...", mode.remote);
+    let status = Command::new("ssh")
+        .args(["-t", "--", &mode.remote, &command])
+        .status()
+        .map_err(|e| format!("Failed to run ssh: {}", e));
+
+    match session.finish(&mode.id) {
+        Ok(log_path) => println!("Remote log saved to {}", log_path.display()),
+        Err(e) => println!("Warning: {}", e),
+    }
+    match status? {
+        status if status.success() => Ok(()),
+        status => Err(format!("Remote llama-cli exited with status {}", status.code().unwrap_or(-1))),
+    }
+}
+
+/// Runs a remote mode once and returns the generated text (the remote side of --print)
+fn run_remote_non_interactive(mode: &ChatModeConfig) -> Result<String, String> {
+    let session = RemoteSession::stage(mode)?;
+    let command = remote_llama_command(&get_remote_llama_cli_path(), mode, &session.dir, &session.prompt_file_name, false);
+    let output = Command::new("ssh")
+        .args(["--", &mode.remote, &command])
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|e| format!("Failed to run ssh: {}", e))?;
+
+    let log_path = session.finish(&mode.id);
+    if !output.status.success() {
+        let stderr = log_path.ok()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .unwrap_or_else(|| String::from_utf8_lossy(&output.stderr).to_string());
+        return Err(format!(
+            "Remote llama-cli on {} exited with status {}.\n{}",
+            mode.remote,
+            output.status.code().unwrap_or(-1),
+            describe_llama_failure(&stderr)
+        ));
+    }
+    if let Err(e) = log_path {
+        eprintln!("Warning: {}", e);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// llama.cpp image used by `launch_backend = "docker"` (its entrypoint is llama-cli)
+const DEFAULT_DOCKER_IMAGE: &str = "ghcr.io/ggml-org/llama.cpp:light";
+
+/// CUDA variant of DEFAULT_DOCKER_IMAGE, used when docker_gpus is set
+const DEFAULT_DOCKER_CUDA_IMAGE: &str = "ghcr.io/ggml-org/llama.cpp:light-cuda";
+
+/// Returns true when llama-cli runs in the llama.cpp container (`launch_backend = "docker"`)
+fn uses_docker_backend() -> bool {
+    read_field_from_toml("launch_backend") == "docker"
+}
+
+/// Name of the container a mode runs in, so it can be stopped and its logs read
+fn docker_container_name(mode_id: &str) -> String {
+    format!("query_gguf-{}", mode_id)
+}
+
+/// Builds the `docker run` arguments that start llama-cli for a mode
+///
+/// The model's and prompt's directories are mounted read-only at /models
+/// and /prompts, and the file arguments point there. The llama-cli
+/// parameters follow the returned arguments, exactly as for a native run.
+///
+/// # Arguments
+/// * `mode` - Mode to run
+/// * `image` - llama.cpp image (entrypoint llama-cli)
+/// * `gpus` - Value for `docker run --gpus` (e.g. "all"), empty for CPU only
+/// * `interactive` - Attach a terminal (`-it`) for a chat session
+/// * `offline` - Never pull the image (`--pull=never`); it must be there already
+fn docker_run_args(mode: &ChatModeConfig, image: &str, gpus: &str, interactive: bool, offline: bool) -> Result<Vec<String>, String> {
+    let split = |path: &str| -> Result<(String, String), String> {
+        let path = Path::new(path);
+        let file_name = path.file_name()
+            .ok_or_else(|| format!("Not a file path: {}", path.display()))?
+            .to_string_lossy()
+            .to_string();
+        let directory = path.parent()
+            .and_then(|parent| fs::canonicalize(parent).ok().or_else(|| Some(parent.to_path_buf())))
+            .unwrap_or_default();
+        Ok((directory.display().to_string(), file_name))
+    };
+    let (model_dir, model_file) = split(&mode.model_path)?;
+    let (prompt_dir, prompt_file) = split(&mode.prompt_path)?;
+
+    let mut args: Vec<String> = vec!["run".to_string()];
+    if offline {
+        args.push("--pull=never".to_string());
+    }
+    if interactive {
+        args.push("-it".to_string());
+    }
+    args.extend([
+        "--name".to_string(), docker_container_name(&mode.id),
+        "-v".to_string(), format!("{}:/models:ro", model_dir),
+        "-v".to_string(), format!("{}:/prompts:ro", prompt_dir),
+    ]);
+    if !gpus.is_empty() {
+        args.extend(["--gpus".to_string(), gpus.to_string()]);
+    }
+    args.extend([
+        image.to_string(),
+        "-m".to_string(), format!("/models/{}", model_file),
+        "--file".to_string(), format!("/prompts/{}", prompt_file),
+    ]);
+    Ok(args)
+}
+
+/// Builds `docker run` arguments for a mode from the docker_image and docker_gpus config keys
+fn docker_run_args_from_config(mode: &ChatModeConfig, interactive: bool) -> Result<Vec<String>, String> {
+    let gpus = read_field_from_toml("docker_gpus");
+    let mut image = read_field_from_toml("docker_image");
+    if image.is_empty() {
+        image = if gpus.is_empty() { DEFAULT_DOCKER_IMAGE } else { DEFAULT_DOCKER_CUDA_IMAGE }.to_string();
+    }
+    docker_run_args(mode, &image, &gpus, interactive, is_offline())
+}
+
+/// Gets a mode's container ready to be started again
+///
+/// The container is kept after llama-cli exits so `query_gguf docker logs`
+/// still works; it is removed here, before the next run reuses its name.
+///
+/// # Returns
+/// - Ok(()): No container with the mode's name is left
+/// - Err(String): docker is not installed, or the mode is still running in its container
+fn prepare_docker_container(mode_id: &str) -> Result<(), String> {
+    let name = docker_container_name(mode_id);
+    let output = Command::new("docker")
+        .args(["ps", "-a", "--filter", &format!("name=^{}$", name), "--format", "{{.State}}"])
+        .output()
+        .map_err(|e| format!("Failed to run docker (is it installed?): {}", e))?;
+    let state = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if state == "running" {
+        return Err(format!("Mode '{}' is already running in container {} (stop it with: query_gguf docker stop {})", mode_id, name, mode_id));
+    }
+    if !state.is_empty() {
+        let _ = Command::new("docker").args(["rm", &name]).output();
+    }
+    Ok(())
+}
+
+/// Manages the containers of `launch_backend = "docker"`: `query_gguf docker ...`
+///
+/// - `docker list` shows query_gguf's containers
+/// - `docker stop <mode>` stops a mode's container
+/// - `docker logs <mode>` shows a mode's llama-cli output (also after it exited)
+/// - `docker pull` updates the configured llama.cpp image
+fn handle_docker_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: docker list | docker stop <mode> | docker logs <mode> | docker pull";
+    let docker = |docker_args: &[&str]| -> Result<(), String> {
+        let status = Command::new("docker")
+            .args(docker_args)
+            .status()
+            .map_err(|e| format!("Failed to run docker (is it installed?): {}", e))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("docker {} failed", docker_args.join(" ")))
+        }
+    };
+    let mode_container = |selector: Option<&String>| -> Result<String, String> {
+        let selector = selector.ok_or_else(|| usage.to_string())?;
+        let modes = read_saved_modes()?;
+        let mode_id = find_mode_by_selector(&modes, selector)
+            .map(|mode| mode.id.clone())
+            .unwrap_or_else(|| selector.clone());
+        Ok(docker_container_name(&mode_id))
+    };
+
+    match args.first().map(|arg| arg.as_str()) {
+        Some("list") => docker(&["ps", "-a", "--filter", "name=^query_gguf-",
+            "--format", "table {{.Names}}\t{{.Status}}\t{{.Image}}"]),
+        Some("stop") => docker(&["stop", &mode_container(args.get(1))?]),
+        Some("logs") => docker(&["logs", &mode_container(args.get(1))?]),
+        Some("pull") => {
+            let image = match read_field_from_toml("docker_image") {
+                image if !image.is_empty() => image,
+                _ if !read_field_from_toml("docker_gpus").is_empty() => DEFAULT_DOCKER_CUDA_IMAGE.to_string(),
+                _ => DEFAULT_DOCKER_IMAGE.to_string(),
+            };
+            ensure_online("docker pull")?;
+            docker(&["pull", &image])
+        },
+        _ => Err(usage.to_string()),
+    }
+}
+
+/// Makes a mode's model and prompt paths absolute before launch
+///
+/// A terminal window may start in another directory than query_gguf, so
+/// relative paths are resolved against the current one. Empty paths are
+/// left for llama-cli to report.
+fn resolve_launch_paths(mode: &mut ChatModeConfig) {
+    for path in [&mut mode.model_path, &mut mode.prompt_path] {
+        if let Ok(absolute) = std::path::absolute(&*path) {
+            *path = absolute.to_string_lossy().to_string();
+        }
+    }
+}
+
+/// Full path of a program given by name only (e.g. `llama-cli`), looked up on PATH
+///
+/// Paths, and names not found on PATH, are returned unchanged.
+fn resolve_program_path(program: &str) -> String {
+    if Path::new(program).components().count() != 1 {
+        return std::path::absolute(program)
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_else(|_| program.to_string());
+    }
+    find_program_on_path(program)
+        .or_else(|| find_program_on_path(&format!("{}{}", program, std::env::consts::EXE_SUFFIX)))
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_else(|| program.to_string())
+}
+
+/// The llama-cli command of an interactive session, as run by `launch_llama`
+///
+/// Also printed by `--dry-run` and `show`, so what is shown is what runs.
+///
+/// # Returns
+/// - Ok((ChatModeConfig, Vec<OsString>, bool)): The mode adjusted for the
+///   binary and platform (absolute or WSL paths), the program and its
+///   arguments, and whether llama-cli runs inside WSL
+/// - Err(String): Unknown backend, or no llama-cli configured
+fn build_llama_session(mode: &ChatModeConfig) -> Result<(ChatModeConfig, Vec<OsString>, bool), String> {
+    let use_docker = uses_docker_backend();
+    let (llama_cli_path, mut mode) = if use_docker {
+        ("docker".to_string(), mode.clone())
+    } else {
+        select_llama_binary(mode)?
+    };
+    resolve_launch_paths(&mut mode);
+
+    let run_in_wsl = !use_docker && cfg!(target_os = "windows") && read_config_bool("windows_run_in_wsl") == Some(true);
+    if run_in_wsl {
+        for path in [&mut mode.model_path, &mut mode.prompt_path] {
+            if let Some(translated) = windows_path_to_wsl(path) {
+                *path = translated;
+            }
+        }
+    }
+
+    // The program and its arguments, passed as is (no shell) when running in this terminal
+    let mut llama_args: Vec<OsString> = if use_docker {
+        let mut args = vec![OsString::from("docker")];
+        args.extend(docker_run_args_from_config(&mode, true)?.into_iter().map(OsString::from));
+        args
+    } else {
+        vec![
+            OsString::from(resolve_program_path(&llama_cli_path)),
+            OsString::from("-m"), OsString::from(&mode.model_path),
+            OsString::from("--file"), OsString::from(&mode.prompt_path),
+        ]
+    };
+    llama_args.extend(llama_session_flags(&mode.parameters).into_iter().map(OsString::from));
+    Ok((mode, llama_args, run_in_wsl))
+}
+
+/// The llama-cli command of a single non-interactive run (`--print`, `ask`)
+///
+/// # Returns
+/// - Ok((ChatModeConfig, String, Vec<String>)): The mode adjusted for the binary, the program, and its arguments
+/// - Err(String): Unknown backend, or no llama-cli configured
+fn build_llama_print_command(mode: &ChatModeConfig) -> Result<(ChatModeConfig, String, Vec<String>), String> {
+    let use_docker = uses_docker_backend();
+    let (llama_cli_path, mut mode) = if use_docker {
+        ("docker".to_string(), mode.clone())
+    } else {
+        select_llama_binary(mode)?
+    };
+    resolve_launch_paths(&mut mode);
+
+    let mut command_args: Vec<String> = if use_docker {
+        docker_run_args_from_config(&mode, false)?
+    } else {
+        vec![
+            "-m".to_string(), mode.model_path.clone(),
+            "--file".to_string(), mode.prompt_path.clone(),
+        ]
+    };
+    command_args.extend([
+        "--temp".to_string(), mode.parameters.temperature_value.to_string(),
+        "--top-k".to_string(), mode.parameters.top_k_sampling.to_string(),
+        "--top-p".to_string(), mode.parameters.top_p_sampling.to_string(),
+        "--ctx-size".to_string(), mode.parameters.context_size.to_string(),
+        "--threads".to_string(), mode.parameters.thread_count.to_string(),
+    ]);
+    if mode.parameters.gpu_layers > 0 {
+        command_args.push("--n-gpu-layers".to_string());
+        command_args.push(mode.parameters.gpu_layers.to_string());
+    }
+    command_args.push("-no-cnv".to_string());
+    command_args.extend(llama_output_flags(&mode.parameters, false));
+    let program = if use_docker { llama_cli_path } else { resolve_program_path(&llama_cli_path) };
+    Ok((mode, program, command_args))
+}
+
+/// Prints the command a launch of `mode` would run, without running it
+///
+/// Used by `--dry-run` (the command alone, to copy into scripts) and
+/// `show`. Remote modes print the ssh command; the prompt is copied to a
+/// new session directory on the host first, shown as `<session>`.
+///
+/// # Arguments
+/// * `mode` - Mode to launch
+/// * `print` - Show the non-interactive command of `--print` instead of a chat session
+fn print_launch_command(mode: &ChatModeConfig, print: bool) -> Result<(), String> {
+    if mode.kind == "image" {
+        return Err(format!("Mode '{}' is an image mode (kind=image) and has no llama-cli command", mode.id));
+    }
+    if !mode.endpoint.is_empty() {
+        return Err(format!(
+            "Mode '{}' talks to {} and has no llama-cli command; use: query_gguf chat {}",
+            mode.id, mode.endpoint, mode.id
+        ));
+    }
+    if !mode.remote.is_empty() {
+        let prompt_file_name = Path::new(&mode.prompt_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "prompt.txt".to_string());
+        let session_dir = format!("{}/{}_<session>", REMOTE_SESSION_BASE_DIR, mode.id);
+        let command = remote_llama_command(&get_remote_llama_cli_path(), mode, &session_dir, &prompt_file_name, !print);
+        println!("ssh {}{} {}", if print { "" } else { "-t " }, mode.remote, sh_quote(&command));
+        return Ok(());
+    }
+
+    let command_line = if print {
+        let (_, program, args) = build_llama_print_command(mode)?;
+        let mut command = vec![OsString::from(program)];
+        command.extend(args.into_iter().map(OsString::from));
+        shell_command_line(&command, cfg!(target_os = "windows"))
+    } else {
+        let (_, args, run_in_wsl) = build_llama_session(mode)?;
+        shell_command_line(&args, cfg!(target_os = "windows") && !run_in_wsl)
+    };
+    println!("{}", command_line);
+    Ok(())
+}
+
+/// Shows how a mode launches: `query_gguf show <mode> [--print]`
+///
+/// Prints the mode's resolved files (marking missing ones) and the
+/// llama-cli command a launch runs, as `--dry-run` does.
+fn handle_show_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: show <mode number or id> [--print]";
+    let selector = args.first().filter(|arg| !arg.starts_with('-')).ok_or(usage)?;
+    let print = match &args[1..] {
+        [] => false,
+        [flag] if flag == "--print" => true,
+        _ => return Err(usage.to_string()),
+    };
+    let modes = read_saved_modes()?;
+    let mode = find_mode_by_selector(&modes, selector)
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?;
+
+    println!("Mode:    {} [{}]", mode.name, mode.id);
+    if !mode.extends.is_empty() {
+        println!("Extends: {}", mode.extends);
+    }
+    let mut resolved = mode.clone();
+    resolve_launch_paths(&mut resolved);
+    for (label, path) in [("Model:  ", &resolved.model_path), ("Prompt: ", &resolved.prompt_path)] {
+        // Files of remote modes are on the host (the prompt is copied there)
+        let missing = mode.remote.is_empty() && mode.endpoint.is_empty() && !Path::new(path).is_file();
+        println!("{} {}{}", label, path, if missing { "  (not found)" } else { "" });
+    }
+    match (mode.endpoint.as_str(), mode.remote.as_str()) {
+        ("", "") => println!("Backend: {}", if mode.backend.is_empty() { "default" } else { &mode.backend }),
+        ("", remote) => println!("Host:    {}", remote),
+        (endpoint, _) => println!("Endpoint: {}", endpoint),
+    }
+    println!("Command:");
+    print_launch_command(mode, print)
+}
+
+/// old version with new terminal
+/// TODO add docstring
+///
+/// Modes with remote=user@host run over ssh in this terminal instead
+/// (see `launch_remote_llama`).
+///
+/// With `launch_backend = "docker"`, llama-cli runs in the llama.cpp
+/// container image instead of a local build (see `docker_run_args`).
+///
+/// Without a desktop (containers, SSH sessions, `TERM=dumb`), inside tmux or
+/// screen, or with `inline` (--inline), the session runs in the current
+/// terminal instead: llama-cli is a child process using this terminal's
+/// stdin/stdout. When stdin/stdout are not a terminal at all the prompt is
+/// answered once and printed, like `--print`. With `logging_enabled = true`,
+/// sessions in this terminal (and piped answers) are also written to a log
+/// in the log directory (see start_inline_session_log).
+///
+/// In WSL with `wsl_terminal = "wt"`, the session opens in Windows Terminal.
+/// On Windows with `windows_run_in_wsl = true`, llama-cli runs inside WSL
+/// (Windows model and prompt paths are translated to /mnt/...).
+fn launch_llama(mode: &ChatModeConfig, inline: bool) -> Result<(), String> {
+    use std::io::IsTerminal;
+    let terminal_launch = choose_terminal_launch(
+        is_in_container(),
+        has_graphical_display(),
+        &std::env::var("TERM").unwrap_or_default(),
+        io::stdin().is_terminal() && io::stdout().is_terminal(),
+        inline || is_multiplexer_or_ssh_session(),
+    );
+    if terminal_launch == TerminalLaunch::Piped {
+        let response = run_llama_non_interactive(mode)?;
+        println!("{}", response.trim_end());
+        // The input is the prompt file, already in the log's header
+        if let Some((path, mut log)) = start_inline_session_log(mode) {
+            if let Err(e) = writeln!(log, "{}", response.trim_end()) {
+                println!("Warning: Failed to write session log {}: {}", path.display(), e);
+            }
+        }
+        return Ok(());
+    }
+    if !mode.remote.is_empty() {
+        return launch_remote_llama(mode);
+    }
+    if !mode.endpoint.is_empty() {
+        return Err(format!(
+            "Mode '{}' talks to {} and has no llama-cli session; use: query_gguf chat {}",
+            mode.id, mode.endpoint, mode.id
+        ));
+    }
+    check_model_file(mode)?;
+
+    check_duplicate_launch(mode, false)?;
+    if let Some(warning) = check_model_quantization_speed(mode) {
+        println!("Warning: {}", warning);
+    }
+    if uses_docker_backend() {
+        prepare_docker_container(&mode.id)?;
+    }
+    let (mode, llama_args, run_in_wsl) = build_llama_session(mode)?;
+    let mode = &mode;
+
+    // Terminal windows take one command string: quoted here, for the shell they run
+    let llama_command = shell_command_line(&llama_args, cfg!(target_os = "windows") && !run_in_wsl);
+
+    if terminal_launch == TerminalLaunch::CurrentTerminal {
+        if inline {
+            println!("\nRunning llama-cli in this terminal (--inline)...");
+        } else {
+            println!("\nNo terminal window available, running llama-cli in this terminal...");
+        }
+        println!("Command: {}", llama_command);
+        return run_in_current_terminal(&llama_args, open_session_log_for_terminal(mode));
+    }
+
+    println!("\nPreparing to launch LLaMA.cpp gguf llama-cli in a new terminal...");
+    println!("Command: {}", llama_command);
+
+    // On bash terminals, keep a copy of stderr so a failed run is explained
+    // by `query_gguf diagnose` before the window can be closed
+    let bash_command = match (get_temp_dir(), std::env::current_exe()) {
+        (Ok(temp_dir), Ok(exe_path)) => {
+            let stderr_path = sh_quote(&temp_dir.join("llama_cli_stderr.log").to_string_lossy());
+            format!(
+                "{} 2> >(tee {} >&2) || (sleep 1; {} diagnose {})",
+                llama_command, stderr_path, sh_quote(&exe_path.to_string_lossy()), stderr_path
+            )
+        },
+        _ => llama_command.clone(),
+    };
+
+    // Launch in new terminal based on OS
+    let launch_result = if run_in_wsl {
+        Command::new("cmd")
+            .args(["/C", "start", "wsl.exe", "-e", "bash", "-c",
+                &format!("{};read -p 'Press Enter to close...'", llama_command)])
+            .status()
+            .map_err(|e| format!("Failed to launch WSL from Windows: {}", e))
+    } else if is_wsl() && read_field_from_toml("wsl_terminal") == "wt" {
+        // Windows Terminal splits its own commands on ';', so those are escaped
+        let session = format!("{};read -p 'Press Enter to close...'", bash_command);
+        let mut wt_args: Vec<String> = vec!["wsl.exe".to_string()];
+        if let Ok(distro) = std::env::var("WSL_DISTRO_NAME") {
+            wt_args.extend(["-d".to_string(), distro]);
+        }
+        wt_args.extend(["-e".to_string(), "bash".to_string(), "-c".to_string(), session.replace(';', "\\;")]);
+        Command::new("wt.exe")
+            .args(&wt_args)
+            .status()
+            .map_err(|e| format!("Failed to launch Windows Terminal (wt.exe): {}", e))
+    } else if cfg!(target_os = "windows") {
+        Command::new("cmd")
+            .args(["/C", "start", "cmd", "/K", &llama_command])
+            .status()
+            .map_err(|e| format!("Failed to launch Windows terminal: {}", e))
+    } else if (cfg!(target_os = "linux") || is_bsd()) && !read_field_from_toml("terminal_command").is_empty() {
+        let terminal_command = read_field_from_toml("terminal_command");
+        let args = terminal_command_args(&terminal_command, &format!("{};read -p 'Press Enter to close...'", bash_command));
+        match Command::new(&args[0]).args(&args[1..]).status() {
+            Ok(status) => Ok(status),
+            Err(e) => {
+                println!("Warning: Failed to launch terminal_command '{}': {}, running llama-cli in this terminal", args[0], e);
+                return run_in_current_terminal(&llama_args, open_session_log_for_terminal(mode));
+            }
+        }
+    } else if cfg!(target_os = "linux") || is_bsd() {
+        // No terminal_command configured (older configs): try the common terminal emulators (X11 terminals are the same on Linux and the BSDs)
+        let terminals = ["xterm", "gnome-terminal", "konsole", "xfce4-terminal"];
+        let mut last_error = String::from("No terminal emulator found");
+
+        for terminal in terminals.iter() {
+            // The session is passed to bash as one argument, so its quoting is left intact
+            let execute_flag = match *terminal {
+                "gnome-terminal" => "--",
+                "xfce4-terminal" => "-x",
+                _ => "-e",
+            };
+            let result = Command::new(terminal)
+                .args([execute_flag, "bash", "-c", &format!("{};read -p 'Press Enter to close...'", bash_command)])
+                .status();
+
+            match result {
+                Ok(_) => return Ok(()),
+                Err(e) => last_error = format!("Failed to launch {}: {}", terminal, e),
+            }
+        }
+
+        // None of the terminal emulators is installed: fall back to this terminal
+        println!("Warning: {}, running llama-cli in this terminal", last_error);
+        return run_in_current_terminal(&llama_args, open_session_log_for_terminal(mode));
+    } else if cfg!(target_os = "macos") {
+        Command::new("osascript")
+            .args(["-e", &format!(
+                "tell application \"Terminal\" to do script \"{}\"",
+                llama_command.replace('\\', "\\\\").replace('"', "\\\"")
+            )])
+            .status()
+            .map_err(|e| format!("Failed to launch macOS terminal: {}", e))
+    } else {
+        Err(String::from("Unsupported operating system"))
+    };
+
+    match launch_result {
+        Ok(_) => {
+            println!("LLaMA launched in new terminal window");
+            Ok(())
+        },
+        Err(e) => Err(format!("Failed to launch LLaMA: {}", e))
+    }
+}
+
+/// Runs llama-cli without a terminal or chat, capturing the generated text
+///
+/// Used for `--print` launches: the prompt (with any question/context already
+/// rendered into it) is run once in conversation-less mode and the output
+/// is returned instead of opening an interactive session. If the mode was
+/// preloaded (`query_gguf preload`), its running server answers instead.
+///
+/// # Arguments
+/// * `mode` - Mode whose model, prompt, and parameters are used
+///
+/// # Returns
+/// - Ok(String): Generated text (stdout of llama-cli)
+/// - Err(String): llama-cli not configured, failed to start, or exited with an error
+fn run_llama_non_interactive(mode: &ChatModeConfig) -> Result<String, String> {
+    run_llama_with_limits(mode, &GenerationLimits::default())
+}
+
+/// Cutoffs for a non-interactive generation (`--max-seconds`, `--max-bytes`)
+#[derive(Debug, Clone, Copy, Default)]
+struct GenerationLimits {
+    max_seconds: Option<u64>,  // stop llama-cli after this long
+    max_bytes: Option<usize>,  // stop once this much text has been generated
+}
+
+/// Start of the line added to output that was cut off by a GenerationLimits cutoff
+const TRUNCATED_OUTPUT_MARKER: &str = "[output truncated:";
+
+/// Cuts text to at most `max_bytes` bytes, on a character boundary
+fn truncate_to_bytes(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Marks output as cut off, on its own line, and says so on stderr
+fn mark_truncated(output: &str, reason: &str) -> String {
+    eprintln!("Warning: generation stopped, {}", reason);
+    format!("{}\n{} {}]", output.trim_end(), TRUNCATED_OUTPUT_MARKER, reason)
+}
+
+/// Applies the --max-bytes cutoff to a finished response (servers, remote hosts)
+fn apply_byte_limit(output: String, limits: &GenerationLimits) -> String {
+    match limits.max_bytes {
+        Some(max_bytes) if output.len() > max_bytes => {
+            mark_truncated(truncate_to_bytes(&output, max_bytes), &format!("--max-bytes {} reached", max_bytes))
+        },
+        _ => output,
+    }
+}
+
+/// Runs a mode once like `run_llama_non_interactive`, stopping at the given cutoffs
+///
+/// llama-cli is stopped (kill) as soon as a cutoff is hit and the text so far
+/// is returned, ending with a TRUNCATED_OUTPUT_MARKER line. Preloaded
+/// servers, endpoints, and remote hosts answer in one piece, so only
+/// --max-bytes applies to them.
+fn run_llama_with_limits(mode: &ChatModeConfig, limits: &GenerationLimits) -> Result<String, String> {
+    // A preloaded server for this mode answers without loading the model again
+    if let Some(server) = LlamaServer::attach_preloaded(mode) {
+        return run_on_preloaded_server(&server, mode).map(|output| apply_byte_limit(output, limits));
+    }
+    if !mode.remote.is_empty() {
+        return run_remote_non_interactive(mode).map(|output| apply_byte_limit(output, limits));
+    }
+    if !mode.endpoint.is_empty() {
+        return run_on_preloaded_server(&LlamaServer::connect(mode), mode).map(|output| apply_byte_limit(output, limits));
+    }
+    check_model_file(mode)?;
+
+    if let Some(warning) = check_model_quantization_speed(mode) {
+        eprintln!("Warning: {}", warning);
+    }
+
+    // Not interactive, so only warn about loading the model a second time
+    if let Some(running) = find_running_models(&mode.model_path).first() {
+        eprintln!("Warning: {} is already loaded by {} (pid {})", mode.model_path, running.program, running.pid);
+    }
+    let use_docker = uses_docker_backend();
+    if use_docker {
+        prepare_docker_container(&mode.id)?;
+    }
+    let (mode, llama_cli_path, command_args) = build_llama_print_command(mode)?;
+    let mode = &mode;
+
+    let started = std::time::Instant::now();
+    let mut child = Command::new(&llama_cli_path)
+        .args(&command_args)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run llama-cli at {}: {}", llama_cli_path, e))?;
+
+    // --max-seconds: a watchdog stops llama-cli unless it finished in time
+    let finished = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let timed_out = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    if let Some(max_seconds) = limits.max_seconds {
+        let (finished, timed_out) = (finished.clone(), timed_out.clone());
+        let pid = child.id();
+        std::thread::spawn(move || {
+            let deadline = started + std::time::Duration::from_secs(max_seconds);
+            while std::time::Instant::now() < deadline {
+                if finished.load(std::sync::atomic::Ordering::SeqCst) {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            if !finished.load(std::sync::atomic::Ordering::SeqCst) {
+                timed_out.store(true, std::sync::atomic::Ordering::SeqCst);
+                let _ = stop_process(pid);
+            }
+        });
+    }
+
+    // Read stderr on its own thread so a full pipe can never block llama-cli
+    let mut child_stderr = child.stderr.take()
+        .ok_or_else(|| "Failed to capture llama-cli stderr".to_string())?;
+    let stderr_reader = std::thread::spawn(move || {
+        let mut stderr = Vec::new();
+        let _ = io::Read::read_to_end(&mut child_stderr, &mut stderr);
+        String::from_utf8_lossy(&stderr).to_string()
+    });
+
+    // Read stdout as it arrives, noting when the first generated text shows up
+    let mut child_stdout = child.stdout.take()
+        .ok_or_else(|| "Failed to capture llama-cli stdout".to_string())?;
+    let mut stdout = Vec::new();
+    let mut first_output_at = None;
+    let mut buffer = [0u8; 4096];
+    let mut truncated_reason = None;
+    loop {
+        let read = io::Read::read(&mut child_stdout, &mut buffer)
+            .map_err(|e| format!("Failed to read llama-cli output: {}", e))?;
+        if read == 0 {
+            break;
+        }
+        if first_output_at.is_none() {
+            first_output_at = Some(started.elapsed());
+        }
+        stdout.extend_from_slice(&buffer[..read]);
+        if let Some(max_bytes) = limits.max_bytes.filter(|max_bytes| stdout.len() >= *max_bytes) {
+            truncated_reason = Some(format!("--max-bytes {} reached", max_bytes));
+            let _ = child.kill();
+            break;
+        }
+    }
+
+    let status = child.wait()
+        .map_err(|e| format!("Error waiting for llama-cli: {}", e))?;
+    finished.store(true, std::sync::atomic::Ordering::SeqCst);
+    let stderr = stderr_reader.join().unwrap_or_default();
+    if timed_out.load(std::sync::atomic::Ordering::SeqCst) && truncated_reason.is_none() {
+        truncated_reason = Some(format!("--max-seconds {} reached", limits.max_seconds.unwrap_or(0)));
+    }
+
+    if let Some(reason) = truncated_reason {
+        if use_docker {
+            // Stopping the docker client leaves the container running
+            let _ = Command::new("docker").args(["stop", &docker_container_name(&mode.id)]).output();
+        }
+        let output = String::from_utf8_lossy(&stdout).to_string();
+        let output = truncate_to_bytes(&output, limits.max_bytes.unwrap_or(usize::MAX));
+        return Ok(mark_truncated(output, &reason));
+    }
+
+    if !status.success() {
+        // A GPU build that starts but fails to load the model gets one retry on the CPU build
+        if !use_docker && !mode.backend.is_empty() && mode.backend != "cpu" {
+            println!("Warning: '{}' build failed, retrying with the CPU build", mode.backend);
+            let mut cpu_mode = mode.clone();
+            cpu_mode.backend = "cpu".to_string();
+            cpu_mode.parameters.gpu_layers = 0;
+            return run_llama_with_limits(&cpu_mode, limits);
+        }
+        return Err(format!(
+            "llama-cli exited with status {}.\n{}",
+            status.code().unwrap_or(-1),
+            describe_llama_failure(&stderr)
+        ));
+    }
+
+    // The first output is the first generated token; it arrives after the model
+    // has loaded, so subtract the reported load time to get first token latency
+    let load_ms = parse_llama_perf_ms(&stderr, "load time").unwrap_or(0.0);
+    let first_output_ms = first_output_at.map(|at| at.as_secs_f64() * 1000.0).unwrap_or(0.0);
+    let timing = SessionTiming {
+        kind: "print".to_string(),
+        mode_id: mode.id.clone(),
+        model_path: mode.model_path.clone(),
+        load_ms,
+        first_token_ms: (first_output_ms - load_ms).max(0.0),
+        tokens_per_second: parse_llama_tokens_per_second(&stderr).unwrap_or(0.0),
+        tokens_generated: parse_llama_eval_tokens(&stderr).unwrap_or(0),
+    };
+    display_session_timing(&timing);
+    if let Err(e) = record_session_timing(&timing) {
+        println!("Warning: {}", e);
+    }
+
+    Ok(String::from_utf8_lossy(&stdout).to_string())
+}
+
+/// Timing measurements of one supervised session (a --print run or a tune session)
+#[derive(Debug, Clone)]
+struct SessionTiming {
+    kind: String,            // "print", "tune", or "chat"
+    mode_id: String,
+    model_path: String,
+    load_ms: f64,            // model load time
+    first_token_ms: f64,     // time from start of generation to the first token, 0 if unknown
+    tokens_per_second: f64,  // generation speed, 0 if unknown
+    tokens_generated: u64,   // tokens generated in the session
+}
+
+/// Gets the absolute path to the session stats file
+///
+/// Location: ~/query_gguf/session_stats.tsv
+///
+/// One tab separated line per session:
+/// timestamp, kind, mode id, model path, load ms, first token ms, tokens per second, tokens generated
+/// (lines written before tokens generated was recorded have 7 fields)
+fn get_session_stats_path() -> Result<PathBuf, String> {
+    Ok(get_app_base_dir()?.join("session_stats.tsv"))
+}
+
+/// Appends a session's timings to the stats file
+fn record_session_timing(timing: &SessionTiming) -> Result<(), String> {
+    let stats_path = get_session_stats_path()?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&stats_path)
+        .map_err(|e| format!("Failed to open stats file {}: {}", stats_path.display(), e))?;
+    // One write per line, so lines of parallel sessions do not interleave
+    let line = format!(
+        "{}\t{}\t{}\t{}\t{:.0}\t{:.0}\t{:.2}\t{}\n",
+        generate_timestamp_string(),
+        timing.kind,
+        timing.mode_id,
+        timing.model_path,
+        timing.load_ms,
+        timing.first_token_ms,
+        timing.tokens_per_second,
+        timing.tokens_generated
+    );
+    file.write_all(line.as_bytes()).map_err(|e| format!("Failed to write stats file: {}", e))
+}
+
+/// Prints a session's timings in one line
+fn display_session_timing(timing: &SessionTiming) {
+    let speed = if timing.tokens_per_second > 0.0 {
+        format!(", {:.1} tok/s", timing.tokens_per_second)
+    } else {
+        String::new()
+    };
+    status_println!(
+        "(model load {:.1}s, first token after {:.0}ms{})",
+        timing.load_ms / 1000.0, timing.first_token_ms, speed
+    );
+}
+
+/// Reads a value in ms from llama.cpp's performance report on stderr
+///
+/// e.g. for label "load time":
+/// `llama_perf_context_print:        load time =     612.34 ms`
+fn parse_llama_perf_ms(stderr: &str, label: &str) -> Option<f64> {
+    stderr.lines()
+        .filter(|line| line.contains("llama_perf"))
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?.1.split_once('=')?;
+            if name.trim() != label {
+                return None;
+            }
+            value.split_whitespace().next()?.parse::<f64>().ok()
+        })
+}
+
+/// Reads the generation speed (tokens per second) from llama.cpp's performance report
+///
+/// Uses the `eval time` line, not `prompt eval time`:
+/// `llama_perf_context_print:        eval time =  1000.00 ms /  49 runs   (  20.41 ms per token,  49.00 tokens per second)`
+fn parse_llama_tokens_per_second(stderr: &str) -> Option<f64> {
+    stderr.lines()
+        .filter(|line| line.contains("llama_perf"))
+        .find_map(|line| {
+            let (name, rest) = line.split_once(':')?.1.split_once('=')?;
+            if name.trim() != "eval time" {
+                return None;
+            }
+            let before = rest.split("tokens per second").next()?;
+            before.rsplit(',').next()?.trim().parse::<f64>().ok()
+        })
+}
+
+/// Reads the number of generated tokens from llama.cpp's performance report
+///
+/// From the `eval time` line: `eval time = 1000.00 ms /  49 runs ...` gives 49.
+fn parse_llama_eval_tokens(stderr: &str) -> Option<u64> {
+    stderr.lines()
+        .filter(|line| line.contains("llama_perf"))
+        .find_map(|line| {
+            let (name, rest) = line.split_once(':')?.1.split_once('=')?;
+            if name.trim() != "eval time" {
+                return None;
+            }
+            rest.split_once('/')?.1.split_whitespace().next()?.parse::<u64>().ok()
+        })
+}
+
+/// Handles `query_gguf stats`: per model averages of load time, first token latency and speed
+fn handle_stats_command() -> Result<(), String> {
+    let stats_path = get_session_stats_path()?;
+    let content = fs::read_to_string(&stats_path).unwrap_or_default();
+
+    // model -> (sessions, total load ms, total first token ms, total tok/s, sessions with tok/s, sessions with first token)
+    let mut per_model: Vec<(String, usize, f64, f64, f64, usize, usize)> = Vec::new();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 7 {
+            continue;
+        }
+        let model = Path::new(fields[3]).file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| fields[3].to_string());
+        let load_ms: f64 = fields[4].parse().unwrap_or(0.0);
+        let first_token_ms: f64 = fields[5].parse().unwrap_or(0.0);
+        let tokens_per_second: f64 = fields[6].parse().unwrap_or(0.0);
+
+        let index = match per_model.iter().position(|entry| entry.0 == model) {
+            Some(index) => index,
+            None => {
+                per_model.push((model, 0, 0.0, 0.0, 0.0, 0, 0));
+                per_model.len() - 1
+            }
+        };
+        let entry = &mut per_model[index];
+        entry.1 += 1;
+        entry.2 += load_ms;
+        // Chat sessions do not measure first token latency
+        if first_token_ms > 0.0 {
+            entry.3 += first_token_ms;
+            entry.6 += 1;
+        }
+        if tokens_per_second > 0.0 {
+            entry.4 += tokens_per_second;
+            entry.5 += 1;
+        }
+    }
+
+    if per_model.is_empty() {
+        println!("No session stats yet ({}).", stats_path.display());
+        println!("Stats are recorded for --print runs, chat, and tune sessions.");
+        return Ok(());
+    }
+
+    println!("{:<50} {:>8} {:>10} {:>12} {:>8}", "Model", "Sessions", "Avg load", "Avg 1st tok", "Avg t/s");
+    for (model, sessions, load, first_token, speed, speed_count, first_token_count) in &per_model {
+        let avg_speed = if *speed_count > 0 { speed / *speed_count as f64 } else { 0.0 };
+        let avg_first_token = if *first_token_count > 0 { first_token / *first_token_count as f64 } else { 0.0 };
+        println!(
+            "{:<50} {:>8} {:>9.1}s {:>10.0}ms {:>8.1}",
+            model,
+            sessions,
+            load / *sessions as f64 / 1000.0,
+            avg_first_token,
+            avg_speed
+        );
+    }
+    Ok(())
+}
+
+/// Builds the Markdown usage report from session stats and model file sizes
+///
+/// # Arguments
+/// * `stats` - Contents of the session stats file
+/// * `model_sizes` - (model file path, size in bytes) of the models on disk
+/// * `since` - Only sessions at or after this unix time are counted
+fn build_usage_report(stats: &str, model_sizes: &[(String, u64)], since: u64) -> String {
+    // (name, sessions, tokens generated, total tok/s, sessions with tok/s)
+    fn add_session(table: &mut Vec<(String, usize, u64, f64, usize)>, name: &str, tokens: u64, speed: f64) {
+        let index = match table.iter().position(|row| row.0 == name) {
+            Some(index) => index,
+            None => {
+                table.push((name.to_string(), 0, 0, 0.0, 0));
+                table.len() - 1
+            },
+        };
+        let row = &mut table[index];
+        row.1 += 1;
+        row.2 += tokens;
+        if speed > 0.0 {
+            row.3 += speed;
+            row.4 += 1;
+        }
+    }
+
+    let mut per_mode = Vec::new();
+    let mut per_model = Vec::new();
+    let mut total_sessions = 0;
+    let mut total_tokens = 0;
+    for line in stats.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 7 {
+            continue;
+        }
+        let timestamp: u64 = fields[0].parse().unwrap_or(0);
+        if timestamp < since {
+            continue;
+        }
+        let model = Path::new(fields[3]).file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| fields[3].to_string());
+        let speed: f64 = fields[6].parse().unwrap_or(0.0);
+        let tokens: u64 = fields.get(7).and_then(|value| value.parse().ok()).unwrap_or(0);
+        let mode = if fields[2].is_empty() { "(no mode)" } else { fields[2] };
+
+        add_session(&mut per_mode, &format!("{} ({})", mode, fields[1]), tokens, speed);
+        add_session(&mut per_model, &model, tokens, speed);
+        total_sessions += 1;
+        total_tokens += tokens;
+    }
+    per_mode.sort_by_key(|row| std::cmp::Reverse(row.1));
+    per_model.sort_by_key(|row| std::cmp::Reverse(row.1));
+
+    let mut report = String::from("# query_gguf usage report\n\n");
+    report.push_str("Generated locally from query_gguf's own files. This report is never sent anywhere.\n\n");
+    report.push_str(&format!("- Sessions: {}\n- Tokens generated: {}\n\n", total_sessions, total_tokens));
+    report.push_str("Sessions are --print runs, chat, and tune sessions. Terminal launches are not measured.\n\n");
+
+    for (title, table) in [("Modes used", &per_mode), ("Models used", &per_model)] {
+        report.push_str(&format!("## {}\n\n", title));
+        if table.is_empty() {
+            report.push_str("No sessions in this period.\n\n");
+            continue;
+        }
+        report.push_str("| Name | Sessions | Tokens generated | Avg tok/s |\n|---|---:|---:|---:|\n");
+        for (name, sessions, tokens, speed, speed_count) in table.iter() {
+            let avg_speed = if *speed_count > 0 { format!("{:.1}", speed / *speed_count as f64) } else { "-".to_string() };
+            report.push_str(&format!("| {} | {} | {} | {} |\n", name, sessions, tokens, avg_speed));
+        }
+        report.push('\n');
+    }
+
+    let mut sizes = model_sizes.to_vec();
+    sizes.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    let total_size: u64 = sizes.iter().map(|(_, size)| size).sum();
+    report.push_str(&format!("## Disk usage by model ({} total)\n\n", format_bytes(total_size)));
+    report.push_str("| Model file | Size |\n|---|---:|\n");
+    for (path, size) in &sizes {
+        report.push_str(&format!("| {} | {} |\n", path, format_bytes(*size)));
+    }
+    report
+}
+
+/// Writes a local usage report: `query_gguf report [--days N] [--out file.md]`
+///
+/// Covers the last 30 days unless --days is given. The report is written
+/// to ~/query_gguf/reports/ by default and is never transmitted.
+fn handle_report_command(args: &[String]) -> Result<(), String> {
+    let option_value = |name: &str| args.iter()
+        .position(|arg| arg == name)
+        .and_then(|index| args.get(index + 1))
+        .cloned();
+    let days: u64 = match option_value("--days") {
+        Some(value) => value.parse().map_err(|_| format!("Invalid --days value: {}", value))?,
+        None => 30,
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let since = now.saturating_sub(days * 24 * 60 * 60);
+    let stats = fs::read_to_string(get_session_stats_path()?).unwrap_or_default();
+    let model_sizes: Vec<(String, u64)> = find_gguf_models()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|model| {
+            let size = fs::metadata(&model.full_path).map(|meta| meta.len()).unwrap_or(0);
+            (model.full_path, size)
+        })
+        .collect();
+
+    let report = format!(
+        "{}\nPeriod: last {} days.\n",
+        build_usage_report(&stats, &model_sizes, since),
+        days
+    );
+
+    let output_path = match option_value("--out") {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let reports_dir = get_app_base_dir()?.join("reports");
+            fs::create_dir_all(&reports_dir)
+                .map_err(|e| format!("Failed to create {}: {}", reports_dir.display(), e))?;
+            reports_dir.join(format!("usage_report_{}.md", generate_timestamp_string()))
+        },
+    };
+    fs::write(&output_path, report)
+        .map_err(|e| format!("Failed to write report {}: {}", output_path.display(), e))?;
+    println!("Usage report written to {}", output_path.display());
+    Ok(())
+}
+
+/// Gets the absolute path to the response cache directory and ensures it exists
+///
+/// Location: ~/query_gguf/cache/
+fn get_cache_dir() -> Result<PathBuf, String> {
+    let cache_dir = get_app_base_dir()?.join("cache");
+    fs::create_dir_all(&cache_dir)
+        .map_err(|e| format!("Failed to create cache directory: {}", e))?;
+    Ok(cache_dir)
+}
+
+/// Returns true if the response cache is enabled (`response_cache = true` in config)
+fn is_response_cache_enabled() -> bool {
+    read_config_bool("response_cache") == Some(true)
+}
+
+/// 64-bit FNV-1a hash
+///
+/// Used instead of std's DefaultHasher because cache keys must stay the
+/// same across Rust versions and program runs.
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Builds the cache key for a generation from where it runs (backend, remote
+/// host, or endpoint), model, parameters, and rendered prompt
+fn make_response_cache_key(mode: &ChatModeConfig, backend: &str, rendered_prompt: &str) -> String {
+    let params = &mode.parameters;
+    let key_material = format!(
+        "{}|{}|{}\n{}\n{}|{}|{}|{}|{}\n{}",
+        backend,
+        mode.remote,
+        mode.endpoint,
+        mode.model_path,
+        params.temperature_value,
+        params.top_k_sampling,
+        params.top_p_sampling,
+        params.context_size,
+        params.gpu_layers,
+        rendered_prompt
+    );
+    format!("{:016x}", fnv1a_hash(key_material.as_bytes()))
+}
+
+/// Adds one hit or miss to the cache counters in ~/query_gguf/cache/stats.txt
+fn record_cache_lookup(hit: bool) {
+    let stats_path = match get_cache_dir() {
+        Ok(dir) => dir.join("stats.txt"),
+        Err(_) => return,
+    };
+    let _ = update_state_file(&stats_path, |content| {
+        let (mut hits, mut misses) = parse_cache_counters(content);
+        if hit { hits += 1 } else { misses += 1 }
+        Some(format!("hits={}\nmisses={}\n", hits, misses))
+    });
+}
+
+/// Reads (hits, misses) from the cache counters file, zero if missing
+fn read_cache_counters(stats_path: &Path) -> (u64, u64) {
+    parse_cache_counters(&fs::read_to_string(stats_path).unwrap_or_default())
+}
+
+fn parse_cache_counters(content: &str) -> (u64, u64) {
+    let read_counter = |name: &str| -> u64 {
+        content.lines()
+            .find_map(|line| line.strip_prefix(name).and_then(|v| v.strip_prefix('=')))
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0)
+    };
+    (read_counter("hits"), read_counter("misses"))
+}
+
+/// Runs a non-interactive generation, using the response cache when enabled
+///
+/// # Arguments
+/// * `mode` - Mode with the final (rendered) prompt path
+/// * `use_cache` - false when `--no-cache` was given
+///
+/// # Returns
+/// - Ok(String): Cached or freshly generated response
+/// - Err(String): Generation failed
+fn run_non_interactive_cached(mode: &ChatModeConfig, use_cache: bool, limits: &GenerationLimits) -> Result<String, String> {
+    if !use_cache || !is_response_cache_enabled() {
+        return run_llama_with_limits(mode, limits);
+    }
+
+    let rendered_prompt = fs::read_to_string(&mode.prompt_path)
+        .map_err(|e| format!("Failed to read prompt {}: {}", mode.prompt_path, e))?;
+    let cache_path = get_cache_dir()?
+        .join(format!("{}.txt", make_response_cache_key(mode, &read_field_from_toml("launch_backend"), &rendered_prompt)));
+
+    if let Ok(cached) = fs::read_to_string(&cache_path) {
+        record_cache_lookup(true);
+        eprintln!("(cached response, use --no-cache to regenerate)");
+        return Ok(cached);
+    }
+
+    record_cache_lookup(false);
+    let response = run_llama_with_limits(mode, limits)?;
+    // A cut-off response is not the model's answer, so it is not cached
+    if response.contains(TRUNCATED_OUTPUT_MARKER) {
+        return Ok(response);
+    }
+    if let Err(e) = write_file_atomically(&cache_path, &response) {
+        eprintln!("Warning: Could not write response cache: {}", e);
+    }
+    Ok(response)
+}
+
+/// Handles the `cache` command line subcommands
+///
+/// - `query_gguf cache stats` -> number of cached responses, size, hits and misses
+/// - `query_gguf cache clear` -> delete all cached responses
+fn handle_cache_command(args: &[String]) -> Result<(), String> {
+    let cache_dir = get_cache_dir()?;
+    let stats_path = cache_dir.join("stats.txt");
+
+    let entries: Vec<PathBuf> = fs::read_dir(&cache_dir)
+        .map_err(|e| format!("Failed to read cache directory: {}", e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path != &stats_path && path.extension().and_then(|e| e.to_str()) == Some("txt"))
+        .collect();
+
+    match args.first().map(|s| s.as_str()) {
+        Some("stats") => {
+            let total_bytes: u64 = entries.iter()
+                .filter_map(|path| fs::metadata(path).ok())
+                .map(|metadata| metadata.len())
+                .sum();
+            let (hits, misses) = read_cache_counters(&stats_path);
+            println!("Response cache: {}", cache_dir.display());
+            println!("  Enabled: {}", is_response_cache_enabled());
+            println!("  Entries: {}", entries.len());
+            println!("  Size: {} bytes", total_bytes);
+            println!("  Hits: {}  Misses: {}", hits, misses);
+            Ok(())
+        },
+        Some("clear") => {
+            for path in &entries {
+                fs::remove_file(path)
+                    .map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+            }
+            let _ = fs::remove_file(&stats_path);
+            println!("Removed {} cached responses", entries.len());
+            Ok(())
+        },
+        Some(other) => Err(format!("Unknown cache command '{}'. Use: cache stats, cache clear", other)),
+        None => Err("Missing cache command. Use: cache stats, cache clear".to_string()),
+    }
+}
+
+/// Sends an HTTP request to a local server and returns (status code, body)
+///
+/// Only meant for talking to a llama-server on 127.0.0.1, so no proxy is
+/// used and offline mode does not apply.
+///
+/// # Arguments
+/// * `port` - Port on 127.0.0.1
+/// * `method` - "GET" or "POST"
+/// * `path` - Request path, e.g. "/completion"
+/// * `body` - Request body (sent as JSON), empty for none
+/// * `timeout` - Connect timeout, and read/write timeout for the exchange
+fn local_http_request(
+    port: u16,
+    method: &str,
+    path: &str,
+    body: &str,
+    timeout: std::time::Duration,
+) -> Result<(u16, String), String> {
+    let request = http::Request::new(method, &format!("http://127.0.0.1:{}{}", port, path))
+        .header("Content-Type", "application/json")
+        .body(body)
+        .timeout(timeout);
+    let response = http::Client::default().send(&request)?;
+    Ok((response.status, response.text()))
+}
+
+/// An OpenAI-compatible server a mode talks to (`endpoint=<url>` in the mode)
+#[derive(Debug, Clone)]
+struct RemoteEndpoint {
+    url: String,    // base URL; request paths such as /v1/chat/completions are appended
+    model: String,  // model name on the server
+}
+
+/// API keys entered during this run, by host, so each is asked for only once
+///
+/// Keys are kept in memory only; they are never written to disk.
+static API_KEY_CACHE: std::sync::Mutex<Vec<(String, String)>> = std::sync::Mutex::new(Vec::new());
+
+/// Gets the host of a URL, e.g. "api.example.com" for "https://user@api.example.com:443/v1"
+///
+/// IPv6 hosts keep their brackets: "[::1]" for "http://[::1]:8080".
+fn url_host(url: &str) -> String {
+    let without_scheme = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let authority = without_scheme.split('/').next().unwrap_or("");
+    let host_port = authority.rsplit_once('@').map(|(_, host)| host).unwrap_or(authority);
+    if host_port.starts_with('[') {
+        if let Some(end) = host_port.find(']') {
+            return host_port[..=end].to_string();
+        }
+    }
+    host_port.split(':').next().unwrap_or("").to_string()
+}
+
+/// Replaces every occurrence of a secret in text, for error messages and logs
+fn mask_secret(text: &str, secret: &str) -> String {
+    if secret.is_empty() {
+        return text.to_string();
+    }
+    text.replace(secret, "****")
+}
+
+/// Looks up a stored API key for a host in the OS keychain
+///
+/// Uses secret-tool (libsecret) on Linux/BSD, `security` on macOS, and the
+/// CredentialManager PowerShell module on Windows (target query_gguf:<host>).
+fn read_api_key_from_keychain(host: &str) -> Option<String> {
+    let output = if cfg!(target_os = "macos") {
+        Command::new("security")
+            .args(["find-generic-password", "-s", "query_gguf", "-a", host, "-w"])
+            .output()
+    } else if cfg!(target_os = "windows") {
+        Command::new("powershell")
+            .args(["-NoProfile", "-Command", &format!(
+                "(Get-StoredCredential -Target 'query_gguf:{}').GetNetworkCredential().Password",
+                host.replace('\'', "")
+            )])
+            .output()
+    } else {
+        Command::new("secret-tool")
+            .args(["lookup", "service", "query_gguf", "host", host])
+            .output()
+    }.ok()?;
+    let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !key.is_empty()).then_some(key)
+}
+
+/// Reads a line without echoing it (for API keys)
+fn read_hidden_input(prompt: &str) -> Result<String, String> {
+    print!("{}", prompt);
+    io::stdout().flush().map_err(|e| e.to_string())?;
+    let echo_off = cfg!(unix) && Command::new("stty")
+        .arg("-echo")
+        .stdin(std::process::Stdio::inherit())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false);
+    let mut line = String::new();
+    let result = io::stdin().read_line(&mut line);
+    if echo_off {
+        let _ = Command::new("stty").arg("echo").stdin(std::process::Stdio::inherit()).status();
+        println!();
+    }
+    result.map_err(|e| format!("Failed to read input: {}", e))?;
+    Ok(line.trim().to_string())
+}
+
+/// Finds the API key for an endpoint host without asking
+///
+/// In order: keys entered earlier in this run, the environment variable
+/// named by `api_key_env`, the OS keychain. A plaintext `api_key` in the
+/// config file is refused.
+fn find_api_key(host: &str) -> Option<String> {
+    if let Some((_, key)) = API_KEY_CACHE.lock().ok()?.iter().find(|(cached_host, _)| cached_host == host) {
+        return Some(key.clone());
+    }
+    if !read_field_from_toml("api_key").is_empty() {
+        println!("Warning: api_key in the config file is ignored; use api_key_env or the OS keychain");
+    }
+    let env_name = read_field_from_toml("api_key_env");
+    if !env_name.is_empty() {
+        if let Some(key) = std::env::var(&env_name).ok().filter(|key| !key.is_empty()) {
+            return Some(key);
+        }
+    }
+    read_api_key_from_keychain(host)
+}
+
+/// Asks for an endpoint's API key and keeps it in memory for the rest of the run
+fn ask_for_api_key(host: &str) -> Result<Option<String>, String> {
+    use std::io::IsTerminal;
+    if !io::stdin().is_terminal() {
+        return Ok(None);
+    }
+    let key = read_hidden_input(&format!("API key for {} (not saved): ", host))?;
+    if key.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(mut cache) = API_KEY_CACHE.lock() {
+        cache.push((host.to_string(), key.clone()));
+    }
+    Ok(Some(key))
+}
+
+/// Retries of a failed endpoint request when `endpoint_retries` is not set
+const DEFAULT_ENDPOINT_RETRIES: u32 = 3;
+
+/// Longest wait between two endpoint request attempts
+const MAX_ENDPOINT_RETRY_DELAY_SECS: u64 = 30;
+
+/// Returns true for HTTP statuses that may succeed when retried (rate limited, server errors)
+fn is_retryable_status(status: u16) -> bool {
+    status == 429 || (500..=599).contains(&status)
+}
+
+/// Wait before retry number `attempt` (0-based): 1s, 2s, 4s, ... up to MAX_ENDPOINT_RETRY_DELAY_SECS
+fn endpoint_retry_delay(attempt: u32) -> std::time::Duration {
+    std::time::Duration::from_secs(2u64.saturating_pow(attempt).min(MAX_ENDPOINT_RETRY_DELAY_SECS))
+}
+
+/// Checks a client-side rate limit against recent request times
+///
+/// # Arguments
+/// * `request_times` - Unix seconds of earlier requests
+/// * `now` - Current unix seconds
+/// * `limit_per_minute` - Allowed requests in any 60 seconds, 0 for no limit
+///
+/// # Returns
+/// - None: The request may be sent now
+/// - Some(u64): Seconds until it may be sent
+fn rate_limit_wait_secs(request_times: &[u64], now: u64, limit_per_minute: u32) -> Option<u64> {
+    if limit_per_minute == 0 {
+        return None;
+    }
+    let mut recent: Vec<u64> = request_times.iter()
+        .copied()
+        .filter(|time| now.saturating_sub(*time) < 60)
+        .collect();
+    if recent.len() < limit_per_minute as usize {
+        return None;
+    }
+    recent.sort_unstable();
+    // Wait until enough of the recent requests are a minute old
+    let oldest_blocking = recent[recent.len() - limit_per_minute as usize];
+    Some((oldest_blocking + 60).saturating_sub(now).max(1))
+}
+
+/// Counts a request against `endpoint_rate_limit` (requests per minute per host)
+///
+/// Request times are kept in the state file, so the limit holds across
+/// runs (e.g. a loop of --print calls).
+///
+/// # Returns
+/// - Ok(()): Under the limit; the request is recorded
+/// - Err(String): The limit is reached, with how long to wait
+fn check_endpoint_rate_limit(host: &str) -> Result<(), String> {
+    let limit = read_config_int("endpoint_rate_limit").and_then(|limit| u32::try_from(limit).ok()).unwrap_or(0);
+    if limit == 0 {
+        return Ok(());
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    record_endpoint_request(&get_state_path()?, host, limit, now)
+}
+
+/// State field holding a host's recent request times
+///
+/// Dots and colons in the host would make a dotted (nested) TOML key, so
+/// everything but letters, digits, and `-` becomes `_`.
+fn endpoint_requests_field(host: &str) -> String {
+    let safe_host: String = host.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    format!("endpoint_requests_{}", safe_host)
+}
+
+/// Records a request to `host` in the state file at `state_path`, unless `limit` is reached
+fn record_endpoint_request(state_path: &Path, host: &str, limit: u32, now: u64) -> Result<(), String> {
+    // Checked and recorded under one lock, so parallel runs cannot all slip under the limit
+    let mut wait = None;
+    update_state_list_at(state_path, &endpoint_requests_field(host), |times| {
+        let mut request_times: Vec<u64> = times.iter()
+            .filter_map(|time| time.parse().ok())
+            .filter(|time| now.saturating_sub(*time) < 60)
+            .collect();
+        wait = rate_limit_wait_secs(&request_times, now, limit);
+        if wait.is_none() {
+            request_times.push(now);
+        }
+        request_times.iter().map(|time| time.to_string()).collect()
+    })?;
+    match wait {
+        Some(wait) => Err(format!(
+            "Rate limit reached for {}: {} requests per minute (endpoint_rate_limit). Try again in {}s.",
+            host, limit, wait
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Sends one HTTP request to an endpoint, returning (status code, body)
+///
+/// The key is sent as an Authorization header and never put on a command
+/// line, so it does not show up in process listings. A 401 without a known
+/// key asks for one once and retries.
+///
+/// Connection errors, 429, and 5xx responses are retried with exponential
+/// backoff (`endpoint_retries`, default 3). `endpoint_timeout_secs` replaces
+/// the caller's timeout for each attempt, and every attempt counts against
+/// `endpoint_rate_limit`.
+fn endpoint_http_request(
+    endpoint: &RemoteEndpoint,
+    method: &str,
+    path: &str,
+    body: &str,
+    timeout: std::time::Duration,
+) -> Result<(u16, String), String> {
+    let host = url_host(&endpoint.url);
+    let mut api_key = find_api_key(&host);
+    let config = read_config();
+    let timeout = config.get_float("endpoint_timeout_secs")
+        .filter(|secs| *secs > 0.0 && secs.is_finite())
+        .map(std::time::Duration::from_secs_f64)
+        .unwrap_or(timeout);
+    let retries = config.get_int("endpoint_retries")
+        .and_then(|retries| u32::try_from(retries).ok())
+        .unwrap_or(DEFAULT_ENDPOINT_RETRIES);
+    let mut attempt = 0;
+    loop {
+        check_endpoint_rate_limit(&host)?;
+        let problem = match send_endpoint_request(endpoint, method, path, body, timeout, api_key.as_deref()) {
+            Ok((401, response)) if api_key.is_none() => match ask_for_api_key(&host)? {
+                Some(key) => {
+                    api_key = Some(key);
+                    continue;
+                },
+                None => return Ok((401, response)),
+            },
+            Ok((status, _)) if is_retryable_status(status) && attempt < retries => {
+                format!("HTTP {}", status)
+            },
+            Ok((status, response)) => {
+                return Ok((status, mask_secret(&response, api_key.as_deref().unwrap_or(""))));
+            },
+            Err(failure) if failure.retryable && attempt < retries => failure.message,
+            Err(failure) if attempt > 0 => {
+                return Err(format!("{} (gave up after {} retries)", failure.message, attempt));
+            },
+            Err(failure) => return Err(failure.message),
+        };
+        let delay = endpoint_retry_delay(attempt);
+        attempt += 1;
+        eprintln!("Warning: {} from {}, retrying in {}s ({}/{})", problem, host, delay.as_secs(), attempt, retries);
+        std::thread::sleep(delay);
+    }
+}
+
+/// Sends one request for `endpoint_http_request`
+fn send_endpoint_request(
+    endpoint: &RemoteEndpoint,
+    method: &str,
+    path: &str,
+    body: &str,
+    timeout: std::time::Duration,
+    api_key: Option<&str>,
+) -> Result<(u16, String), http::Error> {
+    let url = format!("{}{}", endpoint.url.trim_end_matches('/'), path);
+    let client = http_client(&url).map_err(|message| http::Error { message, retryable: false })?;
+    let mut request = http::Request::new(method, &url)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .timeout(timeout);
+    if let Some(key) = api_key {
+        request = request.header("Authorization", &format!("Bearer {}", key));
+    }
+    let response = client.send(&request)
+        .map_err(|e| http::Error { message: mask_secret(&e.message, api_key.unwrap_or("")), ..e })?;
+    Ok((response.status, response.text()))
+}
+
+/// Result of one llama-server completion request
+#[derive(Debug, Clone, Default)]
+struct CompletionResult {
+    content: String,
+    tokens_evaluated: u64,   // prompt tokens
+    tokens_predicted: u64,   // generated tokens
+    prompt_ms: f64,          // time to process the prompt (~ time to first token)
+    predicted_ms: f64,       // time spent generating
+}
+
+/// A llama-server process kept running so the model stays loaded between generations
+///
+/// The server listens only on 127.0.0.1 on a free port, and is stopped when
+/// this value is dropped. A server attached to (already running) is left running.
+struct LlamaServer {
+    child: Option<std::process::Child>, // None when attached to a server started elsewhere
+    port: u16,
+    load_time: std::time::Duration,
+    preloaded: Option<PreloadedServer>, // registry entry when attached to a preloaded server, marked used per request
+    endpoint: Option<RemoteEndpoint>,   // set when talking to an OpenAI-compatible server elsewhere (port unused)
+}
+
+impl LlamaServer {
+    /// Starts llama-server for a mode's model and waits until the model is loaded
+    ///
+    /// The llama-server binary is taken from `llama_server_path` in the config,
+    /// or found next to the mode's llama-cli binary.
+    fn start(mode: &ChatModeConfig) -> Result<LlamaServer, String> {
+        LlamaServer::start_with_args(mode, &[], false)
+    }
+
+    /// Starts llama-server with extra arguments
+    ///
+    /// # Arguments
+    /// * `mode` - Mode whose model and parameters are used
+    /// * `extra_args` - Added to the llama-server command line (e.g. --mlock)
+    /// * `detached` - Run in its own process group, so it can outlive this
+    ///   program (see `detach`) and is not stopped by Ctrl-C here
+    fn start_with_args(mode: &ChatModeConfig, extra_args: &[&str], detached: bool) -> Result<LlamaServer, String> {
+        check_model_file(mode)?;
+        let server_path = get_llama_server_path(mode)?;
+
+        // Ask the OS for a free port
+        let port = std::net::TcpListener::bind(("127.0.0.1", 0))
+            .and_then(|listener| listener.local_addr())
+            .map(|address| address.port())
+            .map_err(|e| format!("Failed to find a free port: {}", e))?;
+
+        let mut command_args: Vec<String> = vec![
+            "-m".to_string(), mode.model_path.clone(),
+            "--ctx-size".to_string(), mode.parameters.context_size.to_string(),
+            "--threads".to_string(), mode.parameters.thread_count.to_string(),
+            "--host".to_string(), "127.0.0.1".to_string(),
+            "--port".to_string(), port.to_string(),
+        ];
+        if mode.parameters.gpu_layers > 0 {
+            command_args.push("--n-gpu-layers".to_string());
+            command_args.push(mode.parameters.gpu_layers.to_string());
+        }
+        command_args.extend(extra_args.iter().map(|arg| arg.to_string()));
+
+        // stderr goes to a file so a failed start can be explained; a detached
+        // (preloaded) server keeps it as a session log for `query_gguf logs`
+        let stderr_path = if detached {
+            get_sessions_dir()?.join(format!("server_{}_{}.log", mode.id, generate_timestamp_string()))
+        } else {
+            get_temp_dir()?.join(format!("llama_server_{}.log", port))
+        };
+        let mut stderr_file = fs::File::create(&stderr_path)
+            .map_err(|e| format!("Failed to create {}: {}", stderr_path.display(), e))?;
+        if detached {
+            write!(stderr_file, "[[session kind=server mode={} port={}]]\n[[stderr {}]]\n", mode.id, port, session_clock())
+                .map_err(|e| format!("Failed to write {}: {}", stderr_path.display(), e))?;
+        }
+
+        if let Some(warning) = check_model_quantization_speed(mode) {
+            println!("Warning: {}", warning);
+        }
+        println!("Starting llama-server on port {} (loading model)...", port);
+        let started = std::time::Instant::now();
+        let mut command = Command::new(&server_path);
+        command
+            .args(&command_args)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(stderr_file);
+        if detached {
+            detach_from_terminal(&mut command);
+        }
+        let child = command.spawn()
+            .map_err(|e| format!("Failed to start llama-server at {}: {}", server_path, e))?;
+        if detached {
+            // Appended after llama-server's own first lines, wherever they end
+            if let Ok(mut log) = fs::OpenOptions::new().append(true).open(&stderr_path) {
+                let _ = writeln!(log, "\n[[pid {}]]", child.id());
+            }
+        }
+
+        let mut server = LlamaServer { child: Some(child), port, load_time: std::time::Duration::ZERO, preloaded: None, endpoint: None };
+        if let Err(e) = server.wait_until_ready() {
+            let stderr = fs::read_to_string(&stderr_path).unwrap_or_default();
+            return Err(format!("{}\n{}", e, describe_llama_failure(&stderr)));
+        }
+        if !detached {
+            let _ = fs::remove_file(&stderr_path);
+        }
+        server.load_time = started.elapsed();
+        println!("Model loaded in {:.1}s", server.load_time.as_secs_f64());
+        Ok(server)
+    }
+
+    /// Leaves the server running after this value is dropped
+    ///
+    /// # Returns
+    /// Process id of the server
+    fn detach(mut self) -> u32 {
+        self.child.take().map(|child| child.id()).unwrap_or(0)
+    }
+
+    /// Uses a llama-server that is already running on a local port
+    fn attach(port: u16) -> Result<LlamaServer, String> {
+        match local_http_request(port, "GET", "/health", "", std::time::Duration::from_secs(5)) {
+            Ok((200, _)) => Ok(LlamaServer { child: None, port, load_time: std::time::Duration::ZERO, preloaded: None, endpoint: None }),
+            Ok((status, _)) => Err(format!("llama-server on port {} is not ready (HTTP {})", port, status)),
+            Err(e) => Err(format!("Could not reach llama-server on port {}: {}", port, e)),
+        }
+    }
+
+    /// Talks to the OpenAI-compatible server of a mode with `endpoint=<url>`
+    ///
+    /// Nothing is started; the mode's model field names the model on that server.
+    fn connect(mode: &ChatModeConfig) -> LlamaServer {
+        LlamaServer {
+            child: None,
+            port: 0,
+            load_time: std::time::Duration::ZERO,
+            preloaded: None,
+            endpoint: Some(RemoteEndpoint { url: mode.endpoint.clone(), model: mode.model_path.clone() }),
+        }
+    }
+
+    /// Sends a request to this server (local llama-server or remote endpoint)
+    fn request(&self, method: &str, path: &str, body: &str, timeout: std::time::Duration) -> Result<(u16, String), String> {
+        match &self.endpoint {
+            Some(endpoint) => endpoint_http_request(endpoint, method, path, body, timeout),
+            None => local_http_request(self.port, method, path, body, timeout),
+        }
+    }
+
+    /// Attaches to the preloaded server of a mode (`query_gguf preload`), if one is up
+    ///
+    /// The registered server must still be running the mode's model.
+    fn attach_preloaded(mode: &ChatModeConfig) -> Option<LlamaServer> {
+        let entry = read_preloaded_server(&mode.id)
+            .filter(|entry| entry.model_path == mode.model_path && is_process_running(entry.pid))?;
+        // Marked as used first, so the idle watcher does not unload it while attaching
+        if !touch_preloaded_server(&entry).unwrap_or(false) {
+            return None;
+        }
+        let mut server = LlamaServer::attach(entry.port).ok()?;
+        server.preloaded = Some(entry);
+        Some(server)
+    }
+
+    /// Marks an attached preloaded server as used, so it is not unloaded as idle
+    fn mark_used(&self) {
+        if let Some(entry) = &self.preloaded {
+            let _ = touch_preloaded_server(entry);
+        }
+    }
+
+    /// Polls /health until the server reports the model is loaded
+    fn wait_until_ready(&mut self) -> Result<(), String> {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(600);
+        while std::time::Instant::now() < deadline {
+            if let Some(Ok(Some(status))) = self.child.as_mut().map(|child| child.try_wait()) {
+                return Err(format!("llama-server exited while loading (status {})", status.code().unwrap_or(-1)));
+            }
+            if let Ok((200, _)) = local_http_request(self.port, "GET", "/health", "", std::time::Duration::from_secs(5)) {
+                return Ok(());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(250));
+        }
+        Err("Timed out waiting for llama-server to load the model".to_string())
+    }
+
+    /// Runs one completion of `prompt` with the given sampling parameters
+    ///
+    /// Endpoints are only expected to offer chat completions, so there the
+    /// prompt is sent as a single user message.
+    fn complete(&self, prompt: &str, params: &LlamaCppParameters, max_tokens: i32) -> Result<CompletionResult, String> {
+        if self.endpoint.is_some() {
+            let message = ChatMessage { role: "user".to_string(), content: prompt.to_string() };
+            let started = std::time::Instant::now();
+            let result = self.chat(&[message], params, max_tokens, None)?;
+            return Ok(CompletionResult {
+                content: result.content,
+                tokens_evaluated: result.prompt_tokens,
+                tokens_predicted: result.completion_tokens,
+                prompt_ms: 0.0,
+                predicted_ms: started.elapsed().as_secs_f64() * 1000.0,
+            });
+        }
+        self.mark_used();
+        let body = json::Value::object([
+            ("prompt", prompt.into()),
+            ("temperature", params.temperature_value.into()),
+            ("top_k", params.top_k_sampling.into()),
+            ("top_p", params.top_p_sampling.into()),
+            ("n_predict", max_tokens.into()),
+            ("cache_prompt", true.into()),
+        ]);
+        let (status, response) = self.request("POST", "/completion", &body.to_string(), std::time::Duration::from_secs(3600))?;
+        if status != 200 {
+            return Err(format!("llama-server returned HTTP {}: {}", status, response));
+        }
+        parse_completion_response(&response)
+    }
+
+    /// Runs one chat turn through the OpenAI-compatible /v1/chat/completions endpoint
+    ///
+    /// llama-server applies the model's own chat template, so no prompt
+    /// format needs to be configured per model.
+    ///
+    /// `seed` fixes the sampling seed (e.g. to get a different answer on /regen).
+    fn chat(&self, messages: &[ChatMessage], params: &LlamaCppParameters, max_tokens: i32, seed: Option<i64>) -> Result<ChatResult, String> {
+        self.mark_used();
+        let messages_json: Vec<json::Value> = messages.iter()
+            .map(|message| json::Value::object([
+                ("role", message.role.as_str().into()),
+                ("content", message.content.as_str().into()),
+            ]))
+            .collect();
+        let mut body = json::Value::object([
+            ("messages", messages_json.into()),
+            ("temperature", params.temperature_value.into()),
+            ("top_k", params.top_k_sampling.into()),
+            ("top_p", params.top_p_sampling.into()),
+        ]);
+        // Hosted APIs need the model name; llama-server ignores it
+        if let Some(endpoint) = &self.endpoint {
+            body.insert("model", endpoint.model.as_str());
+        }
+        // -1 (unlimited) is llama.cpp's convention; other servers want the field left out
+        if max_tokens > 0 || self.endpoint.is_none() {
+            body.insert("max_tokens", max_tokens);
+        }
+        if let Some(seed) = seed {
+            body.insert("seed", seed);
+        }
+        let (status, response) = self.request("POST", "/v1/chat/completions", &body.to_string(), std::time::Duration::from_secs(3600))?;
+        if status != 200 {
+            return Err(format!("llama-server returned HTTP {}: {}", status, response));
+        }
+        parse_chat_response(&response)
+    }
+}
+
+/// Reads a llama-server /completion response
+fn parse_completion_response(response: &str) -> Result<CompletionResult, String> {
+    let response = json::parse(response).map_err(|e| format!("llama-server sent an unreadable response: {}", e))?;
+    let number = |path: &str| response.path(path).and_then(json::Value::as_f64).unwrap_or(0.0);
+    Ok(CompletionResult {
+        content: response.get("content").and_then(json::Value::as_str)
+            .ok_or_else(|| "llama-server response has no content".to_string())?
+            .to_string(),
+        tokens_evaluated: number("tokens_evaluated") as u64,
+        tokens_predicted: number("tokens_predicted") as u64,
+        prompt_ms: number("timings.prompt_ms"),
+        predicted_ms: number("timings.predicted_ms"),
+    })
+}
+
+/// Reads an OpenAI-style /v1/chat/completions response
+///
+/// Tokens per second come from llama-server's `timings`; other servers leave it 0.
+fn parse_chat_response(response: &str) -> Result<ChatResult, String> {
+    let response = json::parse(response).map_err(|e| format!("The server sent an unreadable response: {}", e))?;
+    let number = |path: &str| response.path(path).and_then(json::Value::as_f64).unwrap_or(0.0);
+    Ok(ChatResult {
+        content: response.path("choices.0.message.content").and_then(json::Value::as_str)
+            .ok_or_else(|| "llama-server response has no message content".to_string())?
+            .to_string(),
+        prompt_tokens: number("usage.prompt_tokens") as u64,
+        completion_tokens: number("usage.completion_tokens") as u64,
+        tokens_per_second: number("timings.predicted_per_second"),
+    })
+}
+
+impl Drop for LlamaServer {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// A llama.cpp process found running with some model
+#[derive(Debug, Clone, PartialEq)]
+struct RunningModel {
+    pid: u32,
+    program: String,    // e.g. llama-server
+    port: Option<u16>,  // --port, for servers
+}
+
+/// Finds llama.cpp processes using a model in `ps -A -o pid=,args=` output
+fn parse_running_models(ps_output: &str, model_path: &str) -> Vec<RunningModel> {
+    ps_output.lines()
+        .filter_map(|line| {
+            let (pid, args) = line.trim().split_once(' ')?;
+            let program = Path::new(args.split_whitespace().next()?)
+                .file_name()?
+                .to_string_lossy()
+                .to_string();
+            if !program.starts_with("llama") || !args.contains(model_path) {
+                return None;
+            }
+            let words: Vec<&str> = args.split_whitespace().collect();
+            let port = words.iter()
+                .position(|word| *word == "--port")
+                .and_then(|index| words.get(index + 1)?.parse().ok());
+            Some(RunningModel { pid: pid.parse().ok()?, program, port })
+        })
+        .collect()
+}
+
+/// Lists llama.cpp processes that have a model loaded (empty where `ps` is not available)
+fn find_running_models(model_path: &str) -> Vec<RunningModel> {
+    if cfg!(windows) || model_path.is_empty() {
+        return Vec::new();
+    }
+    // -A (all processes) means the same on Linux, macOS, and the BSDs; -e does not
+    match Command::new("ps").args(["-A", "-o", "pid=,args="]).output() {
+        Ok(output) => parse_running_models(&String::from_utf8_lossy(&output.stdout), model_path)
+            .into_iter()
+            .filter(|running| running.pid != std::process::id())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// What to do when the model to launch is already loaded
+#[derive(Debug, Clone, PartialEq)]
+enum DuplicateLaunchChoice {
+    Proceed,       // load it again (or it was not running)
+    Attach(u16),   // use the running llama-server on this port
+}
+
+/// Warns before loading a model that is already running, and asks what to do
+///
+/// Loading a large model twice can exhaust memory and lock up the machine.
+/// The choices are to attach to a running llama-server (when `can_attach`),
+/// stop the running process, load it anyway, or cancel.
+///
+/// # Returns
+/// - Ok(choice): Proceed or attach
+/// - Err(String): The user cancelled
+fn check_duplicate_launch(mode: &ChatModeConfig, can_attach: bool) -> Result<DuplicateLaunchChoice, String> {
+    let running = find_running_models(&mode.model_path);
+    if running.is_empty() {
+        return Ok(DuplicateLaunchChoice::Proceed);
+    }
+
+    println!("\nWarning: {} is already loaded:", mode.model_path);
+    for process in &running {
+        match process.port {
+            Some(port) => println!("  {} (pid {}, port {})", process.program, process.pid, port),
+            None => println!("  {} (pid {})", process.program, process.pid),
+        }
+    }
+    let attach_port = running.iter().find_map(|process| process.port).filter(|_| can_attach);
+
+    loop {
+        let attach_option = if attach_port.is_some() { "[a]ttach, " } else { "" };
+        print!("{}[k]ill it, [p]roceed anyway, or [c]ancel? ", attach_option);
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        match read_user_input()?.trim().to_lowercase().as_str() {
+            "a" | "attach" if attach_port.is_some() => {
+                return Ok(DuplicateLaunchChoice::Attach(attach_port.unwrap_or_default()));
+            },
+            "k" | "kill" => {
+                for process in &running {
+                    match stop_process(process.pid) {
+                        Ok(()) => println!("Stopped pid {}", process.pid),
+                        Err(e) => println!("Warning: {}", e),
+                    }
+                }
+                // Give the OS a moment to free the model's memory
+                std::thread::sleep(std::time::Duration::from_secs(1));
+                return Ok(DuplicateLaunchChoice::Proceed);
+            },
+            "p" | "proceed" => return Ok(DuplicateLaunchChoice::Proceed),
+            "c" | "cancel" | "" => return Err("Launch cancelled: model already running".to_string()),
+            _ => println!("Please choose one of the options."),
+        }
+    }
+}
+
+/// Stops a process by id (kill on Unix, taskkill on Windows)
+fn stop_process(pid: u32) -> Result<(), String> {
+    let status = if cfg!(windows) {
+        Command::new("taskkill").args(["/PID", &pid.to_string(), "/F"]).status()
+    } else {
+        Command::new("kill").arg(pid.to_string()).status()
+    };
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        _ => Err(format!("Could not stop pid {}", pid)),
+    }
+}
+
+/// Checks whether a process is still running
+fn is_process_running(pid: u32) -> bool {
+    if cfg!(windows) {
+        Command::new("tasklist")
+            .args(["/FI", &format!("PID eq {}", pid), "/NH"])
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()))
+            .unwrap_or(false)
+    } else {
+        Command::new("kill").args(["-0", &pid.to_string()])
+            .stderr(std::process::Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false)
+    }
+}
+
+/// Puts a command in its own process group (Unix), so it keeps running
+/// when this program exits or the terminal gets Ctrl-C
+#[cfg(unix)]
+fn detach_from_terminal(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn detach_from_terminal(_command: &mut Command) {}
+
+/// Default minutes a preloaded model may sit unused before it is unloaded
+const DEFAULT_PRELOAD_IDLE_MINUTES: u64 = 30;
+
+/// Seconds between idle checks of the preload watcher
+const PRELOAD_WATCH_INTERVAL_SECS: u64 = 30;
+
+/// A llama-server started by `query_gguf preload`, kept running between commands
+///
+/// Stored as ~/query_gguf/preloaded/<mode id>.txt; the file's modification
+/// time is when the server was last used (see `touch_preloaded_server`).
+#[derive(Debug, Clone, PartialEq)]
+struct PreloadedServer {
+    mode_id: String,
+    model_path: String,
+    port: u16,
+    pid: u32,
+    idle_minutes: u64,  // unload after this long unused, 0 for never
+}
+
+impl PreloadedServer {
+    fn to_text(&self) -> String {
+        format!(
+            "mode_id={}\nmodel_path={}\nport={}\npid={}\nidle_minutes={}\n",
+            self.mode_id, self.model_path, self.port, self.pid, self.idle_minutes
+        )
+    }
+
+    /// Reads a registry entry; None if a field is missing or invalid
+    fn parse(content: &str) -> Option<PreloadedServer> {
+        let field = |name: &str| -> Option<String> {
+            content.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+                .map(|value| value.to_string())
+        };
+        Some(PreloadedServer {
+            mode_id: field("mode_id")?,
+            model_path: field("model_path")?,
+            port: field("port")?.parse().ok()?,
+            pid: field("pid")?.parse().ok()?,
+            idle_minutes: field("idle_minutes")?.parse().ok()?,
+        })
+    }
+}
+
+/// Gets the preloaded server registry directory and ensures it exists
+///
+/// Location: ~/query_gguf/preloaded/
+fn get_preload_dir() -> Result<PathBuf, String> {
+    let preload_dir = get_app_base_dir()?.join("preloaded");
+    fs::create_dir_all(&preload_dir)
+        .map_err(|e| format!("Failed to create {}: {}", preload_dir.display(), e))?;
+    Ok(preload_dir)
+}
+
+/// Finds the preloaded server of a mode, if one is registered
+fn read_preloaded_server(mode_id: &str) -> Option<PreloadedServer> {
+    let path = get_preload_dir().ok()?.join(format!("{}.txt", mode_id));
+    PreloadedServer::parse(&fs::read_to_string(path).ok()?)
+}
+
+/// Lists all registered preloaded servers
+fn read_preloaded_servers() -> Vec<PreloadedServer> {
+    let Ok(entries) = get_preload_dir().and_then(|dir| fs::read_dir(dir).map_err(|e| e.to_string())) else {
+        return Vec::new();
+    };
+    // Lock and temporary files sit next to the <mode id>.txt entries
+    let mut servers: Vec<PreloadedServer> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "txt"))
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .filter_map(|content| PreloadedServer::parse(&content))
+        .collect();
+    servers.sort_by(|a, b| a.mode_id.cmp(&b.mode_id));
+    servers
+}
+
+/// Writes a preloaded server's registry entry, replacing any earlier one
+fn register_preloaded_server(server: &PreloadedServer) -> Result<(), String> {
+    let path = get_preload_dir()?.join(format!("{}.txt", server.mode_id));
+    update_state_file(&path, |_| Some(server.to_text()))
+}
+
+/// Marks a preloaded server as just used, by rewriting its registry entry
+///
+/// An entry that was removed or now names another server (e.g. the idle
+/// watcher unloaded it meanwhile) is left alone rather than brought back.
+///
+/// # Returns
+/// - Ok(true): The server is still registered, and marked as used
+/// - Ok(false): The server is no longer registered
+fn touch_preloaded_server(server: &PreloadedServer) -> Result<bool, String> {
+    let path = get_preload_dir()?.join(format!("{}.txt", server.mode_id));
+    let mut registered = false;
+    update_state_file(&path, |content| {
+        registered = PreloadedServer::parse(content).map(|current| current.pid == server.pid).unwrap_or(false);
+        if registered { Some(content.to_string()) } else { None }
+    })?;
+    Ok(registered)
+}
+
+/// Removes a preloaded server's registry entry
+fn remove_preloaded_server(mode_id: &str) {
+    if let Ok(dir) = get_preload_dir() {
+        let path = dir.join(format!("{}.txt", mode_id));
+        let _lock = StateFileLock::acquire(&path);
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Seconds since a preloaded server was last used
+fn preloaded_server_idle_secs(mode_id: &str) -> Option<u64> {
+    let path = get_preload_dir().ok()?.join(format!("{}.txt", mode_id));
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(SystemTime::now().duration_since(modified).map(|idle| idle.as_secs()).unwrap_or(0))
+}
+
+/// Keeps a model loaded in RAM for quick answers: `query_gguf preload ...`
+///
+/// - `preload <mode> [--idle-minutes N]` starts llama-server with --mlock
+///   in the background and registers it; a watcher process unloads it
+///   after N unused minutes (default 30, 0 to keep it until stopped)
+/// - `preload list` shows the preloaded servers
+/// - `preload stop <mode>` unloads one
+/// - `preload watch <mode id>` is the watcher itself (started automatically)
+fn handle_preload_command(args: &[String]) -> Result<(), String> {
+    match args.first().map(|arg| arg.as_str()) {
+        None => Err("Use: preload <mode> [--idle-minutes N] | preload list | preload stop <mode>".to_string()),
+        Some("list") => {
+            let servers = read_preloaded_servers();
+            if servers.is_empty() {
+                println!("No preloaded models.");
+            }
+            for server in servers {
+                let state = if is_process_running(server.pid) { "running" } else { "not running" };
+                let idle = preloaded_server_idle_secs(&server.mode_id).unwrap_or(0) / 60;
+                println!("{}  port {}  pid {}  {}  idle {} min  {}",
+                    server.mode_id, server.port, server.pid, state, idle, server.model_path);
+            }
+            Ok(())
+        },
+        Some("stop") => {
+            let selector = args.get(1).ok_or_else(|| "Use: preload stop <mode>".to_string())?;
+            let modes = read_saved_modes()?;
+            let mode_id = find_mode_by_selector(&modes, selector)
+                .map(|mode| mode.id.clone())
+                .unwrap_or_else(|| selector.clone());
+            let server = read_preloaded_server(&mode_id)
+                .ok_or_else(|| format!("Mode '{}' is not preloaded", mode_id))?;
+            if is_process_running(server.pid) {
+                stop_process(server.pid)?;
+            }
+            remove_preloaded_server(&mode_id);
+            println!("Unloaded {}", mode_id);
+            Ok(())
+        },
+        Some("watch") => {
+            let mode_id = args.get(1).ok_or_else(|| "Use: preload watch <mode id>".to_string())?;
+            watch_preloaded_server(mode_id);
+            Ok(())
+        },
+        Some(selector) => {
+            let idle_minutes = match args.iter().position(|arg| arg == "--idle-minutes") {
+                Some(index) => args.get(index + 1)
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .ok_or_else(|| "--idle-minutes needs a number".to_string())?,
+                None => DEFAULT_PRELOAD_IDLE_MINUTES,
+            };
+            let modes = read_saved_modes()?;
+            let mode = find_mode_by_selector(&modes, selector)
+                .ok_or_else(|| format!("Unknown mode: {}", selector))?
+                .clone();
+
+            if let Some(existing) = read_preloaded_server(&mode.id) {
+                if is_process_running(existing.pid) {
+                    touch_preloaded_server(&existing)?;
+                    println!("{} is already preloaded on port {}", mode.id, existing.port);
+                    return Ok(());
+                }
+                remove_preloaded_server(&mode.id);
+            }
+            if let DuplicateLaunchChoice::Attach(_) = check_duplicate_launch(&mode, false)? {
+                return Ok(());
+            }
+
+            // --mlock keeps the model in RAM instead of letting the OS page it out
+            let server = LlamaServer::start_with_args(&mode, &["--mlock"], true)?;
+            let port = server.port;
+            let pid = server.detach();
+            register_preloaded_server(&PreloadedServer {
+                mode_id: mode.id.clone(),
+                model_path: mode.model_path.clone(),
+                port,
+                pid,
+                idle_minutes,
+            })?;
+
+            if idle_minutes > 0 {
+                let exe_path = std::env::current_exe()
+                    .map_err(|e| format!("Failed to find query_gguf itself: {}", e))?;
+                let mut watcher = Command::new(exe_path);
+                watcher.args(["preload", "watch", &mode.id])
+                    .stdin(std::process::Stdio::null())
+                    .stdout(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::null());
+                detach_from_terminal(&mut watcher);
+                if let Err(e) = watcher.spawn() {
+                    println!("Warning: Could not start the idle watcher, the model stays loaded: {}", e);
+                }
+            }
+
+            println!("Preloaded {} [{}] on port {} (pid {}).", mode.name, mode.id, port, pid);
+            if idle_minutes > 0 {
+                println!("It is unloaded after {} unused minutes, or with: query_gguf preload stop {}", idle_minutes, mode.id);
+            } else {
+                println!("Unload it with: query_gguf preload stop {}", mode.id);
+            }
+            Ok(())
+        },
+    }
+}
+
+/// Watches a preloaded server and unloads it once it has been idle too long
+///
+/// Exits when the server is stopped or unregistered some other way.
+fn watch_preloaded_server(mode_id: &str) {
+    let Ok(path) = get_preload_dir().map(|dir| dir.join(format!("{}.txt", mode_id))) else { return };
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(PRELOAD_WATCH_INTERVAL_SECS));
+        // Checked under the entry's lock, so a run marking the server as used is not cut off
+        let Ok(_lock) = StateFileLock::acquire(&path) else { continue };
+        let Some(server) = read_preloaded_server(mode_id) else { return };
+        if !is_process_running(server.pid) {
+            let _ = fs::remove_file(&path);
+            return;
+        }
+        let idle_secs = preloaded_server_idle_secs(mode_id).unwrap_or(0);
+        if server.idle_minutes > 0 && idle_secs >= server.idle_minutes * 60 {
+            let _ = stop_process(server.pid);
+            let _ = fs::remove_file(&path);
+            return;
+        }
+    }
+}
+
+/// Serves a mode's model and answers quick queries in this terminal: `query_gguf server <mode>`
+///
+/// ```text
+/// server 2 [--raw]
+/// ```
+/// llama-server is started with the mode's model and parameters (or the
+/// mode's preloaded server is used), and each line typed is answered on its
+/// own, with the mode's prompt file as system message and no conversation
+/// history (use `chat` for a conversation). While it runs, the server is
+/// registered like a preloaded one, so `--print`, chat, batch, and other
+/// runs of the mode from other terminals use it instead of loading the model,
+/// and its OpenAI-compatible URL is shown for other clients. The server
+/// stops when this command exits.
+fn handle_server_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: server <mode number or id> [--raw]";
+    let selector = args.first().filter(|arg| !arg.starts_with('-')).ok_or_else(|| usage.to_string())?;
+    let raw_output = match args.get(1).map(|arg| arg.as_str()) {
+        None => false,
+        Some("--raw") if args.len() == 2 => true,
+        Some(other) => return Err(format!("Unknown option: {}. {}", other, usage)),
+    };
+    let modes = read_saved_modes()?;
+    let mode = find_mode_by_selector(&modes, selector)
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?
+        .clone();
+    if !mode.endpoint.is_empty() {
+        return Err(format!("Mode '{}' already uses the server {}; use: query_gguf chat {}", mode.id, mode.endpoint, mode.id));
+    }
+
+    let server = start_or_attach_server(&mode)?;
+    // Register a server started here, so other runs of the mode attach to it
+    let registered = match server.child.as_ref().map(|child| child.id()) {
+        Some(pid) => {
+            register_preloaded_server(&PreloadedServer {
+                mode_id: mode.id.clone(),
+                model_path: mode.model_path.clone(),
+                port: server.port,
+                pid,
+                idle_minutes: 0,
+            })?;
+            true
+        },
+        None => false,
+    };
+
+    let system_prompt = fs::read_to_string(&mode.prompt_path).unwrap_or_default();
+    println!("\n=== Server: {} [{}] ===", mode.name, mode.id);
+    println!("OpenAI-compatible API: http://127.0.0.1:{}/v1 (this machine only)", server.port);
+    println!("Type a query; each is answered on its own. /quit stops the server.");
+    let result = (|| -> Result<(), String> {
+        loop {
+            print!("\n> ");
+            io::stdout().flush().map_err(|e| e.to_string())?;
+            let input = read_user_input_with_history("server")?;
+            // End of input (e.g. Ctrl-D)
+            if input.is_empty() {
+                return Ok(());
+            }
+            let query = input.trim();
+            match query {
+                "" => continue,
+                "/q" | "/quit" | "/exit" => return Ok(()),
+                _ => (),
+            }
+
+            let mut messages = Vec::new();
+            if !system_prompt.trim().is_empty() {
+                messages.push(ChatMessage { role: "system".to_string(), content: system_prompt.trim().to_string() });
+            }
+            messages.push(ChatMessage { role: "user".to_string(), content: query.to_string() });
+            let started = std::time::Instant::now();
+            match server.chat(&messages, &mode.parameters, -1, None) {
+                Ok(reply) => {
+                    println!("\n{}", format_reply_for_display(&reply.content, raw_output));
+                    println!("\n({} tokens in {:.1}s)", reply.completion_tokens, started.elapsed().as_secs_f64());
+                },
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+    })();
+
+    if registered {
+        remove_preloaded_server(&mode.id);
+    }
+    result
+}
+
+/// Gets a llama-server for a mode: its preloaded server if there is one,
+/// otherwise a new one, after checking the model is not already running
+fn start_or_attach_server(mode: &ChatModeConfig) -> Result<LlamaServer, String> {
+    if !mode.endpoint.is_empty() {
+        println!("Using the endpoint {}", mode.endpoint);
+        return Ok(LlamaServer::connect(mode));
+    }
+    if let Some(server) = LlamaServer::attach_preloaded(mode) {
+        println!("Using the preloaded llama-server on port {}", server.port);
+        return Ok(server);
+    }
+    match check_duplicate_launch(mode, true)? {
+        DuplicateLaunchChoice::Attach(port) => {
+            let server = LlamaServer::attach(port)?;
+            println!("Attached to llama-server on port {}", port);
+            Ok(server)
+        },
+        DuplicateLaunchChoice::Proceed => LlamaServer::start(mode),
+    }
+}
+
+/// Runs a `--print` generation on a preloaded server (or a mode's endpoint) instead of llama-cli
+///
+/// The rendered prompt is sent as a raw completion, like llama-cli's
+/// conversation-less mode, so the answer comes without a model load.
+fn run_on_preloaded_server(server: &LlamaServer, mode: &ChatModeConfig) -> Result<String, String> {
+    let prompt = fs::read_to_string(&mode.prompt_path)
+        .map_err(|e| format!("Failed to read prompt {}: {}", mode.prompt_path, e))?;
+    match &server.endpoint {
+        Some(endpoint) => eprintln!("(using the endpoint {})", endpoint.url),
+        None => eprintln!("(using the preloaded llama-server on port {})", server.port),
+    }
+    let result = server.complete(&prompt, &mode.parameters, -1)?;
+
+    let timing = SessionTiming {
+        kind: "print".to_string(),
+        mode_id: mode.id.clone(),
+        model_path: mode.model_path.clone(),
+        load_ms: 0.0,
+        first_token_ms: result.prompt_ms,
+        tokens_per_second: if result.predicted_ms > 0.0 {
+            result.tokens_predicted as f64 * 1000.0 / result.predicted_ms
+        } else {
+            0.0
+        },
+        tokens_generated: result.tokens_predicted,
+    };
+    display_session_timing(&timing);
+    if let Err(e) = record_session_timing(&timing) {
+        println!("Warning: {}", e);
+    }
+    Ok(result.content)
+}
+
+/// Finds the llama-server binary for a mode
+///
+/// Uses `llama_server_path` from the config if set, otherwise looks for
+/// llama-server in the same directory as the mode's llama-cli binary.
+fn get_llama_server_path(mode: &ChatModeConfig) -> Result<String, String> {
+    let configured = read_field_from_toml("llama_server_path");
+    if !configured.is_empty() {
+        return Ok(configured);
+    }
+
+    let (llama_cli_path, _) = select_llama_binary(mode)?;
+    let server_name = if cfg!(windows) { "llama-server.exe" } else { "llama-server" };
+    let server_path = Path::new(&llama_cli_path).with_file_name(server_name);
+    if !server_path.exists() {
+        return Err(format!(
+            "llama-server not found at {}. Set llama_server_path in the config.",
+            server_path.display()
+        ));
+    }
+    Ok(server_path.to_string_lossy().to_string())
+}
+
+/// Sets parameters in one mode entry of the config content
+///
+/// Existing name=value parts (or table keys) are replaced; missing ones are
+/// inserted after the prompt path (or at the end of the table). Other parts
+/// (name, description, id...) are kept.
+///
+/// # Arguments
+/// * `content` - Full text of the config file
+/// * `mode_index` - 0-based position of the mode in menu order (sorted by mode number)
+/// * `updates` - (parameter name, new value) pairs
+///
+/// # Returns
+/// - Ok(String): Updated content
+/// - Err(String): No mode at that position
+fn update_mode_entry_parameters(content: &str, mode_index: usize, updates: &[(&str, String)]) -> Result<String, String> {
+    update_mode_entry_parts(content, mode_index, |parts| {
+        for (name, new_value) in updates {
+            let prefix = format!("{}=", name);
+            match parts.iter_mut().find(|part| part.starts_with(&prefix)) {
+                Some(part) => *part = format!("{}{}", prefix, new_value),
+                None => {
+                    let insert_at = parts.len().min(2);
+                    parts.insert(insert_at, format!("{}{}", prefix, new_value));
+                }
+            }
+        }
+    }, |_| updates.iter().map(|(name, new_value)| (name.to_string(), new_value.clone())).collect())
+}
+
+/// Sets the model and/or prompt path of a mode entry, as chosen in `query_gguf edit`
+///
+/// Unlike `update_mode_entry_model_path`, entries that inherit the path with
+/// extends= get one of their own, since the user picked it for this mode.
+fn set_mode_entry_files(content: &str, mode_index: usize, model_path: Option<&str>, prompt_path: Option<&str>) -> Result<String, String> {
+    update_mode_entry_parts(content, mode_index, |parts| {
+        if let Some(model_path) = model_path {
+            parts[0] = model_path.to_string();
+        }
+        if let Some(prompt_path) = prompt_path {
+            // An entry without a prompt has a name=value part (or nothing) second
+            if parts.len() > 1 && !parts[1].contains('=') {
+                parts[1] = prompt_path.to_string();
+            } else {
+                parts.insert(1.min(parts.len()), prompt_path.to_string());
+            }
+        }
+    }, |_| {
+        let mut updates = Vec::new();
+        if let Some(model_path) = model_path {
+            updates.push(("model".to_string(), model_path.to_string()));
+        }
+        if let Some(prompt_path) = prompt_path {
+            updates.push(("prompt".to_string(), prompt_path.to_string()));
+        }
+        updates
+    })
+}
+
+/// The parameters `configure_parameters_interactive` asks for that differ between two sets, as mode settings
+fn changed_parameter_settings(before: &LlamaCppParameters, after: &LlamaCppParameters) -> Vec<(&'static str, String)> {
+    let mut changes = Vec::new();
+    if after.temperature_value != before.temperature_value {
+        changes.push(("temp", after.temperature_value.to_string()));
+    }
+    if after.top_k_sampling != before.top_k_sampling {
+        changes.push(("top_k", after.top_k_sampling.to_string()));
+    }
+    if after.top_p_sampling != before.top_p_sampling {
+        changes.push(("top_p", after.top_p_sampling.to_string()));
+    }
+    if after.context_size != before.context_size {
+        changes.push(("ctx_size", after.context_size.to_string()));
+    }
+    if after.thread_count != before.thread_count {
+        changes.push(("threads", after.thread_count.to_string()));
+    }
+    if after.gpu_layers != before.gpu_layers {
+        changes.push(("gpu_layers", after.gpu_layers.to_string()));
+    }
+    if after.interactive_first != before.interactive_first {
+        changes.push(("interactive_first", after.interactive_first.to_string()));
+    }
+    changes
+}
+
+/// Points a mode entry at a new model file
+///
+/// Entries without a model path of their own (they inherit it with
+/// extends=) are left unchanged.
+///
+/// # Arguments
+/// * `content` - Full text of the config file
+/// * `mode_index` - 0-based position of the mode in menu order (sorted by mode number)
+/// * `model_path` - New model path
+fn update_mode_entry_model_path(content: &str, mode_index: usize, model_path: &str) -> Result<String, String> {
+    update_mode_entry_parts(content, mode_index, |parts| {
+        if !parts[0].trim().is_empty() {
+            parts[0] = model_path.to_string();
+        }
+    }, |table| match table.get_text("model") {
+        Some(model) if !model.trim().is_empty() => vec![("model".to_string(), model_path.to_string())],
+        _ => Vec::new(),
+    })
+}
+
+/// Points a mode entry at a new prompt file
+///
+/// Entries without a prompt path of their own (they inherit it with
+/// extends=, or use the blank prompt) are left unchanged.
+fn update_mode_entry_prompt_path(content: &str, mode_index: usize, prompt_path: &str) -> Result<String, String> {
+    update_mode_entry_parts(content, mode_index, |parts| {
+        if parts.len() > 1 && !parts[1].trim().is_empty() && !parts[1].contains('=') {
+            parts[1] = prompt_path.to_string();
+        }
+    }, |table| match table.get_text("prompt") {
+        Some(prompt) if !prompt.trim().is_empty() => vec![("prompt".to_string(), prompt_path.to_string())],
+        _ => Vec::new(),
+    })
+}
+
+/// Rewrites the mode_index-th mode entry, in menu order (see config_mode_entries)
+///
+/// A `mode_N` string has its |-separated parts rewritten by `update_parts`. A
+/// `[mode.<id>]` table gets the (key, value) pairs `table_updates` returns
+/// set in place, so its comments and other keys are kept.
+fn update_mode_entry_parts(
+    content: &str,
+    mode_index: usize,
+    update_parts: impl FnOnce(&mut Vec<String>),
+    table_updates: impl FnOnce(&toml_lite::Table) -> Vec<(String, String)>,
+) -> Result<String, String> {
+    let mode_lines = mode_string_lines(content);
+    let mut lines: Vec<String> = content.lines().map(|line| line.to_string()).collect();
+    if let Some(&(mode_num, target_line)) = mode_lines.get(mode_index) {
+        let value = lines[target_line].split_once('=')
+            .map(|(_, v)| v.trim().trim_matches('"').to_string())
+            .unwrap_or_default();
+
+        let mut parts: Vec<String> = value.split('|').map(|part| part.to_string()).collect();
+        update_parts(&mut parts);
+
+        lines[target_line] = format!("mode_{} = \"{}\"", mode_num, parts.join("|"));
+    } else {
+        // Table modes follow the mode_N strings
+        let (root, _) = toml_lite::parse(content);
+        let table_entries: Vec<&toml_lite::Entry> = root.get_table("mode")
+            .map(|tables| tables.entries().iter().filter(|entry| entry.value.as_table().is_some()).collect())
+            .unwrap_or_default();
+        let entry = table_entries.get(mode_index - mode_lines.len())
+            .ok_or_else(|| format!("No mode number {} in config", mode_index + 1))?;
+        let table = entry.value.as_table().cloned().unwrap_or_default();
+        for (key, value) in table_updates(&table) {
+            set_table_key_line(&mut lines, entry.line - 1, &key, &format_mode_table_value(&value));
+        }
+    }
+    Ok(join_config_lines(&lines, content))
+}
+
+/// The `mode_N = "..."` lines of the config as (N, 0-based line index), sorted by N
+fn mode_string_lines(content: &str) -> Vec<(usize, usize)> {
+    let mut mode_lines: Vec<(usize, usize)> = content.lines()
+        .enumerate()
+        .take_while(|(_, line)| !line.trim_start().starts_with('['))
+        .filter_map(|(line_index, line)| {
+            let key = line.trim().split('=').next()?.trim();
+            let num = key.strip_prefix("mode_")?.parse::<usize>().ok()?;
+            Some((num, line_index))
+        })
+        .collect();
+    mode_lines.sort_by_key(|(num, _)| *num);
+    mode_lines
+}
+
+/// Joins edited config lines, with a final newline if the original content had one
+fn join_config_lines(lines: &[String], original: &str) -> String {
+    let mut output = lines.join("\n");
+    if original.ends_with('\n') {
+        output.push('\n');
+    }
+    output
+}
+
+/// Returns true for the "# Mode N - name - description" comments written above mode_N lines
+fn is_mode_comment_line(line: &str) -> bool {
+    line.trim().strip_prefix("# Mode ")
+        .map(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+        .unwrap_or(false)
+}
+
+/// Removes the mode_index-th mode entry, in menu order (see config_mode_entries)
+///
+/// A `mode_N` line goes with its "# Mode N - ..." comment, and the
+/// remaining `mode_N` entries are renumbered 1..N, so the numbers in the
+/// file match the menu again. A `[mode.<id>]` table is removed up to the
+/// next table (comments just above that table are kept).
+///
+/// default_mode is removed if it names the removed mode (by `mode_id` or
+/// number), and a number pointing at a later mode is lowered by one.
+fn remove_mode_entry(content: &str, mode_index: usize, mode_id: &str) -> Result<String, String> {
+    let mode_lines = mode_string_lines(content);
+    let mut lines: Vec<String> = content.lines().map(|line| line.to_string()).collect();
+    let mut removed_lines: Vec<usize> = Vec::new();
+
+    if let Some(&(_, target_line)) = mode_lines.get(mode_index) {
+        removed_lines.push(target_line);
+        if target_line > 0 && is_mode_comment_line(&lines[target_line - 1]) {
+            removed_lines.push(target_line - 1);
+        }
+        let remaining = mode_lines.iter().filter(|(_, line_index)| *line_index != target_line);
+        for (position, &(num, line_index)) in remaining.enumerate() {
+            let new_num = position + 1;
+            if new_num == num {
+                continue;
+            }
+            let value = lines[line_index].split_once('=').map(|(_, value)| value.to_string()).unwrap_or_default();
+            lines[line_index] = format!("mode_{} ={}", new_num, value);
+            let old_comment = format!("# Mode {} ", num);
+            if line_index > 0 && lines[line_index - 1].trim_start().starts_with(&old_comment) {
+                lines[line_index - 1] = lines[line_index - 1].replacen(&old_comment, &format!("# Mode {} ", new_num), 1);
+            }
+        }
+    } else {
+        let (root, _) = toml_lite::parse(content);
+        let table_entries: Vec<&toml_lite::Entry> = root.get_table("mode")
+            .map(|tables| tables.entries().iter().filter(|entry| entry.value.as_table().is_some()).collect())
+            .unwrap_or_default();
+        let entry = table_entries.get(mode_index - mode_lines.len())
+            .ok_or_else(|| format!("No mode number {} in config", mode_index + 1))?;
+        let header_index = entry.line - 1;
+        let next_table = (header_index + 1..lines.len())
+            .find(|&index| lines[index].trim_start().starts_with('['))
+            .unwrap_or(lines.len());
+        // Comments and blank lines before the next table belong to it
+        let end = (header_index + 1..next_table).rev()
+            .find(|&index| !lines[index].trim().is_empty() && !lines[index].trim_start().starts_with('#'))
+            .map(|index| index + 1)
+            .unwrap_or(header_index + 1);
+        removed_lines.extend(header_index..end);
+        if header_index > 0 && lines[header_index - 1].trim().is_empty() {
+            removed_lines.push(header_index - 1);
+        }
+    }
+
+    // default_mode only counts before the first [table]
+    let first_table = lines.iter().position(|line| line.trim_start().starts_with('[')).unwrap_or(lines.len());
+    if let Some(default_index) = (0..first_table).find(|&index| lines[index].trim().split('=').next().map(str::trim) == Some("default_mode")) {
+        let value = lines[default_index].split_once('=')
+            .map(|(_, value)| value.trim().trim_matches('"').trim().to_string())
+            .unwrap_or_default();
+        match value.parse::<usize>() {
+            Ok(number) if number == mode_index + 1 => removed_lines.push(default_index),
+            Ok(number) if number > mode_index + 1 => lines[default_index] = format!("default_mode = \"{}\"", number - 1),
+            Ok(_) => (),
+            Err(_) if value == mode_id => removed_lines.push(default_index),
+            Err(_) => (),
+        }
+    }
+
+    let kept: Vec<String> = lines.into_iter()
+        .enumerate()
+        .filter(|(index, _)| !removed_lines.contains(index))
+        .map(|(_, line)| line)
+        .collect();
+    Ok(join_config_lines(&kept, content))
+}
+
+/// Sets the display name of the mode_index-th mode entry
+///
+/// A `mode_N` string without an `id=` part gets `id=<mode_id>`, since its id
+/// would otherwise be derived from the new name, and default_mode, favorites,
+/// and scripts that use the id would lose the mode.
+fn rename_mode_entry(content: &str, mode_index: usize, mode_id: &str, name: &str) -> Result<String, String> {
+    if mode_index < mode_string_lines(content).len() && name.contains('|') {
+        return Err(format!("Names of mode_N entries cannot contain '|': {}", name));
+    }
+    update_mode_entry_parts(content, mode_index, |parts| {
+        // Name and description are the last two parts after model and prompt that are not name=value
+        let text_positions: Vec<usize> = (2..parts.len()).filter(|&index| !parts[index].contains('=')).collect();
+        let name_index = match text_positions.len() {
+            count if count >= 2 => text_positions[count - 2],
+            _ => {
+                parts.extend([String::new(), String::new()]);
+                parts.len() - 2
+            },
+        };
+        parts[name_index] = name.to_string();
+        if !parts.iter().any(|part| part.trim().starts_with("id=")) {
+            parts.insert(name_index, format!("id={}", mode_id));
+        }
+    }, |_| vec![("name".to_string(), name.to_string())])
+}
+
+/// Formats a mode entry as a `[mode.<key>]` table, with its settings as written
+///
+/// Unlike `format_mode_entry`, nothing inherited with extends= is filled in,
+/// so a copy of a mode keeps following its parent. An `id` setting is left
+/// out; the table name is the id.
+fn format_mode_entry_table(key: &str, entry: &ModeEntry) -> String {
+    let quoted = |text: &str| toml_lite::Value::String(text.to_string()).to_string();
+    let mut text = format!("\n[mode.{}]\n", toml_lite::format_key(key));
+    text.push_str(&format!("name = {}\n", quoted(&entry.name)));
+    text.push_str(&format!("description = {}\n", quoted(&entry.description)));
+    for (field, value) in [("model", &entry.model), ("prompt", &entry.prompt)] {
+        if !value.trim().is_empty() {
+            text.push_str(&format!("{} = {}\n", field, quoted(value)));
+        }
+    }
+    for (setting, value) in entry.settings.iter().filter(|(setting, _)| setting != "id") {
+        text.push_str(&format!("{} = {}\n", toml_lite::format_key(setting), format_mode_table_value(value)));
+    }
+    text
+}
+
+/// Sets `key = value` in the table whose header is on line `header_index`
+///
+/// Replaces the key's line if the table has one, otherwise adds the line
+/// after the table's last key (comments just above the next table stay with it).
+fn set_table_key_line(lines: &mut Vec<String>, header_index: usize, key: &str, value: &str) {
+    let end = (header_index + 1..lines.len())
+        .find(|&index| lines[index].trim_start().starts_with('['))
+        .unwrap_or(lines.len());
+    let new_line = format!("{} = {}", key, value);
+    let existing = (header_index + 1..end).find(|&index| {
+        let line = lines[index].trim();
+        !line.starts_with('#') && line.split('=').next().map(|line_key| line_key.trim() == key).unwrap_or(false)
+    });
+    match existing {
+        Some(index) => lines[index] = new_line,
+        None => {
+            let last = (header_index..end).rev()
+                .find(|&index| !lines[index].trim().is_empty() && !lines[index].trim_start().starts_with('#'))
+                .unwrap_or(header_index);
+            lines.insert(last + 1, new_line);
+        },
+    }
+}
+
+/// Formats a mode setting as a TOML value: numbers and booleans as they are, the rest quoted
+fn format_mode_table_value(value: &str) -> String {
+    match toml_lite::parse_value(value) {
+        Ok(parsed @ (toml_lite::Value::Integer(_) | toml_lite::Value::Float(_) | toml_lite::Value::Boolean(_))) => parsed.to_string(),
+        _ => toml_lite::Value::String(value.to_string()).to_string(),
+    }
+}
+
+/// One message of a supervised chat
+#[derive(Debug, Clone)]
+struct ChatMessage {
+    role: String,     // "system", "user", or "assistant"
+    content: String,
+}
+
+/// Result of one chat turn
+#[derive(Debug, Clone, Default)]
+struct ChatResult {
+    content: String,
+    prompt_tokens: u64,      // tokens of the whole conversation sent to the model
+    completion_tokens: u64,  // tokens of the new reply
+    tokens_per_second: f64,  // generation speed reported by llama-server, 0 if not reported
+}
+
+/// Percentage of the context window at which the chat starts warning
+const CONTEXT_WARN_PERCENT: u64 = 80;
+
+/// Formats the context window usage meter shown after each chat turn
+///
+/// e.g. `[context ████████░░░░░░░░░░░░ 1650/4096 tokens (40%)]`
+fn format_context_meter(used_tokens: u64, context_size: u64) -> String {
+    let width = 20;
+    let percent = (used_tokens * 100).checked_div(context_size).unwrap_or(0).min(100);
+    let filled = (percent as usize * width / 100).min(width);
+    format!(
+        "[context {}{} {}/{} tokens ({}%)]",
+        "█".repeat(filled),
+        "░".repeat(width - filled),
+        used_tokens,
+        context_size,
+        percent
+    )
+}
+
+/// Returns a warning when context usage reaches CONTEXT_WARN_PERCENT, None otherwise
+fn context_usage_warning(used_tokens: u64, context_size: u64) -> Option<String> {
+    if context_size == 0 {
+        return None;
+    }
+    let percent = used_tokens * 100 / context_size;
+    if percent >= 100 {
+        Some("Warning: the context window is full; the oldest parts of the conversation no longer fit.".to_string())
+    } else if percent >= CONTEXT_WARN_PERCENT {
+        Some(format!(
+            "Warning: {}% of the context window is used. Use /clear to start over, or raise ctx_size for this mode.",
+            percent
+        ))
+    } else {
+        None
+    }
+}
+
+/// Number of most recent messages kept word for word when compacting a chat
+const COMPACTION_KEEP_RECENT_MESSAGES: usize = 4;
+
+/// Splits chat messages into (messages to summarize, messages to keep) for compaction
+///
+/// System messages at the start are never summarized (they are returned
+/// with the kept messages, in place). The newest `keep_recent` messages are
+/// kept as they are.
+///
+/// # Returns
+/// * `Option<(Vec<ChatMessage>, Vec<ChatMessage>)>` - None if there is nothing old enough to summarize
+fn split_for_compaction(messages: &[ChatMessage], keep_recent: usize) -> Option<(Vec<ChatMessage>, Vec<ChatMessage>)> {
+    let leading_system = messages.iter().take_while(|m| m.role == "system").count();
+    let conversation = &messages[leading_system..];
+    if conversation.len() <= keep_recent {
+        return None;
+    }
+
+    let split_at = conversation.len() - keep_recent;
+    let to_summarize = conversation[..split_at].to_vec();
+    let mut kept = messages[..leading_system].to_vec();
+    kept.extend_from_slice(&conversation[split_at..]);
+    Some((to_summarize, kept))
+}
+
+/// Builds the request asking a model to summarize old chat turns
+fn build_summary_request(to_summarize: &[ChatMessage]) -> Vec<ChatMessage> {
+    let transcript: Vec<String> = to_summarize.iter()
+        .map(|message| format!("{}: {}", message.role, message.content.trim()))
+        .collect();
+    vec![ChatMessage {
+        role: "user".to_string(),
+        content: format!(
+            "Summarize the following conversation so it can replace it as context for continuing the chat. \
+             Keep facts, decisions, names, numbers, and open questions. Be concise.\n\n{}",
+            transcript.join("\n\n")
+        ),
+    }]
+}
+
+/// First line of the system message that replaces compacted chat turns
+const COMPACTION_SUMMARY_HEADER: &str = "Summary of the earlier conversation:";
+
+/// Replaces the summarized messages with one system message holding the summary
+///
+/// The summary goes right after the leading system messages of `kept`.
+fn insert_compaction_summary(kept: Vec<ChatMessage>, summary: &str) -> Vec<ChatMessage> {
+    let leading_system = kept.iter().take_while(|m| m.role == "system").count();
+    let mut compacted = kept[..leading_system].to_vec();
+    compacted.push(ChatMessage {
+        role: "system".to_string(),
+        content: format!("{}\n{}", COMPACTION_SUMMARY_HEADER, summary.trim()),
+    });
+    compacted.extend_from_slice(&kept[leading_system..]);
+    compacted
+}
+
+/// What a chat's context window holds, for the /context command
+struct ContextReport<'a> {
+    messages: &'a [ChatMessage],
+    prompt_file: Option<(&'a str, u64)>, // the mode's prompt file (system prompt) and its size
+    summarized_messages: usize,          // messages replaced by compaction summaries
+    used_tokens: u64,                    // tokens the server reported for the last turn, 0 before the first
+    context_size: u64,
+}
+
+/// Formats the /context report: system prompt, files, turns with token counts, and what was compacted
+///
+/// Per-message token counts are estimates (`estimate_token_count`); the
+/// meter uses the count llama-server reported for the last turn.
+fn format_context_report(report: &ContextReport) -> String {
+    let mut lines = vec!["=== Context window ===".to_string()];
+    if report.used_tokens > 0 {
+        lines.push(format!("{} as of the last reply", format_context_meter(report.used_tokens, report.context_size)));
+    }
+    let estimated_total: usize = report.messages.iter().map(|message| estimate_token_count(&message.content)).sum();
+    lines.push(format!("Estimated now: ~{} tokens of {}", estimated_total, report.context_size));
+
+    let summaries: Vec<&ChatMessage> = report.messages.iter()
+        .filter(|message| message.role == "system" && message.content.starts_with(COMPACTION_SUMMARY_HEADER))
+        .collect();
+    let system_prompt = report.messages.iter()
+        .find(|message| message.role == "system" && !message.content.starts_with(COMPACTION_SUMMARY_HEADER));
+
+    lines.push(String::new());
+    match system_prompt {
+        Some(message) => lines.push(format!(
+            "System prompt: ~{} tokens: {}",
+            estimate_token_count(&message.content),
+            one_line_preview(&message.content, 60)
+        )),
+        None => lines.push("System prompt: none".to_string()),
+    }
+    match report.prompt_file {
+        Some((path, size)) => lines.push(format!("Files: {} ({})", path, format_bytes(size))),
+        None => lines.push("Files: none".to_string()),
+    }
+
+    lines.push(String::new());
+    let turns: Vec<&ChatMessage> = report.messages.iter().filter(|message| message.role != "system").collect();
+    if turns.is_empty() {
+        lines.push("Turns: none yet".to_string());
+    } else {
+        lines.push(format!("Turns ({}):", turns.len()));
+        for (index, message) in turns.iter().enumerate() {
+            lines.push(format!(
+                "  {:>3}. {:<9} ~{:>5} tokens  {}",
+                index + 1,
+                message.role,
+                estimate_token_count(&message.content),
+                one_line_preview(&message.content, 50)
+            ));
+        }
+    }
+
+    lines.push(String::new());
+    if summaries.is_empty() {
+        lines.push("Compacted: nothing (all turns are in the window word for word)".to_string());
+    } else {
+        let summary_tokens: usize = summaries.iter().map(|message| estimate_token_count(&message.content)).sum();
+        lines.push(format!(
+            "Compacted: {} earlier message(s) replaced by {} summary message(s), ~{} tokens (full text is kept for the log)",
+            report.summarized_messages, summaries.len(), summary_tokens
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Gets the absolute path to the chat log directory and ensures it exists
+///
+/// Uses log_directory_path from the config (relative paths are relative to
+/// the home directory), or ~/query_gguf/chatlogs/ if it is not set.
+fn get_chat_log_dir() -> Result<PathBuf, String> {
+    let configured = read_field_from_toml("log_directory_path");
+    let log_dir = if configured.is_empty() {
+        get_app_base_dir()?.join("chatlogs")
+    } else if Path::new(&configured).is_absolute() {
+        PathBuf::from(configured)
+    } else {
+        PathBuf::from(get_home_dir()?).join(configured)
+    };
+    fs::create_dir_all(&log_dir)
+        .map_err(|e| format!("Failed to create log directory {}: {}", log_dir.display(), e))?;
+    Ok(log_dir)
+}
+
+/// Bytes hashed from each end of a model file for its fingerprint
+const MODEL_FINGERPRINT_SAMPLE_BYTES: u64 = 1024 * 1024;
+
+/// Fingerprints a model file without reading all of it
+///
+/// Hashes the file size with the first and last MiB (FNV-1a). Model files
+/// are many GB, and different quantizations or versions differ in header
+/// metadata and size, so this tells them apart in a fraction of a second.
+fn model_fingerprint(path: &Path) -> Result<String, String> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = File::open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let size = file.metadata().map_err(|e| e.to_string())?.len();
+
+    let mut sample = size.to_le_bytes().to_vec();
+    let mut head = Vec::new();
+    (&mut file).take(MODEL_FINGERPRINT_SAMPLE_BYTES).read_to_end(&mut head).map_err(|e| e.to_string())?;
+    sample.extend_from_slice(&head);
+    if size > MODEL_FINGERPRINT_SAMPLE_BYTES * 2 {
+        let mut tail = Vec::new();
+        file.seek(SeekFrom::End(-(MODEL_FINGERPRINT_SAMPLE_BYTES as i64))).map_err(|e| e.to_string())?;
+        file.read_to_end(&mut tail).map_err(|e| e.to_string())?;
+        sample.extend_from_slice(&tail);
+    }
+    Ok(format!("fnv1a:{:016x}", fnv1a_hash(&sample)))
+}
+
+/// Formats the resolved mode of a session, so its results can be reproduced later
+///
+/// Includes the mode as a config entry (after `extends` is applied), the
+/// model fingerprint, and the prompt file's hash and contents, so a later
+/// edit of the mode or prompt does not change what the log says was used.
+fn format_mode_snapshot(mode: &ChatModeConfig, model_fingerprint: &str, prompt_text: &str) -> String {
+    format!(
+        "### session\n{}\nmodel_fingerprint = \"{}\"\nprompt_hash = \"fnv1a:{:016x}\"\n\n### prompt file\n{}\n",
+        format_mode_entry(mode).trim(),
+        model_fingerprint,
+        fnv1a_hash(prompt_text.as_bytes()),
+        prompt_text.trim()
+    )
+}
+
+/// Writes chat messages as a plain text transcript, after a session header (may be empty)
+fn write_chat_transcript(path: &Path, header: &str, messages: &[ChatMessage]) -> Result<(), String> {
+    let mut transcript: Vec<String> = Vec::new();
+    if !header.is_empty() {
+        transcript.push(header.to_string());
+    }
+    transcript.extend(messages.iter()
+        .map(|message| format!("### {}\n{}\n", message.role, message.content.trim())));
+    fs::write(path, transcript.join("\n"))
+        .map_err(|e| format!("Failed to write chat log {}: {}", path.display(), e))
+}
+
+/// Gets the live session log directory and ensures it exists
+///
+/// Location: ~/query_gguf/sessions/
+fn get_sessions_dir() -> Result<PathBuf, String> {
+    let sessions_dir = get_app_base_dir()?.join("sessions");
+    fs::create_dir_all(&sessions_dir)
+        .map_err(|e| format!("Failed to create {}: {}", sessions_dir.display(), e))?;
+    Ok(sessions_dir)
+}
+
+/// Finished session logs kept in the sessions directory; older ones are removed
+const MAX_SESSION_LOGS: usize = 50;
+
+/// Last line of a session log whose session ended normally
+const SESSION_LOG_END_MARKER: &str = "[[end]]";
+
+/// Current time of day (UTC) as HH:MM:SS, for session log markers
+fn session_clock() -> String {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0) % 86_400;
+    format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Live log of a running session (chat, agent, batch), followed with `query_gguf logs -f`
+///
+/// The log is appended to as the session goes, unlike the transcript that
+/// is written at its end:
+/// ```text
+/// [[session kind=chat mode=coder pid=4242]]
+/// [[user 10:00:01]]
+/// what is a borrow?
+/// [[assistant 10:00:04]]
+/// A borrow is ...
+/// [[end]]
+/// ```
+/// Logging never stops a session: if the log cannot be written, a warning
+/// is printed once and the session goes on without it.
+struct SessionLog {
+    file: Option<File>,
+}
+
+impl SessionLog {
+    /// Starts the log of a new session, e.g. ~/query_gguf/sessions/chat_coder_<timestamp>.log
+    fn start(kind: &str, mode_id: &str) -> SessionLog {
+        let opened = get_sessions_dir().and_then(|sessions_dir| {
+            prune_session_logs(&sessions_dir);
+            let path = sessions_dir.join(format!("{}_{}_{}.log", kind, mode_id, generate_timestamp_string()));
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| format!("Failed to create session log {}: {}", path.display(), e))?;
+            writeln!(file, "[[session kind={} mode={} pid={}]]", kind, mode_id, std::process::id())
+                .map_err(|e| format!("Failed to write session log {}: {}", path.display(), e))?;
+            Ok(file)
+        });
+        match opened {
+            Ok(file) => SessionLog { file: Some(file) },
+            Err(e) => {
+                println!("Warning: {} (no live log for `query_gguf logs`)", e);
+                SessionLog { file: None }
+            },
+        }
+    }
+
+    /// Appends a message, e.g. role "user", "assistant", "tool", "info", or "error"
+    fn append(&self, role: &str, text: &str) {
+        if let Some(mut file) = self.file.as_ref() {
+            let _ = write!(file, "[[{} {}]]\n{}\n", role, session_clock(), text.trim_end());
+        }
+    }
+}
+
+impl Drop for SessionLog {
+    fn drop(&mut self) {
+        if let Some(mut file) = self.file.as_ref() {
+            let _ = writeln!(file, "{}", SESSION_LOG_END_MARKER);
+        }
+    }
+}
+
+/// What the marker lines of a session log say about it
+#[derive(Debug, Clone, Default, PartialEq)]
+struct SessionLogStatus {
+    kind: String,
+    mode_id: String,
+    pid: Option<u32>,
+    ended: bool,
+}
+
+/// Reads the kind, mode, process, and end of a session log
+fn parse_session_log_status(content: &str) -> SessionLogStatus {
+    let mut status = SessionLogStatus::default();
+    for line in content.lines() {
+        if let Some(fields) = line.strip_prefix("[[session ").and_then(|rest| rest.strip_suffix("]]")) {
+            for (key, value) in fields.split_whitespace().filter_map(|field| field.split_once('=')) {
+                match key {
+                    "kind" => status.kind = value.to_string(),
+                    "mode" => status.mode_id = value.to_string(),
+                    "pid" => status.pid = value.parse().ok(),
+                    _ => (),
+                }
+            }
+        } else if let Some(pid) = line.strip_prefix("[[pid ").and_then(|rest| rest.strip_suffix("]]")) {
+            // Server logs learn the pid after llama-server has started writing to them
+            status.pid = pid.trim().parse().ok();
+        } else if line == SESSION_LOG_END_MARKER {
+            status.ended = true;
+        }
+    }
+    status
+}
+
+/// Checks whether a session log's session is still running
+fn is_session_running(status: &SessionLogStatus) -> bool {
+    !status.ended && status.pid.map(is_process_running).unwrap_or(false)
+}
+
+/// Removes the oldest finished session logs beyond MAX_SESSION_LOGS
+fn prune_session_logs(sessions_dir: &Path) {
+    let mut finished: Vec<(SystemTime, PathBuf)> = list_session_logs(sessions_dir)
+        .into_iter()
+        .filter(|(_, status, _)| !is_session_running(status))
+        .filter_map(|(path, _, _)| Some((fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()?, path)))
+        .collect();
+    if finished.len() < MAX_SESSION_LOGS {
+        return;
+    }
+    finished.sort();
+    for (_, path) in &finished[..finished.len() + 1 - MAX_SESSION_LOGS] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Lists the session logs, newest first: (path, status, name without .log)
+fn list_session_logs(sessions_dir: &Path) -> Vec<(PathBuf, SessionLogStatus, String)> {
+    let Ok(entries) = fs::read_dir(sessions_dir) else {
+        return Vec::new();
+    };
+    let mut logs: Vec<(PathBuf, SessionLogStatus, String)> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().and_then(|extension| extension.to_str()) == Some("log"))
+        .filter_map(|path| {
+            let name = path.file_stem()?.to_str()?.to_string();
+            let status = parse_session_log_status(&fs::read_to_string(&path).ok()?);
+            Some((path, status, name))
+        })
+        .collect();
+    // Names end with a timestamp, but start with the kind and mode: sort by time written
+    logs.sort_by_key(|(path, _, _)| std::cmp::Reverse(fs::metadata(path).and_then(|metadata| metadata.modified()).ok()));
+    logs
+}
+
+/// Style of each role's lines in `logs` output
+fn session_role_style(role: &str) -> &'static str {
+    match role {
+        "user" => STYLE_CODE,
+        "assistant" => STYLE_STRING,
+        "tool" => STYLE_NUMBER,
+        "error" => STYLE_REMOVED,
+        _ => STYLE_COMMENT, // info, stderr (server logs), system
+    }
+}
+
+/// Renders one session log line for the terminal
+///
+/// Marker lines become headings; other lines are styled by the role of the
+/// message they belong to (`role` is updated as markers go by).
+///
+/// # Returns
+/// * `Option<String>` - The line to print, None for lines not shown ([[pid N]])
+fn render_session_log_line(line: &str, role: &mut String, color: bool) -> Option<String> {
+    let style = |text: String, style: &str| if color { format!("{}{}{}", style, text, STYLE_RESET) } else { text };
+    if line.starts_with("[[pid ") && line.ends_with("]]") {
+        return None;
+    }
+    if line == SESSION_LOG_END_MARKER {
+        return Some(style("=== session ended ===".to_string(), STYLE_BOLD));
+    }
+    if let Some(fields) = line.strip_prefix("[[session ").and_then(|rest| rest.strip_suffix("]]")) {
+        return Some(style(format!("=== {} ===", fields), STYLE_BOLD));
+    }
+    if let Some(marker) = line.strip_prefix("[[").and_then(|rest| rest.strip_suffix("]]")) {
+        let (marker_role, clock) = marker.split_once(' ').unwrap_or((marker, ""));
+        *role = marker_role.to_string();
+        return Some(style(format!("--- {} {} ---", marker_role, clock), STYLE_BOLD));
+    }
+    Some(style(line.to_string(), session_role_style(role)))
+}
+
+/// Finds a session log by name, or by part of its name (most recent match)
+fn find_session_log(sessions_dir: &Path, selector: &str) -> Result<(PathBuf, SessionLogStatus, String), String> {
+    let logs = list_session_logs(sessions_dir);
+    logs.iter()
+        .find(|(_, _, name)| name == selector)
+        .or_else(|| logs.iter().find(|(_, _, name)| name.contains(selector)))
+        .cloned()
+        .ok_or_else(|| format!("No session log matches '{}' (see: query_gguf logs)", selector))
+}
+
+/// Shows live session logs: `query_gguf logs [-f] [session]`
+///
+/// ```text
+/// logs                  list session logs (running ones first)
+/// logs chat_coder       print the most recent log whose name contains "chat_coder"
+/// logs -f [session]     follow a log as it grows, until its session ends
+///                       (without a session: the most recent running one)
+/// ```
+/// Chat, agent, and batch sessions write logs, and so do preloaded
+/// llama-servers (their stderr), so a long run can be watched from another
+/// terminal without attaching to it. Lines are colored by role.
+fn handle_logs_command(args: &[String]) -> Result<(), String> {
+    use std::io::{IsTerminal, Read, Seek};
+    let usage = "Use: logs [-f] [session]";
+    let follow = args.iter().any(|arg| arg == "-f" || arg == "--follow");
+    let selectors: Vec<&String> = args.iter().filter(|arg| *arg != "-f" && *arg != "--follow").collect();
+    if selectors.len() > 1 || selectors.iter().any(|selector| selector.starts_with('-')) {
+        return Err(usage.to_string());
+    }
+    let sessions_dir = get_sessions_dir()?;
+
+    let (path, status, name) = match (selectors.first(), follow) {
+        (Some(selector), _) => find_session_log(&sessions_dir, selector)?,
+        (None, true) => list_session_logs(&sessions_dir)
+            .into_iter()
+            .find(|(_, status, _)| is_session_running(status))
+            .ok_or_else(|| "No running session to follow (see: query_gguf logs)".to_string())?,
+        (None, false) => {
+            let mut logs = list_session_logs(&sessions_dir);
+            if logs.is_empty() {
+                println!("No session logs yet (chat, agent, batch, and preload write them).");
+                return Ok(());
+            }
+            logs.sort_by_key(|(_, status, _)| !is_session_running(status));
+            println!("Session logs in {}:", sessions_dir.display());
+            for (path, status, name) in &logs {
+                let size = fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+                let state = if is_session_running(status) { "running" } else { "ended" };
+                println!("  {:<45} {:<8} {}", name, state, format_bytes(size));
+            }
+            return Ok(());
+        },
+    };
+
+    let color = io::stdout().is_terminal();
+    let mut role = String::from("info");
+    let mut file = File::open(&path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    if !follow {
+        let mut content = String::new();
+        file.read_to_string(&mut content).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        for line in content.lines() {
+            if let Some(rendered) = render_session_log_line(line, &mut role, color) {
+                println!("{}", rendered);
+            }
+        }
+        return Ok(());
+    }
+
+    if !is_session_running(&status) {
+        println!("Session {} has ended; showing its log.", name);
+    }
+    let mut pending = Vec::new(); // bytes of a line not yet complete
+    loop {
+        let mut chunk = Vec::new();
+        file.read_to_end(&mut chunk).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        if chunk.is_empty() {
+            // Check only after the log is read up to its end, so nothing written last is missed
+            let status = parse_session_log_status(&fs::read_to_string(&path).unwrap_or_default());
+            if !is_session_running(&status) {
+                if !status.ended {
+                    println!("(session process is gone)");
+                }
+                return Ok(());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            // A log replaced by a shorter file (e.g. pruned and recreated) is read from its start
+            let position = file.stream_position().unwrap_or(0);
+            if fs::metadata(&path).map(|metadata| metadata.len() < position).unwrap_or(false) {
+                file.seek(std::io::SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+            }
+            continue;
+        }
+        pending.extend(chunk);
+        while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]);
+            if let Some(rendered) = render_session_log_line(line.trim_end_matches('\r'), &mut role, color) {
+                println!("{}", rendered);
+            }
+        }
+        io::stdout().flush().map_err(|e| e.to_string())?;
+    }
+}
+
+/// File in the chat log directory holding the `history search` index
+const HISTORY_INDEX_FILE: &str = ".history_index.tsv";
+
+/// Most results shown by `history search`
+const HISTORY_SEARCH_MAX_RESULTS: usize = 10;
+
+/// Inverted index over chat transcripts: word -> (transcript, count)
+///
+/// Stored as tab-separated lines:
+/// ```text
+/// doc<TAB>0<TAB>chat_coder_2025_01_02_10_00_00.txt
+/// term<TAB>borrow<TAB>0:3,4:1
+/// ```
+#[derive(Debug, Default, PartialEq)]
+struct HistoryIndex {
+    documents: Vec<String>,                                          // transcript file names
+    postings: std::collections::BTreeMap<String, Vec<(usize, u32)>>, // word -> (document, count)
+}
+
+impl HistoryIndex {
+    /// Reads an index from its text form; malformed lines are skipped
+    fn parse(content: &str) -> HistoryIndex {
+        let mut index = HistoryIndex::default();
+        for line in content.lines() {
+            let fields: Vec<&str> = line.splitn(3, '\t').collect();
+            match fields.as_slice() {
+                ["doc", _, name] => index.documents.push(name.to_string()),
+                ["term", word, postings] => {
+                    let parsed = postings.split(',')
+                        .filter_map(|posting| {
+                            let (document, count) = posting.split_once(':')?;
+                            Some((document.parse().ok()?, count.parse().ok()?))
+                        })
+                        .collect();
+                    index.postings.insert(word.to_string(), parsed);
+                },
+                _ => {},
+            }
+        }
+        index
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (number, name) in self.documents.iter().enumerate() {
+            text.push_str(&format!("doc\t{}\t{}\n", number, name));
+        }
+        for (word, postings) in &self.postings {
+            let postings: Vec<String> = postings.iter()
+                .map(|(document, count)| format!("{}:{}", document, count))
+                .collect();
+            text.push_str(&format!("term\t{}\t{}\n", word, postings.join(",")));
+        }
+        text
+    }
+
+    /// Adds a transcript's words to the index
+    fn add_document(&mut self, name: &str, text: &str) {
+        let document = self.documents.len();
+        self.documents.push(name.to_string());
+
+        let mut counts: std::collections::BTreeMap<String, u32> = std::collections::BTreeMap::new();
+        for word in tokenize_for_search(text) {
+            *counts.entry(word).or_insert(0) += 1;
+        }
+        for (word, count) in counts {
+            self.postings.entry(word).or_default().push((document, count));
+        }
+    }
+
+    /// Ranks transcripts for a query (TF-IDF; transcripts with every word first)
+    ///
+    /// # Returns
+    /// (document number, score) pairs, best first
+    fn search(&self, query: &str) -> Vec<(usize, f64)> {
+        let words = tokenize_for_search(query);
+        let document_count = self.documents.len().max(1) as f64;
+        let mut scores: std::collections::BTreeMap<usize, (usize, f64)> = std::collections::BTreeMap::new();
+
+        for word in &words {
+            let Some(postings) = self.postings.get(word) else { continue };
+            let idf = (document_count / postings.len() as f64).ln() + 1.0;
+            for (document, count) in postings {
+                let entry = scores.entry(*document).or_insert((0, 0.0));
+                entry.0 += 1;
+                entry.1 += (1.0 + (*count as f64).ln()) * idf;
+            }
+        }
+
+        let mut ranked: Vec<(usize, usize, f64)> = scores.into_iter()
+            .map(|(document, (matched, score))| (document, matched, score))
+            .collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then(b.2.total_cmp(&a.2)));
+        ranked.into_iter().map(|(document, _, score)| (document, score)).collect()
+    }
+}
+
+/// Splits text into lowercase words for the history index (2+ letters or digits)
+fn tokenize_for_search(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 2)
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// Finds the first line of a transcript containing a query word, shortened for display
+fn make_search_snippet(text: &str, query: &str) -> String {
+    let words = tokenize_for_search(query);
+    let line = text.lines()
+        .find(|line| {
+            let line_words = tokenize_for_search(line);
+            words.iter().any(|word| line_words.contains(word))
+        })
+        .unwrap_or("")
+        .trim();
+    if line.chars().count() > 120 {
+        format!("{}...", line.chars().take(117).collect::<String>())
+    } else {
+        line.to_string()
+    }
+}
+
+/// Adds transcripts that are not indexed yet to the history index
+///
+/// Only new files are read, so keeping the index current after each
+/// session is cheap. Compacted copies are skipped (the full log has the
+/// same text). Returns the number of transcripts added.
+fn update_history_index() -> Result<usize, String> {
+    let log_dir = get_chat_log_dir()?;
+    let index_path = log_dir.join(HISTORY_INDEX_FILE);
+    // Held while indexing, so two sessions ending together do not both add the same files
+    let _lock = StateFileLock::acquire(&index_path)?;
+    let mut index = HistoryIndex::parse(&fs::read_to_string(&index_path).unwrap_or_default());
+
+    let mut new_files: Vec<String> = fs::read_dir(&log_dir)
+        .map_err(|e| format!("Failed to read {}: {}", log_dir.display(), e))?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".txt") && !name.ends_with("_compacted.txt"))
+        .filter(|name| !index.documents.contains(name))
+        .collect();
+    new_files.sort();
+
+    for name in &new_files {
+        if let Ok(text) = fs::read_to_string(log_dir.join(name)) {
+            index.add_document(name, &text);
+        }
+    }
+    if !new_files.is_empty() || !index_path.exists() {
+        write_file_atomically(&index_path, &index.to_text())?;
+    }
+    Ok(new_files.len())
+}
+
+/// Section headers written by `format_mode_snapshot` and `write_chat_transcript`
+///
+/// Only these start a section, so markdown headings in replies do not.
+const TRANSCRIPT_SECTIONS: &[&str] = &["session", "prompt file", "system", "user", "assistant"];
+
+/// Most lines compared at once by `diff_sequences` before it gives up on
+/// finding common lines (the table is this squared)
+const MAX_DIFF_LINES: usize = 2000;
+
+/// Splits a chat transcript into its sections, e.g. ("user", "Why...?")
+fn parse_transcript_sections(text: &str) -> Vec<(String, String)> {
+    let mut sections: Vec<(String, String)> = Vec::new();
+    for line in text.lines() {
+        match line.strip_prefix("### ").filter(|name| TRANSCRIPT_SECTIONS.contains(name)) {
+            Some(name) => sections.push((name.to_string(), String::new())),
+            None => if let Some((_, content)) = sections.last_mut() {
+                content.push_str(line);
+                content.push('\n');
+            },
+        }
+    }
+    for (_, content) in sections.iter_mut() {
+        *content = content.trim().to_string();
+    }
+    sections
+}
+
+/// The (question, response) pairs of a transcript, in order
+///
+/// Logs without chat sections (e.g. llama-cli session logs) are one
+/// response with no question.
+fn transcript_exchanges(text: &str) -> Vec<(String, String)> {
+    let sections = parse_transcript_sections(text);
+    if !sections.iter().any(|(role, _)| role == "assistant") {
+        return vec![(String::new(), text.trim().to_string())];
+    }
+    let mut question = String::new();
+    let mut exchanges = Vec::new();
+    for (role, content) in sections {
+        match role.as_str() {
+            "user" => question = content,
+            "assistant" => exchanges.push((std::mem::take(&mut question), content)),
+            _ => (),
+        }
+    }
+    exchanges
+}
+
+/// One step of a diff
+#[derive(Debug, Clone, PartialEq)]
+enum DiffOp<T> {
+    Same(T),    // in both
+    Removed(T), // only in the first
+    Added(T),   // only in the second
+}
+
+/// Diffs two sequences by their longest common subsequence
+///
+/// The common start and end are matched first. If what is left is longer
+/// than MAX_DIFF_LINES, it is shown as all removed then all added.
+fn diff_sequences<T: PartialEq + Clone>(old: &[T], new: &[T]) -> Vec<DiffOp<T>> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_middle, new_middle) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    let mut ops: Vec<DiffOp<T>> = old[..prefix].iter().cloned().map(DiffOp::Same).collect();
+    if old_middle.len().max(new_middle.len()) > MAX_DIFF_LINES {
+        ops.extend(old_middle.iter().cloned().map(DiffOp::Removed));
+        ops.extend(new_middle.iter().cloned().map(DiffOp::Added));
+    } else {
+        // lengths[i][j]: longest common subsequence of old_middle[i..] and new_middle[j..]
+        let (n, m) = (old_middle.len(), new_middle.len());
+        let mut lengths = vec![vec![0u32; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lengths[i][j] = if old_middle[i] == new_middle[j] {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    lengths[i + 1][j].max(lengths[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && old_middle[i] == new_middle[j] {
+                ops.push(DiffOp::Same(old_middle[i].clone()));
+                i += 1;
+                j += 1;
+            } else if j == m || (i < n && lengths[i + 1][j] >= lengths[i][j + 1]) {
+                ops.push(DiffOp::Removed(old_middle[i].clone()));
+                i += 1;
+            } else {
+                ops.push(DiffOp::Added(new_middle[j].clone()));
+                j += 1;
+            }
+        }
+    }
+    ops.extend(old[old.len() - suffix..].iter().cloned().map(DiffOp::Same));
+    ops
+}
+
+/// Splits text into words and the whitespace between them (joined, they give the text back)
+fn split_words_for_diff(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    for c in text.chars() {
+        match tokens.last_mut() {
+            Some(token) if token.chars().all(char::is_whitespace) == c.is_whitespace() => token.push(c),
+            _ => tokens.push(c.to_string()),
+        }
+    }
+    tokens
+}
+
+/// Joins neighbouring words of the same kind, so a changed phrase is highlighted as one
+fn merge_word_diff(ops: Vec<DiffOp<String>>) -> Vec<DiffOp<String>> {
+    let mut merged: Vec<DiffOp<String>> = Vec::new();
+    for op in ops {
+        match (merged.last_mut(), op) {
+            (Some(DiffOp::Same(text)), DiffOp::Same(word))
+            | (Some(DiffOp::Removed(text)), DiffOp::Removed(word))
+            | (Some(DiffOp::Added(text)), DiffOp::Added(word)) => text.push_str(&word),
+            (_, op) => merged.push(op),
+        }
+    }
+    merged
+}
+
+/// Groups a line diff into runs of equal lines and changed hunks
+///
+/// # Returns
+/// (equal lines, removed lines, added lines) steps; a step has either
+/// equal lines or a hunk of removed and/or added lines
+fn group_line_diff(ops: Vec<DiffOp<String>>) -> Vec<(Vec<String>, Vec<String>, Vec<String>)> {
+    let mut steps: Vec<(Vec<String>, Vec<String>, Vec<String>)> = Vec::new();
+    for op in ops {
+        let in_hunk = matches!(steps.last(), Some((same, _, _)) if same.is_empty());
+        match op {
+            DiffOp::Same(line) => match steps.last_mut() {
+                Some((same, removed, added)) if removed.is_empty() && added.is_empty() => same.push(line),
+                _ => steps.push((vec![line], Vec::new(), Vec::new())),
+            },
+            DiffOp::Removed(line) => match steps.last_mut() {
+                // A removal after additions starts a new hunk, so hunks read removed-then-added
+                Some((_, removed, added)) if in_hunk && added.is_empty() => removed.push(line),
+                _ => steps.push((Vec::new(), vec![line], Vec::new())),
+            },
+            DiffOp::Added(line) => match steps.last_mut() {
+                Some((_, _, added)) if in_hunk => added.push(line),
+                _ => steps.push((Vec::new(), Vec::new(), vec![line])),
+            },
+        }
+    }
+    steps
+}
+
+/// Highlights one diff token: colored, or marked [-removed-] / {+added+} without color
+fn highlight_diff_token(token: &str, op_style: &str, color: bool) -> String {
+    match (color, op_style) {
+        (true, style) => format!("{}{}{}", style, token, STYLE_RESET),
+        (false, style) if style == STYLE_REMOVED => format!("[-{}-]", token),
+        (false, _) => format!("{{+{}+}}", token),
+    }
+}
+
+/// Unified word diff of two texts, like `git diff --word-diff`
+///
+/// Lines are compared first; within changed lines, removed words are
+/// shown in red (or [-like this-]) and added words in green ({+like this+}).
+fn render_unified_word_diff(old: &str, new: &str, color: bool) -> String {
+    let old_lines: Vec<String> = old.lines().map(str::to_string).collect();
+    let new_lines: Vec<String> = new.lines().map(str::to_string).collect();
+    let mut output = String::new();
+
+    for (same, removed, added) in group_line_diff(diff_sequences(&old_lines, &new_lines)) {
+        for line in same {
+            output.push_str(&format!("  {}\n", line));
+        }
+        if removed.is_empty() && added.is_empty() {
+            continue;
+        }
+        let old_words = split_words_for_diff(&removed.join("\n"));
+        let new_words = split_words_for_diff(&added.join("\n"));
+        let mut hunk = String::new();
+        for op in merge_word_diff(diff_sequences(&old_words, &new_words)) {
+            match op {
+                DiffOp::Same(word) => hunk.push_str(&word),
+                DiffOp::Removed(word) => hunk.push_str(&highlight_diff_token(&word, STYLE_REMOVED, color)),
+                DiffOp::Added(word) => hunk.push_str(&highlight_diff_token(&word, STYLE_ADDED, color)),
+            }
+        }
+        for line in hunk.lines() {
+            output.push_str(&format!("~ {}\n", line));
+        }
+    }
+    output
+}
+
+/// Text of one side of a side-by-side diff line, with the style of each part
+type DiffSegments<'a> = Vec<(String, Option<&'a str>)>;
+
+/// Lays out a line of (text, style) segments in a column of `width` characters
+///
+/// Long lines are wrapped; every returned line is padded to `width`.
+fn layout_diff_column(segments: &[(String, Option<&str>)], width: usize) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    let mut used = 0;
+    for (text, style) in segments {
+        for c in text.chars() {
+            if used == width {
+                lines.push(String::new());
+                used = 0;
+            }
+            let line = lines.last_mut().expect("lines starts with one line");
+            match style {
+                Some(style) => line.push_str(&format!("{}{}{}", style, c, STYLE_RESET)),
+                None => line.push(c),
+            }
+            used += 1;
+        }
+    }
+    let last = lines.last_mut().expect("lines starts with one line");
+    last.push_str(&" ".repeat(width - used));
+    lines
+}
+
+/// Side-by-side diff of two texts in columns of `width` characters
+///
+/// Changed lines are paired up; within them, removed words are highlighted
+/// in the left column and added words in the right one.
+fn render_side_by_side_diff(old: &str, new: &str, width: usize, color: bool) -> String {
+    let old_lines: Vec<String> = old.lines().map(str::to_string).collect();
+    let new_lines: Vec<String> = new.lines().map(str::to_string).collect();
+    let mut rows: Vec<(DiffSegments, DiffSegments, char)> = Vec::new();
+
+    for (same, removed, added) in group_line_diff(diff_sequences(&old_lines, &new_lines)) {
+        for line in same {
+            rows.push((vec![(line.clone(), None)], vec![(line, None)], '|'));
+        }
+        for index in 0..removed.len().max(added.len()) {
+            let old_line = removed.get(index).map(String::as_str).unwrap_or("");
+            let new_line = added.get(index).map(String::as_str).unwrap_or("");
+            let (mut left, mut right): (DiffSegments, DiffSegments) = (Vec::new(), Vec::new());
+            let ops = merge_word_diff(diff_sequences(&split_words_for_diff(old_line), &split_words_for_diff(new_line)));
+            for op in ops {
+                match op {
+                    DiffOp::Same(word) => {
+                        left.push((word.clone(), None));
+                        right.push((word, None));
+                    },
+                    DiffOp::Removed(word) if color => left.push((word, Some(STYLE_REMOVED))),
+                    DiffOp::Removed(word) => left.push((format!("[-{}-]", word), None)),
+                    DiffOp::Added(word) if color => right.push((word, Some(STYLE_ADDED))),
+                    DiffOp::Added(word) => right.push((format!("{{+{}+}}", word), None)),
+                }
+            }
+            let marker = match (index < removed.len(), index < added.len()) {
+                (true, true) => '~',
+                (true, false) => '<',
+                _ => '>',
+            };
+            rows.push((left, right, marker));
+        }
+    }
+
+    let mut output = String::new();
+    for (left, right, marker) in rows {
+        let left_lines = layout_diff_column(&left, width);
+        let right_lines = layout_diff_column(&right, width);
+        for index in 0..left_lines.len().max(right_lines.len()) {
+            let blank = " ".repeat(width);
+            output.push_str(&format!(
+                "{} {} {}\n",
+                left_lines.get(index).unwrap_or(&blank),
+                if index == 0 { marker } else { ' ' },
+                right_lines.get(index).unwrap_or(&blank).trim_end()
+            ));
+        }
+    }
+    output
+}
+
+/// Width of the terminal in columns: from stty, $COLUMNS, or 80
+fn terminal_width() -> usize {
+    let from_stty = Command::new("stty")
+        .arg("size")
+        .stdin(std::process::Stdio::inherit())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .split_whitespace()
+                .nth(1)
+                .and_then(|columns| columns.parse::<usize>().ok())
+        });
+    from_stty
+        .or_else(|| std::env::var("COLUMNS").ok().and_then(|columns| columns.parse().ok()))
+        .filter(|columns| *columns > 0)
+        .unwrap_or(80)
+}
+
+/// Finds a transcript by path, file name, or a unique part of its name in the chat log directory
+fn find_history_transcript(log_dir: &Path, id: &str) -> Result<PathBuf, String> {
+    let given = Path::new(id);
+    if given.is_file() {
+        return Ok(given.to_path_buf());
+    }
+    for name in [id.to_string(), format!("{}.txt", id)] {
+        if log_dir.join(&name).is_file() {
+            return Ok(log_dir.join(name));
+        }
+    }
+
+    let mut matches: Vec<String> = fs::read_dir(log_dir)
+        .map_err(|e| format!("Failed to read {}: {}", log_dir.display(), e))?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name != HISTORY_INDEX_FILE && name.contains(id))
+        .collect();
+    matches.sort();
+    match matches.len() {
+        0 => Err(format!("No chat transcript matches '{}' in {}", id, log_dir.display())),
+        1 => Ok(log_dir.join(&matches[0])),
+        _ => Err(format!("'{}' matches {} transcripts: {}", id, matches.len(), matches.join(", "))),
+    }
+}
+
+/// Shortens text to one line of at most `max_chars` characters, for headings
+fn one_line_preview(text: &str, max_chars: usize) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() > max_chars {
+        format!("{}...", line.chars().take(max_chars.saturating_sub(3)).collect::<String>())
+    } else {
+        line
+    }
+}
+
+/// `history diff <id1> <id2> [--side-by-side]`: compares two sessions' responses
+///
+/// Responses are paired up in order (first with first, ...). Each pair is
+/// shown with its question, as a unified word diff or side by side.
+fn handle_history_diff(args: &[String]) -> Result<(), String> {
+    use std::io::IsTerminal;
+    let usage = "Use: history diff <id1> <id2> [--side-by-side]";
+    let ids: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+    let (first_id, second_id) = match ids.as_slice() {
+        [first, second] => (first.as_str(), second.as_str()),
+        _ => return Err(usage.to_string()),
+    };
+    let side_by_side = match args.iter().find(|arg| arg.starts_with("--")).map(|arg| arg.as_str()) {
+        Some("--side-by-side") => true,
+        Some(other) => return Err(format!("Unknown option: {}. {}", other, usage)),
+        None => false,
+    };
+
+    let log_dir = get_chat_log_dir()?;
+    let first_path = find_history_transcript(&log_dir, first_id)?;
+    let second_path = find_history_transcript(&log_dir, second_id)?;
+    let read = |path: &Path| fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e));
+    let first = transcript_exchanges(&read(&first_path)?);
+    let second = transcript_exchanges(&read(&second_path)?);
+
+    let color = io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    let column_width = terminal_width().saturating_sub(3).max(20) / 2;
+
+    println!("--- {}\n+++ {}", first_path.display(), second_path.display());
+    for (index, ((first_question, first_response), (second_question, second_response))) in first.iter().zip(&second).enumerate() {
+        println!("\n=== Response {} ===", index + 1);
+        if !first_question.is_empty() {
+            println!("Question: {}", one_line_preview(first_question, 100));
+        }
+        if first_question.trim() != second_question.trim() {
+            println!("Note: the questions differ; second: {}", one_line_preview(second_question, 100));
+        }
+        if first_response == second_response {
+            println!("(identical)");
+        } else if side_by_side {
+            print!("{}", render_side_by_side_diff(first_response, second_response, column_width, color));
+        } else {
+            print!("{}", render_unified_word_diff(first_response, second_response, color));
+        }
+    }
+    if first.len() != second.len() {
+        let (longer, path) = if first.len() > second.len() { (&first, &first_path) } else { (&second, &second_path) };
+        println!(
+            "\n{} more response(s) only in {}",
+            longer.len() - first.len().min(second.len()),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Handles `query_gguf history search <words>`, `history reindex`, and `history diff <id1> <id2>`
+fn handle_history_command(args: &[String]) -> Result<(), String> {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("search") if args.len() > 1 => {
+            update_history_index()?;
+            let log_dir = get_chat_log_dir()?;
+            let index = HistoryIndex::parse(
+                &fs::read_to_string(log_dir.join(HISTORY_INDEX_FILE)).unwrap_or_default()
+            );
+            let query = args[1..].join(" ");
+            let results = index.search(&query);
+            if results.is_empty() {
+                println!("No chats mention: {}", query);
+                return Ok(());
+            }
+
+            println!("{} chat(s) match \"{}\":\n", results.len(), query);
+            for (document, score) in results.iter().take(HISTORY_SEARCH_MAX_RESULTS) {
+                let path = log_dir.join(&index.documents[*document]);
+                // Only the shown results are read, for their snippets
+                let snippet = fs::read_to_string(&path)
+                    .map(|text| make_search_snippet(&text, &query))
+                    .unwrap_or_else(|_| "(file no longer exists)".to_string());
+                println!("{:6.2}  {}\n        {}", score, path.display(), snippet);
+            }
+            Ok(())
+        },
+        Some("reindex") => {
+            let index_path = get_chat_log_dir()?.join(HISTORY_INDEX_FILE);
+            if index_path.exists() {
+                fs::remove_file(&index_path)
+                    .map_err(|e| format!("Failed to remove {}: {}", index_path.display(), e))?;
+            }
+            println!("Indexed {} chat transcript(s).", update_history_index()?);
+            Ok(())
+        },
+        Some("diff") => handle_history_diff(&args[1..]),
+        _ => Err("Use: history search <words> | history reindex | history diff <id1> <id2> [--side-by-side]".to_string()),
+    }
+}
+
+/// Supervised chat with a saved mode: `query_gguf chat <mode>`
+///
+/// Runs the model in llama-server (started and stopped by query_gguf) and
+/// chats in this terminal. The mode's prompt file is used as the system
+/// message. After each reply a meter shows how much of ctx_size is used,
+/// with a warning as the window gets full.
+///
+/// When usage reaches CONTEXT_WARN_PERCENT, the oldest turns are summarized
+/// and replaced by the summary (context compaction), so the chat can go on.
+/// The summary is written by the same model, or by the mode set as
+/// `summary_mode` in the config (e.g. a small, fast model). When compaction
+/// happened, both the full and the compacted history are written to the
+/// chat log directory at the end of the chat; otherwise the transcript is.
+/// Logs start with a snapshot of the mode, model, and prompt file used.
+/// Transcripts are added to the `history search` index.
+///
+/// Replies are rendered as markdown in a terminal; `--raw` prints them as-is.
+/// With `tts_command` set in the config, each reply is also read aloud.
+/// At the end, fenced code blocks from the replies are offered for saving
+/// to files, or all written to the directory given with `--save-code DIR`.
+///
+/// Commands:
+/// ```text
+/// /clear                      start the conversation over (keeps the system prompt)
+/// /regen [seed=N] [temp=X]    answer the last message again, optionally with a new seed/temperature
+/// /edit                       edit the last message in $EDITOR and send it again
+/// /dictate                    speak the next message (transcribed by stt_command)
+/// /context                    show what is in the context window (prompt, turns, summaries)
+/// /quit                       stop the server and exit (also: /q)
+/// ```
+fn handle_chat_command(args: &[String]) -> Result<(), String> {
+    let selector = args.first()
+        .ok_or_else(|| "Missing mode. Use: chat <mode number or id> [--raw] [--save-code DIR]".to_string())?;
+    let raw_output = args.iter().any(|arg| arg == "--raw");
+    let save_code_dir = match args.iter().position(|arg| arg == "--save-code") {
+        Some(index) => Some(args.get(index + 1)
+            .map(PathBuf::from)
+            .ok_or_else(|| "Missing directory after --save-code".to_string())?),
+        None => None,
+    };
+    let modes = read_saved_modes()?;
+    let mode = find_mode_by_selector(&modes, selector)
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?
+        .clone();
+
+    let system_prompt = fs::read_to_string(&mode.prompt_path).unwrap_or_default();
+    let base_messages: Vec<ChatMessage> = if system_prompt.trim().is_empty() {
+        Vec::new()
+    } else {
+        vec![ChatMessage { role: "system".to_string(), content: system_prompt.trim().to_string() }]
+    };
+
+    let fingerprint = model_fingerprint(Path::new(&mode.model_path))
+        .unwrap_or_else(|e| format!("unavailable ({})", e));
+    let session_snapshot = format_mode_snapshot(&mode, &fingerprint, &system_prompt);
+
+    let server = start_or_attach_server(&mode)?;
+    let context_size = mode.parameters.context_size.max(0) as u64;
+    let mut messages = base_messages.clone();
+    // Everything said, never compacted, for the log
+    let mut full_history = base_messages.clone();
+    let mut compaction_count = 0;
+    // Messages of the current window replaced by summaries, and the last reported usage, for /context
+    let mut summarized_messages = 0;
+    let mut last_used_tokens = 0;
+    let mut tokens_generated = 0;
+    let mut speeds: Vec<f64> = Vec::new();
+
+    // Optional separate (smaller) model for writing summaries, started on first use
+    let summary_mode_selector = read_field_from_toml("summary_mode");
+    let mut summary_server: Option<LlamaServer> = None;
+
+    let session_log = SessionLog::start("chat", &mode.id);
+    println!("\n=== Chat: {} [{}] ===", mode.name, mode.id);
+    println!("Type a message. Commands: /clear | /regen [seed=N] [temp=X] | /edit | /dictate | /context | /quit");
+
+    loop {
+        print!("\n> ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let input = read_user_input_with_history("chat")?;
+        // End of input (e.g. Ctrl-D)
+        if input.is_empty() {
+            break;
+        }
+        let input = input.trim();
+
+        let mut turn_parameters = mode.parameters.clone();
+        let mut turn_seed = None;
+        let (command, command_args) = input.split_once(' ').unwrap_or((input, ""));
+        let user_text = match command {
+            "" => continue,
+            "/q" | "/quit" | "/exit" => break,
+            "/dictate" => match run_dictation() {
+                Ok(transcript) => transcript,
+                Err(e) => {
+                    println!("{}", e);
+                    continue;
+                },
+            },
+            "/clear" => {
+                messages = base_messages.clone();
+                summarized_messages = 0;
+                last_used_tokens = 0;
+                println!("Conversation cleared.");
+                continue;
+            },
+            "/context" => {
+                let prompt_size = fs::metadata(&mode.prompt_path).map(|metadata| metadata.len()).ok();
+                println!("\n{}", format_context_report(&ContextReport {
+                    messages: &messages,
+                    prompt_file: prompt_size.filter(|_| !base_messages.is_empty()).map(|size| (mode.prompt_path.as_str(), size)),
+                    summarized_messages,
+                    used_tokens: last_used_tokens,
+                    context_size,
+                }));
+                continue;
+            },
+            "/regen" | "/edit" => {
+                let options = if command == "/regen" {
+                    parse_regen_options(command_args)
+                } else {
+                    Ok((None, None))
+                };
+                let (seed, temperature) = match options {
+                    Ok(options) => options,
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    },
+                };
+                // Take back the last exchange; the old answer stays in the full log
+                let Some(last_user_text) = take_last_exchange(&mut messages) else {
+                    println!("Nothing to {} yet.", &command[1..]);
+                    continue;
+                };
+                if let Some(temperature) = temperature {
+                    turn_parameters.temperature_value = temperature;
+                }
+                turn_seed = seed;
+
+                if command == "/edit" {
+                    match edit_text_in_editor(&last_user_text) {
+                        Ok(edited) if !edited.trim().is_empty() => edited.trim().to_string(),
+                        Ok(_) => {
+                            println!("Edited message is empty; keeping the original.");
+                            last_user_text
+                        },
+                        Err(e) => {
+                            println!("Warning: {}; keeping the original message.", e);
+                            last_user_text
+                        },
+                    }
+                } else {
+                    last_user_text
+                }
+            },
+            _ => input.to_string(),
+        };
+        if user_text != input {
+            println!("\n> {}", user_text);
+        }
+
+        messages.push(ChatMessage { role: "user".to_string(), content: user_text.clone() });
+        session_log.append("user", &user_text);
+        match server.chat(&messages, &turn_parameters, -1, turn_seed) {
+            Ok(result) => {
+                session_log.append("assistant", &result.content);
+                println!("\n{}", format_reply_for_display(&result.content, raw_output));
+                if let Err(e) = speak_text(&result.content) {
+                    println!("Warning: {}", e);
+                }
+                full_history.push(ChatMessage { role: "user".to_string(), content: user_text });
+                full_history.push(ChatMessage { role: "assistant".to_string(), content: result.content.clone() });
+                messages.push(ChatMessage { role: "assistant".to_string(), content: result.content });
+
+                tokens_generated += result.completion_tokens;
+                if result.tokens_per_second > 0.0 {
+                    speeds.push(result.tokens_per_second);
+                }
+
+                let used_tokens = result.prompt_tokens + result.completion_tokens;
+                last_used_tokens = used_tokens;
+                println!("\n{}", format_context_meter(used_tokens, context_size));
+
+                let over_threshold = context_size > 0 && used_tokens * 100 / context_size >= CONTEXT_WARN_PERCENT;
+                let split = if over_threshold {
+                    split_for_compaction(&messages, COMPACTION_KEEP_RECENT_MESSAGES)
+                } else {
+                    None
+                };
+                match split {
+                    Some((to_summarize, kept)) => {
+                        println!("Compacting: summarizing {} older messages...", to_summarize.len());
+
+                        if !summary_mode_selector.is_empty() && summary_server.is_none() {
+                            match find_mode_by_selector(&modes, &summary_mode_selector) {
+                                Some(summary_mode) => match LlamaServer::start(summary_mode) {
+                                    Ok(started) => summary_server = Some(started),
+                                    Err(e) => println!("Warning: summary_mode failed to start, using this model: {}", e),
+                                },
+                                None => println!("Warning: unknown summary_mode '{}', using this model", summary_mode_selector),
+                            }
+                        }
+                        let summarizer = summary_server.as_ref().unwrap_or(&server);
+
+                        match summarizer.chat(&build_summary_request(&to_summarize), &mode.parameters, 512, None) {
+                            Ok(summary) => {
+                                messages = insert_compaction_summary(kept, &summary.content);
+                                compaction_count += 1;
+                                summarized_messages += to_summarize.len();
+                                println!("Compacted {} messages into a summary.", to_summarize.len());
+                                session_log.append("info", &format!("Compacted {} messages into a summary", to_summarize.len()));
+                            },
+                            Err(e) => println!("Warning: Compaction failed: {}", e),
+                        }
+                    },
+                    None => {
+                        if let Some(warning) = context_usage_warning(used_tokens, context_size) {
+                            println!("{}", warning);
+                        }
+                    },
+                }
+            },
+            Err(e) => {
+                // Drop the unanswered message so it is not sent twice
+                messages.pop();
+                println!("Error: {}", e);
+                session_log.append("error", &e);
+            },
+        }
+    }
+
+    if tokens_generated > 0 {
+        let timing = SessionTiming {
+            kind: "chat".to_string(),
+            mode_id: mode.id.clone(),
+            model_path: mode.model_path.clone(),
+            load_ms: server.load_time.as_secs_f64() * 1000.0,
+            first_token_ms: 0.0,
+            tokens_per_second: if speeds.is_empty() { 0.0 } else { speeds.iter().sum::<f64>() / speeds.len() as f64 },
+            tokens_generated,
+        };
+        if let Err(e) = record_session_timing(&timing) {
+            println!("Warning: {}", e);
+        }
+    }
+
+    let code_blocks: Vec<CodeBlock> = full_history.iter()
+        .filter(|message| message.role == "assistant")
+        .flat_map(|message| extract_code_blocks(&message.content))
+        .collect();
+    if !code_blocks.is_empty() {
+        if let Err(e) = save_code_blocks(&code_blocks, save_code_dir.as_deref()) {
+            println!("Warning: {}", e);
+        }
+    }
+
+    if compaction_count > 0 {
+        let log_dir = get_chat_log_dir()?;
+        let timestamp = generate_timestamp_string();
+        let full_path = log_dir.join(format!("chat_{}_{}_full.txt", mode.id, timestamp));
+        let compacted_path = log_dir.join(format!("chat_{}_{}_compacted.txt", mode.id, timestamp));
+        write_chat_transcript(&full_path, &session_snapshot, &full_history)?;
+        write_chat_transcript(&compacted_path, &session_snapshot, &messages)?;
+        println!("Chat was compacted {} time(s). Logs:\n  {}\n  {}",
+            compaction_count, full_path.display(), compacted_path.display());
+    } else if full_history.iter().any(|message| message.role == "user") {
+        let transcript_path = get_chat_log_dir()?
+            .join(format!("chat_{}_{}.txt", mode.id, generate_timestamp_string()));
+        write_chat_transcript(&transcript_path, &session_snapshot, &full_history)?;
+        println!("Chat log: {}", transcript_path.display());
+    }
+
+    if let Err(e) = update_history_index() {
+        println!("Warning: Could not update the history search index: {}", e);
+    }
+
+    Ok(())
+}
+
+/// Parses the options of `/regen`: `seed=N` and/or `temp=X`, in any order
+///
+/// # Returns
+/// - Ok((seed, temperature)): Options given, None for those not given
+/// - Err(String): Unknown option or bad number
+fn parse_regen_options(args: &str) -> Result<(Option<i64>, Option<f32>), String> {
+    let mut seed = None;
+    let mut temperature = None;
+    for option in args.split_whitespace() {
+        match option.split_once('=') {
+            Some(("seed", value)) => seed = Some(value.parse::<i64>()
+                .map_err(|_| format!("Invalid seed: {}", value))?),
+            Some(("temp", value)) => temperature = Some(value.parse::<f32>()
+                .map_err(|_| format!("Invalid temp: {}", value))?),
+            _ => return Err(format!("Unknown /regen option '{}'. Use: /regen [seed=N] [temp=X]", option)),
+        }
+    }
+    Ok((seed, temperature))
+}
+
+/// Removes the last user message and the reply to it from a conversation
+///
+/// # Returns
+/// - Some(String): Text of the removed user message, to send again
+/// - None: The conversation does not end with an answered user message
+fn take_last_exchange(messages: &mut Vec<ChatMessage>) -> Option<String> {
+    let ends_with_exchange = messages.len() >= 2
+        && messages[messages.len() - 1].role == "assistant"
+        && messages[messages.len() - 2].role == "user";
+    if !ends_with_exchange {
+        return None;
+    }
+    messages.pop();
+    messages.pop().map(|message| message.content)
+}
+
+/// ANSI styles used when rendering model replies
+const STYLE_RESET: &str = "\x1b[0m";
+const STYLE_BOLD: &str = "\x1b[1m";
+const STYLE_ITALIC: &str = "\x1b[3m";
+const STYLE_FENCE: &str = "\x1b[2m";    // dim ``` lines
+const STYLE_CODE: &str = "\x1b[36m";    // cyan inline `code`
+const STYLE_KEYWORD: &str = "\x1b[35m"; // magenta
+const STYLE_STRING: &str = "\x1b[32m";  // green
+const STYLE_NUMBER: &str = "\x1b[33m";  // yellow
+const STYLE_COMMENT: &str = "\x1b[90m"; // gray
+const STYLE_REMOVED: &str = "\x1b[31m"; // red, `history diff`
+const STYLE_ADDED: &str = "\x1b[32m";   // green, `history diff`
+
+/// What the small built-in highlighter knows about a language
+struct CodeSyntax {
+    keywords: &'static [&'static str],
+    line_comment: &'static str,
+    single_quote_strings: bool,  // false where ' is a lifetime or char (Rust)
+}
+
+/// Looks up highlighting rules by the name after a code fence (```rust)
+///
+/// # Returns
+/// - Some(CodeSyntax): A language the highlighter knows
+/// - None: Unknown or missing language, shown without highlighting
+fn code_syntax(language: &str) -> Option<CodeSyntax> {
+    let syntax = match language.to_lowercase().as_str() {
+        "rust" | "rs" => CodeSyntax {
+            keywords: &["as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+                "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+                "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "type", "unsafe",
+                "use", "where", "while", "Some", "None", "Ok", "Err"],
+            line_comment: "//",
+            single_quote_strings: false,
+        },
+        "python" | "py" => CodeSyntax {
+            keywords: &["and", "as", "async", "await", "break", "class", "continue", "def", "elif", "else",
+                "except", "False", "finally", "for", "from", "global", "if", "import", "in", "is", "lambda",
+                "None", "not", "or", "pass", "raise", "return", "self", "True", "try", "while", "with", "yield"],
+            line_comment: "#",
+            single_quote_strings: true,
+        },
+        "javascript" | "js" | "jsx" | "typescript" | "ts" | "tsx" => CodeSyntax {
+            keywords: &["async", "await", "break", "catch", "class", "const", "continue", "default", "else",
+                "export", "extends", "false", "finally", "for", "from", "function", "if", "import", "in",
+                "instanceof", "interface", "let", "new", "null", "of", "return", "this", "throw", "true", "try",
+                "type", "typeof", "undefined", "var", "while"],
+            line_comment: "//",
+            single_quote_strings: true,
+        },
+        "c" | "h" | "cpp" | "c++" | "hpp" | "java" | "cs" | "csharp" | "go" => CodeSyntax {
+            keywords: &["bool", "break", "case", "char", "class", "const", "continue", "default", "defer",
+                "delete", "do", "double", "else", "enum", "false", "float", "for", "func", "go", "if", "import",
+                "include", "int", "interface", "long", "namespace", "new", "null", "nullptr", "package",
+                "private", "protected", "public", "range", "return", "static", "struct", "switch", "this",
+                "true", "type", "typedef", "using", "var", "void", "while"],
+            line_comment: "//",
+            single_quote_strings: true,
+        },
+        "sh" | "bash" | "shell" | "zsh" | "console" => CodeSyntax {
+            keywords: &["case", "do", "done", "echo", "elif", "else", "esac", "exit", "export", "fi", "for",
+                "function", "if", "in", "local", "return", "then", "while"],
+            line_comment: "#",
+            single_quote_strings: true,
+        },
+        "toml" | "yaml" | "yml" => CodeSyntax {
+            keywords: &["true", "false"],
+            line_comment: "#",
+            single_quote_strings: true,
+        },
+        "json" => CodeSyntax {
+            keywords: &["true", "false", "null"],
+            line_comment: "",
+            single_quote_strings: false,
+        },
+        _ => return None,
+    };
+    Some(syntax)
+}
+
+/// Colors keywords, strings, numbers, and line comments in one line of code
+fn highlight_code_line(line: &str, syntax: &CodeSyntax) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let comment: Vec<char> = syntax.line_comment.chars().collect();
+    let mut output = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if !comment.is_empty() && chars[i..].starts_with(&comment) {
+            let rest: String = chars[i..].iter().collect();
+            output.push_str(&format!("{}{}{}", STYLE_COMMENT, rest, STYLE_RESET));
+            break;
+        }
+        if c == '"' || (c == '\'' && syntax.single_quote_strings) {
+            // Until the matching unescaped quote, or the end of the line
+            let start = i;
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            i = (i + 1).min(chars.len());
+            let text: String = chars[start..i].iter().collect();
+            output.push_str(&format!("{}{}{}", STYLE_STRING, text, STYLE_RESET));
+            continue;
+        }
+        if c.is_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || (chars[start].is_ascii_digit() && chars[i] == '.')) {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if c.is_ascii_digit() {
+                output.push_str(&format!("{}{}{}", STYLE_NUMBER, word, STYLE_RESET));
+            } else if syntax.keywords.contains(&word.as_str()) {
+                output.push_str(&format!("{}{}{}", STYLE_KEYWORD, word, STYLE_RESET));
+            } else {
+                output.push_str(&word);
+            }
+            continue;
+        }
+        output.push(c);
+        i += 1;
+    }
+    output
+}
+
+/// Renders `code`, **bold**, and *italic* spans of one line of text
+///
+/// Markers without a closing partner are left as they are.
+fn render_inline_markdown(line: &str) -> String {
+    let mut output = String::new();
+    let mut rest = line;
+
+    while let Some(c) = rest.chars().next() {
+        let (marker, style) = if rest.starts_with("**") {
+            ("**", STYLE_BOLD)
+        } else if c == '`' {
+            ("`", STYLE_CODE)
+        } else if c == '*' && rest[1..].starts_with(|next: char| !next.is_whitespace()) {
+            ("*", STYLE_ITALIC)
+        } else {
+            output.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+
+        let after = &rest[marker.len()..];
+        match after.find(marker).filter(|end| *end > 0) {
+            Some(end) => {
+                output.push_str(&format!("{}{}{}", style, &after[..end], STYLE_RESET));
+                rest = &after[end + marker.len()..];
+            },
+            None => {
+                output.push_str(marker);
+                rest = after;
+            },
+        }
+    }
+    output
+}
+
+/// Renders a model reply written in markdown for the terminal
+///
+/// Fenced code blocks are highlighted by language, headings and **bold**
+/// are bold, *italics* are italic, and `-`/`*` list items get bullets.
+fn render_markdown(text: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    // Inside a fenced block: Some(highlighting rules, if the language is known)
+    let mut code_block: Option<Option<CodeSyntax>> = None;
+
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+
+        if let Some(language) = trimmed.strip_prefix("```") {
+            code_block = match code_block {
+                Some(_) => None,
+                None => Some(code_syntax(language.trim())),
+            };
+            lines.push(format!("{}{}{}", STYLE_FENCE, line, STYLE_RESET));
+            continue;
+        }
+        if let Some(syntax) = &code_block {
+            lines.push(match syntax {
+                Some(syntax) => highlight_code_line(line, syntax),
+                None => line.to_string(),
+            });
+            continue;
+        }
+
+        let heading = trimmed.trim_start_matches('#');
+        if trimmed.starts_with('#') && heading.starts_with(' ') {
+            lines.push(format!("{}{}{}", STYLE_BOLD, heading.trim(), STYLE_RESET));
+        } else if let Some(item) = ["- ", "* ", "+ "].iter().find_map(|bullet| trimmed.strip_prefix(bullet)) {
+            lines.push(format!("{}• {}", indent, render_inline_markdown(item)));
+        } else {
+            lines.push(render_inline_markdown(line));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Formats a model reply for printing
+///
+/// Markdown is rendered when writing to a terminal, unless `raw` is set
+/// (--raw) or NO_COLOR is set in the environment.
+fn format_reply_for_display(reply: &str, raw: bool) -> String {
+    use std::io::IsTerminal;
+
+    let reply = reply.trim();
+    if raw || std::env::var_os("NO_COLOR").is_some() || !io::stdout().is_terminal() {
+        return reply.to_string();
+    }
+    render_markdown(reply)
+}
+
+/// A fenced code block found in a model reply
+#[derive(Debug, Clone, PartialEq)]
+struct CodeBlock {
+    language: String,               // from the opening fence, may be empty
+    suggested_path: Option<String>, // from a `// file: path` (or `# file:`) first line
+    code: String,                   // without the fences and the file hint line
+}
+
+/// Finds the fenced code blocks of a reply
+///
+/// A first line like `// file: src/main.rs`, `# file: run.sh`, or
+/// `<!-- file: index.html -->` is taken as the suggested file path and left
+/// out of the code. An unclosed block at the end still counts.
+fn extract_code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut current: Option<(String, Vec<&str>)> = None;
+
+    for line in text.lines() {
+        let fence = line.trim_start().strip_prefix("```");
+        match (&mut current, fence) {
+            (None, Some(language)) => current = Some((language.trim().to_string(), Vec::new())),
+            (Some(_), Some(_)) => {
+                let (language, lines) = current.take().unwrap_or_default();
+                blocks.push(make_code_block(language, &lines));
+            },
+            (Some((_, lines)), None) => lines.push(line),
+            (None, None) => {},
+        }
+    }
+    if let Some((language, lines)) = current {
+        blocks.push(make_code_block(language, &lines));
+    }
+    blocks
+}
+
+/// Builds a CodeBlock, taking a file hint from its first line if there is one
+fn make_code_block(language: String, lines: &[&str]) -> CodeBlock {
+    let hint = lines.first().and_then(|first| {
+        let first = first.trim();
+        let rest = first.strip_prefix("//")
+            .or_else(|| first.strip_prefix('#'))
+            .or_else(|| first.strip_prefix("<!--").map(|rest| rest.trim_end_matches("-->")))?;
+        let path = rest.trim().strip_prefix("file:")?.trim();
+        if path.is_empty() { None } else { Some(path.to_string()) }
+    });
+    let code_lines = if hint.is_some() { &lines[1..] } else { lines };
+
+    let mut code = code_lines.join("\n");
+    code.push('\n');
+    CodeBlock { language, suggested_path: hint, code }
+}
+
+/// File name for a code block without a file hint, e.g. snippet_2.py
+fn default_code_file_name(number: usize, language: &str) -> String {
+    let extension = match language.to_lowercase().as_str() {
+        "rust" | "rs" => "rs",
+        "python" | "py" => "py",
+        "javascript" | "js" => "js",
+        "typescript" | "ts" => "ts",
+        "sh" | "bash" | "shell" | "zsh" => "sh",
+        "c" => "c",
+        "cpp" | "c++" => "cpp",
+        "go" => "go",
+        "java" => "java",
+        "toml" => "toml",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "html" => "html",
+        "css" => "css",
+        "markdown" | "md" => "md",
+        _ => "txt",
+    };
+    format!("snippet_{}.{}", number, extension)
+}
+
+/// Checks that a suggested path stays inside the output directory
+fn is_safe_relative_path(path: &str) -> bool {
+    let path = Path::new(path);
+    !path.as_os_str().is_empty()
+        && path.components().all(|component| matches!(component, std::path::Component::Normal(_)))
+}
+
+/// Saves code blocks to files
+///
+/// With `batch_dir` (--save-code DIR), every block is written under that
+/// directory, using its file hint when it is a safe relative path;
+/// existing files are not overwritten. Without it, the user is asked for
+/// each block: Enter accepts the suggested path, `s` skips.
+fn save_code_blocks(blocks: &[CodeBlock], batch_dir: Option<&Path>) -> Result<(), String> {
+    if batch_dir.is_none() {
+        println!("\nThe replies have {} code block(s).", blocks.len());
+    }
+
+    for (index, block) in blocks.iter().enumerate() {
+        let default_name = default_code_file_name(index + 1, &block.language);
+        let suggested = block.suggested_path.clone()
+            .filter(|path| is_safe_relative_path(path))
+            .unwrap_or(default_name);
+
+        let target = match batch_dir {
+            Some(dir) => dir.join(&suggested),
+            None => {
+                let first_line = block.code.lines().next().unwrap_or("");
+                println!(
+                    "\nBlock {} ({}, {} lines): {}",
+                    index + 1,
+                    if block.language.is_empty() { "text" } else { &block.language },
+                    block.code.lines().count(),
+                    first_line
+                );
+                let answer = read_path_input(&format!(
+                    "Save to [{}] (Enter to accept, a path, or 's' to skip): ", suggested
+                ))?;
+                match answer.trim() {
+                    "s" | "skip" => continue,
+                    "" => PathBuf::from(&suggested),
+                    path => PathBuf::from(path),
+                }
+            },
+        };
+
+        if target.exists() {
+            if batch_dir.is_some() {
+                println!("Warning: {} already exists, not overwritten", target.display());
+                continue;
+            }
+            if !prompt_yes_no(&format!("{} exists. Overwrite?", target.display()))? {
+                continue;
+            }
+        }
+        if let Some(parent) = target.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        fs::write(&target, &block.code)
+            .map_err(|e| format!("Failed to write {}: {}", target.display(), e))?;
+        println!("Saved {}", target.display());
+    }
+    Ok(())
+}
+
+/// Tells the model how to answer in `patch` mode; the task follows it
+const PATCH_FORMAT_INSTRUCTIONS: &str = "\
+Answer with the change as a unified diff in one ```diff code block.
+Use paths relative to the directory above, with a/ and b/ prefixes, e.g.
+--- a/src/main.rs
++++ b/src/main.rs
+Include a few unchanged context lines around each change. Do not include anything else in the code block.";
+
+/// Finds the unified diff in a model reply
+///
+/// Uses the first ```diff or ```patch block, or else the text from the
+/// first `--- ` line on.
+fn extract_unified_diff(reply: &str) -> Option<String> {
+    let from_block = extract_code_blocks(reply).into_iter()
+        .find(|block| block.language == "diff" || block.language == "patch")
+        .map(|block| block.code);
+    let diff = from_block.or_else(|| {
+        let start = reply.lines().position(|line| line.starts_with("--- "))?;
+        Some(reply.lines().skip(start).collect::<Vec<_>>().join("\n") + "\n")
+    })?;
+
+    if diff.lines().any(|line| line.starts_with("+++ ")) && diff.lines().any(|line| line.starts_with("@@")) {
+        Some(diff)
+    } else {
+        None
+    }
+}
+
+/// Lists the files a unified diff touches
+///
+/// # Returns
+/// - (paths, strip_level): Paths without their a/ or b/ prefix, and the
+///   `-p` level for git apply (1 if all paths had prefixes, else 0)
+fn diff_file_paths(diff: &str) -> (Vec<String>, usize) {
+    let raw_paths: Vec<&str> = diff.lines()
+        .filter_map(|line| line.strip_prefix("--- ").or_else(|| line.strip_prefix("+++ ")))
+        // Drop timestamps some diff tools add after a tab
+        .map(|path| path.split('\t').next().unwrap_or("").trim())
+        .filter(|path| *path != "/dev/null")
+        .collect();
+    let prefixed = !raw_paths.is_empty()
+        && raw_paths.iter().all(|path| path.starts_with("a/") || path.starts_with("b/"));
+
+    let mut paths: Vec<String> = raw_paths.iter()
+        .map(|path| if prefixed { path[2..].to_string() } else { path.to_string() })
+        .collect();
+    paths.dedup();
+    (paths, if prefixed { 1 } else { 0 })
+}
+
+/// Runs `git apply` with a patch file, from the repository top (or the directory itself)
+///
+/// Inside a git repository, paths are made relative to the scanned
+/// directory with --directory; elsewhere git apply works like `patch`.
+fn run_git_apply(directory: &Path, patch_path: &Path, strip_level: usize, extra_args: &[&str]) -> Result<String, String> {
+    let git_output = |args: &[&str]| -> Option<String> {
+        let output = Command::new("git").arg("-C").arg(directory).args(args).output().ok()?;
+        if output.status.success() {
+            Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        } else {
+            None
+        }
+    };
+
+    let mut command = Command::new("git");
+    command.arg("apply").arg(format!("-p{}", strip_level)).arg("--recount");
+    match (git_output(&["rev-parse", "--show-toplevel"]), git_output(&["rev-parse", "--show-prefix"])) {
+        (Some(top), Some(prefix)) => {
+            command.current_dir(top);
+            if !prefix.is_empty() {
+                command.arg(format!("--directory={}", prefix.trim_end_matches('/')));
+            }
+        },
+        _ => {
+            command.current_dir(directory);
+        },
+    }
+    let output = command.args(extra_args).arg(patch_path)
+        .output()
+        .map_err(|e| format!("Failed to run git apply: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("git apply failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Saves the current working tree changes as a stash entry without touching the files
+///
+/// # Returns
+/// - Ok(Some(String)): Description of the backup
+/// - Ok(None): Nothing to back up (no changes) or not a git repository
+fn backup_with_git_stash(directory: &Path) -> Result<Option<String>, String> {
+    let created = Command::new("git").arg("-C").arg(directory)
+        .args(["stash", "create", "query_gguf: before patch"])
+        .output()
+        .map_err(|e| format!("Failed to run git stash: {}", e))?;
+    let stash_commit = String::from_utf8_lossy(&created.stdout).trim().to_string();
+    if !created.status.success() || stash_commit.is_empty() {
+        return Ok(None);
+    }
+
+    let stored = Command::new("git").arg("-C").arg(directory)
+        .args(["stash", "store", "-m", "query_gguf: before patch", &stash_commit])
+        .status()
+        .map_err(|e| format!("Failed to run git stash store: {}", e))?;
+    if !stored.success() {
+        return Err("git stash store failed; patch not applied".to_string());
+    }
+    Ok(Some(format!("stash@{{0}} ({}), restore with: git stash apply stash@{{0}}", &stash_commit[..stash_commit.len().min(10)])))
+}
+
+/// Diff-apply mode: `query_gguf patch <mode> <directory> -q "task" [--dry-run]`
+///
+/// Runs the mode with the directory's contents (like directory mode) and
+/// asks for the change as a unified diff. The diff is checked with
+/// `git apply --check` and previewed; with confirmation it is applied to
+/// the directory. Uncommitted changes are first saved to the git stash
+/// list (the working tree is left as it is), so the state before the
+/// patch can be restored.
+fn handle_patch_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: patch <mode number or id> <directory> -q \"task\" [--dry-run]";
+    let (selector, directory) = match args {
+        [selector, directory, ..] => (selector, directory),
+        _ => return Err(format!("Missing mode or directory. {}", usage)),
+    };
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let task = args.iter().position(|arg| arg == "-q" || arg == "--question")
+        .and_then(|index| args.get(index + 1))
+        .ok_or_else(|| format!("Missing task. {}", usage))?;
+    let directory = Path::new(directory);
+    if !directory.is_dir() {
+        return Err(format!("Directory not found: {}", directory.display()));
+    }
+
+    let modes = read_saved_modes()?;
+    let mut mode = find_mode_by_selector(&modes, selector)
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?
+        .clone();
+    let combined_prompt_path = create_combined_prompt(&mode.prompt_path, &directory.to_string_lossy(), &ScanOptions::default(), &mode.parameters)?;
+    mode.prompt_path = create_rendered_prompt(
+        &combined_prompt_path,
+        &[],
+        &[],
+        None,
+        Some(&format!("{}\n\nTask: {}", PATCH_FORMAT_INSTRUCTIONS, task)),
+    )?;
+    mode.parameters.interactive_first = false;
+
+    println!("Asking {} [{}] for a patch...", mode.name, mode.id);
+    let reply = run_llama_non_interactive(&mode)?;
+    let diff = extract_unified_diff(&reply)
+        .ok_or_else(|| format!("The reply has no unified diff:\n{}", reply.trim()))?;
+
+    let (paths, strip_level) = diff_file_paths(&diff);
+    if let Some(unsafe_path) = paths.iter().find(|path| !is_safe_relative_path(path)) {
+        return Err(format!("The patch touches a path outside the directory: {}", unsafe_path));
+    }
+
+    let patch_path = get_temp_dir()?.join(format!("patch_{}.diff", generate_timestamp_string()));
+    fs::write(&patch_path, &diff)
+        .map_err(|e| format!("Failed to write {}: {}", patch_path.display(), e))?;
+
+    println!("\n{}", diff.trim_end());
+    println!("\n{}", run_git_apply(directory, &patch_path, strip_level, &["--stat"])?.trim_end());
+    run_git_apply(directory, &patch_path, strip_level, &["--check"])
+        .map_err(|e| format!("The patch does not apply cleanly (saved at {}): {}", patch_path.display(), e))?;
+    println!("The patch applies cleanly. Saved at {}", patch_path.display());
+
+    if dry_run {
+        println!("Dry run: no files were changed.");
+        return Ok(());
+    }
+    if !prompt_yes_no("Apply this patch?")? {
+        println!("Not applied.");
+        return Ok(());
+    }
+
+    match backup_with_git_stash(directory)? {
+        Some(backup) => println!("Saved uncommitted changes to {}", backup),
+        None => println!("No uncommitted changes to back up (or not a git repository)."),
+    }
+    run_git_apply(directory, &patch_path, strip_level, &[])?;
+    println!("Applied the patch to {} file(s).", paths.len());
+    Ok(())
+}
+
+/// Most tool calls the agent may make in a row before control returns to the user
+const DEFAULT_AGENT_MAX_STEPS: usize = 5;
+
+/// Tells the model how to call tools; the list of tools follows it
+const AGENT_SYSTEM_PROMPT: &str = "\
+You can use tools to answer. To use a tool, reply with only one line:
+TOOL: {\"tool\": \"<name>\", <arguments>}
+The result comes back in the next message. When you have the answer, reply normally without TOOL:.";
+
+/// A tool the agent can offer to the model
+///
+/// The built-in tools (run_command, read_file) have no command template;
+/// tools from the config's `[tools.<name>]` tables do:
+/// ```toml
+/// [tools.find_todos]
+/// description = "Search the project for TODO comments"
+/// parameters = '{"path": "directory to search"}'
+/// command = "grep -rn TODO {path}"
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+struct ToolDefinition {
+    name: String,
+    description: String,
+    parameters: String,  // JSON-ish object: parameter name -> description
+    command: String,     // command template with {parameter} placeholders, empty for built-ins
+    sandbox: bool,       // run inside the agent sandbox (`sandbox = false` in the table turns it off)
+    path_parameters: Vec<String>,  // parameters whose values must be paths under agent_file_root
+}
+
+/// The tools every agent session has
+fn builtin_tool_definitions() -> Vec<ToolDefinition> {
+    vec![
+        ToolDefinition {
+            name: "run_command".to_string(),
+            description: "Run an allowed program (no shell, no pipes)".to_string(),
+            parameters: r#"{"command": "the command line, e.g. git log -5 --oneline"}"#.to_string(),
+            command: String::new(),
+            sandbox: true,
+            path_parameters: Vec::new(),
+        },
+        ToolDefinition {
+            name: "read_file".to_string(),
+            description: "Read a text file".to_string(),
+            parameters: r#"{"path": "file path, e.g. src/main.rs"}"#.to_string(),
+            command: String::new(),
+            sandbox: true,
+            path_parameters: vec!["path".to_string()],
+        },
+    ]
+}
+
+/// Reads the `[tools.<name>]` tables of a config into tool definitions
+///
+/// Tables without a command are skipped with a warning. Without a
+/// `path_parameters` list, parameters named like paths (`path`, `file`,
+/// `dir`, or ending in `_path`, `_file`, `_dir`) are path parameters.
+fn parse_tool_definitions(content: &str) -> Vec<ToolDefinition> {
+    let (root, _) = toml_lite::parse(content);
+    let tools = match root.get_table("tools") {
+        Some(tools) => tools,
+        None => return Vec::new(),
+    };
+    tools.tables()
+        .filter_map(|(name, table)| {
+            let field = |key: &str| table.get_text(key).unwrap_or_default();
+            let command = field("command");
+            if command.is_empty() {
+                println!("Warning: [tools.{}] has no command and is ignored", name);
+                return None;
+            }
+            let parameters = if field("parameters").is_empty() { "{}".to_string() } else { field("parameters") };
+            let path_parameters = match table.get_text("path_parameters") {
+                Some(list) => list.split(',').map(str::trim).filter(|name| !name.is_empty()).map(String::from).collect(),
+                None => tool_parameter_names(&parameters).into_iter()
+                    .filter(|name| ["path", "file", "dir"].contains(&name.as_str())
+                        || ["_path", "_file", "_dir"].iter().any(|suffix| name.ends_with(suffix)))
+                    .collect(),
+            };
+            Some(ToolDefinition {
+                name: name.to_string(),
+                description: field("description"),
+                parameters,
+                command,
+                sandbox: table.get_bool("sandbox") != Some(false),
+                path_parameters,
+            })
+        })
+        .collect()
+}
+
+/// Gets all tools for an agent session: the built-ins plus the config's tool registry
+fn read_tool_registry() -> Vec<ToolDefinition> {
+    let content = get_config_path()
+        .and_then(|path| fs::read_to_string(path).map_err(|e| e.to_string()))
+        .unwrap_or_default();
+    let mut tools = builtin_tool_definitions();
+    for tool in parse_tool_definitions(&content) {
+        if tools.iter().any(|existing| existing.name == tool.name) {
+            println!("Warning: [tools.{}] has the name of a built-in tool and is ignored", tool.name);
+            continue;
+        }
+        tools.push(tool);
+    }
+    tools
+}
+
+/// Lists the tools for the model, as part of the agent system prompt
+fn describe_tools_for_prompt(tools: &[ToolDefinition]) -> String {
+    let lines: Vec<String> = tools.iter()
+        .map(|tool| format!("- {}: {}. Arguments: {}", tool.name, tool.description, tool.parameters))
+        .collect();
+    format!("Available tools:\n{}", lines.join("\n"))
+}
+
+/// Gets the parameter names (the keys) from a tool's JSON parameters object
+///
+/// # Returns
+/// The keys in order, or nothing if the parameters are not a JSON object
+fn tool_parameter_names(parameters: &str) -> Vec<String> {
+    json::parse(parameters).ok()
+        .and_then(|value| value.as_object().map(|entries| entries.iter().map(|(key, _)| key.clone()).collect()))
+        .unwrap_or_default()
+}
+
+/// Builds the argv for a registry tool from its command template and the model's arguments
+///
+/// The template is split into words first and each `{name}` is then
+/// replaced inside its word, so an argument value is never split or
+/// interpreted by a shell. Each word is filled in one pass, so a value
+/// containing `{other}` is kept as it is.
+///
+/// Path parameters must resolve to a path under `root` and are passed
+/// as that absolute path. A value may not turn a word into an option
+/// (`--output=/etc/x`), so one starting with `-` at the start of a word is refused.
+fn build_tool_command(tool: &ToolDefinition, arguments: &json::Value, root: &Path) -> Result<Vec<String>, String> {
+    let names = tool_parameter_names(&tool.parameters);
+    let words = split_command_line(&tool.command).into_iter()
+        .map(|word| {
+            let filled = fill_tool_placeholders(&word, &names, |name| {
+                let value = match arguments.get(name) {
+                    Some(json::Value::String(text)) => Some(text.clone()),
+                    Some(number @ json::Value::Number(_)) => Some(number.to_string()),
+                    _ => None,
+                };
+                let value = value.ok_or_else(|| format!("{} needs a \"{}\" argument", tool.name, name))?;
+                if tool.path_parameters.iter().any(|path_name| path_name == name) {
+                    return resolve_path_under_root(root, &value).map(|path| path.to_string_lossy().to_string());
+                }
+                Ok(value)
+            })?;
+            if filled.starts_with('-') && !word.starts_with('-') {
+                return Err(format!("{}: an argument may not start with '-' ({})", tool.name, filled));
+            }
+            Ok(filled)
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+    if words.is_empty() {
+        return Err(format!("{} has an empty command", tool.name));
+    }
+    Ok(words)
+}
+
+/// Replaces each `{name}` of a known parameter in a template word, left to right
+///
+/// Inserted values are not scanned again; braces around anything else are kept.
+fn fill_tool_placeholders(
+    word: &str,
+    names: &[String],
+    value_of: impl Fn(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut filled = String::new();
+    let mut rest = word;
+    while let Some(open) = rest.find('{') {
+        filled.push_str(&rest[..open]);
+        let after_open = &rest[open + 1..];
+        match after_open.find('}').map(|close| &after_open[..close]) {
+            Some(name) if names.iter().any(|known| known == name) => {
+                filled.push_str(&value_of(name)?);
+                rest = &after_open[name.len() + 1..];
+            },
+            _ => {
+                filled.push('{');
+                rest = after_open;
+            },
+        }
+    }
+    filled.push_str(rest);
+    Ok(filled)
+}
+
+/// A tool call requested by the model
+#[derive(Debug, Clone, PartialEq)]
+struct ToolCall {
+    name: String,            // tool name, e.g. "run_command"
+    arguments: json::Value,  // the whole JSON object the model sent
+}
+
+/// Finds a tool call in a model reply: a line `TOOL: {"tool": "...", ...}`
+///
+/// Text after the object's closing brace (models sometimes add a remark) is ignored.
+fn parse_tool_call(reply: &str) -> Option<ToolCall> {
+    reply.lines()
+        .filter_map(|line| line.trim().strip_prefix("TOOL:"))
+        .find_map(|text| {
+            let text = text.trim();
+            let arguments = json::parse(&text[..text.rfind('}')? + 1]).ok()?;
+            let name = arguments.get("tool")?.as_str()?.to_string();
+            Some(ToolCall { name, arguments })
+        })
+}
+
+/// Resolves a path the model asked for, refusing anything outside `root`
+///
+/// Relative paths are taken from `root`. Symlinks and `..` are resolved
+/// before the check, so they cannot be used to leave the root.
+fn resolve_path_under_root(root: &Path, requested: &str) -> Result<PathBuf, String> {
+    let root = root.canonicalize()
+        .map_err(|e| format!("Agent file root {} is not accessible: {}", root.display(), e))?;
+    let joined = if Path::new(requested).is_absolute() {
+        PathBuf::from(requested)
+    } else {
+        root.join(requested)
+    };
+    let resolved = joined.canonicalize()
+        .map_err(|e| format!("Cannot open {}: {}", requested, e))?;
+    if !resolved.starts_with(&root) {
+        return Err(format!("{} is outside the allowed root {}", requested, root.display()));
+    }
+    Ok(resolved)
+}
+
+/// Gets the root directory the agent may read files under
+///
+/// `agent_file_root` in the config, or the current directory.
+fn get_agent_file_root() -> Result<PathBuf, String> {
+    let configured = read_field_from_toml("agent_file_root");
+    if configured.is_empty() {
+        std::env::current_dir().map_err(|e| format!("Cannot get current directory: {}", e))
+    } else {
+        Ok(PathBuf::from(configured))
+    }
+}
+
+/// Runs one tool call and returns the text to send back to the model
+///
+/// For run_command, the program must be in `agent_command_allowlist`; for
+/// read_file, the file must be under the agent file root. Registry tools run
+/// their configured command. Refusals are returned as text so the model can adjust.
+fn execute_tool_call(call: &ToolCall, tools: &[ToolDefinition]) -> String {
+    let result = match call.name.as_str() {
+        "run_command" => call.arguments.get("command").and_then(json::Value::as_str)
+            .ok_or_else(|| "run_command needs a \"command\" argument".to_string())
+            .and_then(|command| {
+                let program = split_command_line(command).into_iter().next().unwrap_or_default();
+                if !is_command_allowed(&program, &read_config().get_string_list("agent_command_allowlist").join(",")) {
+                    return Err(format!("'{}' is not an allowed command", program));
+                }
+                run_agent_command(&split_command_line(command), true)
+            }),
+        "read_file" => call.arguments.get("path").and_then(json::Value::as_str)
+            .ok_or_else(|| "read_file needs a \"path\" argument".to_string())
+            .and_then(|path| {
+                let resolved = resolve_path_under_root(&get_agent_file_root()?, path)?;
+                fs::read_to_string(&resolved)
+                    .map(truncate_tool_output)
+                    .map_err(|e| format!("Cannot read {}: {}", path, e))
+            }),
+        other => match tools.iter().find(|tool| tool.name == other && !tool.command.is_empty()) {
+            Some(tool) => get_agent_file_root()
+                .and_then(|root| build_tool_command(tool, &call.arguments, &root))
+                .and_then(|argv| run_agent_command(&argv, tool.sandbox)),
+            None => Err(format!("Unknown tool '{}'", other)),
+        },
+    };
+    match result {
+        Ok(output) => output,
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+/// Sandbox programs that can wrap agent-run commands
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SandboxKind {
+    Bubblewrap,   // bwrap (Linux)
+    Firejail,     // firejail (Linux)
+    SandboxExec,  // sandbox-exec (macOS)
+    PsExec,       // psexec -l, a restricted low-integrity token (Windows)
+}
+
+impl SandboxKind {
+    fn program(&self) -> &'static str {
+        match self {
+            SandboxKind::Bubblewrap => "bwrap",
+            SandboxKind::Firejail => "firejail",
+            SandboxKind::SandboxExec => "sandbox-exec",
+            SandboxKind::PsExec => "psexec",
+        }
+    }
+}
+
+/// Checks whether a program can be found in PATH (with `.exe` on Windows)
+fn program_in_path(program: &str) -> bool {
+    let names = if cfg!(windows) { vec![program.to_string(), format!("{}.exe", program)] } else { vec![program.to_string()] };
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|dir| names.iter().any(|name| dir.join(name).is_file())))
+        .unwrap_or(false)
+}
+
+/// Picks the sandbox from the `agent_sandbox` setting
+///
+/// `auto` (the default) uses the first available sandbox for this OS;
+/// `bwrap`, `firejail`, `sandbox-exec`, or `psexec` require that one; `off`
+/// runs commands unsandboxed. PsExec does not block the network, so `auto`
+/// never picks it: on Windows it must be chosen explicitly.
+///
+/// # Arguments
+/// * `setting` - Value of agent_sandbox ("" means auto)
+/// * `is_available` - Checks whether a sandbox program is installed
+///
+/// # Returns
+/// - Ok(Some(kind)): Wrap commands with this sandbox
+/// - Ok(None): Sandboxing is turned off
+/// - Err(String): The wanted sandbox is not available, so commands must not run
+fn choose_sandbox(setting: &str, is_available: impl Fn(&str) -> bool) -> Result<Option<SandboxKind>, String> {
+    let candidates: Vec<SandboxKind> = match setting {
+        "off" => return Ok(None),
+        "" | "auto" => if cfg!(target_os = "macos") {
+            vec![SandboxKind::SandboxExec]
+        } else if cfg!(target_os = "linux") {
+            vec![SandboxKind::Bubblewrap, SandboxKind::Firejail]
+        } else {
+            Vec::new()
+        },
+        "bwrap" => vec![SandboxKind::Bubblewrap],
+        "firejail" => vec![SandboxKind::Firejail],
+        "sandbox-exec" => vec![SandboxKind::SandboxExec],
+        "psexec" => vec![SandboxKind::PsExec],
+        other => return Err(format!(
+            "Unknown agent_sandbox '{}'. Use auto, bwrap, firejail, sandbox-exec, psexec, or off.", other
+        )),
+    };
+
+    candidates.into_iter()
+        .find(|kind| is_available(kind.program()))
+        .map(Some)
+        .ok_or_else(|| "No sandbox is available for agent commands (install bubblewrap or firejail on Linux; \
+                        on Windows, agent_sandbox = \"psexec\" lowers their privileges but leaves the network \
+                        reachable; the BSDs are not supported). Commands were not run. \
+                        Set agent_sandbox = \"off\" in the config to run them unsandboxed.".to_string())
+}
+
+/// Wraps a command so it runs with a read-only filesystem and, except under
+/// PsExec, no network
+///
+/// The project root is mounted read-only and used as the working directory;
+/// /tmp is a private scratch directory where the sandbox supports it.
+/// PsExec's `-l` (Windows) runs the command with a restricted token at low
+/// integrity, so it cannot write to the user's files, but the network stays
+/// reachable. PsExec's license must have been accepted once by running it.
+fn wrap_in_sandbox(kind: SandboxKind, argv: &[String], root: &Path) -> Vec<String> {
+    let root = root.to_string_lossy().to_string();
+    let mut wrapped: Vec<String> = match kind {
+        SandboxKind::Bubblewrap => vec![
+            "bwrap", "--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp",
+            "--unshare-all", "--die-with-parent", "--ro-bind", &root, &root, "--chdir", &root, "--",
+        ].into_iter().map(String::from).collect(),
+        SandboxKind::Firejail => vec![
+            "firejail".to_string(), "--quiet".to_string(), "--net=none".to_string(),
+            "--private-tmp".to_string(), "--read-only=/".to_string(), "--".to_string(),
+        ],
+        SandboxKind::SandboxExec => vec![
+            "sandbox-exec".to_string(),
+            "-p".to_string(),
+            "(version 1)(allow default)(deny network*)(deny file-write*)(allow file-write* (literal \"/dev/null\"))".to_string(),
+        ],
+        SandboxKind::PsExec => vec![
+            "psexec", "-nobanner", "-l", "-w", &root,
+        ].into_iter().map(String::from).collect(),
+    };
+    wrapped.extend(argv.iter().cloned());
+    wrapped
+}
+
+/// Runs an agent tool command in the agent file root, sandboxed unless the tool opts out
+fn run_agent_command(argv: &[String], sandboxed: bool) -> Result<String, String> {
+    let root = get_agent_file_root()?;
+    let sandbox = if sandboxed {
+        choose_sandbox(&read_field_from_toml("agent_sandbox"), program_in_path)?
+    } else {
+        None
+    };
+    match sandbox {
+        Some(kind) => run_argv_capture(&wrap_in_sandbox(kind, argv, &root), Some(&root)),
+        None => run_argv_capture(argv, Some(&root)),
+    }
+}
+
+/// Experimental local agent: `query_gguf agent <mode> [--max-steps N] [--raw]`
+///
+/// Chats with the mode's model in llama-server like `chat`, but the model
+/// may ask to run an allowlisted command (`agent_command_allowlist`), read
+/// a file under `agent_file_root`, or use a tool from the config's
+/// `[tools.<name>]` registry. Each tool call is shown and must be
+/// confirmed; its result is sent back and the model continues, up to
+/// --max-steps tool calls per question.
+///
+/// Commands run in a sandbox (no network, read-only files) chosen by
+/// `agent_sandbox`; if no sandbox is available they are refused.
+fn handle_agent_command(args: &[String]) -> Result<(), String> {
+    let selector = args.first()
+        .ok_or_else(|| "Missing mode. Use: agent <mode number or id> [--max-steps N] [--raw]".to_string())?;
+    let raw_output = args.iter().any(|arg| arg == "--raw");
+    let max_steps = match args.iter().position(|arg| arg == "--max-steps") {
+        Some(index) => args.get(index + 1)
+            .and_then(|value| value.parse::<usize>().ok())
+            .ok_or_else(|| "--max-steps needs a number".to_string())?,
+        None => DEFAULT_AGENT_MAX_STEPS,
+    };
+    let modes = read_saved_modes()?;
+    let mode = find_mode_by_selector(&modes, selector)
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?
+        .clone();
+
+    let tools = read_tool_registry();
+    let mode_prompt = fs::read_to_string(&mode.prompt_path).unwrap_or_default();
+    let mut messages = vec![ChatMessage {
+        role: "system".to_string(),
+        content: format!(
+            "{}\n{}\n\n{}",
+            AGENT_SYSTEM_PROMPT, describe_tools_for_prompt(&tools), mode_prompt.trim()
+        ).trim().to_string(),
+    }];
+
+    let server = start_or_attach_server(&mode)?;
+    let session_log = SessionLog::start("agent", &mode.id);
+    println!("\nAgent mode (experimental) with {} [{}]. Every tool call asks for confirmation.", mode.name, mode.id);
+    if read_field_from_toml("agent_sandbox") == "psexec" {
+        println!("Warning: agent_sandbox = \"psexec\" only lowers the privileges of commands; they can still reach the network.");
+    }
+    println!("Type /quit to exit.");
+
+    loop {
+        print!("\n> ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let input = read_user_input_with_history("agent")?;
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        if input == "/quit" || input == "/exit" {
+            break;
+        }
+        messages.push(ChatMessage { role: "user".to_string(), content: input.to_string() });
+        session_log.append("user", input);
+
+        let mut steps = 0;
+        loop {
+            let reply = match server.chat(&messages, &mode.parameters, -1, None) {
+                Ok(result) => result.content,
+                Err(e) => {
+                    println!("Error: {}", e);
+                    session_log.append("error", &e);
+                    break;
+                },
+            };
+            session_log.append("assistant", &reply);
+            messages.push(ChatMessage { role: "assistant".to_string(), content: reply.clone() });
+
+            let Some(call) = parse_tool_call(&reply) else {
+                println!("\n{}", format_reply_for_display(&reply, raw_output));
+                break;
+            };
+            if steps >= max_steps {
+                println!("\nStopped: reached the limit of {} tool calls for this question.", max_steps);
+                break;
+            }
+            steps += 1;
+
+            println!("\n[step {}/{}] The model wants to use {}: {}", steps, max_steps, call.name, call.arguments);
+            let result = if prompt_yes_no("Allow?")? {
+                execute_tool_call(&call, &tools)
+            } else {
+                "Error: the user declined this tool call".to_string()
+            };
+            let preview: String = result.chars().take(500).collect();
+            println!("{}", preview);
+            session_log.append("tool", &format!("{} {}\n{}", call.name, call.arguments, result));
+            messages.push(ChatMessage {
+                role: "user".to_string(),
+                content: format!("TOOL RESULT ({}):\n{}", call.name, result),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Interactive parameter experimentation for a saved mode: `query_gguf tune <mode>`
+///
+/// Starts llama-server once so the model stays loaded, then repeatedly runs
+/// the mode's (fixed) prompt while the user changes sampling parameters:
+/// ```text
+/// temp 0.5     set temperature
+/// topk 40      set top-k
+/// topp 0.8     set top-p
+/// n 256        set max tokens per generation
+/// regen        generate again with the current settings (also: r, Enter)
+/// history      list earlier generations and their settings
+/// save         write the current temp/top_k/top_p back to the mode
+/// quit         stop the server and exit (also: q)
+/// ```
+fn handle_tune_command(args: &[String]) -> Result<(), String> {
+    let selector = args.first()
+        .ok_or_else(|| "Missing mode. Use: tune <mode number or id>".to_string())?;
+    let modes = read_saved_modes()?;
+    let mode_index = match selector.parse::<usize>() {
+        Ok(number) => number.checked_sub(1).filter(|index| *index < modes.len()),
+        Err(_) => modes.iter().position(|mode| mode.id == *selector),
+    }.ok_or_else(|| format!("Unknown mode: {}", selector))?;
+    let mode = modes[mode_index].clone();
+
+    let prompt = fs::read_to_string(&mode.prompt_path)
+        .map_err(|e| format!("Failed to read prompt {}: {}", mode.prompt_path, e))?;
+    if prompt.trim().is_empty() {
+        return Err(format!("The prompt of mode '{}' is empty, nothing to generate from", mode.id));
+    }
+
+    let server = start_or_attach_server(&mode)?;
+    let mut params = mode.parameters.clone();
+    let mut max_tokens: i32 = 256;
+    // (settings summary, output) of each generation
+    let mut history: Vec<(String, String)> = Vec::new();
+    let mut session_timing: Option<SessionTiming> = None;
+
+    println!("\n=== Tune: {} [{}] ===", mode.name, mode.id);
+    println!("Commands: temp X | topk N | topp X | n N | regen (r, Enter) | history | save | quit (q)");
+
+    let mut generate = true;
+    loop {
+        if generate {
+            let settings = format!(
+                "temp={} top_k={} top_p={} n={}",
+                params.temperature_value, params.top_k_sampling, params.top_p_sampling, max_tokens
+            );
+            println!("\n--- Generation {} ({}) ---", history.len() + 1, settings);
+            match server.complete(&prompt, &params, max_tokens) {
+                Ok(result) => {
+                    println!("{}", result.content.trim());
+                    let tokens_per_second = if result.predicted_ms > 0.0 {
+                        result.tokens_predicted as f64 / (result.predicted_ms / 1000.0)
+                    } else {
+                        0.0
+                    };
+                    println!("({} prompt tokens in {:.0}ms, {} generated at {:.1} tok/s)",
+                        result.tokens_evaluated, result.prompt_ms, result.tokens_predicted, tokens_per_second);
+
+                    // Load time and first token latency are taken once per session,
+                    // generated tokens are counted over the whole session
+                    match session_timing.as_mut() {
+                        None => {
+                            let timing = SessionTiming {
+                                kind: "tune".to_string(),
+                                mode_id: mode.id.clone(),
+                                model_path: mode.model_path.clone(),
+                                load_ms: server.load_time.as_secs_f64() * 1000.0,
+                                first_token_ms: result.prompt_ms,
+                                tokens_per_second,
+                                tokens_generated: result.tokens_predicted,
+                            };
+                            display_session_timing(&timing);
+                            session_timing = Some(timing);
+                        },
+                        Some(timing) => timing.tokens_generated += result.tokens_predicted,
+                    }
+                    history.push((settings, result.content));
+                },
+                Err(e) => println!("Error: {}", e),
+            }
+            generate = false;
+        }
+
+        print!("\ntune> ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let input = read_user_input()?;
+        let mut words = input.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let argument = words.next().unwrap_or("");
+
+        match command {
+            "" | "r" | "regen" => generate = true,
+            "temp" => match argument.parse() {
+                Ok(value) => { params.temperature_value = value; generate = true; },
+                Err(_) => println!("Usage: temp 0.7"),
+            },
+            "topk" => match argument.parse() {
+                Ok(value) => { params.top_k_sampling = value; generate = true; },
+                Err(_) => println!("Usage: topk 40"),
+            },
+            "topp" => match argument.parse() {
+                Ok(value) => { params.top_p_sampling = value; generate = true; },
+                Err(_) => println!("Usage: topp 0.9"),
+            },
+            "n" => match argument.parse() {
+                Ok(value) => { max_tokens = value; generate = true; },
+                Err(_) => println!("Usage: n 256"),
+            },
+            "history" => {
+                for (index, (settings, output)) in history.iter().enumerate() {
+                    let preview: String = output.trim().chars().take(120).collect();
+                    println!("{}. ({}) {}", index + 1, settings, preview.replace('\n', " "));
+                }
+            },
+            "save" => {
+                let config_path = get_config_path()?;
+                let content = fs::read_to_string(&config_path)
+                    .map_err(|e| format!("Failed to read config at {}: {}", config_path.display(), e))?;
+                let updated = update_mode_entry_parameters(&content, mode_index, &[
+                    ("temp", params.temperature_value.to_string()),
+                    ("top_k", params.top_k_sampling.to_string()),
+                    ("top_p", params.top_p_sampling.to_string()),
+                ])?;
+                backup_existing_config()?;
+                write_config_file(&config_path, &updated, "tune save")?;
+                println!("Saved temp={} top_k={} top_p={} to mode '{}'",
+                    params.temperature_value, params.top_k_sampling, params.top_p_sampling, mode.id);
+            },
+            "q" | "quit" | "exit" => break,
+            other => println!("Unknown command: {}", other),
+        }
+    }
+
+    if let Some(timing) = session_timing {
+        if let Err(e) = record_session_timing(&timing) {
+            println!("Warning: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Settings `bench --sweep` can vary (n is the number of tokens to generate)
+const BENCH_SWEEP_KEYS: &[&str] = &["temp", "top_k", "top_p", "n"];
+
+/// Most generations one bench run may do
+const MAX_BENCH_RUNS: usize = 1000;
+
+/// Characters of each output kept in the bench CSV
+const BENCH_SAMPLE_CHARS: usize = 80;
+
+/// Parses one `--sweep` value into a setting name and the values to try
+///
+/// Accepts `key=start:end:step` (inclusive range), `key=a,b,c`, or `key=value`.
+///
+/// # Examples
+/// ```
+/// assert_eq!(parse_sweep("temp=0.2:0.6:0.2").unwrap(), ("temp".to_string(), vec!["0.2".to_string(), "0.4".to_string(), "0.6".to_string()]));
+/// ```
+fn parse_sweep(spec: &str) -> Result<(String, Vec<String>), String> {
+    let (key, values) = spec.split_once('=')
+        .ok_or_else(|| format!("--sweep needs key=values, got '{}'", spec))?;
+    let key = key.trim();
+    if !BENCH_SWEEP_KEYS.contains(&key) {
+        return Err(format!("Cannot sweep '{}' (use one of: {})", key, BENCH_SWEEP_KEYS.join(", ")));
+    }
+
+    let values: Vec<String> = match values.split(':').collect::<Vec<_>>()[..] {
+        [start, end, step] => {
+            let parse = |value: &str| value.trim().parse::<f64>()
+                .map_err(|_| format!("Invalid number '{}' in --sweep {}", value, spec));
+            let (start, end, step) = (parse(start)?, parse(end)?, parse(step)?);
+            if step <= 0.0 || end < start {
+                return Err(format!("--sweep {} needs start <= end and a step above 0", spec));
+            }
+            let count = ((end - start) / step + 1e-9).floor() as usize + 1;
+            if count > MAX_BENCH_RUNS {
+                return Err(format!("--sweep {} has {} values, more than {}", spec, count, MAX_BENCH_RUNS));
+            }
+            // Rounded so 0.2 + 2 * 0.2 prints as 0.6
+            (0..count).map(|i| ((start + i as f64 * step) * 1e6).round() / 1e6).map(|value| value.to_string()).collect()
+        },
+        [list] => list.split(',').map(|value| value.trim().to_string()).filter(|value| !value.is_empty()).collect(),
+        _ => return Err(format!("--sweep {} must be a list (a,b,c) or a range (start:end:step)", spec)),
+    };
+    if values.is_empty() {
+        return Err(format!("--sweep {} has no values", spec));
+    }
+    for value in &values {
+        let valid = match key {
+            "temp" | "top_p" => value.parse::<f32>().is_ok(),
+            _ => value.parse::<i32>().map(|number| number > 0 || key == "top_k").unwrap_or(false),
+        };
+        if !valid {
+            return Err(format!("Invalid value '{}' for {} in --sweep", value, key));
+        }
+    }
+    Ok((key.to_string(), values))
+}
+
+/// Cartesian product of sweeps: every combination of one value per setting
+///
+/// The first sweep changes slowest, like nested loops in the order given.
+fn sweep_combinations(sweeps: &[(String, Vec<String>)]) -> Vec<Vec<(String, String)>> {
+    let mut combinations: Vec<Vec<(String, String)>> = vec![Vec::new()];
+    for (key, values) in sweeps {
+        combinations = combinations.iter()
+            .flat_map(|combination| values.iter().map(move |value| {
+                let mut extended = combination.clone();
+                extended.push((key.clone(), value.clone()));
+                extended
+            }))
+            .collect();
+    }
+    combinations
+}
+
+/// Quotes a CSV field when it holds a comma, quote, or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Runs a fixed prompt over a grid of sampling settings: `query_gguf bench <mode> --sweep ...`
+///
+/// ```text
+/// bench 2 --sweep "temp=0.2:1.0:0.2" --sweep "top_p=0.8,0.9,0.95" [-q "prompt"] [--out results.csv]
+/// ```
+/// Every combination of the sweeps is generated once, with the model kept
+/// loaded in llama-server. The prompt is -q, or the mode's prompt file.
+/// The CSV (one row per combination: settings, token counts, timings, and
+/// the start of the output) goes to --out, or ~/query_gguf/reports/.
+fn handle_bench_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: bench <mode> --sweep key=start:end:step|key=a,b,c [--sweep ...] [-q \"prompt\"] [--out file.csv]";
+    let selector = args.first().filter(|arg| !arg.starts_with('-')).ok_or_else(|| usage.to_string())?;
+    let mut sweeps: Vec<(String, Vec<String>)> = Vec::new();
+    let mut question: Option<String> = None;
+    let mut output_path: Option<PathBuf> = None;
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().cloned().ok_or_else(|| format!("Missing value after {}", arg));
+        match arg.as_str() {
+            "--sweep" => {
+                let (key, values) = parse_sweep(&value()?)?;
+                if sweeps.iter().any(|(existing, _)| *existing == key) {
+                    return Err(format!("{} is swept twice", key));
+                }
+                sweeps.push((key, values));
+            },
+            "-q" | "--question" => question = Some(value()?),
+            "--out" => output_path = Some(PathBuf::from(value()?)),
+            other => return Err(format!("Unknown option: {}. {}", other, usage)),
+        }
+    }
+    if sweeps.is_empty() {
+        return Err(usage.to_string());
+    }
+    let combinations = sweep_combinations(&sweeps);
+    if combinations.len() > MAX_BENCH_RUNS {
+        return Err(format!("The sweeps make {} combinations, more than {}", combinations.len(), MAX_BENCH_RUNS));
+    }
+
+    let modes = read_saved_modes()?;
+    let mode = find_mode_by_selector(&modes, selector)
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?
+        .clone();
+    let prompt = match question {
+        Some(question) => question,
+        None => fs::read_to_string(&mode.prompt_path)
+            .map_err(|e| format!("Failed to read prompt {}: {}", mode.prompt_path, e))?,
+    };
+    if prompt.trim().is_empty() {
+        return Err(format!("The prompt of mode '{}' is empty; give one with -q", mode.id));
+    }
+
+    let output_path = match output_path {
+        Some(path) => path,
+        None => {
+            let reports_dir = get_app_base_dir()?.join("reports");
+            fs::create_dir_all(&reports_dir)
+                .map_err(|e| format!("Failed to create {}: {}", reports_dir.display(), e))?;
+            reports_dir.join(format!("bench_{}_{}.csv", mode.id, generate_timestamp_string()))
+        },
+    };
+
+    let server = start_or_attach_server(&mode)?;
+    println!("\n=== Bench: {} [{}], {} combination(s) ===", mode.name, mode.id, combinations.len());
+
+    let mut csv = sweeps.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>().join(",");
+    csv.push_str(",prompt_tokens,generated_tokens,prompt_ms,generation_ms,tokens_per_second,sample\n");
+    for (index, combination) in combinations.iter().enumerate() {
+        let mut params = mode.parameters.clone();
+        let mut max_tokens: i32 = 256;
+        let parts: Vec<String> = combination.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        for (key, value) in combination {
+            if key == "n" {
+                max_tokens = value.parse().unwrap_or(max_tokens);
+            }
+        }
+        let part_refs: Vec<&str> = parts.iter().map(String::as_str).collect();
+        apply_parameters_from_parts(&mut params, &part_refs);
+
+        println!("[{}/{}] {}", index + 1, combinations.len(), parts.join(" "));
+        let values: Vec<String> = combination.iter().map(|(_, value)| value.clone()).collect();
+        match server.complete(&prompt, &params, max_tokens) {
+            Ok(result) => {
+                let tokens_per_second = if result.predicted_ms > 0.0 {
+                    result.tokens_predicted as f64 / (result.predicted_ms / 1000.0)
+                } else {
+                    0.0
+                };
+                println!("  {} tokens at {:.1} tok/s", result.tokens_predicted, tokens_per_second);
+                csv.push_str(&format!(
+                    "{},{},{},{:.0},{:.0},{:.2},{}\n",
+                    values.join(","),
+                    result.tokens_evaluated,
+                    result.tokens_predicted,
+                    result.prompt_ms,
+                    result.predicted_ms,
+                    tokens_per_second,
+                    csv_field(&one_line_preview(&result.content, BENCH_SAMPLE_CHARS))
+                ));
+            },
+            Err(e) => {
+                println!("  Error: {}", e);
+                csv.push_str(&format!("{},,,,,,{}\n", values.join(","), csv_field(&format!("error: {}", e))));
+            },
+        }
+    }
+
+    fs::write(&output_path, csv)
+        .map_err(|e| format!("Failed to write {}: {}", output_path.display(), e))?;
+    println!("\nBench results written to {}", output_path.display());
+    Ok(())
+}
+
+/// Runs of `verify-determinism` without --runs
+const DETERMINISM_DEFAULT_RUNS: usize = 3;
+
+/// Byte offset where two outputs first differ (None if identical)
+fn first_difference(a: &str, b: &str) -> Option<usize> {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+        Some(offset) => Some(offset),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+/// Groups run outputs by content: the (1-based) run numbers of each distinct output, first seen first
+fn group_identical_outputs(outputs: &[String]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (index, output) in outputs.iter().enumerate() {
+        match groups.iter_mut().find(|group| outputs[group[0] - 1] == *output) {
+            Some(group) => group.push(index + 1),
+            None => groups.push(vec![index + 1]),
+        }
+    }
+    groups
+}
+
+/// Likely sources of nondeterminism in a mode's setup, as advice lines
+fn nondeterminism_hints(mode: &ChatModeConfig) -> Vec<String> {
+    let mut hints = Vec::new();
+    if !mode.endpoint.is_empty() {
+        hints.push(format!("endpoint={}: hosted servers may ignore the seed or batch requests together", mode.endpoint));
+    }
+    if mode.parameters.thread_count > 1 {
+        hints.push(format!("threads={}: multi-threaded CPU math can sum in a different order; try threads=1", mode.parameters.thread_count));
+    }
+    if mode.parameters.gpu_layers > 0 {
+        hints.push(format!("gpu_layers={}: GPU kernels (CUDA, Metal, Vulkan) are not always deterministic; try gpu_layers=0", mode.parameters.gpu_layers));
+    }
+    if !mode.backend.is_empty() {
+        hints.push(format!("backend={}: compare with the default llama.cpp build", mode.backend));
+    }
+    hints
+}
+
+/// Checks that a seeded prompt gives byte-identical output: `query_gguf verify-determinism <mode>`
+///
+/// ```text
+/// verify-determinism 2 [--runs N] [--seed S] [-n TOKENS] [-q "prompt"]
+/// ```
+/// The prompt (-q, with the mode's prompt file as system message, or the
+/// prompt file alone) is run N times (default 3) with the same seed on one
+/// llama-server. When outputs differ, the first difference is shown with
+/// the settings that commonly cause it (threads, GPU offload, backend).
+///
+/// # Returns
+/// - Ok(()): All runs gave the same output
+/// - Err(String): Outputs differed, or the runs could not be made
+fn handle_verify_determinism_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: verify-determinism <mode> [--runs N] [--seed S] [-n TOKENS] [-q \"prompt\"]";
+    let selector = args.first().filter(|arg| !arg.starts_with('-')).ok_or_else(|| usage.to_string())?;
+    let mut runs = DETERMINISM_DEFAULT_RUNS;
+    let mut seed: i64 = 42;
+    let mut max_tokens: i32 = 128;
+    let mut question: Option<String> = None;
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        let value = iter.next().ok_or_else(|| format!("Missing value after {}", arg))?;
+        match arg.as_str() {
+            "--runs" => runs = value.parse().ok().filter(|runs| *runs >= 2)
+                .ok_or_else(|| "--runs needs a number of runs (2 or more)".to_string())?,
+            "--seed" => seed = value.parse().map_err(|_| format!("Invalid --seed value: {}", value))?,
+            "-n" => max_tokens = value.parse().ok().filter(|tokens| *tokens > 0)
+                .ok_or_else(|| "-n needs a number of tokens (1 or more)".to_string())?,
+            "-q" | "--question" => question = Some(value.clone()),
+            other => return Err(format!("Unknown option: {}. {}", other, usage)),
+        }
+    }
+
+    let modes = read_saved_modes()?;
+    let mode = find_mode_by_selector(&modes, selector)
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?
+        .clone();
+    let prompt_text = fs::read_to_string(&mode.prompt_path).unwrap_or_default();
+    let messages: Vec<ChatMessage> = match question {
+        Some(question) if prompt_text.trim().is_empty() => vec![ChatMessage { role: "user".to_string(), content: question }],
+        Some(question) => vec![
+            ChatMessage { role: "system".to_string(), content: prompt_text.trim().to_string() },
+            ChatMessage { role: "user".to_string(), content: question },
+        ],
+        None if prompt_text.trim().is_empty() => {
+            return Err(format!("The prompt of mode '{}' is empty; give one with -q", mode.id));
+        },
+        None => vec![ChatMessage { role: "user".to_string(), content: prompt_text.trim().to_string() }],
+    };
+
+    let server = start_or_attach_server(&mode)?;
+    println!("\n=== Determinism: {} [{}], {} runs, seed {} ===", mode.name, mode.id, runs, seed);
+    let mut outputs: Vec<String> = Vec::new();
+    for run in 1..=runs {
+        let result = server.chat(&messages, &mode.parameters, max_tokens, Some(seed))?;
+        println!("Run {}: {} bytes: {}", run, result.content.len(), one_line_preview(&result.content, 60));
+        outputs.push(result.content);
+    }
+
+    let groups = group_identical_outputs(&outputs);
+    if groups.len() == 1 {
+        println!("\nDeterministic: all {} runs are byte-identical ({} bytes).", runs, outputs[0].len());
+        return Ok(());
+    }
+
+    println!("\nNOT deterministic: {} different outputs in {} runs.", groups.len(), runs);
+    for group in &groups {
+        let runs_list: Vec<String> = group.iter().map(|run| run.to_string()).collect();
+        println!("  runs {}", runs_list.join(", "));
+    }
+    let other = groups[1][0];
+    if let Some(offset) = first_difference(&outputs[0], &outputs[other - 1]) {
+        let context = |text: &str| {
+            let start = (0..=offset.min(text.len())).rev().find(|&i| text.is_char_boundary(i)).unwrap_or(0);
+            one_line_preview(&text[start..], 40)
+        };
+        println!("Run 1 and run {} first differ at byte {}:", other, offset);
+        println!("  run 1: {}", context(&outputs[0]));
+        println!("  run {}: {}", other, context(&outputs[other - 1]));
+    }
+    let hints = nondeterminism_hints(&mode);
+    if !hints.is_empty() {
+        println!("\nLikely causes:");
+        for hint in &hints {
+            println!("  - {}", hint);
+        }
+    }
+    Err(format!("Outputs of mode '{}' differ between runs with seed {}", mode.id, seed))
+}
+
+/// Reads batch inputs: one per non-empty line
+fn read_batch_inputs(content: &str) -> Vec<String> {
+    content.lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// First line of a batch checkpoint, identifying the run it belongs to
+///
+/// A checkpoint only resumes the same mode over the same inputs.
+fn batch_checkpoint_header(mode_id: &str, inputs: &[String]) -> String {
+    json::Value::object([
+        ("batch_mode", mode_id.into()),
+        ("input_hash", format!("{:016x}", fnv1a_hash(inputs.join("\n").as_bytes())).into()),
+        ("items", inputs.len().into()),
+    ]).to_string()
+}
+
+/// Output of one batch item and the id of the mode that wrote it
+type BatchOutput = (String, String);
+
+/// Reads the outputs saved in a batch checkpoint, by item index
+///
+/// Lines cut off by an interruption (no complete JSON) are ignored, so those
+/// items run again. Lines without a mode (written by a single-mode run
+/// before worker pools) get an empty mode id.
+///
+/// # Returns
+/// - Ok(Vec<Option<BatchOutput>>): (mode id, output) of each item, None for items still to do
+/// - Err(String): The checkpoint belongs to a different mode or input file
+fn parse_batch_checkpoint(content: &str, expected_header: &str, items: usize) -> Result<Vec<Option<BatchOutput>>, String> {
+    let mut lines = content.lines();
+    if lines.next() != Some(expected_header) {
+        return Err("The batch checkpoint is for a different mode or input file; use --restart to start over".to_string());
+    }
+    let mut outputs: Vec<Option<BatchOutput>> = vec![None; items];
+    for line in lines {
+        let Ok(entry) = json::parse(line) else { continue };
+        let index = entry.get("index").and_then(json::Value::as_u64).map(|index| index as usize);
+        if let (Some(index), Some(output)) = (index, entry.get("output").and_then(json::Value::as_str)) {
+            if index < items {
+                let mode_id = entry.get("mode").and_then(json::Value::as_str).unwrap_or_default();
+                outputs[index] = Some((mode_id.to_string(), output.to_string()));
+            }
+        }
+    }
+    Ok(outputs)
+}
+
+/// Most items one batch worker group may run at once
+const MAX_BATCH_WORKERS_PER_MODE: usize = 64;
+
+/// Parses `--workers coder:2,gpubox:1` into (mode selector, items run at once) pairs
+///
+/// A mode without a count runs one item at a time.
+fn parse_batch_workers(spec: &str) -> Result<Vec<(String, usize)>, String> {
+    let mut workers: Vec<(String, usize)> = Vec::new();
+    for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (selector, count) = match entry.rsplit_once(':') {
+            Some((selector, count)) => {
+                let count = count.trim().parse::<usize>().ok()
+                    .filter(|count| (1..=MAX_BATCH_WORKERS_PER_MODE).contains(count))
+                    .ok_or_else(|| format!("--workers {}: the count must be 1 to {}", entry, MAX_BATCH_WORKERS_PER_MODE))?;
+                (selector.trim(), count)
+            },
+            None => (entry, 1),
+        };
+        if selector.is_empty() {
+            return Err(format!("--workers {}: missing mode", entry));
+        }
+        if workers.iter().any(|(existing, _)| existing == selector) {
+            return Err(format!("--workers lists mode '{}' twice", selector));
+        }
+        workers.push((selector.to_string(), count));
+    }
+    if workers.is_empty() {
+        return Err("--workers needs mode:count entries, e.g. coder:2,gpubox:1".to_string());
+    }
+    Ok(workers)
+}
+
+/// Gets the server of a batch worker group that runs `slots` items at once
+///
+/// Local models get their own llama-server with `--parallel slots`; since
+/// llama-server splits ctx_size between slots, it is started with the
+/// mode's ctx_size times the slots. Endpoints take concurrent requests as they are.
+fn start_batch_server(mode: &ChatModeConfig, slots: usize) -> Result<LlamaServer, String> {
+    if !mode.remote.is_empty() {
+        return Err(format!("Mode '{}' runs llama-cli over ssh; batch workers need a local model or an endpoint", mode.id));
+    }
+    if slots <= 1 || !mode.endpoint.is_empty() {
+        return start_or_attach_server(mode);
+    }
+    let mut wide_mode = mode.clone();
+    wide_mode.parameters.context_size = wide_mode.parameters.context_size.saturating_mul(slots as i32);
+    let slots_arg = slots.to_string();
+    println!("Starting llama-server for '{}' with {} parallel slots", mode.id, slots);
+    LlamaServer::start_with_args(&wide_mode, &["--parallel", &slots_arg], false)
+}
+
+/// Formats seconds as e.g. "2h 05m", "4m 10s", or "12s"
+fn format_eta(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+/// Runs a mode over every line of an input file: `query_gguf batch <mode> <inputs.txt>`
+///
+/// ```text
+/// batch 2 inputs.txt [--out results.jsonl] [-n TOKENS] [--workers MODE:N,...] [--resume | --restart]
+/// ```
+/// Each non-empty line is sent as a user message (with the mode's prompt
+/// file as system message) to one llama-server. Results are written as JSON
+/// lines `{"index":..,"mode":..,"input":..,"output":..}` in input order to
+/// --out (default: `<inputs>.out.jsonl`).
+///
+/// `--workers coder:2,gpubox:1` spreads the items over a pool of modes (e.g.
+/// a local small model and an endpoint), each running up to N items at once;
+/// the batch mode itself runs one at a time unless it is listed. Items are
+/// handed out as workers become free, and results are still merged in input order.
+///
+/// Progress is checkpointed: every finished item is appended to
+/// `<out>.partial` right away, so a run stopped by sleep, reboot, or Ctrl-C
+/// continues with --resume where it stopped. The checkpoint is removed when
+/// the run completes. Failed items are not checkpointed and run again on resume.
+fn handle_batch_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: batch <mode> <inputs.txt> [--out results.jsonl] [-n TOKENS] [--workers MODE:N,...] [--resume | --restart]";
+    let (Some(selector), Some(input_path)) = (args.first(), args.get(1)) else {
+        return Err(usage.to_string());
+    };
+    let mut output_path = PathBuf::from(format!("{}.out.jsonl", input_path));
+    let mut max_tokens: i32 = -1;
+    let mut resume = false;
+    let mut restart = false;
+    let mut worker_specs: Vec<(String, usize)> = Vec::new();
+    let mut iter = args[2..].iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--resume" => resume = true,
+            "--restart" => restart = true,
+            "--workers" => worker_specs = parse_batch_workers(iter.next().ok_or_else(|| "Missing MODE:N,... after --workers".to_string())?)?,
+            "--out" => output_path = PathBuf::from(iter.next().ok_or_else(|| "Missing file after --out".to_string())?),
+            "-n" => max_tokens = iter.next().and_then(|value| value.parse().ok()).filter(|tokens| *tokens > 0)
+                .ok_or_else(|| "-n needs a number of tokens (1 or more)".to_string())?,
+            other => return Err(format!("Unknown option: {}. {}", other, usage)),
+        }
+    }
+    if resume && restart {
+        return Err("--resume and --restart cannot be used together".to_string());
+    }
+
+    let modes = read_saved_modes()?;
+    let mode = find_mode_by_selector(&modes, selector)
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?
+        .clone();
+    let inputs = read_batch_inputs(&fs::read_to_string(input_path)
+        .map_err(|e| format!("Failed to read inputs {}: {}", input_path, e))?);
+    if inputs.is_empty() {
+        return Err(format!("No inputs in {}", input_path));
+    }
+
+    // Pick up a checkpoint of an interrupted run
+    let checkpoint_path = PathBuf::from(format!("{}.partial", output_path.display()));
+    let header = batch_checkpoint_header(&mode.id, &inputs);
+    let mut outputs: Vec<Option<BatchOutput>> = vec![None; inputs.len()];
+    if checkpoint_path.exists() && !restart {
+        let saved = fs::read_to_string(&checkpoint_path)
+            .map_err(|e| format!("Failed to read checkpoint {}: {}", checkpoint_path.display(), e))?;
+        let saved_outputs = parse_batch_checkpoint(&saved, &header, inputs.len())?;
+        let done = saved_outputs.iter().filter(|output| output.is_some()).count();
+        if !resume {
+            return Err(format!(
+                "An earlier batch run was interrupted ({}/{} done, checkpoint {}); use --resume to continue or --restart to start over",
+                done, inputs.len(), checkpoint_path.display()
+            ));
+        }
+        println!("Resuming: {}/{} done, {} left", done, inputs.len(), inputs.len() - done);
+        outputs = saved_outputs;
+    }
+
+    let system_prompt = fs::read_to_string(&mode.prompt_path).unwrap_or_default();
+    let pending: Vec<usize> = (0..inputs.len()).filter(|index| outputs[*index].is_none()).collect();
+    let mut failed = 0;
+    if !pending.is_empty() {
+        // Worker groups: each mode with how many items it runs at once
+        let mut groups: Vec<(ChatModeConfig, usize)> = vec![(mode.clone(), 1)];
+        for (worker_selector, workers) in &worker_specs {
+            let worker_mode = find_mode_by_selector(&modes, worker_selector)
+                .ok_or_else(|| format!("Unknown mode in --workers: {}", worker_selector))?;
+            match groups.iter_mut().find(|(group_mode, _)| group_mode.id == worker_mode.id) {
+                Some(group) => group.1 = *workers,
+                None => groups.push((worker_mode.clone(), *workers)),
+            }
+        }
+        let servers = groups.iter()
+            .map(|(group_mode, workers)| start_batch_server(group_mode, *workers))
+            .collect::<Result<Vec<LlamaServer>, String>>()?;
+        // The checkpoint is started once the models are up, so a failed start leaves none behind
+        if !checkpoint_path.exists() || restart {
+            fs::write(&checkpoint_path, format!("{}\n", header))
+                .map_err(|e| format!("Failed to write checkpoint {}: {}", checkpoint_path.display(), e))?;
+        }
+        let mut checkpoint = fs::OpenOptions::new()
+            .append(true)
+            .open(&checkpoint_path)
+            .map_err(|e| format!("Failed to open checkpoint {}: {}", checkpoint_path.display(), e))?;
+        let pool: Vec<String> = groups.iter().map(|(group_mode, workers)| format!("{} x{}", group_mode.id, workers)).collect();
+        println!("\n=== Batch: {} [{}], {} item(s) to run on {} ===", mode.name, mode.id, pending.len(), pool.join(", "));
+        let session_log = SessionLog::start("batch", &mode.id);
+        session_log.append("info", &format!("{} item(s) to run on {}", pending.len(), pool.join(", ")));
+
+        // Workers take the next item from the queue and send back (index, mode id, result);
+        // only this thread writes the checkpoint and prints progress
+        let queue = std::sync::Mutex::new(pending.iter().copied().collect::<std::collections::VecDeque<usize>>());
+        let (sender, receiver) = std::sync::mpsc::channel::<(usize, String, Result<ChatResult, String>)>();
+        let started = std::time::Instant::now();
+        std::thread::scope(|scope| -> Result<(), String> {
+            for ((group_mode, workers), server) in groups.iter().zip(&servers) {
+                for _ in 0..*workers {
+                    let sender = sender.clone();
+                    let (queue, inputs, system_prompt) = (&queue, &inputs, &system_prompt);
+                    // The queue lock is held only while taking an item, not during the request
+                    let next_item = move || queue.lock().ok().and_then(|mut queue| queue.pop_front());
+                    scope.spawn(move || while let Some(index) = next_item() {
+                        let mut messages = Vec::new();
+                        if !system_prompt.trim().is_empty() {
+                            messages.push(ChatMessage { role: "system".to_string(), content: system_prompt.trim().to_string() });
+                        }
+                        messages.push(ChatMessage { role: "user".to_string(), content: inputs[index].clone() });
+                        let result = server.chat(&messages, &group_mode.parameters, max_tokens, None);
+                        if sender.send((index, group_mode.id.clone(), result)).is_err() {
+                            break;
+                        }
+                    });
+                }
+            }
+            drop(sender);
+
+            for (done, (index, mode_id, result)) in receiver.iter().enumerate() {
+                match result {
+                    Ok(result) => {
+                        let entry = json::Value::object([
+                            ("index", index.into()),
+                            ("mode", mode_id.as_str().into()),
+                            ("output", result.content.as_str().into()),
+                        ]);
+                        let written = writeln!(checkpoint, "{}", entry).and_then(|_| checkpoint.flush());
+                        if let Err(e) = written {
+                            // Stop handing out items; running ones finish and are dropped
+                            if let Ok(mut queue) = queue.lock() {
+                                queue.clear();
+                            }
+                            return Err(format!("Failed to write checkpoint {}: {}", checkpoint_path.display(), e));
+                        }
+                        session_log.append("user", &inputs[index]);
+                        session_log.append("assistant", &result.content);
+                        outputs[index] = Some((mode_id.clone(), result.content));
+                    },
+                    Err(e) => {
+                        failed += 1;
+                        println!("Item {} failed on {}: {}", index + 1, mode_id, e);
+                        session_log.append("error", &format!("Item {} failed on {}: {}", index + 1, mode_id, e));
+                    },
+                }
+
+                let finished = done + 1;
+                let seconds_per_item = started.elapsed().as_secs_f64() / finished as f64;
+                let remaining = ((pending.len() - finished) as f64 * seconds_per_item) as u64;
+                let progress = format!(
+                    "[{}/{}] item {} done by {} ({:.1}s/item, about {} left)",
+                    inputs.len() - pending.len() + finished, inputs.len(), index + 1, mode_id, seconds_per_item, format_eta(remaining)
+                );
+                println!("{}", progress);
+                session_log.append("info", &progress);
+            }
+            Ok(())
+        })?;
+    }
+
+    let completed = outputs.iter().filter(|output| output.is_some()).count();
+    println!("\nBatch summary: {} of {} item(s) done, {} failed this run", completed, inputs.len(), failed);
+    if completed < inputs.len() {
+        return Err(format!(
+            "{} item(s) failed; run again with --resume to retry them (checkpoint {})",
+            inputs.len() - completed, checkpoint_path.display()
+        ));
+    }
+
+    let mut results = String::new();
+    for (index, (input, output)) in inputs.iter().zip(&outputs).enumerate() {
+        let (mode_id, output) = output.clone().unwrap_or_default();
+        let entry = json::Value::object([
+            ("index", index.into()),
+            ("mode", if mode_id.is_empty() { mode.id.as_str() } else { mode_id.as_str() }.into()),
+            ("input", input.as_str().into()),
+            ("output", output.into()),
+        ]);
+        results.push_str(&format!("{}\n", entry));
+    }
+    fs::write(&output_path, results)
+        .map_err(|e| format!("Failed to write {}: {}", output_path.display(), e))?;
+    let _ = fs::remove_file(&checkpoint_path);
+    println!("Results written to {}", output_path.display());
+    Ok(())
+}
+
+/// One element of an expected-answer pattern
+#[derive(Debug, Clone, PartialEq)]
+enum PatternAtom {
+    Char(char),
+    Any,
+    Class(Vec<(char, char)>, bool), // character ranges, negated
+}
+
+/// An atom with its repetition: `x` (1..=1), `x?` (0..=1), `x*` (0..), `x+` (1..)
+#[derive(Debug, Clone, PartialEq)]
+struct PatternPiece {
+    atom: PatternAtom,
+    min: usize,
+    max: Option<usize>,
+}
+
+/// One `|` alternative of an expected-answer pattern
+#[derive(Debug, Clone, PartialEq)]
+struct PatternBranch {
+    pieces: Vec<PatternPiece>,
+    anchored_start: bool,
+    anchored_end: bool,
+}
+
+/// Expected-answer regex of an eval question
+///
+/// A small regex subset (no crates): literals, `.`, `[a-z]`/`[^...]`,
+/// `\d \w \s` (and `\D \W \S`), `* + ?`, `^ $`, top-level `|`, and a
+/// leading `(?i)` for case-insensitive matching. Groups and `{n,m}` are not
+/// supported. Like a regex search, the pattern may match anywhere in the answer.
+#[derive(Debug, Clone, PartialEq)]
+struct AnswerPattern {
+    branches: Vec<PatternBranch>,
+    ignore_case: bool,
+}
+
+/// Character ranges of a `\d`, `\w`, or `\s` class (None for other escapes)
+fn escape_class_ranges(escape: char) -> Option<(Vec<(char, char)>, bool)> {
+    let ranges = match escape.to_ascii_lowercase() {
+        'd' => vec![('0', '9')],
+        'w' => vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')],
+        's' => vec![(' ', ' '), ('\t', '\t'), ('\n', '\n'), ('\r', '\r')],
+        _ => return None,
+    };
+    Some((ranges, escape.is_ascii_uppercase()))
+}
+
+impl AnswerPattern {
+    /// Parses a pattern, e.g. `(?i)^\s*paris` or `42|forty-two`
+    fn parse(pattern: &str) -> Result<AnswerPattern, String> {
+        let (ignore_case, body) = match pattern.strip_prefix("(?i)") {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let chars: Vec<char> = body.chars().collect();
+        let mut branches = Vec::new();
+        let mut branch = PatternBranch { pieces: Vec::new(), anchored_start: false, anchored_end: false };
+        let mut quantified = false;
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            i += 1;
+            let atom = match c {
+                '|' => {
+                    branches.push(branch);
+                    branch = PatternBranch { pieces: Vec::new(), anchored_start: false, anchored_end: false };
+                    continue;
+                },
+                '^' if branch.pieces.is_empty() && !branch.anchored_start => {
+                    branch.anchored_start = true;
+                    continue;
+                },
+                '$' if i == chars.len() || chars[i] == '|' => {
+                    branch.anchored_end = true;
+                    continue;
+                },
+                '*' | '+' | '?' => {
+                    let piece = match branch.pieces.last_mut() {
+                        Some(piece) if !quantified => piece,
+                        _ => return Err(format!("'{}' has nothing to repeat in pattern: {}", c, pattern)),
+                    };
+                    piece.min = if c == '+' { 1 } else { 0 };
+                    piece.max = if c == '?' { Some(1) } else { None };
+                    quantified = true;
+                    continue;
+                },
+                '(' | ')' | '{' | '}' => {
+                    return Err(format!("'{}' is not supported in expect patterns (no groups or {{n,m}}): {}", c, pattern));
+                },
+                '.' => PatternAtom::Any,
+                '\\' => {
+                    let escaped = *chars.get(i).ok_or_else(|| format!("Pattern ends with '\\': {}", pattern))?;
+                    i += 1;
+                    match escape_class_ranges(escaped) {
+                        Some((ranges, negated)) => PatternAtom::Class(ranges, negated),
+                        None => PatternAtom::Char(escaped),
+                    }
+                },
+                '[' => {
+                    let negated = chars.get(i) == Some(&'^');
+                    if negated {
+                        i += 1;
+                    }
+                    let mut ranges = Vec::new();
+                    loop {
+                        let c = *chars.get(i).ok_or_else(|| format!("Unclosed '[' in pattern: {}", pattern))?;
+                        i += 1;
+                        let start = match c {
+                            ']' if !ranges.is_empty() => break,
+                            '\\' => {
+                                let escaped = *chars.get(i).ok_or_else(|| format!("Unclosed '[' in pattern: {}", pattern))?;
+                                i += 1;
+                                match escape_class_ranges(escaped) {
+                                    Some((class_ranges, false)) => {
+                                        ranges.extend(class_ranges);
+                                        continue;
+                                    },
+                                    Some((_, true)) => return Err(format!("Negated classes inside [...] are not supported: {}", pattern)),
+                                    None => escaped,
+                                }
+                            },
+                            other => other,
+                        };
+                        match (chars.get(i), chars.get(i + 1)) {
+                            (Some('-'), Some(&end)) if end != ']' => {
+                                if end < start {
+                                    return Err(format!("Invalid range {}-{} in pattern: {}", start, end, pattern));
+                                }
+                                ranges.push((start, end));
+                                i += 2;
+                            },
+                            _ => ranges.push((start, start)),
+                        }
+                    }
+                    PatternAtom::Class(ranges, negated)
+                },
+                other => PatternAtom::Char(other),
+            };
+            branch.pieces.push(PatternPiece { atom, min: 1, max: Some(1) });
+            quantified = false;
+        }
+        branches.push(branch);
+        Ok(AnswerPattern { branches, ignore_case })
+    }
+
+    /// Checks whether the pattern matches anywhere in the text
+    fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        self.branches.iter().any(|branch| {
+            // Failed (piece, position) pairs: without captures a retry would fail again
+            let mut failed = std::collections::HashSet::new();
+            let last_start = if branch.anchored_start { 0 } else { text.len() };
+            (0..=last_start).any(|start| self.match_pieces(branch, 0, &text, start, &mut failed))
+        })
+    }
+
+    fn atom_matches(&self, atom: &PatternAtom, c: char) -> bool {
+        let matches_char = |c: char| match atom {
+            PatternAtom::Char(expected) => *expected == c,
+            PatternAtom::Any => c != '\n',
+            PatternAtom::Class(ranges, negated) => {
+                ranges.iter().any(|(start, end)| (*start..=*end).contains(&c)) != *negated
+            },
+        };
+        matches_char(c) || (self.ignore_case && c.is_alphabetic() && (
+            c.to_lowercase().any(matches_char) || c.to_uppercase().any(matches_char)
+        ))
+    }
+
+    /// Greedy backtracking match of the branch's pieces from `piece_index` at text position `pos`
+    fn match_pieces(
+        &self,
+        branch: &PatternBranch,
+        piece_index: usize,
+        text: &[char],
+        pos: usize,
+        failed: &mut std::collections::HashSet<(usize, usize)>,
+    ) -> bool {
+        let piece = match branch.pieces.get(piece_index) {
+            Some(piece) => piece,
+            None => return !branch.anchored_end || pos == text.len(),
+        };
+        if failed.contains(&(piece_index, pos)) {
+            return false;
+        }
+        let max = piece.max.unwrap_or(usize::MAX);
+        let mut count = 0;
+        while count < max && pos + count < text.len() && self.atom_matches(&piece.atom, text[pos + count]) {
+            count += 1;
+        }
+        let matched = count >= piece.min && (piece.min..=count).rev()
+            .any(|repeats| self.match_pieces(branch, piece_index + 1, text, pos + repeats, failed));
+        if !matched {
+            failed.insert((piece_index, pos));
+        }
+        matched
+    }
+}
+
+/// System message for the judge mode of `eval`
+const EVAL_JUDGE_INSTRUCTIONS: &str = "\
+You grade an answer against a rubric. Reply with PASS or FAIL on the first line, \
+then one sentence saying why. Judge only by the rubric.";
+
+/// Tokens generated per answer in `eval` without max_tokens in the suite
+const EVAL_DEFAULT_MAX_TOKENS: i32 = 256;
+
+/// One `[questions.<name>]` table of an eval suite
+#[derive(Debug, Clone)]
+struct EvalQuestion {
+    name: String,
+    question: String,
+    expect: Option<AnswerPattern>, // the answer must match this pattern
+    rubric: String,                // the judge mode must PASS the answer against this (empty: no judge)
+}
+
+/// An eval suite file, e.g.
+/// ```toml
+/// [suite]
+/// modes = "small, coder"
+/// judge = "big"
+/// rubric = "Correct, and no longer than two sentences"
+///
+/// [questions.capital]
+/// question = "What is the capital of France?"
+/// expect = '(?i)paris'
+///
+/// [questions.haiku]
+/// question = "Write a haiku about rust"
+/// rubric = "Three lines of 5, 7, and 5 syllables about metal or oxidation"
+/// ```
+/// Questions without expect or rubric use the suite's rubric. `modes` may
+/// also be a list (`modes = ["small", "coder"]`), and questions may be
+/// `[[questions]]` entries with an optional `name` instead of named tables.
+#[derive(Debug, Clone)]
+struct EvalSuite {
+    modes: Vec<String>,
+    judge: String,
+    questions: Vec<EvalQuestion>,
+    max_tokens: i32,
+    seed: i64,
+}
+
+/// Parses an eval suite (see `EvalSuite`)
+///
+/// # Returns
+/// - Ok(EvalSuite): At least one question, each with something to check
+/// - Err(String): Invalid patterns or numbers, or questions that cannot be scored
+fn parse_eval_suite(content: &str) -> Result<EvalSuite, String> {
+    let (root, errors) = toml_lite::parse(content);
+    if let Some(error) = errors.first() {
+        return Err(error.clone());
+    }
+    let no_fields = toml_lite::Table::default();
+    let suite = root.get_table("suite").unwrap_or(&no_fields);
+
+    let max_tokens = match suite.get("max_tokens") {
+        None => EVAL_DEFAULT_MAX_TOKENS,
+        Some(value) => value.as_int().and_then(|tokens| i32::try_from(tokens).ok()).filter(|tokens| *tokens > 0)
+            .ok_or_else(|| format!("[suite] max_tokens must be a number of tokens: {}", value))?,
+    };
+    let seed = match suite.get("seed") {
+        None => 42,
+        Some(value) => value.as_int().ok_or_else(|| format!("[suite] seed must be a number: {}", value))?,
+    };
+    let default_rubric = suite.get_text("rubric").unwrap_or_default();
+
+    // Named [questions.<name>] tables, or a [[questions]] list
+    let mut question_tables: Vec<(String, &toml_lite::Table)> = root.get_table("questions")
+        .map(|tables| tables.tables().map(|(name, table)| (name.to_string(), table)).collect())
+        .unwrap_or_default();
+    for (index, table) in root.get_tables("questions").into_iter().enumerate() {
+        let name = table.get_text("name").unwrap_or_else(|| format!("{}", index + 1));
+        question_tables.push((name, table));
+    }
+
+    let mut questions = Vec::new();
+    for (name, table) in question_tables {
+        let field = |key: &str| table.get_text(key).unwrap_or_default();
+        let question = field("question");
+        if question.is_empty() {
+            return Err(format!("[questions.{}] has no question", name));
+        }
+        let expect = match field("expect").as_str() {
+            "" => None,
+            pattern => Some(AnswerPattern::parse(pattern).map_err(|e| format!("[questions.{}] {}", name, e))?),
+        };
+        let mut rubric = field("rubric");
+        if expect.is_none() && rubric.is_empty() {
+            if default_rubric.is_empty() {
+                return Err(format!("[questions.{}] needs expect or rubric (or a rubric in [suite])", name));
+            }
+            rubric = default_rubric.clone();
+        }
+        questions.push(EvalQuestion { name, question, expect, rubric });
+    }
+    if questions.is_empty() {
+        return Err("The suite has no [questions.<name>] tables".to_string());
+    }
+
+    Ok(EvalSuite {
+        modes: suite.get_string_list("modes").iter()
+            .flat_map(|modes| modes.split(','))
+            .map(|mode| mode.trim().to_string())
+            .filter(|mode| !mode.is_empty())
+            .collect(),
+        judge: suite.get_text("judge").unwrap_or_default(),
+        questions,
+        max_tokens,
+        seed,
+    })
+}
+
+/// Reads the judge's verdict from the first word of its reply (None if neither PASS nor FAIL)
+fn parse_judge_verdict(reply: &str) -> Option<bool> {
+    let first_word: String = reply.split_whitespace().next()?
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .collect();
+    match first_word.to_uppercase().as_str() {
+        "PASS" => Some(true),
+        "FAIL" => Some(false),
+        _ => None,
+    }
+}
+
+/// Result of one question on one mode
+#[derive(Debug, Clone, Default)]
+struct EvalOutcome {
+    answer: String,
+    passed: bool,
+    note: String, // why it failed (pattern, judge reason, error)
+}
+
+/// Formats the question × mode pass/fail table, with a score row per mode
+///
+/// # Arguments
+/// * `question_names` - Row labels
+/// * `mode_ids` - Column labels
+/// * `outcomes` - outcomes[mode][question]
+fn format_eval_matrix(question_names: &[String], mode_ids: &[String], outcomes: &[Vec<EvalOutcome>]) -> String {
+    let label_width = question_names.iter().map(|name| name.chars().count()).max().unwrap_or(0).max("question".len());
+    let column_widths: Vec<usize> = mode_ids.iter().map(|id| id.chars().count().max(6)).collect();
+    let mut table = format!("{:<width$}", "question", width = label_width);
+    for (id, width) in mode_ids.iter().zip(&column_widths) {
+        table.push_str(&format!("  {:<width$}", id, width = *width));
+    }
+    table.push('\n');
+    for (question_index, name) in question_names.iter().enumerate() {
+        table.push_str(&format!("{:<width$}", name, width = label_width));
+        for (mode_outcomes, width) in outcomes.iter().zip(&column_widths) {
+            let cell = if mode_outcomes[question_index].passed { "PASS" } else { "FAIL" };
+            table.push_str(&format!("  {:<width$}", cell, width = *width));
+        }
+        table.push('\n');
+    }
+    table.push_str(&format!("{:<width$}", "score", width = label_width));
+    for (mode_outcomes, width) in outcomes.iter().zip(&column_widths) {
+        let passed = mode_outcomes.iter().filter(|outcome| outcome.passed).count();
+        table.push_str(&format!("  {:<width$}", format!("{}/{}", passed, mode_outcomes.len()), width = *width));
+    }
+    table.push('\n');
+    table
+}
+
+/// Scores modes on a suite of questions: `query_gguf eval suite.toml`
+///
+/// ```text
+/// eval suite.toml [--modes small,coder] [--judge big] [--out results.csv]
+/// ```
+/// Every mode answers every question (with the mode's prompt file as system
+/// message and the suite's seed), one mode's server at a time. Answers are
+/// checked against the question's expect pattern; questions with a rubric
+/// are then graded PASS/FAIL by the judge mode, after all answers are in, so
+/// the judge model is not loaded beside the others. The comparison matrix is
+/// printed, and a CSV of every answer goes to --out, or ~/query_gguf/reports/.
+fn handle_eval_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: eval <suite.toml> [--modes a,b] [--judge mode] [--out file.csv]";
+    let suite_path = args.first().filter(|arg| !arg.starts_with('-')).ok_or_else(|| usage.to_string())?;
+    let mut mode_selectors: Option<Vec<String>> = None;
+    let mut judge_selector: Option<String> = None;
+    let mut output_path: Option<PathBuf> = None;
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        let value = iter.next().ok_or_else(|| format!("Missing value after {}", arg))?;
+        match arg.as_str() {
+            "--modes" => mode_selectors = Some(value.split(',').map(|mode| mode.trim().to_string()).filter(|mode| !mode.is_empty()).collect()),
+            "--judge" => judge_selector = Some(value.clone()),
+            "--out" => output_path = Some(PathBuf::from(value)),
+            other => return Err(format!("Unknown option: {}. {}", other, usage)),
+        }
+    }
+
+    let content = fs::read_to_string(suite_path)
+        .map_err(|e| format!("Failed to read suite {}: {}", suite_path, e))?;
+    let suite = parse_eval_suite(&content).map_err(|e| format!("{}: {}", suite_path, e))?;
+    let mode_selectors = mode_selectors.unwrap_or_else(|| suite.modes.clone());
+    if mode_selectors.is_empty() {
+        return Err(format!("No modes to evaluate: add modes = \"...\" to [suite] or use --modes. {}", usage));
+    }
+
+    let saved_modes = read_saved_modes()?;
+    let modes = mode_selectors.iter()
+        .map(|selector| find_mode_by_selector(&saved_modes, selector).cloned().ok_or_else(|| format!("Unknown mode: {}", selector)))
+        .collect::<Result<Vec<ChatModeConfig>, String>>()?;
+    let judge_selector = judge_selector.unwrap_or_else(|| suite.judge.clone());
+    let needs_judge = suite.questions.iter().any(|question| !question.rubric.is_empty());
+    let judge = match (needs_judge, judge_selector.is_empty()) {
+        (false, _) => None,
+        (true, true) => return Err("Questions with a rubric need a judge mode: add judge = \"...\" to [suite] or use --judge".to_string()),
+        (true, false) => Some(find_mode_by_selector(&saved_modes, &judge_selector)
+            .cloned()
+            .ok_or_else(|| format!("Unknown judge mode: {}", judge_selector))?),
+    };
+
+    let output_path = match output_path {
+        Some(path) => path,
+        None => {
+            let reports_dir = get_app_base_dir()?.join("reports");
+            fs::create_dir_all(&reports_dir)
+                .map_err(|e| format!("Failed to create {}: {}", reports_dir.display(), e))?;
+            let suite_name = Path::new(suite_path).file_stem().and_then(|stem| stem.to_str()).unwrap_or("suite");
+            reports_dir.join(format!("eval_{}_{}.csv", suite_name, generate_timestamp_string()))
+        },
+    };
+
+    println!("\n=== Eval: {}, {} question(s) on {} mode(s) ===", suite_path, suite.questions.len(), modes.len());
+    let mut outcomes: Vec<Vec<EvalOutcome>> = Vec::new();
+    for mode in &modes {
+        println!("\n--- {} [{}] ---", mode.name, mode.id);
+        let server = match start_or_attach_server(mode) {
+            Ok(server) => server,
+            Err(e) => {
+                println!("Error: {}", e);
+                let note = format!("mode did not start: {}", e);
+                outcomes.push(vec![EvalOutcome { note, ..EvalOutcome::default() }; suite.questions.len()]);
+                continue;
+            },
+        };
+        let prompt_text = fs::read_to_string(&mode.prompt_path).unwrap_or_default();
+        let mut mode_outcomes = Vec::new();
+        for question in &suite.questions {
+            let mut messages = Vec::new();
+            if !prompt_text.trim().is_empty() {
+                messages.push(ChatMessage { role: "system".to_string(), content: prompt_text.trim().to_string() });
+            }
+            messages.push(ChatMessage { role: "user".to_string(), content: question.question.clone() });
+            let outcome = match server.chat(&messages, &mode.parameters, suite.max_tokens, Some(suite.seed)) {
+                Ok(result) => {
+                    let matched = question.expect.as_ref().map(|pattern| pattern.is_match(&result.content)).unwrap_or(true);
+                    EvalOutcome {
+                        answer: result.content,
+                        passed: matched,
+                        note: if matched { String::new() } else { "answer does not match expect".to_string() },
+                    }
+                },
+                Err(e) => EvalOutcome { note: format!("error: {}", e), ..EvalOutcome::default() },
+            };
+            let status = if !outcome.passed {
+                "FAIL"
+            } else if question.rubric.is_empty() {
+                "PASS"
+            } else {
+                "to judge"
+            };
+            println!("{}: {} {}", question.name, status, one_line_preview(&outcome.answer, 60));
+            mode_outcomes.push(outcome);
+        }
+        outcomes.push(mode_outcomes);
+    }
+
+    if let Some(judge) = judge {
+        println!("\n--- Judging with {} [{}] ---", judge.name, judge.id);
+        let server = start_or_attach_server(&judge)?;
+        for (mode, mode_outcomes) in modes.iter().zip(outcomes.iter_mut()) {
+            for (question, outcome) in suite.questions.iter().zip(mode_outcomes.iter_mut()) {
+                if question.rubric.is_empty() || !outcome.passed {
+                    continue;
+                }
+                let messages = vec![
+                    ChatMessage { role: "system".to_string(), content: EVAL_JUDGE_INSTRUCTIONS.to_string() },
+                    ChatMessage {
+                        role: "user".to_string(),
+                        content: format!("Rubric:\n{}\n\nQuestion:\n{}\n\nAnswer:\n{}", question.rubric, question.question, outcome.answer),
+                    },
+                ];
+                let (passed, note) = match server.chat(&messages, &judge.parameters, 64, Some(suite.seed)) {
+                    Ok(result) => match parse_judge_verdict(&result.content) {
+                        Some(passed) => (passed, one_line_preview(&result.content, 120)),
+                        None => (false, format!("unclear judge verdict: {}", one_line_preview(&result.content, 80))),
+                    },
+                    Err(e) => (false, format!("judge error: {}", e)),
+                };
+                println!("{} / {}: {}", mode.id, question.name, if passed { "PASS" } else { "FAIL" });
+                outcome.passed = passed;
+                outcome.note = note;
+            }
+        }
+    }
+
+    let question_names: Vec<String> = suite.questions.iter().map(|question| question.name.clone()).collect();
+    let mode_ids: Vec<String> = modes.iter().map(|mode| mode.id.clone()).collect();
+    println!("\n=== Results ===");
+    print!("{}", format_eval_matrix(&question_names, &mode_ids, &outcomes));
+
+    let mut csv = String::from("mode,question,passed,note,answer\n");
+    for (mode_id, mode_outcomes) in mode_ids.iter().zip(&outcomes) {
+        for (name, outcome) in question_names.iter().zip(mode_outcomes) {
+            csv.push_str(&format!(
+                "{},{},{},{},{}\n",
+                csv_field(mode_id),
+                csv_field(name),
+                outcome.passed,
+                csv_field(&outcome.note),
+                csv_field(&outcome.answer)
+            ));
+        }
+    }
+    fs::write(&output_path, csv)
+        .map_err(|e| format!("Failed to write {}: {}", output_path.display(), e))?;
+    println!("\nEval results written to {}", output_path.display());
+    Ok(())
+}
+
+/// Extra launch options given on the command line after the mode selection
+///
+/// e.g. `query_gguf 4 --stdin-as-context -q "why is this crashing?"`
+#[derive(Debug, Default, Clone)]
+struct LaunchOptions {
+    stdin_as_context: bool,    // --stdin-as-context: piped stdin is appended to the prompt
+    question: Option<String>,  // -q / --question: initial question, skips asking for one
+    print: bool,               // --print: run once without a terminal and print the response
+    no_cache: bool,            // --no-cache: skip the response cache for --print runs
+    variables: Vec<(String, String)>, // --var NAME=VALUE: values for {{ask:...}} prompt placeholders
+    save_code_dir: Option<String>,     // --save-code DIR: write fenced code blocks of a --print response here
+    outline: bool,             // --outline: directory mode includes only signatures of supported languages
+    tree_only: bool,           // --tree-only: directory mode includes the tree without file contents
+    depth: Option<usize>,      // --depth N: directory mode scans N levels of directories
+    max_seconds: Option<u64>,  // --max-seconds N: stop a --print generation after N seconds
+    max_bytes: Option<usize>,  // --max-bytes N: stop a --print generation after N bytes of output
+    pager: bool,               // --pager: show the --print response in $PAGER (implies --print)
+    dictate: bool,             // --dictate: the question is spoken, transcribed by stt_command
+    force: bool,               // --force: launch even when the mode's conditions (only_if, hours, ...) are not met
+    inline: bool,              // --inline: chat in this terminal instead of a new terminal window
+    dry_run: bool,             // --dry-run: print the llama-cli command instead of running it
+}
+
+const ASK_USAGE: &str = "Use: ask <mode number or id> [\"question\" | -] [--no-cache] [--max-seconds N] [--max-bytes N] [--var NAME=VALUE]";
+
+/// Splits `ask` arguments into the mode selector and launch options
+///
+/// The question, if given, is the word after the mode (or -q "question")
+/// and ends up in the options.
+fn parse_ask_args(args: &[String]) -> Result<(String, LaunchOptions), String> {
+    let selector = args.first().filter(|arg| !arg.starts_with('-')).ok_or_else(|| ASK_USAGE.to_string())?;
+    let (question, option_args) = match args.get(1) {
+        Some(question) if !question.starts_with("--") && question != "-q" => (Some(question.clone()), &args[2..]),
+        _ => (None, &args[1..]),
+    };
+    let mut options = parse_launch_options(option_args).map_err(|e| format!("{}. {}", e, ASK_USAGE))?;
+    if options.pager || options.dictate || options.inline {
+        return Err(format!("ask prints the answer to stdout; --pager, --dictate, and --inline do not apply. {}", ASK_USAGE));
+    }
+    if question.is_some() {
+        options.question = question;
+    }
+    Ok((selector.clone(), options))
+}
+
+/// Answers one question with a saved mode and prints only the answer: `query_gguf ask`
+///
+/// ```text
+/// ask <mode> "question" [--no-cache] [--max-seconds N] [--max-bytes N] [--var NAME=VALUE] ...
+/// echo "question" | ask <mode>
+/// ```
+/// The question is read from stdin when it is not given (or given as "-").
+/// The mode runs like `<mode> -q "question" --print` (llama-cli with
+/// -no-cnv, the response cache, limits), but stdout gets only the answer:
+/// status lines and warnings go to stderr, so scripts can pipe or capture it.
+fn handle_ask_command(args: &[String]) -> Result<(), String> {
+    let (selector, mut options) = parse_ask_args(args)?;
+    let modes = read_saved_modes()?;
+    let mode = find_mode_by_selector(&modes, &selector)
+        .ok_or_else(|| format!("Unknown mode: {}", selector))?;
+    if mode.kind == "image" {
+        return Err(format!("Mode '{}' is an image mode; ask needs a text mode", mode.id));
+    }
+
+    let question = match options.question.take() {
+        Some(question) if question != "-" => question,
+        _ if options.stdin_as_context => {
+            return Err(format!("Give the question as an argument when stdin is the context. {}", ASK_USAGE));
+        },
+        _ => read_stdin_context()?,
+    };
+    if question.trim().is_empty() {
+        return Err(format!("No question given. {}", ASK_USAGE));
+    }
+    options.question = Some(question.trim_end().to_string());
+    options.print = true;
+    handle_mode_selection(&mode.id, &options)?;
+    Ok(())
+}
+
+/// Parses launch options from the command line arguments following the mode selection
+fn parse_launch_options(args: &[String]) -> Result<LaunchOptions, String> {
+    let mut options = LaunchOptions::default();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--stdin-as-context" => options.stdin_as_context = true,
+            "--print" => options.print = true,
+            "--pager" => {
+                options.pager = true;
+                options.print = true;
+            },
+            "--no-cache" => options.no_cache = true,
+            "--dictate" => options.dictate = true,
+            "--force" => options.force = true,
+            "--inline" => options.inline = true,
+            "--dry-run" => options.dry_run = true,
+            "--outline" => options.outline = true,
+            "--tree-only" => options.tree_only = true,
+            "--depth" => {
+                let depth = iter.next()
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .filter(|depth| *depth > 0)
+                    .ok_or_else(|| "--depth needs a number of levels (1 or more)".to_string())?;
+                options.depth = Some(depth);
+            },
+            "--max-seconds" => {
+                let seconds = iter.next()
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .filter(|seconds| *seconds > 0)
+                    .ok_or_else(|| "--max-seconds needs a number of seconds (1 or more)".to_string())?;
+                options.max_seconds = Some(seconds);
+            },
+            "--max-bytes" => {
+                let bytes = iter.next()
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .filter(|bytes| *bytes > 0)
+                    .ok_or_else(|| "--max-bytes needs a number of bytes (1 or more)".to_string())?;
+                options.max_bytes = Some(bytes);
+            },
+            "-q" | "--question" => {
+                let question = iter.next()
+                    .ok_or_else(|| format!("Missing question after {}", arg))?;
+                options.question = Some(question.clone());
+            },
+            "--var" => {
+                let assignment = iter.next()
+                    .ok_or_else(|| "Missing NAME=VALUE after --var".to_string())?;
+                let (name, value) = assignment.split_once('=')
+                    .ok_or_else(|| format!("--var needs NAME=VALUE, got '{}'", assignment))?;
+                options.variables.push((name.trim().to_string(), value.to_string()));
+            },
+            "--save-code" => {
+                let dir = iter.next()
+                    .ok_or_else(|| "Missing directory after --save-code".to_string())?;
+                options.save_code_dir = Some(dir.clone());
+            },
+            other => return Err(format!("Unknown option: {}", other)),
+        }
+    }
+
+    if options.dictate && options.question.is_some() {
+        return Err("--dictate and -q/--question cannot be used together".to_string());
+    }
+    Ok(options)
+}
+
+/// Cleans up speech-to-text output into one message
+///
+/// Timestamps such as whisper.cpp's `[00:00:00.000 --> 00:00:02.000]` are
+/// removed, and the lines are joined with spaces.
+fn clean_transcript(output: &str) -> String {
+    output.lines()
+        .map(|line| {
+            let line = line.trim();
+            match line.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+                Some((timestamp, text)) if timestamp.contains("-->") => text.trim(),
+                _ => line,
+            }
+        })
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Records and transcribes a spoken message with the configured `stt_command`
+///
+/// The command (e.g. a script that records from the microphone and runs the
+/// whisper.cpp CLI) keeps this terminal for its own messages and prints the
+/// transcript on stdout.
+///
+/// # Returns
+/// - Ok(String): The transcript
+/// - Err(String): stt_command not set, failed, or recognized no speech
+fn run_dictation() -> Result<String, String> {
+    let stt_command = read_field_from_toml("stt_command");
+    let mut words = split_command_line(&stt_command);
+    if words.is_empty() {
+        return Err("Dictation needs stt_command in the config (a command that prints the transcript)".to_string());
+    }
+    let program = words.remove(0);
+    eprintln!("Listening (stt_command: {})...", program);
+    let output = Command::new(&program)
+        .args(&words)
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Failed to run stt_command '{}': {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!("stt_command '{}' exited with {}", program, output.status));
+    }
+    let transcript = clean_transcript(&String::from_utf8_lossy(&output.stdout));
+    if transcript.is_empty() {
+        return Err("No speech was recognized".to_string());
+    }
+    Ok(transcript)
+}
+
+/// Reads all of piped stdin, for use as prompt context
+fn read_stdin_context() -> Result<String, String> {
+    let mut context = String::new();
+    io::Read::read_to_string(&mut io::stdin(), &mut context)
+        .map_err(|e| format!("Failed to read stdin: {}", e))?;
+    if context.trim().is_empty() {
+        return Err("--stdin-as-context was given but stdin was empty".to_string());
+    }
+    Ok(context)
+}
+
+/// Combines the image description with the mode's prompt file (e.g. a style: "watercolor, soft light")
+fn image_prompt(description: &str, style: &str) -> String {
+    match (description.trim(), style.trim()) {
+        (description, "") => description.to_string(),
+        ("", style) => style.to_string(),
+        (description, style) => format!("{}, {}", description, style),
+    }
+}
+
+/// Builds the stable-diffusion.cpp (`sd`) arguments that generate one image for a mode
+///
+/// # Arguments
+/// * `mode` - Image mode whose model, steps, and cfg_scale are used
+/// * `prompt` - What to draw
+/// * `output_path` - PNG file to write
+fn image_generation_args(mode: &ChatModeConfig, prompt: &str, output_path: &Path) -> Vec<String> {
+    let mut args = vec![
+        "-m".to_string(), mode.model_path.clone(),
+        "-p".to_string(), prompt.to_string(),
+        "-o".to_string(), output_path.to_string_lossy().to_string(),
+        "-t".to_string(), mode.parameters.thread_count.to_string(),
+    ];
+    if let Some(image_steps) = mode.parameters.image_steps {
+        args.extend(["--steps".to_string(), image_steps.to_string()]);
+    }
+    if let Some(cfg_scale) = mode.parameters.cfg_scale {
+        args.extend(["--cfg-scale".to_string(), cfg_scale.to_string()]);
+    }
+    args
+}
+
+/// Generates an image with a `kind=image` mode using stable-diffusion.cpp
+///
+/// The description comes from -q, piped stdin (--stdin-as-context), or is
+/// asked for; the mode's prompt file, if not blank, is appended as a style.
+/// The image is written to the mode's output_dir (default ~/query_gguf/images)
+/// as `<mode id>_<unix time>.png`. The `sd` program is `sd_cli_path` from the
+/// config, or `sd` on PATH.
+///
+/// # Returns
+/// - Ok(String): "image_mode::<id>" after the image was saved
+/// - Err(String): No description, model missing, or sd failed
+fn handle_image_mode(mode: &ChatModeConfig, options: &LaunchOptions) -> Result<String, String> {
+    println!("\nSelected image mode: {} [{}]", mode.name, mode.id);
+    if !Path::new(&mode.model_path).is_file() {
+        return Err(format!("Model file not found: {} (mode '{}')", mode.model_path, mode.id));
+    }
+
+    let description = match &options.question {
+        Some(question) => question.clone(),
+        None if options.stdin_as_context => read_stdin_context()?,
+        None if options.print => String::new(),
+        None => {
+            print!("Describe the image: ");
+            io::stdout().flush().map_err(|e| e.to_string())?;
+            read_user_input()?
+        },
+    };
+    let style = fs::read_to_string(&mode.prompt_path).unwrap_or_default();
+    let prompt = image_prompt(&description, &style);
+    if description.trim().is_empty() {
+        return Err("No image description given (use -q \"<description>\")".to_string());
+    }
+
+    let output_dir = if mode.parameters.output_dir.is_empty() {
+        get_app_base_dir()?.join("images")
+    } else {
+        PathBuf::from(&mode.parameters.output_dir)
+    };
+    fs::create_dir_all(&output_dir)
+        .map_err(|e| format!("Failed to create {}: {}", output_dir.display(), e))?;
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let output_path = output_dir.join(format!("{}_{}.png", mode.id, seconds));
+
+    let sd_path = match read_field_from_toml("sd_cli_path") {
+        path if path.is_empty() => "sd".to_string(),
+        path => path,
+    };
+    println!("Prompt: {}", prompt);
+    let status = Command::new(&sd_path)
+        .args(image_generation_args(mode, &prompt, &output_path))
+        .status()
+        .map_err(|e| format!("Failed to launch stable-diffusion.cpp ({}): {}", sd_path, e))?;
+    if !status.success() {
+        return Err(format!("stable-diffusion.cpp ({}) exited with {}", sd_path, status));
+    }
+
+    println!("Image saved: {}", output_path.display());
+    Ok(format!("image_mode::{}", mode.id))
+}
+
+fn handle_mode_selection(choice: &str, options: &LaunchOptions) -> Result<String, String> {
+    use std::io::IsTerminal;
+    match choice.trim() {
+        "dir" | "directory" => {
+            println!("\nDirectory Mode Setup:");
+            
+            // Get directory to scan (recently used ones can be picked by number)
+            let dir_path = prompt_for_scan_directory()?;
+            
+            // Get mode number or id to use
+            print!("Enter mode number or id to use: ");
+            io::stdout().flush().map_err(|e| e.to_string())?;
+            let mode_selector = read_user_input()?.trim().to_string();
+            
+            // Get the selected mode
+            let saved_modes = read_saved_modes()?;
+            let mut selected_mode = find_mode_by_selector(&saved_modes, &mode_selector)
+                .ok_or("Invalid mode selection")?
+                .clone();  // Now clones the entire ChatModeConfig
+
+            // Create combined prompt
+            let scan_options = ScanOptions {
+                outline: options.outline,
+                tree_only: options.tree_only,
+                max_depth: options.depth,
+            };
+            let combined_prompt_path = create_combined_prompt(
+                &selected_mode.prompt_path,
+                &dir_path,
+                &scan_options,
+                &selected_mode.parameters,
+            )?;
+            // The combined prompt file is kept, so the printed command can be run
+            if options.dry_run {
+                selected_mode.prompt_path = combined_prompt_path;
+                print_launch_command(&selected_mode, false)?;
+                return Ok(format!("dry_run::{}", selected_mode.id));
+            }
+
+            if let Err(e) = remember_recent_directory(&dir_path) {
+                println!("Warning: {}", e);
+            }
+
+            // Update mode to use combined prompt
+            selected_mode.prompt_path = combined_prompt_path;
+            check_prompt_tokens(&mut selected_mode, true)?;
+            confirm_rendered_prompt(&mut selected_mode)?;
+
+            // Launch with combined prompt
+            launch_llama(&selected_mode, options.inline)?;
+
+            Ok(format!("directory_mode::{}", selected_mode.id))
+        },
+        "make" | "manual" => handle_manual_mode_selection(),
+        selector => {
+            let saved_modes = read_saved_modes()?;
+            
+            // Accept either the menu number (1-based) or the stable mode id
+            if let Some(mode) = find_mode_by_selector(&saved_modes, selector) {
+                // The mode's own prompt file; --question, --var, and piped context
+                // would go into a rendered copy of it at launch
+                if options.dry_run {
+                    print_launch_command(mode, options.print)?;
+                    return Ok(format!("dry_run::{}", mode.id));
+                }
+                if !options.force {
+                    if let Some(reason) = unmet_mode_condition(&mode.parameters, &MachineState::current()) {
+                        return Err(format!(
+                            "Mode '{}' is not available now: {} (use --force to launch anyway)",
+                            mode.id, reason
+                        ));
+                    }
+                }
+                if mode.kind == "image" {
+                    return handle_image_mode(mode, options);
+                }
+                // `ask` keeps stdout for the answer
+                if !answer_only_stdout() {
+                    println!("\nSelected saved mode: {} [{}]", mode.name, mode.id);
+                    println!("Model: {}", mode.model_path);
+                    println!("Prompt: {}", mode.prompt_path); // Now always present
+                    println!("Parameters:");
+                    display_parameters(&mode.parameters);
+                }
+
+                // Piped stdin becomes context appended to the prompt
+                let context = if options.stdin_as_context {
+                    Some(read_stdin_context()?)
+                } else {
+                    None
+                };
+
+                // Optionally start with a question so the model answers right away
+                // (stdin is already used up when it was piped in as context,
+                // and --print runs are not interactive)
+                let question = match &options.question {
+                    Some(question) => Some(question.clone()),
+                    None if options.dictate => {
+                        let transcript = run_dictation()?;
+                        eprintln!("> {}", transcript);
+                        Some(transcript)
+                    },
+                    None if options.stdin_as_context || options.print => None,
+                    None => prompt_for_initial_question()?,
+                };
+
+                // Fill in {{ask:...}} placeholders from --var, asking for any missing ones
+                // (only when stdin is still free for answers)
+                let prompt_text = fs::read_to_string(&mode.prompt_path)
+                    .map_err(|e| format!("Failed to read prompt {}: {}", mode.prompt_path, e))?;
+                let can_ask = !options.stdin_as_context && !options.print;
+                let variables = collect_prompt_variables(&prompt_text, &options.variables, can_ask)?;
+                // {{cmd:...}} placeholders: allowlisted commands, output spliced in
+                let command_outputs = run_prompt_commands(&prompt_text, can_ask)?;
+
+                let mut launch_mode = mode.clone();
+                if context.is_some() || question.is_some() || !variables.is_empty() || !command_outputs.is_empty() {
+                    launch_mode.prompt_path = create_rendered_prompt(
+                        &mode.prompt_path,
+                        &variables,
+                        &command_outputs,
+                        context.as_deref(),
+                        question.as_deref(),
+                    )?;
+                    let rendered_size = fs::metadata(&launch_mode.prompt_path)
+                        .map_err(|e| format!("Failed to read prompt {}: {}", launch_mode.prompt_path, e))?
+                        .len();
+                    check_prompt_size(rendered_size, None, &launch_mode.parameters)?;
+                }
+                if question.is_some() {
+                    // The question is in the prompt, so let the model respond first
+                    launch_mode.parameters.interactive_first = false;
+                }
+                check_prompt_tokens(&mut launch_mode, can_ask)?;
+                confirm_rendered_prompt(&mut launch_mode)?;
+
+                if options.print {
+                    let limits = GenerationLimits { max_seconds: options.max_seconds, max_bytes: options.max_bytes };
+                    let response = run_non_interactive_cached(&launch_mode, !options.no_cache, &limits)?;
+                    if options.pager && io::stdout().is_terminal() {
+                        show_in_pager(response.trim_end());
+                    } else {
+                        println!("{}", response.trim_end());
+                    }
+                    if let Err(e) = speak_text(&response) {
+                        eprintln!("Warning: {}", e);
+                    }
+                    if let Some(dir) = &options.save_code_dir {
+                        save_code_blocks(&extract_code_blocks(&response), Some(Path::new(dir)))?;
+                    }
+                    if response.contains(&format!("{} --max-seconds", TRUNCATED_OUTPUT_MARKER)) {
+                        return Err(format!("Generation timed out (--max-seconds {})", options.max_seconds.unwrap_or(0)));
+                    }
+                    return Ok(format!("print_mode::{}", mode.id));
+                }
+                
+                println!("\nLaunching LLaMA...");
+                launch_llama(&launch_mode, options.inline)?;
+                
+                Ok(format!("saved_mode::{}", mode.id))
+            } else {
+                Err("Invalid mode selection".to_string())
+            }
+        },
+    }
+}
+
+/// Asks for an optional initial question before launching a saved mode
+///
+/// Disabled when `quick_question = false` is set in the config.
+///
+/// # Returns
+/// - Ok(Some(String)): The question typed by the user
+/// - Ok(None): User pressed Enter to skip, or the step is disabled
+/// - Err(String): Input could not be read
+fn prompt_for_initial_question() -> Result<Option<String>, String> {
+    if read_config_bool("quick_question") == Some(false) {
+        return Ok(None);
+    }
+
+    print!("\nType an initial question (or Enter to skip): ");
+    io::stdout().flush().map_err(|e| e.to_string())?;
+    let question = read_user_input_with_history("question")?.trim().to_string();
+
+    if question.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(question))
+    }
+}
+
+/// Gets the absolute path to the temporary session files directory and ensures it exists
+///
+/// Location: ~/query_gguf/temp/
+///
+/// Used for generated prompt files that only matter for a single launch.
+fn get_temp_dir() -> Result<PathBuf, String> {
+    let temp_dir = get_app_base_dir()?.join("temp");
+    fs::create_dir_all(&temp_dir)
+        .map_err(|e| format!("Failed to create temp directory: {}", e))?;
+    Ok(temp_dir)
+}
+
+/// Finds the `{{<kind>:...}}` placeholders in a prompt, in order, without repeats
+///
+/// # Arguments
+/// * `prompt` - Prompt text
+/// * `kind` - Placeholder kind: "ask" or "cmd"
+///
+/// # Returns
+/// * `Vec<String>` - The text of each placeholder (e.g. "Project name" for `{{ask:Project name}}`)
+fn find_placeholders(prompt: &str, kind: &str) -> Vec<String> {
+    let opening = format!("{{{{{}:", kind);
+    let mut labels: Vec<String> = Vec::new();
+    let mut rest = prompt;
+    while let Some(start) = rest.find(&opening) {
+        let after = &rest[start + opening.len()..];
+        let Some(end) = after.find("}}") else { break };
+        let label = after[..end].trim().to_string();
+        if !label.is_empty() && !labels.contains(&label) {
+            labels.push(label);
+        }
+        rest = &after[end + 2..];
+    }
+    labels
+}
+
+/// Looks up the value for a placeholder, by its exact question text or its slug
+///
+/// `--var "Project name=x"` and `--var project-name=x` both match `{{ask:Project name}}`.
+fn lookup_prompt_variable<'a>(variables: &'a [(String, String)], label: &str) -> Option<&'a str> {
+    let slug = slugify_mode_name(label);
+    variables.iter()
+        .find(|(name, _)| name == label || slugify_mode_name(name) == slug)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Gets a value for every `{{ask:...}}` placeholder in the prompt
+///
+/// Values come from `--var` first; missing ones are asked for when
+/// `can_ask` is true, and are an error otherwise.
+///
+/// # Returns
+/// - Ok(Vec<(String, String)>): (question text, value) for each placeholder
+/// - Err(String): A value is missing and cannot be asked for
+fn collect_prompt_variables(
+    prompt: &str,
+    given: &[(String, String)],
+    can_ask: bool,
+) -> Result<Vec<(String, String)>, String> {
+    let mut values = Vec::new();
+    for label in find_placeholders(prompt, "ask") {
+        let value = match lookup_prompt_variable(given, &label) {
+            Some(value) => value.to_string(),
+            None if can_ask => {
+                print!("{}: ", label);
+                io::stdout().flush().map_err(|e| e.to_string())?;
+                read_user_input()?.trim().to_string()
+            },
+            None => return Err(format!(
+                "The prompt asks for '{}'. Pass it with --var \"{}=...\"",
+                label, label
+            )),
+        };
+        values.push((label, value));
+    }
+    Ok(values)
+}
+
+/// Replaces each `{{<kind>:...}}` placeholder that has a value; others are left as they are
+///
+/// All kinds are filled in one left-to-right pass over the prompt, so a
+/// value (e.g. an answer containing `{{cmd:...}}`) is never expanded again.
+///
+/// # Arguments
+/// * `prompt` - Prompt text
+/// * `values_by_kind` - For each kind ("ask", "cmd"), the (label, value) pairs
+fn substitute_placeholders(prompt: &str, values_by_kind: &[(&str, &[(String, String)])]) -> String {
+    let mut result = String::new();
+    let mut rest = prompt;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after.find("}}").and_then(|end| {
+            let (kind, label) = after[..end].split_once(':')?;
+            let (_, values) = values_by_kind.iter().find(|(known, _)| *known == kind)?;
+            let (_, value) = values.iter().find(|(name, _)| name == label.trim())?;
+            Some((value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                result.push_str(value);
+                rest = &after[end + 2..];
+            },
+            None => {
+                result.push_str("{{");
+                rest = after;
+            },
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Most characters of one command's output put into a prompt
+const MAX_PROMPT_COMMAND_OUTPUT_CHARS: usize = 20_000;
+
+/// Splits a command line into program and arguments, honoring single and double quotes
+///
+/// No shell is involved, so pipes, redirects, and variables are not interpreted.
+fn split_command_line(command_line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    for c in command_line.chars() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), c) => current.push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                in_word = true;
+            },
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            },
+            (None, c) => {
+                current.push(c);
+                in_word = true;
+            },
+        }
+    }
+    if in_word {
+        words.push(current);
+    }
+    words
+}
+
+/// Checks a program name against a comma separated allowlist (e.g. `prompt_command_allowlist`)
+fn is_command_allowed(program: &str, allowlist: &str) -> bool {
+    allowlist.split(',')
+        .map(|allowed| allowed.trim())
+        .any(|allowed| !allowed.is_empty() && allowed == program)
+}
+
+/// Runs the `{{cmd:...}}` placeholders of a prompt and collects their output
+///
+/// Only programs listed in `prompt_command_allowlist` in the config may run.
+/// The commands are shown and must be confirmed first, unless
+/// `prompt_command_confirm = false` is set in the config. Commands run
+/// without a shell.
+///
+/// # Arguments
+/// * `prompt` - Prompt text
+/// * `can_ask` - Whether the confirmation question can be asked (stdin is free)
+///
+/// # Returns
+/// - Ok(Vec<(String, String)>): (command, output) for each placeholder
+/// - Err(String): A command is not allowed, was declined, or cannot be confirmed
+fn run_prompt_commands(prompt: &str, can_ask: bool) -> Result<Vec<(String, String)>, String> {
+    let commands = find_placeholders(prompt, "cmd");
+    if commands.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // A comma separated string or a list of strings
+    let allowlist = read_config().get_string_list("prompt_command_allowlist").join(",");
+    for command in &commands {
+        let program = split_command_line(command).into_iter().next().unwrap_or_default();
+        if !is_command_allowed(&program, &allowlist) {
+            return Err(format!(
+                "The prompt wants to run '{}', but '{}' is not in prompt_command_allowlist in the config.",
+                command, program
+            ));
+        }
+    }
+
+    if read_config_bool("prompt_command_confirm") != Some(false) {
+        if !can_ask {
+            return Err("The prompt runs commands, which need confirmation, but input is not interactive. \
+                        Set prompt_command_confirm = false in the config to allow this.".to_string());
+        }
+        println!("\nThe prompt will run these commands and include their output:");
+        for command in &commands {
+            println!("  $ {}", command);
+        }
+        if !prompt_yes_no("Run them?")? {
+            return Err("Prompt commands were not confirmed".to_string());
+        }
+    }
+
+    let mut outputs = Vec::new();
+    for command in commands {
+        let text = run_command_capture(&command)?;
+        outputs.push((command, text));
+    }
+    Ok(outputs)
+}
+
+/// Runs a command line without a shell and returns its output for a model to read
+///
+/// A failed command's exit status and stderr are added to the output.
+/// Output longer than MAX_PROMPT_COMMAND_OUTPUT_CHARS is cut off.
+fn run_command_capture(command_line: &str) -> Result<String, String> {
+    run_argv_capture(&split_command_line(command_line), None)
+}
+
+/// Runs a program with arguments (no shell) and returns its output for a model to read
+///
+/// # Arguments
+/// * `argv` - Program and arguments
+/// * `working_dir` - Directory to run in, or None for the current directory
+fn run_argv_capture(argv: &[String], working_dir: Option<&Path>) -> Result<String, String> {
+    let (program, arguments) = argv.split_first()
+        .ok_or_else(|| "Empty command".to_string())?;
+    let mut command = Command::new(program);
+    if let Some(dir) = working_dir {
+        command.current_dir(dir);
+    }
+    let output = command
+        .args(arguments)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|e| format!("Failed to run '{}': {}", argv.join(" "), e))?;
+
+    let mut text = String::from_utf8_lossy(&output.stdout).trim_end().to_string();
+    if !output.status.success() {
+        text.push_str(&format!(
+            "\n(exited with status {}: {})",
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(truncate_tool_output(text))
+}
+
+/// Cuts text given to a model down to MAX_PROMPT_COMMAND_OUTPUT_CHARS
+fn truncate_tool_output(text: String) -> String {
+    if text.chars().count() <= MAX_PROMPT_COMMAND_OUTPUT_CHARS {
+        return text;
+    }
+    let mut truncated: String = text.chars().take(MAX_PROMPT_COMMAND_OUTPUT_CHARS).collect();
+    truncated.push_str("\n(output truncated)");
+    truncated
+}
+
+/// Creates a temporary prompt file from the original prompt plus optional context and question
+///
+/// # Arguments
+/// * `original_prompt_path` - Path to the mode's prompt file
+/// * `variables` - Values for the prompt's `{{ask:...}}` placeholders
+/// * `command_outputs` - Output for the prompt's `{{cmd:...}}` placeholders
+/// * `context` - Optional document text (e.g. piped stdin) to include
+/// * `question` - Optional question to put at the end
+///
+/// # Returns
+/// - Ok(String): Path to the new prompt file in ~/query_gguf/temp/
+/// - Err(String): Prompt could not be read or the new file could not be written
+fn create_rendered_prompt(
+    original_prompt_path: &str,
+    variables: &[(String, String)],
+    command_outputs: &[(String, String)],
+    context: Option<&str>,
+    question: Option<&str>,
+) -> Result<String, String> {
+    let original_prompt = fs::read_to_string(original_prompt_path)
+        .map_err(|e| format!("Failed to read original prompt: {}", e))?;
+    let original_prompt = substitute_placeholders(&original_prompt, &[("ask", variables), ("cmd", command_outputs)]);
+
+    let rendered_prompt_path = get_temp_dir()?
+        .join(format!("rendered_prompt_{}.txt", generate_timestamp_string()));
+
+    fs::write(&rendered_prompt_path, render_prompt_text(&original_prompt, context, question))
+        .map_err(|e| format!("Failed to write rendered prompt: {}", e))?;
+
+    Ok(rendered_prompt_path.to_string_lossy().to_string())
+}
+
+/// Joins prompt text, optional context, and optional question, separated by blank lines
+fn render_prompt_text(prompt: &str, context: Option<&str>, question: Option<&str>) -> String {
+    let mut sections = Vec::new();
+    if !prompt.trim().is_empty() {
+        sections.push(prompt.trim_end().to_string());
+    }
+    if let Some(context) = context {
+        sections.push(format!("### Context ###\n{}", context.trim_end()));
+    }
+    if let Some(question) = question {
+        sections.push(question.trim().to_string());
+    }
+    format!("{}\n", sections.join("\n\n"))
+}
+
+/// Clears the terminal screen in a cross-platform way
+fn clear_screen() {
+    if cfg!(windows) {
+        let _ = Command::new("cmd").arg("/c").arg("cls").status();
+    } else {
+        let _ = Command::new("clear").status();
+    }
+}
+
+/// Reads a line of user input
+///
+/// In a terminal, the line can be edited (arrow keys, Home/End, Backspace,
+/// Delete). The returned string ends with a newline, like `read_line`.
+fn read_user_input() -> Result<String, String> {
+    read_edited_line(&LineEditorOptions::default())
+}
+
+/// Reads a line of user input with up/down history that is kept across runs
+///
+/// # Arguments
+/// * `history_kind` - Which history to use, e.g. "selection" or "question"
+fn read_user_input_with_history(history_kind: &str) -> Result<String, String> {
+    read_edited_line(&LineEditorOptions { history_kind: Some(history_kind), ..Default::default() })
+}
+
+/// Prints `prompt` and reads a file or directory path, with Tab completion
+///
+/// Tab completes the path as far as it is unambiguous; pressing Tab again
+/// lists the possible completions.
+fn read_path_input(prompt: &str) -> Result<String, String> {
+    print!("{}", prompt);
+    io::stdout().flush().map_err(|e| e.to_string())?;
+    read_edited_line(&LineEditorOptions {
+        history_kind: Some("path"),
+        complete_paths: true,
+        prompt,
+    })
+}
+
+/// Completes a partly typed path against the filesystem
+///
+/// `~` is expanded for the lookup but kept in the returned text.
+///
+/// # Returns
+/// * `(String, Vec<String>)` - The completed text, and the candidates if more than one
+///   entry matches (directories end with '/')
+fn complete_path(input: &str) -> (String, Vec<String>) {
+    let (dir_text, prefix) = match input.rfind('/') {
+        Some(index) => (&input[..=index], &input[index + 1..]),
+        None => ("", input),
+    };
+
+    let lookup_dir = if dir_text.is_empty() {
+        PathBuf::from(".")
+    } else if let (Some(rest), Ok(home)) = (dir_text.strip_prefix('~'), get_home_dir()) {
+        PathBuf::from(format!("{}{}", home, rest))
+    } else {
+        PathBuf::from(dir_text)
+    };
+
+    let mut matches: Vec<String> = match fs::read_dir(&lookup_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                    return None;
+                }
+                let is_dir = entry.path().is_dir();
+                Some(if is_dir { format!("{}/", name) } else { name })
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    matches.sort();
+
+    match matches.len() {
+        0 => (input.to_string(), Vec::new()),
+        1 => (format!("{}{}", dir_text, matches[0]), Vec::new()),
+        _ => {
+            // Extend to the longest prefix all candidates share
+            let mut common: Vec<char> = matches[0].chars().collect();
+            for candidate in &matches[1..] {
+                let shared = common.iter()
+                    .zip(candidate.chars())
+                    .take_while(|(a, b)| **a == *b)
+                    .count();
+                common.truncate(shared);
+            }
+            let common: String = common.into_iter().collect();
+            (format!("{}{}", dir_text, common), matches)
+        },
+    }
+}
+
+/// Most entries kept in each input history file
+const MAX_HISTORY_ENTRIES: usize = 500;
+
+/// Gets the path to an input history file: ~/query_gguf/history/<kind>.txt
+fn get_history_path(history_kind: &str) -> Result<PathBuf, String> {
+    let history_dir = get_app_base_dir()?.join("history");
+    fs::create_dir_all(&history_dir)
+        .map_err(|e| format!("Failed to create history directory: {}", e))?;
+    Ok(history_dir.join(format!("{}.txt", history_kind)))
+}
+
+/// Adds an entry to an input history file, skipping blanks and repeats of the last entry
+fn append_history_entry(history_kind: &str, entry: &str) -> Result<(), String> {
+    let entry = entry.trim();
+    if entry.is_empty() {
+        return Ok(());
+    }
+    update_state_file(&get_history_path(history_kind)?, |content| {
+        let mut entries: Vec<&str> = content.lines().collect();
+        if entries.last() == Some(&entry) {
+            return None;
+        }
+        entries.push(entry);
+        let keep_from = entries.len().saturating_sub(MAX_HISTORY_ENTRIES);
+        Some(format!("{}\n", entries[keep_from..].join("\n")))
+    })
+}
+
+/// The line being edited: characters and the cursor position (in characters)
+#[derive(Debug, Default)]
+struct LineBuffer {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl LineBuffer {
+    fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    /// Replaces the whole line, with the cursor at the end
+    fn set_text(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    /// Removes the character before the cursor
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    /// Removes the character under the cursor
+    fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    fn move_left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn move_right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.chars.len());
+    }
+}
+
+/// Up/down browsing through previous entries while editing a line
+#[derive(Debug, Default)]
+struct HistoryBrowser {
+    entries: Vec<String>,
+    position: usize,     // entries.len() means "the line being typed"
+    draft: String,       // the line being typed, kept while browsing
+}
+
+impl HistoryBrowser {
+    fn new(entries: Vec<String>) -> Self {
+        let position = entries.len();
+        HistoryBrowser { entries, position, draft: String::new() }
+    }
+
+    /// Moves to the previous (older) entry; None if already at the oldest
+    fn previous(&mut self, current: &str) -> Option<String> {
+        if self.position == 0 {
+            return None;
+        }
+        if self.position == self.entries.len() {
+            self.draft = current.to_string();
+        }
+        self.position -= 1;
+        Some(self.entries[self.position].clone())
+    }
+
+    /// Moves to the next (newer) entry, ending at the line being typed
+    fn next(&mut self) -> Option<String> {
+        if self.position >= self.entries.len() {
+            return None;
+        }
+        self.position += 1;
+        if self.position == self.entries.len() {
+            Some(self.draft.clone())
+        } else {
+            Some(self.entries[self.position].clone())
+        }
+    }
+}
+
+/// Puts the terminal into unbuffered, no-echo mode and restores it when dropped
+#[cfg(unix)]
+struct RawTerminalMode {
+    saved_settings: String,
+}
+
+#[cfg(unix)]
+impl RawTerminalMode {
+    /// Returns None if the terminal settings could not be changed
+    fn enable() -> Option<RawTerminalMode> {
+        let saved = Command::new("stty")
+            .arg("-g")
+            .stdin(std::process::Stdio::inherit())
+            .output()
+            .ok()?;
+        if !saved.status.success() {
+            return None;
+        }
+        let saved_settings = String::from_utf8_lossy(&saved.stdout).trim().to_string();
+
+        // -isig so Ctrl-C reaches us and the terminal can be restored before exiting
+        let status = Command::new("stty")
+            .args(["-icanon", "-echo", "-isig", "min", "1"])
+            .stdin(std::process::Stdio::inherit())
+            .status()
+            .ok()?;
+        if !status.success() {
+            return None;
+        }
+        Some(RawTerminalMode { saved_settings })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawTerminalMode {
+    fn drop(&mut self) {
+        let _ = Command::new("stty")
+            .arg(&self.saved_settings)
+            .stdin(std::process::Stdio::inherit())
+            .status();
+    }
+}
+
+/// Key presses the line editor acts on
+#[derive(Debug, PartialEq)]
+enum EditorKey {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Tab,
+    Interrupt,   // Ctrl-C
+    EndOfInput,  // Ctrl-D
+    Ignored,
+}
+
+/// Reads one byte from the terminal, None at end of input
+fn read_terminal_byte(input: &mut impl io::Read) -> Option<u8> {
+    let mut byte = [0u8; 1];
+    match input.read(&mut byte) {
+        Ok(1) => Some(byte[0]),
+        _ => None,
+    }
+}
+
+/// Reads one key press, decoding UTF-8 characters and arrow key escape sequences
+fn read_editor_key(input: &mut impl io::Read) -> Option<EditorKey> {
+    let first = read_terminal_byte(input)?;
+    let key = match first {
+        b'\r' | b'\n' => EditorKey::Enter,
+        127 | 8 => EditorKey::Backspace,
+        b'\t' => EditorKey::Tab,
+        1 => EditorKey::Home,        // Ctrl-A
+        5 => EditorKey::End,         // Ctrl-E
+        3 => EditorKey::Interrupt,   // Ctrl-C
+        4 => EditorKey::EndOfInput,  // Ctrl-D
+        27 => {
+            // ESC [ X, ESC O X, or ESC [ n ~
+            let introducer = read_terminal_byte(input)?;
+            if introducer != b'[' && introducer != b'O' {
+                return Some(EditorKey::Ignored);
+            }
+            match read_terminal_byte(input)? {
+                b'A' => EditorKey::Up,
+                b'B' => EditorKey::Down,
+                b'C' => EditorKey::Right,
+                b'D' => EditorKey::Left,
+                b'H' => EditorKey::Home,
+                b'F' => EditorKey::End,
+                digit @ b'0'..=b'9' => {
+                    // Read up to the final '~' (or other terminator)
+                    let mut terminator = read_terminal_byte(input)?;
+                    while terminator.is_ascii_digit() || terminator == b';' {
+                        terminator = read_terminal_byte(input)?;
+                    }
+                    match (digit, terminator) {
+                        (b'3', b'~') => EditorKey::Delete,
+                        (b'1', b'~') | (b'7', b'~') => EditorKey::Home,
+                        (b'4', b'~') | (b'8', b'~') => EditorKey::End,
+                        _ => EditorKey::Ignored,
+                    }
+                },
+                _ => EditorKey::Ignored,
+            }
+        },
+        byte if byte < 32 => EditorKey::Ignored,
+        byte => {
+            // Multi-byte UTF-8: the leading byte says how many bytes follow
+            let length = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..length {
+                bytes.push(read_terminal_byte(input)?);
+            }
+            match std::str::from_utf8(&bytes).ok().and_then(|text| text.chars().next()) {
+                Some(c) => EditorKey::Char(c),
+                None => EditorKey::Ignored,
+            }
+        },
+    };
+    Some(key)
+}
+
+/// Redraws the line after the prompt and puts the terminal cursor in place
+///
+/// The cursor position at the start of the line was saved with ESC 7.
+fn redraw_edited_line(line: &LineBuffer) -> Result<(), String> {
+    let mut output = format!("\x1b8\x1b[K{}", line.text());
+    let chars_after_cursor = line.chars.len() - line.cursor;
+    if chars_after_cursor > 0 {
+        output.push_str(&format!("\x1b[{}D", chars_after_cursor));
+    }
+    print!("{}", output);
+    io::stdout().flush().map_err(|e| e.to_string())
+}
+
+/// Options for one line editor read
+#[derive(Debug, Default)]
+struct LineEditorOptions<'a> {
+    history_kind: Option<&'a str>,  // Which history file to browse and add to, if any
+    complete_paths: bool,           // Tab completes filesystem paths
+    prompt: &'a str,                // Reprinted after listing completions
+}
+
+/// Reads a line with editing, plus history and path completion as set in `options`
+///
+/// Falls back to plain `read_line` when stdin or stdout is not a terminal
+/// (e.g. piped input) or the terminal mode cannot be changed.
+fn read_edited_line(options: &LineEditorOptions) -> Result<String, String> {
+    let history_kind = options.history_kind;
+    use std::io::IsTerminal;
+
+    #[cfg(unix)]
+    let raw_mode = if io::stdin().is_terminal() && io::stdout().is_terminal() {
+        RawTerminalMode::enable()
+    } else {
+        None
+    };
+    #[cfg(not(unix))]
+    let raw_mode: Option<()> = None;
+
+    if raw_mode.is_none() {
+        let mut input = String::new();
+        io::stdin()
+            .read_line(&mut input)
+            .map_err(|e| format!("Failed to read input: {}", e))?;
+        if let Some(kind) = history_kind {
+            let _ = append_history_entry(kind, &input);
+        }
+        return Ok(input);
+    }
+
+    let history_entries = history_kind
+        .and_then(|kind| get_history_path(kind).ok())
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|content| content.lines().map(|line| line.to_string()).collect())
+        .unwrap_or_default();
+    let mut history = HistoryBrowser::new(history_entries);
+    let mut line = LineBuffer::default();
+
+    // Remember where the line starts so it can be redrawn in place
+    print!("\x1b7");
+    io::stdout().flush().map_err(|e| e.to_string())?;
+
+    let mut stdin = io::stdin().lock();
+    while let Some(key) = read_editor_key(&mut stdin) {
+        match key {
+            EditorKey::Enter => break,
+            EditorKey::Char(c) => line.insert(c),
+            EditorKey::Backspace => line.backspace(),
+            EditorKey::Delete => line.delete(),
+            EditorKey::Left => line.move_left(),
+            EditorKey::Right => line.move_right(),
+            EditorKey::Home => line.cursor = 0,
+            EditorKey::End => line.cursor = line.chars.len(),
+            EditorKey::Up => {
+                if let Some(entry) = history.previous(&line.text()) {
+                    line.set_text(&entry);
+                }
+            },
+            EditorKey::Down => {
+                if let Some(entry) = history.next() {
+                    line.set_text(&entry);
+                }
+            },
+            EditorKey::Interrupt => {
+                drop(raw_mode);
+                println!("^C");
+                std::process::exit(EXIT_USER_ABORT);
+            },
+            EditorKey::Tab if options.complete_paths => {
+                let typed = line.text();
+                let (completed, candidates) = complete_path(&typed);
+                if completed == typed && !candidates.is_empty() {
+                    // Nothing more to add: list the choices and start a fresh prompt line
+                    print!("\r\n{}\r\n{}\x1b7", candidates.join("  "), options.prompt);
+                }
+                line.set_text(&completed);
+            },
+            EditorKey::EndOfInput if line.chars.is_empty() => break,
+            EditorKey::EndOfInput | EditorKey::Tab | EditorKey::Ignored => {},
+        }
+        redraw_edited_line(&line)?;
+    }
+    drop(raw_mode);
+    println!();
+
+    let text = line.text();
+    if let Some(kind) = history_kind {
+        let _ = append_history_entry(kind, &text);
+    }
+    Ok(format!("{}\n", text))
+}
+
+/// Represents a model file with its path and name
+struct ModelFile {
+    full_path: String,
+    display_name: String,
+}
+
+/// Splits a model file name into base model name and quantization tag
+///
+/// The tag is the last '-', '.', or '_' separated part that looks like a
+/// llama.cpp quantization (Q4_K_M, IQ3_XS, Q8_0, F16, BF16...).
+///
+/// # Returns
+/// * `(String, String)` - e.g. ("Mistral-7B-Instruct-v0.2", "Q4_K_M"); the tag is empty if none is found
+fn split_model_quantization(file_name: &str) -> (String, String) {
+    // A split model is named after its set, without the part number
+    let split_prefix = parse_split_part(file_name).map(|(prefix, _, _)| prefix);
+    let stem = match &split_prefix {
+        Some(prefix) => prefix.as_str(),
+        None => file_name.strip_suffix(".gguf").unwrap_or(file_name),
+    };
+
+    let is_quantization_tag = |tag: &str| {
+        let upper = tag.to_uppercase();
+        let rest = upper.strip_prefix("IQ").or_else(|| upper.strip_prefix('Q'));
+        let quantized = rest
+            .map(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+            .unwrap_or(false);
+        let float = ["F16", "BF16", "F32", "FP16"].contains(&upper.as_str());
+        (quantized || float) && upper.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+
+    // The first separator where everything after it is one quantization tag
+    for (index, c) in stem.char_indices() {
+        if index > 0 && (c == '-' || c == '.' || c == '_') && is_quantization_tag(&stem[index + 1..]) {
+            return (stem[..index].to_string(), stem[index + 1..].to_string());
+        }
+    }
+    (stem.to_string(), String::new())
+}
+
+/// Groups models by base model name (case-insensitive), keeping first-seen order
+///
+/// # Returns
+/// * `Vec<(String, Vec<usize>)>` - Base model name and the indices of its variants in `models`
+fn group_models_by_family(models: &[ModelFile]) -> Vec<(String, Vec<usize>)> {
+    let mut families: Vec<(String, Vec<usize>)> = Vec::new();
+    for (index, model) in models.iter().enumerate() {
+        let (family, _) = split_model_quantization(&model.display_name);
+        match families.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case(&family)) {
+            Some((_, variants)) => variants.push(index),
+            None => families.push((family, vec![index])),
+        }
+    }
+    families
+}
+
+/// Lists models grouped by base model and asks for a family, then a variant
+///
+/// Variants used by saved modes are marked with the mode ids.
+///
+/// # Returns
+/// - Ok(&ModelFile): The chosen model file
+/// - Err(String): Invalid selection or input error
+fn select_model_grouped(models: &[ModelFile]) -> Result<&ModelFile, String> {
+    let saved_modes = read_saved_modes().unwrap_or_default();
+    let used_by = |model: &ModelFile| -> Vec<String> {
+        saved_modes.iter()
+            .filter(|mode| mode.model_path == model.full_path)
+            .map(|mode| mode.id.clone())
+            .collect()
+    };
+    let quantization_of = |model: &ModelFile| {
+        let (_, tag) = split_model_quantization(&model.display_name);
+        if tag.is_empty() { model.display_name.clone() } else { tag }
+    };
+
+    let families = group_models_by_family(models);
+    println!("\nAvailable Models ({} files, {} models):", models.len(), families.len());
+    for (index, (family, variants)) in families.iter().enumerate() {
+        let tags: Vec<String> = variants.iter()
+            .map(|&variant| {
+                let tag = quantization_of(&models[variant]);
+                if used_by(&models[variant]).is_empty() { tag } else { format!("{}*", tag) }
+            })
+            .collect();
+        println!("{}. {}  [{}]", index + 1, family, tags.join(", "));
+    }
+    println!("(* = used by a saved mode)");
+
+    print!("\nSelect model number: ");
+    io::stdout().flush().map_err(|e| e.to_string())?;
+    let model_choice = read_user_input()?;
+    let (_, variants) = model_choice.trim().parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_sub(1))
+        .and_then(|index| families.get(index))
+        .ok_or("Invalid model number".to_string())?;
+
+    if variants.len() == 1 {
+        return Ok(&models[variants[0]]);
+    }
+
+    println!();
+    for (index, &variant) in variants.iter().enumerate() {
+        let modes = used_by(&models[variant]);
+        let modes_note = if modes.is_empty() {
+            String::new()
+        } else {
+            format!("  (used by: {})", modes.join(", "))
+        };
+        println!("  {}. {}{}", index + 1, quantization_of(&models[variant]), modes_note);
+    }
+    print!("\nSelect variant number: ");
+    io::stdout().flush().map_err(|e| e.to_string())?;
+    let variant_choice = read_user_input()?;
+    variant_choice.trim().parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_sub(1))
+        .and_then(|index| variants.get(index))
+        .map(|&variant| &models[variant])
+        .ok_or("Invalid variant number".to_string())
+}
+
+/// Guides the user through creating a new chat mode configuration
+/// 
+/// This interactive process:
+/// 1. Lists available GGUF models from configured directories
+/// 2. Allows model selection
+/// 3. Offers prompt file selection
+/// 4. Enables parameter configuration
+/// 5. Provides option to save as a named mode
+/// 
+/// File paths are handled using standard locations:
+/// - Models: Read from directories in ~/query_gguf/query_gguf_config.toml
+/// - Prompts: ~/query_gguf/prompts/
+/// - Config: ~/query_gguf/query_gguf_config.toml
+/// 
+/// # Returns
+/// - Ok(String): Success message with format "manual::{model_name}"
+/// - Err(String): Error message if any step fails
+/// 
+/// # Error Cases
+/// - No models found
+/// - Invalid model selection
+/// - Prompt file access fails
+/// - Parameter configuration fails
+/// - Save operation fails
+/// 
+/// # Example Success Return
+/// ```
+/// Ok("manual::llama-7b-q4")
+/// ```
+/// 
+/// # File Path Handling
+/// - Uses absolute paths for reliability
+/// - Expands home directory (~) in paths
+/// - Validates file existence before operations
+///
+/// Handles the manual mode selection process
+fn handle_manual_mode_selection() -> Result<String, String> {
+    
+    // turn off for debugging
+    clear_screen();
+    
+    println!("\n=== Manual Mode Setup ===");
+
+    // 1. Find and list available models
+    let models = find_gguf_models()?;
+    if models.is_empty() {
+        return Err("No GGUF models found in configured directories".to_string());
+    }
+
+    // 2. Get model selection (grouped by base model, then quantization variant)
+    let selected_model = select_model_grouped(&models)?;
+
+    // 3. Handle prompt selection
+    let prompt_path = if prompt_yes_no("Would you like to use a prompt file?")? {
+        select_prompt_file()?
+    } else {
+        // Use blank prompt when no prompt is selected
+        get_prompts_dir()?.join("blankprompt.txt").to_string_lossy().to_string()
+    };
+
+    // 4. Configure parameters
+    let parameters = configure_model_parameters()?;
+
+    // 5. Create launch configuration
+    let launch_config = LaunchConfiguration {
+        model_path: selected_model.full_path.clone(),
+        prompt_path,
+        parameters,
+    };
+
+    // 6. Offer to save as mode
+    offer_to_save_mode(&launch_config)?;
+
+    Ok(format!("manual::{}", selected_model.display_name))
+}
+
+/// Cost of one directory entry for ScanThrottle, in bytes (about one metadata block)
+const SCAN_ENTRY_COST_BYTES: u64 = 4096;
+
+/// Paces the model, prompt, and directory scanners
+///
+/// A recursive scan at full speed can saturate a spinning disk or network
+/// mount and hang the desktop. `scan_io_limit_kb` sets the KiB per second a
+/// scan may read (directory entries count as SCAN_ENTRY_COST_BYTES each,
+/// file reads as their size), as a token bucket holding up to one second's
+/// worth. `scan_time_budget_secs` stops a scan that takes longer; the
+/// model and prompt lists then fall back to the last complete scan (see
+/// `complete_scan_from_index`).
+struct ScanThrottle {
+    rate: f64,                              // bytes per second, 0 for no limit
+    tokens: f64,                            // below 0 after a large read: a debt paid off by waiting
+    last_refill: std::time::Instant,
+    deadline: Option<std::time::Instant>,
+    ran_out: bool,                          // the time budget ran out, so the scan is incomplete
+}
+
+impl ScanThrottle {
+    /// A throttle allowing `kib_per_second` (0 for no limit), stopping after `budget`
+    fn new(kib_per_second: u64, budget: Option<std::time::Duration>) -> ScanThrottle {
+        let now = std::time::Instant::now();
+        let rate = (kib_per_second * 1024) as f64;
+        ScanThrottle { rate, tokens: rate, last_refill: now, deadline: budget.map(|budget| now + budget), ran_out: false }
+    }
+
+    /// The throttle set in the config (`scan_io_limit_kb`, `scan_time_budget_secs`)
+    fn from_config() -> ScanThrottle {
+        let config = read_config();
+        let kib_per_second = config.get_int("scan_io_limit_kb").and_then(|limit| u64::try_from(limit).ok()).unwrap_or(0);
+        let budget = config.get_float("scan_time_budget_secs")
+            .filter(|secs| *secs > 0.0 && secs.is_finite())
+            .map(std::time::Duration::from_secs_f64);
+        ScanThrottle::new(kib_per_second, budget)
+    }
+
+    /// Takes `cost` bytes from the bucket at `now`, returning how long to wait before reading them
+    fn take(&mut self, cost: u64, now: std::time::Instant) -> std::time::Duration {
+        if self.rate <= 0.0 {
+            return std::time::Duration::ZERO;
+        }
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+        self.tokens -= cost as f64;
+        if self.tokens >= 0.0 {
+            std::time::Duration::ZERO
+        } else {
+            std::time::Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    /// Pays for a read of `cost` bytes, waiting first if the scan is over its rate
+    ///
+    /// # Returns
+    /// false once the time budget has run out: the scan should stop
+    fn spend(&mut self, cost: u64) -> bool {
+        if self.ran_out {
+            return false;
+        }
+        let now = std::time::Instant::now();
+        let wait = self.take(cost, now);
+        if let Some(deadline) = self.deadline {
+            // A wait past the deadline would only delay the same outcome
+            if now + wait >= deadline {
+                self.ran_out = true;
+                return false;
+            }
+        }
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+        true
+    }
+}
+
+/// State field holding the model files found by the last complete model scan
+const MODEL_SCAN_INDEX_FIELD: &str = "scan_index_models";
+
+/// State field holding the prompt files found by the last complete prompt scan
+const PROMPT_SCAN_INDEX_FIELD: &str = "scan_index_prompts";
+
+/// Adds the files of an earlier scan that an interrupted scan did not reach
+///
+/// Only index entries under one of `roots` (the directories scanned now)
+/// are used, so directories removed from the config do not come back.
+fn merge_scan_index(found: &[String], index: &[String], roots: &[PathBuf]) -> Vec<String> {
+    let mut merged = found.to_vec();
+    for path in index {
+        if !merged.contains(path) && roots.iter().any(|root| Path::new(path).starts_with(root)) {
+            merged.push(path.clone());
+        }
+    }
+    merged
+}
+
+/// Finishes a model or prompt scan with the scan index in the state file
+///
+/// A complete scan becomes the new index. A scan stopped by
+/// `scan_time_budget_secs` is completed from the index, since a list from
+/// an earlier scan is more useful than a partial one.
+///
+/// # Arguments
+/// * `field_name` - MODEL_SCAN_INDEX_FIELD or PROMPT_SCAN_INDEX_FIELD
+/// * `found` - Files this scan found
+/// * `roots` - The directories scanned
+/// * `throttle` - The throttle the scan used
+/// * `what` - "model" or "prompt", for the warning
+fn complete_scan_from_index(field_name: &str, found: Vec<String>, roots: &[PathBuf], throttle: &ScanThrottle, what: &str) -> Vec<String> {
+    let index = read_state_list(field_name);
+    if !throttle.ran_out {
+        if found != index {
+            if let Err(e) = update_state_list(field_name, |_| found.clone()) {
+                println!("Warning: Could not save the {} scan index: {}", what, e);
+            }
+        }
+        return found;
+    }
+    let merged = merge_scan_index(&found, &index, roots);
+    println!(
+        "Warning: The {} scan ran out of time (scan_time_budget_secs) after {} files; {} more from the last complete scan",
+        what,
+        found.len(),
+        merged.len() - found.len()
+    );
+    merged
+}
+
+/// Finds all GGUF model files in the configured model directories
+/// 
+/// Reads the configuration file from the standard location:
+/// - Linux/MacOS: ~/query_gguf/query_gguf_config.toml
+/// - Windows: \Users\username\query_gguf\query_gguf_config.toml
+/// 
+/// Searches all directories listed as gguf_model_directory_* entries in the config,
+/// including their subdirectories, for files with .gguf extension. The scan
+/// is paced by `scan_io_limit_kb` and `scan_time_budget_secs` (see ScanThrottle).
+/// 
+/// # Returns
+/// - Ok(Vec<ModelFile>): List of found model files with their paths and names
+/// - Err(String): Error message if config cannot be read or directories cannot be accessed
+/// 
+/// # Path Handling
+/// - Uses absolute paths for reliability
+/// - Expands home directory (~) in paths
+/// - Maintains both full path and display name for each model
+/// 
+/// # Error Cases
+/// - Config file not found
+/// - Cannot read config file
+/// - Model directories don't exist
+/// - Insufficient permissions
+/// 
+/// # Example Config Entries
+/// ```toml
+/// gguf_model_directory_1 = "/home/user/models"
+/// gguf_model_directory_2 = "~/alternative/models"
+/// ```
+fn find_gguf_models() -> Result<Vec<ModelFile>, String> {
+    // Get absolute path to config file
+    let config_path = get_config_path()?;
+    
+    // Read config file
+    let config_content = fs::read_to_string(&config_path)
+        .map_err(|e| format!("Failed to read config at {}: {}", config_path.display(), e))?;
+
+    let mut models = Vec::new();
+    let home_dir = get_home_dir()?;
+    let mut throttle = ScanThrottle::from_config();
+    let mut roots = Vec::new();
+
+    let config = toml_lite::parse(&config_content).0;
+    for path in numbered_config_values(&config, "gguf_model_directory") {
+        let raw_path = to_native_path(&path);
+        let raw_path = raw_path.as_str();
+
+        // Resolve path to absolute, handling ~ expansion
+        let base_path = if let Some(rest) = raw_path.strip_prefix('~') {
+            format!("{}{}", home_dir, rest)
+        } else if !Path::new(raw_path).is_absolute() {
+            format!("{}/{}", home_dir, raw_path)
+        } else {
+            raw_path.to_string()
+        };
+
+        println!("Searching for models in: {}", base_path);
+        search_directory_for_gguf(&mut models, Path::new(&base_path), &mut throttle)?;
+        roots.push(PathBuf::from(base_path));
+    }
+
+    let found: Vec<String> = models.iter().map(|model| model.full_path.clone()).collect();
+    let model_paths = complete_scan_from_index(MODEL_SCAN_INDEX_FIELD, found, &roots, &throttle, "model");
+    let mut models: Vec<ModelFile> = model_paths.into_iter()
+        .map(|full_path| ModelFile {
+            display_name: Path::new(&full_path).file_name().unwrap_or_default().to_string_lossy().to_string(),
+            full_path,
+        })
+        .collect();
+
+    if models.is_empty() {
+        println!("\nWarning: No .gguf files found in configured directories or their subdirectories.");
+    } else {
+        models.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        println!("Found {} model files", models.len());
+    }
+
+    Ok(models)
+}
+
+/// Recursively searches a directory and its subdirectories for .gguf files
+///
+/// Stops early (without an error) when the throttle's time budget runs out.
+fn search_directory_for_gguf(models: &mut Vec<ModelFile>, dir: &Path, throttle: &mut ScanThrottle) -> Result<(), String> {
+    if !dir.exists() {
+        return Err(format!("Directory does not exist: {}", dir.display()));
+    }
+
+    match fs::read_dir(dir) {
+        Ok(entries) => {
+            for entry in entries {
+                if !throttle.spend(SCAN_ENTRY_COST_BYTES) {
+                    break;
+                }
+                match entry {
+                    Ok(entry) => {
+                        let path = entry.path();
+                        if path.is_dir() {
+                            // Recursively search subdirectories
+                            let _ = search_directory_for_gguf(models, &path, throttle);
+                        } else if path.extension().and_then(|s| s.to_str()) == Some("gguf") {
+                            // Parts of a split model are loaded through the first part only
+                            let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                            if parse_split_part(&file_name).map(|(_, part, _)| part > 1).unwrap_or(false) {
+                                continue;
+                            }
+                            // Found a .gguf file
+                            println!("Found model: {}", path.display());
+                            models.push(ModelFile {
+                                full_path: path.to_string_lossy().to_string(),
+                                display_name: path.file_name()
+                                    .unwrap_or_default()
+                                    .to_string_lossy()
+                                    .to_string(),
+                            });
+                        }
+                    }
+                    Err(e) => println!("Warning: Error reading directory entry: {}", e),
+                }
+            }
+            Ok(())
+        }
+        Err(e) => Err(format!("Failed to read directory {}: {}", dir.display(), e))
+    }
+}
+
+/// Provides interactive prompt file selection from the standard prompts directory
+/// 
+/// Lists available prompt files from:
+/// - Linux/MacOS: ~/query_gguf/prompts/
+/// - Windows: \Users\username\query_gguf\prompts\
+/// 
+/// This function:
+/// 1. Lists all available prompt files with numbers
+/// 2. Allows user selection by number
+/// 3. Returns absolute path to selected prompt
+/// 
+/// # Returns
+/// - Ok(String): Absolute path to selected prompt file
+/// - Err(String): Error message if:
+///   - No prompt files found
+///   - Invalid selection
+///   - File access errors
+/// 
+/// # Path Handling
+/// - Uses absolute paths for reliability
+/// - Validates file existence before returning
+/// - Maintains consistent path format across OS
+/// 
+/// # Example Success Path
+/// ```
+/// "/home/username/query_gguf/prompts/system_prompt.txt"
+/// ```
+/// 
+/// # Error Cases
+/// - Empty prompts directory
+/// - Invalid number entered
+/// - Number out of range
+/// - Selected file no longer exists
+fn select_prompt_file() -> Result<String, String> {
+    // Get all prompt files
+    let prompts = find_prompt_files()?;
+    
+    if prompts.is_empty() {
+        return Err("No prompt files found in configured directories".to_string());
+    }
+
+    // Favorites first, then the rest; 'fav <number>' toggles a favorite and redraws
+    let (prompts, index) = loop {
+        let favorites = read_state_list(FAVORITE_PROMPTS_FIELD);
+        let prompts: Vec<String> = order_favorites_first(&prompts, &favorites)
+            .into_iter()
+            .map(|index| prompts[index].clone())
+            .collect();
+
+        println!("\nAvailable Prompts:");
+        // Display prompts with cleaner names
+        for (index, prompt) in prompts.iter().enumerate() {
+            let path = Path::new(prompt);
+            let display_name = path.file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or(prompt.as_str());
+            let star = if favorites.contains(prompt) { "★ " } else { "" };
+            println!("{}. {}{} ({})", index + 1, star, display_name, path.display());
+        }
+
+        print!("\nSelect prompt number (1-{}), or 'fav <number>' to star/unstar: ", prompts.len());
+        io::stdout().flush().map_err(|e| format!("Failed to flush output: {}", e))?;
+
+        let choice = read_user_input()?;
+        let (is_fav_command, number) = match choice.trim().strip_prefix("fav ") {
+            Some(number) => (true, number.trim().to_string()),
+            None => (false, choice.trim().to_string()),
+        };
+        let index = number.parse::<usize>()
+            .map_err(|_| "Please enter a valid number".to_string())?
+            .checked_sub(1)
+            .ok_or("Please enter a number greater than 0".to_string())?;
+
+        if index >= prompts.len() {
+            return Err(format!("Please enter a number between 1 and {}", prompts.len()));
+        }
+
+        if is_fav_command {
+            toggle_favorite(FAVORITE_PROMPTS_FIELD, &prompts[index])?;
+            continue;
+        }
+        break (prompts, index);
+    };
+
+    // Get the selected prompt path
+    let selected_prompt = &prompts[index];
+    
+    // Verify the path and convert to absolute
+    let absolute_path = Path::new(selected_prompt).canonicalize()
+        .map_err(|e| format!("Failed to resolve prompt path: {}", e))?;
+
+    // Verify file still exists
+    if !absolute_path.exists() {
+        return Err("Selected prompt file no longer exists".to_string());
+    }
+
+    // Log the selection
+    println!("Selected prompt: {}", absolute_path.display());
+
+    Ok(absolute_path.to_string_lossy().to_string())
+}
+    
+/// Finds all prompt files in the configured prompts directory
+/// 
+/// This function:
+/// 1. Gets the absolute path to the standard prompts directory
+/// 2. Creates the directory if it doesn't exist
+/// 3. Recursively searches for all files in that directory
+/// 4. Returns paths as absolute paths for reliability
+/// 
+/// Standard Location:
+/// - Linux/MacOS: ~/query_gguf/prompts/
+/// - Windows: \Users\username\query_gguf\prompts\
+/// 
+/// # Returns
+/// - Ok(Vec<String>): List of absolute paths to found prompt files
+/// - Err(String): Error message if directory cannot be accessed or created
+/// 
+/// # Error Cases
+/// - Home directory cannot be determined
+/// - Insufficient permissions to create/access directory
+/// - IO errors while reading directory contents
+/// 
+/// # Example Usage
+/// ```
+/// match find_prompt_files() {
+///     Ok(prompts) => {
+///         for prompt in prompts {
+///             println!("Found prompt: {}", prompt);
+///         }
+///     },
+///     Err(e) => println!("Error finding prompts: {}", e)
+/// }
+/// ```
+fn find_prompt_files() -> Result<Vec<String>, String> {
+    // Get absolute path to prompts directory
+    let prompts_dir = get_prompts_dir()?;
+    
+    println!("Searching for prompts in: {}", prompts_dir.display());
+    
+    let mut prompts = Vec::new();
+    let mut throttle = ScanThrottle::from_config();
+    
+    // Search the directory recursively
+    search_directory_for_prompts(&mut prompts, &prompts_dir, &mut throttle)?;
+    // Prompt paths are canonical, so the root is too
+    let root = prompts_dir.canonicalize().unwrap_or_else(|_| prompts_dir.clone());
+    let mut prompts = complete_scan_from_index(PROMPT_SCAN_INDEX_FIELD, prompts, &[root], &throttle, "prompt");
+
+    if prompts.is_empty() {
+        println!("\nNotice: No prompt files found in directory: {}", prompts_dir.display());
+        println!("You can add prompt files to this directory at any time.");
+    } else {
+        prompts.sort();
+        println!("Found {} prompt files", prompts.len());
+    }
+
+    Ok(prompts)
+}
+
+/// Recursively searches a directory and its subdirectories for prompt files
+/// 
+/// This function:
+/// 1. Creates the directory if it doesn't exist
+/// 2. Recursively searches the directory and all subdirectories
+/// 3. Adds all found files to the prompts vector
+/// 4. Stores paths as absolute paths
+/// 
+/// # Arguments
+/// * `prompts` - Vector to store found prompt file paths
+/// * `dir` - Directory to search
+/// * `throttle` - Paces the search; it stops early when its time budget runs out
+/// 
+/// # Returns
+/// - Ok(()): Search completed successfully
+/// - Err(String): Error message if directory cannot be accessed
+/// 
+/// # Error Cases
+/// - Directory creation fails
+/// - Insufficient permissions
+/// - IO errors while reading directory
+/// 
+fn search_directory_for_prompts(prompts: &mut Vec<String>, dir: &Path, throttle: &mut ScanThrottle) -> Result<(), String> {
+    // Create directory if it doesn't exist
+    if !dir.exists() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create directory {}: {}", dir.display(), e))?;
+        println!("Created directory: {}", dir.display());
+        return Ok(());
+    }
+
+    // Read directory contents
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+
+    // Process each entry
+    for entry_result in entries {
+        if !throttle.spend(SCAN_ENTRY_COST_BYTES) {
+            break;
+        }
+        match entry_result {
+            Ok(entry) => {
+                let path = entry.path();
+                
+                // Skip hidden files and directories (e.g. .git in synced prompts)
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+
+                if path.is_dir() {
+                    // Recursively search subdirectories
+                    if let Err(e) = search_directory_for_prompts(prompts, &path, throttle) {
+                        println!("Warning: Error searching subdirectory {}: {}", path.display(), e);
+                    }
+                } else {
+                    // Convert path to absolute if it isn't already
+                    match path.canonicalize() {
+                        Ok(abs_path) => {
+                            println!("Found prompt file: {}", abs_path.display());
+                            prompts.push(abs_path.to_string_lossy().to_string());
+                        },
+                        Err(e) => println!("Warning: Could not resolve path {}: {}", path.display(), e)
+                    }
+                }
+            },
+            Err(e) => println!("Warning: Error reading directory entry: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Gets the absolute path to the managed directory for prompts synced from `prompt_repo`
+///
+/// Location: ~/query_gguf/prompts/synced/
+///
+/// This directory is owned by `prompts sync`: it is cloned and pulled with git,
+/// so local edits inside it may be overwritten or cause the pull to fail.
+fn get_synced_prompts_dir() -> Result<PathBuf, String> {
+    Ok(get_prompts_dir()?.join("synced"))
+}
+
+/// Clones or updates the prompt library configured as `prompt_repo`
+///
+/// Shells out to `git`:
+/// - first run: `git clone <prompt_repo> ~/query_gguf/prompts/synced`
+/// - later runs: `git -C ~/query_gguf/prompts/synced pull --ff-only`
+///
+/// Synced prompts are then found by the normal prompt search, since the
+/// managed directory is inside the prompts directory.
+///
+/// # Returns
+/// - Ok(()): Prompts cloned or updated
+/// - Err(String): prompt_repo not set, git not available, or git failed
+///
+/// # Example Config Entry
+/// ```toml
+/// prompt_repo = "https://github.com/me/prompts"
+/// ```
+fn sync_prompt_repo() -> Result<(), String> {
+    let repo_url = read_field_from_toml("prompt_repo");
+    if repo_url.is_empty() {
+        return Err("No prompt_repo set in config, e.g.: prompt_repo = \"https://github.com/me/prompts\"".to_string());
+    }
+
+    let synced_dir = get_synced_prompts_dir()?;
+
+    let status = if synced_dir.join(".git").exists() {
+        println!("Updating prompts from {} ...", repo_url);
+        git_command()?
+            .arg("-C")
+            .arg(&synced_dir)
+            .args(["pull", "--ff-only"])
+            .status()
+    } else {
+        if synced_dir.exists() && fs::read_dir(&synced_dir).map(|mut d| d.next().is_some()).unwrap_or(false) {
+            return Err(format!(
+                "{} exists but is not a git checkout. Move it away and run 'prompts sync' again.",
+                synced_dir.display()
+            ));
+        }
+        println!("Cloning prompts from {} ...", repo_url);
+        git_command()?
+            .arg("clone")
+            .arg("--")  // a URL starting with '-' is not read as an option
+            .arg(&repo_url)
+            .arg(&synced_dir)
+            .status()
+    };
+
+    let status = status.map_err(|e| format!("Failed to run git (is it installed?): {}", e))?;
+    if !status.success() {
+        return Err(format!("git exited with status: {}", status.code().unwrap_or(-1)));
+    }
+
+    println!("Prompts synced to: {}", synced_dir.display());
+    Ok(())
+}
+
+/// Formats a byte count for messages, e.g. "4.1 GB"
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+/// Gets the free space on the filesystem holding `path`, using `df`
+///
+/// # Returns
+/// * `Option<u64>` - Free bytes, or None if it could not be determined (e.g. no `df` on Windows)
+fn get_available_disk_bytes(path: &Path) -> Option<u64> {
+    let output = Command::new("df").arg("-Pk").arg(path).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    // Filesystem 1024-blocks Used Available Capacity Mounted-on
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse::<u64>()
+        .ok()
+        .map(|kilobytes| kilobytes * 1024)
+}
+
+/// Refuses to start writing a file of `needed_bytes` into `dir` if it will not fit
+///
+/// If free space cannot be determined, a warning is printed and the check passes.
+fn check_disk_space(dir: &Path, needed_bytes: u64) -> Result<(), String> {
+    match get_available_disk_bytes(dir) {
+        Some(available) if available < needed_bytes => Err(format!(
+            "Not enough disk space in {}: needs about {}, only {} free. Free up space or choose another directory.",
+            dir.display(), format_bytes(needed_bytes), format_bytes(available)
+        )),
+        Some(_) => Ok(()),
+        None => {
+            println!("Warning: Could not check free disk space in {}", dir.display());
+            Ok(())
+        },
+    }
+}
+
+/// Approximate bits per weight of llama.cpp quantization types, for size estimates
+fn quantization_bits_per_weight(tag: &str) -> Option<f64> {
+    let bits = match tag.to_uppercase().as_str() {
+        "F32" => 32.0,
+        "F16" | "BF16" | "FP16" => 16.0,
+        "Q8_0" => 8.5,
+        "Q6_K" => 6.56,
+        "Q5_1" => 6.0,
+        "Q5_K_M" => 5.69,
+        "Q5_K_S" => 5.54,
+        "Q5_0" => 5.5,
+        "Q4_1" => 5.0,
+        "Q4_K_M" => 4.85,
+        "Q4_K_S" => 4.58,
+        "Q4_0" | "IQ4_NL" => 4.5,
+        "Q3_K_L" => 4.27,
+        "IQ4_XS" => 4.25,
+        "Q3_K_M" => 3.91,
+        "IQ3_M" => 3.66,
+        "Q3_K_S" => 3.5,
+        "IQ3_XS" => 3.3,
+        "IQ3_XXS" => 3.06,
+        "Q2_K" => 3.0,
+        "IQ2_M" => 2.7,
+        "IQ2_XS" => 2.31,
+        "IQ2_XXS" => 2.06,
+        "IQ1_S" => 1.56,
+        _ => return None,
+    };
+    Some(bits)
+}
+
+/// Estimates the size of a quantized model from the source file size
+///
+/// Uses the ratio of bits per weight between the source and target types
+/// (an unknown source type is taken as F16). Unknown target types are
+/// estimated as the source size, which errs on the safe side.
+fn estimate_quantized_size(source_bytes: u64, source_tag: &str, target_tag: &str) -> u64 {
+    let source_bits = quantization_bits_per_weight(source_tag).unwrap_or(16.0);
+    match quantization_bits_per_weight(target_tag) {
+        Some(target_bits) => (source_bytes as f64 * target_bits / source_bits) as u64,
+        None => source_bytes,
+    }
+}
+
+/// Reads the file size from the headers of a response to a HEAD request
+///
+/// Hugging Face reports large files in `x-linked-size`; otherwise
+/// `content-length` is used.
+fn parse_remote_file_size(response: &http::Response) -> Option<u64> {
+    let header_value = |name: &str| -> Option<u64> {
+        response.header(name)
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .filter(|size| *size > 0)
+    };
+    header_value("x-linked-size").or_else(|| header_value("content-length"))
+}
+
+/// Gets the first configured model directory (with ~ and relative paths resolved)
+fn get_default_model_directory() -> Result<PathBuf, String> {
+    let raw_path = read_basename_fields_from_toml("gguf_model_directory")
+        .into_iter()
+        .next()
+        .ok_or("No gguf_model_directory set in config")?;
+    let raw_path = to_native_path(&raw_path);
+    let home_dir = get_home_dir()?;
+    Ok(if let Some(rest) = raw_path.strip_prefix('~') {
+        PathBuf::from(format!("{}{}", home_dir, rest))
+    } else if !Path::new(&raw_path).is_absolute() {
+        PathBuf::from(home_dir).join(raw_path)
+    } else {
+        PathBuf::from(raw_path)
+    })
+}
+
+/// Finds a llama.cpp tool (e.g. llama-quantize) next to the configured llama-cli
+fn get_llama_tool_path(tool_name: &str) -> Result<String, String> {
+    let llama_cli_path = get_cpu_llama_cli_path()?;
+    let file_name = if cfg!(windows) { format!("{}.exe", tool_name) } else { tool_name.to_string() };
+    let tool_path = Path::new(&llama_cli_path).with_file_name(file_name);
+    if !tool_path.exists() {
+        return Err(format!("{} not found at {}", tool_name, tool_path.display()));
+    }
+    Ok(tool_path.to_string_lossy().to_string())
+}
+
+/// Recognizes one file of a split GGUF set, e.g. `model-Q8_0-00002-of-00003.gguf`
+///
+/// # Returns
+/// * `Option<(String, u32, u32)>` - (name before the part number, part number, number of parts)
+fn parse_split_part(file_name: &str) -> Option<(String, u32, u32)> {
+    let stem = file_name.strip_suffix(".gguf")?;
+    let (rest, total) = stem.rsplit_once("-of-")?;
+    let (prefix, part) = rest.rsplit_once('-')?;
+    if part.len() != 5 || total.len() != 5 {
+        return None;
+    }
+    let part = part.parse::<u32>().ok()?;
+    let total = total.parse::<u32>().ok()?;
+    if part == 0 || part > total {
+        return None;
+    }
+    Some((prefix.to_string(), part, total))
+}
+
+/// Builds the file name of one part of a split GGUF set
+fn split_part_file_name(prefix: &str, part: u32, total: u32) -> String {
+    format!("{}-{:05}-of-{:05}.gguf", prefix, part, total)
+}
+
+/// Checks whether a host is excluded from proxying by a NO_PROXY list
+///
+/// Entries are comma separated host names or domain suffixes
+/// (`example.com` and `.example.com` both match `www.example.com`); `*` matches all.
+fn no_proxy_matches(host: &str, no_proxy: &str) -> bool {
+    let host = host.to_lowercase();
+    no_proxy.split(',')
+        .map(|entry| entry.trim().trim_start_matches('.').to_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| entry == "*" || host == entry || host.ends_with(&format!(".{}", entry)))
+}
+
+/// Picks the proxy for a URL from the proxy environment variables
+///
+/// Uses `HTTPS_PROXY` or `HTTP_PROXY` by the URL's scheme, then `ALL_PROXY`
+/// (lowercase names first, as curl does), unless the host is in `NO_PROXY`.
+///
+/// # Arguments
+/// * `url` - The URL to be fetched
+/// * `get_var` - Reads an environment variable (a parameter so it can be tested)
+fn select_proxy_for_url(url: &str, get_var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let read = |name: &str| {
+        get_var(&name.to_lowercase())
+            .or_else(|| get_var(&name.to_uppercase()))
+            .filter(|value| !value.trim().is_empty())
+    };
+
+    let (scheme, rest) = url.split_once("://").unwrap_or(("http", url));
+    let host = rest.split(['/', ':', '?']).next().unwrap_or("");
+    if let Some(no_proxy) = read("no_proxy") {
+        if no_proxy_matches(host, &no_proxy) {
+            return None;
+        }
+    }
 
-//     // Validate that paths exist
-//     for path in [&llama_cli_path, &mode.model_path, &mode.prompt_path] {
-//         if !std::path::Path::new(path).exists() {
-//             return Err(format!("Path does not exist: {}", path));
-//         }
-//     }
+    let scheme_variable = if scheme.eq_ignore_ascii_case("https") { "https_proxy" } else { "http_proxy" };
+    read(scheme_variable).or_else(|| read("all_proxy"))
+}
 
-//     // Build command arguments as a Vec for cleaner handling
-//     let mut command_args: Vec<String> = Vec::new();
-    
-//     // Add model path
-//     command_args.push("-m".to_string());
-//     command_args.push(mode.model_path.clone());
+/// Set by the `--offline` command line flag
+static OFFLINE_FLAG: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
 
-//     // Add clean terminal print (no load-data)    
-//     // command_args.push("2>/dev/null".to_string());
-    
-//     // Add prompt file
-//     command_args.push("--file".to_string());
-//     command_args.push(mode.prompt_path.clone());
-    
-//     // Add all parameters
-//     command_args.extend(vec![
-//         "--temp".to_string(), mode.parameters.temperature_value.to_string(),
-//         "--top-k".to_string(), mode.parameters.top_k_sampling.to_string(),
-//         "--top-p".to_string(), mode.parameters.top_p_sampling.to_string(),
-//         "--ctx-size".to_string(), mode.parameters.context_size.to_string(),
-//         "--threads".to_string(), mode.parameters.thread_count.to_string(),
-//     ]);
+/// Reads the `[aliases]` table: word -> query_gguf arguments
+///
+/// ```toml
+/// [aliases]
+/// review = "2 --var 'Project name=query_gguf' -q 'review the latest changes'"
+/// ```
+fn read_aliases() -> Vec<(String, String)> {
+    config_aliases(&read_config())
+}
 
-//     // Add GPU layers if specified
-//     if mode.parameters.gpu_layers > 0 {
-//         command_args.extend(vec![
-//             "--n-gpu-layers".to_string(),
-//             mode.parameters.gpu_layers.to_string(),
-//         ]);
-//     }
+/// The `[aliases]` table of a parsed config
+fn config_aliases(config: &toml_lite::Table) -> Vec<(String, String)> {
+    config.get_table("aliases")
+        .map(|aliases| aliases.entries().iter()
+            .map(|entry| (entry.key.clone(), entry.value.to_text()))
+            .collect())
+        .unwrap_or_default()
+}
 
-//     // Add interactive-first if enabled
-//     if mode.parameters.interactive_first {
-//         command_args.push("--interactive-first".to_string());
-//     }
+/// Replaces an alias in the first argument with the arguments it stands for
+///
+/// Arguments after the alias are kept after the expansion, so
+/// `query_gguf review --print` adds --print to the aliased command.
+/// Aliases are expanded once (an alias naming another alias is not expanded again).
+///
+/// # Arguments
+/// * `args` - Command line arguments, program name first
+/// * `aliases` - (word, arguments) pairs from `[aliases]`
+fn expand_alias(args: Vec<String>, aliases: &[(String, String)]) -> Vec<String> {
+    let Some((_, expansion)) = args.get(1).and_then(|word| aliases.iter().find(|(alias, _)| alias == word)) else {
+        return args;
+    };
+    let mut expanded = vec![args[0].clone()];
+    expanded.extend(split_command_line(expansion));
+    expanded.extend(args.into_iter().skip(2));
+    expanded
+}
 
-//     // Add no-display-prompt flag
-//     command_args.push("--no-display-prompt".to_string());
+/// Takes the global `--data-dir DIR` (or `--data-dir=DIR`) out of the arguments
+///
+/// # Returns
+/// - Some(dir): The directory, empty when the flag has no value
+/// - None: No `--data-dir` given
+fn take_data_dir_flag(args: &mut Vec<String>) -> Option<String> {
+    let index = args.iter().position(|arg| arg == "--data-dir" || arg.starts_with("--data-dir="))?;
+    let flag = args.remove(index);
+    match flag.strip_prefix("--data-dir=") {
+        Some(dir) => Some(dir.to_string()),
+        None if index < args.len() => Some(args.remove(index)),
+        None => Some(String::new()),
+    }
+}
 
-//     // Log the complete command for debugging
-//     println!("\nLaunching LLaMA.cpp with command:");
-//     println!("{} {}", llama_cli_path, command_args.join(" "));
+/// Command line arguments with the global `--offline` and `--data-dir` flags
+/// taken out (and applied) and `[aliases]` expanded
+fn command_line_args() -> Vec<String> {
+    let mut args: Vec<String> = std::env::args().collect();
+    // Before the aliases, which are read from the config in the data directory
+    if let Some(dir) = take_data_dir_flag(&mut args) {
+        let dir = PathBuf::from(normalize_config_path_value(&dir, &get_home_dir().unwrap_or_default()));
+        let dir = match std::env::current_dir() {
+            Ok(cwd) if dir.is_relative() && !dir.as_os_str().is_empty() => cwd.join(dir),
+            _ => dir,
+        };
+        let _ = DATA_DIR_FLAG.set(dir);
+    }
+    let mut args = expand_alias(args, &read_aliases());
+    if args.iter().any(|arg| arg == "--offline") {
+        OFFLINE_FLAG.store(true, std::sync::atomic::Ordering::Relaxed);
+        args.retain(|arg| arg != "--offline");
+    }
+    args
+}
 
-//     // Create and configure command
-//     let process_result = Command::new(&llama_cli_path)
-//         .args(&command_args)
-//         .stdin(std::process::Stdio::inherit())
-//         .stdout(std::process::Stdio::inherit())
-//         .stderr(std::process::Stdio::inherit())
-//         .spawn();
+/// True when network use is disabled by `offline = true` in the config or `--offline`
+fn is_offline() -> bool {
+    OFFLINE_FLAG.load(std::sync::atomic::Ordering::Relaxed) || read_config_bool("offline") == Some(true)
+}
 
-//     // Handle process creation result
-//     let mut process = match process_result {
-//         Ok(process) => process,
-//         Err(e) => return Err(format!(
-//             "Failed to launch LLaMA process: {}. Check if llama-cli path is correct: {}", 
-//             e, llama_cli_path
-//         )),
-//     };
+/// Refuses a network-using feature in offline mode
+fn ensure_online(feature: &str) -> Result<(), String> {
+    if is_offline() {
+        return Err(format!(
+            "{} needs the network, but offline mode is on (offline = true in the config, or --offline).",
+            feature
+        ));
+    }
+    Ok(())
+}
 
-//     // Wait for process completion
-//     match process.wait() {
-//         Ok(status) => {
-//             if status.success() {
-//                 Ok(())
-//             } else {
-//                 Err(format!(
-//                     "LLaMA process exited with status: {}",
-//                     status.code().unwrap_or(-1)
-//                 ))
-//             }
-//         },
-//         Err(e) => Err(format!("Error waiting for LLaMA process: {}", e)),
-//     }
-// }
+/// Creates an HTTP client with the proxy, CA bundle, and TLS settings applied
+///
+/// The proxy comes from the environment (see `select_proxy_for_url`). A
+/// custom CA bundle (e.g. for TLS-intercepting corporate proxies) is taken
+/// from `ca_bundle_path` in the config, and `https_backend` picks the
+/// program that makes https connections: "curl" (the default) or "openssl".
+///
+/// Downloads go through here or `git_command`, so offline mode is enforced
+/// there; remote modes and docker check it themselves.
+fn http_client(url: &str) -> Result<http::Client, String> {
+    ensure_online("Downloading")?;
+    let backend = read_field_from_toml("https_backend");
+    let tls = if backend.is_empty() {
+        http::TlsBackend::default()
+    } else {
+        http::TlsBackend::from_name(&backend)
+            .ok_or_else(|| format!("Unknown https_backend '{}'. Use curl or openssl.", backend))?
+    };
+    let ca_bundle_path = read_field_from_toml("ca_bundle_path");
+    Ok(http::Client {
+        proxy: select_proxy_for_url(url, |name| std::env::var(name).ok()),
+        ca_bundle: Some(ca_bundle_path).filter(|path| !path.is_empty()),
+        tls,
+    })
+}
 
-/// old version with new terminal
-/// TODO add docstring
-fn launch_llama(mode: &ChatModeConfig) -> Result<(), String> {
-    let llama_cli_path = read_field_from_toml("llama_cli_path");
-    if llama_cli_path.is_empty() {
-        return Err("LLaMA CLI path not found in configuration".to_string());
+/// Creates a git command with the `ca_bundle_path` from the config applied
+///
+/// git reads the proxy environment variables (`https_proxy`, `HTTPS_PROXY`,
+/// `no_proxy`...) itself.
+fn git_command() -> Result<Command, String> {
+    ensure_online("Prompt sync")?;
+    let mut command = Command::new("git");
+    let ca_bundle_path = read_field_from_toml("ca_bundle_path");
+    if !ca_bundle_path.is_empty() {
+        command.arg("-c").arg(format!("http.sslCAInfo={}", ca_bundle_path));
     }
+    Ok(command)
+}
 
-    // Construct the llama-cli command string
-    let mut llama_command = format!("\"{}\" -m \"{}\"", llama_cli_path, mode.model_path);
-    
-    // Add prompt file (now always present)
-    llama_command.push_str(&format!(" --file \"{}\"", mode.prompt_path));
+/// Gets the size of a remote file from its headers, None if the server does not say
+fn fetch_remote_file_size(url: &str) -> Result<Option<u64>, String> {
+    let response = http_client(url)?.send(&http::Request::new("HEAD", url))?;
+    if response.status >= 400 {
+        return Err(format!("Could not get {} (HTTP {})", url, response.status));
+    }
+    Ok(parse_remote_file_size(&response))
+}
+
+/// Writes a download into its `.part` file, showing progress on stderr
+struct PartFileWriter {
+    part_path: PathBuf,
+    file: Option<File>,       // None when the response is not the file (e.g. an error page)
+    written: u64,             // bytes in the .part file
+    total: Option<u64>,       // size of the whole file, if the server says
+    last_shown: std::time::Instant,
+}
+
+impl PartFileWriter {
+    fn new(part_path: &Path) -> PartFileWriter {
+        PartFileWriter {
+            part_path: part_path.to_path_buf(),
+            file: None,
+            written: fs::metadata(part_path).map(|meta| meta.len()).unwrap_or(0),
+            total: None,
+            last_shown: std::time::Instant::now(),
+        }
+    }
+
+    fn show_progress(&self) {
+        match self.total {
+            Some(total) if total > 0 => eprint!(
+                "\r{} of {} ({}%)   ", format_bytes(self.written), format_bytes(total), self.written * 100 / total
+            ),
+            _ => eprint!("\r{}   ", format_bytes(self.written)),
+        }
+        let _ = io::stderr().flush();
+    }
+}
+
+impl http::ResponseSink for PartFileWriter {
+    /// Appends to the `.part` file for a 206 (the rest of the file), starts it over for a 200
+    fn begin(&mut self, response: &http::Response) -> io::Result<()> {
+        // Content-Range: bytes 100-199/200, or bytes */200 for a 416
+        let range_total = response.header("content-range")
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, total)| total.trim().parse::<u64>().ok());
+        match response.status {
+            206 => {
+                self.file = Some(fs::OpenOptions::new().append(true).open(&self.part_path)?);
+                self.total = range_total;
+            },
+            200 => {
+                self.file = Some(File::create(&self.part_path)?);
+                self.written = 0;
+                self.total = response.header("content-length").and_then(|length| length.trim().parse().ok());
+            },
+            _ => self.total = range_total,
+        }
+        Ok(())
+    }
+}
+
+impl Write for PartFileWriter {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let Some(file) = &mut self.file else {
+            return Ok(buffer.len());
+        };
+        let count = file.write(buffer)?;
+        self.written += count as u64;
+        if self.last_shown.elapsed() >= std::time::Duration::from_millis(500) {
+            self.show_progress();
+            self.last_shown = std::time::Instant::now();
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Downloads one file as `<name>.part`, resuming an earlier attempt, then renames it
+fn download_file_resumable(url: &str, target_path: &Path) -> Result<(), String> {
+    let part_path = PathBuf::from(format!("{}.part", target_path.display()));
+    let mut writer = PartFileWriter::new(&part_path);
+    let mut request = http::Request::get(url);
+    if writer.written > 0 {
+        request = request.header("Range", &format!("bytes={}-", writer.written));
+    }
+    let result = http_client(url)?.send_to(&request, &mut writer);
+    if writer.file.is_some() {
+        writer.show_progress();
+        eprintln!();
+    }
+    let response = result.map_err(|e| format!("Download failed: {}. Run the same command again to resume.", e))?;
+    writer.flush().map_err(|e| format!("Failed to write {}: {}", part_path.display(), e))?;
+    match response.status {
+        200 | 206 => {},
+        // Nothing left to download
+        416 if writer.total == Some(writer.written) => {},
+        status => return Err(format!(
+            "Download failed (HTTP {}). Run the same command again to resume, or delete {} to start over.",
+            status, part_path.display()
+        )),
+    }
+    if let Some(total) = writer.total.filter(|total| *total != writer.written) {
+        return Err(format!(
+            "Download incomplete ({} of {}). Run the same command again to resume.",
+            format_bytes(writer.written), format_bytes(total)
+        ));
+    }
+    fs::rename(&part_path, target_path)
+        .map_err(|e| format!("Failed to move download into place: {}", e))
+}
+
+/// Downloads a GGUF from Hugging Face: `query_gguf pull <owner/repo> <file.gguf> [--dir <path>] [--merge]`
+///
+/// Downloads into the first model directory (or --dir), as
+/// `.part` files that are renamed when complete. Interrupted downloads are
+/// resumed. The total size is checked against free disk space first.
+///
+/// Naming any part of a split model (`-00001-of-00003.gguf`) downloads all
+/// parts. llama.cpp loads the set from the first part, so the parts are
+/// kept as they are unless `--merge` is given, which joins them with
+/// llama-gguf-split into one file and removes the parts.
+fn handle_pull_command(args: &[String]) -> Result<(), String> {
+    ensure_online("pull")?;
+    let usage = "Usage: query_gguf pull <owner/repo> <file.gguf> [--dir <path>] [--merge]";
+    let repo = args.first().ok_or(usage)?;
+    let file_name = args.get(1).ok_or(usage)?;
+    let merge = args.iter().any(|arg| arg == "--merge");
+    let target_dir = match args.iter().position(|arg| arg == "--dir") {
+        Some(index) => PathBuf::from(args.get(index + 1).ok_or(usage)?),
+        None => get_default_model_directory()?,
+    };
+    fs::create_dir_all(&target_dir)
+        .map_err(|e| format!("Failed to create {}: {}", target_dir.display(), e))?;
+
+    let split = parse_split_part(file_name);
+    let file_names: Vec<String> = match &split {
+        Some((prefix, _, total)) => (1..=*total).map(|part| split_part_file_name(prefix, part, *total)).collect(),
+        None => vec![file_name.clone()],
+    };
+
+    // Sizes from the server, minus what earlier interrupted downloads already have
+    let mut downloads = Vec::new();
+    let mut needed_bytes = 0;
+    let mut sizes_known = true;
+    for name in &file_names {
+        let target_path = target_dir.join(name);
+        if target_path.exists() {
+            println!("Already downloaded: {}", target_path.display());
+            continue;
+        }
+        let url = format!("https://huggingface.co/{}/resolve/main/{}", repo, name);
+        match fetch_remote_file_size(&url)? {
+            Some(size) => {
+                let already = fs::metadata(format!("{}.part", target_path.display())).map(|meta| meta.len()).unwrap_or(0);
+                needed_bytes += size.saturating_sub(already);
+            },
+            None => sizes_known = false,
+        }
+        downloads.push((url, target_path));
+    }
+    if sizes_known {
+        if !downloads.is_empty() {
+            println!("Downloading {} file(s), {} to go", downloads.len(), format_bytes(needed_bytes));
+        }
+        check_disk_space(&target_dir, needed_bytes)?;
+    } else {
+        println!("Warning: Could not get the file size, skipping the disk space check");
+    }
+
+    for (url, target_path) in &downloads {
+        download_file_resumable(url, target_path)?;
+        println!("Saved: {}", target_path.display());
+    }
+
+    if let (Some((prefix, _, _)), true) = (&split, merge) {
+        let part_paths: Vec<PathBuf> = file_names.iter().map(|name| target_dir.join(name)).collect();
+        let merged_path = target_dir.join(format!("{}.gguf", prefix));
+        let total_size: u64 = part_paths.iter().filter_map(|path| fs::metadata(path).ok()).map(|meta| meta.len()).sum();
+        check_disk_space(&target_dir, total_size)?;
+
+        let split_tool = get_llama_tool_path("llama-gguf-split")?;
+        println!("Merging {} parts into {}", part_paths.len(), merged_path.display());
+        let status = Command::new(&split_tool)
+            .arg("--merge")
+            .arg(&part_paths[0])
+            .arg(&merged_path)
+            .status()
+            .map_err(|e| format!("Failed to run {}: {}", split_tool, e))?;
+        if !status.success() {
+            let _ = fs::remove_file(&merged_path);
+            return Err(format!(
+                "llama-gguf-split exited with status {}. The parts were kept and can be used as they are.",
+                status.code().unwrap_or(-1)
+            ));
+        }
+        for part_path in &part_paths {
+            let _ = fs::remove_file(part_path);
+        }
+        println!("Saved: {}", merged_path.display());
+    } else if split.is_some() {
+        println!("Split model ready. It is listed in the model picker by its first part.");
+    }
+    Ok(())
+}
+
+/// Largest ctx_size `add` sets from a model's context length (long contexts need a lot of memory)
+const ADD_MAX_CONTEXT_SIZE: u64 = 8192;
+
+/// Quantizations `add` picks when none is given and it cannot ask, best first
+const ADD_PREFERRED_QUANTIZATIONS: &[&str] = &["Q4_K_M", "Q4_K_S", "Q5_K_M", "Q4_0", "Q8_0"];
+
+/// What the Hugging Face API says about a GGUF repo
+#[derive(Debug, Default, PartialEq)]
+struct HfModelInfo {
+    gguf_files: Vec<(String, Option<u64>)>, // GGUF file names (first part of split models) and sizes
+    architecture: String,                   // e.g. "llama", empty if not given
+    context_length: Option<u64>,            // trained context length
+    has_chat_template: bool,                // the GGUF has a chat template
+}
+
+/// Gets the repo (owner/name) and file, if any, from a Hugging Face URL or `owner/name`
+///
+/// # Examples
+/// ```text
+/// https://huggingface.co/owner/repo                          -> ("owner/repo", None)
+/// https://huggingface.co/owner/repo/blob/main/model.Q4_K_M.gguf -> ("owner/repo", Some("model.Q4_K_M.gguf"))
+/// ```
+fn parse_hf_model_url(url: &str) -> Option<(String, Option<String>)> {
+    let path = url.trim()
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("www.");
+    let path = path.strip_prefix("huggingface.co/")
+        .or_else(|| path.strip_prefix("hf.co/"))
+        .unwrap_or(path);
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    let parts: Vec<&str> = path.split('/').collect();
+    let (owner, name) = match parts.as_slice() {
+        [owner, name, ..] if !owner.is_empty() && !name.is_empty() && !owner.contains('.') => (owner, name),
+        _ => return None,
+    };
+    let file = parts.last()
+        .filter(|part| part.ends_with(".gguf"))
+        .map(|part| part.to_string());
+    Some((format!("{}/{}", owner, name), file))
+}
+
+/// Reads the GGUF files and metadata from a `api/models/<repo>?blobs=true` response
+///
+/// An unreadable response gives an empty info (no GGUF files).
+fn parse_hf_model_info(json: &str) -> HfModelInfo {
+    let Ok(response) = json::parse(json) else { return HfModelInfo::default() };
+    let mut info = HfModelInfo {
+        architecture: response.find("architecture").and_then(json::Value::as_str).unwrap_or_default().to_string(),
+        context_length: response.find("context_length").and_then(json::Value::as_u64),
+        has_chat_template: response.find("chat_template").and_then(json::Value::as_str).is_some_and(|template| !template.is_empty()),
+        ..HfModelInfo::default()
+    };
+
+    for sibling in response.get("siblings").and_then(json::Value::as_array).unwrap_or_default() {
+        let Some(name) = sibling.get("rfilename").and_then(json::Value::as_str) else { continue };
+        let is_later_part = parse_split_part(name).map(|(_, part, _)| part > 1).unwrap_or(false);
+        if name.ends_with(".gguf") && !is_later_part {
+            info.gguf_files.push((name.to_string(), sibling.get("size").and_then(json::Value::as_u64)));
+        }
+    }
+    info
+}
+
+/// Reads sampling settings recommended in a model card, e.g. "temperature=0.6, top_p=0.95"
+///
+/// # Returns
+/// Mode parameter parts (e.g. "temp=0.6"); values outside sane ranges are ignored
+fn infer_card_parameters(card: &str) -> Vec<String> {
+    let text = card.to_lowercase();
+    let find_value = |keys: &[&str], valid: &dyn Fn(f32) -> bool| -> Option<String> {
+        for key in keys {
+            for (index, _) in text.match_indices(key) {
+                let rest = text[index + key.len()..]
+                    .trim_start_matches(|c: char| " \t:=`*\"'|(".contains(c));
+                let rest = rest.strip_prefix("of ").unwrap_or(rest);
+                let number: String = rest.chars().take_while(|c| c.is_ascii_digit() || *c == '.').collect();
+                let number = number.trim_end_matches('.');
+                if number.parse::<f32>().is_ok_and(valid) {
+                    return Some(number.to_string());
+                }
+            }
+        }
+        None
+    };
+
+    let mut parts = Vec::new();
+    if let Some(value) = find_value(&["temperature"], &|value| value <= 2.0) {
+        parts.push(format!("temp={}", value));
+    }
+    if let Some(value) = find_value(&["top_k", "top-k", "topk"], &|value| value >= 1.0 && value.fract() == 0.0) {
+        parts.push(format!("top_k={}", value));
+    }
+    if let Some(value) = find_value(&["top_p", "top-p", "topp"], &|value| value > 0.0 && value <= 1.0) {
+        parts.push(format!("top_p={}", value));
+    }
+    parts
+}
+
+/// Picks the GGUF file to download for `add`
+///
+/// `--quant` (or a file in the URL) decides; otherwise the files are listed
+/// to choose from, or without a terminal the first of ADD_PREFERRED_QUANTIZATIONS.
+fn choose_hf_gguf_file(info: &HfModelInfo, wanted: Option<&str>) -> Result<String, String> {
+    use std::io::IsTerminal;
+    let quantization_of = |name: &str| split_model_quantization(name).1.to_uppercase();
+    let available = || info.gguf_files.iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if let Some(wanted) = wanted {
+        return info.gguf_files.iter()
+            .map(|(name, _)| name)
+            .find(|name| *name == wanted || quantization_of(name) == wanted.to_uppercase())
+            .cloned()
+            .ok_or_else(|| format!("No GGUF file for '{}' in the repo. Available: {}", wanted, available()));
+    }
+    let preferred = ADD_PREFERRED_QUANTIZATIONS.iter()
+        .find_map(|quantization| info.gguf_files.iter().position(|(name, _)| quantization_of(name) == *quantization));
+
+    if !io::stdin().is_terminal() {
+        return preferred
+            .map(|index| info.gguf_files[index].0.clone())
+            .ok_or_else(|| format!("Choose a file with --quant. Available: {}", available()));
+    }
+
+    println!("\nGGUF files:");
+    for (number, (name, size)) in info.gguf_files.iter().enumerate() {
+        let size = size.map(|size| format!(" ({})", format_bytes(size))).unwrap_or_default();
+        let marker = if Some(number) == preferred { "  <- default" } else { "" };
+        println!("  {}. {}{}{}", number + 1, name, size, marker);
+    }
+    print!("Enter selection{}: ", preferred.map(|index| format!(" [{}]", index + 1)).unwrap_or_default());
+    io::stdout().flush().map_err(|e| e.to_string())?;
+    let choice = read_user_input()?;
+    let index = match (choice.trim(), preferred) {
+        ("", Some(index)) => index,
+        (number, _) => number.parse::<usize>().ok()
+            .and_then(|number| number.checked_sub(1))
+            .filter(|index| *index < info.gguf_files.len())
+            .ok_or_else(|| "Invalid selection".to_string())?,
+    };
+    Ok(info.gguf_files[index].0.clone())
+}
+
+/// Creates a mode from a Hugging Face repo: `query_gguf add <url> [--quant TYPE] [--dir <path>]`
+///
+/// 1. Reads the repo's GGUF files and metadata from the Hugging Face API,
+///    and its model card (README.md)
+/// 2. Picks the quantization (--quant, a file in the URL, or asks)
+/// 3. Downloads it like `pull` (disk space check, resume, split models)
+/// 4. Saves a mode: ctx_size from the model's context length (at most
+///    ADD_MAX_CONTEXT_SIZE), conversation=true when the GGUF has a chat
+///    template, and temp/top_k/top_p when the model card recommends them
+fn handle_add_command(args: &[String]) -> Result<(), String> {
+    ensure_online("add")?;
+    let usage = "Usage: query_gguf add <huggingface url or owner/repo> [--quant TYPE] [--dir <path>]";
+    let url = args.first().filter(|arg| !arg.starts_with("--")).ok_or(usage)?;
+    let (repo, url_file) = parse_hf_model_url(url)
+        .ok_or_else(|| format!("Not a Hugging Face model URL: {}. {}", url, usage))?;
+    let option_value = |name: &str| -> Result<Option<String>, String> {
+        match args.iter().position(|arg| arg == name) {
+            Some(index) => args.get(index + 1).cloned().map(Some).ok_or_else(|| usage.to_string()),
+            None => Ok(None),
+        }
+    };
+    let wanted = option_value("--quant")?.or(url_file);
+    let target_dir = match option_value("--dir")? {
+        Some(dir) => PathBuf::from(dir),
+        None => get_default_model_directory()?,
+    };
 
-    // Add all parameters
-    llama_command.push_str(&format!(" --temp {}", mode.parameters.temperature_value));
-    llama_command.push_str(&format!(" --top-k {}", mode.parameters.top_k_sampling));
-    llama_command.push_str(&format!(" --top-p {}", mode.parameters.top_p_sampling));
-    llama_command.push_str(&format!(" --ctx-size {}", mode.parameters.context_size));
-    llama_command.push_str(&format!(" --threads {}", mode.parameters.thread_count));
+    println!("Reading https://huggingface.co/{}", repo);
+    let api_url = format!("https://huggingface.co/api/models/{}?blobs=true", repo);
+    let response = http_client(&api_url)?.send(&http::Request::get(&api_url))?;
+    if response.status != 200 {
+        return Err(format!("Could not read the repo {} (HTTP {}). Is the name right, and is it public?",
+            repo, response.status));
+    }
+    let info = parse_hf_model_info(&response.text());
+    if info.gguf_files.is_empty() {
+        return Err(format!("{} has no GGUF files. Look for a -GGUF version of the model.", repo));
+    }
 
-    if mode.parameters.gpu_layers > 0 {
-        llama_command.push_str(&format!(" --n-gpu-layers {}", mode.parameters.gpu_layers));
+    let card_url = format!("https://huggingface.co/{}/raw/main/README.md", repo);
+    let card = http_client(&card_url)?
+        .send(&http::Request::get(&card_url))
+        .ok()
+        .filter(|response| response.status == 200)
+        .map(|response| response.text());
+    if card.is_none() {
+        println!("Warning: No model card found, using default sampling settings");
     }
 
-    if mode.parameters.interactive_first {
-        llama_command.push_str(" --interactive-first");
+    let file_name = choose_hf_gguf_file(&info, wanted.as_deref())?;
+    let mut pull_args = vec![repo.clone(), file_name.clone(), "--dir".to_string(), target_dir.to_string_lossy().to_string()];
+    if parse_split_part(&file_name).is_some() {
+        println!("Split model: downloading all parts (loaded through the first one)");
+        pull_args.truncate(2);
+        pull_args.extend(["--dir".to_string(), target_dir.to_string_lossy().to_string()]);
     }
+    handle_pull_command(&pull_args)?;
 
-    llama_command.push_str(" --no-display-prompt");
+    let mut parts: Vec<String> = Vec::new();
+    if let Some(context_length) = info.context_length {
+        parts.push(format!("ctx_size={}", context_length.min(ADD_MAX_CONTEXT_SIZE)));
+    }
+    if info.has_chat_template {
+        parts.push("conversation=true".to_string());
+    }
+    parts.extend(card.as_deref().map(infer_card_parameters).unwrap_or_default());
+    let part_refs: Vec<&str> = parts.iter().map(String::as_str).collect();
+    let parameters = parse_parameters_from_parts(&part_refs);
+
+    let (base_name, quantization) = split_model_quantization(&file_name);
+    let name = if quantization.is_empty() { base_name } else { format!("{} {}", base_name, quantization) };
+    let existing_modes = read_saved_modes().unwrap_or_default();
+    let mode = ChatModeConfig {
+        id: make_unique_mode_id(&slugify_mode_name(&name), &existing_modes),
+        backend: String::new(),
+        remote: String::new(),
+        endpoint: String::new(),
+        extends: String::new(),
+        kind: String::new(),
+        name,
+        description: format!("from huggingface.co/{}", repo),
+        model_path: target_dir.join(&file_name).to_string_lossy().to_string(),
+        prompt_path: get_prompts_dir()?.join("blankprompt.txt").to_string_lossy().to_string(),
+        parameters,
+    };
 
-    println!("\nPreparing to launch LLaMA.cpp gguf llama-cli in a new terminal...");
-    println!("Command: {}", llama_command);
+    println!("\nNew mode: {} [{}]", mode.name, mode.id);
+    if !info.architecture.is_empty() {
+        println!("Architecture: {}", info.architecture);
+    }
+    if !parts.is_empty() {
+        println!("From the model's metadata and card: {}", parts.join(", "));
+    }
+    display_parameters(&mode.parameters);
+    save_mode_to_config(&mode)?;
+    println!("\nMode saved. Launch it with: query_gguf {}", mode.id);
+    Ok(())
+}
 
-    // Launch in new terminal based on OS
-    let launch_result = if cfg!(target_os = "windows") {
-        Command::new("cmd")
-            .args(&["/C", "start", "cmd", "/K", &llama_command])
-            .status()
-            .map_err(|e| format!("Failed to launch Windows terminal: {}", e))
-    } else if cfg!(target_os = "linux") {
-        // Try different terminal emulators
-        let terminals = ["xterm", "gnome-terminal", "konsole", "xfce4-terminal"];
-        let mut last_error = String::from("No terminal emulator found");
+/// Record of archived models, in the application directory: one tab separated line
+/// per moved model (time archived, original path, archive path, modes disabled)
+const ARCHIVED_MODELS_FILE: &str = "archived_models.tsv";
 
-        for terminal in terminals.iter() {
-            let result = if *terminal == "gnome-terminal" {
-                Command::new(terminal)
-                    .args(&["--", "bash", "-c", &format!("{};read -p 'Press Enter to close...'", llama_command)])
-                    .status()
-            } else {
-                Command::new(terminal)
-                    .args(&["-e", &format!("bash -c '{};read -p \"Press Enter to close...\"'", llama_command)])
-                    .status()
-            };
+/// Models not used for this long are archived when --older-than is not given
+const ARCHIVE_DEFAULT_DAYS: u64 = 90;
 
-            match result {
-                Ok(_) => return Ok(()),
-                Err(e) => last_error = format!("Failed to launch {}: {}", terminal, e),
-            }
-        }
-        
-        Err(last_error)
-    } else if cfg!(target_os = "macos") {
-        Command::new("osascript")
-            .args(&["-e", &format!(
-                "tell application \"Terminal\" to do script \"{}\"",
-                llama_command
-            )])
-            .status()
-            .map_err(|e| format!("Failed to launch macOS terminal: {}", e))
+/// Parses an age like "90d", "12w", or "90" (days) into days
+fn parse_age_days(age: &str) -> Option<u64> {
+    let age = age.trim();
+    let (number, days_per_unit) = if let Some(number) = age.strip_suffix('d') {
+        (number, 1)
+    } else if let Some(number) = age.strip_suffix('w') {
+        (number, 7)
     } else {
-        Err(String::from("Unsupported operating system"))
+        (age, 1)
     };
+    number.parse::<u64>().ok().map(|count| count * days_per_unit)
+}
 
-    match launch_result {
-        Ok(_) => {
-            println!("LLaMA launched in new terminal window");
-            Ok(())
-        },
-        Err(e) => Err(format!("Failed to launch LLaMA: {}", e))
+/// Latest session time (Unix seconds) of each model in the session stats file
+///
+/// # Returns
+/// * `Vec<(String, u64)>` - (model path as recorded, time of its latest session)
+fn latest_use_by_model(stats: &str) -> Vec<(String, u64)> {
+    let mut latest: Vec<(String, u64)> = Vec::new();
+    for line in stats.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let (Some(when), Some(model)) = (fields.first().and_then(|when| when.parse::<u64>().ok()), fields.get(3)) else {
+            continue;
+        };
+        match latest.iter_mut().find(|(path, _)| path == model) {
+            Some(entry) => entry.1 = entry.1.max(when),
+            None => latest.push((model.to_string(), when)),
+        }
     }
+    latest
 }
 
-fn handle_mode_selection(choice: &str) -> Result<String, String> {
-    match choice.trim() {
-        "dir" | "directory" => {
-            println!("\nDirectory Mode Setup:");
-            
-            // Get directory to scan
-            print!("Enter directory path to scan: ");
-            io::stdout().flush().map_err(|e| e.to_string())?;
-            let dir_path = read_user_input()?.trim().to_string();
-            
-            // Get mode number to use
-            print!("Enter mode number to use: ");
-            io::stdout().flush().map_err(|e| e.to_string())?;
-            let mode_num = read_user_input()?.trim().to_string();
-            
-            // Get the selected mode
-            let saved_modes = read_saved_modes()?;
-            let mode_index = mode_num.parse::<usize>()
-                .map_err(|_| "Invalid mode number".to_string())?
-                .checked_sub(1)
-                .ok_or("Invalid mode number".to_string())?;
-            
-            let mut selected_mode = saved_modes.get(mode_index)
-                .ok_or("Invalid mode selection")?
-                .clone();  // Now clones the entire ChatModeConfig
+/// A path with symlinks and `..` resolved, for comparing paths written differently
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
 
-            // Create combined prompt
-            let combined_prompt_path = create_combined_prompt(
-                &selected_mode.prompt_path,
-                &dir_path
-            )?;
+/// Moves a file, copying it when the target is on another filesystem
+///
+/// A copy goes to `<target>.partial` first, so an interrupted move
+/// never leaves a truncated model under the real name.
+fn move_file(from: &Path, to: &Path) -> Result<(), String> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let partial = to.with_file_name(format!("{}.partial", to.file_name().unwrap_or_default().to_string_lossy()));
+    let copied = fs::copy(from, &partial).and_then(|bytes| {
+        let expected = fs::metadata(from)?.len();
+        if bytes != expected {
+            return Err(io::Error::other(format!("copied {} of {} bytes", bytes, expected)));
+        }
+        fs::rename(&partial, to)
+    });
+    if let Err(e) = copied {
+        let _ = fs::remove_file(&partial);
+        return Err(format!("Failed to move {} to {}: {}", from.display(), to.display(), e));
+    }
+    fs::remove_file(from).map_err(|e| format!("Copied {} to {}, but could not remove the original: {}", from.display(), to.display(), e))
+}
 
-            // Update mode to use combined prompt
-            selected_mode.prompt_path = combined_prompt_path;
+/// A model `models archive` moves: its files (all parts of a split model) and the modes using it
+struct ArchiveCandidate {
+    path: PathBuf,
+    files: Vec<PathBuf>,
+    bytes: u64,
+    last_session: u64, // Unix seconds, 0 if no session was recorded
+    mode_indexes: Vec<usize>,
+}
 
-            // Launch with combined prompt
-            launch_llama(&selected_mode)?;
+/// Handles `query_gguf models archive`: moves models not used for a while to another disk
+///
+/// ```text
+/// models archive [--older-than 90d] [--to /mnt/archive] [--dry-run] [--yes]
+/// ```
+/// A model counts as used when a session (from the session stats) ran it or
+/// its file was written (downloaded, quantized) within the age; the default
+/// is 90 days. `--to` defaults to `model_archive_directory` in the config.
+/// The models found in the model directories are moved there (all parts of
+/// split models), each move is recorded in ~/query_gguf/archived_models.tsv,
+/// and the modes using a moved model are pointed at its new location and
+/// disabled with disabled=<note>, so they are kept but grayed out until the
+/// note is removed (or launched with --force, from the archive).
+fn handle_models_command(args: &[String]) -> Result<(), String> {
+    use std::io::IsTerminal;
+    let usage = "Use: models archive [--older-than 90d] [--to <dir>] [--dry-run] [--yes]";
+    if args.first().map(|arg| arg.as_str()) != Some("archive") {
+        return Err(usage.to_string());
+    }
+    let mut max_age_days = ARCHIVE_DEFAULT_DAYS;
+    let mut archive_dir = read_field_from_toml("model_archive_directory");
+    let mut dry_run = false;
+    let mut assume_yes = false;
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--older-than" => max_age_days = rest.next()
+                .and_then(|age| parse_age_days(age))
+                .ok_or_else(|| format!("--older-than needs an age like 90d or 12w. {}", usage))?,
+            "--to" => archive_dir = rest.next().ok_or_else(|| usage.to_string())?.to_string(),
+            "--dry-run" => dry_run = true,
+            "--yes" => assume_yes = true,
+            other => return Err(format!("Unknown option: {}. {}", other, usage)),
+        }
+    }
+    if archive_dir.trim().is_empty() {
+        return Err(format!("No archive directory: give --to <dir> or set model_archive_directory in the config. {}", usage));
+    }
+    // Mode entries get the new model paths, so a relative --to is made absolute
+    let archive_dir = std::path::absolute(normalize_config_path_value(&archive_dir, &get_home_dir()?))
+        .map_err(|e| format!("Invalid archive directory {}: {}", archive_dir, e))?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let cutoff = now.saturating_sub(max_age_days * 86_400);
+    let modes = read_saved_modes().unwrap_or_default();
+    let stats = fs::read_to_string(get_session_stats_path()?).unwrap_or_default();
+    let latest_use: Vec<(PathBuf, u64)> = latest_use_by_model(&stats).into_iter()
+        .map(|(path, when)| (canonical_path(Path::new(&path)), when))
+        .collect();
+    let archive_canonical = canonical_path(&archive_dir);
+
+    let mut candidates = Vec::new();
+    for model in find_gguf_models()? {
+        let path = PathBuf::from(&model.full_path);
+        let canonical = canonical_path(&path);
+        if canonical.starts_with(&archive_canonical) {
+            continue;
+        }
+        // Split models move with all their parts
+        let files: Vec<PathBuf> = match parse_split_part(&model.display_name) {
+            Some((prefix, _, total)) => (1..=total)
+                .map(|part| path.with_file_name(split_part_file_name(&prefix, part, total)))
+                .filter(|part| part.is_file())
+                .collect(),
+            None => vec![path.clone()],
+        };
+        let written = files.iter()
+            .filter_map(|file| fs::metadata(file).and_then(|metadata| metadata.modified()).ok())
+            .filter_map(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs())
+            .max()
+            .unwrap_or(0);
+        let last_session = latest_use.iter()
+            .filter(|(used_path, _)| *used_path == canonical)
+            .map(|(_, when)| *when)
+            .max()
+            .unwrap_or(0);
+        let last_used = written.max(last_session);
+        if last_used >= cutoff {
+            continue;
+        }
+        let mode_indexes = modes.iter().enumerate()
+            .filter(|(_, mode)| mode.remote.is_empty() && mode.endpoint.is_empty())
+            .filter(|(_, mode)| canonical_path(Path::new(&mode.model_path)) == canonical)
+            .map(|(index, _)| index)
+            .collect();
+        let bytes = files.iter().filter_map(|file| fs::metadata(file).ok()).map(|metadata| metadata.len()).sum();
+        candidates.push(ArchiveCandidate { path, files, bytes, last_session, mode_indexes });
+    }
 
-            Ok(format!("directory_mode::{}", selected_mode.name))
-        },
-        "make" | "manual" => handle_manual_mode_selection(),
-        number => {
-            let mode_num = number.parse::<usize>()
-                .map_err(|_| "Invalid mode number".to_string())?;
+    if candidates.is_empty() {
+        println!("No models unused for {} days.", max_age_days);
+        return Ok(());
+    }
+    let total_bytes: u64 = candidates.iter().map(|candidate| candidate.bytes).sum();
+    println!("\nModels unused for {} days (to {}):", max_age_days, archive_dir.display());
+    for candidate in &candidates {
+        let last_used = match candidate.last_session {
+            0 => "no recorded sessions".to_string(),
+            when => format!("last used {}", &format_unix_time(when)[..10]),
+        };
+        let mode_ids: Vec<&str> = candidate.mode_indexes.iter().map(|&index| modes[index].id.as_str()).collect();
+        let used_by = if mode_ids.is_empty() { String::new() } else { format!("; disables mode {}", mode_ids.join(", ")) };
+        println!("  {} ({}, {}{})", candidate.path.display(), format_bytes(candidate.bytes), last_used, used_by);
+    }
+    println!("Total: {}", format_bytes(total_bytes));
+    if dry_run {
+        return Ok(());
+    }
+    if !assume_yes {
+        if !io::stdin().is_terminal() {
+            return Err("Not archiving without confirmation: stdin is not a terminal (use --yes)".to_string());
+        }
+        if !prompt_yes_no(&format!("Move {} models to {}?", candidates.len(), archive_dir.display()))? {
+            return Ok(());
+        }
+    }
 
-            let saved_modes = read_saved_modes()?;
-            
-            // Directly use the mode number (1-based index)
-            let mode_index = mode_num - 1;
-            
-            if let Some(mode) = saved_modes.get(mode_index) {
-                println!("\nSelected saved mode: {}", mode.name);
-                println!("Model: {}", mode.model_path);
-                println!("Prompt: {}", mode.prompt_path); // Now always present
-                println!("Parameters:");
-                display_parameters(&mode.parameters);
-                
-                println!("\nLaunching LLaMA...");
-                launch_llama(mode)?;
-                
-                Ok(format!("saved_mode::{}", mode.name))
-            } else {
-                Err("Invalid mode selection".to_string())
-            }
-        },
+    fs::create_dir_all(&archive_dir)
+        .map_err(|e| format!("Failed to create {}: {}", archive_dir.display(), e))?;
+    check_disk_space(&archive_dir, total_bytes)?;
+
+    let config_path = get_config_path()?;
+    let original_config = fs::read_to_string(&config_path)
+        .map_err(|e| format!("Failed to read config at {}: {}", config_path.display(), e))?;
+    let mut config = original_config.clone();
+    let mut record = String::new();
+    let mut moved_bytes = 0;
+    let mut disabled_modes = Vec::new();
+    let note = format!("model archived to {} on {}", archive_dir.display(), &format_unix_time(now)[..10]);
+    for candidate in &candidates {
+        let targets: Vec<PathBuf> = candidate.files.iter()
+            .map(|file| archive_dir.join(file.file_name().unwrap_or_default()))
+            .collect();
+        if let Some(existing) = targets.iter().find(|target| target.exists()) {
+            println!("Warning: Skipping {}: {} already exists", candidate.path.display(), existing.display());
+            continue;
+        }
+        println!("Moving {} ({})...", candidate.path.display(), format_bytes(candidate.bytes));
+        if let Some(e) = candidate.files.iter().zip(&targets).find_map(|(file, target)| move_file(file, target).err()) {
+            println!("Warning: {}", e);
+            continue;
+        }
+        moved_bytes += candidate.bytes;
+
+        let new_path = targets[0].to_string_lossy().to_string();
+        let mode_ids: Vec<&str> = candidate.mode_indexes.iter().map(|&index| modes[index].id.as_str()).collect();
+        record.push_str(&format!("{}\t{}\t{}\t{}\n", now, candidate.path.display(), new_path, mode_ids.join(",")));
+        for &index in &candidate.mode_indexes {
+            config = update_mode_entry_model_path(&config, index, &new_path)?;
+            config = update_mode_entry_parameters(&config, index, &[("disabled", note.clone())])?;
+            disabled_modes.push(modes[index].id.clone());
+        }
     }
-}
 
-/// Clears the terminal screen in a cross-platform way
-fn clear_screen() {
-    if cfg!(windows) {
-        let _ = Command::new("cmd").arg("/c").arg("cls").status();
-    } else {
-        let _ = Command::new("clear").status();
+    if !record.is_empty() {
+        let record_path = get_app_base_dir()?.join(ARCHIVED_MODELS_FILE);
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&record_path)
+            .and_then(|mut file| file.write_all(record.as_bytes()))
+            .map_err(|e| format!("Failed to write {}: {}", record_path.display(), e))?;
+    }
+    if config != original_config {
+        write_config_file(&config_path, &config, "models archive")?;
     }
-}
 
-/// Reads a line of user input
-fn read_user_input() -> Result<String, String> {
-    let mut input = String::new();
-    io::stdin()
-        .read_line(&mut input)
-        .map_err(|e| format!("Failed to read input: {}", e))?;
-    Ok(input)
+    println!("\nArchived {} to {}.", format_bytes(moved_bytes), archive_dir.display());
+    if !disabled_modes.is_empty() {
+        println!(
+            "Disabled modes: {} (they now point to the archive; remove disabled=... from an entry to use it again)",
+            disabled_modes.join(", ")
+        );
+    }
+    Ok(())
 }
 
-/// Represents a model file with its path and name
-struct ModelFile {
-    full_path: String,
-    display_name: String,
+/// Formats a parameter count the way model names do, e.g. 1.24B, 135M
+fn format_parameter_count(count: u64) -> String {
+    match count {
+        0..=999_999 => format!("{}K", count / 1000),
+        1_000_000..=999_999_999 => format!("{}M", count / 1_000_000),
+        _ => format!("{:.2}B", count as f64 / 1e9),
+    }
 }
 
-/// Guides the user through creating a new chat mode configuration
-/// 
-/// This interactive process:
-/// 1. Lists available GGUF models from configured directories
-/// 2. Allows model selection
-/// 3. Offers prompt file selection
-/// 4. Enables parameter configuration
-/// 5. Provides option to save as a named mode
-/// 
-/// File paths are handled using standard locations:
-/// - Models: Read from directories in ~/query_gguf/query_gguf_config.toml
-/// - Prompts: ~/query_gguf/prompts/
-/// - Config: ~/query_gguf/query_gguf_config.toml
-/// 
-/// # Returns
-/// - Ok(String): Success message with format "manual::{model_name}"
-/// - Err(String): Error message if any step fails
-/// 
-/// # Error Cases
-/// - No models found
-/// - Invalid model selection
-/// - Prompt file access fails
-/// - Parameter configuration fails
-/// - Save operation fails
-/// 
-/// # Example Success Return
-/// ```
-/// Ok("manual::llama-7b-q4")
+/// Shows what a model file is, from its GGUF header: `query_gguf inspect <model>`
+///
+/// ```text
+/// inspect <model.gguf, mode number, or mode id> [--all] [--template]
 /// ```
-/// 
-/// # File Path Handling
-/// - Uses absolute paths for reliability
-/// - Expands home directory (~) in paths
-/// - Validates file existence before operations
-/// Handles the manual mode selection process
-fn handle_manual_mode_selection() -> Result<String, String> {
-    
-    // turn off for debugging
-    clear_screen();
-    
-    println!("\n=== Manual Mode Setup ===");
-
-    // 1. Find and list available models
-    let models = find_gguf_models()?;
-    if models.is_empty() {
-        return Err("No GGUF models found in configured directories".to_string());
-    }
-
-    println!("\nAvailable Models:");
-    for (index, model) in models.iter().enumerate() {
-        println!("{}. {}", index + 1, model.display_name);
+/// Shows the architecture, parameter count, quantization type, trained
+/// context length, and the start of the chat template. `--all` lists every
+/// metadata key (arrays such as the tokenizer vocabulary are summarized),
+/// `--template` prints the whole chat template. Only the header is read,
+/// so this is quick even for large models.
+fn handle_inspect_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: inspect <model.gguf, mode number, or mode id> [--all] [--template]";
+    let mut target = None;
+    let mut show_all = false;
+    let mut show_template = false;
+    for arg in args {
+        match arg.as_str() {
+            "--all" => show_all = true,
+            "--template" => show_template = true,
+            other if other.starts_with('-') => return Err(format!("Unknown option: {}. {}", other, usage)),
+            other if target.is_none() => target = Some(other),
+            _ => return Err(usage.to_string()),
+        }
     }
+    let target = target.ok_or_else(|| usage.to_string())?;
 
-    // 2. Get model selection
-    print!("\nSelect model number: ");
-    io::stdout().flush().map_err(|e| e.to_string())?;
-    let model_choice = read_user_input()?;
-    let model_index = model_choice.trim().parse::<usize>()
-        .map_err(|_| "Invalid model number".to_string())?
-        .checked_sub(1)
-        .ok_or("Invalid model number".to_string())?;
-
-    let selected_model = models.get(model_index)
-        .ok_or("Invalid model selection".to_string())?;
-
-    // 3. Handle prompt selection
-    let prompt_path = if prompt_yes_no("Would you like to use a prompt file?")? {
-        select_prompt_file()?
+    let model_path = if Path::new(target).is_file() {
+        PathBuf::from(target)
     } else {
-        // Use blank prompt when no prompt is selected
-        get_prompts_dir()?.join("blankprompt.txt").to_string_lossy().to_string()
+        let modes = read_saved_modes().unwrap_or_default();
+        let mode = find_mode_by_selector(&modes, target)
+            .ok_or_else(|| format!("Model file not found: {} (and no mode has that number or id)", target))?;
+        if !mode.endpoint.is_empty() || !mode.remote.is_empty() {
+            return Err(format!("Mode '{}' uses a model on another machine; inspect needs a local model file", mode.id));
+        }
+        check_model_file(mode)?;
+        PathBuf::from(&mode.model_path)
     };
 
-    // 4. Configure parameters
-    let parameters = configure_model_parameters()?;
-
-    // 5. Create launch configuration
-    let launch_config = LaunchConfiguration {
-        model_path: selected_model.full_path.clone(),
-        prompt_path,
-        parameters,
-    };
+    let header = gguf::read_gguf_header(&model_path)?;
+    let file_size = fs::metadata(&model_path).map(|metadata| metadata.len()).unwrap_or(0);
 
-    // 6. Offer to save as mode
-    offer_to_save_mode(&launch_config)?;
+    println!("Model:          {}", model_path.display());
+    println!("File size:      {} (GGUF version {})", format_bytes(file_size), header.version);
+    if let Some(name) = header.get("general.name").and_then(gguf::GgufValue::as_str) {
+        println!("Name:           {}", name);
+    }
+    println!("Architecture:   {}", header.architecture().unwrap_or("(unknown)"));
+    let split_count = header.get("split.count").and_then(gguf::GgufValue::as_u64).unwrap_or(1);
+    if split_count > 1 {
+        println!("Parameters:     {} in this part of {} (split model)", format_parameter_count(header.parameter_count()), split_count);
+    } else {
+        println!("Parameters:     {}", format_parameter_count(header.parameter_count()));
+    }
+    println!("Quantization:   {}", header.quantization().unwrap_or_else(|| "(unknown)".to_string()));
+    println!("Context length: {}", header.context_length().map(|length| length.to_string()).unwrap_or_else(|| "(unknown)".to_string()));
+    for (label, field) in [("Layers:        ", "block_count"), ("Embedding:     ", "embedding_length"), ("Heads:         ", "head_count")] {
+        if let Some(value) = header.architecture_u64(field) {
+            println!("{} {}", label, value);
+        }
+    }
+    println!("Tensors:        {}", header.tensors.len());
+    match header.chat_template() {
+        Some(template) if show_template => println!("Chat template:\n{}", template),
+        Some(template) => println!(
+            "Chat template:  {} ({} chars; --template shows it all)",
+            one_line_preview(template, 60), template.chars().count()
+        ),
+        None => println!("Chat template:  (none; llama.cpp falls back to its default)"),
+    }
 
-    Ok(format!("manual::{}", selected_model.display_name))
+    if show_all {
+        println!("\nMetadata ({} keys):", header.metadata.len());
+        for (key, value) in &header.metadata {
+            let value = value.to_string();
+            // Templates and other long strings: the start only
+            let value = if value.chars().count() > 100 { one_line_preview(&value, 100) } else { value };
+            println!("  {} = {}", key, value);
+        }
+    }
+    Ok(())
 }
 
-/// Finds all GGUF model files in the configured model directories
-/// 
-/// Reads the configuration file from the standard location:
-/// - Linux/MacOS: ~/query_gguf/query_gguf_config.toml
-/// - Windows: \Users\username\query_gguf\query_gguf_config.toml
-/// 
-/// Searches all directories listed as gguf_model_directory_* entries in the config,
-/// including their subdirectories, for files with .gguf extension.
-/// 
-/// # Returns
-/// - Ok(Vec<ModelFile>): List of found model files with their paths and names
-/// - Err(String): Error message if config cannot be read or directories cannot be accessed
-/// 
-/// # Path Handling
-/// - Uses absolute paths for reliability
-/// - Expands home directory (~) in paths
-/// - Maintains both full path and display name for each model
-/// 
-/// # Error Cases
-/// - Config file not found
-/// - Cannot read config file
-/// - Model directories don't exist
-/// - Insufficient permissions
-/// 
-/// # Example Config Entries
-/// ```toml
-/// gguf_model_directory_1 = "/home/user/models"
-/// gguf_model_directory_2 = "~/alternative/models"
-/// ```
-fn find_gguf_models() -> Result<Vec<ModelFile>, String> {
-    // Get absolute path to config file
-    let config_path = get_config_path()?;
-    
-    // Read config file
-    let config_content = fs::read_to_string(&config_path)
-        .map_err(|e| format!("Failed to read config at {}: {}", config_path.display(), e))?;
+/// Quantizes a model with llama-quantize: `query_gguf quantize <model.gguf> <TYPE> [output.gguf]`
+///
+/// The output defaults to `<base model>-<TYPE>.gguf` next to the source.
+/// The output size is estimated from the source size and checked against
+/// free disk space before starting.
+fn handle_quantize_command(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: query_gguf quantize <model.gguf> <TYPE, e.g. Q4_K_M> [output.gguf]";
+    let source_path = PathBuf::from(args.first().ok_or(usage)?);
+    let target_type = args.get(1).ok_or(usage)?.to_uppercase();
+
+    let source_size = fs::metadata(&source_path)
+        .map_err(|e| format!("Cannot read {}: {}", source_path.display(), e))?
+        .len();
+    let source_name = source_path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let (base_name, source_type) = split_model_quantization(&source_name);
+
+    let output_path = match args.get(2) {
+        Some(output) => PathBuf::from(output),
+        None => source_path.with_file_name(format!("{}-{}.gguf", base_name, target_type)),
+    };
+    if output_path.exists() {
+        return Err(format!("Output already exists: {}", output_path.display()));
+    }
 
-    let mut models = Vec::new();
-    let home_dir = get_home_dir()?;
+    let output_dir = output_path.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let estimated_size = estimate_quantized_size(source_size, &source_type, &target_type);
+    println!("Quantizing {} to {} (about {})", source_name, target_type, format_bytes(estimated_size));
+    check_disk_space(output_dir, estimated_size)?;
+
+    let quantize_path = get_llama_tool_path("llama-quantize")?;
+    let status = Command::new(&quantize_path)
+        .arg(&source_path)
+        .arg(&output_path)
+        .arg(&target_type)
+        .status()
+        .map_err(|e| format!("Failed to run {}: {}", quantize_path, e))?;
+    if !status.success() {
+        // Do not leave a half-written model behind
+        let _ = fs::remove_file(&output_path);
+        return Err(format!("llama-quantize exited with status {}", status.code().unwrap_or(-1)));
+    }
 
-    // Parse config file line by line to find model directories
-    for line in config_content.lines() {
-        if line.starts_with("gguf_model_directory_") {
-            if let Some(path) = line.split('=').nth(1) {
-                let raw_path = path.trim().trim_matches('"');
-                
-                // Resolve path to absolute, handling ~ expansion
-                let base_path = if raw_path.starts_with('~') {
-                    format!("{}{}", home_dir, &raw_path[1..])
-                } else if !Path::new(raw_path).is_absolute() {
-                    format!("{}/{}", home_dir, raw_path)
-                } else {
-                    raw_path.to_string()
-                };
+    println!("Saved: {}", output_path.display());
+    Ok(())
+}
 
-                println!("Searching for models in: {}", base_path);
-                search_directory_for_gguf(&mut models, Path::new(&base_path))?;
-            }
-        }
+/// Handles the `prompts` command line subcommands
+///
+/// - `query_gguf prompts sync` -> clone/pull the prompt_repo into the prompts directory
+/// - `query_gguf prompts new <name> [--from-stdin]` -> create a prompt file
+fn handle_prompts_command(args: &[String]) -> Result<(), String> {
+    let usage = "Use: prompts sync | prompts new <name> [--from-stdin] | prompts dedup [--yes]";
+    match args.first().map(|s| s.as_str()) {
+        Some("sync") => sync_prompt_repo(),
+        Some("new") => handle_prompts_new(&args[1..]),
+        Some("dedup") => handle_prompts_dedup(&args[1..]),
+        Some(other) => Err(format!("Unknown prompts command '{}'. {}", other, usage)),
+        None => Err(format!("Missing prompts command. {}", usage)),
     }
+}
 
-    if models.is_empty() {
-        println!("\nWarning: No .gguf files found in configured directories or their subdirectories.");
+/// Makes a prompt file name from a name given on the command line
+///
+/// `.txt` is added when the name has no extension. Names with path
+/// separators or starting with '.' are refused.
+fn prompt_file_name_from(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(format!("Invalid prompt name '{}': use a plain file name, e.g. review", name));
+    }
+    if Path::new(name).extension().is_some() {
+        Ok(name.to_string())
     } else {
-        models.sort_by(|a, b| a.display_name.cmp(&b.display_name));
-        println!("Found {} model files", models.len());
+        Ok(format!("{}.txt", name))
     }
-
-    Ok(models)
 }
 
-/// Recursively searches a directory and its subdirectories for .gguf files
-fn search_directory_for_gguf(models: &mut Vec<ModelFile>, dir: &Path) -> Result<(), String> {
-    if !dir.exists() {
-        return Err(format!("Directory does not exist: {}", dir.display()));
+/// Returns `dir/file_name`, or `dir/<stem>-2.<ext>`, `-3`... if that file already exists
+fn unique_file_path(dir: &Path, file_name: &str) -> PathBuf {
+    let path = dir.join(file_name);
+    if !path.exists() {
+        return path;
     }
+    let file_path = Path::new(file_name);
+    let stem = file_path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = file_path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    (2..)
+        .map(|number| dir.join(format!("{}-{}{}", stem, number, extension)))
+        .find(|candidate| !candidate.exists())
+        .expect("some numbered name is free")
+}
 
-    match fs::read_dir(dir) {
-        Ok(entries) => {
-            for entry in entries {
-                match entry {
-                    Ok(entry) => {
-                        let path = entry.path();
-                        if path.is_dir() {
-                            // Recursively search subdirectories
-                            let _ = search_directory_for_gguf(models, &path);
-                        } else if path.extension().and_then(|s| s.to_str()) == Some("gguf") {
-                            // Found a .gguf file
-                            println!("Found model: {}", path.display());
-                            models.push(ModelFile {
-                                full_path: path.to_string_lossy().to_string(),
-                                display_name: path.file_name()
-                                    .unwrap_or_default()
-                                    .to_string_lossy()
-                                    .to_string(),
-                            });
-                        }
-                    }
-                    Err(e) => println!("Warning: Error reading directory entry: {}", e),
-                }
+/// Creates a prompt file: `query_gguf prompts new <name> [--from-stdin]`
+///
+/// The text comes from stdin when it is piped (or with --from-stdin), so
+/// scripts and heredocs work; in a terminal it is typed in, ending with a
+/// line with only "." or Ctrl-D. A name already taken gets a number
+/// (review-2.txt). The prompt pickers list the new file right away.
+fn handle_prompts_new(args: &[String]) -> Result<(), String> {
+    use std::io::IsTerminal;
+    let usage = "Use: prompts new <name> [--from-stdin]";
+    let from_stdin = args.iter().any(|arg| arg == "--from-stdin");
+    if let Some(unknown) = args.iter().find(|arg| arg.starts_with("--") && *arg != "--from-stdin") {
+        return Err(format!("Unknown option: {}. {}", unknown, usage));
+    }
+    let name = args.iter()
+        .find(|arg| !arg.starts_with("--"))
+        .ok_or_else(|| format!("Missing prompt name. {}", usage))?;
+    let file_name = prompt_file_name_from(name)?;
+
+    let text = if from_stdin || !io::stdin().is_terminal() {
+        let mut text = String::new();
+        io::Read::read_to_string(&mut io::stdin(), &mut text)
+            .map_err(|e| format!("Failed to read stdin: {}", e))?;
+        text
+    } else {
+        println!("Type the prompt. End with a line with only \".\" (or Ctrl-D):");
+        let mut lines = Vec::new();
+        for line in io::stdin().lock().lines() {
+            let line = line.map_err(|e| format!("Failed to read input: {}", e))?;
+            if line == "." {
+                break;
             }
-            Ok(())
+            lines.push(line);
         }
-        Err(e) => Err(format!("Failed to read directory {}: {}", dir.display(), e))
+        format!("{}\n", lines.join("\n"))
+    };
+    if text.trim().is_empty() {
+        return Err("The prompt is empty; nothing was saved".to_string());
+    }
+
+    let prompt_path = unique_file_path(&get_prompts_dir()?, &file_name);
+    if prompt_path.file_name().map(|saved| saved.to_string_lossy() != file_name).unwrap_or(false) {
+        println!("{} already exists, saving as {}", file_name, prompt_path.display());
     }
+    fs::write(&prompt_path, &text)
+        .map_err(|e| format!("Failed to write {}: {}", prompt_path.display(), e))?;
+    println!("Saved prompt: {}", prompt_path.display());
+    Ok(())
 }
 
-/// Provides interactive prompt file selection from the standard prompts directory
-/// 
-/// Lists available prompt files from:
-/// - Linux/MacOS: ~/query_gguf/prompts/
-/// - Windows: \Users\username\query_gguf\prompts\
-/// 
-/// This function:
-/// 1. Lists all available prompt files with numbers
-/// 2. Allows user selection by number
-/// 3. Returns absolute path to selected prompt
-/// 
+/// Prompt files with the same text, found by `prompts dedup`
+#[derive(Debug, PartialEq)]
+struct PromptDuplicates {
+    paths: Vec<String>,
+    exact: bool, // byte-identical; otherwise only whitespace differs
+}
+
+/// Groups prompt files whose text is the same once whitespace is normalized
+///
+/// Blank files are left out (blankprompt.txt is the default prompt of new modes).
+///
+/// # Arguments
+/// * `files` - (path, content) of each prompt file
+///
 /// # Returns
-/// - Ok(String): Absolute path to selected prompt file
-/// - Err(String): Error message if:
-///   - No prompt files found
-///   - Invalid selection
-///   - File access errors
-/// 
-/// # Path Handling
-/// - Uses absolute paths for reliability
-/// - Validates file existence before returning
-/// - Maintains consistent path format across OS
-/// 
-/// # Example Success Path
-/// ```
-/// "/home/username/query_gguf/prompts/system_prompt.txt"
-/// ```
-/// 
-/// # Error Cases
-/// - Empty prompts directory
-/// - Invalid number entered
-/// - Number out of range
-/// - Selected file no longer exists
-fn select_prompt_file() -> Result<String, String> {
-    // Get all prompt files
-    let prompts = find_prompt_files()?;
-    
-    if prompts.is_empty() {
-        return Err("No prompt files found in configured directories".to_string());
+/// * `Vec<PromptDuplicates>` - Groups of two or more files, in the order of their first file
+fn find_duplicate_prompts(files: &[(String, String)]) -> Vec<PromptDuplicates> {
+    let normalize = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+    // (normalized text hash, normalized text, indexes into files)
+    let mut groups: Vec<(u64, String, Vec<usize>)> = Vec::new();
+    for (index, (_, content)) in files.iter().enumerate() {
+        let normalized = normalize(content);
+        if normalized.is_empty() {
+            continue;
+        }
+        let hash = fnv1a_hash(normalized.as_bytes());
+        match groups.iter_mut().find(|(other_hash, other, _)| *other_hash == hash && *other == normalized) {
+            Some(group) => group.2.push(index),
+            None => groups.push((hash, normalized, vec![index])),
+        }
     }
+    groups.into_iter()
+        .filter(|(_, _, indexes)| indexes.len() > 1)
+        .map(|(_, _, indexes)| PromptDuplicates {
+            exact: indexes.iter().all(|&index| files[index].1 == files[indexes[0]].1),
+            paths: indexes.iter().map(|&index| files[index].0.clone()).collect(),
+        })
+        .collect()
+}
 
-    println!("\nAvailable Prompts:");
-    // Display prompts with cleaner names
-    for (index, prompt) in prompts.iter().enumerate() {
-        let path = Path::new(prompt);
-        let display_name = path.file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or_else(|| prompt.as_str());
-        println!("{}. {} ({})", index + 1, display_name, path.display());
+/// The prompts directory plus each existing `prompt_directory` from the config
+fn configured_prompt_dirs() -> Result<Vec<PathBuf>, String> {
+    let home_dir = get_home_dir()?;
+    let mut prompt_dirs = vec![get_prompts_dir()?];
+    for dir in read_basename_fields_from_toml("prompt_directory") {
+        let dir = PathBuf::from(normalize_config_path_value(&to_native_path(&dir), &home_dir));
+        let dir = if dir.is_absolute() { dir } else { Path::new(&home_dir).join(dir) };
+        if dir.is_dir() {
+            prompt_dirs.push(dir);
+        }
     }
+    Ok(prompt_dirs)
+}
 
-    print!("\nSelect prompt number (1-{}): ", prompts.len());
-    io::stdout().flush().map_err(|e| format!("Failed to flush output: {}", e))?;
-    
-    let choice = read_user_input()?;
-    let index = choice.trim().parse::<usize>()
-        .map_err(|_| "Please enter a valid number".to_string())?
-        .checked_sub(1)
-        .ok_or("Please enter a number greater than 0".to_string())?;
+/// Finds duplicate prompt files and merges them: `query_gguf prompts dedup [--yes]`
+///
+/// All prompt directories are searched: ~/query_gguf/prompts and the
+/// prompt_directory_N entries of the config. Files with the same text
+/// (exactly, or with only whitespace differences) are listed with the
+/// modes using them. Merging a group keeps one file (one from the synced
+/// prompt library, else the one most modes use), points the modes at it,
+/// and deletes the other copies; synced files are never deleted, as
+/// `prompts sync` owns them. Each group is confirmed, or all are merged
+/// with --yes. Without a terminal and without --yes, only the report is shown.
+fn handle_prompts_dedup(args: &[String]) -> Result<(), String> {
+    use std::io::IsTerminal;
+    let usage = "Use: prompts dedup [--yes]";
+    let assume_yes = match args {
+        [] => false,
+        [flag] if flag == "--yes" => true,
+        _ => return Err(format!("Unknown option: {}. {}", args.join(" "), usage)),
+    };
 
-    if index >= prompts.len() {
-        return Err(format!("Please enter a number between 1 and {}", prompts.len()));
+    let mut paths = Vec::new();
+    let mut throttle = ScanThrottle::from_config();
+    for dir in &configured_prompt_dirs()? {
+        search_directory_for_prompts(&mut paths, dir, &mut throttle)?;
     }
-
-    // Get the selected prompt path
-    let selected_prompt = &prompts[index];
-    
-    // Verify the path and convert to absolute
-    let absolute_path = Path::new(selected_prompt).canonicalize()
-        .map_err(|e| format!("Failed to resolve prompt path: {}", e))?;
-
-    // Verify file still exists
-    if !absolute_path.exists() {
-        return Err("Selected prompt file no longer exists".to_string());
+    if throttle.ran_out {
+        println!("Warning: The prompt scan ran out of time (scan_time_budget_secs); only {} files are checked", paths.len());
+    }
+    paths.sort();
+    paths.dedup();
+    let files: Vec<(String, String)> = paths.into_iter()
+        .filter_map(|path| fs::read_to_string(&path).ok().map(|content| (path, content)))
+        .collect();
+
+    let groups = find_duplicate_prompts(&files);
+    if groups.is_empty() {
+        println!("No duplicate prompts in {} files.", files.len());
+        return Ok(());
     }
 
-    // Log the selection
-    println!("Selected prompt: {}", absolute_path.display());
+    let modes = read_saved_modes().unwrap_or_default();
+    let modes_using = |path: &str| -> Vec<usize> {
+        let canonical = canonical_path(Path::new(path));
+        modes.iter().enumerate()
+            .filter(|(_, mode)| canonical_path(Path::new(&mode.prompt_path)) == canonical)
+            .map(|(index, _)| index)
+            .collect()
+    };
+    let synced_dir = canonical_path(&get_synced_prompts_dir()?);
+    let is_synced = |path: &str| Path::new(path).starts_with(&synced_dir);
+    let interactive = io::stdin().is_terminal();
 
-    Ok(absolute_path.to_string_lossy().to_string())
-}
-    
-/// Finds all prompt files in the configured prompts directory
-/// 
-/// This function:
-/// 1. Gets the absolute path to the standard prompts directory
-/// 2. Creates the directory if it doesn't exist
-/// 3. Recursively searches for all files in that directory
-/// 4. Returns paths as absolute paths for reliability
-/// 
-/// Standard Location:
-/// - Linux/MacOS: ~/query_gguf/prompts/
-/// - Windows: \Users\username\query_gguf\prompts\
-/// 
-/// # Returns
-/// - Ok(Vec<String>): List of absolute paths to found prompt files
-/// - Err(String): Error message if directory cannot be accessed or created
-/// 
-/// # Error Cases
-/// - Home directory cannot be determined
-/// - Insufficient permissions to create/access directory
-/// - IO errors while reading directory contents
-/// 
-/// # Example Usage
-/// ```
-/// match find_prompt_files() {
-///     Ok(prompts) => {
-///         for prompt in prompts {
-///             println!("Found prompt: {}", prompt);
-///         }
-///     },
-///     Err(e) => println!("Error finding prompts: {}", e)
-/// }
-/// ```
-fn find_prompt_files() -> Result<Vec<String>, String> {
-    // Get absolute path to prompts directory
-    let prompts_dir = get_prompts_dir()?;
-    
-    println!("Searching for prompts in: {}", prompts_dir.display());
-    
-    let mut prompts = Vec::new();
-    
-    // Search the directory recursively
-    search_directory_for_prompts(&mut prompts, &prompts_dir)?;
+    let config_path = get_config_path()?;
+    let original_config = fs::read_to_string(&config_path).unwrap_or_default();
+    let mut config = original_config.clone();
+    let mut removed = 0;
+    for (number, group) in groups.iter().enumerate() {
+        let keep = group.paths.iter()
+            .max_by_key(|path| (is_synced(path), modes_using(path).len(), std::cmp::Reverse(path.len())))
+            .expect("groups have two or more files");
+        let kind = if group.exact { "identical" } else { "same text, whitespace differs" };
+        println!("\nDuplicates {} ({}):", number + 1, kind);
+        for path in &group.paths {
+            let mode_ids: Vec<&str> = modes_using(path).iter().map(|&index| modes[index].id.as_str()).collect();
+            let used_by = if mode_ids.is_empty() { String::new() } else { format!(" (mode {})", mode_ids.join(", ")) };
+            let marker = if path == keep { "keep" } else if is_synced(path) { "synced, kept" } else { "remove" };
+            println!("  [{}] {}{}", marker, path, used_by);
+        }
 
-    if prompts.is_empty() {
-        println!("\nNotice: No prompt files found in directory: {}", prompts_dir.display());
-        println!("You can add prompt files to this directory at any time.");
-    } else {
-        prompts.sort();
-        println!("Found {} prompt files", prompts.len());
+        let to_remove: Vec<&String> = group.paths.iter().filter(|path| *path != keep && !is_synced(path)).collect();
+        if to_remove.is_empty() {
+            continue;
+        }
+        let merge = if assume_yes {
+            true
+        } else if interactive {
+            prompt_yes_no(&format!("Merge into {}?", keep))?
+        } else {
+            false
+        };
+        if !merge {
+            continue;
+        }
+        for path in to_remove {
+            for index in modes_using(path) {
+                config = update_mode_entry_prompt_path(&config, index, keep)?;
+            }
+            match fs::remove_file(path) {
+                Ok(()) => removed += 1,
+                Err(e) => println!("Warning: Could not remove {}: {}", path, e),
+            }
+        }
     }
 
-    Ok(prompts)
+    if config != original_config {
+        write_config_file(&config_path, &config, "prompts dedup")?;
+    }
+    if removed > 0 {
+        println!("\nRemoved {} duplicate prompt files.", removed);
+    } else if !interactive && !assume_yes {
+        println!("\nNothing merged (not a terminal); use --yes to merge all groups.");
+    }
+    Ok(())
 }
 
-/// Recursively searches a directory and its subdirectories for prompt files
-/// 
-/// This function:
-/// 1. Creates the directory if it doesn't exist
-/// 2. Recursively searches the directory and all subdirectories
-/// 3. Adds all found files to the prompts vector
-/// 4. Stores paths as absolute paths
-/// 
-/// # Arguments
-/// * `prompts` - Vector to store found prompt file paths
-/// * `dir` - Directory to search
-/// 
-/// # Returns
-/// - Ok(()): Search completed successfully
-/// - Err(String): Error message if directory cannot be accessed
-/// 
-/// # Error Cases
-/// - Directory creation fails
-/// - Insufficient permissions
-/// - IO errors while reading directory
-/// 
-fn search_directory_for_prompts(prompts: &mut Vec<String>, dir: &Path) -> Result<(), String> {
-    // Create directory if it doesn't exist
-    if !dir.exists() {
-        fs::create_dir_all(dir)
-            .map_err(|e| format!("Failed to create directory {}: {}", dir.display(), e))?;
-        println!("Created directory: {}", dir.display());
-        return Ok(());
-    }
+/// One mode as written in the config, before its id and `extends` are resolved
+///
+/// Comes from a `mode_N = "model|prompt|name=value|...|name|description"`
+/// string or from a `[mode.<id>]` table with named keys.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ModeEntry {
+    model: String,                   // model path; empty when inherited with extends
+    prompt: String,                  // prompt path; empty for the blank prompt or when inherited
+    settings: Vec<(String, String)>, // parameters and id, backend, extends..., in file order
+    name: String,
+    description: String,
+}
 
-    // Read directory contents
-    let entries = fs::read_dir(dir)
-        .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+/// Keys of a `[mode.<id>]` table that are not settings
+const MODE_TABLE_FIELD_KEYS: &[&str] = &["model", "prompt", "name", "description"];
 
-    // Process each entry
-    for entry_result in entries {
-        match entry_result {
-            Ok(entry) => {
-                let path = entry.path();
-                
-                if path.is_dir() {
-                    // Recursively search subdirectories
-                    if let Err(e) = search_directory_for_prompts(prompts, &path) {
-                        println!("Warning: Error searching subdirectory {}: {}", path.display(), e);
-                    }
-                } else {
-                    // Convert path to absolute if it isn't already
-                    match path.canonicalize() {
-                        Ok(abs_path) => {
-                            println!("Found prompt file: {}", abs_path.display());
-                            prompts.push(abs_path.to_string_lossy().to_string());
-                        },
-                        Err(e) => println!("Warning: Could not resolve path {}: {}", path.display(), e)
-                    }
-                }
-            },
-            Err(e) => println!("Warning: Error reading directory entry: {}", e),
+impl ModeEntry {
+    /// Reads a pipe-delimited `mode_N` value
+    ///
+    /// # Returns
+    /// None if the value has fewer than two parts
+    fn from_parts(value: &str) -> Option<ModeEntry> {
+        let parts: Vec<&str> = value.split('|').collect();
+        if parts.len() < 2 {
+            return None;
+        }
+        // Name and description are the last two parts that are not name=value
+        let non_param_parts: Vec<&str> = parts.iter().filter(|part| !part.contains('=')).copied().collect();
+        let (name, description) = match non_param_parts.len() {
+            count if count >= 2 => (non_param_parts[count - 2], non_param_parts[count - 1]),
+            _ => ("", ""),
+        };
+        Some(ModeEntry {
+            model: parts[0].to_string(),
+            prompt: if parts[1].contains('=') { String::new() } else { parts[1].to_string() },
+            settings: parts.iter()
+                .filter_map(|part| part.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            name: name.to_string(),
+            description: description.to_string(),
+        })
+    }
+
+    /// Reads a `[mode.<key>]` table; the table name is the id unless the table sets `id`
+    fn from_table(key: &str, table: &toml_lite::Table) -> ModeEntry {
+        let text = |field: &str| table.get_text(field).unwrap_or_default();
+        let mut settings: Vec<(String, String)> = table.entries().iter()
+            .filter(|entry| !MODE_TABLE_FIELD_KEYS.contains(&entry.key.as_str()))
+            .map(|entry| (entry.key.clone(), entry.value.to_text()))
+            .collect();
+        if !settings.iter().any(|(setting, _)| setting == "id") {
+            settings.push(("id".to_string(), key.to_string()));
+        }
+        ModeEntry {
+            model: text("model"),
+            prompt: text("prompt"),
+            settings,
+            name: table.get_text("name").unwrap_or_else(|| key.to_string()),
+            description: text("description"),
         }
     }
 
-    Ok(())
+    /// A setting's value, trimmed (the first if it is set more than once)
+    fn setting(&self, key: &str) -> Option<&str> {
+        self.settings.iter().find(|(setting, _)| setting == key).map(|(_, value)| value.trim())
+    }
+
+    /// The settings as name=value parts, for apply_parameters_from_parts
+    fn parameter_parts(&self) -> Vec<String> {
+        self.settings.iter().map(|(key, value)| format!("{}={}", key, value)).collect()
+    }
+}
+
+/// The modes defined in the config, in menu order
+///
+/// `mode_N` strings come first, by number, then `[mode.<id>]` tables in file order.
+/// Malformed `mode_N` strings are skipped with a warning.
+fn config_mode_entries(config: &toml_lite::Table) -> Vec<ModeEntry> {
+    let mut entries = Vec::new();
+    for (index, value) in numbered_config_values(config, "mode").iter().enumerate() {
+        match ModeEntry::from_parts(value) {
+            Some(entry) => entries.push(entry),
+            None => println!("Warning: Skipping malformed mode entry {}: insufficient parts", index + 1),
+        }
+    }
+    if let Some(mode_tables) = config.get_table("mode") {
+        entries.extend(mode_tables.tables().map(|(key, table)| ModeEntry::from_table(key, table)));
+    }
+    entries
 }
 
 /// Reads and parses all saved chat modes from the configuration file
 /// 
 /// This function:
 /// 1. Gets the absolute path to the config file in the user's home directory
-/// 2. Reads all mode_* entries from the config file
-/// 3. Parses each mode entry into a ChatModeConfig struct
+/// 2. Reads all mode_* entries and [mode.<id>] tables from the config file
+/// 3. Parses each mode into a ChatModeConfig struct
 /// 
 /// Config file location:
 /// - Linux/MacOS: ~/query_gguf/query_gguf_config.toml
 /// - Windows: \Users\username\query_gguf\query_gguf_config.toml
 /// 
-/// Mode entries in config should be formatted as:
-/// mode_1 = "model_path|prompt_path|param=value|param=value|name|description"
+/// Modes can be tables with named keys (what `save_mode_to_config` writes):
+/// ```toml
+/// [mode.fast]
+/// name = "FastMode"
+/// description = "Quick responses | short answers"
+/// model = "/path/to/model.gguf"
+/// prompt = "prompts/system.txt"
+/// temp = 0.8
+/// top_k = 40
+/// ```
+/// or, in the older format, pipe-delimited strings:
+/// ```toml
+/// mode_1 = "/path/to/model.gguf|prompts/system.txt|temp=0.8|top_k=40|FastMode|Quick responses"
+/// ```
 /// 
 /// # Returns
 /// - Ok(Vec<ChatModeConfig>): Vector of parsed chat modes
 /// - Err(String): Error message if config cannot be read or parsed
 /// 
-/// # Example Config Entry
-/// ```toml
-/// mode_1 = "/path/to/model.gguf|prompts/system.txt|temp=0.8|top_k=40|FastMode|Quick responses"
-/// ```
-/// 
-/// # Field Order
+/// # Field Order (mode_N strings)
 /// 1. model_path (required)
 /// 2. prompt_path (required)
 /// 3. parameters (optional, format: name=value)
 /// 4. mode name (required)
 /// 5. description (required)
 /// 
+/// # Mode Ids
+/// Each mode has a stable id: the table name, or an `id=...` part. If a
+/// mode_N string has none, the id is derived from the mode name (see
+/// slugify_mode_name). Ids are used for default_mode and CLI selection, so
+/// they survive deletions and reordering.
+/// 
 /// # Error Cases
 /// - Config file not found
 /// - Invalid mode format
 /// - Missing required fields
 /// 
 fn read_saved_modes() -> Result<Vec<ChatModeConfig>, String> {
-    // let config_path = get_config_path()?;
-    let mode_fields = read_basename_fields_from_toml("mode");
+    // In strict mode, malformed entries are errors instead of skipped warnings
+    check_config_strict_if_enabled()?;
+
+    let mode_entries = config_mode_entries(&read_config());
     let mut modes = Vec::new();
 
     // Get base directories once at the start
     let home_dir = get_home_dir()?;
     let prompts_dir = get_prompts_dir()?;
     
-    for (index, config_str) in mode_fields.iter().enumerate() {
-        let parts: Vec<&str> = config_str.split('|').collect();
-        if parts.len() < 2 {
-            println!("Warning: Skipping malformed mode entry {}: insufficient parts", index + 1);
-            continue;
-        }
-
-        // 1. CHANGE: Resolve model path to absolute path
-        let model_path = if Path::new(parts[0]).is_absolute() {
-            parts[0].to_string()
+    for (index, entry) in mode_entries.iter().enumerate() {
+        // Resolve model path to absolute path
+        // (in WSL, Windows paths like C:\models become /mnt/c/models)
+        let raw_model_path = to_native_path(&entry.model);
+        let model_path = if Path::new(&raw_model_path).is_absolute() {
+            raw_model_path
         } else {
-            format!("{}/{}", home_dir, parts[0].trim_start_matches("/"))
+            format!("{}/{}", home_dir, raw_model_path.trim_start_matches("/"))
         };
-        
-        // // Keep For Inspection
-        // println!("Resolved model path: {}", model_path);
 
-        // 2. CHANGE: Resolve prompt path to absolute path
-        let prompt_path = if parts.len() > 1 && !parts[1].contains('=') {
-            if Path::new(parts[1]).is_absolute() {
-                parts[1].to_string()
+        // Resolve prompt path to absolute path
+        let prompt_path = if !entry.prompt.is_empty() {
+            if Path::new(&to_native_path(&entry.prompt)).is_absolute() {
+                to_native_path(&entry.prompt)
             } else {
                 // Strip any leading "prompts/" from the path before joining
-                let clean_path = parts[1]
+                let clean_path = entry.prompt
                     .trim_start_matches("prompts/")
                     .trim_start_matches('/');
                 prompts_dir.join(clean_path)
@@ -1916,37 +15963,42 @@ fn read_saved_modes() -> Result<Vec<ChatModeConfig>, String> {
                     .to_string()
             }
         } else {
-            // 3. CHANGE: Use absolute path for default blank prompt
+            // Use absolute path for default blank prompt
             prompts_dir.join("blankprompt.txt")
                 .to_string_lossy()
                 .to_string()
         };
-        
-        // // Keep For Inspection
-        // println!("Resolved prompt path: {}", prompt_path);
 
-        // Get the last two non-parameter parts for name and description
-        let mut name = String::new();
-        let mut description = String::new();
-            
-        // Find the last two non-parameter parts
-        let non_param_parts: Vec<&str> = parts.iter()
-            .filter(|&&part| !part.contains('='))
-            .cloned()
-            .collect();
-            
-        if non_param_parts.len() >= 2 {
-            name = non_param_parts[non_param_parts.len() - 2].to_string();
-            description = non_param_parts[non_param_parts.len() - 1].to_string();
-        } else {
+        if entry.name.is_empty() && entry.description.is_empty() {
             println!("Warning: Mode {} missing name or description", index + 1);
         }
 
+        let parameter_parts = entry.parameter_parts();
+        let parts: Vec<&str> = parameter_parts.iter().map(String::as_str).collect();
         let parameters = parse_parameters_from_parts(&parts);
 
+        // Use the stored id if present, otherwise derive one from the name
+        let stored_id = entry.setting("id")
+            .map(|id| id.to_string())
+            .filter(|id| !id.is_empty());
+        let base_id = stored_id.unwrap_or_else(|| {
+            let slug = slugify_mode_name(&entry.name);
+            if slug.is_empty() { format!("mode-{}", index + 1) } else { slug }
+        });
+        let id = make_unique_mode_id(&base_id, &modes);
+
+        let setting = |key: &str| entry.setting(key).unwrap_or_default().to_string();
+        let kind = Some(setting("kind")).filter(|kind| kind != "text").unwrap_or_default();
+
         let mode_config = ChatModeConfig {
-            name,
-            description,
+            id,
+            backend: setting("backend"),
+            remote: setting("remote"),
+            endpoint: setting("endpoint"),
+            extends: setting("extends"),
+            kind,
+            name: entry.name.clone(),
+            description: entry.description.clone(),
             model_path,
             prompt_path,
             parameters,
@@ -1958,13 +16010,149 @@ fn read_saved_modes() -> Result<Vec<ChatModeConfig>, String> {
         println!("Warning: No valid modes found in config file");
     }
 
+    let modes = resolve_mode_inheritance(&modes, &mode_entries);
+
     Ok(modes)
 }
 
+/// Applies `extends=<parent id>` inheritance to parsed modes
+///
+/// A mode that extends another starts from the parent's model, prompt,
+/// backend, and parameters, and overrides only what it sets itself:
+/// - model/prompt: when its model or prompt field is non-empty
+/// - backend/remote/endpoint/kind: when it has a backend=/remote=/endpoint=/kind= part
+/// - parameters: each name=value part it contains
+///
+/// Parents may extend other modes. Unknown parents and cycles are reported
+/// as warnings and the mode is used as written.
+///
+/// # Example Config Entries
+/// ```toml
+/// mode_1 = "/models/coder.gguf|prompts/code.txt|temp=0.8|ctx_size=8000|id=base-coder|Base Coder|shared settings"
+/// mode_2 = "||extends=base-coder|temp=0.3|Careful Coder|lower temperature"
+/// ```
+///
+/// # Arguments
+/// * `modes` - Modes as parsed, each without inheritance applied
+/// * `entries` - The config entry of each mode, same order as `modes`
+///
+/// # Returns
+/// * `Vec<ChatModeConfig>` - Modes with inheritance applied
+fn resolve_mode_inheritance(modes: &[ChatModeConfig], entries: &[ModeEntry]) -> Vec<ChatModeConfig> {
+    fn resolve(
+        index: usize,
+        modes: &[ChatModeConfig],
+        entries: &[ModeEntry],
+        chain: &mut Vec<usize>,
+    ) -> ChatModeConfig {
+        let mode = &modes[index];
+        if mode.extends.is_empty() {
+            return mode.clone();
+        }
+
+        let parent_index = match modes.iter().position(|m| m.id == mode.extends) {
+            Some(parent_index) => parent_index,
+            None => {
+                println!("Warning: Mode '{}' extends unknown mode '{}'", mode.id, mode.extends);
+                return mode.clone();
+            }
+        };
+        if chain.contains(&parent_index) || parent_index == index {
+            println!("Warning: Mode '{}' has circular extends (via '{}')", mode.id, mode.extends);
+            return mode.clone();
+        }
+
+        chain.push(index);
+        let parent = resolve(parent_index, modes, entries, chain);
+        chain.pop();
+
+        let entry = &entries[index];
+        let has_own_model = !entry.model.trim().is_empty();
+        let has_own_prompt = !entry.prompt.trim().is_empty();
+
+        let parameter_parts = entry.parameter_parts();
+        let parts: Vec<&str> = parameter_parts.iter().map(String::as_str).collect();
+        let mut parameters = parent.parameters.clone();
+        apply_parameters_from_parts(&mut parameters, &parts);
+
+        ChatModeConfig {
+            id: mode.id.clone(),
+            backend: if mode.backend.is_empty() { parent.backend } else { mode.backend.clone() },
+            remote: if mode.remote.is_empty() { parent.remote } else { mode.remote.clone() },
+            endpoint: if mode.endpoint.is_empty() { parent.endpoint } else { mode.endpoint.clone() },
+            extends: mode.extends.clone(),
+            kind: if mode.kind.is_empty() { parent.kind } else { mode.kind.clone() },
+            name: mode.name.clone(),
+            description: mode.description.clone(),
+            model_path: if has_own_model { mode.model_path.clone() } else { parent.model_path },
+            prompt_path: if has_own_prompt { mode.prompt_path.clone() } else { parent.prompt_path },
+            parameters,
+        }
+    }
+
+    (0..modes.len())
+        .map(|index| resolve(index, modes, entries, &mut Vec::new()))
+        .collect()
+}
+
+/// Converts a mode name into a stable id (slug)
+///
+/// Lowercases the name, keeps ascii letters and digits, and replaces every
+/// other run of characters with a single '-'.
+///
+/// # Examples
+/// ```
+/// assert_eq!(slugify_mode_name("Base Coder v2"), "base-coder-v2");
+/// ```
+fn slugify_mode_name(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.trim().chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Returns `base_id`, or `base_id-2`, `base_id-3`... if already taken by another mode
+fn make_unique_mode_id(base_id: &str, existing_modes: &[ChatModeConfig]) -> String {
+    let is_taken = |candidate: &str| existing_modes.iter().any(|m| m.id == candidate);
+    if !is_taken(base_id) {
+        return base_id.to_string();
+    }
+    let mut suffix = 2;
+    loop {
+        let candidate = format!("{}-{}", base_id, suffix);
+        if !is_taken(&candidate) {
+            return candidate;
+        }
+        suffix += 1;
+    }
+}
+
+/// Finds a saved mode by menu number (1-based) or by stable mode id
+///
+/// Numbers are only a display convenience and can shift when modes are
+/// deleted or reordered; ids stay the same.
+fn find_mode_by_selector<'a>(modes: &'a [ChatModeConfig], selector: &str) -> Option<&'a ChatModeConfig> {
+    let selector = selector.trim();
+    if let Ok(number) = selector.parse::<usize>() {
+        return number.checked_sub(1).and_then(|index| modes.get(index));
+    }
+    modes.iter().find(|mode| mode.id == selector)
+}
+
 /// Parses parameters from mode configuration parts
 fn parse_parameters_from_parts(parts: &[&str]) -> LlamaCppParameters {
     let mut params = LlamaCppParameters::default();
+    apply_parameters_from_parts(&mut params, parts);
+    params
+}
 
+/// Overrides parameters with the name=value parts of a mode entry
+fn apply_parameters_from_parts(params: &mut LlamaCppParameters, parts: &[&str]) {
     for part in parts {
         if let Some((key, value)) = part.split_once('=') {
             match key {
@@ -1977,12 +16165,366 @@ fn parse_parameters_from_parts(parts: &[&str]) -> LlamaCppParameters {
                 },
                 "gpu_layers" => if let Ok(v) = value.parse() { params.gpu_layers = v },
                 "interactive_first" => if let Ok(v) = value.parse() { params.interactive_first = v },
+                "display_prompt" => if let Ok(v) = value.parse() { params.display_prompt = v },
+                "verbose_prompt" => if let Ok(v) = value.parse() { params.verbose_prompt = v },
+                "log_disable" => if let Ok(v) = value.parse() { params.log_disable = v },
+                "multiline_input" => if let Ok(v) = value.parse() { params.multiline_input = v },
+                "in_prefix" => params.in_prefix = value.to_string(),
+                "in_suffix" => params.in_suffix = value.to_string(),
+                "conversation" => if let Ok(v) = value.parse() { params.conversation = Some(v) },
+                "max_prompt_bytes" => if let Ok(v) = value.parse() { params.max_prompt_bytes = Some(v) },
+                "max_files" => if let Ok(v) = value.parse() { params.max_files = Some(v) },
+                "steps" => if let Ok(v) = value.parse() { params.image_steps = Some(v) },
+                "cfg_scale" => if let Ok(v) = value.parse() { params.cfg_scale = Some(v) },
+                "output_dir" => params.output_dir = value.trim().to_string(),
+                "only_if" => params.only_if = value.trim().to_string(),
+                "hours" => params.hours = value.trim().to_string(),
+                "min_free_ram_gb" => if let Ok(v) = value.parse() { params.min_free_ram_gb = Some(v) },
+                "tests" => params.tests = value.trim().to_string(),
+                "disabled" => params.disabled = value.trim().to_string(),
                 _ => (), // Ignore unknown parameters
             }
         }
     }
+}
 
-    params
+/// Top-level config keys that are recognized by query_gguf
+///
+/// Numbered keys (e.g. gguf_model_directory_1, mode_3) are listed by their
+/// base name in KNOWN_NUMBERED_CONFIG_KEYS instead.
+const KNOWN_CONFIG_KEYS: &[&str] = &[
+    "llama_cli_path",
+    "logging_enabled",
+    "log_directory_path",
+    "prompt_directory",
+    "default_mode",
+    "strict",
+    "prompt_repo",
+    "quick_question",
+    "response_cache",
+    "llama_server_path",
+    "summary_mode",
+    "ca_bundle_path",
+    "https_backend",
+    "scan_io_limit_kb",
+    "scan_time_budget_secs",
+    "scan_exclude",
+    "scan_max_file_kb",
+    "scan_max_total_kb",
+    "offline",
+    "prompt_command_allowlist",
+    "prompt_command_confirm",
+    "agent_command_allowlist",
+    "agent_file_root",
+    "agent_sandbox",
+    "wsl_terminal",
+    "terminal_command",
+    "editor",
+    "tts_command",
+    "stt_command",
+    "model_archive_directory",
+    "sd_cli_path",
+    "windows_run_in_wsl",
+    "launch_backend",
+    "docker_image",
+    "docker_gpus",
+    "remote_llama_cli_path",
+    "api_key_env",
+    "endpoint_timeout_secs",
+    "endpoint_retries",
+    "endpoint_rate_limit",
+    "confirm_prompt",
+];
+
+/// Base names of numbered config keys, e.g. "mode" for mode_1, mode_2, ...
+const KNOWN_NUMBERED_CONFIG_KEYS: &[&str] = &[
+    "gguf_model_directory",
+    "prompt_file_directory",
+    "prompt_directory",
+    "mode",
+];
+
+/// Config keys that take true or false
+const BOOLEAN_CONFIG_KEYS: &[&str] = &[
+    "logging_enabled",
+    "offline",
+    "strict",
+    "windows_run_in_wsl",
+    "quick_question",
+    "confirm_prompt",
+    "response_cache",
+    "prompt_command_confirm",
+];
+
+/// Prefixes of recognized [table] names, e.g. "binaries." for [binaries.cuda]
+const KNOWN_CONFIG_TABLE_PREFIXES: &[&str] = &[
+    "binaries.",
+    "tools.",
+    "mode.",
+];
+
+/// Recognized [table] names without a sub-name
+const KNOWN_CONFIG_TABLES: &[&str] = &[
+    "aliases",
+];
+
+/// Returns true if `key` is recognized inside the [table] named `table`
+fn is_known_table_key(table: &str, key: &str) -> bool {
+    if table.starts_with("binaries.") {
+        return key == "path";
+    }
+    if table.starts_with("tools.") {
+        return ["description", "parameters", "command", "sandbox", "path_parameters"].contains(&key);
+    }
+    if table.starts_with("mode.") {
+        return MODE_TABLE_FIELD_KEYS.contains(&key) || KNOWN_MODE_PARAMETER_KEYS.contains(&key);
+    }
+    // Any word can be an alias
+    table == "aliases"
+}
+
+/// Parameter keys recognized inside a mode entry (name=value parts)
+const KNOWN_MODE_PARAMETER_KEYS: &[&str] = &[
+    "temp",
+    "top_k",
+    "top_p",
+    "ctx_size",
+    "threads",
+    "gpu_layers",
+    "interactive_first",
+    "display_prompt",
+    "verbose_prompt",
+    "log_disable",
+    "multiline_input",
+    "in_prefix",
+    "in_suffix",
+    "conversation",
+    "max_prompt_bytes",
+    "max_files",
+    "steps",
+    "cfg_scale",
+    "output_dir",
+    "kind",
+    "only_if",
+    "hours",
+    "min_free_ram_gb",
+    "tests",
+    "disabled",
+    "id",
+    "backend",
+    "remote",
+    "endpoint",
+    "extends",
+];
+
+/// Returns true if `strict = true` is set in the config file
+///
+/// In strict mode, problems that are normally printed as warnings and
+/// skipped (unknown keys, malformed modes, duplicates) become hard errors.
+fn is_strict_config_enabled() -> bool {
+    read_config_bool("strict") == Some(true)
+}
+
+/// Returns true if `key` is a known config key (plain or numbered)
+fn is_known_config_key(key: &str) -> bool {
+    if KNOWN_CONFIG_KEYS.contains(&key) {
+        return true;
+    }
+    KNOWN_NUMBERED_CONFIG_KEYS.iter().any(|base| {
+        key.strip_prefix(base)
+            .and_then(|rest| rest.strip_prefix('_'))
+            .map(|num| num.parse::<usize>().is_ok())
+            .unwrap_or(false)
+    })
+}
+
+/// Checks a single mode entry value for problems
+///
+/// # Arguments
+/// * `value` - The mode string (without surrounding quotes)
+///
+/// # Returns
+/// * `Vec<String>` - Description of each problem found (empty if none)
+fn find_mode_entry_problems(value: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let parts: Vec<&str> = value.split('|').collect();
+
+    if parts.len() < 2 {
+        problems.push("insufficient parts (expected model_path|prompt_path|...)".to_string());
+        return problems;
+    }
+
+    let non_param_count = parts.iter().filter(|part| !part.contains('=')).count();
+    if non_param_count < 4 {
+        problems.push("missing name or description".to_string());
+    }
+
+    for part in &parts {
+        if let Some((key, value)) = part.split_once('=') {
+            if !KNOWN_MODE_PARAMETER_KEYS.contains(&key) {
+                problems.push(format!("unknown parameter '{}'", key));
+            } else if !is_valid_mode_setting(key, value) {
+                problems.push(format!("invalid value for '{}': {}", key, value));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Returns true if `value` is usable for the known mode parameter `key`
+fn is_valid_mode_setting(key: &str, value: &str) -> bool {
+    match key {
+        "temp" | "top_p" => value.parse::<f32>().is_ok(),
+        "top_k" | "ctx_size" | "threads" | "gpu_layers" => value.parse::<i32>().is_ok(),
+        "interactive_first" | "display_prompt" | "verbose_prompt" | "log_disable"
+        | "multiline_input" | "conversation" => value.parse::<bool>().is_ok(),
+        // Whitespace is a common prefix/suffix (e.g. in_prefix= )
+        "in_prefix" | "in_suffix" => true,
+        "max_prompt_bytes" => value.parse::<u64>().is_ok(),
+        "max_files" => value.parse::<usize>().is_ok(),
+        "steps" => value.parse::<u32>().map(|steps| steps > 0).unwrap_or(false),
+        "cfg_scale" => value.parse::<f32>().is_ok(),
+        "kind" => ["text", "image"].contains(&value.trim()),
+        "only_if" => KNOWN_MODE_CONDITIONS.contains(&value.trim()),
+        "hours" => parse_hours_ranges(value).is_some(),
+        "min_free_ram_gb" => value.parse::<f64>().map(|gb| gb >= 0.0).unwrap_or(false),
+        "tests" => !toml_string_list(value).is_empty(),
+        _ => !value.trim().is_empty(),
+    }
+}
+
+/// Validates config content for strict mode
+///
+/// Reports, with 1-based line numbers:
+/// - lines that are not comments and have no '='
+/// - unknown keys
+/// - duplicate keys (including duplicate mode numbers)
+/// - malformed mode entries
+///
+/// # Arguments
+/// * `content` - Full text of the config file
+///
+/// # Returns
+/// - Ok(()): No problems found
+/// - Err(String): One line per problem found
+fn validate_config_strict(content: &str) -> Result<(), String> {
+    let problems = find_config_problems(content);
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Strict config check failed:\n  {}", problems.join("\n  ")))
+    }
+}
+
+/// Finds the problems `validate_config_strict` reports, each starting with "line N: "
+fn find_config_problems(content: &str) -> Vec<String> {
+    let (root, errors) = toml_lite::parse(content);
+    // (line, problem) pairs; parse errors already start with "line N: "
+    let mut problems: Vec<(usize, String)> = errors.into_iter()
+        .map(|error| {
+            let line = error.strip_prefix("line ")
+                .and_then(|rest| rest.split(':').next())
+                .and_then(|number| number.parse().ok())
+                .unwrap_or(0);
+            (line, error)
+        })
+        .collect();
+    let mut add = |line: usize, problem: String| problems.push((line, format!("line {}: {}", line, problem)));
+
+    for entry in root.entries() {
+        let key = entry.key.as_str();
+        let table = match &entry.value {
+            toml_lite::Value::Table(table) => table,
+            value => {
+                if !is_known_config_key(key) {
+                    add(entry.line, format!("unknown key '{}'", key));
+                } else if BOOLEAN_CONFIG_KEYS.contains(&key) && value.as_bool().is_none() {
+                    add(entry.line, format!("'{}' must be true or false, not {}", key, value));
+                } else if key == "https_backend" && http::TlsBackend::from_name(&value.to_text()).is_none() {
+                    add(entry.line, format!("'https_backend' must be curl or openssl, not {}", value));
+                }
+                let is_mode_entry = key.strip_prefix("mode_")
+                    .map(|num| num.parse::<usize>().is_ok())
+                    .unwrap_or(false);
+                if is_mode_entry {
+                    for problem in find_mode_entry_problems(&value.to_text()) {
+                        add(entry.line, format!("{}: {}", key, problem));
+                    }
+                }
+                continue;
+            },
+        };
+
+        // [aliases] itself, or the [binaries.<name>] (or [mode.<id>]...) tables under "binaries"
+        let tables: Vec<(String, &toml_lite::Table)> = if KNOWN_CONFIG_TABLES.contains(&key) {
+            vec![(key.to_string(), table)]
+        } else if KNOWN_CONFIG_TABLE_PREFIXES.contains(&format!("{}.", key).as_str()) {
+            table.entries().iter()
+                .filter_map(|sub| match &sub.value {
+                    toml_lite::Value::Table(subtable) => Some((format!("{}.{}", key, sub.key), subtable)),
+                    _ => {
+                        add(sub.line, format!("unknown key '{}' in [{}]", sub.key, key));
+                        None
+                    },
+                })
+                .collect()
+        } else {
+            add(entry.line, format!("unknown table [{}]", key));
+            Vec::new()
+        };
+        for (name, table) in tables {
+            for field in table.entries() {
+                if !is_known_table_key(&name, &field.key) {
+                    add(field.line, format!("unknown key '{}' in [{}]", field.key, name));
+                } else if name.starts_with("mode.") && KNOWN_MODE_PARAMETER_KEYS.contains(&field.key.as_str())
+                    && !is_valid_mode_setting(&field.key, &field.value.to_text()) {
+                    add(field.line, format!("invalid value for '{}' in [{}]: {}", field.key, name, field.value));
+                }
+            }
+        }
+    }
+
+    // Stable, so problems of one line keep their order
+    problems.sort_by_key(|(line, _)| *line);
+    problems.into_iter().map(|(_, problem)| problem).collect()
+}
+
+/// Problems in the edited config that the config did not have before the edit
+///
+/// Problems are compared without their line numbers, since an edit moves
+/// lines; a problem that now occurs more often counts as new.
+fn new_config_problems(before: &str, after: &str) -> Vec<String> {
+    let without_line_number = |problem: &str| problem.split_once(": ").map(|(_, rest)| rest.to_string()).unwrap_or_default();
+    let mut old_problems: Vec<String> = find_config_problems(before).iter().map(|problem| without_line_number(problem)).collect();
+    find_config_problems(after)
+        .into_iter()
+        .filter(|problem| {
+            let text = without_line_number(problem);
+            match old_problems.iter().position(|old| *old == text) {
+                Some(index) => {
+                    old_problems.remove(index);
+                    false
+                },
+                None => true,
+            }
+        })
+        .collect()
+}
+
+/// Runs the strict config check if `strict = true` is set
+///
+/// # Returns
+/// - Ok(()): Strict mode is off, or the config has no problems
+/// - Err(String): Problems found in strict mode
+fn check_config_strict_if_enabled() -> Result<(), String> {
+    if !is_strict_config_enabled() {
+        return Ok(());
+    }
+    let config_path = get_config_path()?;
+    let content = fs::read_to_string(&config_path)
+        .map_err(|e| format!("Failed to read config at {}: {}", config_path.display(), e))?;
+    validate_config_strict(&content)
 }
 
 /// Configuration for launching LLaMA
@@ -2024,6 +16566,55 @@ fn display_parameters(params: &LlamaCppParameters) {
     println!("  Threads: {}", params.thread_count);
     println!("  GPU Layers: {}", params.gpu_layers);
     println!("  Interactive First: {}", params.interactive_first);
+    for (label, enabled) in [
+        ("Display Prompt", params.display_prompt),
+        ("Verbose Prompt", params.verbose_prompt),
+        ("Log Disable", params.log_disable),
+        ("Multiline Input", params.multiline_input),
+    ] {
+        if enabled {
+            println!("  {}: true", label);
+        }
+    }
+    if !params.in_prefix.is_empty() {
+        println!("  Input Prefix: {:?}", params.in_prefix);
+    }
+    if !params.in_suffix.is_empty() {
+        println!("  Input Suffix: {:?}", params.in_suffix);
+    }
+    if let Some(conversation) = params.conversation {
+        println!("  Conversation: {}", conversation);
+    }
+    if let Some(max_prompt_bytes) = params.max_prompt_bytes {
+        println!("  Max Prompt Bytes: {}", max_prompt_bytes);
+    }
+    if let Some(max_files) = params.max_files {
+        println!("  Max Files: {}", max_files);
+    }
+    if let Some(image_steps) = params.image_steps {
+        println!("  Steps: {}", image_steps);
+    }
+    if let Some(cfg_scale) = params.cfg_scale {
+        println!("  CFG Scale: {}", cfg_scale);
+    }
+    if !params.output_dir.is_empty() {
+        println!("  Output Directory: {}", params.output_dir);
+    }
+    if !params.only_if.is_empty() {
+        println!("  Only If: {}", params.only_if);
+    }
+    if !params.hours.is_empty() {
+        println!("  Hours: {}", params.hours);
+    }
+    if let Some(min_free_ram_gb) = params.min_free_ram_gb {
+        println!("  Min Free RAM: {} GB", min_free_ram_gb);
+    }
+    if !params.tests.is_empty() {
+        println!("  Tests: {}", params.tests);
+    }
+    if !params.disabled.is_empty() {
+        println!("  Disabled: {}", params.disabled);
+    }
 }
 
 /// Validates and adjusts thread count to ensure it's within reasonable bounds
@@ -2105,13 +16696,22 @@ fn configure_parameters_interactive(params: &mut LlamaCppParameters) -> Result<(
     }
 
     // Interactive First
-    params.interactive_first = prompt_yes_no("Enable interactive-first mode (user-first)? (Select No to start with AI reaction to Prompt)")?;
+    params.interactive_first = prompt_yes_no(&format!(
+        "Enable interactive-first mode (user-first)? (Select No to start with AI reaction to Prompt; currently {})",
+        if params.interactive_first { "yes" } else { "no" }
+    ))?;
 
     Ok(())
 }
 
 #[derive(Debug, Clone)]
 struct ChatModeConfig {
+    id: String,         // stable id (slug), stored as id=... in the mode entry
+    backend: String,    // llama.cpp build from [binaries.<backend>], empty for the default
+    remote: String,     // ssh host (user@host) llama-cli runs on, empty for this machine
+    endpoint: String,   // OpenAI-compatible server URL the mode talks to, empty for local llama.cpp
+    extends: String,    // id of the mode this one inherits from, empty for none
+    kind: String,       // "image" for stable-diffusion.cpp modes, empty for text (llama.cpp)
     name: String,
     description: String,
     model_path: String,
@@ -2138,7 +16738,14 @@ fn offer_to_save_mode(config: &LaunchConfiguration) -> Result<(), String> {
         io::stdout().flush().map_err(|e| e.to_string())?;
         let description = read_user_input()?.trim().to_string();
 
+        let existing_modes = read_saved_modes().unwrap_or_default();
         let new_mode = ChatModeConfig {
+            id: make_unique_mode_id(&slugify_mode_name(&mode_name), &existing_modes),
+            backend: String::new(),
+            remote: String::new(),
+            endpoint: String::new(),
+            extends: String::new(),
+            kind: String::new(),
             name: mode_name.clone(),
             description,
             model_path: config.model_path.clone(),
@@ -2147,126 +16754,380 @@ fn offer_to_save_mode(config: &LaunchConfiguration) -> Result<(), String> {
         };
 
         save_mode_to_config(&new_mode)?;
-        println!("\nMode '{}' saved successfully!", mode_name);
+        println!("\nMode '{}' saved successfully! (id: {})", mode_name, new_mode.id);
+    }
+    Ok(())
+}
+
+/// Saves a new chat mode configuration to the config file
+/// 
+/// This function:
+/// 1. Reads existing configuration
+/// 2. Optionally sets as default mode
+/// 3. Formats and appends the new mode as a `[mode.<id>]` table
+/// 4. Saves updated configuration
+/// 
+/// # Arguments
+/// * `mode` - ChatModeConfig containing all mode settings
+/// 
+/// # Returns
+/// - Ok(()): Mode saved successfully
+/// - Err(String): Error message if save fails
+/// 
+/// # Format
+/// Saves modes in format:
+/// ```toml
+/// [mode.<id>]
+/// name = "name"
+/// description = "description"
+/// model = "model_path"
+/// prompt = "prompt_path"
+/// temp = 0.8
+/// ...
+/// ```
+/// 
+/// # Error Cases
+/// - Config file not found
+/// - A mode table with the same id already exists
+/// - Permission denied
+/// - Disk full
+/// - IO errors
+fn save_mode_to_config(mode: &ChatModeConfig) -> Result<(), String> {
+    let config_path = get_config_path()?;
+    
+    let full_content = fs::read_to_string(&config_path)
+        .map_err(|e| format!("Failed to read config at {}: {}", config_path.display(), e))?;
+    let table_key = mode_table_key(mode);
+    if toml_lite::parse(&full_content).0.get_table("mode").is_some_and(|tables| tables.get_table(&table_key).is_some()) {
+        return Err(format!("The config already has a [mode.{}] table", table_key));
+    }
+
+    // default_mode must go before any [table], or it would belong to it
+    let (mut config_content, table_content) = split_config_tables(&full_content);
+
+    // Ask if this should be the default mode
+    if prompt_yes_no("Would you like to make this the default mode?")? {
+        // Remove existing default_mode line if it exists
+        config_content = config_content.lines()
+            .filter(|line| !line.starts_with("default_mode"))
+            .collect::<Vec<&str>>()
+            .join("\n");
+        
+        // Add new default_mode line (by id, which survives renumbering)
+        config_content.push_str(&format!("\ndefault_mode = \"{}\"\n", mode.id));
+    }
+    
+    // Mode tables go at the end, after any other tables
+    if !table_content.is_empty() {
+        config_content.push('\n');
+        config_content.push_str(table_content.trim_end());
+        config_content.push('\n');
     }
+    config_content.push_str(&format_mode_entry(mode));
+    write_config_file(&config_path, &config_content, &format!("mode saved ({})", mode.id))?;
     Ok(())
 }
 
-/// Saves a new chat mode configuration to the config file
-/// 
-/// Writes to standard config location:
-/// - Linux/MacOS: ~/query_gguf/query_gguf_config.toml
-/// - Windows: \Users\username\query_gguf\query_gguf_config.toml
-/// 
-/// This function:
-/// 1. Reads existing configuration
-/// 2. Counts existing modes
-/// 3. Optionally sets as default mode
-/// 4. Formats and appends new mode entry
-/// 5. Saves updated configuration
-/// 
+/// The table name of a mode's `[mode.<name>]` table: its id, or the slug of its name
+fn mode_table_key(mode: &ChatModeConfig) -> String {
+    if !mode.id.is_empty() {
+        return mode.id.clone();
+    }
+    let slug = slugify_mode_name(&mode.name);
+    if slug.is_empty() { "mode".to_string() } else { slug }
+}
+
+/// Formats a mode as a `[mode.<id>]` config table
+///
+/// # Format
+/// ```toml
+/// [mode.<id>]
+/// name = "name"
+/// description = "description"
+/// model = "model_path"
+/// prompt = "prompt_path"
+/// temp = 0.8
+/// ...
+/// ```
+fn format_mode_entry(mode: &ChatModeConfig) -> String {
+    let quoted = |text: &str| toml_lite::Value::String(text.to_string()).to_string();
+    let mut new_mode_entry = format!("\n[mode.{}]\n", toml_lite::format_key(&mode_table_key(mode)));
+    new_mode_entry.push_str(&format!("name = {}\n", quoted(&mode.name)));
+    new_mode_entry.push_str(&format!("description = {}\n", quoted(&mode.description)));
+    new_mode_entry.push_str(&format!("model = {}\n", quoted(&mode.model_path)));
+    new_mode_entry.push_str(&format!("prompt = {}\n", quoted(&mode.prompt_path)));
+
+    // Parameters
+    let mut settings: Vec<(&str, String)> = vec![
+        ("temp", mode.parameters.temperature_value.to_string()),
+        ("top_k", mode.parameters.top_k_sampling.to_string()),
+        ("top_p", mode.parameters.top_p_sampling.to_string()),
+        ("ctx_size", mode.parameters.context_size.to_string()),
+        ("threads", mode.parameters.thread_count.to_string()),
+        ("gpu_layers", mode.parameters.gpu_layers.to_string()),
+        ("interactive_first", mode.parameters.interactive_first.to_string()),
+    ];
+    for (key, enabled) in [
+        ("display_prompt", mode.parameters.display_prompt),
+        ("verbose_prompt", mode.parameters.verbose_prompt),
+        ("log_disable", mode.parameters.log_disable),
+        ("multiline_input", mode.parameters.multiline_input),
+    ] {
+        if enabled {
+            settings.push((key, "true".to_string()));
+        }
+    }
+    for (key, text) in [
+        ("in_prefix", &mode.parameters.in_prefix),
+        ("in_suffix", &mode.parameters.in_suffix),
+    ] {
+        if !text.is_empty() {
+            settings.push((key, text.clone()));
+        }
+    }
+    if let Some(conversation) = mode.parameters.conversation {
+        settings.push(("conversation", conversation.to_string()));
+    }
+    if let Some(max_prompt_bytes) = mode.parameters.max_prompt_bytes {
+        settings.push(("max_prompt_bytes", max_prompt_bytes.to_string()));
+    }
+    if let Some(max_files) = mode.parameters.max_files {
+        settings.push(("max_files", max_files.to_string()));
+    }
+    if let Some(image_steps) = mode.parameters.image_steps {
+        settings.push(("steps", image_steps.to_string()));
+    }
+    if let Some(cfg_scale) = mode.parameters.cfg_scale {
+        settings.push(("cfg_scale", cfg_scale.to_string()));
+    }
+    if let Some(min_free_ram_gb) = mode.parameters.min_free_ram_gb {
+        settings.push(("min_free_ram_gb", min_free_ram_gb.to_string()));
+    }
+    for (key, text) in [
+        ("output_dir", &mode.parameters.output_dir),
+        ("only_if", &mode.parameters.only_if),
+        ("hours", &mode.parameters.hours),
+        ("tests", &mode.parameters.tests),
+        ("disabled", &mode.parameters.disabled),
+        // llama.cpp build, remote host, OpenAI-compatible endpoint, parent mode, and kind (text modes leave it out)
+        ("backend", &mode.backend),
+        ("remote", &mode.remote),
+        ("endpoint", &mode.endpoint),
+        ("extends", &mode.extends),
+        ("kind", &mode.kind),
+    ] {
+        if !text.is_empty() {
+            settings.push((key, text.clone()));
+        }
+    }
+
+    for (key, value) in settings {
+        let value = match key {
+            // Always text, even when it looks like a number
+            "in_prefix" | "in_suffix" | "hours" | "tests" | "disabled" => quoted(&value),
+            _ => format_mode_table_value(&value),
+        };
+        new_mode_entry.push_str(&format!("{} = {}\n", key, value));
+    }
+
+    new_mode_entry
+}
+
+/// Splits config content into the top level part and the [tables] part
+///
+/// The second string starts at the first [table] header (and is empty if
+/// there is none), so `top + tables` always gives back the original content.
+fn split_config_tables(content: &str) -> (String, String) {
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        if line.trim_start().starts_with('[') {
+            let (top, tables) = content.split_at(offset);
+            return (top.to_string(), tables.to_string());
+        }
+        offset += line.len();
+    }
+    (content.to_string(), String::new())
+}
+
+/// Conditions recognized in a mode's only_if=
+const KNOWN_MODE_CONDITIONS: &[&str] = &["on_ac_power"];
+
+/// What the machine looks like now, for checking mode conditions
+///
+/// Each field is None when it could not be found out on this system;
+/// conditions on unknown state are treated as met.
+#[derive(Debug, Default)]
+struct MachineState {
+    on_ac_power: Option<bool>,
+    minutes_of_day: Option<u32>, // local time, minutes since midnight
+    free_ram_gb: Option<f64>,
+}
+
+impl MachineState {
+    /// Looks up power source, local time, and free memory
+    fn current() -> MachineState {
+        MachineState {
+            on_ac_power: detect_ac_power(),
+            minutes_of_day: local_minutes_of_day(),
+            free_ram_gb: detect_free_ram_gb(),
+        }
+    }
+}
+
+/// Returns true if the mode has any launch conditions
+fn has_mode_conditions(params: &LlamaCppParameters) -> bool {
+    !params.only_if.is_empty() || !params.hours.is_empty() || params.min_free_ram_gb.is_some() || !params.disabled.is_empty()
+}
+
+/// Parses "HH:MM-HH:MM" ranges (comma separated) into minutes since midnight
+///
+/// A range may wrap past midnight, e.g. "22:00-06:00".
+fn parse_hours_ranges(hours: &str) -> Option<Vec<(u32, u32)>> {
+    let parse_time = |time: &str| -> Option<u32> {
+        let (hour, minute) = time.trim().split_once(':')?;
+        let (hour, minute) = (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?);
+        (hour <= 24 && minute < 60 && hour * 60 + minute <= 24 * 60).then_some(hour * 60 + minute)
+    };
+    let ranges: Option<Vec<(u32, u32)>> = hours.split(',')
+        .map(|range| {
+            let (start, end) = range.split_once('-')?;
+            Some((parse_time(start)?, parse_time(end)?))
+        })
+        .collect();
+    ranges.filter(|ranges| !ranges.is_empty())
+}
+
+/// Explains the first launch condition of a mode that is not met, if any
+///
 /// # Arguments
-/// * `mode` - ChatModeConfig containing all mode settings
-/// 
+/// * `params` - Mode parameters holding disabled, only_if, hours, and min_free_ram_gb
+/// * `state` - Current machine state
+///
 /// # Returns
-/// - Ok(()): Mode saved successfully
-/// - Err(String): Error message if save fails
-/// 
-/// # Format
-/// Saves modes in format:
-/// ```toml
-/// # Mode N - name - description
-/// mode_N = "model_path|prompt_path|params...|name|description"
-/// ```
-/// 
-/// # Error Cases
-/// - Config file not found
-/// - Permission denied
-/// - Disk full
-/// - IO errors
-fn save_mode_to_config(mode: &ChatModeConfig) -> Result<(), String> {
-    // let config_path = "query_gguf_config.toml";
-    let config_path = get_config_path()?;
-    
-    // Read existing config
-    // let mut config_content = fs::read_to_string(config_path)
-    //     .map_err(|e| format!("Failed to read config: {}", e))?;
-    let mut config_content = fs::read_to_string(&config_path)
-        .map_err(|e| format!("Failed to read config at {}: {}", config_path.display(), e))?;
-    
-    // Count existing modes
-    let mode_count = config_content.lines()
-        .filter(|line| line.starts_with("mode_"))
-        .count();
-    let new_mode_num = mode_count + 1;
+/// - Some(String): Why the mode cannot launch now
+/// - None: All conditions met (or not checkable here)
+fn unmet_mode_condition(params: &LlamaCppParameters, state: &MachineState) -> Option<String> {
+    if !params.disabled.is_empty() {
+        return Some(format!("disabled: {}", params.disabled));
+    }
+    if params.only_if == "on_ac_power" && state.on_ac_power == Some(false) {
+        return Some("only_if=on_ac_power, but running on battery".to_string());
+    }
+    if let (Some(ranges), Some(now)) = (parse_hours_ranges(&params.hours), state.minutes_of_day) {
+        let in_range = ranges.iter().any(|&(start, end)| {
+            if start <= end { now >= start && now < end } else { now >= start || now < end }
+        });
+        if !in_range {
+            return Some(format!("hours={}, and it is {:02}:{:02}", params.hours, now / 60, now % 60));
+        }
+    }
+    if let (Some(needed), Some(free)) = (params.min_free_ram_gb, state.free_ram_gb) {
+        if free < needed {
+            return Some(format!("min_free_ram_gb={}, but {:.1} GB is free", needed, free));
+        }
+    }
+    None
+}
 
-    // Ask if this should be the default mode
-    if prompt_yes_no("Would you like to make this the default mode?")? {
-        // Remove existing default_mode line if it exists
-        config_content = config_content.lines()
-            .filter(|line| !line.starts_with("default_mode"))
-            .collect::<Vec<&str>>()
-            .join("\n");
-        
-        // Add new default_mode line
-        config_content.push_str(&format!("\ndefault_mode = {}\n", new_mode_num));
+/// Returns whether the machine runs on AC power (None if unknown)
+///
+/// Machines without a battery count as on AC power.
+fn detect_ac_power() -> Option<bool> {
+    if cfg!(target_os = "macos") {
+        let output = Command::new("pmset").args(["-g", "batt"]).output().ok()?;
+        return Some(String::from_utf8_lossy(&output.stdout).contains("AC Power"));
     }
-    
-    // Format new mode entry with comment showing name and description
-    let mut new_mode_entry = format!("\n# Mode {} - {} - {}\n", 
-        new_mode_num, 
-        mode.name,
-        mode.description
-    );
-    
-    // Start the mode entry with the model path and prompt path (now always present)
-    new_mode_entry.push_str(&format!("mode_{} = \"{}|{}",
-        new_mode_num, 
-        mode.model_path,
-        mode.prompt_path
-    ));
-    
-    // Add parameters
-    new_mode_entry.push_str(&format!("|temp={}|top_k={}|top_p={}|ctx_size={}|threads={}|gpu_layers={}|interactive_first={}",
-        mode.parameters.temperature_value,
-        mode.parameters.top_k_sampling,
-        mode.parameters.top_p_sampling,
-        mode.parameters.context_size,
-        mode.parameters.thread_count,
-        mode.parameters.gpu_layers,
-        mode.parameters.interactive_first,
-    ));
-    
-    // Add name and description at the end
-    new_mode_entry.push_str(&format!("|{}|{}\"\n", mode.name, mode.description));
-
-    // Append to config file
-    config_content.push_str(&new_mode_entry);
-    // fs::write(config_path, config_content)
-    //     .map_err(|e| format!("Failed to write config: {}", e))?;
-    fs::write(&config_path, config_content)
-        .map_err(|e| format!("Failed to write config to {}: {}", config_path.display(), e))?;
-    Ok(())
+    let supplies = fs::read_dir("/sys/class/power_supply").ok()?;
+    let mut has_battery = false;
+    let mut adapters_online: Vec<bool> = Vec::new();
+    for supply in supplies.flatten() {
+        let read = |name: &str| fs::read_to_string(supply.path().join(name)).unwrap_or_default().trim().to_string();
+        match read("type").as_str() {
+            "Mains" => adapters_online.push(read("online") == "1"),
+            "Battery" => has_battery = true,
+            _ => (),
+        }
+    }
+    if !adapters_online.is_empty() {
+        Some(adapters_online.contains(&true))
+    } else {
+        Some(!has_battery)
+    }
+}
+
+/// Returns the local time as minutes since midnight, from the `date` command
+fn local_minutes_of_day() -> Option<u32> {
+    let output = if cfg!(windows) {
+        Command::new("powershell").args(["-NoProfile", "-Command", "Get-Date -Format HH:mm"]).output().ok()?
+    } else {
+        Command::new("date").arg("+%H:%M").output().ok()?
+    };
+    let time = String::from_utf8_lossy(&output.stdout);
+    let (hour, minute) = time.trim().split_once(':')?;
+    Some(hour.parse::<u32>().ok()? * 60 + minute.parse::<u32>().ok()?)
+}
+
+/// Returns the memory available for new programs in GB (None if unknown)
+fn detect_free_ram_gb() -> Option<f64> {
+    const BYTES_PER_GB: f64 = 1024.0 * 1024.0 * 1024.0;
+    if cfg!(target_os = "macos") {
+        // Free, inactive, and speculative pages can be given to a new program
+        let output = Command::new("vm_stat").output().ok()?;
+        let text = String::from_utf8_lossy(&output.stdout);
+        let page_size = text.split("page size of ").nth(1)?
+            .split_whitespace().next()?.parse::<f64>().ok()?;
+        let pages: f64 = text.lines()
+            .filter(|line| ["Pages free:", "Pages inactive:", "Pages speculative:"].iter().any(|label| line.starts_with(label)))
+            .filter_map(|line| line.split(':').nth(1)?.trim().trim_end_matches('.').parse::<f64>().ok())
+            .sum();
+        return Some(pages * page_size / BYTES_PER_GB);
+    }
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let kilobytes = meminfo.lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .split_whitespace().next()?.parse::<f64>().ok()?;
+    Some(kilobytes * 1024.0 / BYTES_PER_GB)
 }
 
 /// Displays the available modes in a simplified format
 fn display_available_modes() {
+    use std::io::IsTerminal;
     println!("\nSelect a mode number or type a command:");
     // println!("Commands:");
     println!("  'make' or 'manual'   -> Create a new mode.");
     println!("  'dir' or 'directory' -> Add project directory files to any mode prompt.");
     println!("  'config'             -> Open the config file in editor.");
+    println!("  'fav <mode>'         -> Star/unstar a mode (favorites are listed first).");
 
     println!("\nAvailable Modes:");
     match read_saved_modes() {
         Ok(modes) => {
-            // println!("\n");
-            for (index, mode) in modes.iter().enumerate() {
-                println!("{}. {} - {}", 
+            // Favorites are listed first but keep their numbers,
+            // so `query_gguf <number>` always means the same mode
+            let favorites = read_state_list(FAVORITE_MODES_FIELD);
+            let mode_ids: Vec<String> = modes.iter().map(|mode| mode.id.clone()).collect();
+            // Modes whose conditions are not met now are grayed out
+            let machine_state = if modes.iter().any(|mode| has_mode_conditions(&mode.parameters)) {
+                MachineState::current()
+            } else {
+                MachineState::default()
+            };
+            let color = io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+            for index in order_favorites_first(&mode_ids, &favorites) {
+                let mode = &modes[index];
+                let star = if favorites.contains(&mode.id) { "★ " } else { "" };
+                let line = format!("{}. {}{} - {} [{}]", 
                     index + 1, 
+                    star,
                     mode.name,        // Display the actual name
-                    mode.description  // Display the actual description
+                    mode.description, // Display the actual description
+                    mode.id           // Stable id, usable instead of the number
                 );
+                match unmet_mode_condition(&mode.parameters, &machine_state) {
+                    Some(reason) if color => println!("{}{} (unavailable: {}){}", STYLE_COMMENT, line, reason, STYLE_RESET),
+                    Some(reason) => println!("{} (unavailable: {})", line, reason),
+                    None => println!("{}", line),
+                }
             }
         }
         Err(e) => {
@@ -2288,6 +17149,10 @@ fn display_available_modes() {
 /// - Linux/MacOS: ~/query_gguf/query_gguf_config.toml
 /// - Windows: \Users\username\query_gguf\query_gguf_config.toml
 /// 
+/// The config is backed up first. After the editor closes, it is checked
+/// again, and problems the edit added (with line numbers) are reported with
+/// the choice to edit again, revert to the backup, or keep the edit.
+///
 /// # Returns
 /// - Ok(()): Editor opened and config edited successfully
 /// - Err(String): Error message if:
@@ -2306,6 +17171,7 @@ fn display_available_modes() {
 /// - Insufficient permissions
 /// - Process spawn failure
 fn open_config_in_editor() -> Result<(), String> {
+    use std::io::IsTerminal;
     // Get absolute path to config file
     let config_path = get_config_path()?;
     
@@ -2314,33 +17180,556 @@ fn open_config_in_editor() -> Result<(), String> {
         return Err(format!("Configuration file not found at: {}", config_path.display()));
     }
 
-    // Select appropriate default editor based on platform
-    let default_editor = if cfg!(windows) {
-        "notepad"
-    } else {
-        "nano"
-    };
+    let editor = get_text_editor();
+
+    println!("Opening config with editor: {}", editor);
+    println!("Config path: {}", config_path.display());
+    let content_before = fs::read_to_string(&config_path).unwrap_or_default();
+    let backup_path = backup_existing_config()?;
+
+    loop {
+        let content_previous = fs::read_to_string(&config_path).unwrap_or_default();
+
+        // Launch editor with absolute config path
+        let status = editor_command(&editor, &config_path)
+            .spawn()
+            .map_err(|e| format!("Failed to launch editor '{}': {}", editor, e))?
+            .wait()
+            .map_err(|e| format!("Error while editing with '{}': {}", editor, e))?;
+
+        // Check if editor exited successfully
+        if !status.success() {
+            return Err(format!("Editor '{}' exited with error status", editor));
+        }
+
+        let content_after = fs::read_to_string(&config_path).unwrap_or_default();
+        record_config_changes(&format!("edited with {}", editor), &content_previous, &content_after);
+
+        let problems = new_config_problems(&content_before, &content_after);
+        if problems.is_empty() {
+            println!("Configuration file edited successfully");
+            return Ok(());
+        }
+
+        println!("\nThe edit added {} problem(s):\n  {}", problems.len(), problems.join("\n  "));
+        let Some(backup_path) = &backup_path else {
+            return Ok(());
+        };
+        if !io::stdin().is_terminal() {
+            println!("Warning: Keeping the edit. The config before it is in {}", backup_path.display());
+            return Ok(());
+        }
+        print!("[e]dit again, [r]evert to the backup, or [k]eep the edit? ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        match read_user_input()?.trim().to_lowercase().as_str() {
+            "" | "e" | "edit" => continue,
+            "r" | "revert" => {
+                write_config_file(&config_path, &content_before, "reverted an edit")?;
+                println!("Reverted the config to {}", backup_path.display());
+                return Ok(());
+            },
+            _ => {
+                println!("Keeping the edit. The config before it is in {}", backup_path.display());
+                return Ok(());
+            },
+        }
+    }
+}
+
+/// Returns the text editor to use: the `editor` config key, $EDITOR, or notepad (Windows) / nano
+fn get_text_editor() -> String {
+    // Select appropriate default editor based on platform
+    let default_editor = if cfg!(windows) {
+        "notepad"
+    } else {
+        "nano"
+    };
+
+    // Prefer the editor picked during setup, then the environment
+    let configured_editor = read_field_from_toml("editor");
+    if !configured_editor.trim().is_empty() {
+        return configured_editor;
+    }
+    std::env::var("EDITOR").unwrap_or_else(|_| default_editor.to_string())
+}
+
+/// Builds the command that opens a file in the editor (editors may carry arguments, e.g. `code --wait`)
+fn editor_command(editor: &str, file_path: &Path) -> Command {
+    let words = split_command_line(editor);
+    let mut command = Command::new(words.first().map(String::as_str).unwrap_or(editor));
+    command.args(words.iter().skip(1)).arg(file_path.as_os_str());
+    command
+}
+
+/// Lets the user edit some text in their editor, via a temporary file
+///
+/// # Returns
+/// - Ok(String): The text as saved by the editor
+/// - Err(String): Editor could not run or exited with an error
+fn edit_text_in_editor(text: &str) -> Result<String, String> {
+    let edit_path = get_temp_dir()?.join(format!("edit_{}.txt", std::process::id()));
+    fs::write(&edit_path, text)
+        .map_err(|e| format!("Failed to write {}: {}", edit_path.display(), e))?;
+
+    let editor = get_text_editor();
+    let status = editor_command(&editor, &edit_path)
+        .status()
+        .map_err(|e| format!("Failed to launch editor '{}': {}", editor, e));
+    let edited = fs::read_to_string(&edit_path)
+        .map_err(|e| format!("Failed to read {}: {}", edit_path.display(), e));
+    let _ = fs::remove_file(&edit_path);
+
+    if !status?.success() {
+        return Err(format!("Editor '{}' exited with error status", editor));
+    }
+    edited
+}
+
+/// Returns the pager to use: $PAGER, or more (Windows) / less -R
+fn get_pager() -> String {
+    let default_pager = if cfg!(windows) { "more" } else { "less -R" };
+    std::env::var("PAGER").ok().filter(|pager| !pager.trim().is_empty()).unwrap_or_else(|| default_pager.to_string())
+}
+
+/// Shows text in the pager, or prints it if the pager cannot run
+fn show_in_pager(text: &str) {
+    let pager = get_pager();
+    let mut words = split_command_line(&pager);
+    if words.is_empty() {
+        println!("{}", text);
+        return;
+    }
+    let program = words.remove(0);
+    let mut command = Command::new(&program);
+    command.args(&words).stdin(std::process::Stdio::piped());
+    // Like git: let less pass colors through unless $LESS says otherwise
+    if std::env::var_os("LESS").is_none() {
+        command.env("LESS", "R");
+    }
+    let shown = command
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(mut stdin) = child.stdin.take() {
+                // The pager may be quit before reading everything
+                let _ = stdin.write_all(text.as_bytes());
+            }
+            child.wait()
+        });
+    if shown.is_err() {
+        println!("{}", text);
+    }
+}
+
+/// Turns a markdown reply into text for reading aloud
+///
+/// Fenced code blocks are replaced by a short note, and markdown markers
+/// (headings, quotes, emphasis, inline code) are dropped.
+fn text_for_speech(reply: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut in_code_block = false;
+    for line in reply.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            if !in_code_block {
+                lines.push("Code block omitted.".to_string());
+            }
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            continue;
+        }
+        let text = trimmed.trim_start_matches(['#', '>']).trim_start();
+        let text: String = text.chars().filter(|c| !matches!(c, '*' | '`' | '_')).collect();
+        if !text.trim().is_empty() {
+            lines.push(text);
+        }
+    }
+    lines.join("\n")
+}
+
+/// Reads a completed response aloud with the configured `tts_command`
+///
+/// The text is written to the command's stdin (e.g. `piper --model voice.onnx
+/// --output-raw | aplay` via a wrapper script); no shell is involved.
+/// Does nothing when `tts_command` is not set.
+///
+/// # Returns
+/// - Ok(()): Spoken, or no TTS command configured
+/// - Err(String): The command failed to start or exited with an error
+fn speak_text(reply: &str) -> Result<(), String> {
+    let tts_command = read_field_from_toml("tts_command");
+    let mut words = split_command_line(&tts_command);
+    if words.is_empty() {
+        return Ok(());
+    }
+    let text = text_for_speech(reply);
+    if text.trim().is_empty() {
+        return Ok(());
+    }
+    let program = words.remove(0);
+    let mut child = Command::new(&program)
+        .args(&words)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Failed to run tts_command '{}': {}", program, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())
+            .map_err(|e| format!("Failed to send text to tts_command '{}': {}", program, e))?;
+    }
+    let status = child.wait()
+        .map_err(|e| format!("Failed to wait for tts_command '{}': {}", program, e))?;
+    if !status.success() {
+        return Err(format!("tts_command '{}' exited with {}", program, status));
+    }
+    Ok(())
+}
+
+/// Rough token count of text (about 4 characters per token for English and code)
+fn estimate_token_count(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Shows the fully rendered prompt before launch when `confirm_prompt = true`
+///
+/// The prompt (placeholders filled in, directory content, context, and
+/// question included) is shown in the pager with its size and a token
+/// estimate. Approving launches it; editing opens a copy in the editor
+/// (the mode's own prompt file is never changed) and shows it again.
+///
+/// # Arguments
+/// * `mode` - Mode about to launch; its prompt_path is replaced by the edited copy
+///
+/// # Returns
+/// - Ok(()): Approved (or confirm_prompt is off, or stdin is not a terminal to ask on)
+/// - Err(String): Cancelled, or the prompt could not be read or edited
+fn confirm_rendered_prompt(mode: &mut ChatModeConfig) -> Result<(), String> {
+    use std::io::IsTerminal;
+    if read_config_bool("confirm_prompt") != Some(true) {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() {
+        println!("Warning: confirm_prompt is on, but stdin is not a terminal; sending the prompt unconfirmed");
+        return Ok(());
+    }
+
+    loop {
+        let text = fs::read_to_string(&mode.prompt_path)
+            .map_err(|e| format!("Failed to read prompt {}: {}", mode.prompt_path, e))?;
+        show_in_pager(&text);
+
+        let tokens = estimate_token_count(&text);
+        println!(
+            "\nPrompt: {} lines, {} characters, about {} tokens (ctx_size {})",
+            text.lines().count(), text.chars().count(), tokens, mode.parameters.context_size
+        );
+        if tokens as i32 >= mode.parameters.context_size {
+            println!("Warning: the prompt probably does not fit in the context window");
+        }
+
+        print!("[a]pprove, [e]dit, or [c]ancel? ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        match read_user_input()?.trim().to_lowercase().as_str() {
+            "" | "a" | "approve" | "y" | "yes" => return Ok(()),
+            "e" | "edit" => {
+                let edited = edit_text_in_editor(&text)?;
+                let edited_path = get_temp_dir()?.join(format!("confirmed_prompt_{}.txt", generate_timestamp_string()));
+                fs::write(&edited_path, edited)
+                    .map_err(|e| format!("Failed to write {}: {}", edited_path.display(), e))?;
+                mode.prompt_path = edited_path.to_string_lossy().to_string();
+            },
+            "c" | "cancel" | "n" | "no" | "q" => return Err("Launch cancelled at the prompt preview".to_string()),
+            _ => println!("Please enter a, e, or c"),
+        }
+    }
+}
+
+/// Old config key names and the names that replaced them
+///
+/// Numbered keys are matched by base name, e.g. prompt_file_directory_2
+/// becomes prompt_directory_2.
+const DEPRECATED_CONFIG_KEYS: &[(&str, &str)] = &[
+    ("llama_cpp_directory", "llama_cli_path"),
+    ("log_directory", "log_directory_path"),
+    ("prompt_file_directory", "prompt_directory"),
+];
+
+/// Returns the current name for a possibly deprecated config key
+fn rename_deprecated_config_key(key: &str) -> Option<String> {
+    for (old, new) in DEPRECATED_CONFIG_KEYS {
+        if key == *old {
+            return Some(new.to_string());
+        }
+        if let Some(num) = key.strip_prefix(old).and_then(|rest| rest.strip_prefix('_')) {
+            if num.parse::<usize>().is_ok() {
+                return Some(format!("{}_{}", new, num));
+            }
+        }
+    }
+    None
+}
+
+/// Returns true for config keys whose value is a file system path
+fn is_path_config_key(key: &str) -> bool {
+    key == "llama_cli_path"
+        || key == "log_directory_path"
+        || key == "prompt_directory"
+        || key == "llama_server_path"
+        || key == "ca_bundle_path"
+        || key == "agent_file_root"
+        || key == "model_archive_directory"
+        || key.starts_with("gguf_model_directory_")
+        || key.starts_with("prompt_directory_")
+}
+
+/// Normalizes a path value from the config: expands a leading ~ and removes trailing slashes
+fn normalize_config_path_value(value: &str, home_dir: &str) -> String {
+    let expanded = match value.strip_prefix('~') {
+        Some(rest) => format!("{}{}", home_dir, rest),
+        None => value.to_string(),
+    };
+    let trimmed = expanded.trim_end_matches('/');
+    if trimmed.is_empty() && expanded.starts_with('/') {
+        "/".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// Normalizes the model and prompt paths inside a mode entry value
+fn normalize_mode_entry_paths(value: &str, home_dir: &str) -> String {
+    value.split('|')
+        .enumerate()
+        .map(|(index, part)| {
+            if index < 2 && !part.contains('=') {
+                normalize_config_path_value(part, home_dir)
+            } else {
+                part.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("|")
+}
+
+/// Lints config content and returns the fixed content and a list of changes
+///
+/// Fixes applied:
+/// - deprecated key names are renamed
+/// - path values have ~ expanded and trailing slashes removed
+/// - duplicate keys are removed (first occurrence is kept)
+/// - mode entries are sorted, renumbered 1..N, and moved to the end with
+///   regenerated "# Mode N - name - description" comments
+/// - a numeric default_mode is updated to follow its mode's new number
+///
+/// # Arguments
+/// * `content` - Full text of the config file
+/// * `home_dir` - Home directory used to expand ~
+///
+/// # Returns
+/// * `(String, Vec<String>)` - Fixed content, and one description per change
+fn lint_config_content(content: &str, home_dir: &str) -> (String, Vec<String>) {
+    let mut changes = Vec::new();
+    let mut kept_lines: Vec<String> = Vec::new();
+    let mut seen_keys: Vec<String> = Vec::new();
+    // (original mode number, value)
+    let mut mode_entries: Vec<(usize, String)> = Vec::new();
+
+    // [tables] are kept as they are, after the (top level) mode entries
+    let mut table_lines: Vec<&str> = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        let trimmed = line.trim();
+
+        if !table_lines.is_empty() || trimmed.starts_with('[') {
+            table_lines.push(line);
+            continue;
+        }
+
+        // Mode comments are regenerated below
+        let is_mode_comment = trimmed.strip_prefix("# Mode ")
+            .map(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+            .unwrap_or(false);
+        if is_mode_comment {
+            continue;
+        }
+
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            // Collapse runs of blank lines
+            if trimmed.is_empty() && kept_lines.last().map(|l| l.trim().is_empty()).unwrap_or(true) {
+                continue;
+            }
+            kept_lines.push(line.to_string());
+            continue;
+        }
+
+        let (raw_key, raw_value) = match trimmed.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => {
+                kept_lines.push(line.to_string());
+                continue;
+            }
+        };
+        let quoted = raw_value.starts_with('"');
+        let value = raw_value.trim_matches('"').trim();
+
+        let key = match rename_deprecated_config_key(raw_key) {
+            Some(new_key) => {
+                changes.push(format!("line {}: renamed deprecated key '{}' to '{}'", line_number, raw_key, new_key));
+                new_key
+            },
+            None => raw_key.to_string(),
+        };
+
+        if let Some(num) = key.strip_prefix("mode_").and_then(|n| n.parse::<usize>().ok()) {
+            let normalized = normalize_mode_entry_paths(value, home_dir);
+            if normalized != value {
+                changes.push(format!("line {}: normalized paths in {}", line_number, key));
+            }
+            if mode_entries.iter().any(|(_, existing)| *existing == normalized) {
+                changes.push(format!("line {}: removed duplicate entry {}", line_number, key));
+                continue;
+            }
+            mode_entries.push((num, normalized));
+            continue;
+        }
+
+        if seen_keys.contains(&key) {
+            changes.push(format!("line {}: removed duplicate key '{}'", line_number, key));
+            continue;
+        }
+        seen_keys.push(key.clone());
+
+        let new_value = if is_path_config_key(&key) {
+            let normalized = normalize_config_path_value(value, home_dir);
+            if normalized != value {
+                changes.push(format!("line {}: normalized path for '{}': {} -> {}", line_number, key, value, normalized));
+            }
+            normalized
+        } else {
+            value.to_string()
+        };
+
+        if key == raw_key && new_value == value {
+            kept_lines.push(line.to_string());
+        } else if quoted {
+            kept_lines.push(format!("{} = \"{}\"", key, new_value));
+        } else {
+            kept_lines.push(format!("{} = {}", key, new_value));
+        }
+    }
+
+    // Sort (stable, so duplicate numbers keep file order) and renumber modes
+    let original_order: Vec<usize> = mode_entries.iter().map(|(num, _)| *num).collect();
+    mode_entries.sort_by_key(|(num, _)| *num);
+    let mut number_map: Vec<(usize, usize)> = Vec::new();
+    for (new_index, (old_num, _)) in mode_entries.iter().enumerate() {
+        let new_num = new_index + 1;
+        if *old_num != new_num {
+            changes.push(format!("renumbered mode_{} to mode_{}", old_num, new_num));
+        }
+        if !number_map.iter().any(|(old, _)| old == old_num) {
+            number_map.push((*old_num, new_num));
+        }
+    }
+    let sorted_order: Vec<usize> = mode_entries.iter().map(|(num, _)| *num).collect();
+    if original_order != sorted_order {
+        changes.push("sorted mode entries by number".to_string());
+    }
+
+    // Keep a numeric default_mode pointing at the same mode
+    for line in kept_lines.iter_mut() {
+        if let Some((key, value)) = line.split_once('=') {
+            if key.trim() != "default_mode" {
+                continue;
+            }
+            let value = value.trim().trim_matches('"');
+            if let Ok(old_num) = value.parse::<usize>() {
+                if let Some((_, new_num)) = number_map.iter().find(|(old, _)| *old == old_num) {
+                    if *new_num != old_num {
+                        changes.push(format!("updated default_mode from {} to {}", old_num, new_num));
+                        *line = format!("default_mode = {}", new_num);
+                    }
+                }
+            }
+        }
+    }
+
+    while kept_lines.last().map(|l| l.trim().is_empty()).unwrap_or(false) {
+        kept_lines.pop();
+    }
+
+    let mut output = kept_lines.join("\n");
+    output.push('\n');
+    for (index, (_, value)) in mode_entries.iter().enumerate() {
+        let non_param_parts: Vec<&str> = value.split('|')
+            .filter(|part| !part.contains('='))
+            .collect();
+        let (name, description) = if non_param_parts.len() >= 4 {
+            (non_param_parts[non_param_parts.len() - 2], non_param_parts[non_param_parts.len() - 1])
+        } else {
+            ("", "")
+        };
+        output.push_str(&format!("\n# Mode {} - {} - {}\n", index + 1, name, description));
+        output.push_str(&format!("mode_{} = \"{}\"\n", index + 1, value));
+    }
+
+    if !table_lines.is_empty() {
+        output.push('\n');
+        output.push_str(&table_lines.join("\n"));
+        output.push('\n');
+    }
+
+    (output, changes)
+}
+
+/// Lints the config file, optionally fixing it in place
+///
+/// Prints every change that was (or would be) made. With `fix`, the
+/// current config is backed up first, then overwritten with the fixed version.
+/// Problems that cannot be fixed automatically (e.g. unknown keys) are
+/// reported using the strict config check.
+///
+/// # Arguments
+/// * `fix` - Write the fixed config back to disk
+///
+/// # Returns
+/// - Ok(()): Lint completed
+/// - Err(String): Config could not be read, backed up, or written
+fn handle_config_lint(fix: bool) -> Result<(), String> {
+    let config_path = get_config_path()?;
+    let content = fs::read_to_string(&config_path)
+        .map_err(|e| format!("Failed to read config at {}: {}", config_path.display(), e))?;
+    let home_dir = get_home_dir()?;
+
+    let (fixed_content, changes) = lint_config_content(&content, &home_dir);
 
-    // Get editor from environment or use default
-    let editor = std::env::var("EDITOR").unwrap_or_else(|_| default_editor.to_string());
+    if changes.is_empty() {
+        println!("Config lint: no fixable problems found in {}", config_path.display());
+    } else {
+        println!("Config lint: {} change(s) {}:", changes.len(), if fix { "made" } else { "suggested" });
+        for change in &changes {
+            println!("  - {}", change);
+        }
+    }
 
-    println!("Opening config with editor: {}", editor);
-    println!("Config path: {}", config_path.display());
+    if let Err(remaining) = validate_config_strict(&fixed_content) {
+        println!("\nProblems that need manual attention:\n{}", remaining);
+    }
 
-    // Launch editor with absolute config path
-    let status = Command::new(&editor)
-        .arg(config_path.as_os_str())
-        .spawn()
-        .map_err(|e| format!("Failed to launch editor '{}': {}", editor, e))?
-        .wait()
-        .map_err(|e| format!("Error while editing with '{}': {}", editor, e))?;
+    if changes.is_empty() {
+        return Ok(());
+    }
 
-    // Check if editor exited successfully
-    if !status.success() {
-        return Err(format!("Editor '{}' exited with error status", editor));
+    if fix {
+        backup_existing_config()?;
+        write_config_file(&config_path, &fixed_content, "config lint --fix")?;
+        println!("Config updated: {}", config_path.display());
+    } else {
+        println!("\nRun 'query_gguf config lint --fix' to apply these changes.");
     }
 
-    println!("Configuration file edited successfully");
     Ok(())
 }
 
@@ -2354,6 +17743,191 @@ fn open_config_in_editor() -> Result<(), String> {
 struct DirectoryScan {
     tree_structure: String,
     file_contents: String,
+    file_count: usize, // files whose contents (or outlines) are included
+}
+
+/// How much of a directory `scan_directory` includes
+#[derive(Debug, Clone, Copy, Default)]
+struct ScanOptions {
+    outline: bool,            // only signatures/outlines of files with an extractor (see `extract_outline`)
+    tree_only: bool,          // only the tree, no file contents
+    max_depth: Option<usize>, // levels of directories to scan (1 = only the top level), None for all
+}
+
+/// Names directory mode never enters, besides those in `scan_exclude`
+const DEFAULT_SCAN_EXCLUDES: &[&str] = &[".git", "target", "node_modules", "__pycache__", ".venv"];
+
+/// Ignore files read in each scanned directory, in .gitignore syntax
+const SCAN_IGNORE_FILES: &[&str] = &[".gitignore", ".query_gguf_ignore"];
+
+/// Per-file limit for directory mode when `scan_max_file_kb` is not set
+const DEFAULT_SCAN_MAX_FILE_KB: u64 = 256;
+
+/// Limit of included file contents for directory mode when `scan_max_total_kb` is not set
+const DEFAULT_SCAN_MAX_TOTAL_KB: u64 = 1024;
+
+/// One pattern line of a .gitignore-style file
+#[derive(Debug, Clone)]
+struct IgnoreRule {
+    pattern: String,  // glob, without the leading `!`, `/`, or trailing `/`
+    base: PathBuf,    // directory of the ignore file; anchored patterns are relative to it
+    negated: bool,    // `!pattern`: includes again what an earlier rule ignored
+    anchored: bool,   // contains a `/`, so it matches the path from `base`, not just the name
+    dir_only: bool,   // `pattern/`: only matches directories
+}
+
+/// Parses a .gitignore-style file: `#` comments, `!` negation, `/` anchoring, `*`, `?`, and `**`
+fn parse_ignore_rules(content: &str, base: &Path) -> Vec<IgnoreRule> {
+    content.lines()
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let anchored = line.contains('/');
+            let pattern = line.trim_start_matches('/');
+            (!pattern.is_empty()).then(|| IgnoreRule {
+                pattern: pattern.to_string(),
+                base: base.to_path_buf(),
+                negated,
+                anchored,
+                dir_only,
+            })
+        })
+        .collect()
+}
+
+/// Whether the last of `rules` matching `path` ignores it (git's precedence)
+fn is_ignored_by_rules(rules: &[IgnoreRule], path: &Path, is_dir: bool) -> bool {
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    rules.iter()
+        .rev()
+        .find(|rule| {
+            if rule.dir_only && !is_dir {
+                return false;
+            }
+            if !rule.anchored {
+                return glob_match(&rule.pattern, &name);
+            }
+            path.strip_prefix(&rule.base)
+                .map(|relative| {
+                    let relative: Vec<String> = relative.components()
+                        .map(|component| component.as_os_str().to_string_lossy().to_string())
+                        .collect();
+                    glob_match(&rule.pattern, &relative.join("/"))
+                })
+                .unwrap_or(false)
+        })
+        .is_some_and(|rule| !rule.negated)
+}
+
+/// Matches a glob against a `/`-separated path
+///
+/// `*` and `?` stay within one path component; `**` matches across them,
+/// and `**/` also matches no directories at all.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern {
+            [] => text.is_empty(),
+            ['*', '*', '/', rest @ ..] => (0..=text.len())
+                .any(|at| (at == 0 || text[at - 1] == '/') && matches(rest, &text[at..])),
+            ['*', '*', rest @ ..] => (0..=text.len()).any(|at| matches(rest, &text[at..])),
+            ['*', rest @ ..] => (0..=text.len())
+                .take_while(|&at| at == 0 || text[at - 1] != '/')
+                .any(|at| matches(rest, &text[at..])),
+            ['?', rest @ ..] => text.first().is_some_and(|c| *c != '/') && matches(rest, &text[1..]),
+            [c, rest @ ..] => text.first() == Some(c) && matches(rest, &text[1..]),
+        }
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches(&pattern, &text)
+}
+
+/// What directory mode left out, for the summary after a scan
+#[derive(Debug, Default)]
+struct ScanSkipped {
+    ignored: usize,          // entries matched by an ignore file
+    excluded: Vec<String>,   // names matched by DEFAULT_SCAN_EXCLUDES or scan_exclude
+    too_large: Vec<String>,  // files over scan_max_file_kb
+    over_total: Vec<String>, // files left out once scan_max_total_kb was reached
+}
+
+/// Which files directory mode skips, and how much it includes
+///
+/// Ignore files (SCAN_IGNORE_FILES) apply to their directory and the ones
+/// below it while it is scanned. Excluded and over-size entries are still
+/// shown in the tree, with a note, so the model knows they exist.
+struct ScanFilter {
+    excludes: Vec<String>,          // name globs: DEFAULT_SCAN_EXCLUDES and scan_exclude
+    max_file_bytes: u64,            // 0 for no limit
+    max_total_bytes: u64,           // 0 for no limit
+    ignore_rules: Vec<IgnoreRule>,  // rules of the ignore files in the directories being scanned
+    included_bytes: u64,
+    skipped: ScanSkipped,
+}
+
+impl ScanFilter {
+    /// A filter excluding `excludes` (name globs), with limits in bytes (0 for none)
+    fn new(excludes: Vec<String>, max_file_bytes: u64, max_total_bytes: u64) -> ScanFilter {
+        ScanFilter {
+            excludes,
+            max_file_bytes,
+            max_total_bytes,
+            ignore_rules: Vec::new(),
+            included_bytes: 0,
+            skipped: ScanSkipped::default(),
+        }
+    }
+
+    /// The filter set in the config (`scan_exclude`, `scan_max_file_kb`, `scan_max_total_kb`)
+    fn from_config() -> ScanFilter {
+        let config = read_config();
+        let kib = |key: &str, default: u64| config.get_int(key).and_then(|kib| u64::try_from(kib).ok()).unwrap_or(default) * 1024;
+        let mut excludes: Vec<String> = DEFAULT_SCAN_EXCLUDES.iter().map(|name| name.to_string()).collect();
+        excludes.extend(config.get_string_list("scan_exclude"));
+        ScanFilter::new(
+            excludes,
+            kib("scan_max_file_kb", DEFAULT_SCAN_MAX_FILE_KB),
+            kib("scan_max_total_kb", DEFAULT_SCAN_MAX_TOTAL_KB),
+        )
+    }
+
+    /// Whether `name` matches one of the exclude globs
+    fn is_excluded(&self, name: &str) -> bool {
+        self.excludes.iter().any(|pattern| glob_match(pattern, name))
+    }
+
+    /// One line saying what was skipped, or None when nothing was
+    fn summary(&self) -> Option<String> {
+        let list = |names: &[String]| {
+            let shown = names.iter().take(5).cloned().collect::<Vec<_>>().join(", ");
+            if names.len() > 5 { format!("{}, and {} more", shown, names.len() - 5) } else { shown }
+        };
+        let mut parts = Vec::new();
+        if self.skipped.ignored > 0 {
+            parts.push(format!("{} entries listed in ignore files", self.skipped.ignored));
+        }
+        if !self.skipped.excluded.is_empty() {
+            parts.push(format!("excluded: {}", list(&self.skipped.excluded)));
+        }
+        if !self.skipped.too_large.is_empty() {
+            parts.push(format!("over scan_max_file_kb ({}): {}", format_bytes(self.max_file_bytes), list(&self.skipped.too_large)));
+        }
+        if !self.skipped.over_total.is_empty() {
+            parts.push(format!(
+                "{} file(s) after scan_max_total_kb ({}) was reached",
+                self.skipped.over_total.len(), format_bytes(self.max_total_bytes)
+            ));
+        }
+        (!parts.is_empty()).then(|| format!("Skipped: {}", parts.join("; ")))
+    }
 }
 
 /// Determines if a file is likely to be a text file based on its extension
@@ -2383,6 +17957,130 @@ fn is_likely_text_file(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
+/// Extracts an outline of a source file: its declarations without bodies
+///
+/// - Rust: `fn`, `struct`, `enum`, `trait`, `impl`, `mod`, `type`, `const`,
+///   `static`, and `macro_rules!` headers (with any `pub`/`async`/`unsafe`)
+/// - Python: `class` and `def` lines, indentation kept
+///
+/// # Returns
+/// - Some(String): Outline lines, one per declaration
+/// - None: No extractor for this file type, use the full contents
+fn extract_outline(path: &Path, content: &str) -> Option<String> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    let lines: Vec<String> = match extension.as_str() {
+        "rs" => {
+            let declaration_words = ["fn", "struct", "enum", "trait", "impl", "mod", "type", "const", "static", "macro_rules!"];
+            let modifiers = ["pub", "pub(crate)", "pub(super)", "async", "unsafe", "extern", "default"];
+            content.lines()
+                .filter(|line| {
+                    let first_word = line.split_whitespace()
+                        .find(|word| !modifiers.contains(word) && !word.starts_with("pub("));
+                    first_word.is_some_and(|word| {
+                        declaration_words.iter().any(|declaration| word == *declaration || word.starts_with(&format!("{}<", declaration)))
+                    })
+                })
+                .map(|line| line.trim_end().trim_end_matches('{').trim_end().to_string())
+                .collect()
+        },
+        "py" => content.lines()
+            .filter(|line| {
+                let trimmed = line.trim_start();
+                trimmed.starts_with("def ") || trimmed.starts_with("async def ") || trimmed.starts_with("class ")
+            })
+            .map(|line| line.trim_end().to_string())
+            .collect(),
+        _ => return None,
+    };
+    Some(lines.join("\n"))
+}
+
+/// Bytes read from the start of a file to identify it (enough for JPEG dimensions)
+const FILE_HEADER_READ_BYTES: usize = 64 * 1024;
+
+/// Reads the start of a file and describes it, e.g. `PNG image, 640x480, 12.3 KB`
+fn describe_non_text_file(path: &Path) -> Option<String> {
+    let size = fs::metadata(path).ok()?.len();
+    let mut header = Vec::new();
+    io::Read::read_to_end(
+        &mut io::Read::take(fs::File::open(path).ok()?, FILE_HEADER_READ_BYTES as u64),
+        &mut header,
+    ).ok()?;
+    Some(describe_file_header(&header, size))
+}
+
+/// Describes a file from its first bytes (magic numbers) and size
+///
+/// Image dimensions are read from PNG, GIF, and JPEG headers. Files
+/// without a known signature are called text or binary by whether the
+/// header is valid UTF-8 without NUL bytes.
+fn describe_file_header(header: &[u8], size: u64) -> String {
+    let le_u16 = |at: usize| header.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u32);
+    let be_u32 = |at: usize| header.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+
+    let (kind, dimensions) = if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+        ("PNG image", be_u32(16).zip(be_u32(20)))
+    } else if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+        ("GIF image", le_u16(6).zip(le_u16(8)))
+    } else if header.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        ("JPEG image", jpeg_dimensions(header))
+    } else {
+        let kind = if header.starts_with(b"GGUF") {
+            "GGUF model"
+        } else if header.starts_with(b"%PDF") {
+            "PDF document"
+        } else if header.starts_with(b"PK\x03\x04") {
+            "ZIP archive"
+        } else if header.starts_with(&[0x1F, 0x8B]) {
+            "gzip archive"
+        } else if header.starts_with(b"\x7FELF") {
+            "ELF executable"
+        } else if header.starts_with(b"MZ") {
+            "Windows executable"
+        } else if header.starts_with(&[0xCF, 0xFA, 0xED, 0xFE]) {
+            "Mach-O executable"
+        } else if header.starts_with(b"\0asm") {
+            "WebAssembly module"
+        } else if header.starts_with(b"SQLite format 3\0") {
+            "SQLite database"
+        } else if header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WEBP") {
+            "WebP image"
+        } else if header.starts_with(b"BM") && size >= 26 {
+            "BMP image"
+        } else if !header.contains(&0) && std::str::from_utf8(header).is_ok() {
+            "text file, not included"
+        } else {
+            "binary file"
+        };
+        (kind, None)
+    };
+
+    match dimensions {
+        Some((width, height)) => format!("{}, {}x{}, {}", kind, width, height, format_bytes(size)),
+        None => format!("{}, {}", kind, format_bytes(size)),
+    }
+}
+
+/// Finds the width and height in a JPEG's start-of-frame segment
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let mut at = 2;
+    while at + 9 < data.len() {
+        if data[at] != 0xFF {
+            return None;
+        }
+        let marker = data[at + 1];
+        let length = u16::from_be_bytes([data[at + 2], data[at + 3]]) as usize;
+        // SOF0..SOF15, except DHT (C4), JPG (C8), and DAC (CC)
+        if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+            let height = u16::from_be_bytes([data[at + 5], data[at + 6]]) as u32;
+            let width = u16::from_be_bytes([data[at + 7], data[at + 8]]) as u32;
+            return Some((width, height));
+        }
+        at += 2 + length;
+    }
+    None
+}
+
 /// Recursively scans a directory creating a tree structure and collecting file contents
 /// 
 /// Creates a hierarchical view of the directory structure and collects contents
@@ -2391,6 +18089,9 @@ fn is_likely_text_file(path: &Path) -> bool {
 /// # Arguments
 /// * `path` - Directory path to scan
 /// * `prefix` - String prefix for tree formatting (used in recursion)
+/// * `options` - Outline/tree-only/depth settings (see `ScanOptions`)
+/// * `filter` - Ignore files, excludes, and size limits; collects what was skipped (see `ScanFilter`)
+/// * `throttle` - Read rate and time budget (see `ScanThrottle`)
 /// 
 /// # Returns
 /// - Ok(DirectoryScan): Successful scan results
@@ -2409,9 +18110,16 @@ fn is_likely_text_file(path: &Path) -> bool {
 /// - Directory does not exist
 /// - Permission denied
 /// - File read errors
-fn scan_directory(path: &Path, prefix: &str) -> Result<DirectoryScan, String> {
+fn scan_directory(
+    path: &Path,
+    prefix: &str,
+    options: &ScanOptions,
+    filter: &mut ScanFilter,
+    throttle: &mut ScanThrottle,
+) -> Result<DirectoryScan, String> {
     let mut tree = String::new();
     let mut contents = String::new();
+    let mut file_count = 0;
 
     if !path.exists() {
         return Err(format!("Directory not found: {}", path.display()));
@@ -2425,44 +18133,251 @@ fn scan_directory(path: &Path, prefix: &str) -> Result<DirectoryScan, String> {
         .map_err(|e| format!("Failed to collect directory entries: {}", e))?;
     entries.sort_by_key(|entry| entry.path());
 
+    // This directory's ignore files apply until its scan is done
+    let inherited_rules = filter.ignore_rules.len();
+    for ignore_file in SCAN_IGNORE_FILES {
+        if let Ok(content) = fs::read_to_string(path.join(ignore_file)) {
+            filter.ignore_rules.extend(parse_ignore_rules(&content, path));
+        }
+    }
+    let before_ignoring = entries.len();
+    entries.retain(|entry| !is_ignored_by_rules(&filter.ignore_rules, &entry.path(), entry.path().is_dir()));
+    filter.skipped.ignored += before_ignoring - entries.len();
+
     for (i, entry) in entries.iter().enumerate() {
+        if throttle.ran_out {
+            break;
+        }
+        if !throttle.spend(SCAN_ENTRY_COST_BYTES) {
+            tree.push_str(&format!("{}└── ... (scan stopped: scan_time_budget_secs reached)\n", prefix));
+            break;
+        }
         let is_last = i == entries.len() - 1;
         let path = entry.path();
         let name = path.file_name()
             .and_then(|n| n.to_str())
             .unwrap_or("invalid_filename");
 
+        let size = entry.metadata().map(|meta| meta.len()).unwrap_or(0);
+        let excluded = filter.is_excluded(name);
+        let include_contents = path.is_file() && is_likely_text_file(&path) && !options.tree_only && !excluded;
+        let too_large = include_contents && filter.max_file_bytes > 0 && size > filter.max_file_bytes;
+        let over_total = include_contents && !too_large && filter.max_total_bytes > 0
+            && filter.included_bytes + size > filter.max_total_bytes;
+
+        // Files whose contents are not included get a short descriptor instead
+        let descriptor = if excluded {
+            filter.skipped.excluded.push(name.to_string());
+            " [excluded]".to_string()
+        } else if too_large {
+            filter.skipped.too_large.push(name.to_string());
+            format!(" [{}, over scan_max_file_kb]", format_bytes(size))
+        } else if over_total {
+            filter.skipped.over_total.push(name.to_string());
+            format!(" [{}, not included: scan_max_total_kb reached]", format_bytes(size))
+        } else if path.is_file() && !is_likely_text_file(&path) {
+            describe_non_text_file(&path)
+                .map(|description| format!(" [{}]", description))
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
         // Add to tree structure
-        tree.push_str(&format!("{}{} {}\n", 
+        tree.push_str(&format!("{}{} {}{}\n", 
             prefix,
             if is_last { "└──" } else { "├──" },
-            name));
+            name,
+            descriptor));
 
         if path.is_dir() {
+            // Directories at the depth limit, and excluded ones, are listed but not entered
+            if options.max_depth == Some(1) || excluded {
+                continue;
+            }
+
             // Recursively scan subdirectory
             let next_prefix = format!("{}{}",
                 prefix,
                 if is_last { "    " } else { "│   " });
+            let next_options = ScanOptions {
+                max_depth: options.max_depth.map(|depth| depth - 1),
+                ..*options
+            };
             
-            let scan_result = scan_directory(&path, &next_prefix)?;
+            let scan_result = scan_directory(&path, &next_prefix, &next_options, filter, throttle)?;
             tree.push_str(&scan_result.tree_structure);
             contents.push_str(&scan_result.file_contents);
+            file_count += scan_result.file_count;
         } else {
-            // Read file contents if it's a text file
-            if is_likely_text_file(&path) {
+            // Read file contents if it's a text file within the limits
+            if include_contents && !too_large && !over_total && throttle.spend(size) {
                 if let Ok(content) = fs::read_to_string(&path) {
-                    contents.push_str(&format!("\n=== {} ===\n{}\n", name, content));
+                    filter.included_bytes += size;
+                    match extract_outline(&path, &content).filter(|_| options.outline) {
+                        Some(outline) => contents.push_str(&format!("\n=== {} (outline) ===\n{}\n", name, outline)),
+                        None => contents.push_str(&format!("\n=== {} ===\n{}\n", name, content)),
+                    }
+                    file_count += 1;
                 }
             }
         }
     }
 
+    filter.ignore_rules.truncate(inherited_rules);
     Ok(DirectoryScan {
         tree_structure: tree,
         file_contents: contents,
+        file_count,
     })
 }
 
+/// Checks a prompt built from a directory or attachments against the mode's limits
+///
+/// Over max_prompt_bytes or max_files is an error, with suggestions for
+/// making the prompt smaller. Whether the prompt fits ctx_size is checked
+/// before launch by `check_prompt_tokens`.
+///
+/// # Arguments
+/// * `prompt_bytes` - Size of the built prompt
+/// * `file_count` - Files included from a directory, None when no directory was scanned
+/// * `parameters` - The mode's parameters (max_prompt_bytes, max_files)
+///
+/// # Returns
+/// - Ok(()): Within the limits
+/// - Err(String): Which limit is exceeded and what to do instead
+fn check_prompt_size(prompt_bytes: u64, file_count: Option<usize>, parameters: &LlamaCppParameters) -> Result<(), String> {
+    let suggestions = "Use --outline, --tree-only, or --depth N, point at a smaller directory, \
+        pass less context, or use a mode with a bigger ctx_size (and limits).";
+
+    if let (Some(count), Some(max_files)) = (file_count, parameters.max_files) {
+        if count > max_files {
+            return Err(format!(
+                "The directory has {} files to include, over this mode's max_files={}. {}",
+                count, max_files, suggestions
+            ));
+        }
+    }
+    if let Some(max_prompt_bytes) = parameters.max_prompt_bytes {
+        if prompt_bytes > max_prompt_bytes {
+            return Err(format!(
+                "The prompt would be {} bytes, over this mode's max_prompt_bytes={}. {}",
+                prompt_bytes, max_prompt_bytes, suggestions
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Tokens left free for the model's reply when ctx_size is raised or the prompt truncated
+const PROMPT_REPLY_RESERVE_TOKENS: u64 = 1024;
+
+/// Path of llama-tokenize next to the mode's llama-cli, if it is there
+fn find_llama_tokenize(mode: &ChatModeConfig) -> Option<PathBuf> {
+    let (llama_cli_path, _) = select_llama_binary(mode).ok()?;
+    let path = Path::new(&llama_cli_path)
+        .with_file_name(format!("llama-tokenize{}", std::env::consts::EXE_SUFFIX));
+    path.is_file().then_some(path)
+}
+
+/// Reports the prompt's token count before launch and handles a prompt over ctx_size
+///
+/// The count is estimated from the prompt's size (see `tokens`). When the
+/// estimate is over half of ctx_size and llama-tokenize is next to
+/// llama-cli, the model's tokenizer counts it exactly. A prompt over
+/// ctx_size gets a warning and, when there is a terminal to ask on, the
+/// choice to raise ctx_size for this launch or to cut the middle out of
+/// the prompt (in a copy; the prompt file is not changed). ctx_size 0
+/// means the model's trained context length, read from the GGUF header.
+///
+/// # Arguments
+/// * `mode` - Mode about to launch; its ctx_size or prompt_path may be changed
+/// * `can_ask` - Whether stdin is free for asking (not --print or piped context)
+///
+/// # Returns
+/// - Ok(()): The prompt fits, or the user chose what to do
+/// - Err(String): The prompt could not be read or the truncated copy written
+fn check_prompt_tokens(mode: &mut ChatModeConfig, can_ask: bool) -> Result<(), String> {
+    use std::io::IsTerminal;
+    // Endpoint modes: the server's context window is not known here
+    if !mode.endpoint.is_empty() {
+        return Ok(());
+    }
+    let prompt_bytes = fs::metadata(&mode.prompt_path)
+        .map_err(|e| format!("Failed to read prompt {}: {}", mode.prompt_path, e))?
+        .len();
+    let trained_context = || gguf::read_gguf_header(Path::new(&mode.model_path)).ok()?.context_length();
+    let context_size = match u64::try_from(mode.parameters.context_size) {
+        Ok(size) if size > 0 => size,
+        _ => match trained_context() {
+            Some(size) => size,
+            None => return Ok(()),
+        },
+    };
+
+    let estimate = tokens::estimate_from_bytes(prompt_bytes);
+    // The model is only on this machine for local modes
+    let tokenizer = if estimate * 2 > context_size && mode.remote.is_empty() { find_llama_tokenize(mode) } else { None };
+    let (prompt_tokens, exact) = match tokenizer {
+        Some(tokenizer) => match tokens::count_with_tokenizer(&tokenizer, Path::new(&mode.model_path), Path::new(&mode.prompt_path)) {
+            Ok(count) => (count, true),
+            Err(e) => {
+                status_println!("Warning: {}; using the size estimate", e);
+                (estimate, false)
+            },
+        },
+        None => (estimate, false),
+    };
+    status_println!(
+        "Prompt: {}{} tokens (ctx_size {})",
+        if exact { "" } else { "about " }, prompt_tokens, context_size
+    );
+    if prompt_tokens <= context_size {
+        return Ok(());
+    }
+
+    status_println!(
+        "Warning: The prompt is larger than ctx_size {}; llama.cpp will not see all of it",
+        context_size
+    );
+    if !can_ask || !io::stdin().is_terminal() {
+        return Ok(());
+    }
+    let raised_size = (prompt_tokens + PROMPT_REPLY_RESERVE_TOKENS).next_multiple_of(1024);
+    // Raising past the trained context length does not help
+    let can_raise = trained_context().is_none_or(|trained| raised_size <= trained);
+    loop {
+        if can_raise {
+            print!("[r]aise ctx_size to {} for this launch, [t]runcate the prompt to fit, or [c]ontinue? ", raised_size);
+        } else {
+            print!("[t]runcate the prompt to fit, or [c]ontinue? ");
+        }
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        match read_user_input()?.trim().to_lowercase().as_str() {
+            "r" | "raise" if can_raise => {
+                mode.parameters.context_size = i32::try_from(raised_size).unwrap_or(i32::MAX);
+                println!("To keep it, use 'query_gguf edit {}'.", mode.id);
+                return Ok(());
+            },
+            "t" | "truncate" => {
+                let text = fs::read_to_string(&mode.prompt_path)
+                    .map_err(|e| format!("Failed to read prompt {}: {}", mode.prompt_path, e))?;
+                let keep_tokens = context_size - PROMPT_REPLY_RESERVE_TOKENS.min(context_size / 4);
+                // Scaled from the count, so it also holds for exact counts
+                let keep_bytes = (text.len() as u64 * keep_tokens / prompt_tokens) as usize;
+                let truncated_path = get_temp_dir()?.join(format!("truncated_prompt_{}.txt", generate_timestamp_string()));
+                fs::write(&truncated_path, tokens::cut_middle(&text, keep_bytes))
+                    .map_err(|e| format!("Failed to write {}: {}", truncated_path.display(), e))?;
+                println!("Truncated the prompt to about {} tokens: {}", keep_tokens, truncated_path.display());
+                mode.prompt_path = truncated_path.to_string_lossy().to_string();
+                return Ok(());
+            },
+            "" | "c" | "continue" => return Ok(()),
+            _ => println!("Please enter {}", if can_raise { "r, t, or c" } else { "t or c" }),
+        }
+    }
+}
+
 /// Creates a temporary combined prompt file from original prompt and directory contents
 /// 
 /// Combines:
@@ -2473,6 +18388,8 @@ fn scan_directory(path: &Path, prefix: &str) -> Result<DirectoryScan, String> {
 /// # Arguments
 /// * `original_prompt_path` - Optional path to original prompt file
 /// * `directory_contents` - String containing scanned directory contents
+/// * `scan_options` - How much of the directory to include (--outline, --tree-only, --depth)
+/// * `parameters` - The mode's parameters, for its max_prompt_bytes/max_files limits
 /// 
 /// # Returns
 /// - Ok(PathBuf): Path to created temporary combined prompt file
@@ -2502,10 +18419,14 @@ fn scan_directory(path: &Path, prefix: &str) -> Result<DirectoryScan, String> {
 /// - Cannot create temp directory
 /// - Cannot write temp file
 /// - Original prompt file not readable
+/// - The result is over the mode's max_prompt_bytes or max_files (see `check_prompt_size`)
+///
 /// Creates a combined prompt file with directory contents
 fn create_combined_prompt(
     original_prompt_path: &str,
-    directory_path: &str
+    directory_path: &str,
+    scan_options: &ScanOptions,
+    parameters: &LlamaCppParameters,
 ) -> Result<String, String> {
     // Get the prompts directory
     let prompts_dir = get_prompts_dir()?;
@@ -2520,18 +18441,41 @@ fn create_combined_prompt(
         .map_err(|e| format!("Failed to read original prompt: {}", e))?;
 
     // Scan directory
+    let mut filter = ScanFilter::from_config();
+    let mut throttle = ScanThrottle::from_config();
     let scan_result = scan_directory(
         Path::new(directory_path), 
-        ""
+        "",
+        scan_options,
+        &mut filter,
+        &mut throttle
     )?;
+    if throttle.ran_out {
+        println!("Warning: The directory scan ran out of time (scan_time_budget_secs); the prompt has only part of {}", directory_path);
+    }
+    // Also in the prompt, so the model knows what it was not shown
+    let skipped_note = match filter.summary() {
+        Some(summary) => {
+            println!("{}", summary);
+            format!("\n{}\n", summary)
+        },
+        None => String::new(),
+    };
 
     // Combine prompts
-    let combined_content = format!(
-        "{}\n\nDirectory Structure:\n{}\n\nFile Contents:{}\n",
-        original_prompt,
-        scan_result.tree_structure,
-        scan_result.file_contents
-    );
+    let combined_content = if scan_options.tree_only {
+        format!("{}\n\nDirectory Structure:\n{}{}\n", original_prompt, scan_result.tree_structure, skipped_note)
+    } else {
+        format!(
+            "{}\n\nDirectory Structure:\n{}{}\n\nFile Contents:{}\n",
+            original_prompt,
+            scan_result.tree_structure,
+            skipped_note,
+            scan_result.file_contents
+        )
+    };
+
+    check_prompt_size(combined_content.len() as u64, Some(scan_result.file_count), parameters)?;
 
     // Write combined prompt
     fs::write(&combined_prompt_path, combined_content)
@@ -2542,24 +18486,38 @@ fn create_combined_prompt(
 
 
 
+/// Fingerprint of the config file's content (None if it cannot be read)
+fn config_fingerprint() -> Option<u64> {
+    let content = fs::read(get_config_path().ok()?).ok()?;
+    Some(fnv1a_hash(&content))
+}
+
 /// Modified mode selection screen for simpler interaction
 fn display_mode_selection_screen() -> Result<String, String> {
     loop {
+        let drawn_config = config_fingerprint();
         display_available_modes();
 
         print!("\nEnter selection: ");
         io::stdout().flush().map_err(|e| e.to_string())?;
 
-        let choice = read_user_input()?.trim().to_lowercase();
+        let choice = read_user_input_with_history("selection")?.trim().to_lowercase();
+
+        // Another shell may have saved, removed, or renumbered modes while the
+        // list was shown, so a number from it could now mean another mode
+        let selects_mode = !matches!(choice.as_str(), "quit" | "q" | "exit" | "config" | "make" | "manual" | "dir" | "directory");
+        if selects_mode && config_fingerprint() != drawn_config {
+            println!("\nThe config changed since the list was shown. Here is the current list:");
+            continue;
+        }
         
         match choice.as_str() {
             "" => {
                 // Handle empty input - try to use default mode
+                // default_mode may be a mode id or (older configs) a mode number
                 let default_mode = read_field_from_toml("default_mode");
                 if !default_mode.is_empty() {
-                    if let Ok(mode_num) = default_mode.parse::<usize>() {
-                        return handle_mode_selection(&mode_num.to_string());
-                    }
+                    return handle_mode_selection(&default_mode, &LaunchOptions::default());
                 }
                 println!("\nNo default mode set. Please make a selection.");
                 continue;
@@ -2575,12 +18533,27 @@ fn display_mode_selection_screen() -> Result<String, String> {
                 return handle_manual_mode_selection();
             },
             "dir" | "directory" => {
-                return handle_mode_selection("dir");
+                return handle_mode_selection("dir", &LaunchOptions::default());
+            },
+            command if command.starts_with("fav ") => {
+                let selector = command["fav ".len()..].trim();
+                let modes = read_saved_modes()?;
+                match find_mode_by_selector(&modes, selector) {
+                    Some(mode) => {
+                        let starred = toggle_favorite(FAVORITE_MODES_FIELD, &mode.id)?;
+                        println!("\n{} {}", if starred { "Starred" } else { "Unstarred" }, mode.name);
+                    },
+                    None => println!("\nNo mode '{}'", selector),
+                }
+                continue;
             },
-            number => {
-                // Try to parse as a mode number
-                if let Ok(mode_num) = number.parse::<usize>() {
-                    match handle_mode_selection(&mode_num.to_string()) {
+            selector => {
+                // Try as a mode number or mode id
+                let is_known_mode = read_saved_modes()
+                    .map(|modes| find_mode_by_selector(&modes, selector).is_some())
+                    .unwrap_or(false);
+                if is_known_mode || selector.parse::<usize>().is_ok() {
+                    match handle_mode_selection(selector, &LaunchOptions::default()) {
                         Ok(mode) => return Ok(mode),
                         Err(e) => {
                             println!("\nError: {}", e);
@@ -2599,23 +18572,361 @@ fn display_mode_selection_screen() -> Result<String, String> {
     }
 }
 
+/// Handles the `config` command line subcommands
+///
+/// - `query_gguf config`            -> open the config file in the editor
+/// - `query_gguf config lint`       -> report config problems that can be fixed
+/// - `query_gguf config lint --fix` -> fix them (after backing up the config)
+/// - `query_gguf config log [N]`    -> show the last N config changes
+fn handle_config_command(args: &[String]) -> Result<(), String> {
+    match args.first().map(|s| s.as_str()) {
+        None => open_config_in_editor(),
+        Some("lint") => {
+            let fix = args.iter().any(|arg| arg == "--fix");
+            handle_config_lint(fix)
+        },
+        Some("log") => show_config_log(&args[1..]),
+        Some(other) => Err(format!(
+            "Unknown config command '{}'. Use: config, config lint, config lint --fix, config log [N]",
+            other
+        )),
+    }
+}
+
 /// Handles quick launch by checking for command line arguments
-fn handle_quick_launch() -> Result<(), String> {
+///
+/// # Returns
+/// - Ok(true): A command line argument was handled, nothing more to do
+/// - Ok(false): No arguments, continue to the interactive mode selection screen
+/// - Err(String): The command failed
+fn handle_quick_launch() -> Result<bool, String> {
     // Only check for command line arguments
-    let args: Vec<String> = std::env::args().collect();
+    let args = command_line_args();
     if args.len() > 1 {
-        // Use the first argument as mode selection
-        handle_mode_selection(&args[1])?;
-        return Ok(());
+        if args[1] == "config" {
+            handle_config_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "prompts" {
+            handle_prompts_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "cache" {
+            handle_cache_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "ask" {
+            handle_ask_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "chat" {
+            handle_chat_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "patch" {
+            handle_patch_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "agent" {
+            handle_agent_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "docker" {
+            handle_docker_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "preload" {
+            handle_preload_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "server" {
+            handle_server_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "tune" {
+            handle_tune_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "bench" {
+            handle_bench_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "batch" {
+            handle_batch_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "verify-determinism" {
+            handle_verify_determinism_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "eval" {
+            handle_eval_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "report" {
+            handle_report_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "history" {
+            handle_history_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "logs" {
+            handle_logs_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "stats" {
+            handle_stats_command()?;
+            return Ok(true);
+        }
+        if args[1] == "pull" {
+            handle_pull_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "add" {
+            handle_add_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "models" {
+            handle_models_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "inspect" {
+            handle_inspect_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "quantize" {
+            handle_quantize_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "mode" {
+            handle_mode_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "modes" {
+            handle_modes_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "edit" {
+            handle_edit_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "show" {
+            handle_show_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "find" {
+            handle_find_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "diagnose" {
+            handle_diagnose_command(&args[2..])?;
+            return Ok(true);
+        }
+        if args[1] == "retune" {
+            run_hardware_retune(&detect_hardware_fingerprint())?;
+            write_state_field("hardware_fingerprint", &detect_hardware_fingerprint().to_state_string())?;
+            return Ok(true);
+        }
+
+        // Words that are not modes may be plugins (query_gguf-<word> on PATH), git-style
+        if !is_mode_selection_word(&args[1]) {
+            if let Some(plugin_path) = find_plugin(&args[1]) {
+                run_plugin(&plugin_path, &args[2..])?;
+                return Ok(true);
+            }
+        }
+
+        // Use the first argument as mode selection, the rest as launch options
+        let options = parse_launch_options(&args[2..])?;
+        handle_mode_selection(&args[1], &options)?;
+        return Ok(true);
     }
 
     // If no command line arguments, return Ok to continue to interactive mode
+    Ok(false)
+}
+
+/// Exit code for an error in the config file or on the command line
+const EXIT_CONFIG_ERROR: i32 = 2;
+/// Exit code for a mode whose model file does not exist
+const EXIT_MODEL_MISSING: i32 = 3;
+/// Exit code for llama.cpp (or docker/ssh/an endpoint) failing to start or run
+const EXIT_LAUNCH_FAILURE: i32 = 4;
+/// Exit code for a generation stopped by --max-seconds
+const EXIT_GENERATION_TIMEOUT: i32 = 5;
+/// Exit code for the user stopping or declining
+const EXIT_USER_ABORT: i32 = 6;
+
+/// Error message prefixes (lowercase) and the exit code they map to, checked in order
+///
+/// Errors are plain strings throughout, so their opening words are the
+/// contract: new errors of one of these kinds should start with a matching
+/// phrase. Only the start is matched, since messages go on to name user
+/// paths and text. In `a*b`, `*` stands for a name (mode id, backend, program)
+/// and `b` must follow it.
+const EXIT_CODE_PREFIXES: &[(&[&str], i32)] = &[
+    (&["launch cancelled"], EXIT_USER_ABORT),
+    (&["generation timed out", "timed out waiting for llama-server"], EXIT_GENERATION_TIMEOUT),
+    (&["model file not found"], EXIT_MODEL_MISSING),
+    (
+        &["use:", "usage:", "llama cli path not found in configuration", "configuration file not found",
+          "configuration has errors", "failed to save configuration", "unknown mode", "invalid mode selection",
+          "missing mode", "unknown option", "backend '*' is not registered", "mode '*' is an image mode"],
+        EXIT_CONFIG_ERROR,
+    ),
+    (
+        &["could not find llama-cli", "failed to run llama-cli", "llama-cli exited", "remote llama-cli",
+          "error waiting for llama-cli", "failed to read llama-cli output", "llama process exited",
+          "failed to start llama-server", "llama-server ", "could not reach llama-server",
+          "failed to run ssh", "failed to run * (is openssh installed?)", "failed to run docker", "docker ",
+          "mode '*' is already running in container", "failed to launch", "request to ", "connection to "],
+        EXIT_LAUNCH_FAILURE,
+    ),
+];
+
+/// Checks a lowercase message against one EXIT_CODE_PREFIXES entry
+fn message_has_prefix(message: &str, prefix: &str) -> bool {
+    match prefix.split_once('*') {
+        Some((start, rest)) => message.strip_prefix(start)
+            .and_then(|after| after.find(rest).map(|at| &after[..at]))
+            .is_some_and(|name| !name.is_empty() && !name.contains(char::is_whitespace)),
+        None => message.starts_with(prefix),
+    }
+}
+
+/// Maps an error message to the exit code scheme documented at the top of this file
+fn exit_code_for_error(message: &str) -> i32 {
+    let lowered = message.to_lowercase();
+    EXIT_CODE_PREFIXES.iter()
+        .find(|(prefixes, _)| prefixes.iter().any(|prefix| message_has_prefix(&lowered, prefix)))
+        .map(|(_, code)| *code)
+        .unwrap_or(1)
+}
+
+/// Fails with a "model file not found" error when a local mode's model is missing
+///
+/// Remote and endpoint modes name models on other machines, so they are not checked.
+/// Image modes fail too: their models are for stable-diffusion.cpp, not llama.cpp.
+fn check_model_file(mode: &ChatModeConfig) -> Result<(), String> {
+    if mode.kind == "image" {
+        return Err(format!(
+            "Mode '{}' is an image mode (kind=image) and has no llama-cli session; use: query_gguf {} -q \"<description>\"",
+            mode.id, mode.id
+        ));
+    }
+    if mode.remote.is_empty() && mode.endpoint.is_empty() && !Path::new(&mode.model_path).is_file() {
+        return Err(format!("Model file not found: {} (mode '{}')", mode.model_path, mode.id));
+    }
+    Ok(())
+}
+
+/// Runs the program and exits with a code from the documented exit code scheme
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(exit_code_for_error(&e));
+    }
+}
+
+/// Returns true if a command line word selects a mode (or directory/manual mode)
+fn is_mode_selection_word(word: &str) -> bool {
+    if ["dir", "directory", "make", "manual"].contains(&word) {
+        return true;
+    }
+    read_saved_modes()
+        .map(|modes| find_mode_by_selector(&modes, word).is_some())
+        .unwrap_or(false)
+}
+
+/// Subcommands query_gguf handles itself; they are matched before plugins,
+/// so a plugin with one of these names never runs
+const BUILTIN_SUBCOMMANDS: &[&str] = &[
+    "ask", "data-dir", "config", "prompts", "cache", "chat", "patch", "agent", "docker", "preload",
+    "server", "tune", "bench", "batch", "verify-determinism", "eval", "report", "history", "logs",
+    "stats", "pull", "add", "models", "inspect", "quantize", "mode", "modes", "edit", "show", "find",
+    "diagnose", "retune",
+];
+
+/// File name of the plugin for a subcommand, e.g. `query_gguf-hello` (`.exe` on Windows)
+fn plugin_file_name(subcommand: &str) -> String {
+    format!("query_gguf-{}{}", subcommand, std::env::consts::EXE_SUFFIX)
+}
+
+/// Finds the plugin executable for a subcommand on PATH
+fn find_plugin(subcommand: &str) -> Option<PathBuf> {
+    find_plugin_in(subcommand, &std::env::var_os("PATH")?)
+}
+
+/// Finds the plugin executable for a subcommand in a PATH-style list of directories
+///
+/// Built-in subcommands and words that are not plain (they become file
+/// names) have no plugin.
+fn find_plugin_in(subcommand: &str, search_path: &std::ffi::OsStr) -> Option<PathBuf> {
+    if subcommand.is_empty() || !subcommand.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+    if BUILTIN_SUBCOMMANDS.contains(&subcommand) {
+        return None;
+    }
+    let file_name = plugin_file_name(subcommand);
+    std::env::split_paths(search_path)
+        .map(|dir| dir.join(&file_name))
+        .find(|path| path.is_file())
+}
+
+/// Finds an executable file by name in the PATH directories
+fn find_program_on_path(file_name: &str) -> Option<PathBuf> {
+    std::env::var_os("PATH")
+        .and_then(|paths| std::env::split_paths(&paths)
+            .map(|dir| dir.join(file_name))
+            .find(|path| path.is_file()))
+}
+
+/// Runs a plugin with the remaining arguments, passing query_gguf's settings in the environment
+///
+/// - QUERY_GGUF_CONFIG: path of the config file
+/// - QUERY_GGUF_HOME: the ~/query_gguf directory
+/// - QUERY_GGUF_MODE: id of the default mode (empty if none is set)
+/// - QUERY_GGUF_MODE_MODEL / QUERY_GGUF_MODE_PROMPT: that mode's model and prompt paths
+///
+/// The plugin's exit code becomes query_gguf's exit code.
+fn run_plugin(plugin_path: &Path, args: &[String]) -> Result<(), String> {
+    let mut command = Command::new(plugin_path);
+    command.args(args)
+        .env("QUERY_GGUF_CONFIG", get_config_path()?)
+        .env("QUERY_GGUF_HOME", get_app_base_dir()?);
+
+    let default_mode = read_field_from_toml("default_mode");
+    let modes = read_saved_modes().unwrap_or_default();
+    match find_mode_by_selector(&modes, &default_mode) {
+        Some(mode) => command.env("QUERY_GGUF_MODE", &mode.id)
+            .env("QUERY_GGUF_MODE_MODEL", &mode.model_path)
+            .env("QUERY_GGUF_MODE_PROMPT", &mode.prompt_path),
+        None => command.env("QUERY_GGUF_MODE", ""),
+    };
+
+    let status = command.status()
+        .map_err(|e| format!("Failed to run plugin {}: {}", plugin_path.display(), e))?;
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
+    }
     Ok(())
 }
 
 /// Modified main function for cleaner flow
-fn main() -> Result<(), String> {
-    println!("Query via gguf llama.cpp llama-cli");
+fn run() -> Result<(), String> {
+    if command_line_args().get(1).map(|arg| arg.as_str()) == Some("ask") {
+        ANSWER_ONLY_STDOUT.store(true, std::sync::atomic::Ordering::Relaxed);
+    } else {
+        println!("Query via gguf llama.cpp llama-cli");
+    }
+
+    // Works without a config, so it can move one that is not found yet
+    let args = command_line_args();
+    if args.get(1).map(|arg| arg.as_str()) == Some("data-dir") {
+        return handle_data_dir_command(&args[2..]);
+    }
 
     // Check if we need to run setup
     if !query_gguf_config_exists() {
@@ -2625,9 +18936,34 @@ fn main() -> Result<(), String> {
         read_user_input()?;
     }
 
+    // Make sure the app directory is usable before doing anything else
+    if let Err(e) = check_app_directory_permissions() {
+        eprintln!("\n{}", e);
+        return Err("Application directory is not accessible".to_string());
+    }
+
+    // With `strict = true`, refuse to run on a config with problems
+    // (the `config` commands stay available so the config can be fixed)
+    let is_config_command = command_line_args().get(1).map(|arg| arg.as_str()) == Some("config");
+    if !is_config_command {
+        if let Err(e) = check_config_strict_if_enabled() {
+            eprintln!("\n{}", e);
+            return Err("Configuration has errors (strict mode)".to_string());
+        }
+    }
+
+    // Only ask about hardware changes in the interactive menu, since
+    // command line runs may be scripted or have stdin piped in
+    if command_line_args().len() == 1 {
+        if let Err(e) = check_hardware_change() {
+            println!("Warning: Hardware check failed: {}", e);
+        }
+    }
+
     // Try quick launch first
     match handle_quick_launch() {
-        Ok(()) => {
+        Ok(true) => Ok(()),
+        Ok(false) => {
             // Quick launch succeeded or wasn't available
             // Show mode selection screen if quick launch didn't handle it
             match display_mode_selection_screen() {
@@ -0,0 +1,158 @@
+// tokens.rs: token counts for prompts, std only
+//
+// A count is estimated from the prompt's size: about 4 bytes per token for
+// English text and code. When llama.cpp's llama-tokenize is available, the
+// model's own tokenizer gives the exact count instead; it only loads the
+// model's vocabulary, so it is quick even for big models.
+
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Bytes per token assumed by the estimate
+pub const BYTES_PER_TOKEN: u64 = 4;
+
+/// Longest llama-tokenize may run before the estimate is used instead
+const TOKENIZE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Marker put where `cut_middle` removed text
+const CUT_MARKER: &str = "\n\n[... part of the prompt was cut to fit the context window ...]\n\n";
+
+/// Estimated tokens for `bytes` bytes of text
+pub fn estimate_from_bytes(bytes: u64) -> u64 {
+    bytes.div_ceil(BYTES_PER_TOKEN)
+}
+
+/// Counts the tokens of a prompt file with llama-tokenize and the model's vocabulary
+///
+/// # Arguments
+/// * `tokenizer` - Path of llama-tokenize
+/// * `model` - The GGUF model whose tokenizer is used
+/// * `prompt_file` - The prompt to count
+///
+/// # Returns
+/// - Ok(u64): The number of tokens
+/// - Err(String): llama-tokenize failed, timed out, or printed no token list
+pub fn count_with_tokenizer(tokenizer: &Path, model: &Path, prompt_file: &Path) -> Result<u64, String> {
+    let mut child = Command::new(tokenizer)
+        .arg("-m").arg(model)
+        .arg("-f").arg(prompt_file)
+        .args(["--ids", "--log-disable"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", tokenizer.display(), e))?;
+
+    // Read while it runs, so a long token list cannot fill the pipe and block it
+    let mut stdout = child.stdout.take().ok_or("llama-tokenize has no output")?;
+    let reader = std::thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stdout.read_to_string(&mut output);
+        output
+    });
+
+    let start = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if start.elapsed() > TOKENIZE_TIMEOUT => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("{} took longer than {}s", tokenizer.display(), TOKENIZE_TIMEOUT.as_secs()));
+            },
+            Ok(None) => std::thread::sleep(Duration::from_millis(50)),
+            Err(e) => return Err(format!("Failed to wait for {}: {}", tokenizer.display(), e)),
+        }
+    };
+    let output = reader.join().unwrap_or_default();
+    if !status.success() {
+        return Err(format!("{} exited with {}", tokenizer.display(), status));
+    }
+    parse_tokenize_output(&output)
+        .ok_or_else(|| format!("{} printed no token list", tokenizer.display()))
+}
+
+/// The token count in llama-tokenize's output
+///
+/// With `--ids` the tokens are one list, `[1, 15043, 2787]`; older builds
+/// ignore the flag and print one `15043 -> ' Hello'` line per token.
+pub fn parse_tokenize_output(output: &str) -> Option<u64> {
+    let id_list = output.lines()
+        .map(str::trim)
+        .rfind(|line| line.starts_with('[') && line.ends_with(']'));
+    if let Some(line) = id_list {
+        let ids = line.trim_start_matches('[').trim_end_matches(']');
+        return Some(if ids.trim().is_empty() { 0 } else { ids.split(',').count() as u64 });
+    }
+    let count = output.lines()
+        .filter(|line| line.split_once(" -> ").is_some_and(|(id, _)| id.trim().parse::<u64>().is_ok()))
+        .count();
+    (count > 0).then_some(count as u64)
+}
+
+/// Shortens text to about `keep_bytes` by cutting out the middle
+///
+/// The start (instructions, directory tree) and the end (context and the
+/// question, which are appended last) are kept, two thirds and one third
+/// of the budget, cut at line ends where possible.
+pub fn cut_middle(text: &str, keep_bytes: usize) -> String {
+    if text.len() <= keep_bytes {
+        return text.to_string();
+    }
+    let head_bytes = keep_bytes * 2 / 3;
+    let tail_bytes = keep_bytes - head_bytes;
+
+    let mut head_end = floor_char_boundary(text, head_bytes);
+    if let Some(newline) = text[..head_end].rfind('\n') {
+        head_end = newline + 1;
+    }
+    let mut tail_start = ceil_char_boundary(text, text.len() - tail_bytes);
+    // Start at the next line, unless the last line is longer than the tail
+    if let Some(newline) = text[tail_start..].find('\n').filter(|newline| tail_start + newline + 1 < text.len()) {
+        tail_start += newline + 1;
+    }
+    if tail_start <= head_end {
+        return text.to_string();
+    }
+    format!("{}{}{}", &text[..head_end], CUT_MARKER, &text[tail_start..])
+}
+
+/// The largest char boundary at or before `index`
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    (0..=index.min(text.len())).rev().find(|at| text.is_char_boundary(*at)).unwrap_or(0)
+}
+
+/// The smallest char boundary at or after `index`
+fn ceil_char_boundary(text: &str, index: usize) -> usize {
+    (index..=text.len()).find(|at| text.is_char_boundary(*at)).unwrap_or(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_counts() {
+        assert_eq!(estimate_from_bytes(0), 0);
+        assert_eq!(estimate_from_bytes(9), 3);
+
+        assert_eq!(parse_tokenize_output("[1, 15043, 2787]\n"), Some(3));
+        assert_eq!(parse_tokenize_output("[]\n"), Some(0));
+        assert_eq!(parse_tokenize_output("     1 -> '<s>'\n 15043 -> ' Hello'\n  2787 -> ' world'\n"), Some(3));
+        assert_eq!(parse_tokenize_output("error: unknown argument\n"), None);
+
+        let text = "instructions\nmore\n".to_string() + &"middle line\n".repeat(100) + "question?\n";
+        let cut = cut_middle(&text, 120);
+        assert!(cut.starts_with("instructions\nmore\n") && cut.ends_with("question?\n"));
+        assert!(cut.contains("was cut") && cut.len() < 200);
+        assert_eq!(cut_middle("short", 100), "short");
+        // Never splits a character
+        assert!(cut_middle(&"é".repeat(200), 51).contains("was cut"));
+        // A last line longer than the tail is cut, not dropped
+        let text = "instructions\n".to_string() + &"middle line\n".repeat(100) + &"long question ".repeat(10) + "\n";
+        let cut = cut_middle(&text, 120);
+        assert!(cut.ends_with("long question \n") && cut.len() > 100);
+    }
+}
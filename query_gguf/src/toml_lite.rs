@@ -0,0 +1,934 @@
+//! A small TOML reader for the config, state, mode test and eval suite files
+//!
+//! Supports comments, bare, quoted and dotted keys, `[tables]`,
+//! `[[arrays of tables]]`, basic and literal strings (single and multi-line),
+//! integers (including hex, octal and binary), floats, booleans, arrays and
+//! inline tables. Dates and times are not supported.
+//!
+//! Parsing is lenient: a line that cannot be read is reported and, where
+//! possible, its value is kept as text, so one typo in the config does not
+//! lose every setting after it. Callers that need a valid file check the
+//! returned errors.
+//!
+//! Older versions of query_gguf wrote paths into basic strings without
+//! escaping them, so a basic string whose backslashes are not all valid
+//! escapes (or that starts with a Windows drive, like `C:\models`) is kept
+//! as written.
+
+use std::fmt;
+
+/// A TOML value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+/// One `key = value` of a table (or a `[table]` header, for subtables)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    pub line: usize, // 1-based line of the key or table header
+}
+
+/// A table: its entries in file order
+///
+/// A duplicate key is reported as an error and kept as a later entry;
+/// the getters return the first one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table {
+    entries: Vec<Entry>,
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Boolean(flag) => Some(*flag),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Integer(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    /// The value as a float (integers convert)
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Float(number) => Some(*number),
+            Value::Integer(number) => Some(*number as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&Table> {
+        match self {
+            Value::Table(table) => Some(table),
+            _ => None,
+        }
+    }
+
+    /// A string as its content, anything else as TOML (e.g. `42`, `true`, `["a", "b"]`)
+    pub fn to_text(&self) -> String {
+        match self {
+            Value::String(text) => text.clone(),
+            other => other.to_string(),
+        }
+    }
+
+    /// A string or an array of strings as a list; other values give an empty list
+    ///
+    /// Empty strings are left out, so `""` and `[]` both mean "none".
+    pub fn string_list(&self) -> Vec<String> {
+        let items = match self {
+            Value::String(text) => vec![text.clone()],
+            Value::Array(items) => items.iter().filter_map(|item| item.as_str().map(str::to_string)).collect(),
+            _ => Vec::new(),
+        };
+        items.into_iter().filter(|item| !item.trim().is_empty()).collect()
+    }
+}
+
+impl Table {
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get_entry(&self, key: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.key == key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.get_entry(key).map(|entry| &entry.value)
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(Value::as_str)
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.get(key).and_then(Value::as_bool)
+    }
+
+    pub fn get_int(&self, key: &str) -> Option<i64> {
+        self.get(key).and_then(Value::as_int)
+    }
+
+    pub fn get_float(&self, key: &str) -> Option<f64> {
+        self.get(key).and_then(Value::as_float)
+    }
+
+    pub fn get_array(&self, key: &str) -> Option<&[Value]> {
+        self.get(key).and_then(Value::as_array)
+    }
+
+    pub fn get_table(&self, key: &str) -> Option<&Table> {
+        self.get(key).and_then(Value::as_table)
+    }
+
+    /// The tables of a `[[key]]` array of tables (empty if there are none)
+    pub fn get_tables(&self, key: &str) -> Vec<&Table> {
+        self.get_array(key)
+            .map(|items| items.iter().filter_map(Value::as_table).collect())
+            .unwrap_or_default()
+    }
+
+    /// A value as text, see `Value::to_text`
+    pub fn get_text(&self, key: &str) -> Option<String> {
+        self.get(key).map(Value::to_text)
+    }
+
+    /// A string or string array value as a list, see `Value::string_list`
+    pub fn get_string_list(&self, key: &str) -> Vec<String> {
+        self.get(key).map(Value::string_list).unwrap_or_default()
+    }
+
+    /// The subtables, e.g. `cuda` and `vulkan` of `[binaries.cuda]` and `[binaries.vulkan]`
+    pub fn tables(&self) -> impl Iterator<Item = (&str, &Table)> {
+        self.entries.iter().filter_map(|entry| entry.value.as_table().map(|table| (entry.key.as_str(), table)))
+    }
+
+    /// All values that are not tables, with dotted keys, e.g. `("aliases.review", ...)`
+    pub fn flatten(&self) -> Vec<(String, &Value)> {
+        let mut values = Vec::new();
+        for entry in &self.entries {
+            match &entry.value {
+                Value::Table(table) => values.extend(
+                    table.flatten().into_iter().map(|(key, value)| (format!("{}.{}", entry.key, key), value))
+                ),
+                value => values.push((entry.key.clone(), value)),
+            }
+        }
+        values
+    }
+
+    fn position(&self, key: &str) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.key == key)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(text) => write!(f, "{}", quote(text)),
+            Value::Integer(number) => write!(f, "{}", number),
+            Value::Float(number) if number.is_nan() => write!(f, "nan"),
+            Value::Float(number) if number.is_infinite() => write!(f, "{}inf", if *number < 0.0 { "-" } else { "" }),
+            Value::Float(number) if number.fract() == 0.0 && number.abs() < 1e16 => write!(f, "{:.1}", number),
+            Value::Float(number) => write!(f, "{}", number),
+            Value::Boolean(flag) => write!(f, "{}", flag),
+            Value::Array(items) => {
+                let items: Vec<String> = items.iter().map(|item| item.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            },
+            Value::Table(table) if table.is_empty() => write!(f, "{{}}"),
+            Value::Table(table) => {
+                let entries: Vec<String> = table.entries.iter()
+                    .map(|entry| format!("{} = {}", format_key(&entry.key), entry.value))
+                    .collect();
+                write!(f, "{{ {} }}", entries.join(", "))
+            },
+        }
+    }
+}
+
+/// Quotes text as a TOML basic string
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn is_bare_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Formats a key as TOML: bare if it can be, quoted otherwise
+pub fn format_key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(is_bare_key_char) {
+        key.to_string()
+    } else {
+        quote(key)
+    }
+}
+
+/// Parses TOML content
+///
+/// # Returns
+/// * `(Table, Vec<String>)` - The root table, and the problems found, as
+///   "line N: message", in file order
+pub fn parse(content: &str) -> (Table, Vec<String>) {
+    let mut parser = Parser::new(content);
+    let mut root = Table::default();
+    let mut errors = Vec::new();
+    // Path of the current [table]; None after a header that could not be read
+    let mut current: Option<Vec<String>> = Some(Vec::new());
+    let mut defined_tables: Vec<(Vec<String>, usize)> = Vec::new();
+
+    loop {
+        parser.skip_whitespace_and_comments(true);
+        if parser.peek().is_none() {
+            break;
+        }
+        let line = parser.line;
+
+        if parser.peek() == Some('[') {
+            let header = parser.parse_table_header().and_then(|header| {
+                parser.expect_line_end()?;
+                Ok(header)
+            });
+            match header {
+                Ok((path, is_array)) => {
+                    if !is_array {
+                        if let Some((_, first_line)) = defined_tables.iter().find(|(defined, _)| *defined == path) {
+                            errors.push(format!("line {}: table [{}] defined twice (first defined on line {})",
+                                line, join_key(&path), first_line));
+                        } else {
+                            defined_tables.push((path.clone(), line));
+                        }
+                    }
+                    match open_table(&mut root, &path, is_array, line) {
+                        Ok(()) => current = Some(path),
+                        Err(message) => {
+                            errors.push(format!("line {}: {}", line, message));
+                            current = None;
+                        },
+                    }
+                },
+                Err(message) => {
+                    errors.push(format!("line {}: {}", parser.line, message));
+                    parser.skip_line();
+                    current = None;
+                },
+            }
+            continue;
+        }
+
+        let key = match parser.parse_key_path() {
+            Ok(key) => key,
+            Err(message) => {
+                errors.push(format!("line {}: {}", line, message));
+                parser.skip_line();
+                continue;
+            },
+        };
+        parser.skip_whitespace();
+        if !parser.eat('=') {
+            errors.push(format!("line {}: malformed line, missing '=': {}", line, parser.line_text(line)));
+            parser.skip_line();
+            continue;
+        }
+
+        // A value that cannot be read is kept as the text of the rest of the line
+        let value_start = parser.save();
+        let value = match parser.parse_value().and_then(|value| {
+            parser.expect_line_end()?;
+            Ok(value)
+        }) {
+            Ok(value) => value,
+            Err(message) => {
+                errors.push(format!("line {}: {}", parser.line, message));
+                parser.restore(value_start);
+                let text = parser.rest_of_line();
+                Value::String(text.trim().trim_matches('"').trim().to_string())
+            },
+        };
+
+        if let Some(path) = &current {
+            if let Err(message) = insert(&mut root, path, &key, value, line) {
+                errors.push(format!("line {}: {}", line, message));
+            }
+        }
+    }
+
+    (root, errors)
+}
+
+/// Parses a single value, e.g. `["a", "b"]` or `"text"`
+pub fn parse_value(text: &str) -> Result<Value, String> {
+    let mut parser = Parser::new(text);
+    parser.skip_whitespace();
+    let value = parser.parse_value()?;
+    parser.skip_whitespace_and_comments(true);
+    match parser.peek() {
+        None => Ok(value),
+        Some(_) => Err(format!("unexpected text after the value: {}", parser.rest_of_line().trim())),
+    }
+}
+
+fn join_key(path: &[String]) -> String {
+    path.iter().map(|key| format_key(key)).collect::<Vec<_>>().join(".")
+}
+
+/// Finds the table at `path`, creating missing tables on the way
+///
+/// A path through an array of tables continues in its last table.
+fn table_at<'t>(root: &'t mut Table, path: &[String], line: usize) -> Result<&'t mut Table, String> {
+    let mut table = root;
+    for key in path {
+        let index = match table.position(key) {
+            Some(index) => index,
+            None => {
+                table.entries.push(Entry { key: key.clone(), value: Value::Table(Table::default()), line });
+                table.entries.len() - 1
+            },
+        };
+        table = match &mut table.entries[index].value {
+            Value::Table(inner) => inner,
+            Value::Array(items) => match items.last_mut() {
+                Some(Value::Table(inner)) => inner,
+                _ => return Err(format!("key '{}' is an array, not a table", key)),
+            },
+            _ => return Err(format!("key '{}' already has a value and cannot be a table", key)),
+        };
+    }
+    Ok(table)
+}
+
+/// Makes the table of a `[path]` header, or adds a table to a `[[path]]` array
+fn open_table(root: &mut Table, path: &[String], is_array: bool, line: usize) -> Result<(), String> {
+    let (last, parent_path) = match path.split_last() {
+        Some(split) => split,
+        None => return Err("empty table name".to_string()),
+    };
+    let parent = table_at(root, parent_path, line)?;
+    match parent.position(last) {
+        None => {
+            let table = Value::Table(Table::default());
+            let value = if is_array { Value::Array(vec![table]) } else { table };
+            parent.entries.push(Entry { key: last.clone(), value, line });
+            Ok(())
+        },
+        Some(index) => match (&mut parent.entries[index].value, is_array) {
+            (Value::Table(_), false) => Ok(()),
+            (Value::Array(items), true) if items.iter().all(|item| item.as_table().is_some()) => {
+                items.push(Value::Table(Table::default()));
+                Ok(())
+            },
+            _ => Err(format!("[{}] conflicts with an earlier value of the same name", join_key(path))),
+        },
+    }
+}
+
+/// Adds `key = value` (the key possibly dotted) to the table at `path`
+fn insert(root: &mut Table, path: &[String], key: &[String], value: Value, line: usize) -> Result<(), String> {
+    let (last, key_path) = match key.split_last() {
+        Some(split) => split,
+        None => return Err("empty key".to_string()),
+    };
+    let full_path: Vec<String> = path.iter().chain(key_path).cloned().collect();
+    let table = table_at(root, &full_path, line)?;
+    let duplicate = table.get_entry(last).map(|entry| entry.line);
+    table.entries.push(Entry { key: last.clone(), value, line });
+    match duplicate {
+        Some(first_line) => Err(format!("duplicate key '{}' (first defined on line {})", last, first_line)),
+        None => Ok(()),
+    }
+}
+
+/// Reads TOML text one character at a time, counting lines
+struct Parser<'a> {
+    text: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Parser { text, chars: text.chars().collect(), pos: 0, line: 1 }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars().enumerate().all(|(offset, c)| self.peek_at(offset) == Some(c))
+    }
+
+    fn save(&self) -> (usize, usize) {
+        (self.pos, self.line)
+    }
+
+    fn restore(&mut self, (pos, line): (usize, usize)) {
+        self.pos = pos;
+        self.line = line;
+    }
+
+    /// The text of a 1-based line, trimmed, for error messages
+    fn line_text(&self, line: usize) -> &str {
+        self.text.lines().nth(line.saturating_sub(1)).unwrap_or("").trim()
+    }
+
+    /// Consumes up to the end of the line (not the newline itself)
+    fn rest_of_line(&mut self) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                break;
+            }
+            text.push(c);
+            self.bump();
+        }
+        text
+    }
+
+    /// Consumes up to and including the next newline
+    fn skip_line(&mut self) {
+        self.rest_of_line();
+        self.bump();
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.bump();
+        }
+    }
+
+    /// Skips spaces and comments, and newlines too if `newlines` is set
+    fn skip_whitespace_and_comments(&mut self, newlines: bool) {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') => { self.bump(); },
+                Some('\n') | Some('\r') if newlines => { self.bump(); },
+                Some('#') => { self.rest_of_line(); },
+                _ => break,
+            }
+        }
+    }
+
+    /// Accepts only spaces and a comment until the end of the line
+    fn expect_line_end(&mut self) -> Result<(), String> {
+        self.skip_whitespace_and_comments(false);
+        self.eat('\r');
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.bump();
+                Ok(())
+            },
+            Some(_) => {
+                let rest = self.rest_of_line();
+                Err(format!("unexpected text after the value: {}", rest.trim()))
+            },
+        }
+    }
+
+    /// Reads `[a.b]` or `[[a.b]]`
+    fn parse_table_header(&mut self) -> Result<(Vec<String>, bool), String> {
+        self.bump();
+        let is_array = self.eat('[');
+        let path = self.parse_key_path()?;
+        self.skip_whitespace();
+        if !self.eat(']') || (is_array && !self.eat(']')) {
+            return Err(format!("malformed table header: {}", self.line_text(self.line)));
+        }
+        Ok((path, is_array))
+    }
+
+    /// Reads a possibly dotted key, e.g. `a."b c".d`
+    fn parse_key_path(&mut self) -> Result<Vec<String>, String> {
+        let mut path = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let key = match self.peek() {
+                Some('"') => {
+                    self.bump();
+                    self.parse_basic_string()?
+                },
+                Some('\'') => {
+                    self.bump();
+                    self.parse_literal_string()?
+                },
+                _ => {
+                    let mut key = String::new();
+                    while let Some(c) = self.peek().filter(|c| is_bare_key_char(*c)) {
+                        key.push(c);
+                        self.bump();
+                    }
+                    if key.is_empty() {
+                        return Err(format!("missing key: {}", self.line_text(self.line)));
+                    }
+                    key
+                },
+            };
+            path.push(key);
+            self.skip_whitespace();
+            if !self.eat('.') {
+                return Ok(path);
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('"') if self.starts_with("\"\"\"") => {
+                self.pos += 3;
+                self.parse_multiline_string('"').map(Value::String)
+            },
+            Some('"') => {
+                self.bump();
+                self.parse_basic_string().map(Value::String)
+            },
+            Some('\'') if self.starts_with("'''") => {
+                self.pos += 3;
+                self.parse_multiline_string('\'').map(Value::String)
+            },
+            Some('\'') => {
+                self.bump();
+                self.parse_literal_string().map(Value::String)
+            },
+            Some('[') => {
+                self.bump();
+                self.parse_array()
+            },
+            Some('{') => {
+                self.bump();
+                self.parse_inline_table()
+            },
+            Some(_) => {
+                let mut token = String::new();
+                while let Some(c) = self.peek().filter(|c| is_bare_key_char(*c) || matches!(c, '+' | '.' | ':')) {
+                    token.push(c);
+                    self.bump();
+                }
+                parse_scalar(&token)
+            },
+            None => Err("missing value".to_string()),
+        }
+    }
+
+    /// Reads a basic string after its opening quote
+    fn parse_basic_string(&mut self) -> Result<String, String> {
+        let mut raw = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(unescape(&raw, false)),
+                Some('\\') => {
+                    raw.push('\\');
+                    match self.peek() {
+                        Some('\n') | None => return Err("unterminated string".to_string()),
+                        Some(c) => {
+                            raw.push(c);
+                            self.bump();
+                        },
+                    }
+                },
+                Some('\n') | None => return Err("unterminated string".to_string()),
+                Some(c) => raw.push(c),
+            }
+        }
+    }
+
+    /// Reads a literal string after its opening quote
+    fn parse_literal_string(&mut self) -> Result<String, String> {
+        let mut text = String::new();
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(text),
+                Some('\n') | None => return Err("unterminated string".to_string()),
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    /// Reads a `"""` or `'''` string after its opening quotes
+    fn parse_multiline_string(&mut self, quote: char) -> Result<String, String> {
+        // A newline right after the opening quotes is not part of the string
+        if self.starts_with("\r\n") {
+            self.pos += 1;
+        }
+        if self.peek() == Some('\n') {
+            self.bump();
+        }
+        let closing: String = std::iter::repeat_n(quote, 3).collect();
+        let mut raw = String::new();
+        loop {
+            if self.starts_with(&closing) {
+                // Up to two quotes just before the closing ones belong to the string
+                let mut quotes = 0;
+                while self.peek_at(quotes) == Some(quote) && quotes < 5 {
+                    quotes += 1;
+                }
+                raw.extend(std::iter::repeat_n(quote, quotes - 3));
+                self.pos += quotes;
+                return Ok(if quote == '"' { unescape(&raw, true) } else { raw });
+            }
+            match self.bump() {
+                Some('\\') if quote == '"' => {
+                    raw.push('\\');
+                    if let Some(c) = self.bump() {
+                        raw.push(c);
+                    }
+                },
+                Some(c) => raw.push(c),
+                None => return Err("unterminated multi-line string".to_string()),
+            }
+        }
+    }
+
+    /// Reads an array after its `[`
+    fn parse_array(&mut self) -> Result<Value, String> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace_and_comments(true);
+            if self.eat(']') {
+                return Ok(Value::Array(items));
+            }
+            items.push(self.parse_value()?);
+            self.skip_whitespace_and_comments(true);
+            if self.eat(']') {
+                return Ok(Value::Array(items));
+            }
+            if !self.eat(',') {
+                return Err("expected ',' or ']' in the array".to_string());
+            }
+        }
+    }
+
+    /// Reads an inline table after its `{`
+    fn parse_inline_table(&mut self) -> Result<Value, String> {
+        let mut table = Table::default();
+        self.skip_whitespace_and_comments(true);
+        if self.eat('}') {
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_whitespace_and_comments(true);
+            let line = self.line;
+            let key = self.parse_key_path()?;
+            self.skip_whitespace();
+            if !self.eat('=') {
+                return Err("expected '=' in the inline table".to_string());
+            }
+            let value = self.parse_value()?;
+            insert(&mut table, &[], &key, value, line)?;
+            self.skip_whitespace_and_comments(true);
+            if self.eat('}') {
+                return Ok(Value::Table(table));
+            }
+            if !self.eat(',') {
+                return Err("expected ',' or '}' in the inline table".to_string());
+            }
+        }
+    }
+}
+
+/// Reads a boolean or number token
+fn parse_scalar(token: &str) -> Result<Value, String> {
+    match token {
+        "" => return Err("missing value".to_string()),
+        "true" => return Ok(Value::Boolean(true)),
+        "false" => return Ok(Value::Boolean(false)),
+        "inf" | "+inf" => return Ok(Value::Float(f64::INFINITY)),
+        "-inf" => return Ok(Value::Float(f64::NEG_INFINITY)),
+        "nan" | "+nan" | "-nan" => return Ok(Value::Float(f64::NAN)),
+        _ => (),
+    }
+
+    let bytes = token.as_bytes();
+    let is_date = bytes.len() >= 10 && bytes[..4].iter().all(u8::is_ascii_digit) && bytes[4] == b'-';
+    if is_date || token.contains(':') {
+        return Err(format!("dates and times are not supported: {}", token));
+    }
+    if !token.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-') {
+        return Err(format!("invalid value '{}' (text needs quotes)", token));
+    }
+
+    let digits = token.replace('_', "");
+    let radix = match digits.get(..2) {
+        Some("0x") => Some(16),
+        Some("0o") => Some(8),
+        Some("0b") => Some(2),
+        _ => None,
+    };
+    let number = match radix {
+        Some(radix) => i64::from_str_radix(&digits[2..], radix).ok().map(Value::Integer),
+        None if digits.contains(['.', 'e', 'E']) => digits.parse().ok().map(Value::Float),
+        None => digits.parse().ok().map(Value::Integer),
+    };
+    number.ok_or_else(|| format!("invalid number: {}", token))
+}
+
+/// Processes the escapes of a basic string
+///
+/// Text with a backslash that is not a valid escape, or that starts with a
+/// Windows drive (`C:\`), is returned as written (see the module docs).
+fn unescape(raw: &str, multiline: bool) -> String {
+    let bytes = raw.as_bytes();
+    let is_windows_path = bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && &bytes[1..3] == b":\\"
+        && bytes.get(3) != Some(&b'\\');
+    if is_windows_path {
+        return raw.to_string();
+    }
+
+    let mut text = String::with_capacity(raw.len());
+    let mut chars = raw.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('b') => '\u{8}',
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('f') => '\u{c}',
+            Some('r') => '\r',
+            Some('e') => '\u{1b}',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some(kind @ ('u' | 'U')) => {
+                let length = if kind == 'u' { 4 } else { 8 };
+                let hex: String = chars.by_ref().take(length).collect();
+                match u32::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == length).and_then(char::from_u32) {
+                    Some(c) => c,
+                    None => return raw.to_string(),
+                }
+            },
+            // A backslash at the end of a line joins it with the next non-blank text
+            Some(c) if multiline && c.is_whitespace() => {
+                let mut rest_is_blank = c == '\n';
+                while let Some(next) = chars.peek().copied().filter(|next| next.is_whitespace()) {
+                    rest_is_blank |= next == '\n';
+                    chars.next();
+                }
+                if !rest_is_blank {
+                    return raw.to_string();
+                }
+                continue;
+            },
+            _ => return raw.to_string(),
+        };
+        text.push(escaped);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_toml_lite() {
+        let content = r#"
+# comment
+name = "query_gguf" # trailing comment
+count = 1_000
+hex = 0xff
+ratio = 0.5
+big = 1e3
+enabled = true
+path = "C:\models\new.gguf"
+escaped = "tab\there \"quoted\" \u00e9"
+literal = 'C:\raw\"text"'
+list = [
+    "a", # first
+    'b',
+]
+nested = [[1, 2], ["x"]]
+point = { x = 1, y.z = "deep" }
+text = """
+first line
+second \
+  joined"""
+raw = '''no \escapes here'''
+
+[binaries.cuda]
+path = "/cuda/llama-cli"
+
+[binaries.vulkan]
+path = "/vulkan/llama-cli"
+
+[[questions]]
+question = "one"
+
+[[questions]]
+question = "two"
+"quoted key" = 2
+"#;
+        let (root, errors) = parse(content);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        assert_eq!(root.get_str("name"), Some("query_gguf"));
+        assert_eq!(root.get_int("count"), Some(1000));
+        assert_eq!(root.get_int("hex"), Some(255));
+        assert_eq!(root.get_float("ratio"), Some(0.5));
+        assert_eq!(root.get_float("big"), Some(1000.0));
+        assert_eq!(root.get_float("count"), Some(1000.0));
+        assert_eq!(root.get_bool("enabled"), Some(true));
+        assert_eq!(root.get_str("path"), Some("C:\\models\\new.gguf"));
+        assert_eq!(root.get_str("escaped"), Some("tab\there \"quoted\" \u{e9}"));
+        assert_eq!(root.get_str("literal"), Some("C:\\raw\\\"text\""));
+        assert_eq!(root.get_string_list("list"), vec!["a", "b"]);
+        assert_eq!(root.get_array("nested").map(|items| items.len()), Some(2));
+        let point = root.get_table("point").unwrap();
+        assert_eq!(point.get_int("x"), Some(1));
+        assert_eq!(point.get_table("y").and_then(|y| y.get_str("z")), Some("deep"));
+        assert_eq!(root.get_str("text"), Some("first line\nsecond joined"));
+        assert_eq!(root.get_str("raw"), Some("no \\escapes here"));
+        assert_eq!(root.get_text("count").as_deref(), Some("1000"));
+        assert_eq!(root.get_text("list").as_deref(), Some("[\"a\", \"b\"]"));
+
+        let binaries: Vec<(&str, Option<&str>)> = root.get_table("binaries").unwrap()
+            .tables()
+            .map(|(name, table)| (name, table.get_str("path")))
+            .collect();
+        assert_eq!(binaries, vec![("cuda", Some("/cuda/llama-cli")), ("vulkan", Some("/vulkan/llama-cli"))]);
+        assert_eq!(root.get_entry("binaries").map(|entry| entry.line), Some(24));
+
+        let questions = root.get_tables("questions");
+        assert_eq!(questions.len(), 2);
+        assert_eq!(questions[1].get_str("question"), Some("two"));
+        assert_eq!(questions[1].get_int("quoted key"), Some(2));
+
+        assert_eq!(parse_value("[\"a.toml\", 'b.toml']").unwrap().string_list(), vec!["a.toml", "b.toml"]);
+        assert!(parse_value("tests/coder.toml").is_err());
+    }
+
+    #[test]
+    fn test_parse_toml_lite_errors() {
+        let content = "a = 1\nb = nano\nc\na = 2\nwhen = 2024-01-01\n[t]\nx = [1,\n[t]\nd = \"ok\" extra\n";
+        let (root, errors) = parse(content);
+        assert_eq!(errors, vec![
+            "line 2: invalid value 'nano' (text needs quotes)",
+            "line 3: malformed line, missing '=': c",
+            "line 4: duplicate key 'a' (first defined on line 1)",
+            "line 5: dates and times are not supported: 2024-01-01",
+            "line 8: invalid value 't' (text needs quotes)",
+            "line 8: table [t] defined twice (first defined on line 6)",
+            "line 9: unexpected text after the value: extra",
+        ]);
+
+        // Unreadable values are kept as text, duplicates keep the first value
+        assert_eq!(root.get_str("b"), Some("nano"));
+        assert_eq!(root.get_int("a"), Some(1));
+        assert_eq!(root.entries().iter().filter(|entry| entry.key == "a").count(), 2);
+        assert_eq!(root.get_str("when"), Some("2024-01-01"));
+        let table = root.get_table("t").unwrap();
+        assert_eq!(table.get_str("x"), Some("[1,"));
+        assert_eq!(table.get_str("d"), Some("ok\" extra"));
+    }
+}